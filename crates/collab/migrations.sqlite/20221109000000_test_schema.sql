@@ -501,11 +501,22 @@ CREATE TABLE IF NOT EXISTS billing_customers (
     user_id INTEGER NOT NULL REFERENCES users (id),
     has_overdue_invoices BOOLEAN NOT NULL DEFAULT FALSE,
     stripe_customer_id TEXT NOT NULL,
-    trial_started_at TIMESTAMP
+    trial_started_at TIMESTAMP,
+    billing_suspended BOOLEAN NOT NULL DEFAULT FALSE,
+    payment_method_expiry_reminded_at TIMESTAMP,
+    winback_offered_at TIMESTAMP,
+    stripe_email TEXT,
+    stripe_email_user_mismatch BOOLEAN NOT NULL DEFAULT FALSE,
+    tax_id_type TEXT,
+    tax_id TEXT,
+    currency TEXT NOT NULL DEFAULT 'usd',
+    free_downgrade_at TIMESTAMP
 );
 
 CREATE UNIQUE INDEX "uix_billing_customers_on_user_id" ON billing_customers (user_id);
 
+CREATE INDEX "ix_billing_customers_on_free_downgrade_at" ON billing_customers (free_downgrade_at);
+
 CREATE UNIQUE INDEX "uix_billing_customers_on_stripe_customer_id" ON billing_customers (stripe_customer_id);
 
 CREATE TABLE IF NOT EXISTS billing_subscriptions (
@@ -515,16 +526,105 @@ CREATE TABLE IF NOT EXISTS billing_subscriptions (
     stripe_subscription_id TEXT NOT NULL,
     stripe_subscription_status TEXT NOT NULL,
     stripe_cancel_at TIMESTAMP,
+    stripe_cancel_at_period_end BOOLEAN NOT NULL DEFAULT FALSE,
     stripe_cancellation_reason TEXT,
     kind TEXT,
+    unclassified BOOLEAN NOT NULL DEFAULT FALSE,
     stripe_current_period_start BIGINT,
-    stripe_current_period_end BIGINT
+    stripe_current_period_end BIGINT,
+    past_due_since TIMESTAMP,
+    seats INTEGER NOT NULL DEFAULT 1,
+    monthly_recurring_revenue_in_cents BIGINT,
+    ended_at TIMESTAMP
 );
 
 CREATE INDEX "ix_billing_subscriptions_on_billing_customer_id" ON billing_subscriptions (billing_customer_id);
 
 CREATE UNIQUE INDEX "uix_billing_subscriptions_on_stripe_subscription_id" ON billing_subscriptions (stripe_subscription_id);
 
+CREATE TABLE IF NOT EXISTS billing_subscription_pauses (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    billing_subscription_id INTEGER NOT NULL REFERENCES billing_subscriptions (id),
+    paused_at TIMESTAMP NOT NULL,
+    resumes_at TIMESTAMP
+);
+
+CREATE INDEX "ix_billing_subscription_pauses_on_billing_subscription_id_paused_at" ON billing_subscription_pauses (billing_subscription_id, paused_at);
+
+CREATE TABLE IF NOT EXISTS billing_subscription_members (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    billing_subscription_id INTEGER NOT NULL REFERENCES billing_subscriptions (id),
+    user_id INTEGER NOT NULL REFERENCES users (id)
+);
+
+CREATE UNIQUE INDEX "uix_billing_subscription_members_on_billing_subscription_id_user_id" ON billing_subscription_members (billing_subscription_id, user_id);
+
+CREATE TABLE IF NOT EXISTS billing_usage_adjustments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    billing_customer_id INTEGER NOT NULL REFERENCES billing_customers (id),
+    model TEXT NOT NULL,
+    mode TEXT NOT NULL,
+    requests INTEGER NOT NULL,
+    amount_in_cents BIGINT NOT NULL,
+    reason TEXT NOT NULL,
+    stripe_balance_transaction_id TEXT NOT NULL
+);
+
+CREATE INDEX "ix_billing_usage_adjustments_on_billing_customer_id" ON billing_usage_adjustments (billing_customer_id);
+
+CREATE TABLE IF NOT EXISTS billing_meter_reports (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    billing_customer_id INTEGER NOT NULL REFERENCES billing_customers (id),
+    meter_event_name TEXT NOT NULL,
+    value INTEGER NOT NULL,
+    identifier TEXT NOT NULL,
+    reported_at TIMESTAMP NOT NULL
+);
+
+CREATE UNIQUE INDEX "uix_billing_meter_reports_on_identifier" ON billing_meter_reports (identifier);
+
+CREATE INDEX "ix_billing_meter_reports_on_billing_customer_id_reported_at" ON billing_meter_reports (billing_customer_id, reported_at);
+
+CREATE TABLE IF NOT EXISTS billing_payment_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    billing_customer_id INTEGER NOT NULL REFERENCES billing_customers (id),
+    kind TEXT NOT NULL,
+    stripe_event_id TEXT NOT NULL,
+    stripe_invoice_id TEXT NOT NULL,
+    amount_in_cents BIGINT NOT NULL,
+    occurred_at TIMESTAMP NOT NULL
+);
+
+CREATE UNIQUE INDEX "uix_billing_payment_events_on_stripe_event_id" ON billing_payment_events (stripe_event_id);
+
+CREATE INDEX "ix_billing_payment_events_on_billing_customer_id_occurred_at" ON billing_payment_events (billing_customer_id, occurred_at);
+
+CREATE TABLE IF NOT EXISTS custom_price_overrides (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    billing_customer_id INTEGER NOT NULL REFERENCES billing_customers (id),
+    meter_event_name TEXT NOT NULL,
+    stripe_price_id TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX "uix_custom_price_overrides_on_billing_customer_id_meter_event_name" ON custom_price_overrides (billing_customer_id, meter_event_name);
+
+CREATE TABLE IF NOT EXISTS billing_price_change_notices (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    billing_subscription_id INTEGER NOT NULL REFERENCES billing_subscriptions (id),
+    stripe_price_id TEXT NOT NULL,
+    amount_in_cents BIGINT NOT NULL,
+    effective_at TIMESTAMP NOT NULL
+);
+
+CREATE UNIQUE INDEX "uix_billing_price_change_notices_on_billing_subscription_id_stripe_price_id" ON billing_price_change_notices (billing_subscription_id, stripe_price_id);
+
 CREATE TABLE IF NOT EXISTS processed_stripe_events (
     stripe_event_id TEXT PRIMARY KEY,
     stripe_event_type TEXT NOT NULL,
@@ -534,6 +634,30 @@ CREATE TABLE IF NOT EXISTS processed_stripe_events (
 
 CREATE INDEX "ix_processed_stripe_events_on_stripe_event_created_timestamp" ON processed_stripe_events (stripe_event_created_timestamp);
 
+CREATE TABLE IF NOT EXISTS stripe_event_cursors (
+    id INTEGER PRIMARY KEY,
+    stripe_event_id TEXT NOT NULL,
+    stripe_event_created_timestamp INTEGER NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS failed_stripe_events (
+    stripe_event_id TEXT PRIMARY KEY,
+    stripe_event_type TEXT NOT NULL,
+    stripe_event_created_timestamp INTEGER NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_error TEXT NOT NULL,
+    first_failed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_failed_at TIMESTAMP NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pending_billing_customer_links (
+    stripe_customer_id TEXT PRIMARY KEY,
+    email TEXT NOT NULL,
+    first_seen_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_seen_at TIMESTAMP NOT NULL
+);
+
 CREATE TABLE IF NOT EXISTS "breakpoints" (
     "id" INTEGER PRIMARY KEY AUTOINCREMENT,
     "project_id" INTEGER NOT NULL REFERENCES projects (id) ON DELETE CASCADE,
@@ -0,0 +1,13 @@
+create table if not exists billing_usage_adjustments (
+    id serial primary key,
+    created_at timestamp without time zone not null default now(),
+    billing_customer_id integer not null references billing_customers(id) on delete cascade,
+    model text not null,
+    mode text not null,
+    requests integer not null,
+    amount_in_cents bigint not null,
+    reason text not null,
+    stripe_balance_transaction_id text not null
+);
+
+create index "ix_billing_usage_adjustments_on_billing_customer_id" on billing_usage_adjustments (billing_customer_id);
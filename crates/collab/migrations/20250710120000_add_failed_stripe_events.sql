@@ -0,0 +1,9 @@
+create table if not exists failed_stripe_events (
+    stripe_event_id text primary key,
+    stripe_event_type text not null,
+    stripe_event_created_timestamp bigint not null,
+    attempts integer not null,
+    last_error text not null,
+    first_failed_at timestamp without time zone not null default now(),
+    last_failed_at timestamp without time zone not null
+);
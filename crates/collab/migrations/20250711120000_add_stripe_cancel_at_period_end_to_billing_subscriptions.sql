@@ -0,0 +1,2 @@
+alter table billing_subscriptions
+    add column stripe_cancel_at_period_end bool not null default false;
@@ -0,0 +1,2 @@
+alter table billing_customers
+    add column billing_suspended bool not null default false;
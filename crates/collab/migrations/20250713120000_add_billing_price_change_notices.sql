@@ -0,0 +1,10 @@
+create table if not exists billing_price_change_notices (
+    id serial primary key,
+    created_at timestamp without time zone not null default now(),
+    billing_subscription_id integer not null references billing_subscriptions(id) on delete cascade,
+    stripe_price_id text not null,
+    amount_in_cents bigint not null,
+    effective_at timestamp without time zone not null
+);
+
+create unique index "uix_billing_price_change_notices_on_billing_subscription_id_stripe_price_id" on billing_price_change_notices (billing_subscription_id, stripe_price_id);
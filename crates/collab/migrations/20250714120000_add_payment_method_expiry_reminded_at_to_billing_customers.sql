@@ -0,0 +1,2 @@
+alter table billing_customers
+    add column payment_method_expiry_reminded_at timestamp without time zone;
@@ -0,0 +1,2 @@
+alter table billing_customers
+    add column winback_offered_at timestamp without time zone;
@@ -0,0 +1,12 @@
+create table if not exists billing_meter_reports (
+    id serial primary key,
+    created_at timestamp without time zone not null default now(),
+    billing_customer_id integer not null references billing_customers(id) on delete cascade,
+    meter_event_name text not null,
+    value integer not null,
+    identifier text not null,
+    reported_at timestamp without time zone not null
+);
+
+create unique index "uix_billing_meter_reports_on_identifier" on billing_meter_reports (identifier);
+create index "ix_billing_meter_reports_on_billing_customer_id_reported_at" on billing_meter_reports (billing_customer_id, reported_at);
@@ -0,0 +1,2 @@
+alter table billing_subscriptions
+    add column unclassified bool not null default false;
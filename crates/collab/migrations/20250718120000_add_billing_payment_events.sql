@@ -0,0 +1,13 @@
+create table if not exists billing_payment_events (
+    id serial primary key,
+    created_at timestamp without time zone not null default now(),
+    billing_customer_id integer not null references billing_customers(id) on delete cascade,
+    kind text not null,
+    stripe_event_id text not null,
+    stripe_invoice_id text not null,
+    amount_in_cents bigint not null,
+    occurred_at timestamp without time zone not null
+);
+
+create unique index "uix_billing_payment_events_on_stripe_event_id" on billing_payment_events (stripe_event_id);
+create index "ix_billing_payment_events_on_billing_customer_id_occurred_at" on billing_payment_events (billing_customer_id, occurred_at);
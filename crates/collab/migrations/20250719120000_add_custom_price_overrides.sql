@@ -0,0 +1,9 @@
+create table if not exists custom_price_overrides (
+    id serial primary key,
+    created_at timestamp without time zone not null default now(),
+    billing_customer_id integer not null references billing_customers(id) on delete cascade,
+    meter_event_name text not null,
+    stripe_price_id text not null
+);
+
+create unique index "uix_custom_price_overrides_on_billing_customer_id_meter_event_name" on custom_price_overrides (billing_customer_id, meter_event_name);
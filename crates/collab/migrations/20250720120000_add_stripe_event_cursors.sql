@@ -0,0 +1,6 @@
+create table if not exists stripe_event_cursors (
+    id integer primary key,
+    stripe_event_id text not null,
+    stripe_event_created_timestamp bigint not null,
+    updated_at timestamp without time zone not null default now()
+);
@@ -0,0 +1,9 @@
+alter table billing_customers add column stripe_email text;
+alter table billing_customers add column stripe_email_user_mismatch bool not null default false;
+
+create table if not exists pending_billing_customer_links (
+    stripe_customer_id text primary key,
+    email text not null,
+    first_seen_at timestamp without time zone not null default now(),
+    last_seen_at timestamp without time zone not null
+);
@@ -0,0 +1,2 @@
+alter table billing_customers add column tax_id_type text;
+alter table billing_customers add column tax_id text;
@@ -0,0 +1 @@
+alter table billing_subscriptions add column past_due_since timestamp without time zone;
@@ -0,0 +1 @@
+alter table billing_customers add column currency text not null default 'usd';
@@ -0,0 +1 @@
+alter table billing_subscriptions add column seats integer not null default 1;
@@ -0,0 +1,4 @@
+alter table billing_customers
+    add column free_downgrade_at timestamp without time zone;
+
+create index "ix_billing_customers_on_free_downgrade_at" on billing_customers (free_downgrade_at);
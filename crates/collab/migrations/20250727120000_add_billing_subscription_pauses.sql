@@ -0,0 +1,9 @@
+create table if not exists billing_subscription_pauses (
+    id serial primary key,
+    created_at timestamp without time zone not null default now(),
+    billing_subscription_id integer not null references billing_subscriptions(id) on delete cascade,
+    paused_at timestamp without time zone not null,
+    resumes_at timestamp without time zone
+);
+
+create index "ix_billing_subscription_pauses_on_billing_subscription_id_paused_at" on billing_subscription_pauses (billing_subscription_id, paused_at);
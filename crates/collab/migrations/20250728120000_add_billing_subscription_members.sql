@@ -0,0 +1,8 @@
+create table if not exists billing_subscription_members (
+    id serial primary key,
+    created_at timestamp without time zone not null default now(),
+    billing_subscription_id integer not null references billing_subscriptions(id) on delete cascade,
+    user_id integer not null references users(id) on delete cascade
+);
+
+create unique index "uix_billing_subscription_members_on_billing_subscription_id_user_id" on billing_subscription_members (billing_subscription_id, user_id);
@@ -0,0 +1,3 @@
+alter table billing_subscriptions
+    add column monthly_recurring_revenue_in_cents bigint,
+    add column ended_at timestamp without time zone;
@@ -0,0 +1,6 @@
+alter table subscription_usage_meters_v2
+    add column dimensions text not null default '';
+
+drop index uix_subscription_usage_meters_v2_on_usage_model_mode;
+
+create unique index uix_subscription_usage_meters_v2_on_usage_model_mode_dimensions on subscription_usage_meters_v2 (subscription_usage_id, model_id, mode, dimensions);
@@ -1,17 +1,30 @@
-use anyhow::{Context as _, bail};
+pub(crate) mod localization;
+pub(crate) mod manifest;
+pub(crate) mod metrics;
+
+pub use metrics::init_billing_metrics;
+
+use anyhow::{Context as _, anyhow, bail};
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderValue, Request};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
 use axum::routing::put;
 use axum::{
     Extension, Json, Router,
-    extract::{self, Query},
+    extract::{self, Path, Query},
     routing::{get, post},
 };
-use chrono::{DateTime, SecondsFormat, Utc};
-use collections::{HashMap, HashSet};
+use chrono::{DateTime, Datelike as _, SecondsFormat, Utc};
+use collections::{BTreeMap, HashMap, HashSet};
+use futures::{FutureExt as _, StreamExt as _};
+use hmac::Mac as _;
+use rand::seq::IteratorRandom as _;
 use reqwest::StatusCode;
-use sea_orm::ActiveValue;
+use sea_orm::{ActiveValue, DbErr, RuntimeErr};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::{str::FromStr, sync::Arc, time::Duration};
+use std::{future::Future, str::FromStr, sync::Arc, time::Duration};
 use stripe::{
     BillingPortalSession, CancellationDetailsReason, CreateBillingPortalSession,
     CreateBillingPortalSessionFlowData, CreateBillingPortalSessionFlowDataAfterCompletion,
@@ -21,66 +34,526 @@ use stripe::{
     CreateBillingPortalSessionFlowDataType, CustomerId, EventObject, EventType, ListEvents,
     PaymentMethod, Subscription, SubscriptionId, SubscriptionStatus,
 };
+use tokio::sync::watch;
+use tracing::Instrument as _;
 use util::{ResultExt, maybe};
+use uuid::Uuid;
 use zed_llm_client::LanguageModelProvider;
 
+use crate::api::billing::localization::{Locale, plan_text};
+use crate::api::billing::manifest::BillingConfigManifest;
+use crate::api::billing::metrics::{billing_metrics, render_billing_metrics};
 use crate::api::events::SnowflakeRow;
+use crate::billing_webhooks::{
+    BillingWebhookPayload, notify_billing_webhook_subscribers, timestamped_mac,
+};
+use crate::db::billing_payment_event::PaymentEventKind;
 use crate::db::billing_subscription::{
-    StripeCancellationReason, StripeSubscriptionStatus, SubscriptionKind,
+    self, StripeCancellationReason, StripeSubscriptionStatus, SubscriptionKind,
+};
+use crate::executor::Executor;
+use crate::llm::db::subscription_usage_meter::{self, CompletionMode, UsageDimensions};
+use crate::llm::db::{ModelId, subscription_usage};
+use crate::llm::{
+    AGENT_EXTENDED_TRIAL_FEATURE_FLAG, BYPASS_ACCOUNT_AGE_CHECK_FEATURE_FLAG,
+    MIN_ACCOUNT_AGE_FOR_LLM_USE,
 };
-use crate::llm::AGENT_EXTENDED_TRIAL_FEATURE_FLAG;
-use crate::llm::db::subscription_usage_meter::{self, CompletionMode};
 use crate::rpc::{ResultExt as _, Server};
 use crate::stripe_client::{
-    StripeCancellationDetailsReason, StripeClient, StripeCustomerId, StripeSubscription,
-    StripeSubscriptionId, UpdateCustomerParams,
+    StripeCancellationDetailsReason, StripeCard, StripeCheckoutSessionPaymentMethodCollection,
+    StripeClient, StripeCreateSubscriptionItems, StripeCreateSubscriptionParams, StripeCustomerId,
+    StripeDiscount, StripeInvoice, StripeInvoiceStatus, StripePreviewSubscriptionUpdateParams,
+    StripePrice, StripePriceId, StripePriceRecurring, StripePriceRecurringInterval,
+    StripeProrationBehavior, StripeSubscription, StripeSubscriptionId,
+    StripeSubscriptionUpdatePreview, StripeTaxExempt, StripeTaxId, StripeTaxIdKind,
+    UpdateCustomerParams, UpdateSubscriptionItems, UpdateSubscriptionParams,
+};
+use crate::{
+    AppState, Config, DuplicateCheckoutBehavior, Error, OverlappingSubscriptionResolution, Result,
+    TrialEndBehavior, TrialPaymentMethodCollection, UnclassifiedSubscriptionPlan,
 };
-use crate::{AppState, Error, Result};
-use crate::{db::UserId, llm::db::LlmDatabase};
 use crate::{
     db::{
-        BillingSubscriptionId, CreateBillingCustomerParams, CreateBillingSubscriptionParams,
-        CreateProcessedStripeEventParams, UpdateBillingCustomerParams,
-        UpdateBillingPreferencesParams, UpdateBillingSubscriptionParams, billing_customer,
+        BillingCustomerId, BillingSubscriptionId, CreateBillingCustomerParams,
+        CreateBillingMeterReportParams, CreateBillingPaymentEventParams,
+        CreateBillingPriceChangeNoticeParams, CreateBillingSubscriptionMemberParams,
+        CreateBillingSubscriptionParams, CreateBillingSubscriptionPauseParams,
+        CreateBillingUsageAdjustmentParams, CreateProcessedStripeEventParams,
+        GetBillingSubscriptionsParams, NotificationBatch, RecordFailedStripeEventParams,
+        UpdateBillingCustomerParams, UpdateBillingPreferencesParams,
+        UpdateBillingSubscriptionParams, UpdateStripeEventCursorParams, billing_customer,
+        billing_preference, billing_subscription_pause, billing_usage_adjustment,
+        custom_price_override, stripe_event_cursor,
     },
-    stripe_billing::StripeBilling,
+    stripe_billing::{StripeBilling, ZedProBillingInterval},
+};
+use crate::{
+    db::{Database, User, UserId},
+    llm::db::LlmDatabase,
 };
 
 pub fn router() -> Router {
     Router::new()
-        .route("/billing/preferences", put(update_billing_preferences))
+        .nest("/billing", routes(BillingApiVersion::V1))
+        .nest("/billing/v1", routes(BillingApiVersion::V1))
+        .nest(
+            "/billing/v2",
+            routes(BillingApiVersion::V2).layer(middleware::from_fn(json_error_response)),
+        )
+        .layer(middleware::from_fn(assign_billing_request_id))
+}
+
+/// The routes that Stripe calls.
+///
+/// Stripe authenticates its requests with a signature rather than our API token, so these must be mounted outside of
+/// the API token check.
+pub fn stripe_webhook_router() -> Router {
+    Router::new().route("/billing/webhook", post(handle_stripe_webhook))
+}
+
+/// A version of the billing API.
+///
+/// Clients pin to a version so that we can evolve the shapes of our responses without breaking them. The unversioned
+/// routes are aliases for v1.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum BillingApiVersion {
+    V1,
+    /// Returns tagged results from `manage` and JSON error bodies that carry the error code.
+    V2,
+}
+
+pub(crate) fn routes(version: BillingApiVersion) -> Router {
+    Router::new()
+        .route("/preferences", put(update_billing_preferences))
+        .route("/preferences/bulk", post(bulk_update_billing_preferences))
         .route(
-            "/billing/subscriptions",
+            "/subscriptions",
             get(list_billing_subscriptions).post(create_billing_subscription),
         )
         .route(
-            "/billing/subscriptions/manage",
-            post(manage_billing_subscription),
+            "/subscriptions/intent",
+            post(create_billing_subscription_intent),
+        )
+        .route("/subscriptions/manage", post(manage_billing_subscription))
+        .route("/subscriptions/sync", post(sync_billing_subscription))
+        .route(
+            "/subscriptions/sync-one",
+            post(sync_one_billing_subscription),
+        )
+        .route("/subscriptions/refund", post(refund_billing_subscription))
+        .route(
+            "/subscriptions/reactivate",
+            post(reactivate_billing_subscription),
+        )
+        .route(
+            "/subscriptions/preview-change",
+            post(preview_billing_subscription_change),
+        )
+        .route("/subscriptions/confirm_checkout", post(confirm_checkout))
+        .route("/tax-id", put(update_tax_id))
+        .route(
+            "/subscriptions/:id/scheduled_changes",
+            get(list_scheduled_changes),
+        )
+        .route(
+            "/subscriptions/:id/scheduled_changes/cancel",
+            post(cancel_scheduled_change),
+        )
+        .route("/trials/extend", post(extend_trial))
+        .route("/usage", get(get_current_usage))
+        .route("/usage/history", get(get_usage_history))
+        .route("/team/members", post(add_team_member))
+        .route("/team/usage", get(get_team_usage))
+        .route("/usage/credit", post(credit_model_request_usage))
+        .route("/plan_recommendation", get(get_plan_recommendation))
+        .route("/meter_reports", get(list_meter_reports))
+        .route("/access_status", get(get_access_status))
+        .route("/customers/:id/payment_events", get(list_payment_events))
+        .route("/invoices", get(list_invoices))
+        .route("/payment-methods", get(list_payment_methods))
+        .route("/winback_eligibility", get(get_winback_eligibility))
+        .route("/winback_offer", post(record_winback_offer))
+        .route("/suspension", put(update_billing_suspension))
+        .route("/customers/clear-overdue", post(clear_overdue_invoices))
+        .route("/mrr", get(get_monthly_recurring_revenue))
+        .route("/metrics", get(get_billing_metrics))
+        .route("/churn_risk", get(get_churn_risk))
+        .route(
+            "/subscriptions/migrate_price",
+            post(migrate_subscriptions_to_price),
+        )
+        .route("/price_changes/notify", post(notify_price_change))
+        .route(
+            "/subscriptions/overlapping",
+            get(list_overlapping_subscriptions),
         )
         .route(
-            "/billing/subscriptions/sync",
-            post(sync_billing_subscription),
+            "/subscriptions/unclassified",
+            get(list_unclassified_subscriptions),
         )
-        .route("/billing/usage", get(get_current_usage))
+        .route("/config/manifest", get(get_billing_config_manifest))
+        .route("/events/stuck", get(list_stuck_stripe_events))
+        .route("/events/:id/retry", post(retry_stripe_event))
+        .layer(Extension(version))
+}
+
+/// The header that identifies why a billing request was rejected.
+pub(crate) const ERROR_CODE_HEADER: &str = "x-zed-error-code";
+
+/// The error code for billing requests that are rejected during maintenance.
+pub(crate) const BILLING_MAINTENANCE_ERROR_CODE: &str = "BillingMaintenance";
+
+/// A reason that a billing request was rejected, which clients can branch on.
+///
+/// Each reason is sent with a stable error code in [`ERROR_CODE_HEADER`], as well as a human-readable message.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum BillingError {
+    /// Billing is read-only during maintenance.
+    BillingMaintenance,
+    /// The user already has an active subscription.
+    AlreadySubscribed,
+    /// The user has to pay their overdue invoices first.
+    OverdueInvoices,
+    /// The user has already used their free trial.
+    TrialAlreadyUsed,
+    /// The user needs to add a payment method first.
+    MissingPaymentMethod,
+    /// Zed Free subscriptions can't be canceled.
+    FreeNotCancelable,
+    /// The promotion code doesn't exist, has expired, or can't be applied, for the given reason.
+    InvalidPromotionCode(String),
+    /// The subscription isn't paused, so it can't be resumed.
+    SubscriptionNotPaused,
+    /// The subscription can't be canceled until its minimum term ends, at the given time.
+    MinimumTerm(DateTime<Utc>),
+    /// The subscription has already been paused as many times as it can be in a year, which is given.
+    PauseLimitReached(u32),
+    /// The number of seats isn't allowed, for the given reason.
+    InvalidSeatCount(String),
+    /// The path to redirect to isn't one that we allow, which is given.
+    InvalidRedirect(String),
+    /// The time to pause the subscription until is missing or isn't valid, for the given reason.
+    InvalidPauseUntil(String),
+    /// The subscription doesn't exist, or doesn't belong to the user.
+    SubscriptionNotFound,
+    /// The user has never been a billing customer.
+    BillingCustomerNotFound,
+    /// No change of the given kind is scheduled that can be canceled.
+    NoScheduledChange,
+    /// Subscriptions can't be changed to a trial.
+    CannotChangeToTrial,
+    /// Only Zed Pro subscriptions can change plans.
+    PlanChangeNotAllowed,
+    /// The subscription is already on the plan that it's being changed to.
+    AlreadyOnPlan,
+    /// Only Zed Pro subscriptions can be refunded.
+    NotRefundable,
+    /// Only Zed Pro subscriptions can be paused.
+    NotPausable,
+    /// The tax ID isn't valid for its kind, for the given reason.
+    InvalidTaxId(String),
+    /// Only canceled subscriptions can be reactivated.
+    SubscriptionNotCanceled,
+    /// The subscription can't be reactivated, for the given reason, so the user has to check out again.
+    ReactivationUnavailable(String),
+    /// The user isn't eligible for a win-back offer.
+    WinbackNotEligible,
+    /// The user is already a member of the team.
+    AlreadyOnTeam,
+    /// Every seat of the subscription is taken.
+    NoSeatsLeft,
+    /// The subscription doesn't have a current billing period.
+    NoCurrentBillingPeriod,
+    /// Billing isn't set up on this server.
+    NotSupported,
+    /// Usage isn't available, as the LLM database isn't set up on this server.
+    LlmDatabaseNotAvailable,
+}
+
+impl BillingError {
+    /// Returns the code that identifies the error, which must not change once clients rely on it.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Self::BillingMaintenance => BILLING_MAINTENANCE_ERROR_CODE,
+            Self::AlreadySubscribed => "AlreadySubscribed",
+            Self::OverdueInvoices => "OverdueInvoices",
+            Self::TrialAlreadyUsed => "TrialAlreadyUsed",
+            Self::MissingPaymentMethod => "MissingPaymentMethod",
+            Self::FreeNotCancelable => "FreeNotCancelable",
+            Self::InvalidPromotionCode(_) => "InvalidPromotionCode",
+            Self::SubscriptionNotPaused => "SubscriptionNotPaused",
+            Self::MinimumTerm(_) => "MinimumTerm",
+            Self::PauseLimitReached(_) => "PauseLimitReached",
+            Self::InvalidSeatCount(_) => "InvalidSeatCount",
+            Self::InvalidRedirect(_) => "InvalidRedirect",
+            Self::InvalidPauseUntil(_) => "InvalidPauseUntil",
+            Self::SubscriptionNotFound => "SubscriptionNotFound",
+            Self::BillingCustomerNotFound => "BillingCustomerNotFound",
+            Self::NoScheduledChange => "NoScheduledChange",
+            Self::CannotChangeToTrial => "CannotChangeToTrial",
+            Self::PlanChangeNotAllowed => "PlanChangeNotAllowed",
+            Self::AlreadyOnPlan => "AlreadyOnPlan",
+            Self::NotRefundable => "NotRefundable",
+            Self::NotPausable => "NotPausable",
+            Self::InvalidTaxId(_) => "InvalidTaxId",
+            Self::SubscriptionNotCanceled => "SubscriptionNotCanceled",
+            Self::ReactivationUnavailable(_) => "ReactivationUnavailable",
+            Self::WinbackNotEligible => "WinbackNotEligible",
+            Self::AlreadyOnTeam => "AlreadyOnTeam",
+            Self::NoSeatsLeft => "NoSeatsLeft",
+            Self::NoCurrentBillingPeriod => "NoCurrentBillingPeriod",
+            Self::NotSupported => "NotSupported",
+            Self::LlmDatabaseNotAvailable => "LlmDatabaseNotAvailable",
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Self::BillingMaintenance => StatusCode::SERVICE_UNAVAILABLE,
+            Self::NotSupported | Self::LlmDatabaseNotAvailable => StatusCode::NOT_IMPLEMENTED,
+            Self::AlreadySubscribed
+            | Self::SubscriptionNotPaused
+            | Self::PlanChangeNotAllowed
+            | Self::AlreadyOnPlan
+            | Self::SubscriptionNotCanceled
+            | Self::ReactivationUnavailable(_)
+            | Self::AlreadyOnTeam
+            | Self::NoSeatsLeft
+            | Self::NoCurrentBillingPeriod => StatusCode::CONFLICT,
+            Self::OverdueInvoices => StatusCode::PAYMENT_REQUIRED,
+            Self::TrialAlreadyUsed | Self::PauseLimitReached(_) => StatusCode::FORBIDDEN,
+            Self::SubscriptionNotFound | Self::BillingCustomerNotFound => StatusCode::NOT_FOUND,
+            Self::MissingPaymentMethod
+            | Self::FreeNotCancelable
+            | Self::InvalidPromotionCode(_)
+            | Self::MinimumTerm(_)
+            | Self::InvalidSeatCount(_)
+            | Self::InvalidRedirect(_)
+            | Self::InvalidPauseUntil(_)
+            | Self::NoScheduledChange
+            | Self::CannotChangeToTrial
+            | Self::NotRefundable
+            | Self::NotPausable
+            | Self::InvalidTaxId(_)
+            | Self::WinbackNotEligible => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    pub(crate) fn message(&self) -> String {
+        match self {
+            Self::BillingMaintenance => "billing is read-only during maintenance".into(),
+            Self::AlreadySubscribed => "user already has an active subscription".into(),
+            Self::OverdueInvoices => "user has overdue invoices".into(),
+            Self::TrialAlreadyUsed => "user already used free trial".into(),
+            Self::MissingPaymentMethod => "missing payment method".into(),
+            Self::FreeNotCancelable => "free subscription cannot be canceled".into(),
+            Self::InvalidPromotionCode(reason) => reason.clone(),
+            Self::SubscriptionNotPaused => "subscription is not paused".into(),
+            Self::MinimumTerm(ends_at) => format!(
+                "subscription cannot be canceled before {}",
+                ends_at.to_rfc3339_opts(SecondsFormat::Millis, true)
+            ),
+            Self::PauseLimitReached(limit) => {
+                format!("subscription can only be paused {limit} times a year")
+            }
+            Self::InvalidSeatCount(reason)
+            | Self::InvalidPauseUntil(reason)
+            | Self::InvalidTaxId(reason) => reason.clone(),
+            Self::InvalidRedirect(redirect_to) => format!("invalid redirect_to: {redirect_to:?}"),
+            Self::SubscriptionNotFound => "subscription not found".into(),
+            Self::BillingCustomerNotFound => "billing customer not found".into(),
+            Self::NoScheduledChange => "no cancelable change of this kind is scheduled".into(),
+            Self::CannotChangeToTrial => "can't change a subscription to a trial".into(),
+            Self::PlanChangeNotAllowed => "only Zed Pro subscriptions can change plans".into(),
+            Self::AlreadyOnPlan => "subscription is already on that plan".into(),
+            Self::NotRefundable => "only Zed Pro subscriptions can be refunded".into(),
+            Self::NotPausable => "only Zed Pro subscriptions can be paused".into(),
+            Self::SubscriptionNotCanceled => {
+                "only canceled subscriptions can be reactivated".into()
+            }
+            Self::ReactivationUnavailable(reason) => {
+                format!("{reason}, start a new checkout instead")
+            }
+            Self::WinbackNotEligible => "user is not eligible for a win-back offer".into(),
+            Self::AlreadyOnTeam => "user is already on the team".into(),
+            Self::NoSeatsLeft => "subscription has no seats left".into(),
+            Self::NoCurrentBillingPeriod => "subscription has no current billing period".into(),
+            Self::NotSupported => "not supported".into(),
+            Self::LlmDatabaseNotAvailable => "LLM database not available".into(),
+        }
+    }
+}
+
+impl From<BillingError> for Error {
+    fn from(error: BillingError) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(ERROR_CODE_HEADER, HeaderValue::from_static(error.code()));
+
+        Error::Http(
+            error.status(),
+            format!("{}: {}", error.code(), error.message()),
+            headers,
+        )
+    }
+}
+
+/// The header that carries the ID of a billing request, both on the request and on the response.
+pub(crate) const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// The longest request ID that we accept from clients.
+pub(crate) const MAX_REQUEST_ID_LEN: usize = 128;
+
+tokio::task_local! {
+    pub(crate) static BILLING_REQUEST_ID: BillingRequestId;
+}
+
+/// The ID that correlates everything we log and record while handling a billing request.
+#[derive(Debug, PartialEq, Eq, Clone, derive_more::Display)]
+pub(crate) struct BillingRequestId(pub(crate) Arc<str>);
+
+impl BillingRequestId {
+    /// Returns the ID that the client sent, so that both sides log the same ID, or generates a new one.
+    fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|request_id| request_id.to_str().ok())
+            .filter(|request_id| !request_id.is_empty() && request_id.len() <= MAX_REQUEST_ID_LEN)
+            .map(|request_id| Self(request_id.into()))
+            .unwrap_or_else(|| Self(Uuid::new_v4().to_string().into()))
+    }
+
+    /// Returns the ID of the billing request that is being handled, if any.
+    pub(crate) fn current() -> Option<Self> {
+        BILLING_REQUEST_ID
+            .try_with(|request_id| request_id.clone())
+            .ok()
+    }
 }
 
+/// Assigns an ID to the billing request, which is attached to its logs and Snowflake rows, and returned in the
+/// response so that support can find them.
+async fn assign_billing_request_id<B>(request: Request<B>, next: Next<B>) -> Response {
+    let request_id = BillingRequestId::from_headers(request.headers());
+    let span = tracing::info_span!("billing_request", request_id = %request_id);
+
+    let mut response = BILLING_REQUEST_ID
+        .scope(request_id.clone(), next.run(request).instrument(span))
+        .await;
+    if let Ok(header_value) = HeaderValue::from_str(&request_id.0) {
+        response
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER, header_value);
+    }
+    response
+}
+
+/// Tags Snowflake rows with the ID of the billing request they were recorded for.
+pub(crate) trait BillingSnowflakeRowExt {
+    fn with_billing_request_id(self) -> Self;
+}
+
+impl BillingSnowflakeRowExt for SnowflakeRow {
+    fn with_billing_request_id(mut self) -> Self {
+        if let (Some(request_id), Some(event_properties)) = (
+            BillingRequestId::current(),
+            self.event_properties.as_object_mut(),
+        ) {
+            event_properties.insert("request_id".into(), request_id.to_string().into());
+        }
+        self
+    }
+}
+
+/// Returns an error if billing is in read-only maintenance mode.
+///
+/// This must be checked at the top of every handler that changes billing state.
+fn ensure_billing_writable(config: &Config) -> Result<()> {
+    if !config.billing_read_only() {
+        return Ok(());
+    }
+
+    Err(BillingError::BillingMaintenance.into())
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BillingErrorJson {
+    /// The code that identifies why the request was rejected, if it has one.
+    pub(crate) code: Option<String>,
+    pub(crate) message: String,
+    /// The ID of the request, which identifies it in our logs.
+    pub(crate) request_id: Option<String>,
+}
+
+/// Rewrites error responses as JSON, so that v2 clients can read the error code without inspecting the headers.
+async fn json_error_response<B>(request: Request<B>, next: Next<B>) -> Response {
+    let response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let message = hyper::body::to_bytes(body)
+        .await
+        .map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+        .unwrap_or_default();
+    let error_code = parts.headers.get(ERROR_CODE_HEADER).cloned();
+    let code = error_code
+        .as_ref()
+        .and_then(|code| code.to_str().ok())
+        .map(|code| code.to_string());
+    let message = match &code {
+        Some(code) => message
+            .strip_prefix(&format!("{code}: "))
+            .map(|message| message.to_string())
+            .unwrap_or(message),
+        None => message,
+    };
+
+    let request_id = BillingRequestId::current().map(|request_id| request_id.to_string());
+    let mut response = (
+        status,
+        Json(BillingErrorJson {
+            code,
+            message,
+            request_id,
+        }),
+    )
+        .into_response();
+    if let Some(error_code) = error_code {
+        response.headers_mut().insert(ERROR_CODE_HEADER, error_code);
+    }
+    response
+}
+
+/// The currency of customers that we haven't yet synced a subscription for.
+pub(crate) const DEFAULT_BILLING_CURRENCY: &str = "usd";
+
 #[derive(Debug, Serialize)]
 struct BillingPreferencesResponse {
     trial_started_at: Option<String>,
+    /// The currency that the amounts are in, as a lowercase ISO code (e.g., `usd`).
+    currency: String,
     max_monthly_llm_usage_spending_in_cents: i32,
     model_request_overages_enabled: bool,
     model_request_overages_spend_limit_in_cents: i32,
 }
 
-#[derive(Debug, Deserialize)]
-struct UpdateBillingPreferencesBody {
-    github_user_id: i32,
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub(crate) struct BillingPreferencesValues {
     #[serde(default)]
-    max_monthly_llm_usage_spending_in_cents: i32,
+    pub(crate) max_monthly_llm_usage_spending_in_cents: i32,
     #[serde(default)]
-    model_request_overages_enabled: bool,
+    pub(crate) model_request_overages_enabled: bool,
     #[serde(default)]
-    model_request_overages_spend_limit_in_cents: i32,
+    pub(crate) model_request_overages_spend_limit_in_cents: i32,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateBillingPreferencesBody {
+    github_user_id: i32,
+    #[serde(flatten)]
+    preferences: BillingPreferencesValues,
 }
 
 async fn update_billing_preferences(
@@ -88,6 +561,8 @@ async fn update_billing_preferences(
     Extension(rpc_server): Extension<Arc<crate::rpc::Server>>,
     extract::Json(body): extract::Json<UpdateBillingPreferencesBody>,
 ) -> Result<Json<BillingPreferencesResponse>> {
+    ensure_billing_writable(&app.config)?;
+
     let user = app
         .db
         .get_user_by_github_user_id(body.github_user_id)
@@ -96,14 +571,51 @@ async fn update_billing_preferences(
 
     let billing_customer = app.db.get_billing_customer_by_user_id(user.id).await?;
 
+    let (billing_preferences, _created) =
+        set_billing_preferences_for_user(&app, &user, body.preferences, false).await?;
+
+    rpc_server.refresh_llm_tokens_for_user(user.id).await;
+
+    Ok(Json(BillingPreferencesResponse {
+        trial_started_at: billing_customer
+            .as_ref()
+            .and_then(|billing_customer| billing_customer.trial_started_at)
+            .map(|trial_started_at| {
+                trial_started_at
+                    .and_utc()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+            }),
+        currency: billing_customer.map_or_else(
+            || DEFAULT_BILLING_CURRENCY.to_string(),
+            |billing_customer| billing_customer.currency,
+        ),
+        max_monthly_llm_usage_spending_in_cents: billing_preferences
+            .max_monthly_llm_usage_spending_in_cents,
+        model_request_overages_enabled: billing_preferences.model_request_overages_enabled,
+        model_request_overages_spend_limit_in_cents: billing_preferences
+            .model_request_overages_spend_limit_in_cents,
+    }))
+}
+
+/// Creates or updates the user's billing preferences, recording the change in Snowflake.
+///
+/// Returns the preferences and whether they were created.
+async fn set_billing_preferences_for_user(
+    app: &AppState,
+    user: &User,
+    preferences: BillingPreferencesValues,
+    bulk: bool,
+) -> Result<(billing_preference::Model, bool)> {
     let max_monthly_llm_usage_spending_in_cents =
-        body.max_monthly_llm_usage_spending_in_cents.max(0);
-    let model_request_overages_spend_limit_in_cents =
-        body.model_request_overages_spend_limit_in_cents.max(0);
+        preferences.max_monthly_llm_usage_spending_in_cents.max(0);
+    let model_request_overages_spend_limit_in_cents = preferences
+        .model_request_overages_spend_limit_in_cents
+        .max(0);
 
-    let billing_preferences =
+    let (billing_preferences, created) =
         if let Some(_billing_preferences) = app.db.get_billing_preferences(user.id).await? {
-            app.db
+            let billing_preferences = app
+                .db
                 .update_billing_preferences(
                     user.id,
                     &UpdateBillingPreferencesParams {
@@ -111,25 +623,28 @@ async fn update_billing_preferences(
                             max_monthly_llm_usage_spending_in_cents,
                         ),
                         model_request_overages_enabled: ActiveValue::set(
-                            body.model_request_overages_enabled,
+                            preferences.model_request_overages_enabled,
                         ),
                         model_request_overages_spend_limit_in_cents: ActiveValue::set(
                             model_request_overages_spend_limit_in_cents,
                         ),
                     },
                 )
-                .await?
+                .await?;
+            (billing_preferences, false)
         } else {
-            app.db
+            let billing_preferences = app
+                .db
                 .create_billing_preferences(
                     user.id,
                     &crate::db::CreateBillingPreferencesParams {
                         max_monthly_llm_usage_spending_in_cents,
-                        model_request_overages_enabled: body.model_request_overages_enabled,
+                        model_request_overages_enabled: preferences.model_request_overages_enabled,
                         model_request_overages_spend_limit_in_cents,
                     },
                 )
-                .await?
+                .await?;
+            (billing_preferences, true)
         };
 
     SnowflakeRow::new(
@@ -142,61 +657,225 @@ async fn update_billing_preferences(
             "model_request_overages_enabled": billing_preferences.model_request_overages_enabled,
             "model_request_overages_spend_limit_in_cents": billing_preferences.model_request_overages_spend_limit_in_cents,
             "max_monthly_llm_usage_spending_in_cents": billing_preferences.max_monthly_llm_usage_spending_in_cents,
+            "bulk": bulk,
         }),
     )
+    .with_billing_request_id()
     .write(&app.kinesis_client, &app.config.kinesis_stream)
     .await
     .log_err();
 
-    rpc_server.refresh_llm_tokens_for_user(user.id).await;
+    Ok((billing_preferences, created))
+}
 
-    Ok(Json(BillingPreferencesResponse {
-        trial_started_at: billing_customer
-            .and_then(|billing_customer| billing_customer.trial_started_at)
-            .map(|trial_started_at| {
-                trial_started_at
-                    .and_utc()
-                    .to_rfc3339_opts(SecondsFormat::Millis, true)
-            }),
-        max_monthly_llm_usage_spending_in_cents: billing_preferences
-            .max_monthly_llm_usage_spending_in_cents,
-        model_request_overages_enabled: billing_preferences.model_request_overages_enabled,
-        model_request_overages_spend_limit_in_cents: billing_preferences
-            .model_request_overages_spend_limit_in_cents,
-    }))
+#[derive(Debug, Deserialize)]
+pub(crate) struct BulkUpdateBillingPreferencesBody {
+    pub(crate) github_user_ids: Vec<i32>,
+    #[serde(flatten)]
+    pub(crate) preferences: BillingPreferencesValues,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(crate) struct BulkUpdateBillingPreferencesResponse {
+    /// The users whose billing preferences were created.
+    pub(crate) created: Vec<i32>,
+    /// The users whose existing billing preferences were updated.
+    pub(crate) updated: Vec<i32>,
+    pub(crate) failed: Vec<FailedBillingPreferencesUpdateJson>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct FailedBillingPreferencesUpdateJson {
+    pub(crate) github_user_id: i32,
+    pub(crate) error: String,
+}
+
+/// Sets the same billing preferences for each of the given users, e.g., when rolling out a new default spend limit.
+async fn bulk_update_billing_preferences(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<crate::rpc::Server>>,
+    extract::Json(body): extract::Json<BulkUpdateBillingPreferencesBody>,
+) -> Result<Json<BulkUpdateBillingPreferencesResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let (report, user_ids) = bulk_update_billing_preferences_inner(&app, &body).await;
+
+    log::info!(
+        "bulk updated billing preferences to {:?}: {} created, {} updated, {} failed",
+        body.preferences,
+        report.created.len(),
+        report.updated.len(),
+        report.failed.len()
+    );
+
+    for user_id in user_ids {
+        rpc_server.refresh_llm_tokens_for_user(user_id).await;
+    }
+
+    Ok(Json(report))
+}
+
+/// Returns the report, along with the IDs of the users whose preferences were set.
+pub(crate) async fn bulk_update_billing_preferences_inner(
+    app: &AppState,
+    body: &BulkUpdateBillingPreferencesBody,
+) -> (BulkUpdateBillingPreferencesResponse, Vec<UserId>) {
+    let mut report = BulkUpdateBillingPreferencesResponse::default();
+    let mut user_ids = Vec::new();
+    for &github_user_id in &body.github_user_ids {
+        let result = async {
+            let user = app
+                .db
+                .get_user_by_github_user_id(github_user_id)
+                .await?
+                .context("user not found")?;
+            let (_billing_preferences, created) =
+                set_billing_preferences_for_user(app, &user, body.preferences, true).await?;
+
+            anyhow::Ok((user.id, created))
+        }
+        .await;
+
+        match result {
+            Ok((user_id, created)) => {
+                user_ids.push(user_id);
+                if created {
+                    report.created.push(github_user_id);
+                } else {
+                    report.updated.push(github_user_id);
+                }
+            }
+            Err(error) => {
+                log::error!(
+                    "failed to update billing preferences for GitHub user {github_user_id}: {error:?}"
+                );
+                report.failed.push(FailedBillingPreferencesUpdateJson {
+                    github_user_id,
+                    error: error.to_string(),
+                });
+            }
+        }
+    }
+
+    (report, user_ids)
 }
 
 #[derive(Debug, Deserialize)]
-struct ListBillingSubscriptionsParams {
-    github_user_id: i32,
+pub(crate) struct ListBillingSubscriptionsParams {
+    pub(crate) github_user_id: i32,
+    /// The locale to display the plans in, overriding the `Accept-Language` header.
+    pub(crate) locale: Option<String>,
+    /// Only list subscriptions with this status.
+    pub(crate) status: Option<StripeSubscriptionStatus>,
+    /// Only list subscriptions that are active (or trialing).
+    #[serde(default)]
+    pub(crate) active_only: bool,
+    /// The maximum number of subscriptions to list. All of them are listed by default.
+    pub(crate) limit: Option<u64>,
+    /// The number of subscriptions to skip, for paginating through them.
+    pub(crate) offset: Option<u64>,
 }
 
 #[derive(Debug, Serialize)]
-struct BillingSubscriptionJson {
-    id: BillingSubscriptionId,
-    name: String,
-    status: StripeSubscriptionStatus,
-    period: Option<BillingSubscriptionPeriodJson>,
-    trial_end_at: Option<String>,
-    cancel_at: Option<String>,
+pub(crate) struct BillingSubscriptionJson {
+    pub(crate) id: BillingSubscriptionId,
+    /// The plan of the subscription, for clients to match on, as the name can change.
+    ///
+    /// This is `null` for usage-based subscriptions from before we had plans.
+    pub(crate) plan: Option<SubscriptionKind>,
+    /// The localized name of the plan.
+    pub(crate) name: String,
+    /// The localized description of the plan.
+    pub(crate) description: String,
+    pub(crate) status: StripeSubscriptionStatus,
+    pub(crate) period: Option<BillingSubscriptionPeriodJson>,
+    pub(crate) trial_end_at: Option<String>,
+    pub(crate) cancel_at: Option<String>,
     /// Whether this subscription can be canceled.
-    is_cancelable: bool,
+    ///
+    /// Derived from `cancelable_status`, and kept for older clients.
+    pub(crate) is_cancelable: bool,
+    /// Whether this subscription can be canceled, and if not, why.
+    pub(crate) cancelable_status: CancelableStatus,
+    /// Whether this subscription renews at the end of the current period.
+    pub(crate) auto_renews: bool,
+    /// The number of seats on the subscription.
+    pub(crate) seats: i32,
+    /// The base cost of the subscription per month, in cents, after any active discount.
+    ///
+    /// This excludes usage, and is only present for subscriptions that are still active.
+    pub(crate) effective_monthly_cost_cents: Option<i64>,
+    /// How often the subscription is billed (e.g., `month` or `year`).
+    ///
+    /// Like the cost, this is only present for subscriptions that are still active.
+    pub(crate) billing_interval: Option<String>,
+    /// How many more times the subscription can be paused in the current rolling year.
+    ///
+    /// This is only present when listing subscriptions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) pauses_remaining: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CancelableStatus {
+    /// The subscription can be canceled.
+    Cancelable,
+    /// The subscription is for Zed Free, which can't be canceled.
+    FreePlan,
+    /// The subscription is already scheduled to be canceled.
+    AlreadyCanceling,
+    /// The subscription's status doesn't allow it to be canceled (e.g., it has already been canceled).
+    IneligibleStatus,
+    /// The subscription hasn't run for the minimum term yet.
+    MinimumTerm,
+}
+
+impl CancelableStatus {
+    pub(crate) fn for_subscription(
+        subscription: &billing_subscription::Model,
+        minimum_term: Duration,
+        now: DateTime<Utc>,
+    ) -> Self {
+        if subscription.kind == Some(SubscriptionKind::ZedFree) {
+            Self::FreePlan
+        } else if !subscription.stripe_subscription_status.is_cancelable() {
+            Self::IneligibleStatus
+        } else if subscription.stripe_cancel_at.is_some() {
+            Self::AlreadyCanceling
+        } else if minimum_term_ends_at(subscription, minimum_term)
+            .is_some_and(|ends_at| now < ends_at)
+        {
+            Self::MinimumTerm
+        } else {
+            Self::Cancelable
+        }
+    }
+
+    pub(crate) fn is_cancelable(&self) -> bool {
+        *self == Self::Cancelable
+    }
 }
 
 #[derive(Debug, Serialize)]
-struct BillingSubscriptionPeriodJson {
-    start_at: String,
-    end_at: String,
+pub(crate) struct BillingSubscriptionPeriodJson {
+    pub(crate) start_at: String,
+    pub(crate) end_at: String,
 }
 
 #[derive(Debug, Serialize)]
-struct ListBillingSubscriptionsResponse {
-    subscriptions: Vec<BillingSubscriptionJson>,
+pub(crate) struct ListBillingSubscriptionsResponse {
+    pub(crate) subscriptions: Vec<BillingSubscriptionJson>,
+    /// The number of subscriptions that match the filters, across all pages.
+    pub(crate) total: u64,
+    /// Whether the card that the user pays with expires soon, so that we can ask them to update it.
+    pub(crate) payment_method_expiring_soon: bool,
 }
 
-async fn list_billing_subscriptions(
+pub(crate) async fn list_billing_subscriptions(
     Extension(app): Extension<Arc<AppState>>,
     Query(params): Query<ListBillingSubscriptionsParams>,
+    headers: HeaderMap,
 ) -> Result<Json<ListBillingSubscriptionsResponse>> {
     let user = app
         .db
@@ -204,75 +883,238 @@ async fn list_billing_subscriptions(
         .await?
         .context("user not found")?;
 
-    let subscriptions = app.db.get_billing_subscriptions(user.id).await?;
+    let locale = Locale::for_request(params.locale.as_deref(), &headers);
+    let (subscriptions, total) = app
+        .db
+        .get_billing_subscriptions_page(
+            user.id,
+            &GetBillingSubscriptionsParams {
+                status: params.status,
+                active_only: params.active_only,
+                limit: params.limit,
+                offset: params.offset,
+            },
+        )
+        .await?;
 
-    Ok(Json(ListBillingSubscriptionsResponse {
-        subscriptions: subscriptions
-            .into_iter()
-            .map(|subscription| BillingSubscriptionJson {
-                id: subscription.id,
-                name: match subscription.kind {
-                    Some(SubscriptionKind::ZedPro) => "Zed Pro".to_string(),
-                    Some(SubscriptionKind::ZedProTrial) => "Zed Pro (Trial)".to_string(),
-                    Some(SubscriptionKind::ZedFree) => "Zed Free".to_string(),
-                    None => "Zed LLM Usage".to_string(),
-                },
-                status: subscription.stripe_subscription_status,
-                period: maybe!({
-                    let start_at = subscription.current_period_start_at()?;
-                    let end_at = subscription.current_period_end_at()?;
-
-                    Some(BillingSubscriptionPeriodJson {
-                        start_at: start_at.to_rfc3339_opts(SecondsFormat::Millis, true),
-                        end_at: end_at.to_rfc3339_opts(SecondsFormat::Millis, true),
-                    })
-                }),
-                trial_end_at: if subscription.kind == Some(SubscriptionKind::ZedProTrial) {
-                    maybe!({
-                        let end_at = subscription.stripe_current_period_end?;
-                        let end_at = DateTime::from_timestamp(end_at, 0)?;
+    // We don't store prices or discounts, so we have to ask Stripe for them. So that listing doesn't make a request
+    // to Stripe per subscription, we only do this for the user's active subscription, and leave the cost out if
+    // Stripe can't be reached. Everything else is served from what we've synced.
+    let active_subscription = app.db.get_active_billing_subscription(user.id).await?;
+    let active_stripe_subscription = match (app.stripe_client.as_ref(), &active_subscription) {
+        (Some(stripe_client), Some(active_subscription))
+            if subscriptions
+                .iter()
+                .any(|subscription| subscription.id == active_subscription.id) =>
+        {
+            let stripe_subscription_id =
+                StripeSubscriptionId(active_subscription.stripe_subscription_id.clone().into());
 
-                        Some(end_at.to_rfc3339_opts(SecondsFormat::Millis, true))
-                    })
-                } else {
-                    None
-                },
-                cancel_at: subscription.stripe_cancel_at.map(|cancel_at| {
-                    cancel_at
-                        .and_utc()
-                        .to_rfc3339_opts(SecondsFormat::Millis, true)
-                }),
-                is_cancelable: subscription.kind != Some(SubscriptionKind::ZedFree)
-                    && subscription.stripe_subscription_status.is_cancelable()
-                    && subscription.stripe_cancel_at.is_none(),
-            })
-            .collect(),
+            stripe_client
+                .get_subscription(&stripe_subscription_id)
+                .await
+                .log_err()
+        }
+        _ => None,
+    };
+
+    let subscription_ids = subscriptions
+        .iter()
+        .map(|subscription| subscription.id)
+        .collect::<Vec<_>>();
+    let pauses_remaining =
+        subscription_pauses_remaining(&app, &subscription_ids, Utc::now()).await?;
+
+    let mut subscription_jsons = Vec::with_capacity(subscriptions.len());
+    for subscription in subscriptions {
+        let subscription_pauses_remaining = pauses_remaining.get(&subscription.id).copied();
+        let stripe_subscription =
+            active_stripe_subscription
+                .as_ref()
+                .filter(|stripe_subscription| {
+                    *stripe_subscription.id.0 == *subscription.stripe_subscription_id
+                });
+
+        let mut subscription_json =
+            BillingSubscriptionJson::new(subscription, locale, app.config.minimum_term());
+        subscription_json.pauses_remaining = subscription_pauses_remaining;
+        if let Some(stripe_subscription) = stripe_subscription {
+            subscription_json.effective_monthly_cost_cents =
+                effective_monthly_cost_in_cents(stripe_subscription, Utc::now());
+            subscription_json.billing_interval = plan_price(stripe_subscription)
+                .and_then(|price| price.recurring.as_ref())
+                .map(|recurring| recurring.interval.as_str().to_string());
+        }
+        subscription_jsons.push(subscription_json);
+    }
+
+    // Only users who pay for their subscription need to keep their card up to date.
+    let is_paying = active_subscription
+        .as_ref()
+        .is_some_and(|subscription| subscription.kind != Some(SubscriptionKind::ZedFree));
+    let payment_method_expiring_soon = match (
+        app.stripe_client.as_ref(),
+        app.db.get_billing_customer_by_user_id(user.id).await?,
+    ) {
+        (Some(stripe_client), Some(billing_customer)) if is_paying => {
+            let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.into());
+
+            stripe_client
+                .get_default_payment_method(&stripe_customer_id)
+                .await
+                .log_err()
+                .flatten()
+                .and_then(|payment_method| payment_method.card)
+                .is_some_and(|card| is_card_expiring_soon(&card, Utc::now()))
+        }
+        _ => false,
+    };
+
+    Ok(Json(ListBillingSubscriptionsResponse {
+        subscriptions: subscription_jsons,
+        total,
+        payment_method_expiring_soon,
     }))
 }
 
-#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
-#[serde(rename_all = "snake_case")]
-enum ProductCode {
-    ZedPro,
-    ZedProTrial,
-}
+/// The number of days before a card expires that we start asking the user to update it.
+const CARD_EXPIRY_WARNING_DAYS: i64 = 60;
 
-#[derive(Debug, Deserialize)]
-struct CreateBillingSubscriptionBody {
-    github_user_id: i32,
-    product: ProductCode,
+/// Returns when the card expires.
+///
+/// Cards are valid through the end of their expiry month.
+pub(crate) fn card_expires_at(card: &StripeCard) -> Option<DateTime<Utc>> {
+    let (year, month) = if card.exp_month >= 12 {
+        (card.exp_year + 1, 1)
+    } else {
+        (card.exp_year, card.exp_month + 1)
+    };
+
+    Some(
+        chrono::NaiveDate::from_ymd_opt(year, month, 1)?
+            .and_hms_opt(0, 0, 0)?
+            .and_utc(),
+    )
 }
 
-#[derive(Debug, Serialize)]
-struct CreateBillingSubscriptionResponse {
-    checkout_session_url: String,
+/// Returns whether the card expires within the warning period (or has already expired).
+pub(crate) fn is_card_expiring_soon(card: &StripeCard, now: DateTime<Utc>) -> bool {
+    card_expires_at(card).is_some_and(|expires_at| {
+        expires_at - now <= chrono::Duration::days(CARD_EXPIRY_WARNING_DAYS)
+    })
 }
 
-/// Initiates a Stripe Checkout session for creating a billing subscription.
-async fn create_billing_subscription(
+/// Returns when the subscription's minimum term ends, if it has one.
+///
+/// Only Zed Pro has a minimum term, which starts when the subscription is created.
+fn minimum_term_ends_at(
+    subscription: &billing_subscription::Model,
+    minimum_term: Duration,
+) -> Option<DateTime<Utc>> {
+    if subscription.kind != Some(SubscriptionKind::ZedPro) || minimum_term.is_zero() {
+        return None;
+    }
+
+    Some(subscription.created_at.and_utc() + chrono::Duration::from_std(minimum_term).ok()?)
+}
+
+/// Returns an error if the subscription can't be canceled yet, because its minimum term hasn't ended.
+pub(crate) fn ensure_minimum_term_ended(
+    subscription: &billing_subscription::Model,
+    minimum_term: Duration,
+    now: DateTime<Utc>,
+) -> Result<(), BillingError> {
+    match minimum_term_ends_at(subscription, minimum_term) {
+        Some(ends_at) if now < ends_at => Err(BillingError::MinimumTerm(ends_at)),
+        _ => Ok(()),
+    }
+}
+
+impl BillingSubscriptionJson {
+    pub(crate) fn new(
+        subscription: billing_subscription::Model,
+        locale: Locale,
+        minimum_term: Duration,
+    ) -> Self {
+        let cancelable_status =
+            CancelableStatus::for_subscription(&subscription, minimum_term, Utc::now());
+        let text = plan_text(subscription.kind, locale);
+
+        Self {
+            id: subscription.id,
+            plan: subscription.kind,
+            name: text.name.to_string(),
+            description: text.description.to_string(),
+            status: subscription.stripe_subscription_status,
+            period: maybe!({
+                let start_at = subscription.current_period_start_at()?;
+                let end_at = subscription.current_period_end_at()?;
+
+                Some(BillingSubscriptionPeriodJson {
+                    start_at: start_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    end_at: end_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                })
+            }),
+            trial_end_at: subscription
+                .trial_end_at()
+                .map(|end_at| end_at.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            cancel_at: subscription.stripe_cancel_at.map(|cancel_at| {
+                cancel_at
+                    .and_utc()
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+            }),
+            is_cancelable: cancelable_status.is_cancelable(),
+            cancelable_status,
+            auto_renews: subscription.stripe_subscription_status.is_cancelable()
+                && !subscription.stripe_cancel_at_period_end
+                && subscription.stripe_cancel_at.is_none(),
+            seats: subscription.seats,
+            effective_monthly_cost_cents: None,
+            billing_interval: None,
+            pauses_remaining: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ProductCode {
+    ZedPro,
+    /// Zed Pro, billed yearly.
+    ZedProAnnual,
+    ZedProTrial,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateBillingSubscriptionBody {
+    pub(crate) github_user_id: i32,
+    pub(crate) product: ProductCode,
+    /// The promotion code to apply to the checkout, as entered by the user.
+    ///
+    /// Only supported for Zed Pro.
+    pub(crate) promotion_code: Option<String>,
+    /// The number of seats to subscribe to, which defaults to one.
+    ///
+    /// Only supported for Zed Pro.
+    pub(crate) seats: Option<u32>,
+    /// The key under which Stripe dedupes retries of this request.
+    pub(crate) idempotency_key: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CreateBillingSubscriptionResponse {
+    pub(crate) checkout_session_url: String,
+    /// Whether the checkout collects a card, when starting a trial.
+    pub(crate) trial_payment_method_collection: Option<TrialPaymentMethodCollection>,
+}
+
+/// Initiates a Stripe Checkout session for creating a billing subscription.
+pub(crate) async fn create_billing_subscription(
     Extension(app): Extension<Arc<AppState>>,
     extract::Json(body): extract::Json<CreateBillingSubscriptionBody>,
 ) -> Result<Json<CreateBillingSubscriptionResponse>> {
+    ensure_billing_writable(&app.config)?;
+
     let user = app
         .db
         .get_user_by_github_user_id(body.github_user_id)
@@ -281,31 +1123,179 @@ async fn create_billing_subscription(
 
     let Some(stripe_billing) = app.stripe_billing.clone() else {
         log::error!("failed to retrieve Stripe billing object");
-        Err(Error::http(
-            StatusCode::NOT_IMPLEMENTED,
-            "not supported".into(),
-        ))?
+        Err(BillingError::NotSupported)?
+    };
+
+    // We validate the promotion code up front so that bad codes are rejected before we create anything in Stripe.
+    let promotion_code = match body.promotion_code.as_deref() {
+        Some(_)
+            if !matches!(
+                body.product,
+                ProductCode::ZedPro | ProductCode::ZedProAnnual
+            ) =>
+        {
+            return Err(BillingError::InvalidPromotionCode(
+                "promotion codes can only be applied to Zed Pro".into(),
+            )
+            .into());
+        }
+        Some(code) => Some(
+            stripe_billing
+                .find_redeemable_promotion_code(code, Utc::now())
+                .await?
+                .ok_or_else(|| {
+                    BillingError::InvalidPromotionCode(format!(
+                        "promotion code {code:?} is invalid or has expired"
+                    ))
+                })?,
+        ),
+        None => None,
+    };
+
+    let seats = body.seats.unwrap_or(1);
+    if seats == 0 {
+        return Err(BillingError::InvalidSeatCount(
+            "a subscription needs at least one seat".into(),
+        )
+        .into());
+    }
+    if seats > 1 && body.product == ProductCode::ZedProTrial {
+        return Err(BillingError::InvalidSeatCount("trials can only have one seat".into()).into());
+    }
+
+    let (existing_billing_customer, customer_id) =
+        customer_for_new_subscription(&app, &stripe_billing, &user, body.product).await?;
+
+    let success_url = format!(
+        "{}/account?checkout_complete=1",
+        app.config.zed_dot_dev_url()
+    );
+
+    let (checkout_session_url, trial_payment_method_collection) = match body.product {
+        ProductCode::ZedPro | ProductCode::ZedProAnnual => {
+            let billing_interval = if body.product == ProductCode::ZedProAnnual {
+                ZedProBillingInterval::Annual
+            } else {
+                ZedProBillingInterval::Monthly
+            };
+            let checkout_session_url = stripe_billing
+                .checkout_with_zed_pro(
+                    &customer_id,
+                    &user.github_login,
+                    billing_interval,
+                    seats,
+                    &success_url,
+                    promotion_code.as_ref(),
+                    body.idempotency_key.as_deref(),
+                )
+                .await
+                .map_err(|error| match error {
+                    // Stripe has the final say on whether the promotion code applies, e.g., when the coupon is
+                    // restricted to certain customers.
+                    Error::Internal(error)
+                        if promotion_code.is_some() && is_stripe_invalid_request_error(&error) =>
+                    {
+                        BillingError::InvalidPromotionCode(format!(
+                            "promotion code can't be applied: {error}"
+                        ))
+                        .into()
+                    }
+                    error => error,
+                })?;
+            (checkout_session_url, None)
+        }
+        ProductCode::ZedProTrial => {
+            let (checkout_session_url, trial_payment_method_collection) =
+                checkout_with_zed_pro_trial(
+                    &app,
+                    &stripe_billing,
+                    existing_billing_customer.as_ref(),
+                    &customer_id,
+                    &user,
+                    &success_url,
+                    body.idempotency_key.as_deref(),
+                )
+                .await?;
+            (checkout_session_url, Some(trial_payment_method_collection))
+        }
+    };
+
+    Ok(Json(CreateBillingSubscriptionResponse {
+        checkout_session_url,
+        trial_payment_method_collection,
+    }))
+}
+
+/// Starts a Checkout session for a Zed Pro trial, collecting a card up front if the config calls for it.
+///
+/// Returns the URL of the Checkout session, along with whether it collects a card.
+pub(crate) async fn checkout_with_zed_pro_trial(
+    app: &AppState,
+    stripe_billing: &StripeBilling,
+    existing_billing_customer: Option<&billing_customer::Model>,
+    customer_id: &StripeCustomerId,
+    user: &User,
+    success_url: &str,
+    idempotency_key: Option<&str>,
+) -> Result<(String, TrialPaymentMethodCollection)> {
+    if let Some(existing_billing_customer) = existing_billing_customer {
+        if existing_billing_customer.trial_started_at.is_some() {
+            return Err(BillingError::TrialAlreadyUsed.into());
+        }
+    }
+
+    let feature_flags = app.db.get_user_flags(user.id).await?;
+
+    let trial_payment_method_collection = app
+        .config
+        .trial_payment_method_collection
+        .unwrap_or_default();
+    let payment_method_collection = match trial_payment_method_collection {
+        TrialPaymentMethodCollection::WithoutCard => {
+            StripeCheckoutSessionPaymentMethodCollection::IfRequired
+        }
+        TrialPaymentMethodCollection::WithCard => {
+            StripeCheckoutSessionPaymentMethodCollection::Always
+        }
     };
 
+    let checkout_session_url = stripe_billing
+        .checkout_with_zed_pro_trial(
+            customer_id,
+            &user.github_login,
+            feature_flags,
+            app.config.zed_pro_trial_days(),
+            app.config.zed_pro_extended_trial_days(),
+            payment_method_collection,
+            success_url,
+            idempotency_key,
+        )
+        .await?;
+
+    Ok((checkout_session_url, trial_payment_method_collection))
+}
+
+/// Checks that the user can subscribe to the product, and returns their billing customer (if they have one) along with
+/// the Stripe customer to subscribe.
+async fn customer_for_new_subscription(
+    app: &AppState,
+    stripe_billing: &StripeBilling,
+    user: &User,
+    product: ProductCode,
+) -> Result<(Option<billing_customer::Model>, StripeCustomerId)> {
     if let Some(existing_subscription) = app.db.get_active_billing_subscription(user.id).await? {
-        let is_checkout_allowed = body.product == ProductCode::ZedProTrial
+        let is_checkout_allowed = product == ProductCode::ZedProTrial
             && existing_subscription.kind == Some(SubscriptionKind::ZedFree);
 
         if !is_checkout_allowed {
-            return Err(Error::http(
-                StatusCode::CONFLICT,
-                "user already has an active subscription".into(),
-            ));
+            return Err(BillingError::AlreadySubscribed.into());
         }
     }
 
     let existing_billing_customer = app.db.get_billing_customer_by_user_id(user.id).await?;
     if let Some(existing_billing_customer) = &existing_billing_customer {
         if existing_billing_customer.has_overdue_invoices {
-            return Err(Error::http(
-                StatusCode::PAYMENT_REQUIRED,
-                "user has overdue invoices".into(),
-            ));
+            return Err(BillingError::OverdueInvoices.into());
         }
     }
 
@@ -314,7 +1304,15 @@ async fn create_billing_subscription(
         if let Some(email) = user.email_address.as_deref() {
             stripe_billing
                 .client()
-                .update_customer(&customer_id, UpdateCustomerParams { email: Some(email) })
+                .update_customer(
+                    &customer_id,
+                    UpdateCustomerParams {
+                        email: Some(email),
+                        tax_exempt: None,
+                        tax_id: None,
+                        idempotency_key: None,
+                    },
+                )
                 .await
                 // Update of email address is best-effort - continue checkout even if it fails
                 .context("error updating stripe customer email address")
@@ -323,47 +1321,66 @@ async fn create_billing_subscription(
         customer_id
     } else {
         stripe_billing
-            .find_or_create_customer_by_email(user.email_address.as_deref())
+            .find_or_create_customer_by_zed_user(user.id, user.email_address.as_deref())
             .await?
     };
 
-    let success_url = format!(
-        "{}/account?checkout_complete=1",
-        app.config.zed_dot_dev_url()
-    );
+    Ok((existing_billing_customer, customer_id))
+}
 
-    let checkout_session_url = match body.product {
-        ProductCode::ZedPro => {
-            stripe_billing
-                .checkout_with_zed_pro(&customer_id, &user.github_login, &success_url)
-                .await?
-        }
-        ProductCode::ZedProTrial => {
-            if let Some(existing_billing_customer) = &existing_billing_customer {
-                if existing_billing_customer.trial_started_at.is_some() {
-                    return Err(Error::http(
-                        StatusCode::FORBIDDEN,
-                        "user already used free trial".into(),
-                    ));
-                }
-            }
+#[derive(Debug, Deserialize)]
+struct CreateBillingSubscriptionIntentBody {
+    github_user_id: i32,
+}
 
-            let feature_flags = app.db.get_user_flags(user.id).await?;
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct CreateBillingSubscriptionIntentResponse {
+    pub(crate) stripe_subscription_id: String,
+    /// The client secret of the payment intent to confirm in the client.
+    pub(crate) client_secret: String,
+}
 
-            stripe_billing
-                .checkout_with_zed_pro_trial(
-                    &customer_id,
-                    &user.github_login,
-                    feature_flags,
-                    &success_url,
-                )
-                .await?
-        }
+/// Creates a Zed Pro subscription whose payment is collected in the client, as an alternative to Stripe Checkout.
+///
+/// The subscription is incomplete until the payment is confirmed, and is activated by the resulting Stripe events.
+async fn create_billing_subscription_intent(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<CreateBillingSubscriptionIntentBody>,
+) -> Result<Json<CreateBillingSubscriptionIntentResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let Some(stripe_billing) = app.stripe_billing.clone() else {
+        log::error!("failed to retrieve Stripe billing object");
+        Err(BillingError::NotSupported)?
     };
 
-    Ok(Json(CreateBillingSubscriptionResponse {
-        checkout_session_url,
-    }))
+    Ok(Json(
+        create_billing_subscription_intent_for_user(&app, &stripe_billing, &user).await?,
+    ))
+}
+
+pub(crate) async fn create_billing_subscription_intent_for_user(
+    app: &AppState,
+    stripe_billing: &StripeBilling,
+    user: &User,
+) -> Result<CreateBillingSubscriptionIntentResponse> {
+    let (_existing_billing_customer, customer_id) =
+        customer_for_new_subscription(app, stripe_billing, user, ProductCode::ZedPro).await?;
+
+    let incomplete_subscription = stripe_billing
+        .subscribe_to_zed_pro_with_payment_intent(customer_id, None)
+        .await?;
+
+    Ok(CreateBillingSubscriptionIntentResponse {
+        stripe_subscription_id: incomplete_subscription.subscription.id.to_string(),
+        client_secret: incomplete_subscription.client_secret,
+    })
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
@@ -381,6 +1398,12 @@ enum ManageSubscriptionIntent {
     Cancel,
     /// The user intends to stop the cancellation of their subscription.
     StopCancellation,
+    /// The user intends to resume their paused subscription.
+    Resume,
+    /// The user intends to pause their subscription until they resume it.
+    Pause,
+    /// The user intends to pause their subscription until `pause_until`.
+    PauseUntil,
 }
 
 #[derive(Debug, Deserialize)]
@@ -389,19 +1412,110 @@ struct ManageBillingSubscriptionBody {
     intent: ManageSubscriptionIntent,
     /// The ID of the subscription to manage.
     subscription_id: BillingSubscriptionId,
+    /// The zed.dev path to return to once the user is done, which must be one of [`ALLOWED_REDIRECT_PATHS`].
     redirect_to: Option<String>,
+    /// When to resume the subscription, for [`ManageSubscriptionIntent::PauseUntil`].
+    pause_until: Option<DateTime<Utc>>,
+    /// The key under which Stripe dedupes retries of this request.
+    idempotency_key: Option<String>,
 }
 
 #[derive(Debug, Serialize)]
-struct ManageBillingSubscriptionResponse {
-    billing_portal_session_url: Option<String>,
+pub(crate) struct ManageBillingSubscriptionResponse {
+    pub(crate) billing_portal_session_url: Option<String>,
+    /// The status of the subscription after it was resumed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) subscription_status: Option<StripeSubscriptionStatus>,
+}
+
+/// The result of managing a subscription.
+///
+/// v2 clients receive this as-is, while v1 clients receive it as a [`ManageBillingSubscriptionResponse`].
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub(crate) enum ManageBillingSubscriptionResult {
+    /// The user needs to finish managing their subscription in a Stripe billing portal session.
+    BillingPortalSession { url: String },
+    /// The subscription was updated without the need for a billing portal session.
+    SubscriptionUpdated,
+    /// The paused subscription was resumed.
+    SubscriptionResumed { status: StripeSubscriptionStatus },
+}
+
+impl From<ManageBillingSubscriptionResult> for ManageBillingSubscriptionResponse {
+    fn from(result: ManageBillingSubscriptionResult) -> Self {
+        match result {
+            ManageBillingSubscriptionResult::BillingPortalSession { url } => Self {
+                billing_portal_session_url: Some(url),
+                subscription_status: None,
+            },
+            ManageBillingSubscriptionResult::SubscriptionUpdated => Self {
+                billing_portal_session_url: None,
+                subscription_status: None,
+            },
+            ManageBillingSubscriptionResult::SubscriptionResumed { status } => Self {
+                billing_portal_session_url: None,
+                subscription_status: Some(status),
+            },
+        }
+    }
+}
+
+impl ManageBillingSubscriptionResult {
+    fn into_response(self, version: BillingApiVersion) -> Response {
+        match version {
+            BillingApiVersion::V1 => {
+                Json(ManageBillingSubscriptionResponse::from(self)).into_response()
+            }
+            BillingApiVersion::V2 => Json(self).into_response(),
+        }
+    }
+}
+
+/// The zed.dev paths that users can be sent back to after managing their subscription.
+const ALLOWED_REDIRECT_PATHS: &[&str] = &[
+    "/account",
+    "/account/billing",
+    "/account/invoices",
+    "/account/usage",
+];
+
+/// Returns the given `redirect_to` if it is a path on zed.dev that we allow redirecting to.
+///
+/// The path is appended to the zed.dev URL, so anything other than a path (e.g., `//example.com` or `@example.com`)
+/// could send the user to another site. A query string and fragment are allowed after the path.
+pub(crate) fn validate_redirect_to(redirect_to: &str) -> Result<&str> {
+    let is_relative_path = redirect_to.starts_with('/')
+        && !redirect_to.starts_with("//")
+        // Browsers treat backslashes in URLs like forward slashes.
+        && !redirect_to.contains('\\')
+        && !redirect_to
+            .chars()
+            .any(|char| char.is_whitespace() || char.is_control());
+    let path = redirect_to.split(['?', '#']).next().unwrap_or(redirect_to);
+
+    if is_relative_path && ALLOWED_REDIRECT_PATHS.contains(&path) {
+        Ok(redirect_to)
+    } else {
+        Err(BillingError::InvalidRedirect(redirect_to.to_string()).into())
+    }
 }
 
 /// Initiates a Stripe customer portal session for managing a billing subscription.
 async fn manage_billing_subscription(
     Extension(app): Extension<Arc<AppState>>,
+    Extension(version): Extension<BillingApiVersion>,
     extract::Json(body): extract::Json<ManageBillingSubscriptionBody>,
-) -> Result<Json<ManageBillingSubscriptionResponse>> {
+) -> Result<Response> {
+    ensure_billing_writable(&app.config)?;
+
+    let redirect_to = body
+        .redirect_to
+        .as_deref()
+        .map(validate_redirect_to)
+        .transpose()?
+        .unwrap_or("/account");
+
     let user = app
         .db
         .get_user_by_github_user_id(body.github_user_id)
@@ -410,18 +1524,12 @@ async fn manage_billing_subscription(
 
     let Some(stripe_client) = app.real_stripe_client.clone() else {
         log::error!("failed to retrieve Stripe client");
-        Err(Error::http(
-            StatusCode::NOT_IMPLEMENTED,
-            "not supported".into(),
-        ))?
+        Err(BillingError::NotSupported)?
     };
 
     let Some(stripe_billing) = app.stripe_billing.clone() else {
         log::error!("failed to retrieve Stripe billing object");
-        Err(Error::http(
-            StatusCode::NOT_IMPLEMENTED,
-            "not supported".into(),
-        ))?
+        Err(BillingError::NotSupported)?
     };
 
     let customer = app
@@ -440,6 +1548,58 @@ async fn manage_billing_subscription(
     let subscription_id = SubscriptionId::from_str(&subscription.stripe_subscription_id)
         .context("failed to parse subscription ID")?;
 
+    if body.intent == ManageSubscriptionIntent::Resume {
+        let Some(stripe_client) = app.stripe_client.clone() else {
+            log::error!("failed to retrieve Stripe client");
+            Err(BillingError::NotSupported)?
+        };
+
+        let subscription = resume_billing_subscription_for_user(
+            &app,
+            &stripe_client,
+            &user,
+            subscription.id,
+            body.idempotency_key.as_deref(),
+        )
+        .await?;
+
+        return Ok(ManageBillingSubscriptionResult::SubscriptionResumed {
+            status: subscription.stripe_subscription_status,
+        }
+        .into_response(version));
+    }
+
+    if matches!(
+        body.intent,
+        ManageSubscriptionIntent::Pause | ManageSubscriptionIntent::PauseUntil
+    ) {
+        let Some(stripe_client) = app.stripe_client.clone() else {
+            log::error!("failed to retrieve Stripe client");
+            Err(BillingError::NotSupported)?
+        };
+
+        let resumes_at = match body.intent {
+            ManageSubscriptionIntent::PauseUntil => Some(body.pause_until.ok_or_else(|| {
+                BillingError::InvalidPauseUntil(
+                    "pause_until is required to pause until a given time".into(),
+                )
+            })?),
+            _ => None,
+        };
+        pause_billing_subscription_for_user(
+            &app,
+            &stripe_client,
+            &user,
+            subscription.id,
+            resumes_at,
+            body.idempotency_key.as_deref(),
+            Utc::now(),
+        )
+        .await?;
+
+        return Ok(ManageBillingSubscriptionResult::SubscriptionUpdated.into_response(version));
+    }
+
     if body.intent == ManageSubscriptionIntent::StopCancellation {
         let updated_stripe_subscription = Subscription::update(
             &stripe_client,
@@ -461,14 +1621,15 @@ async fn manage_billing_subscription(
                             .and_then(|cancel_at| DateTime::from_timestamp(cancel_at, 0))
                             .map(|time| time.naive_utc()),
                     ),
+                    stripe_cancel_at_period_end: ActiveValue::set(
+                        updated_stripe_subscription.cancel_at_period_end,
+                    ),
                     ..Default::default()
                 },
             )
             .await?;
 
-        return Ok(Json(ManageBillingSubscriptionResponse {
-            billing_portal_session_url: None,
-        }));
+        return Ok(ManageBillingSubscriptionResult::SubscriptionUpdated.into_response(version));
     }
 
     let flow = match body.intent {
@@ -500,10 +1661,7 @@ async fn manage_billing_subscription(
 
                 let has_payment_method = !payment_methods.data.is_empty();
                 if !has_payment_method {
-                    return Err(Error::http(
-                        StatusCode::BAD_REQUEST,
-                        "missing payment method".into(),
-                    ));
+                    return Err(BillingError::MissingPaymentMethod.into());
                 }
 
                 // If the user is already on a Zed Pro trial and wants to upgrade to Pro, we just need to end their trial early.
@@ -517,9 +1675,9 @@ async fn manage_billing_subscription(
                 )
                 .await?;
 
-                return Ok(Json(ManageBillingSubscriptionResponse {
-                    billing_portal_session_url: None,
-                }));
+                return Ok(
+                    ManageBillingSubscriptionResult::SubscriptionUpdated.into_response(version)
+                );
             }
 
             let subscription_item_to_update = stripe_subscription
@@ -560,11 +1718,7 @@ async fn manage_billing_subscription(
             after_completion: Some(CreateBillingPortalSessionFlowDataAfterCompletion {
                 type_: stripe::CreateBillingPortalSessionFlowDataAfterCompletionType::Redirect,
                 redirect: Some(CreateBillingPortalSessionFlowDataAfterCompletionRedirect {
-                    return_url: format!(
-                        "{}{path}",
-                        app.config.zed_dot_dev_url(),
-                        path = body.redirect_to.unwrap_or_else(|| "/account".to_string())
-                    ),
+                    return_url: format!("{}{redirect_to}", app.config.zed_dot_dev_url()),
                 }),
                 ..Default::default()
             }),
@@ -572,11 +1726,9 @@ async fn manage_billing_subscription(
         }),
         ManageSubscriptionIntent::Cancel => {
             if subscription.kind == Some(SubscriptionKind::ZedFree) {
-                return Err(Error::http(
-                    StatusCode::BAD_REQUEST,
-                    "free subscription cannot be canceled".into(),
-                ));
+                return Err(BillingError::FreeNotCancelable.into());
             }
+            ensure_minimum_term_ended(&subscription, app.config.minimum_term(), Utc::now())?;
 
             Some(CreateBillingPortalSessionFlowData {
                 type_: CreateBillingPortalSessionFlowDataType::SubscriptionCancel,
@@ -596,7 +1748,10 @@ async fn manage_billing_subscription(
                 ..Default::default()
             })
         }
-        ManageSubscriptionIntent::StopCancellation => unreachable!(),
+        ManageSubscriptionIntent::StopCancellation
+        | ManageSubscriptionIntent::Resume
+        | ManageSubscriptionIntent::Pause
+        | ManageSubscriptionIntent::PauseUntil => unreachable!(),
     };
 
     let mut params = CreateBillingPortalSession::new(customer_id);
@@ -604,908 +1759,8647 @@ async fn manage_billing_subscription(
     let return_url = format!("{}/account", app.config.zed_dot_dev_url());
     params.return_url = Some(&return_url);
 
+    let stripe_client = match body.idempotency_key {
+        Some(idempotency_key) => (*stripe_client)
+            .clone()
+            .with_strategy(stripe::RequestStrategy::Idempotent(idempotency_key)),
+        None => (*stripe_client).clone(),
+    };
     let session = BillingPortalSession::create(&stripe_client, params).await?;
 
-    Ok(Json(ManageBillingSubscriptionResponse {
-        billing_portal_session_url: Some(session.url),
-    }))
-}
-
-#[derive(Debug, Deserialize)]
-struct SyncBillingSubscriptionBody {
-    github_user_id: i32,
+    Ok(
+        ManageBillingSubscriptionResult::BillingPortalSession { url: session.url }
+            .into_response(version),
+    )
 }
 
-#[derive(Debug, Serialize)]
-struct SyncBillingSubscriptionResponse {
-    stripe_customer_id: String,
-}
+/// Resumes a user's paused subscription in Stripe, and syncs it so that the user gets access again right away.
+pub(crate) async fn resume_billing_subscription_for_user(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    user: &User,
+    subscription_id: BillingSubscriptionId,
+    idempotency_key: Option<&str>,
+) -> Result<billing_subscription::Model> {
+    let subscription = get_billing_subscription_for_user(app, user, subscription_id).await?;
+
+    // Stripe has the final say on whether the subscription is paused, in case we haven't synced it yet.
+    let stripe_subscription_id = StripeSubscriptionId(subscription.stripe_subscription_id.into());
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+    if stripe_subscription.pause_collection.is_none() {
+        return Err(BillingError::SubscriptionNotPaused.into());
+    }
 
-async fn sync_billing_subscription(
-    Extension(app): Extension<Arc<AppState>>,
-    extract::Json(body): extract::Json<SyncBillingSubscriptionBody>,
-) -> Result<Json<SyncBillingSubscriptionResponse>> {
-    let Some(stripe_client) = app.stripe_client.clone() else {
-        log::error!("failed to retrieve Stripe client");
-        Err(Error::http(
-            StatusCode::NOT_IMPLEMENTED,
-            "not supported".into(),
-        ))?
-    };
+    stripe_client
+        .resume_subscription(&stripe_subscription_id, idempotency_key)
+        .await?;
 
-    let user = app
+    // The pause has ended, so it no longer has a resume scheduled.
+    if let Some(pause) = app
         .db
-        .get_user_by_github_user_id(body.github_user_id)
+        .get_latest_billing_subscription_pause(subscription.id)
         .await?
-        .context("user not found")?;
+    {
+        if pause.resumes_at.is_some() {
+            app.db
+                .set_billing_subscription_pause_resumes_at(pause.id, None)
+                .await?;
+        }
+    }
 
-    let billing_customer = app
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+    sync_subscription(app, stripe_client, stripe_subscription).await?;
+
+    log::info!(
+        "resumed subscription {stripe_subscription_id} for user {user_id}",
+        user_id = user.id,
+    );
+
+    Ok(app
         .db
-        .get_billing_customer_by_user_id(user.id)
+        .get_billing_subscription_by_stripe_subscription_id(&stripe_subscription_id.0)
         .await?
-        .context("billing customer not found")?;
-    let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+        .context("subscription not found")?)
+}
 
-    let subscriptions = stripe_client
-        .list_subscriptions_for_customer(&stripe_customer_id)
-        .await?;
+/// The window over which we limit how many times a subscription can be paused.
+const SUBSCRIPTION_PAUSE_LIMIT_WINDOW: chrono::Duration = chrono::Duration::days(365);
 
-    for subscription in subscriptions {
-        let subscription_id = subscription.id.clone();
+/// Returns how many more times each of the given subscriptions can be paused in the current rolling year.
+pub(crate) async fn subscription_pauses_remaining(
+    app: &AppState,
+    subscription_ids: &[BillingSubscriptionId],
+    now: DateTime<Utc>,
+) -> Result<HashMap<BillingSubscriptionId, u32>> {
+    let pauses = app
+        .db
+        .get_billing_subscription_pauses_since(
+            subscription_ids,
+            (now - SUBSCRIPTION_PAUSE_LIMIT_WINDOW).naive_utc(),
+        )
+        .await?;
 
-        sync_subscription(&app, &stripe_client, subscription)
-            .await
-            .with_context(|| {
-                format!(
-                    "failed to sync subscription {subscription_id} for user {}",
-                    user.id,
-                )
-            })?;
+    let mut pauses_remaining = subscription_ids
+        .iter()
+        .map(|subscription_id| {
+            (
+                *subscription_id,
+                app.config.max_subscription_pauses_per_year(),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    for pause in pauses {
+        if let Some(remaining) = pauses_remaining.get_mut(&pause.billing_subscription_id) {
+            *remaining = remaining.saturating_sub(1);
+        }
     }
 
-    Ok(Json(SyncBillingSubscriptionResponse {
-        stripe_customer_id: billing_customer.stripe_customer_id.clone(),
-    }))
+    Ok(pauses_remaining)
 }
 
-/// The amount of time we wait in between each poll of Stripe events.
-///
-/// This value should strike a balance between:
-///   1. Being short enough that we update quickly when something in Stripe changes
-///   2. Being long enough that we don't eat into our rate limits.
+/// Pauses a user's subscription in Stripe, until `resumes_at` when given, and records the pause.
 ///
-/// As a point of reference, the Sequin folks say they have this at **500ms**:
+/// A subscription can only be paused [`Config::max_subscription_pauses_per_year`] times in a rolling year, so that
+/// pausing can't be used to avoid paying for most of the year. Pausing a subscription that is already paused only
+/// changes when it resumes, so it doesn't count as another pause.
+pub(crate) async fn pause_billing_subscription_for_user(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    user: &User,
+    subscription_id: BillingSubscriptionId,
+    resumes_at: Option<DateTime<Utc>>,
+    idempotency_key: Option<&str>,
+    now: DateTime<Utc>,
+) -> Result<()> {
+    let subscription = get_billing_subscription_for_user(app, user, subscription_id).await?;
+
+    if subscription.kind != Some(SubscriptionKind::ZedPro) {
+        return Err(BillingError::NotPausable.into());
+    }
+
+    if let Some(resumes_at) = resumes_at {
+        if resumes_at <= now {
+            return Err(BillingError::InvalidPauseUntil(
+                "pause_until must be in the future".into(),
+            )
+            .into());
+        }
+    }
+
+    // Stripe has the final say on whether the subscription is paused, in case we haven't synced it yet.
+    let stripe_subscription_id = StripeSubscriptionId(subscription.stripe_subscription_id.into());
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+    let existing_pause = if stripe_subscription.pause_collection.is_some() {
+        app.db
+            .get_latest_billing_subscription_pause(subscription.id)
+            .await?
+    } else {
+        None
+    };
+
+    if existing_pause.is_none() {
+        let pauses_remaining = subscription_pauses_remaining(app, &[subscription.id], now)
+            .await?
+            .get(&subscription.id)
+            .copied()
+            .unwrap_or(0);
+        if pauses_remaining == 0 {
+            return Err(BillingError::PauseLimitReached(
+                app.config.max_subscription_pauses_per_year(),
+            )
+            .into());
+        }
+    }
+
+    stripe_client
+        .pause_subscription(
+            &stripe_subscription_id,
+            resumes_at.map(|resumes_at| resumes_at.timestamp()),
+            idempotency_key,
+        )
+        .await?;
+
+    let resumes_at = resumes_at.map(|resumes_at| resumes_at.naive_utc());
+    if let Some(existing_pause) = existing_pause {
+        app.db
+            .set_billing_subscription_pause_resumes_at(existing_pause.id, resumes_at)
+            .await?;
+    } else {
+        app.db
+            .create_billing_subscription_pause(&CreateBillingSubscriptionPauseParams {
+                billing_subscription_id: subscription.id,
+                paused_at: now.naive_utc(),
+                resumes_at,
+            })
+            .await?;
+    }
+
+    // The user loses access to their plan while the subscription is paused, so we sync it right away.
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+    sync_subscription(app, stripe_client, stripe_subscription).await?;
+
+    log::info!(
+        "paused subscription {stripe_subscription_id} for user {user_id}",
+        user_id = user.id,
+    );
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncBillingSubscriptionBody {
+    github_user_id: i32,
+}
+
+#[derive(Debug, Serialize)]
+struct SyncBillingSubscriptionResponse {
+    stripe_customer_id: String,
+}
+
+async fn sync_billing_subscription(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<SyncBillingSubscriptionBody>,
+) -> Result<Json<SyncBillingSubscriptionResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await?
+        .context("billing customer not found")?;
+    let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+
+    let subscriptions = stripe_client
+        .list_subscriptions_for_customer(&stripe_customer_id)
+        .await?;
+
+    for subscription in subscriptions {
+        let subscription_id = subscription.id.clone();
+
+        sync_subscription(&app, &stripe_client, subscription)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to sync subscription {subscription_id} for user {}",
+                    user.id,
+                )
+            })?;
+    }
+
+    Ok(Json(SyncBillingSubscriptionResponse {
+        stripe_customer_id: billing_customer.stripe_customer_id.clone(),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct SyncOneBillingSubscriptionBody {
+    stripe_subscription_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SyncOneBillingSubscriptionResponse {
+    stripe_subscription_id: String,
+    stripe_customer_id: String,
+    kind: Option<SubscriptionKind>,
+    status: StripeSubscriptionStatus,
+}
+
+/// Syncs a single subscription from Stripe, for when support needs to fix one subscription without syncing all of
+/// the user's subscriptions.
+async fn sync_one_billing_subscription(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<SyncOneBillingSubscriptionBody>,
+) -> Result<Json<SyncOneBillingSubscriptionResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let stripe_subscription_id = StripeSubscriptionId(body.stripe_subscription_id.into());
+    let (billing_customer, subscription) =
+        sync_one_billing_subscription_for_id(&app, &stripe_client, &stripe_subscription_id).await?;
+
+    Ok(Json(SyncOneBillingSubscriptionResponse {
+        stripe_subscription_id: subscription.stripe_subscription_id,
+        stripe_customer_id: billing_customer.stripe_customer_id,
+        kind: subscription.kind,
+        status: subscription.stripe_subscription_status,
+    }))
+}
+
+pub(crate) async fn sync_one_billing_subscription_for_id(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    stripe_subscription_id: &StripeSubscriptionId,
+) -> Result<(billing_customer::Model, billing_subscription::Model)> {
+    let subscription = stripe_client
+        .get_subscription(stripe_subscription_id)
+        .await?;
+    let billing_customer = sync_subscription(app, stripe_client, subscription)
+        .await
+        .with_context(|| format!("failed to sync subscription {stripe_subscription_id}"))?;
+
+    let subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id(&stripe_subscription_id.0)
+        .await?
+        .context("subscription not found")?;
+
+    log::info!(
+        "synced subscription {stripe_subscription_id} for user {user_id}: {kind:?}, {status:?}",
+        user_id = billing_customer.user_id,
+        kind = subscription.kind,
+        status = subscription.stripe_subscription_status,
+    );
+
+    Ok((billing_customer, subscription))
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfirmCheckoutBody {
+    github_user_id: i32,
+}
+
+#[derive(Debug, Serialize)]
+struct ConfirmCheckoutResponse {
+    subscription: Option<BillingSubscriptionJson>,
+    /// An itemized summary of what the user signed up for.
+    receipt: Option<CheckoutReceiptJson>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct CheckoutReceiptJson {
+    /// The localized name of the plan.
+    pub(crate) plan_name: String,
+    /// The price of the plan for each billing interval, in cents.
+    pub(crate) price: Option<i64>,
+    pub(crate) interval: Option<&'static str>,
+    pub(crate) interval_count: Option<u64>,
+    /// When the trial ends, if the subscription is still in its trial.
+    pub(crate) trial_end_at: Option<String>,
+    /// The next charge, if the subscription renews.
+    ///
+    /// For a trial, this is the first charge after the trial ends.
+    pub(crate) next_charge: Option<NextChargeJson>,
+    pub(crate) discount: Option<CheckoutDiscountJson>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct NextChargeJson {
+    pub(crate) charge_at: String,
+    /// The amount that will be charged, in cents, after any discount.
+    pub(crate) amount: Option<i64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct CheckoutDiscountJson {
+    pub(crate) name: Option<String>,
+    pub(crate) promotion_code: Option<String>,
+    pub(crate) percent_off: Option<f64>,
+    /// The amount that is taken off, in cents.
+    pub(crate) amount_off: Option<i64>,
+    pub(crate) end_at: Option<String>,
+}
+
+/// Returns the receipt for a subscription that was just purchased through Checkout.
+pub(crate) fn checkout_receipt(
+    subscription: &billing_subscription::Model,
+    stripe_subscription: &StripeSubscription,
+    locale: Locale,
+) -> CheckoutReceiptJson {
+    let format_timestamp = |timestamp: i64| {
+        DateTime::from_timestamp(timestamp, 0)
+            .map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::Millis, true))
+    };
+
+    let price = plan_price(stripe_subscription);
+
+    let is_trial = stripe_subscription.status == SubscriptionStatus::Trialing;
+    let renews = matches!(
+        stripe_subscription.status,
+        SubscriptionStatus::Active | SubscriptionStatus::Trialing
+    ) && !stripe_subscription.cancel_at_period_end
+        && stripe_subscription.cancel_at.is_none();
+
+    // The next charge is at the end of the current period, which is when the trial ends for trials.
+    let next_charge_at = stripe_subscription.current_period_end;
+    let next_charge = if renews {
+        format_timestamp(next_charge_at).map(|charge_at| NextChargeJson {
+            charge_at,
+            amount: price.and_then(|price| price.unit_amount).map(
+                |amount| match stripe_subscription
+                    .discount
+                    .as_ref()
+                    .filter(|discount| discount.end.map_or(true, |end| end > next_charge_at))
+                {
+                    Some(discount) => discounted_amount(amount, discount),
+                    None => amount,
+                },
+            ),
+        })
+    } else {
+        None
+    };
+
+    CheckoutReceiptJson {
+        plan_name: plan_text(subscription.kind, locale).name.to_string(),
+        price: price.and_then(|price| price.unit_amount),
+        interval: price
+            .and_then(|price| price.recurring.as_ref())
+            .map(|recurring| recurring.interval.as_str()),
+        interval_count: price
+            .and_then(|price| price.recurring.as_ref())
+            .map(|recurring| recurring.interval_count),
+        trial_end_at: if is_trial {
+            format_timestamp(stripe_subscription.current_period_end)
+        } else {
+            None
+        },
+        next_charge,
+        discount: stripe_subscription
+            .discount
+            .as_ref()
+            .map(|discount| CheckoutDiscountJson {
+                name: discount.coupon_name.clone(),
+                promotion_code: discount.promotion_code.clone(),
+                percent_off: discount.percent_off,
+                amount_off: discount.amount_off,
+                end_at: discount.end.and_then(format_timestamp),
+            }),
+    }
+}
+
+/// Returns the price of the subscription's plan.
+fn plan_price(stripe_subscription: &StripeSubscription) -> Option<&StripePrice> {
+    // Usage is billed through metered prices, so the plan's price is the one that isn't metered.
+    stripe_subscription
+        .items
+        .iter()
+        .filter_map(|item| item.price.as_ref())
+        .min_by_key(|price| {
+            price
+                .recurring
+                .as_ref()
+                .is_some_and(|recurring| recurring.meter.is_some())
+        })
+}
+
+/// Returns the number of seats on the subscription, which is the quantity of its plan.
+fn subscription_seats(stripe_subscription: &StripeSubscription) -> i32 {
+    stripe_subscription
+        .items
+        .iter()
+        .filter(|item| {
+            item.price.as_ref().is_none_or(|price| {
+                price
+                    .recurring
+                    .as_ref()
+                    .is_none_or(|recurring| recurring.meter.is_none())
+            })
+        })
+        .find_map(|item| item.quantity)
+        .and_then(|quantity| i32::try_from(quantity).ok())
+        .unwrap_or(1)
+}
+
+/// Returns the given amount, in cents, with the discount applied.
+pub(crate) fn discounted_amount(amount: i64, discount: &StripeDiscount) -> i64 {
+    let amount = match discount.percent_off {
+        Some(percent_off) => (amount as f64 * (1.0 - percent_off / 100.0)).round() as i64,
+        None => amount,
+    };
+
+    amount
+        .saturating_sub(discount.amount_off.unwrap_or(0))
+        .max(0)
+}
+
+/// Confirms the completion of a Stripe Checkout session.
+///
+/// This is called by the Checkout success page so that the new subscription is
+/// reflected right away, rather than once the event poller catches up.
+async fn confirm_checkout(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<Server>>,
+    extract::Json(body): extract::Json<ConfirmCheckoutBody>,
+) -> Result<Json<ConfirmCheckoutResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let subscription = sync_subscriptions_for_checkout(&app, &stripe_client, &user).await?;
+
+    // The receipt is only a convenience for the success page, so we don't fail the confirmation without it.
+    let receipt = match &subscription {
+        Some(subscription) => {
+            let stripe_subscription_id =
+                StripeSubscriptionId(subscription.stripe_subscription_id.clone().into());
+
+            stripe_client
+                .get_subscription(&stripe_subscription_id)
+                .await
+                .log_err()
+                .map(|stripe_subscription| {
+                    checkout_receipt(subscription, &stripe_subscription, Locale::default())
+                })
+        }
+        None => None,
+    };
+
+    rpc_server.update_plan_for_user(user.id).await.trace_err();
+    rpc_server.refresh_llm_tokens_for_user(user.id).await;
+
+    Ok(Json(ConfirmCheckoutResponse {
+        subscription: subscription.map(|subscription| {
+            BillingSubscriptionJson::new(subscription, Locale::default(), app.config.minimum_term())
+        }),
+        receipt,
+    }))
+}
+
+/// Syncs the user's latest Stripe subscriptions and returns their active subscription, if any.
+pub(crate) async fn sync_subscriptions_for_checkout(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    user: &User,
+) -> anyhow::Result<Option<billing_subscription::Model>> {
+    // The billing customer record may not exist yet if we haven't processed
+    // the `customer.created` event, so we fall back to looking up the Stripe
+    // customer by email, the same way Checkout found it.
+    let stripe_customer_id =
+        if let Some(billing_customer) = app.db.get_billing_customer_by_user_id(user.id).await? {
+            Some(StripeCustomerId(billing_customer.stripe_customer_id.into()))
+        } else if let Some(email) = user.email_address.as_deref() {
+            stripe_client
+                .list_customers_by_email(email)
+                .await?
+                .into_iter()
+                .next()
+                .map(|customer| customer.id)
+        } else {
+            None
+        };
+
+    let Some(stripe_customer_id) = stripe_customer_id else {
+        return Ok(None);
+    };
+
+    let subscriptions = stripe_client
+        .list_subscriptions_for_customer(&stripe_customer_id)
+        .await?;
+
+    for subscription in subscriptions {
+        let subscription_id = subscription.id.clone();
+
+        sync_subscription(app, stripe_client, subscription)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to sync subscription {subscription_id} for user {}",
+                    user.id,
+                )
+            })?;
+    }
+
+    Ok(app.db.get_active_billing_subscription(user.id).await?)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ScheduledChangeKind {
+    /// The trial will end and the subscription will continue as Zed Pro.
+    EndTrial,
+    /// The subscription will be canceled and the user moved to Zed Free.
+    DowngradeToFree,
+    /// The paused subscription will resume.
+    ResumeFromPause,
+    /// The subscription has ended, and the user will be moved to Zed Free unless they reactivate first.
+    DelayedDowngradeToFree,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ScheduledChangeJson {
+    pub(crate) kind: ScheduledChangeKind,
+    pub(crate) effective_at: String,
+    pub(crate) is_cancelable: bool,
+}
+
+/// The changes to a subscription that we schedule ourselves, rather than on the Stripe subscription.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct LocalScheduledChanges {
+    /// The subscription's most recent pause, which may end on its own.
+    pub(crate) latest_pause: Option<billing_subscription_pause::Model>,
+    /// When the user will be moved to Zed Free, if this is their most recent subscription and it has ended.
+    pub(crate) free_downgrade_at: Option<DateTime<Utc>>,
+}
+
+/// Returns the changes to the given subscription that we've scheduled ourselves.
+pub(crate) async fn local_scheduled_changes(
+    app: &AppState,
+    subscription: &billing_subscription::Model,
+) -> Result<LocalScheduledChanges> {
+    let latest_pause = app
+        .db
+        .get_latest_billing_subscription_pause(subscription.id)
+        .await?;
+
+    // Once a subscription ends, the user is moved to Zed Free unless they reactivate it or start a new one.
+    let mut free_downgrade_at = None;
+    if !subscription.stripe_subscription_status.is_cancelable() {
+        let billing_customer = app
+            .db
+            .get_billing_customer_by_id(subscription.billing_customer_id)
+            .await?
+            .context("billing customer not found")?;
+        let is_latest_subscription = app
+            .db
+            .get_billing_subscriptions(billing_customer.user_id)
+            .await?
+            .last()
+            .map_or(false, |latest_subscription| {
+                latest_subscription.id == subscription.id
+            });
+        if is_latest_subscription {
+            free_downgrade_at = billing_customer
+                .free_downgrade_at
+                .map(|free_downgrade_at| free_downgrade_at.and_utc());
+        }
+    }
+
+    Ok(LocalScheduledChanges {
+        latest_pause,
+        free_downgrade_at,
+    })
+}
+
+/// Returns the changes that are scheduled to happen to the given subscription after `now`,
+/// in the order they will take effect.
+///
+/// Most scheduled changes live on the Stripe subscription, which applies them when they take effect, and our copy of
+/// the subscription only mirrors them. At the end of a period, our copy lags behind Stripe until we process the webhook
+/// for the change, so changes whose time has passed are left out rather than shown as still to come.
+///
+/// The end of a pause and a delayed move to Zed Free are scheduled by us, and are given in `local`.
+pub(crate) fn scheduled_changes(
+    subscription: &billing_subscription::Model,
+    local: &LocalScheduledChanges,
+    now: DateTime<Utc>,
+) -> Vec<ScheduledChangeJson> {
+    let mut changes = Vec::new();
+
+    let cancel_at = subscription
+        .stripe_cancel_at
+        .map(|cancel_at| cancel_at.and_utc());
+    let is_active = subscription.stripe_subscription_status.is_cancelable();
+    // A paused subscription still ends at its cancellation time, and its pause only ends on its own while it's still
+    // running.
+    let is_running =
+        is_active || subscription.stripe_subscription_status == StripeSubscriptionStatus::Paused;
+
+    if subscription.kind == Some(SubscriptionKind::ZedProTrial)
+        && subscription.stripe_subscription_status == StripeSubscriptionStatus::Trialing
+    {
+        if let Some(trial_end_at) = subscription.current_period_end_at() {
+            // A trial that is canceled by the time it ends never converts.
+            if cancel_at.map_or(true, |cancel_at| cancel_at > trial_end_at) {
+                changes.push((trial_end_at, ScheduledChangeKind::EndTrial, false));
+            }
+        }
+    }
+
+    if let Some(cancel_at) = cancel_at {
+        if is_running && subscription.kind != Some(SubscriptionKind::ZedFree) {
+            changes.push((cancel_at, ScheduledChangeKind::DowngradeToFree, true));
+        }
+    }
+
+    if let Some(resumes_at) = local
+        .latest_pause
+        .as_ref()
+        .and_then(|pause| pause.resumes_at)
+    {
+        if is_running {
+            changes.push((
+                resumes_at.and_utc(),
+                ScheduledChangeKind::ResumeFromPause,
+                true,
+            ));
+        }
+    }
+
+    if let Some(free_downgrade_at) = local.free_downgrade_at {
+        changes.push((
+            free_downgrade_at,
+            ScheduledChangeKind::DelayedDowngradeToFree,
+            true,
+        ));
+    }
+
+    changes.retain(|(effective_at, _, _)| *effective_at > now);
+    changes.sort_by_key(|(effective_at, _, _)| *effective_at);
+    changes
+        .into_iter()
+        .map(|(effective_at, kind, is_cancelable)| ScheduledChangeJson {
+            kind,
+            effective_at: effective_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            is_cancelable,
+        })
+        .collect()
+}
+
+/// Returns the billing subscription with the given ID, if it belongs to the user.
+pub(crate) async fn get_billing_subscription_for_user(
+    app: &Arc<AppState>,
+    user: &User,
+    subscription_id: BillingSubscriptionId,
+) -> Result<billing_subscription::Model> {
+    let subscription = app
+        .db
+        .get_billing_subscription_by_id(subscription_id)
+        .await?;
+    let billing_customer = app.db.get_billing_customer_by_user_id(user.id).await?;
+
+    match (subscription, billing_customer) {
+        (Some(subscription), Some(billing_customer))
+            if subscription.billing_customer_id == billing_customer.id =>
+        {
+            Ok(subscription)
+        }
+        _ => Err(BillingError::SubscriptionNotFound.into()),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ListScheduledChangesParams {
+    github_user_id: i32,
+}
+
+#[derive(Debug, Serialize)]
+struct ListScheduledChangesResponse {
+    scheduled_changes: Vec<ScheduledChangeJson>,
+}
+
+async fn list_scheduled_changes(
+    Extension(app): Extension<Arc<AppState>>,
+    Path(subscription_id): Path<BillingSubscriptionId>,
+    Query(params): Query<ListScheduledChangesParams>,
+) -> Result<Json<ListScheduledChangesResponse>> {
+    let user = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let subscription = get_billing_subscription_for_user(&app, &user, subscription_id).await?;
+    let local = local_scheduled_changes(&app, &subscription).await?;
+
+    Ok(Json(ListScheduledChangesResponse {
+        scheduled_changes: scheduled_changes(&subscription, &local, Utc::now()),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct CancelScheduledChangeBody {
+    github_user_id: i32,
+    kind: ScheduledChangeKind,
+}
+
+async fn cancel_scheduled_change(
+    Extension(app): Extension<Arc<AppState>>,
+    Path(subscription_id): Path<BillingSubscriptionId>,
+    extract::Json(body): extract::Json<CancelScheduledChangeBody>,
+) -> Result<Json<ListScheduledChangesResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let subscription = get_billing_subscription_for_user(&app, &user, subscription_id).await?;
+    let subscription =
+        cancel_scheduled_change_for_subscription(&app, &stripe_client, subscription, body.kind)
+            .await?;
+    let local = local_scheduled_changes(&app, &subscription).await?;
+
+    Ok(Json(ListScheduledChangesResponse {
+        scheduled_changes: scheduled_changes(&subscription, &local, Utc::now()),
+    }))
+}
+
+/// Cancels a change that is scheduled to happen to the subscription.
+///
+/// We sync the subscription from Stripe first, so that a change that has already taken effect at the end of the period
+/// can't be undone based on our out-of-date copy of the subscription.
+pub(crate) async fn cancel_scheduled_change_for_subscription(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    subscription: billing_subscription::Model,
+    kind: ScheduledChangeKind,
+) -> Result<billing_subscription::Model> {
+    let stripe_subscription_id =
+        StripeSubscriptionId(subscription.stripe_subscription_id.clone().into());
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+    sync_subscription(app, stripe_client, stripe_subscription).await?;
+    let subscription = app
+        .db
+        .get_billing_subscription_by_id(subscription.id)
+        .await?
+        .context("subscription not found")?;
+
+    let local = local_scheduled_changes(app, &subscription).await?;
+    let is_scheduled = scheduled_changes(&subscription, &local, Utc::now())
+        .iter()
+        .any(|change| change.kind == kind && change.is_cancelable);
+    if !is_scheduled {
+        return Err(BillingError::NoScheduledChange.into());
+    }
+
+    match kind {
+        ScheduledChangeKind::DowngradeToFree => {
+            // The subscription may be scheduled to be canceled at a given time rather than at the end of the period,
+            // so we clear both.
+            let updated_stripe_subscription = stripe_client
+                .stop_cancellation(&stripe_subscription_id, None)
+                .await?;
+
+            app.db
+                .update_billing_subscription(
+                    subscription.id,
+                    &UpdateBillingSubscriptionParams {
+                        stripe_cancel_at: ActiveValue::set(
+                            updated_stripe_subscription
+                                .cancel_at
+                                .and_then(|cancel_at| DateTime::from_timestamp(cancel_at, 0))
+                                .map(|time| time.naive_utc()),
+                        ),
+                        stripe_cancel_at_period_end: ActiveValue::set(
+                            updated_stripe_subscription.cancel_at_period_end,
+                        ),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+        }
+        ScheduledChangeKind::ResumeFromPause => {
+            // Pausing again without an end replaces the scheduled resume, so the subscription stays paused until
+            // the user resumes it.
+            stripe_client
+                .pause_subscription(&stripe_subscription_id, None, None)
+                .await?;
+
+            if let Some(pause) = local.latest_pause {
+                app.db
+                    .set_billing_subscription_pause_resumes_at(pause.id, None)
+                    .await?;
+            }
+        }
+        ScheduledChangeKind::DelayedDowngradeToFree => {
+            app.db
+                .update_billing_customer(
+                    subscription.billing_customer_id,
+                    &UpdateBillingCustomerParams {
+                        free_downgrade_at: ActiveValue::set(None),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+        }
+        ScheduledChangeKind::EndTrial => {}
+    }
+
+    Ok(app
+        .db
+        .get_billing_subscription_by_id(subscription.id)
+        .await?
+        .context("subscription not found")?)
+}
+
+/// The most days that a trial can be extended by at once.
+pub(crate) const MAX_TRIAL_EXTENSION_DAYS: u32 = 30;
+
+#[derive(Debug, Deserialize)]
+struct ExtendTrialBody {
+    github_user_id: i32,
+    additional_days: u32,
+}
+
+#[derive(Debug, Serialize)]
+struct ExtendTrialResponse {
+    trial_end_at: String,
+}
+
+/// Extends a user's trial by the given number of days.
+///
+/// This is a one-off extension made by hand, separate from the extended trial that is granted by a feature flag.
+async fn extend_trial(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<ExtendTrialBody>,
+) -> Result<Json<ExtendTrialResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let subscription =
+        extend_trial_for_user(&app, &stripe_client, &user, body.additional_days).await?;
+    let trial_end_at = subscription
+        .current_period_end_at()
+        .context("trial has no end")?;
+
+    Ok(Json(ExtendTrialResponse {
+        trial_end_at: trial_end_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+    }))
+}
+
+pub(crate) async fn extend_trial_for_user(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    user: &User,
+    additional_days: u32,
+) -> Result<billing_subscription::Model> {
+    if additional_days == 0 || additional_days > MAX_TRIAL_EXTENSION_DAYS {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            format!("additional_days must be between 1 and {MAX_TRIAL_EXTENSION_DAYS}"),
+        ));
+    }
+
+    let not_trialing = || {
+        Error::http(
+            StatusCode::CONFLICT,
+            "user's subscription is not trialing".into(),
+        )
+    };
+
+    let subscription = app
+        .db
+        .get_active_billing_subscription(user.id)
+        .await?
+        .filter(|subscription| {
+            subscription.kind == Some(SubscriptionKind::ZedProTrial)
+                && subscription.stripe_subscription_status == StripeSubscriptionStatus::Trialing
+        })
+        .ok_or_else(not_trialing)?;
+
+    // Stripe has the final say on whether the subscription is still trialing, in case we haven't synced it yet.
+    let stripe_subscription_id = StripeSubscriptionId(subscription.stripe_subscription_id.into());
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+    if stripe_subscription.status != SubscriptionStatus::Trialing {
+        return Err(not_trialing());
+    }
+
+    let previous_trial_end = stripe_subscription.current_period_end;
+    let trial_end = previous_trial_end + i64::from(additional_days) * 24 * 60 * 60;
+    stripe_client
+        .update_subscription(
+            &stripe_subscription_id,
+            UpdateSubscriptionParams {
+                trial_end: Some(trial_end),
+                proration_behavior: Some(StripeProrationBehavior::None),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+    sync_subscription(app, stripe_client, stripe_subscription).await?;
+
+    log::info!(
+        "extended trial {stripe_subscription_id} for user {user_id} by {additional_days} days",
+        user_id = user.id,
+    );
+    SnowflakeRow::new(
+        "Trial Extended",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "stripe_subscription_id": stripe_subscription_id.to_string(),
+            "additional_days": additional_days,
+            "previous_trial_end_at": DateTime::from_timestamp(previous_trial_end, 0)
+                .map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+            "trial_end_at": DateTime::from_timestamp(trial_end, 0)
+                .map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        }),
+    )
+    .with_billing_request_id()
+    .write(&app.kinesis_client, &app.config.kinesis_stream)
+    .await
+    .log_err();
+
+    Ok(app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id(&stripe_subscription_id.0)
+        .await?
+        .context("subscription not found")?)
+}
+
+#[derive(Debug, Deserialize)]
+struct PreviewBillingSubscriptionChangeBody {
+    github_user_id: i32,
+    subscription_id: BillingSubscriptionId,
+    /// The plan to preview switching the subscription to.
+    product: ProductCode,
+}
+
+#[derive(Debug, Serialize)]
+struct PreviewBillingSubscriptionChangeResponse {
+    currency: String,
+    /// The one-time amount for the rest of the current period, charged on top of the recurring amount.
+    ///
+    /// This is negative when the user is credited for the unused time on their current plan.
+    proration_amount_in_cents: i64,
+    /// The amount the user will be charged each period on the new plan.
+    recurring_amount_in_cents: i64,
+    next_charge_at: String,
+}
+
+/// Returns what a user would be charged if they switched their Zed Pro subscription to another plan, without
+/// switching it.
+async fn preview_billing_subscription_change(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<PreviewBillingSubscriptionChangeBody>,
+) -> Result<Json<PreviewBillingSubscriptionChangeResponse>> {
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let Some(stripe_billing) = app.stripe_billing.clone() else {
+        log::error!("failed to retrieve Stripe billing object");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let preview = preview_billing_subscription_change_for_user(
+        &app,
+        &stripe_client,
+        &stripe_billing,
+        &user,
+        body.subscription_id,
+        body.product,
+    )
+    .await?;
+    let next_charge_at =
+        DateTime::from_timestamp(preview.next_charge_at, 0).context("invalid next charge date")?;
+
+    Ok(Json(PreviewBillingSubscriptionChangeResponse {
+        currency: preview.currency,
+        proration_amount_in_cents: preview.proration_amount,
+        recurring_amount_in_cents: preview.recurring_amount,
+        next_charge_at: next_charge_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+    }))
+}
+
+pub(crate) async fn preview_billing_subscription_change_for_user(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    stripe_billing: &StripeBilling,
+    user: &User,
+    subscription_id: BillingSubscriptionId,
+    product: ProductCode,
+) -> Result<StripeSubscriptionUpdatePreview> {
+    let new_price_id = match product {
+        ProductCode::ZedPro => stripe_billing.zed_pro_price_id().await?,
+        ProductCode::ZedProAnnual => stripe_billing.zed_pro_annual_price_id().await?,
+        ProductCode::ZedProTrial => {
+            return Err(BillingError::CannotChangeToTrial.into());
+        }
+    };
+
+    let subscription = get_billing_subscription_for_user(app, user, subscription_id).await?;
+    if subscription.kind != Some(SubscriptionKind::ZedPro) {
+        return Err(BillingError::PlanChangeNotAllowed.into());
+    }
+
+    let stripe_subscription_id = StripeSubscriptionId(subscription.stripe_subscription_id.into());
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+    let zed_pro_price_ids = [
+        stripe_billing.zed_pro_price_id().await.ok(),
+        stripe_billing.zed_pro_annual_price_id().await.ok(),
+    ];
+    let item = stripe_subscription
+        .items
+        .iter()
+        .find(|item| {
+            item.price
+                .as_ref()
+                .is_some_and(|price| zed_pro_price_ids.contains(&Some(price.id.clone())))
+        })
+        .context("subscription has no Zed Pro item")?;
+    if item
+        .price
+        .as_ref()
+        .is_some_and(|price| price.id == new_price_id)
+    {
+        return Err(BillingError::AlreadyOnPlan.into());
+    }
+
+    Ok(stripe_client
+        .preview_subscription_update(
+            &stripe_subscription_id,
+            StripePreviewSubscriptionUpdateParams {
+                items: vec![UpdateSubscriptionItems {
+                    id: Some(item.id.clone()),
+                    price: Some(new_price_id),
+                }],
+                proration_behavior: StripeProrationBehavior::CreateProrations,
+                proration_date: None,
+            },
+        )
+        .await?)
+}
+
+#[derive(Debug, Deserialize)]
+struct RefundBillingSubscriptionBody {
+    github_user_id: i32,
+    subscription_id: BillingSubscriptionId,
+}
+
+#[derive(Debug, Serialize)]
+struct RefundBillingSubscriptionResponse {
+    refunded_amount_in_cents: i64,
+}
+
+/// Cancels a user's Zed Pro subscription immediately and refunds the unused portion of the current period.
+///
+/// Calling this again for the same subscription returns the amount that was already refunded instead of refunding it twice.
+async fn refund_billing_subscription(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<RefundBillingSubscriptionBody>,
+) -> Result<Json<RefundBillingSubscriptionResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let Some(stripe_billing) = app.stripe_billing.clone() else {
+        log::error!("failed to retrieve Stripe billing object");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let refunded_amount_in_cents = refund_billing_subscription_for_user(
+        &app,
+        &stripe_client,
+        &stripe_billing,
+        &user,
+        body.subscription_id,
+    )
+    .await?;
+
+    Ok(Json(RefundBillingSubscriptionResponse {
+        refunded_amount_in_cents,
+    }))
+}
+
+pub(crate) async fn refund_billing_subscription_for_user(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    stripe_billing: &StripeBilling,
+    user: &User,
+    subscription_id: BillingSubscriptionId,
+) -> Result<i64> {
+    let subscription = get_billing_subscription_for_user(app, user, subscription_id).await?;
+    if subscription.kind != Some(SubscriptionKind::ZedPro) {
+        return Err(BillingError::NotRefundable.into());
+    }
+
+    let stripe_subscription_id = StripeSubscriptionId(subscription.stripe_subscription_id.into());
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+
+    // We refund before canceling so that the refund is computed against the period that was paid for.
+    let refunded_amount = stripe_billing
+        .refund_unused_time(&stripe_subscription, Utc::now())
+        .await?;
+
+    if stripe_subscription.status != SubscriptionStatus::Canceled {
+        stripe_client
+            .cancel_subscription(
+                &stripe_subscription_id,
+                Some(&format!("cancel-refunded-{stripe_subscription_id}")),
+            )
+            .await?;
+    }
+
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+    sync_subscription(app, stripe_client, stripe_subscription).await?;
+
+    log::info!(
+        "refunded {refunded_amount} cents for subscription {stripe_subscription_id} of user {user_id}",
+        user_id = user.id,
+    );
+    SnowflakeRow::new(
+        "Subscription Refunded",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "stripe_subscription_id": stripe_subscription_id.to_string(),
+            "refunded_amount_in_cents": refunded_amount,
+        }),
+    )
+    .with_billing_request_id()
+    .write(&app.kinesis_client, &app.config.kinesis_stream)
+    .await
+    .log_err();
+
+    Ok(refunded_amount)
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateTaxIdBody {
+    github_user_id: i32,
+    tax_id_type: StripeTaxIdKind,
+    tax_id: String,
+    tax_exempt: Option<StripeTaxExempt>,
+}
+
+#[derive(Debug, Serialize)]
+struct UpdateTaxIdResponse {
+    tax_id_type: StripeTaxIdKind,
+    tax_id: String,
+}
+
+/// Records the tax ID (e.g., a VAT number) that should appear on a user's invoices.
+async fn update_tax_id(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<UpdateTaxIdBody>,
+) -> Result<Json<UpdateTaxIdResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let tax_id = update_tax_id_for_user(
+        &app,
+        &stripe_client,
+        &user,
+        body.tax_id_type,
+        &body.tax_id,
+        body.tax_exempt,
+    )
+    .await?;
+
+    Ok(Json(UpdateTaxIdResponse {
+        tax_id_type: tax_id.kind,
+        tax_id: tax_id.value,
+    }))
+}
+
+pub(crate) async fn update_tax_id_for_user(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    user: &User,
+    kind: StripeTaxIdKind,
+    tax_id: &str,
+    tax_exempt: Option<StripeTaxExempt>,
+) -> Result<StripeTaxId> {
+    let Some(value) = normalize_tax_id(kind, tax_id) else {
+        return Err(BillingError::InvalidTaxId(format!(
+            "{tax_id:?} is not a valid {} tax ID",
+            kind.as_str()
+        ))
+        .into());
+    };
+    let tax_id = StripeTaxId { kind, value };
+
+    let Some(billing_customer) = app.db.get_billing_customer_by_user_id(user.id).await? else {
+        return Err(BillingError::BillingCustomerNotFound.into());
+    };
+
+    // Stripe keeps every tax ID we add to a customer, so we don't add the same one twice.
+    let is_new_tax_id = billing_customer.tax_id_type.as_deref() != Some(kind.as_str())
+        || billing_customer.tax_id.as_deref() != Some(tax_id.value.as_str());
+    if is_new_tax_id || tax_exempt.is_some() {
+        let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.into());
+        stripe_client
+            .update_customer(
+                &stripe_customer_id,
+                UpdateCustomerParams {
+                    email: None,
+                    tax_exempt,
+                    tax_id: is_new_tax_id.then_some(&tax_id),
+                    idempotency_key: None,
+                },
+            )
+            .await?;
+    }
+
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                tax_id_type: ActiveValue::set(Some(kind.as_str().to_string())),
+                tax_id: ActiveValue::set(Some(tax_id.value.clone())),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    log::info!(
+        "updated tax ID for user {user_id} to {} {}",
+        kind.as_str(),
+        tax_id.value,
+        user_id = user.id,
+    );
+
+    Ok(tax_id)
+}
+
+/// The countries whose VAT numbers Stripe accepts as `eu_vat`, keyed by the prefix of the VAT number.
+///
+/// Greece uses `EL` rather than its ISO code, and `XI` is for businesses in Northern Ireland.
+const EU_VAT_COUNTRY_PREFIXES: &[&str] = &[
+    "AT", "BE", "BG", "CY", "CZ", "DE", "DK", "EE", "EL", "ES", "FI", "FR", "HR", "HU", "IE", "IT",
+    "LT", "LU", "LV", "MT", "NL", "PL", "PT", "RO", "SE", "SI", "SK", "XI",
+];
+
+/// Normalizes a tax ID of the given kind, returning `None` if it isn't in a valid format.
+///
+/// This only checks the shape of the ID; Stripe verifies the ID itself with the issuing authority.
+pub(crate) fn normalize_tax_id(kind: StripeTaxIdKind, tax_id: &str) -> Option<String> {
+    let tax_id = tax_id
+        .chars()
+        .filter(|char| !char.is_whitespace() && !matches!(char, '.' | '-'))
+        .collect::<String>()
+        .to_uppercase();
+    if tax_id.is_empty() || !tax_id.chars().all(|char| char.is_ascii_alphanumeric()) {
+        return None;
+    }
+
+    let is_digits = |value: &str| value.bytes().all(|byte| byte.is_ascii_digit());
+    let is_valid = match kind {
+        StripeTaxIdKind::EuVat => {
+            (4..=14).contains(&tax_id.len()) && EU_VAT_COUNTRY_PREFIXES.contains(&&tax_id[..2])
+        }
+        StripeTaxIdKind::GbVat => tax_id
+            .strip_prefix("GB")
+            .is_some_and(|digits| matches!(digits.len(), 9 | 12) && is_digits(digits)),
+        StripeTaxIdKind::AuAbn => tax_id.len() == 11 && is_digits(&tax_id),
+        StripeTaxIdKind::CaBn => tax_id.len() == 9 && is_digits(&tax_id),
+        // A GSTIN is a two-digit state code, the business's ten-character PAN, an entity number, a `Z`, and a check character.
+        StripeTaxIdKind::InGst => {
+            tax_id.len() == 15 && is_digits(&tax_id[..2]) && tax_id.as_bytes()[13] == b'Z'
+        }
+    };
+
+    is_valid.then_some(tax_id)
+}
+
+/// How long after a Zed Pro subscription is canceled it can be reactivated, rather than the user having to go through
+/// checkout again.
+pub(crate) const REACTIVATION_WINDOW: chrono::Duration = chrono::Duration::days(30);
+
+#[derive(Debug, Deserialize)]
+struct ReactivateBillingSubscriptionBody {
+    github_user_id: i32,
+    /// The ID of the canceled subscription to reactivate.
+    subscription_id: BillingSubscriptionId,
+}
+
+#[derive(Debug, Serialize)]
+struct ReactivateBillingSubscriptionResponse {
+    subscription: BillingSubscriptionJson,
+}
+
+/// Reactivates a recently-canceled Zed Pro subscription by subscribing the user to the same plan again.
+///
+/// Responds with a 409 when the subscription can't be reactivated, in which case the user needs to go through
+/// checkout again.
+async fn reactivate_billing_subscription(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<Server>>,
+    extract::Json(body): extract::Json<ReactivateBillingSubscriptionBody>,
+) -> Result<Json<ReactivateBillingSubscriptionResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let subscription = reactivate_billing_subscription_for_user(
+        &app,
+        &stripe_client,
+        &user,
+        body.subscription_id,
+        Utc::now(),
+    )
+    .await?;
+
+    rpc_server.update_plan_for_user(user.id).await.trace_err();
+    rpc_server.refresh_llm_tokens_for_user(user.id).await;
+
+    Ok(Json(ReactivateBillingSubscriptionResponse {
+        subscription: BillingSubscriptionJson::new(
+            subscription,
+            Locale::default(),
+            app.config.minimum_term(),
+        ),
+    }))
+}
+
+pub(crate) async fn reactivate_billing_subscription_for_user(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    user: &User,
+    subscription_id: BillingSubscriptionId,
+    now: DateTime<Utc>,
+) -> Result<billing_subscription::Model> {
+    let start_checkout =
+        |reason: &str| Error::from(BillingError::ReactivationUnavailable(reason.to_string()));
+
+    let subscription = get_billing_subscription_for_user(app, user, subscription_id).await?;
+    if subscription.stripe_subscription_status != StripeSubscriptionStatus::Canceled {
+        return Err(BillingError::SubscriptionNotCanceled.into());
+    }
+    if subscription.kind != Some(SubscriptionKind::ZedPro) {
+        return Err(start_checkout(
+            "only Zed Pro subscriptions can be reactivated",
+        ));
+    }
+
+    let canceled_at = subscription
+        .stripe_cancel_at
+        .map(|cancel_at| cancel_at.and_utc())
+        .or_else(|| subscription.current_period_end_at());
+    if canceled_at.is_none_or(|canceled_at| now - canceled_at > REACTIVATION_WINDOW) {
+        return Err(start_checkout(
+            "the subscription was canceled too long ago to be reactivated",
+        ));
+    }
+
+    let active_subscription = app.db.get_active_billing_subscription(user.id).await?;
+    if active_subscription
+        .as_ref()
+        .is_some_and(|subscription| subscription.kind != Some(SubscriptionKind::ZedFree))
+    {
+        return Err(BillingError::AlreadySubscribed.into());
+    }
+
+    let stripe_subscription_id = StripeSubscriptionId(subscription.stripe_subscription_id.into());
+    let stripe_subscription = stripe_client
+        .get_subscription(&stripe_subscription_id)
+        .await?;
+
+    // Usage-based prices are added back by the usage sync, so we only resubscribe to the plan itself.
+    let items = stripe_subscription
+        .items
+        .iter()
+        .filter_map(|item| item.price.as_ref())
+        .filter(|price| {
+            price
+                .recurring
+                .as_ref()
+                .is_none_or(|recurring| recurring.meter.is_none())
+        })
+        .map(|price| StripeCreateSubscriptionItems {
+            price: Some(price.id.clone()),
+            quantity: Some(1),
+        })
+        .collect::<Vec<_>>();
+    if items.is_empty() {
+        return Err(start_checkout(
+            "the subscription doesn't have a plan to reactivate",
+        ));
+    }
+
+    if stripe_client
+        .get_default_payment_method(&stripe_subscription.customer)
+        .await?
+        .is_none()
+    {
+        return Err(start_checkout("there is no payment method on file"));
+    }
+
+    let reactivated_subscription = stripe_client
+        .create_subscription(StripeCreateSubscriptionParams {
+            customer: stripe_subscription.customer.clone(),
+            items,
+            idempotency_key: Some(format!("reactivate-{stripe_subscription_id}")),
+        })
+        .await?;
+    if reactivated_subscription.status != SubscriptionStatus::Active {
+        // The first payment didn't go through, so we don't leave an incomplete subscription behind.
+        stripe_client
+            .cancel_subscription(&reactivated_subscription.id, None)
+            .await?;
+        return Err(start_checkout("the payment for the subscription failed"));
+    }
+
+    // The reactivated subscription replaces the user's Zed Free subscription. Since the reactivated one is already
+    // active in Stripe, canceling Zed Free doesn't subscribe them to Zed Free again.
+    if let Some(free_subscription) = active_subscription {
+        let free_subscription_id =
+            StripeSubscriptionId(free_subscription.stripe_subscription_id.into());
+        stripe_client
+            .cancel_subscription(&free_subscription_id, None)
+            .await?;
+        let free_subscription = stripe_client
+            .get_subscription(&free_subscription_id)
+            .await?;
+        sync_subscription(app, stripe_client, free_subscription).await?;
+    }
+
+    let reactivated_subscription_id = reactivated_subscription.id.clone();
+    sync_subscription(app, stripe_client, reactivated_subscription).await?;
+    let reactivated_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id(&reactivated_subscription_id.0)
+        .await?
+        .context("reactivated subscription not found")?;
+
+    log::info!(
+        "reactivated subscription {stripe_subscription_id} of user {user_id} as {reactivated_subscription_id}",
+        user_id = user.id,
+    );
+    SnowflakeRow::new(
+        "Subscription Reactivated",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "canceled_stripe_subscription_id": stripe_subscription_id.to_string(),
+            "stripe_subscription_id": reactivated_subscription_id.to_string(),
+        }),
+    )
+    .with_billing_request_id()
+    .write(&app.kinesis_client, &app.config.kinesis_stream)
+    .await
+    .log_err();
+
+    Ok(reactivated_subscription)
+}
+
+/// The amount of time we wait in between each poll of Stripe events.
+///
+/// This value should strike a balance between:
+///   1. Being short enough that we update quickly when something in Stripe changes
+///   2. Being long enough that we don't eat into our rate limits.
+///
+/// As a point of reference, the Sequin folks say they have this at **500ms**:
+///
+/// > We poll the Stripe /events endpoint every 500ms per account
+/// >
+/// > — https://blog.sequinstream.com/events-not-webhooks/
+pub(crate) const POLL_EVENTS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The longest we wait between polls of the Stripe events API while Stripe is rate-limiting us.
+pub(crate) const MAX_POLL_EVENTS_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// How long we keep records of processed Stripe events by default.
+///
+/// Stripe only returns events from the last 30 days, so older records are never consulted.
+pub(crate) const DEFAULT_PROCESSED_STRIPE_EVENT_RETENTION: Duration =
+    Duration::from_secs(30 * 24 * 60 * 60);
+
+const PRUNE_PROCESSED_STRIPE_EVENTS_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The maximum number of events to return per page.
+///
+/// We set this to 100 (the max) so we have to make fewer requests to Stripe.
+///
+/// > Limit can range between 1 and 100, and the default is 10.
+const EVENTS_LIMIT_PER_PAGE: u64 = 100;
+
+/// The number of pages consisting entirely of already-processed events that we
+/// will see before we stop retrieving events, when we don't have a cursor yet.
+///
+/// Once we have a cursor, we stop at it instead.
+const NUMBER_OF_ALREADY_PROCESSED_PAGES_BEFORE_WE_STOP: usize = 4;
+
+/// The types of Stripe events that we handle, whether they're polled or pushed to us by webhook.
+const HANDLED_STRIPE_EVENT_TYPES: [EventType; 9] = [
+    EventType::CustomerCreated,
+    EventType::CustomerUpdated,
+    EventType::CustomerSubscriptionCreated,
+    EventType::CustomerSubscriptionUpdated,
+    EventType::CustomerSubscriptionPaused,
+    EventType::CustomerSubscriptionResumed,
+    EventType::CustomerSubscriptionDeleted,
+    EventType::InvoicePaymentSucceeded,
+    EventType::InvoicePaymentFailed,
+];
+
+/// Coordinates the graceful shutdown of the periodic billing tasks.
+///
+/// The tasks only check for shutdown between steps, so that they never exit
+/// partway through one (e.g., after updating Stripe, but before recording the
+/// change in our database).
+pub struct BillingTasks {
+    shutdown: watch::Sender<bool>,
+}
+
+impl Default for BillingTasks {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BillingTasks {
+    pub fn new() -> Self {
+        Self {
+            shutdown: watch::channel(false).0,
+        }
+    }
+
+    pub(crate) fn shutdown_signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.shutdown.subscribe())
+    }
+
+    /// Signals the billing tasks to stop, and waits for them to finish their current step.
+    pub async fn shutdown(&self) {
+        let _ = self.shutdown.send(true);
+        self.shutdown.closed().await;
+    }
+}
+
+/// Signals that a periodic billing task should stop.
+///
+/// The task keeps running for as long as it holds onto the signal.
+#[derive(Clone)]
+pub(crate) struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub(crate) fn is_requested(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    async fn requested(&mut self) {
+        // If the sender is gone there's no one left to shut us down, so we treat that as a request too.
+        let _ = self.0.wait_for(|requested| *requested).await;
+    }
+}
+
+/// Runs the task every `interval` until shutdown is requested.
+pub(crate) fn spawn_periodic_billing_task<F, Fut>(
+    executor: Executor,
+    billing_tasks: &BillingTasks,
+    name: &'static str,
+    interval: Duration,
+    mut task: F,
+) where
+    F: 'static + Send + FnMut(ShutdownSignal) -> Fut,
+    Fut: 'static + Send + Future<Output = ()>,
+{
+    let mut shutdown = billing_tasks.shutdown_signal();
+    executor.spawn_detached({
+        let executor = executor.clone();
+        async move {
+            while !shutdown.is_requested() {
+                task(shutdown.clone()).await;
+
+                futures::select_biased! {
+                    _ = shutdown.requested().fuse() => break,
+                    _ = executor.sleep(interval).fuse() => {}
+                }
+            }
+
+            log::info!("{name}: shut down");
+        }
+    });
+}
+
+/// Polls the Stripe events API periodically to reconcile the records in our
+/// database with the data in Stripe.
+pub fn poll_stripe_events_periodically(
+    app: Arc<AppState>,
+    rpc_server: Arc<Server>,
+    billing_tasks: &BillingTasks,
+) {
+    let Some(real_stripe_client) = app.real_stripe_client.clone() else {
+        log::warn!("failed to retrieve Stripe client");
+        return;
+    };
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::warn!("failed to retrieve Stripe client");
+        return;
+    };
+
+    let backoff = Arc::new(parking_lot::Mutex::new(PollEventsBackoff::default()));
+
+    spawn_periodic_billing_task(
+        app.executor.clone(),
+        billing_tasks,
+        "Stripe events",
+        POLL_EVENTS_INTERVAL,
+        move |mut shutdown| {
+            let app = app.clone();
+            let rpc_server = rpc_server.clone();
+            let stripe_client = stripe_client.clone();
+            let real_stripe_client = real_stripe_client.clone();
+            let backoff = backoff.clone();
+            async move {
+                if app.config.billing_read_only() {
+                    log::info!("Stripe events: paused while billing is read-only");
+                    return;
+                }
+
+                let result = poll_stripe_events(
+                    &app,
+                    &rpc_server,
+                    &stripe_client,
+                    &real_stripe_client,
+                    &shutdown,
+                )
+                .await;
+                let delay = backoff.lock().delay_after(&result);
+                result.log_err();
+
+                // Reconciling would only make more requests to Stripe, so we leave it for after the backoff.
+                if delay > POLL_EVENTS_INTERVAL {
+                    log::warn!(
+                        "Stripe events: rate-limited by Stripe, waiting {delay:?} before polling again"
+                    );
+                    futures::select_biased! {
+                        _ = shutdown.requested().fuse() => {}
+                        _ = app.executor.sleep(delay - POLL_EVENTS_INTERVAL).fuse() => {}
+                    }
+                    return;
+                }
+
+                if shutdown.is_requested() {
+                    return;
+                }
+
+                if let Some(user_ids) = reconcile_overlapping_subscriptions(&app, &stripe_client)
+                    .await
+                    .log_err()
+                {
+                    for user_id in user_ids {
+                        rpc_server.update_plan_for_user(user_id).await.trace_err();
+                    }
+                }
+            }
+        },
+    );
+}
+
+const REMIND_EXPIRING_PAYMENT_METHODS_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Reminds Zed Pro subscribers whose card is about to expire to update it, so that their renewal doesn't fail.
+pub fn remind_users_of_expiring_payment_methods_periodically(
+    app: Arc<AppState>,
+    billing_tasks: &BillingTasks,
+) {
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::warn!("failed to retrieve Stripe client");
+        return;
+    };
+
+    spawn_periodic_billing_task(
+        app.executor.clone(),
+        billing_tasks,
+        "Expiring payment method reminders",
+        REMIND_EXPIRING_PAYMENT_METHODS_INTERVAL,
+        move |shutdown| {
+            let app = app.clone();
+            let stripe_client = stripe_client.clone();
+            async move {
+                if app.config.billing_read_only() {
+                    log::info!(
+                        "Expiring payment method reminders: paused while billing is read-only"
+                    );
+                    return;
+                }
+
+                remind_users_of_expiring_payment_methods(
+                    &app,
+                    &stripe_client,
+                    Utc::now(),
+                    &shutdown,
+                )
+                .await
+                .context("failed to remind users of expiring payment methods")
+                .trace_err();
+            }
+        },
+    );
+}
+
+/// Reminds each Zed Pro subscriber whose card expires soon to update it, at most once per warning period.
+///
+/// Collab can't send email itself, so the reminder is a "Payment Method Expiring Soon" event, which our email
+/// tooling picks up. Returns the users that were reminded.
+pub(crate) async fn remind_users_of_expiring_payment_methods(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    now: DateTime<Utc>,
+    shutdown: &ShutdownSignal,
+) -> anyhow::Result<Vec<UserId>> {
+    let billing_subscriptions = app.db.get_active_zed_pro_billing_subscriptions().await?;
+
+    let mut reminded_user_ids = Vec::new();
+    for (user_id, (billing_customer, _billing_subscription)) in billing_subscriptions {
+        if shutdown.is_requested() {
+            break;
+        }
+
+        let reminded_recently = billing_customer
+            .payment_method_expiry_reminded_at
+            .is_some_and(|reminded_at| {
+                now - reminded_at.and_utc() < chrono::Duration::days(CARD_EXPIRY_WARNING_DAYS)
+            });
+        if reminded_recently {
+            continue;
+        }
+
+        let reminded = maybe!(async {
+            let stripe_customer_id =
+                StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+            let Some(card) = stripe_client
+                .get_default_payment_method(&stripe_customer_id)
+                .await?
+                .and_then(|payment_method| payment_method.card)
+            else {
+                return anyhow::Ok(false);
+            };
+            let Some(expires_at) = card_expires_at(&card) else {
+                return Ok(false);
+            };
+            if !is_card_expiring_soon(&card, now) {
+                return Ok(false);
+            }
+
+            let user = app
+                .db
+                .get_user_by_id(user_id)
+                .await?
+                .context("user not found")?;
+            SnowflakeRow::new(
+                "Payment Method Expiring Soon",
+                Some(user.metrics_id),
+                user.admin,
+                None,
+                json!({
+                    "user_id": user.id,
+                    "github_login": user.github_login,
+                    "email": user.email_address,
+                    "card_brand": card.brand,
+                    "card_last4": card.last4,
+                    "expires_at": expires_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                }),
+            )
+            .with_billing_request_id()
+            .write(&app.kinesis_client, &app.config.kinesis_stream)
+            .await?;
+
+            app.db
+                .update_billing_customer(
+                    billing_customer.id,
+                    &UpdateBillingCustomerParams {
+                        payment_method_expiry_reminded_at: ActiveValue::set(Some(now.naive_utc())),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+
+            Ok(true)
+        })
+        .await
+        .with_context(|| {
+            format!("failed to remind user {user_id} of their expiring payment method")
+        })
+        .log_err();
+
+        if reminded == Some(true) {
+            reminded_user_ids.push(user_id);
+        }
+    }
+    reminded_user_ids.sort();
+
+    Ok(reminded_user_ids)
+}
+
+/// Prunes the records of processed Stripe events periodically, so that the
+/// table stays small and checking for already-processed events stays fast.
+pub fn prune_processed_stripe_events_periodically(
+    app: Arc<AppState>,
+    billing_tasks: &BillingTasks,
+) {
+    spawn_periodic_billing_task(
+        app.executor.clone(),
+        billing_tasks,
+        "Processed Stripe event pruning",
+        PRUNE_PROCESSED_STRIPE_EVENTS_INTERVAL,
+        move |_shutdown| {
+            let app = app.clone();
+            async move {
+                if app.config.billing_read_only() {
+                    log::info!("Processed Stripe event pruning: paused while billing is read-only");
+                    return;
+                }
+
+                prune_processed_stripe_events(&app, Utc::now())
+                    .await
+                    .log_err();
+            }
+        },
+    );
+}
+
+/// Returns how long to keep records of processed Stripe events.
+///
+/// This is never shorter than the stale event age, so we only prune events
+/// that would be skipped as stale if we saw them again.
+pub(crate) fn processed_stripe_event_retention(config: &Config) -> Duration {
+    config
+        .processed_stripe_event_retention_days
+        .map_or(DEFAULT_PROCESSED_STRIPE_EVENT_RETENTION, |days| {
+            Duration::from_secs(u64::from(days) * 24 * 60 * 60)
+        })
+        .max(config.stale_stripe_event_age())
+}
+
+/// Returns when Stripe events become too old to be processed, as of the given time.
+fn stale_stripe_events_before(config: &Config, now: DateTime<Utc>) -> i64 {
+    let stale_age = config.stale_stripe_event_age().as_secs();
+    now.timestamp()
+        .saturating_sub(i64::try_from(stale_age).unwrap_or(i64::MAX))
+}
+
+/// Returns whether the Stripe event is too old to be processed.
+pub(crate) fn is_stale_stripe_event(
+    config: &Config,
+    stripe_event_created_timestamp: i64,
+    now: DateTime<Utc>,
+) -> bool {
+    stale_stripe_events_before(config, now) > stripe_event_created_timestamp
+}
+
+/// Reports a Stripe event that we dropped for being too old to process, so that it can be reconciled by hand.
+async fn report_stale_stripe_event(app: &AppState, event: &stripe::Event) {
+    let event_type = event_type_to_string(event.type_);
+    log::error!(
+        "Stripe events: dropping '{}' ({event_type}), as it is more than {:?} old, it needs to be reconciled by hand",
+        event.id,
+        app.config.stale_stripe_event_age()
+    );
+    billing_metrics().stripe_events_stale_skipped.inc();
+    stale_stripe_event_row(event.id.as_str(), &event_type, event.created)
+        .with_billing_request_id()
+        .write(&app.kinesis_client, &app.config.kinesis_stream)
+        .await
+        .log_err();
+}
+
+/// Deletes the records of processed Stripe events older than the retention window.
+///
+/// Returns the number of records that were deleted.
+pub(crate) async fn prune_processed_stripe_events(
+    app: &Arc<AppState>,
+    now: DateTime<Utc>,
+) -> anyhow::Result<u64> {
+    let cutoff = now - processed_stripe_event_retention(&app.config);
+    let pruned = app
+        .db
+        .delete_processed_stripe_events_created_before(cutoff.timestamp())
+        .await?;
+    log::info!("Processed Stripe event pruning: pruned {pruned} events created before {cutoff}");
+
+    Ok(pruned)
+}
+
+const DETECT_STATUS_DRIFT_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The number of active subscriptions we check against Stripe on each run of the status drift detection.
+const STATUS_DRIFT_SAMPLE_SIZE: usize = 100;
+
+/// Checks a sample of the subscriptions we consider active against Stripe periodically, correcting those that have
+/// ended in Stripe.
+///
+/// This guards against users keeping Zed Pro for free when we miss the event for their subscription's cancellation.
+pub fn detect_status_drift_periodically(
+    app: Arc<AppState>,
+    rpc_server: Arc<Server>,
+    billing_tasks: &BillingTasks,
+) {
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::warn!("failed to retrieve Stripe client");
+        return;
+    };
+
+    spawn_periodic_billing_task(
+        app.executor.clone(),
+        billing_tasks,
+        "Subscription status drift",
+        DETECT_STATUS_DRIFT_INTERVAL,
+        move |shutdown| {
+            let app = app.clone();
+            let rpc_server = rpc_server.clone();
+            let stripe_client = stripe_client.clone();
+            async move {
+                if app.config.billing_read_only() {
+                    log::info!("Subscription status drift: paused while billing is read-only");
+                    return;
+                }
+
+                if let Some(user_ids) =
+                    detect_status_drift(&app, &stripe_client, STATUS_DRIFT_SAMPLE_SIZE, &shutdown)
+                        .await
+                        .log_err()
+                {
+                    for user_id in user_ids {
+                        rpc_server.update_plan_for_user(user_id).await.trace_err();
+                    }
+                }
+            }
+        },
+    );
+}
+
+/// Returns whether the subscription has ended in Stripe, such that it can never become active again.
+fn is_terminal_subscription_status(status: SubscriptionStatus) -> bool {
+    matches!(
+        status,
+        SubscriptionStatus::Canceled | SubscriptionStatus::IncompleteExpired
+    )
+}
+
+/// Checks a random sample of the active Zed Pro and trial subscriptions against Stripe, and syncs the ones that have
+/// ended in Stripe, which downgrades their users.
+///
+/// Returns the IDs of the users whose subscriptions were corrected, so that their plans can be updated.
+pub(crate) async fn detect_status_drift(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    sample_size: usize,
+    shutdown: &ShutdownSignal,
+) -> anyhow::Result<Vec<UserId>> {
+    let billing_subscriptions = app
+        .db
+        .get_active_zed_pro_and_trial_billing_subscriptions()
+        .await?;
+    let sample = billing_subscriptions
+        .into_iter()
+        .choose_multiple(&mut rand::thread_rng(), sample_size);
+
+    let mut corrected_user_ids = Vec::new();
+    for (user_id, (_billing_customer, billing_subscription)) in sample {
+        if shutdown.is_requested() {
+            break;
+        }
+
+        let stripe_subscription_id =
+            StripeSubscriptionId(billing_subscription.stripe_subscription_id.into());
+        let corrected = async {
+            let subscription = stripe_client
+                .get_subscription(&stripe_subscription_id)
+                .await?;
+            if !is_terminal_subscription_status(subscription.status) {
+                return anyhow::Ok(false);
+            }
+
+            log::error!(
+                "subscription {stripe_subscription_id} for user {user_id} is {:?} in Stripe but {:?} in our database, correcting it",
+                subscription.status,
+                billing_subscription.stripe_subscription_status
+            );
+            sync_subscription(app, stripe_client, subscription).await?;
+
+            Ok(true)
+        }
+        .await
+        .with_context(|| {
+            format!("failed to check subscription {stripe_subscription_id} for status drift")
+        })
+        .log_err();
+
+        if corrected == Some(true) {
+            corrected_user_ids.push(user_id);
+        }
+    }
+    corrected_user_ids.sort();
+
+    Ok(corrected_user_ids)
+}
+
+/// Tracks how long to wait between polls of the Stripe events API, backing off exponentially while Stripe is
+/// rate-limiting us.
+///
+/// async-stripe doesn't give us the headers of the response, so we can't honor a `Retry-After`, and rely on the
+/// backoff alone.
+#[derive(Debug, Default)]
+pub(crate) struct PollEventsBackoff {
+    pub(crate) consecutive_rate_limits: u32,
+}
+
+impl PollEventsBackoff {
+    /// Returns how long to wait before the next poll, given the result of the last one.
+    ///
+    /// The backoff only resets after a poll succeeds, so other errors in between rate limits don't cut it short.
+    pub(crate) fn delay_after(&mut self, result: &anyhow::Result<()>) -> Duration {
+        match result {
+            Ok(()) => {
+                self.consecutive_rate_limits = 0;
+                POLL_EVENTS_INTERVAL
+            }
+            Err(error) if is_stripe_rate_limit_error(error) => {
+                self.consecutive_rate_limits = self.consecutive_rate_limits.saturating_add(1);
+                2u32.checked_pow(self.consecutive_rate_limits)
+                    .and_then(|multiplier| POLL_EVENTS_INTERVAL.checked_mul(multiplier))
+                    .map_or(MAX_POLL_EVENTS_BACKOFF, |delay| {
+                        delay.min(MAX_POLL_EVENTS_BACKOFF)
+                    })
+            }
+            Err(_) => POLL_EVENTS_INTERVAL,
+        }
+    }
+}
+
+/// Returns whether the error is Stripe rejecting a request because we exceeded its rate limits.
+fn is_stripe_rate_limit_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|error| {
+        matches!(
+            error.downcast_ref::<stripe::StripeError>(),
+            Some(stripe::StripeError::Stripe(error))
+                if error.http_status == StatusCode::TOO_MANY_REQUESTS.as_u16()
+        )
+    })
+}
+
+/// Returns whether Stripe rejected the request as invalid, e.g., because of a bad parameter.
+fn is_stripe_invalid_request_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|error| {
+        matches!(
+            error.downcast_ref::<stripe::StripeError>(),
+            Some(stripe::StripeError::Stripe(error))
+                if error.http_status == StatusCode::BAD_REQUEST.as_u16()
+        )
+    })
+}
+
+fn event_type_to_string(event_type: EventType) -> String {
+    // Calling `to_string` on `stripe::EventType` members gives us a quoted string,
+    // so we need to unquote it.
+    event_type.to_string().trim_matches('"').to_string()
+}
+
+async fn poll_stripe_events(
+    app: &Arc<AppState>,
+    rpc_server: &Arc<Server>,
+    stripe_client: &Arc<dyn StripeClient>,
+    real_stripe_client: &stripe::Client,
+    shutdown: &ShutdownSignal,
+) -> anyhow::Result<()> {
+    let event_types = HANDLED_STRIPE_EVENT_TYPES
+        .into_iter()
+        .map(event_type_to_string)
+        .collect::<Vec<_>>();
+
+    let now = Utc::now();
+    let mut stale_event_count = 0;
+    let mut pages_of_already_processed_events = 0;
+    let mut event_positions_by_page = Vec::new();
+    let mut done_event_ids = HashSet::default();
+    let mut unprocessed_events = Vec::new();
+
+    let cursor = app.db.get_stripe_event_cursor().await?;
+    let created_since = stripe_events_created_since(&app.config, cursor.as_ref(), now);
+
+    log::info!(
+        "Stripe events: starting retrieval for {} since {created_since}",
+        event_types.join(", ")
+    );
+    let mut params = ListEvents::new();
+    params.types = Some(event_types.clone());
+    params.created = Some(stripe::RangeQuery::gte(created_since));
+    params.limit = Some(EVENTS_LIMIT_PER_PAGE);
+
+    let mut event_pages = stripe::Event::list(&real_stripe_client, &params)
+        .await?
+        .paginate(params);
+
+    // The pages can overlap when new events come in while we're walking them, so we only keep the first copy of
+    // each event.
+    let mut events = Vec::new();
+    let mut seen_event_ids = HashSet::default();
+    loop {
+        event_positions_by_page.push(
+            event_pages
+                .page
+                .data
+                .iter()
+                .map(|event| StripeEventPosition {
+                    id: event.id.to_string(),
+                    created: event.created,
+                })
+                .collect::<Vec<_>>(),
+        );
+        events.extend(
+            event_pages
+                .page
+                .data
+                .iter()
+                .filter(|event| seen_event_ids.insert(event.id.to_string()))
+                .cloned(),
+        );
+
+        // With a cursor, we only retrieve the events since it, so we walk every page of them. Without one (i.e., on
+        // the first poll), we'd walk back through every event that isn't stale yet, so we stop once we've seen
+        // enough pages of events that we've already processed.
+        if cursor.is_none() {
+            let event_ids_in_page = event_pages
+                .page
+                .data
+                .iter()
+                .map(|event| event.id.as_str())
+                .collect::<Vec<_>>();
+            let processed_events_in_page = app
+                .db
+                .get_processed_stripe_events_by_event_ids(&event_ids_in_page)
+                .await?
+                .len();
+            if processed_events_in_page == event_ids_in_page.len() {
+                pages_of_already_processed_events += 1;
+            }
+        }
+
+        if event_pages.page.has_more {
+            if pages_of_already_processed_events >= NUMBER_OF_ALREADY_PROCESSED_PAGES_BEFORE_WE_STOP
+            {
+                log::info!(
+                    "Stripe events: stopping, saw {pages_of_already_processed_events} pages of already-processed events"
+                );
+                break;
+            }
+
+            log::info!("Stripe events: retrieving next page");
+            event_pages = event_pages.next(&real_stripe_client).await?;
+        } else {
+            break;
+        }
+    }
+
+    // We look up which of the events we've already processed all at once, rather than a page at a time.
+    let processed_event_ids = {
+        let event_ids = events
+            .iter()
+            .map(|event| event.id.as_str())
+            .collect::<Vec<_>>();
+        app.db
+            .get_processed_stripe_events_by_event_ids(&event_ids)
+            .await?
+            .into_iter()
+            .map(|event| event.stripe_event_id)
+            .collect::<HashSet<_>>()
+    };
+
+    for event in events {
+        if processed_event_ids.contains(event.id.as_str()) {
+            done_event_ids.insert(event.id.to_string());
+            log::debug!("Stripe events: already processed '{}', skipping", event.id);
+        } else if is_stale_stripe_event(&app.config, event.created, now) {
+            // The records of stale events may have been pruned, so we treat
+            // them as already processed rather than recording them again.
+            done_event_ids.insert(event.id.to_string());
+            report_stale_stripe_event(app, &event).await;
+            stale_event_count += 1;
+        } else {
+            unprocessed_events.push(event);
+        }
+    }
+
+    log::info!("Stripe events: unprocessed {}", unprocessed_events.len());
+
+    let metrics = billing_metrics();
+    metrics
+        .stripe_events_unprocessed
+        .set(unprocessed_events.len() as i64);
+    metrics
+        .stripe_events_pages
+        .set(event_positions_by_page.len() as i64);
+    metrics
+        .stripe_events_oldest_unprocessed_age
+        .set(oldest_stripe_event_age(
+            unprocessed_events.iter().map(|event| event.created),
+            now,
+        ));
+
+    // Sort all of the unprocessed events in ascending order, so we can handle them in the order they occurred.
+    unprocessed_events.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+
+    for event in unprocessed_events {
+        if shutdown.is_requested() {
+            log::info!("Stripe events: shutting down, remaining events will be processed later");
+            break;
+        }
+
+        let event_id = event.id.clone();
+        let processed_event_params = CreateProcessedStripeEventParams {
+            stripe_event_id: event.id.to_string(),
+            stripe_event_type: event_type_to_string(event.type_),
+            stripe_event_created_timestamp: event.created,
+        };
+
+        // If the event has happened too far in the past, we don't want to
+        // process it and risk overwriting other more-recent updates.
+        if is_stale_stripe_event(&app.config, event.created, Utc::now()) {
+            report_stale_stripe_event(app, &event).await;
+            stale_event_count += 1;
+            app.db
+                .create_processed_stripe_event(&processed_event_params)
+                .await?;
+            done_event_ids.insert(event_id.to_string());
+
+            continue;
+        }
+
+        if process_stripe_event(app, rpc_server, stripe_client, real_stripe_client, event)
+            .await
+            .log_err()
+            .is_some()
+        {
+            done_event_ids.insert(event_id.to_string());
+        }
+    }
+
+    if let Some(position) = advanced_stripe_event_cursor(&event_positions_by_page, &done_event_ids)
+    {
+        app.db
+            .update_stripe_event_cursor(&UpdateStripeEventCursorParams {
+                stripe_event_id: position.id.clone(),
+                stripe_event_created_timestamp: position.created,
+            })
+            .await?;
+    }
+
+    metrics.stripe_events_stale_last_poll.set(stale_event_count);
+    metrics
+        .stripe_events_last_polled_at
+        .set(Utc::now().timestamp());
+
+    Ok(())
+}
+
+/// Returns the age in seconds of the oldest of the events created at the given Unix timestamps, or 0 if there are
+/// none.
+pub(crate) fn oldest_stripe_event_age(
+    created: impl IntoIterator<Item = i64>,
+    now: DateTime<Utc>,
+) -> i64 {
+    created
+        .into_iter()
+        .min()
+        .map_or(0, |created| (now.timestamp() - created).max(0))
+}
+
+/// Where a Stripe event falls in the list of events.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StripeEventPosition {
+    pub(crate) id: String,
+    pub(crate) created: i64,
+}
+
+/// Returns the creation time of the oldest Stripe events that we need to retrieve.
+///
+/// We resume from the cursor, without looking back any further than the events that are too old to process. Events
+/// created in the same second as the cursor are retrieved again, and skipped if they've already been processed.
+pub(crate) fn stripe_events_created_since(
+    config: &Config,
+    cursor: Option<&stripe_event_cursor::Model>,
+    now: DateTime<Utc>,
+) -> i64 {
+    let stale_before = stale_stripe_events_before(config, now);
+    cursor.map_or(stale_before, |cursor| {
+        cursor.stripe_event_created_timestamp.max(stale_before)
+    })
+}
+
+/// Returns the event to move the cursor to, given the pages of events that we retrieved (newest first) and the IDs
+/// of the events that we're done with.
+///
+/// The cursor only moves past a page once every event in it, and in every older page, is done. That way, an event
+/// that failed or that we didn't get to (e.g., because of a crash partway through a page) is retrieved again on the
+/// next poll.
+pub(crate) fn advanced_stripe_event_cursor<'a>(
+    event_positions_by_page: &'a [Vec<StripeEventPosition>],
+    done_event_ids: &HashSet<String>,
+) -> Option<&'a StripeEventPosition> {
+    let mut cursor = None;
+    for page in event_positions_by_page.iter().rev() {
+        if !page
+            .iter()
+            .all(|position| done_event_ids.contains(&position.id))
+        {
+            break;
+        }
+
+        if let Some(newest) = page.iter().max_by_key(|position| position.created) {
+            cursor = Some(newest);
+        }
+    }
+
+    cursor
+}
+
+/// Processes a single Stripe event, recording whether it was processed successfully.
+///
+/// Failed events are recorded with their error, so that they can be inspected and retried later.
+async fn process_stripe_event(
+    app: &Arc<AppState>,
+    rpc_server: &Arc<Server>,
+    stripe_client: &Arc<dyn StripeClient>,
+    real_stripe_client: &stripe::Client,
+    event: stripe::Event,
+) -> anyhow::Result<()> {
+    let event_id = event.id.clone();
+    let processed_event_params = CreateProcessedStripeEventParams {
+        stripe_event_id: event.id.to_string(),
+        stripe_event_type: event_type_to_string(event.type_),
+        stripe_event_created_timestamp: event.created,
+    };
+
+    let api_version_mismatch = stripe_api_version_mismatch(
+        event.api_version.as_ref().map(|version| version.as_str()),
+        stripe::VERSION.as_str(),
+    );
+    if let Some(mismatch) = &api_version_mismatch {
+        log::warn!("Stripe events: event '{event_id}' {mismatch}");
+    }
+
+    let process_result = match event.type_ {
+        EventType::CustomerCreated | EventType::CustomerUpdated => {
+            handle_customer_event(app, real_stripe_client, event).await
+        }
+        EventType::CustomerSubscriptionCreated
+        | EventType::CustomerSubscriptionUpdated
+        | EventType::CustomerSubscriptionPaused
+        | EventType::CustomerSubscriptionResumed
+        | EventType::CustomerSubscriptionDeleted => {
+            handle_customer_subscription_event(app, rpc_server, stripe_client, event).await
+        }
+        EventType::InvoicePaymentSucceeded | EventType::InvoicePaymentFailed => {
+            handle_invoice_event(app, rpc_server, stripe_client, event).await
+        }
+        _ => Ok(()),
+    };
+
+    let process_result = match api_version_mismatch {
+        Some(mismatch) => process_result.context(mismatch),
+        None => process_result,
+    };
+
+    match process_result.with_context(|| format!("failed to process event {event_id} successfully"))
+    {
+        Ok(()) => {
+            app.db
+                .create_processed_stripe_event(&processed_event_params)
+                .await?;
+            billing_metrics().stripe_events_processed.inc();
+
+            Ok(())
+        }
+        Err(error) => {
+            billing_metrics().stripe_events_failed.inc();
+            app.db
+                .record_failed_stripe_event(&RecordFailedStripeEventParams {
+                    stripe_event_id: processed_event_params.stripe_event_id,
+                    stripe_event_type: processed_event_params.stripe_event_type,
+                    stripe_event_created_timestamp: processed_event_params
+                        .stripe_event_created_timestamp,
+                    error: format!("{error:#}"),
+                })
+                .await
+                .log_err();
+
+            Err(error)
+        }
+    }
+}
+
+/// Returns a description of the mismatch if an event was rendered with a
+/// different Stripe API version than the one the `stripe` crate expects.
+///
+/// Events from before the account's API version was upgraded (or after, if the
+/// crate is lagging behind) may not deserialize into the shapes we expect.
+pub(crate) fn stripe_api_version_mismatch(
+    event_api_version: Option<&str>,
+    expected_api_version: &str,
+) -> Option<String> {
+    let event_api_version = event_api_version?;
+    if event_api_version == expected_api_version {
+        return None;
+    }
+
+    Some(format!(
+        "has Stripe API version {event_api_version}, but we expect {expected_api_version}"
+    ))
+}
+
+async fn handle_customer_event(
+    app: &Arc<AppState>,
+    _stripe_client: &stripe::Client,
+    event: stripe::Event,
+) -> anyhow::Result<()> {
+    let EventObject::Customer(customer) = event.data.object else {
+        bail!("unexpected event payload for {}", event.id);
+    };
+
+    log::info!("handling Stripe {} event: {}", event.type_, event.id);
+
+    reconcile_stripe_customer(app, customer.id.as_str(), customer.email.as_deref()).await
+}
+
+/// Brings the billing customer for the Stripe customer in line with the customer's email address in Stripe.
+///
+/// We never move a billing customer to another user on our own, as that would move their subscription with it.
+/// Instead, when the email address now belongs to a different user, we flag the billing customer so that support can
+/// sort it out. When the email address doesn't belong to any user, we record a pending link for the Stripe customer.
+pub(crate) async fn reconcile_stripe_customer(
+    app: &AppState,
+    stripe_customer_id: &str,
+    email: Option<&str>,
+) -> anyhow::Result<()> {
+    let Some(email) = email else {
+        log::info!("Stripe customer has no email: skipping");
+        return Ok(());
+    };
+
+    let user = app.db.get_user_by_email(email).await?;
+    let existing_customer = app
+        .db
+        .get_billing_customer_by_stripe_customer_id(stripe_customer_id)
+        .await?;
+
+    match (existing_customer, user) {
+        (Some(existing_customer), user) => {
+            let stripe_email_user_mismatch = user
+                .as_ref()
+                .is_some_and(|user| user.id != existing_customer.user_id);
+            if let Some(user) = user.filter(|_| stripe_email_user_mismatch) {
+                log::warn!(
+                    "email of Stripe customer {stripe_customer_id} belongs to user {}, but they are billed as user {}",
+                    user.id,
+                    existing_customer.user_id
+                );
+            }
+
+            app.db
+                .update_billing_customer(
+                    existing_customer.id,
+                    &UpdateBillingCustomerParams {
+                        stripe_email: ActiveValue::set(Some(email.to_string())),
+                        stripe_email_user_mismatch: ActiveValue::set(stripe_email_user_mismatch),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+        }
+        (None, Some(user)) => {
+            let billing_customer = app
+                .db
+                .create_billing_customer(&CreateBillingCustomerParams {
+                    user_id: user.id,
+                    stripe_customer_id: stripe_customer_id.to_string(),
+                })
+                .await?;
+            app.db
+                .update_billing_customer(
+                    billing_customer.id,
+                    &UpdateBillingCustomerParams {
+                        stripe_email: ActiveValue::set(Some(email.to_string())),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+            app.db
+                .delete_pending_billing_customer_link(stripe_customer_id)
+                .await?;
+        }
+        (None, None) => {
+            log::info!("no user found for email: recording pending link");
+            app.db
+                .record_pending_billing_customer_link(stripe_customer_id, email)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Cancels a duplicate subscription and refunds the charge for it.
+async fn cancel_and_refund_duplicate_subscription(
+    stripe_client: &Arc<dyn StripeClient>,
+    user_id: UserId,
+    existing_subscription: &billing_subscription::Model,
+    duplicate_subscription_id: &StripeSubscriptionId,
+) -> anyhow::Result<()> {
+    // The event may be replayed, including after we canceled the subscription but failed to refund it, so we check
+    // the subscription's current state and its payment's to avoid canceling or refunding it more than once.
+    let duplicate_subscription = stripe_client
+        .get_subscription(duplicate_subscription_id)
+        .await?;
+    if duplicate_subscription.status == SubscriptionStatus::Canceled {
+        log::info!(
+            "duplicate subscription {duplicate_subscription_id} for user {user_id} was already canceled"
+        );
+    } else {
+        stripe_client
+            .cancel_subscription(
+                duplicate_subscription_id,
+                Some(&format!("cancel-duplicate-{duplicate_subscription_id}")),
+            )
+            .await?;
+    }
+
+    let already_refunded = stripe_client
+        .get_latest_invoice_payment(duplicate_subscription_id)
+        .await?
+        .is_some_and(|payment| payment.amount_refunded >= payment.amount_paid);
+    if already_refunded {
+        log::info!(
+            "duplicate subscription {duplicate_subscription_id} for user {user_id} was already refunded"
+        );
+        return Ok(());
+    }
+
+    let refund = stripe_client
+        .refund_latest_invoice_payment(
+            duplicate_subscription_id,
+            Some(&format!("refund-duplicate-{duplicate_subscription_id}")),
+        )
+        .await?;
+
+    log::error!(
+        "user {user_id} completed checkout for a duplicate subscription {duplicate_subscription_id}; canceled it in favor of {existing_subscription_id} and refunded {refunded} cents",
+        existing_subscription_id = existing_subscription.stripe_subscription_id,
+        refunded = refund.map_or(0, |refund| refund.amount),
+    );
+
+    Ok(())
+}
+
+const BILLING_DB_WRITE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Performs a billing database write, retrying it if it fails with a transient error.
+///
+/// This lets us ride out a database blip without reprocessing a whole Stripe
+/// event, which would also repeat the event's side effects in Stripe.
+pub(crate) async fn retry_billing_db_write<F, Fut, T>(
+    app: &AppState,
+    description: &str,
+    mut write: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let retries = app.config.billing_db_write_retries();
+    let mut attempt = 0;
+    loop {
+        match write().await {
+            Ok(value) => return Ok(value),
+            Err(error) if attempt < retries && is_retryable_db_error(&error) => {
+                attempt += 1;
+                log::warn!("failed to {description}, retrying ({attempt}/{retries}): {error:?}");
+                app.executor
+                    .sleep(BILLING_DB_WRITE_RETRY_DELAY * attempt)
+                    .await;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Returns whether the database error is transient, such that the same write could succeed if retried.
+fn is_retryable_db_error(error: &Error) -> bool {
+    let Error::Database(error) = error else {
+        return false;
+    };
+
+    match error {
+        DbErr::ConnectionAcquire(_) | DbErr::Conn(_) => true,
+        DbErr::Exec(RuntimeErr::SqlxError(error)) | DbErr::Query(RuntimeErr::SqlxError(error)) => {
+            match error {
+                sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+                // Serialization failures and deadlocks are resolved by running the transaction again.
+                sqlx::Error::Database(error) => {
+                    matches!(error.code().as_deref(), Some("40001" | "40P01"))
+                }
+                _ => false,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Returns whether a subscription with the given status has stopped giving the user their plan, so that they should be
+/// subscribed to Zed Free instead.
+///
+/// Past-due subscriptions are left alone, as Stripe is still retrying their payment and they can recover. Stripe only
+/// marks a subscription as unpaid once it has given up on collecting the payment.
+pub(crate) fn falls_back_to_free(status: SubscriptionStatus) -> bool {
+    match status {
+        SubscriptionStatus::Canceled
+        | SubscriptionStatus::Paused
+        | SubscriptionStatus::Unpaid
+        | SubscriptionStatus::IncompleteExpired => true,
+        SubscriptionStatus::Active
+        | SubscriptionStatus::Trialing
+        | SubscriptionStatus::PastDue
+        | SubscriptionStatus::Incomplete => false,
+    }
+}
+
+pub(crate) async fn sync_subscription(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    subscription: StripeSubscription,
+) -> anyhow::Result<billing_customer::Model> {
+    // Stripe leaves a subscription active while collecting payment for it is paused, but the user mustn't keep their
+    // plan while they aren't paying for it.
+    let subscription = if subscription.pause_collection.is_some()
+        && subscription.status == SubscriptionStatus::Active
+    {
+        StripeSubscription {
+            status: SubscriptionStatus::Paused,
+            ..subscription
+        }
+    } else {
+        subscription
+    };
+
+    let subscription_kind = if let Some(stripe_billing) = &app.stripe_billing {
+        stripe_billing
+            .determine_subscription_kind(&subscription)
+            .await
+    } else {
+        None
+    };
+
+    let billing_customer =
+        find_or_create_billing_customer(app, stripe_client.as_ref(), &subscription.customer)
+            .await?
+            .context("billing customer not found")?;
+
+    // Stripe bills all of a customer's subscriptions in the same currency, so we keep it on the customer.
+    let billing_customer = if billing_customer.currency != subscription.currency {
+        app.db
+            .update_billing_customer(
+                billing_customer.id,
+                &UpdateBillingCustomerParams {
+                    currency: ActiveValue::set(subscription.currency.clone()),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        billing_customer::Model {
+            currency: subscription.currency.clone(),
+            ..billing_customer
+        }
+    } else {
+        billing_customer
+    };
+
+    // A subscription that we can't classify would bypass all of the plan logic, so we give it the configured plan
+    // instead, and flag it so that it can be looked into.
+    let unclassified = subscription_kind.is_none() && app.stripe_billing.is_some();
+    let subscription_kind = if unclassified {
+        let plan = app
+            .config
+            .unclassified_subscription_plan
+            .unwrap_or_default();
+        log::warn!(
+            "could not determine the kind of subscription {subscription_id} for user {user_id} from its prices {price_ids:?}, treating it as {plan:?}",
+            subscription_id = subscription.id,
+            user_id = billing_customer.user_id,
+            price_ids = subscription_price_ids(&subscription),
+        );
+
+        if app.config.alert_on_unclassified_subscriptions() {
+            if let Some(user) = app.db.get_user_by_id(billing_customer.user_id).await? {
+                unclassified_subscription_row(&user, &subscription, plan)
+                    .with_billing_request_id()
+                    .write(&app.kinesis_client, &app.config.kinesis_stream)
+                    .await
+                    .log_err();
+            }
+        }
+
+        plan.subscription_kind()
+    } else {
+        subscription_kind
+    };
+
+    if let Some(SubscriptionKind::ZedProTrial) = subscription_kind {
+        if subscription.status == SubscriptionStatus::Trialing {
+            let current_period_start =
+                DateTime::from_timestamp(subscription.current_period_start, 0)
+                    .context("No trial subscription period start")?;
+
+            app.db
+                .update_billing_customer(
+                    billing_customer.id,
+                    &UpdateBillingCustomerParams {
+                        trial_started_at: ActiveValue::set(Some(current_period_start.naive_utc())),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+        }
+    }
+
+    let was_canceled_due_to_payment_failure = subscription.status == SubscriptionStatus::Canceled
+        && subscription
+            .cancellation_details
+            .as_ref()
+            .and_then(|details| details.reason)
+            .map_or(false, |reason| {
+                reason == StripeCancellationDetailsReason::PaymentFailed
+            });
+
+    if was_canceled_due_to_payment_failure {
+        app.db
+            .update_billing_customer(
+                billing_customer.id,
+                &UpdateBillingCustomerParams {
+                    has_overdue_invoices: ActiveValue::set(true),
+                    ..Default::default()
+                },
+            )
+            .await?;
+    }
+
+    let existing_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id(subscription.id.0.as_ref())
+        .await?;
+    let was_trial = existing_subscription
+        .as_ref()
+        .map_or(false, |subscription| {
+            subscription.kind == Some(SubscriptionKind::ZedProTrial)
+        });
+
+    // A trial that gets canceled never turned into a Zed Pro subscription, so
+    // we keep recording it as a trial.
+    let subscription_kind = if was_trial && subscription.status == SubscriptionStatus::Canceled {
+        Some(SubscriptionKind::ZedProTrial)
+    } else {
+        subscription_kind
+    };
+
+    // Users can switch a subscription from a paid plan to Zed Free without canceling it, in which case the
+    // subscription stays active and only its kind changes.
+    let downgraded_from = existing_subscription
+        .as_ref()
+        .and_then(|existing_subscription| existing_subscription.kind)
+        .filter(|kind| {
+            matches!(
+                kind,
+                SubscriptionKind::ZedPro | SubscriptionKind::ZedProTrial
+            )
+        })
+        .filter(|_| subscription_kind == Some(SubscriptionKind::ZedFree));
+
+    // A subscription only returns to active once Stripe has collected what was owed on it, so the customer is no
+    // longer overdue. Downgrades to Zed Free clear the flag themselves, as part of reporting the downgrade, and
+    // resuming a paused subscription doesn't collect anything.
+    let returned_to_active = subscription.status == SubscriptionStatus::Active
+        && existing_subscription
+            .as_ref()
+            .is_some_and(|existing_subscription| {
+                !matches!(
+                    existing_subscription.stripe_subscription_status,
+                    StripeSubscriptionStatus::Active | StripeSubscriptionStatus::Paused
+                )
+            });
+    let billing_customer = if billing_customer.has_overdue_invoices
+        && returned_to_active
+        && downgraded_from.is_none()
+    {
+        log::info!(
+            "subscription {subscription_id} for user {user_id} returned to active, clearing overdue invoices",
+            subscription_id = subscription.id,
+            user_id = billing_customer.user_id,
+        );
+        app.db
+            .update_billing_customer(
+                billing_customer.id,
+                &UpdateBillingCustomerParams {
+                    has_overdue_invoices: ActiveValue::set(false),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        billing_customer::Model {
+            has_overdue_invoices: false,
+            ..billing_customer
+        }
+    } else {
+        billing_customer
+    };
+
+    let seats = subscription_seats(&subscription);
+    let monthly_recurring_revenue_in_cents =
+        effective_monthly_cost_in_cents(&subscription, Utc::now());
+
+    // We keep when the subscription ended, so that revenue as of an earlier time still includes it. Subscriptions that
+    // were scheduled to be canceled end at that time, and others end when we find out they were canceled.
+    let ended_at = (subscription.status == SubscriptionStatus::Canceled).then(|| {
+        existing_subscription
+            .as_ref()
+            .and_then(|subscription| subscription.ended_at)
+            .unwrap_or_else(|| {
+                let now = Utc::now();
+                subscription
+                    .cancel_at
+                    .and_then(|cancel_at| DateTime::from_timestamp(cancel_at, 0))
+                    .filter(|cancel_at| *cancel_at <= now)
+                    .unwrap_or(now)
+                    .naive_utc()
+            })
+    });
+
+    // We only report a cancellation when the subscription first becomes canceled, rather than on every sync of it
+    // afterwards. The row is built up front, as the cancellation details are moved into the update below.
+    let canceled_row = if subscription.status == SubscriptionStatus::Canceled {
+        app.db
+            .get_user_by_id(billing_customer.user_id)
+            .await?
+            .and_then(|user| {
+                subscription_canceled_row(
+                    &user,
+                    existing_subscription
+                        .as_ref()
+                        .map(|subscription| subscription.stripe_subscription_status),
+                    &subscription,
+                    subscription_kind,
+                )
+            })
+    } else {
+        None
+    };
+
+    if let Some(existing_subscription) = existing_subscription {
+        // We keep the time the subscription first became past due, so that later syncs don't extend the grace period.
+        let past_due_since = if subscription.status == SubscriptionStatus::PastDue {
+            let past_due_since = existing_subscription.past_due_since.unwrap_or_else(|| {
+                log::info!(
+                    "subscription {subscription_id} for user {user_id} became past due",
+                    subscription_id = subscription.id,
+                    user_id = billing_customer.user_id,
+                );
+                Utc::now().naive_utc()
+            });
+            Some(past_due_since)
+        } else {
+            None
+        };
+
+        // The user was moved to Zed Free while the subscription was paused, so the subscription takes over from it
+        // again once it resumes.
+        if existing_subscription.stripe_subscription_status == StripeSubscriptionStatus::Paused
+            && subscription.status == SubscriptionStatus::Active
+            && subscription_kind != Some(SubscriptionKind::ZedFree)
+        {
+            if let Some(free_subscription) = app
+                .db
+                .get_active_billing_subscription(billing_customer.user_id)
+                .await?
+                .filter(|subscription| subscription.kind == Some(SubscriptionKind::ZedFree))
+            {
+                let stripe_subscription_id =
+                    StripeSubscriptionId(free_subscription.stripe_subscription_id.into());
+                stripe_client
+                    .cancel_subscription(&stripe_subscription_id, None)
+                    .await?;
+            }
+        }
+
+        let params = UpdateBillingSubscriptionParams {
+            billing_customer_id: ActiveValue::set(billing_customer.id),
+            kind: ActiveValue::set(subscription_kind),
+            unclassified: ActiveValue::set(unclassified),
+            stripe_subscription_id: ActiveValue::set(subscription.id.to_string()),
+            stripe_subscription_status: ActiveValue::set(subscription.status.into()),
+            stripe_cancel_at: ActiveValue::set(
+                subscription
+                    .cancel_at
+                    .and_then(|cancel_at| DateTime::from_timestamp(cancel_at, 0))
+                    .map(|time| time.naive_utc()),
+            ),
+            stripe_cancel_at_period_end: ActiveValue::set(subscription.cancel_at_period_end),
+            stripe_cancellation_reason: ActiveValue::set(
+                subscription
+                    .cancellation_details
+                    .and_then(|details| details.reason)
+                    .map(|reason| reason.into()),
+            ),
+            stripe_current_period_start: ActiveValue::set(Some(subscription.current_period_start)),
+            stripe_current_period_end: ActiveValue::set(Some(subscription.current_period_end)),
+            past_due_since: ActiveValue::set(past_due_since),
+            seats: ActiveValue::set(seats),
+            monthly_recurring_revenue_in_cents: ActiveValue::set(
+                monthly_recurring_revenue_in_cents,
+            ),
+            ended_at: ActiveValue::set(ended_at),
+        };
+        retry_billing_db_write(app, "update billing subscription", || {
+            app.db
+                .update_billing_subscription(existing_subscription.id, &params)
+        })
+        .await?;
+
+        if let Some(previous_kind) = downgraded_from {
+            handle_plan_downgrade(app, &billing_customer, &subscription, previous_kind).await?;
+        }
+    } else {
+        if let Some(existing_subscription) = app
+            .db
+            .get_active_billing_subscription(billing_customer.user_id)
+            .await?
+        {
+            if existing_subscription.kind == Some(SubscriptionKind::ZedFree)
+                && subscription_kind == Some(SubscriptionKind::ZedProTrial)
+            {
+                let stripe_subscription_id = StripeSubscriptionId(
+                    existing_subscription.stripe_subscription_id.clone().into(),
+                );
+
+                stripe_client
+                    .cancel_subscription(&stripe_subscription_id, None)
+                    .await?;
+            } else if existing_subscription.kind == Some(SubscriptionKind::ZedPro)
+                && subscription_kind == Some(SubscriptionKind::ZedPro)
+                && subscription.status == SubscriptionStatus::Active
+                && app.config.duplicate_checkout_behavior.unwrap_or_default()
+                    == DuplicateCheckoutBehavior::CancelAndRefund
+            {
+                // The user paid for Zed Pro twice (e.g., by completing checkout in two
+                // tabs), so we keep the subscription we already have and undo the
+                // newer one.
+                cancel_and_refund_duplicate_subscription(
+                    stripe_client,
+                    billing_customer.user_id,
+                    &existing_subscription,
+                    &subscription.id,
+                )
+                .await?;
+                return Ok(billing_customer);
+            } else {
+                // If the user already has an active billing subscription, ignore the
+                // event and return an `Ok` to signal that it was processed
+                // successfully.
+                //
+                // There is the possibility that this could cause us to not create a
+                // subscription in the following scenario:
+                //
+                //   1. User has an active subscription A
+                //   2. User cancels subscription A
+                //   3. User creates a new subscription B
+                //   4. We process the new subscription B before the cancellation of subscription A
+                //   5. User ends up with no subscriptions
+                //
+                // In theory this situation shouldn't arise as we try to process the events in the order they occur.
+
+                log::info!(
+                    "user {user_id} already has an active subscription, skipping creation of subscription {subscription_id}",
+                    user_id = billing_customer.user_id,
+                    subscription_id = subscription.id
+                );
+                return Ok(billing_customer);
+            }
+        }
+
+        let params = CreateBillingSubscriptionParams {
+            billing_customer_id: billing_customer.id,
+            kind: subscription_kind,
+            unclassified,
+            stripe_subscription_id: subscription.id.to_string(),
+            stripe_subscription_status: subscription.status.into(),
+            stripe_cancellation_reason: subscription
+                .cancellation_details
+                .and_then(|details| details.reason)
+                .map(|reason| reason.into()),
+            stripe_current_period_start: Some(subscription.current_period_start),
+            stripe_current_period_end: Some(subscription.current_period_end),
+            seats,
+            monthly_recurring_revenue_in_cents,
+            ended_at,
+        };
+        // If a retried insert had in fact been committed, the retry fails on the unique
+        // Stripe subscription ID, and reprocessing the event updates the subscription instead.
+        retry_billing_db_write(app, "create billing subscription", || {
+            app.db.create_billing_subscription(&params)
+        })
+        .await?;
+    }
+
+    if let Some(canceled_row) = canceled_row {
+        canceled_row
+            .with_billing_request_id()
+            .write(&app.kinesis_client, &app.config.kinesis_stream)
+            .await
+            .log_err();
+    }
+
+    if let Some(stripe_billing) = app.stripe_billing.as_ref() {
+        if falls_back_to_free(subscription.status) {
+            let already_has_active_billing_subscription = app
+                .db
+                .has_active_billing_subscription(billing_customer.user_id)
+                .await?;
+            let blocks_access_after_trial = was_trial
+                && app.config.trial_end_behavior.unwrap_or_default()
+                    == TrialEndBehavior::BlockAccess;
+
+            if blocks_access_after_trial {
+                log::info!(
+                    "trial subscription {subscription_id} for user {user_id} ended, not subscribing them to Zed Free",
+                    subscription_id = subscription.id,
+                    user_id = billing_customer.user_id,
+                );
+            } else if !already_has_active_billing_subscription {
+                let free_downgrade_delay = app.config.free_downgrade_delay();
+                if free_downgrade_delay.is_zero() {
+                    let stripe_customer_id =
+                        StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+
+                    stripe_billing
+                        .subscribe_to_zed_free(stripe_customer_id)
+                        .await?;
+                } else if let Some(free_downgrade_at) = billing_customer.free_downgrade_at {
+                    // The subscription is synced again on replays and drift checks, which mustn't postpone the
+                    // downgrade.
+                    log::info!(
+                        "subscription {subscription_id} for user {user_id} ended, already subscribing them to Zed Free at {free_downgrade_at}",
+                        subscription_id = subscription.id,
+                        user_id = billing_customer.user_id,
+                    );
+                } else {
+                    log::info!(
+                        "subscription {subscription_id} for user {user_id} ended, subscribing them to Zed Free in {free_downgrade_delay:?} unless they reactivate",
+                        subscription_id = subscription.id,
+                        user_id = billing_customer.user_id,
+                    );
+                    let free_downgrade_at =
+                        Utc::now() + chrono::Duration::from_std(free_downgrade_delay)?;
+                    app.db
+                        .update_billing_customer(
+                            billing_customer.id,
+                            &UpdateBillingCustomerParams {
+                                free_downgrade_at: ActiveValue::set(Some(
+                                    free_downgrade_at.naive_utc(),
+                                )),
+                                ..Default::default()
+                            },
+                        )
+                        .await?;
+                }
+            }
+        }
+    }
+
+    Ok(billing_customer)
+}
+
+/// Handles a subscription that was switched from a paid plan to Zed Free in place, rather than canceled.
+///
+/// The subscription becomes the user's Zed Free subscription, so we don't subscribe them to Zed Free again. We keep
+/// their `trial_started_at`, so that downgrading doesn't make them eligible for another trial.
+async fn handle_plan_downgrade(
+    app: &AppState,
+    billing_customer: &billing_customer::Model,
+    subscription: &StripeSubscription,
+    previous_kind: SubscriptionKind,
+) -> anyhow::Result<()> {
+    log::info!(
+        "subscription {subscription_id} for user {user_id} was downgraded from {previous_kind:?} to Zed Free",
+        subscription_id = subscription.id,
+        user_id = billing_customer.user_id,
+    );
+
+    // Zed Free doesn't cost anything, so once the subscription is in good standing there's nothing left to collect.
+    let cleared_overdue_invoices =
+        billing_customer.has_overdue_invoices && subscription.status == SubscriptionStatus::Active;
+    if cleared_overdue_invoices {
+        app.db
+            .update_billing_customer(
+                billing_customer.id,
+                &UpdateBillingCustomerParams {
+                    has_overdue_invoices: ActiveValue::set(false),
+                    ..Default::default()
+                },
+            )
+            .await?;
+    }
+
+    if let Some(user) = app.db.get_user_by_id(billing_customer.user_id).await? {
+        plan_downgraded_row(&user, subscription, previous_kind, cleared_overdue_invoices)
+            .with_billing_request_id()
+            .write(&app.kinesis_client, &app.config.kinesis_stream)
+            .await
+            .log_err();
+    }
+
+    Ok(())
+}
+
+const APPLY_FREE_DOWNGRADES_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Subscribes customers to Zed Free once their downgrade delay has passed.
+///
+/// The time of each pending downgrade is stored on the billing customer, so downgrades that are due while collab is
+/// restarting are applied once it's back.
+pub fn apply_free_downgrades_periodically(app: Arc<AppState>, billing_tasks: &BillingTasks) {
+    spawn_periodic_billing_task(
+        app.executor.clone(),
+        billing_tasks,
+        "Zed Free downgrades",
+        APPLY_FREE_DOWNGRADES_INTERVAL,
+        move |shutdown| {
+            let app = app.clone();
+            async move {
+                if app.config.billing_read_only() {
+                    log::info!("Zed Free downgrades: paused while billing is read-only");
+                    return;
+                }
+
+                apply_free_downgrades(&app, Utc::now(), &shutdown)
+                    .await
+                    .context("failed to apply Zed Free downgrades")
+                    .trace_err();
+            }
+        },
+    );
+}
+
+/// Subscribes each customer whose downgrade is due to Zed Free, unless they've reactivated by then.
+///
+/// Returns the users that were subscribed to Zed Free.
+pub(crate) async fn apply_free_downgrades(
+    app: &AppState,
+    now: DateTime<Utc>,
+    shutdown: &ShutdownSignal,
+) -> anyhow::Result<Vec<UserId>> {
+    let billing_customers = app
+        .db
+        .get_billing_customers_with_free_downgrade_due(now.naive_utc())
+        .await?;
+
+    let mut downgraded_user_ids = Vec::new();
+    for billing_customer in billing_customers {
+        if shutdown.is_requested() {
+            break;
+        }
+
+        let downgraded = downgrade_to_free_unless_reactivated(app, &billing_customer)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to subscribe user {} to Zed Free",
+                    billing_customer.user_id
+                )
+            })
+            .log_err();
+        if downgraded == Some(true) {
+            downgraded_user_ids.push(billing_customer.user_id);
+        }
+    }
+    downgraded_user_ids.sort();
+
+    Ok(downgraded_user_ids)
+}
+
+/// Subscribes the customer to Zed Free and clears their pending downgrade. Returns whether they were subscribed.
+///
+/// The downgrade is only cleared once the customer is subscribed, so a failed attempt is retried on the next run.
+/// Subscribing to Zed Free is idempotent, so retrying after the subscription was created is safe.
+async fn downgrade_to_free_unless_reactivated(
+    app: &AppState,
+    billing_customer: &billing_customer::Model,
+) -> anyhow::Result<bool> {
+    let reactivated = app
+        .db
+        .has_active_billing_subscription(billing_customer.user_id)
+        .await?;
+    if reactivated {
+        log::info!(
+            "user {} reactivated their subscription, not subscribing them to Zed Free",
+            billing_customer.user_id
+        );
+    } else {
+        let stripe_billing = app
+            .stripe_billing
+            .as_ref()
+            .context("failed to retrieve Stripe billing object")?;
+        let stripe_customer_id =
+            StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+        stripe_billing
+            .subscribe_to_zed_free(stripe_customer_id)
+            .await?;
+    }
+
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                free_downgrade_at: ActiveValue::set(None),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    Ok(!reactivated)
+}
+
+/// Returns whether a user without an active subscription should be denied
+/// access because their trial has ended.
+///
+/// This is only the case when the [`TrialEndBehavior`] is to block access;
+/// otherwise users are moved to Zed Free when their trial ends. Users whose
+/// most recent subscription isn't the trial went on to pay after it, so they
+/// aren't blocked once that subscription ends.
+pub fn is_blocked_after_trial(
+    config: &Config,
+    latest_subscription: Option<&billing_subscription::Model>,
+) -> bool {
+    config.trial_end_behavior.unwrap_or_default() == TrialEndBehavior::BlockAccess
+        && latest_subscription.is_some_and(|subscription| {
+            subscription.kind == Some(SubscriptionKind::ZedProTrial)
+                && !subscription.stripe_subscription_status.is_cancelable()
+        })
+}
+
+async fn handle_customer_subscription_event(
+    app: &Arc<AppState>,
+    rpc_server: &Arc<Server>,
+    stripe_client: &Arc<dyn StripeClient>,
+    event: stripe::Event,
+) -> anyhow::Result<()> {
+    let EventObject::Subscription(subscription) = event.data.object else {
+        bail!("unexpected event payload for {}", event.id);
+    };
+
+    log::info!("handling Stripe {} event: {}", event.type_, event.id);
+
+    let stripe_subscription_id = subscription.id.to_string();
+    let billing_customer = sync_subscription(app, stripe_client, subscription.into()).await?;
+
+    if let Some(billing_subscription) = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id(&stripe_subscription_id)
+        .await
+        .log_err()
+        .flatten()
+    {
+        notify_billing_webhook_subscribers(
+            app,
+            &BillingWebhookPayload {
+                user_id: billing_customer.user_id,
+                plan: billing_subscription
+                    .kind
+                    .map(Into::into)
+                    .unwrap_or(zed_llm_client::Plan::ZedFree)
+                    .as_str()
+                    .to_string(),
+                status: billing_subscription.stripe_subscription_status,
+                event: event_type_to_string(event.type_),
+            },
+        );
+    }
+
+    // When the user's subscription changes, push down any changes to their plan.
+    rpc_server
+        .update_plan_for_user(billing_customer.user_id)
+        .await
+        .trace_err();
+
+    // When the user's subscription changes, we want to refresh their LLM tokens
+    // to either grant/revoke access.
+    rpc_server
+        .refresh_llm_tokens_for_user(billing_customer.user_id)
+        .await;
+
+    Ok(())
+}
+
+async fn handle_invoice_event(
+    app: &Arc<AppState>,
+    rpc_server: &Arc<Server>,
+    stripe_client: &Arc<dyn StripeClient>,
+    event: stripe::Event,
+) -> anyhow::Result<()> {
+    let EventObject::Invoice(invoice) = event.data.object else {
+        bail!("unexpected event payload for {}", event.id);
+    };
+
+    log::info!("handling Stripe {} event: {}", event.type_, event.id);
+
+    let Some(customer) = invoice.customer else {
+        return Ok(());
+    };
+    let stripe_customer_id = StripeCustomerId::from(customer.id());
+
+    let (kind, amount_in_cents) = match event.type_ {
+        EventType::InvoicePaymentFailed => (PaymentEventKind::Failed, invoice.amount_due),
+        _ => (PaymentEventKind::Succeeded, invoice.amount_paid),
+    };
+    record_payment_event(
+        app,
+        &stripe_customer_id,
+        kind,
+        event.id.as_str(),
+        invoice.id.as_str(),
+        amount_in_cents,
+        DateTime::from_timestamp(event.created, 0).unwrap_or_else(Utc::now),
+    )
+    .await?;
+
+    if kind == PaymentEventKind::Failed {
+        return Ok(());
+    }
+
+    let stripe_subscription_id = invoice
+        .subscription
+        .map(|subscription| StripeSubscriptionId::from(subscription.id()));
+
+    let Some(billing_customer) = restore_access_after_payment(
+        app,
+        stripe_client,
+        &stripe_customer_id,
+        stripe_subscription_id.as_ref(),
+    )
+    .await?
+    else {
+        return Ok(());
+    };
+
+    rpc_server
+        .update_plan_for_user(billing_customer.user_id)
+        .await
+        .trace_err();
+
+    // Refresh the user's LLM tokens right away, so that they regain access as soon as they've paid.
+    rpc_server
+        .refresh_llm_tokens_for_user(billing_customer.user_id)
+        .await;
+
+    Ok(())
+}
+
+/// Records that one of the customer's invoices was paid, or failed to be paid, so that we have a history of their
+/// payments.
+///
+/// Does nothing if we don't know about the customer.
+pub(crate) async fn record_payment_event(
+    app: &AppState,
+    stripe_customer_id: &StripeCustomerId,
+    kind: PaymentEventKind,
+    stripe_event_id: &str,
+    stripe_invoice_id: &str,
+    amount_in_cents: i64,
+    occurred_at: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let Some(billing_customer) = app
+        .db
+        .get_billing_customer_by_stripe_customer_id(&stripe_customer_id.0)
+        .await?
+    else {
+        return Ok(());
+    };
+
+    app.db
+        .create_billing_payment_event(&CreateBillingPaymentEventParams {
+            billing_customer_id: billing_customer.id,
+            kind,
+            stripe_event_id: stripe_event_id.to_string(),
+            stripe_invoice_id: stripe_invoice_id.to_string(),
+            amount_in_cents,
+            occurred_at: occurred_at.naive_utc(),
+        })
+        .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct PaymentEventJson {
+    pub(crate) kind: PaymentEventKind,
+    pub(crate) stripe_invoice_id: String,
+    pub(crate) amount_in_cents: i64,
+    pub(crate) occurred_at: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ListPaymentEventsResponse {
+    payment_events: Vec<PaymentEventJson>,
+}
+
+/// Returns the history of a customer's invoice payments, oldest first.
+async fn list_payment_events(
+    Extension(app): Extension<Arc<AppState>>,
+    Path(billing_customer_id): Path<BillingCustomerId>,
+) -> Result<Json<ListPaymentEventsResponse>> {
+    let Some(billing_customer) = app
+        .db
+        .get_billing_customer_by_id(billing_customer_id)
+        .await?
+    else {
+        return Err(BillingError::BillingCustomerNotFound.into());
+    };
+
+    let payment_events = payment_events_for_customer(&app, &billing_customer).await?;
+
+    Ok(Json(ListPaymentEventsResponse { payment_events }))
+}
+
+pub(crate) async fn payment_events_for_customer(
+    app: &AppState,
+    billing_customer: &billing_customer::Model,
+) -> Result<Vec<PaymentEventJson>> {
+    Ok(app
+        .db
+        .get_billing_payment_events(billing_customer.id)
+        .await?
+        .into_iter()
+        .map(|payment_event| PaymentEventJson {
+            kind: payment_event.kind,
+            stripe_invoice_id: payment_event.stripe_invoice_id,
+            amount_in_cents: payment_event.amount_in_cents,
+            occurred_at: payment_event
+                .occurred_at
+                .and_utc()
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+        })
+        .collect())
+}
+
+/// The number of invoices we list when no limit is given.
+const DEFAULT_INVOICES_LIMIT: u64 = 12;
+
+/// The most invoices we list at once, which is the most that Stripe returns in a single page.
+const MAX_INVOICES_LIMIT: u64 = 100;
+
+#[derive(Debug, Deserialize)]
+struct ListInvoicesParams {
+    github_user_id: i32,
+    /// The number of invoices to list, up to [`MAX_INVOICES_LIMIT`].
+    limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct InvoiceJson {
+    pub(crate) stripe_invoice_id: String,
+    pub(crate) number: Option<String>,
+    pub(crate) amount_in_cents: i64,
+    pub(crate) currency: String,
+    pub(crate) status: Option<StripeInvoiceStatus>,
+    pub(crate) period: BillingSubscriptionPeriodJson,
+    pub(crate) created_at: String,
+    pub(crate) hosted_invoice_url: Option<String>,
+    pub(crate) invoice_pdf_url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ListInvoicesResponse {
+    invoices: Vec<InvoiceJson>,
+}
+
+/// Returns a user's most recent invoices, newest first.
+async fn list_invoices(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<ListInvoicesParams>,
+) -> Result<Json<ListInvoicesResponse>> {
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let limit = params
+        .limit
+        .unwrap_or(DEFAULT_INVOICES_LIMIT)
+        .clamp(1, MAX_INVOICES_LIMIT);
+    let invoices = invoices_for_user(&app, &stripe_client, &user, limit).await?;
+
+    Ok(Json(ListInvoicesResponse { invoices }))
+}
+
+pub(crate) async fn invoices_for_user(
+    app: &AppState,
+    stripe_client: &Arc<dyn StripeClient>,
+    user: &User,
+    limit: u64,
+) -> Result<Vec<InvoiceJson>> {
+    // Users who have never checked out don't have any invoices.
+    let Some(billing_customer) = app.db.get_billing_customer_by_user_id(user.id).await? else {
+        return Ok(Vec::new());
+    };
+
+    let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.into());
+    let invoices = stripe_client
+        .list_invoices_for_customer(&stripe_customer_id, limit)
+        .await?;
+
+    Ok(invoices.into_iter().filter_map(invoice_json).collect())
+}
+
+fn invoice_json(invoice: StripeInvoice) -> Option<InvoiceJson> {
+    let start_at = DateTime::from_timestamp(invoice.period_start, 0)?;
+    let end_at = DateTime::from_timestamp(invoice.period_end, 0)?;
+    let created_at = DateTime::from_timestamp(invoice.created, 0)?;
+
+    Some(InvoiceJson {
+        stripe_invoice_id: invoice.id.to_string(),
+        number: invoice.number,
+        amount_in_cents: invoice.total,
+        currency: invoice.currency,
+        status: invoice.status,
+        period: BillingSubscriptionPeriodJson {
+            start_at: start_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            end_at: end_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        },
+        created_at: created_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        hosted_invoice_url: invoice.hosted_invoice_url,
+        invoice_pdf_url: invoice.invoice_pdf,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct ListPaymentMethodsParams {
+    github_user_id: i32,
+}
+
+/// A saved card, with only the details that we can show the user.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct PaymentMethodJson {
+    pub(crate) brand: String,
+    pub(crate) last4: String,
+    pub(crate) exp_month: u32,
+    pub(crate) exp_year: i32,
+    /// Whether the card is the one that the customer's invoices are charged to.
+    pub(crate) is_default: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ListPaymentMethodsResponse {
+    payment_methods: Vec<PaymentMethodJson>,
+}
+
+/// Returns the cards that a user has saved, so that they can see them without opening the Stripe portal.
+async fn list_payment_methods(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<ListPaymentMethodsParams>,
+) -> Result<Json<ListPaymentMethodsResponse>> {
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let payment_methods = payment_methods_for_user(&app, &stripe_client, &user).await?;
+
+    Ok(Json(ListPaymentMethodsResponse { payment_methods }))
+}
+
+pub(crate) async fn payment_methods_for_user(
+    app: &AppState,
+    stripe_client: &Arc<dyn StripeClient>,
+    user: &User,
+) -> Result<Vec<PaymentMethodJson>> {
+    // Users who have never checked out don't have any payment methods.
+    let Some(billing_customer) = app.db.get_billing_customer_by_user_id(user.id).await? else {
+        return Ok(Vec::new());
+    };
+
+    let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.into());
+    let payment_methods = stripe_client
+        .list_payment_methods(&stripe_customer_id)
+        .await?;
+    let default_payment_method_id = stripe_client
+        .get_default_payment_method(&stripe_customer_id)
+        .await?
+        .map(|payment_method| payment_method.id);
+
+    // We only list cards, as they're the only payment methods that we have something to show for.
+    Ok(payment_methods
+        .into_iter()
+        .filter_map(|payment_method| {
+            let card = payment_method.card?;
+            Some(PaymentMethodJson {
+                brand: card.brand,
+                last4: card.last4,
+                exp_month: card.exp_month,
+                exp_year: card.exp_year,
+                is_default: default_payment_method_id.as_ref() == Some(&payment_method.id),
+            })
+        })
+        .collect())
+}
+
+/// Clears the overdue state of a customer whose invoice was paid, and syncs the subscription the invoice was for.
+///
+/// Returns the billing customer, or `None` if we don't know about the customer.
+///
+/// This is idempotent, so it's safe to run again for the same invoice (e.g., when an event is retried).
+pub(crate) async fn restore_access_after_payment(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    stripe_customer_id: &StripeCustomerId,
+    stripe_subscription_id: Option<&StripeSubscriptionId>,
+) -> anyhow::Result<Option<billing_customer::Model>> {
+    let Some(billing_customer) = app
+        .db
+        .get_billing_customer_by_stripe_customer_id(&stripe_customer_id.0)
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    if billing_customer.has_overdue_invoices {
+        app.db
+            .update_billing_customer(
+                billing_customer.id,
+                &UpdateBillingCustomerParams {
+                    has_overdue_invoices: ActiveValue::set(false),
+                    ..Default::default()
+                },
+            )
+            .await?;
+    }
+
+    if let Some(stripe_subscription_id) = stripe_subscription_id {
+        let subscription = stripe_client
+            .get_subscription(stripe_subscription_id)
+            .await?;
+        sync_subscription(app, stripe_client, subscription).await?;
+    }
+
+    Ok(Some(billing_customer))
+}
+
+#[derive(Debug, Deserialize)]
+struct GetCurrentUsageParams {
+    github_user_id: i32,
+    /// The locale to display the plan in, overriding the `Accept-Language` header.
+    locale: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct UsageCounts {
+    pub used: i32,
+    pub limit: Option<i32>,
+    pub remaining: Option<i32>,
+    /// When the counter resets, so that we can tell users how long they have left.
+    pub resets_at: String,
+}
+
+impl UsageCounts {
+    pub(crate) fn new(used: i32, limit: Option<i32>, resets_at: DateTime<Utc>) -> Self {
+        Self {
+            used,
+            limit,
+            remaining: limit.map(|limit| (limit - used).max(0)),
+            resets_at: resets_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        }
+    }
+}
+
+/// When a usage counter resets.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub(crate) enum UsageResetWindow {
+    /// At the end of the subscription period.
+    SubscriptionPeriod,
+    /// At the start of the given day of each month, in UTC.
+    Monthly { anchor_day: u32 },
+}
+
+impl UsageResetWindow {
+    /// Returns when the counter next resets after `now`.
+    pub(crate) fn resets_at(
+        self,
+        period_end_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> DateTime<Utc> {
+        match self {
+            Self::SubscriptionPeriod => period_end_at,
+            Self::Monthly { anchor_day } => {
+                let anchor_in_month = |year: i32, month: u32| {
+                    chrono::NaiveDate::from_ymd_opt(year, month, anchor_day)
+                        .and_then(|date| date.and_hms_opt(0, 0, 0))
+                        .map(|date| date.and_utc())
+                };
+                let (next_year, next_month) = if now.month() == 12 {
+                    (now.year() + 1, 1)
+                } else {
+                    (now.year(), now.month() + 1)
+                };
+
+                anchor_in_month(now.year(), now.month())
+                    .filter(|resets_at| *resets_at > now)
+                    .or_else(|| anchor_in_month(next_year, next_month))
+                    .unwrap_or(period_end_at)
+            }
+        }
+    }
+}
+
+/// The most days into a month that a monthly reset can be anchored on, so that it falls in every month.
+const MAX_USAGE_RESET_ANCHOR_DAY: u32 = 28;
+
+/// Returns when the given usage counter (`model_requests` or `edit_predictions`) resets for the plan.
+///
+/// Counters reset at the end of the subscription period, unless `usage_reset_windows` anchors them to a day of the
+/// month.
+pub(crate) fn usage_reset_window(
+    config: &Config,
+    plan: zed_llm_client::Plan,
+    counter: &str,
+) -> UsageResetWindow {
+    config
+        .usage_reset_windows
+        .iter()
+        .flatten()
+        .filter_map(|entry| {
+            let window = maybe!({
+                let (entry_plan, window) = entry.split_once(':')?;
+                let (entry_counter, anchor_day) = window.split_once('=')?;
+                let anchor_day = anchor_day.trim().parse::<u32>().ok()?;
+                (1..=MAX_USAGE_RESET_ANCHOR_DAY)
+                    .contains(&anchor_day)
+                    .then(|| (entry_plan.trim(), entry_counter.trim(), anchor_day))
+            });
+            if window.is_none() {
+                log::warn!("ignoring malformed usage reset window: {entry:?}");
+            }
+
+            let (entry_plan, entry_counter, anchor_day) = window?;
+            (entry_plan == plan.as_str() && entry_counter == counter)
+                .then_some(UsageResetWindow::Monthly { anchor_day })
+        })
+        .next()
+        .unwrap_or(UsageResetWindow::SubscriptionPeriod)
+}
+
+/// The model requests used out of one of the plan's model request allotments.
+#[derive(Debug, Serialize)]
+pub(crate) struct ModelRequestAllotmentUsage {
+    /// The model that the allotment is for, or `None` when it's pooled across all models.
+    pub model: Option<String>,
+    #[serde(flatten)]
+    pub requests: UsageCounts,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ModelRequestUsage {
+    pub provider: LanguageModelProvider,
+    pub model: String,
+    pub mode: CompletionMode,
+    /// The additional dimensions the requests are billed by, if any.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dimensions: BTreeMap<String, String>,
+    pub requests: i32,
+    /// The number of requests that each of these requests counts as against the plan's limit.
+    pub weight: i32,
+    /// The requests, weighted by `weight`.
+    pub weighted_requests: i32,
+    /// The weighted requests that are billed, after the free overage grace.
+    pub billed_requests: i32,
+    /// The most weighted requests that the plan allows for this model, across all modes and dimensions.
+    ///
+    /// This is `None` when the model is only limited by the plan's model request limit.
+    pub limit: Option<i32>,
+    /// The weighted requests left for this model before it reaches `limit`.
+    pub remaining: Option<i32>,
+}
+
+/// A model whose requests count as more than one request against the plan's limit.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ModelRequestWeight {
+    pub provider: LanguageModelProvider,
+    pub model: String,
+    pub mode: CompletionMode,
+    pub weight: i32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct EditPredictionOverage {
+    pub edit_predictions: i32,
+    pub spend_in_cents: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CurrentUsage {
+    /// The model requests made in the current period, weighted by `model_request_weights`.
+    pub model_requests: UsageCounts,
+    /// The model requests used out of each of the plan's model request allotments.
+    pub model_request_allotments: Vec<ModelRequestAllotmentUsage>,
+    pub model_request_usage: Vec<ModelRequestUsage>,
+    /// The models whose requests count as more than one request, so that we can explain the weighting.
+    pub model_request_weights: Vec<ModelRequestWeight>,
+    /// The number of model requests the user can make before they start being charged for overages.
+    ///
+    /// This is `None` when the user isn't charged for overages, or when their plan doesn't have a pooled limit.
+    pub overage_begins_in_requests: Option<i32>,
+    pub edit_predictions: UsageCounts,
+    /// The edit predictions beyond the plan's limit, when edit prediction overages are enabled.
+    pub edit_prediction_overage: Option<EditPredictionOverage>,
+}
+
+/// Why a user doesn't have access to Zed's hosted models.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessBlockedReason {
+    /// The user hasn't accepted the terms of service.
+    TermsOfServiceNotAccepted,
+    /// The user's trial ended and they haven't subscribed.
+    TrialEnded,
+    /// The user has invoices that failed to be paid.
+    OverdueInvoices,
+    /// The user's account is too new to use Zed's hosted models without Zed Pro.
+    AccountTooYoung,
+}
+
+impl AccessBlockedReason {
+    /// Returns whether we refuse to issue an LLM token for this reason.
+    ///
+    /// For the other reasons we issue a token, and the LLM service enforces the restriction using the token's claims.
+    pub fn denies_llm_token(self) -> bool {
+        match self {
+            Self::TermsOfServiceNotAccepted | Self::TrialEnded => true,
+            Self::OverdueInvoices | Self::AccountTooYoung => false,
+        }
+    }
+
+    /// Returns a message explaining the restriction to the user.
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::TermsOfServiceNotAccepted => "terms of service not accepted",
+            Self::TrialEnded => {
+                "Your Zed Pro trial has ended. Visit https://zed.dev/account to subscribe."
+            }
+            Self::OverdueInvoices => {
+                "You have overdue invoices. Visit https://zed.dev/account to pay them."
+            }
+            Self::AccountTooYoung => {
+                "Your account is too new to use Zed's hosted models without a Zed Pro subscription."
+            }
+        }
+    }
+}
+
+/// Returns why the user doesn't have access to Zed's hosted models, if they don't.
+///
+/// This is the decision the LLM token issuer makes, so that what we tell users matches what they experience.
+pub fn llm_access_blocked_reason(
+    config: &Config,
+    user: &User,
+    is_staff: bool,
+    feature_flags: &[String],
+    billing_customer: Option<&billing_customer::Model>,
+    active_subscription: Option<&billing_subscription::Model>,
+    latest_subscription: Option<&billing_subscription::Model>,
+) -> Option<AccessBlockedReason> {
+    if user.accepted_tos_at.is_none() {
+        return Some(AccessBlockedReason::TermsOfServiceNotAccepted);
+    }
+
+    if active_subscription.is_none()
+        && !is_staff
+        && is_blocked_after_trial(config, latest_subscription)
+    {
+        return Some(AccessBlockedReason::TrialEnded);
+    }
+
+    if billing_customer.is_some_and(|billing_customer| billing_customer.has_overdue_invoices) {
+        return Some(AccessBlockedReason::OverdueInvoices);
+    }
+
+    // Users without a subscription are subscribed to Zed Free when their token is issued.
+    let is_zed_pro = is_staff
+        || active_subscription
+            .is_some_and(|subscription| subscription.kind == Some(SubscriptionKind::ZedPro));
+    let bypass_account_age_check = feature_flags
+        .iter()
+        .any(|flag| flag == BYPASS_ACCOUNT_AGE_CHECK_FEATURE_FLAG);
+    if !is_zed_pro && !bypass_account_age_check && user.account_age() < MIN_ACCOUNT_AGE_FOR_LLM_USE
+    {
+        return Some(AccessBlockedReason::AccountTooYoung);
+    }
+
+    None
+}
+
+/// Returns the subscription that determines the user's plan.
+///
+/// This is their active subscription or, within the configured grace period, one that has become past due, so that a
+/// declined card doesn't cost the user their plan while Stripe retries the payment.
+pub async fn get_entitled_billing_subscription(
+    db: &Database,
+    config: &Config,
+    user_id: UserId,
+) -> anyhow::Result<Option<billing_subscription::Model>> {
+    if let Some(subscription) = db.get_active_billing_subscription(user_id).await? {
+        return Ok(Some(subscription));
+    }
+
+    let grace_period = config.past_due_grace_period();
+    if grace_period.is_zero() {
+        return Ok(None);
+    }
+
+    let past_due_since = Utc::now().naive_utc() - chrono::Duration::from_std(grace_period)?;
+    Ok(db
+        .get_past_due_billing_subscription_since(user_id, past_due_since)
+        .await?)
+}
+
+#[derive(Debug, Deserialize)]
+struct GetAccessStatusParams {
+    github_user_id: i32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct GetAccessStatusResponse {
+    pub(crate) allowed: bool,
+    pub(crate) reason: Option<AccessBlockedReason>,
+    /// A message explaining the restriction, when access isn't allowed.
+    pub(crate) details: Option<String>,
+}
+
+/// Returns whether the user has access to Zed's hosted models and, if not, why.
+async fn get_access_status(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<GetAccessStatusParams>,
+) -> Result<Json<GetAccessStatusResponse>> {
+    let user = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    Ok(Json(access_status_for_user(&app, &user).await?))
+}
+
+pub(crate) async fn access_status_for_user(
+    app: &AppState,
+    user: &User,
+) -> Result<GetAccessStatusResponse> {
+    let feature_flags = app.db.get_user_flags(user.id).await?;
+    let billing_customer = app.db.get_billing_customer_by_user_id(user.id).await?;
+    let active_subscription =
+        get_entitled_billing_subscription(&app.db, &app.config, user.id).await?;
+    let latest_subscription = app.db.get_billing_subscriptions(user.id).await?.pop();
+
+    let reason = llm_access_blocked_reason(
+        &app.config,
+        user,
+        user.is_staff(),
+        &feature_flags,
+        billing_customer.as_ref(),
+        active_subscription.as_ref(),
+        latest_subscription.as_ref(),
+    );
+
+    Ok(GetAccessStatusResponse {
+        allowed: reason.is_none(),
+        reason,
+        details: reason.map(|reason| reason.message().to_string()),
+    })
+}
+
+/// How long after canceling Zed Pro a user can be offered a win-back.
+pub(crate) const WINBACK_WINDOW: chrono::Duration = chrono::Duration::days(90);
+
+/// How long after being offered a win-back a user can be offered another one.
+const WINBACK_OFFER_COOLDOWN: chrono::Duration = chrono::Duration::days(365);
+
+/// A discount offered to a user who canceled Zed Pro, applied when they resubscribe.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct WinbackOffer {
+    pub(crate) stripe_coupon_id: String,
+    /// When the user stops being eligible for the offer.
+    pub(crate) expires_at: String,
+}
+
+/// Returns the win-back offer the user is eligible for, if any.
+///
+/// A user is eligible when they canceled Zed Pro within the last [`WINBACK_WINDOW`], they're now on Zed Free,
+/// and they haven't been offered a win-back within the last [`WINBACK_OFFER_COOLDOWN`].
+pub(crate) fn winback_offer(
+    config: &Config,
+    billing_customer: Option<&billing_customer::Model>,
+    subscriptions: &[billing_subscription::Model],
+    now: DateTime<Utc>,
+) -> Option<WinbackOffer> {
+    let stripe_coupon_id = config.winback_coupon_id.clone()?;
+    let billing_customer = billing_customer?;
+
+    let offered_recently = billing_customer
+        .winback_offered_at
+        .is_some_and(|offered_at| now - offered_at.and_utc() < WINBACK_OFFER_COOLDOWN);
+    if offered_recently {
+        return None;
+    }
+
+    let is_on_paid_plan = subscriptions.iter().any(|subscription| {
+        subscription.stripe_subscription_status.is_cancelable()
+            && matches!(
+                subscription.kind,
+                Some(SubscriptionKind::ZedPro | SubscriptionKind::ZedProTrial)
+            )
+    });
+    if is_on_paid_plan {
+        return None;
+    }
+
+    let canceled_at = subscriptions
+        .iter()
+        .filter(|subscription| {
+            subscription.kind == Some(SubscriptionKind::ZedPro)
+                && subscription.stripe_subscription_status == StripeSubscriptionStatus::Canceled
+        })
+        .filter_map(|subscription| {
+            subscription
+                .stripe_cancel_at
+                .map(|cancel_at| cancel_at.and_utc())
+                .or_else(|| subscription.current_period_end_at())
+        })
+        .max()?;
+    if canceled_at > now || now - canceled_at > WINBACK_WINDOW {
+        return None;
+    }
+
+    Some(WinbackOffer {
+        stripe_coupon_id,
+        expires_at: (canceled_at + WINBACK_WINDOW).to_rfc3339_opts(SecondsFormat::Millis, true),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct GetWinbackEligibilityParams {
+    github_user_id: i32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct GetWinbackEligibilityResponse {
+    eligible: bool,
+    offer: Option<WinbackOffer>,
+}
+
+/// Returns whether the user qualifies for a discount to win them back after canceling Zed Pro.
+async fn get_winback_eligibility(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<GetWinbackEligibilityParams>,
+) -> Result<Json<GetWinbackEligibilityResponse>> {
+    let user = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let offer = winback_offer_for_user(&app, &user, Utc::now()).await?;
+    Ok(Json(GetWinbackEligibilityResponse {
+        eligible: offer.is_some(),
+        offer,
+    }))
+}
+
+pub(crate) async fn winback_offer_for_user(
+    app: &AppState,
+    user: &User,
+    now: DateTime<Utc>,
+) -> Result<Option<WinbackOffer>> {
+    let billing_customer = app.db.get_billing_customer_by_user_id(user.id).await?;
+    let subscriptions = app.db.get_billing_subscriptions(user.id).await?;
+
+    Ok(winback_offer(
+        &app.config,
+        billing_customer.as_ref(),
+        &subscriptions,
+        now,
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct RecordWinbackOfferBody {
+    github_user_id: i32,
+}
+
+/// Records that the user was sent their win-back offer, so that they aren't offered another one.
+async fn record_winback_offer(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<RecordWinbackOfferBody>,
+) -> Result<Json<WinbackOffer>> {
+    ensure_billing_writable(&app.config)?;
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    Ok(Json(
+        record_winback_offer_for_user(&app, &user, Utc::now()).await?,
+    ))
+}
+
+pub(crate) async fn record_winback_offer_for_user(
+    app: &AppState,
+    user: &User,
+    now: DateTime<Utc>,
+) -> Result<WinbackOffer> {
+    let Some(offer) = winback_offer_for_user(app, user, now).await? else {
+        return Err(BillingError::WinbackNotEligible.into());
+    };
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await?
+        .context("billing customer not found")?;
+
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                winback_offered_at: ActiveValue::set(Some(now.naive_utc())),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    Ok(offer)
+}
+
+/// The usage limits of a plan, where `None` means unlimited.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct UsageLimits {
+    /// The pooled model request limit, which doesn't apply to plans with per-model allotments.
+    pub model_requests: Option<i32>,
+    pub model_request_allotments: Vec<ModelRequestAllotment>,
+    /// The models with their own limit, on top of the plan's model request limit.
+    pub model_request_limits: Vec<ModelRequestLimit>,
+    pub edit_predictions: Option<i32>,
+}
+
+/// The model requests that a plan includes before we bill for overage.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct ModelRequestAllotment {
+    /// The model that the requests are included for, or `None` when they're pooled across all models.
+    pub model: Option<String>,
+    /// The number of included requests, weighted by `model_request_weights`, where `None` means unlimited.
+    pub limit: Option<i32>,
+}
+
+/// A model whose requests a plan limits separately from the plan's model request limit.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub(crate) struct ModelRequestLimit {
+    pub model: String,
+    /// The most requests that can be made to the model, weighted by `model_request_weights`.
+    pub limit: i32,
+}
+
+/// Returns the models and request counts configured for the given plan in `entries`.
+///
+/// Each entry is of the form `<plan>:<model>=<requests>`. Malformed entries are logged and ignored.
+fn plan_model_request_entries<'a>(
+    entries: &'a Option<Vec<String>>,
+    plan: zed_llm_client::Plan,
+    description: &'static str,
+) -> impl Iterator<Item = (&'a str, i32)> {
+    entries.iter().flatten().filter_map(move |entry| {
+        let parsed = maybe!({
+            let (entry_plan, requests) = entry.split_once(':')?;
+            let (model, requests) = requests.split_once('=')?;
+            let requests = requests.trim().parse::<i32>().ok()?;
+            Some((entry_plan.trim(), model.trim(), requests))
+        });
+        if parsed.is_none() {
+            log::warn!("ignoring malformed {description}: {entry:?}");
+        }
+
+        let (entry_plan, model, requests) = parsed?;
+        (entry_plan == plan.as_str()).then_some((model, requests.max(0)))
+    })
+}
+
+/// Returns the model request allotments of the given plan.
+///
+/// Plans with per-model allotments in `model_request_allotments` have an allotment for each of those models.
+/// Every other plan has a single allotment of `pooled_limit`, shared by all models.
+pub(crate) fn model_request_allotments(
+    config: &Config,
+    plan: zed_llm_client::Plan,
+    pooled_limit: Option<i32>,
+) -> Vec<ModelRequestAllotment> {
+    let allotments = plan_model_request_entries(
+        &config.model_request_allotments,
+        plan,
+        "model request allotment",
+    )
+    .map(|(model, limit)| ModelRequestAllotment {
+        model: Some(model.to_string()),
+        limit: Some(limit),
+    })
+    .collect::<Vec<_>>();
+
+    if allotments.is_empty() {
+        vec![ModelRequestAllotment {
+            model: None,
+            limit: pooled_limit,
+        }]
+    } else {
+        allotments
+    }
+}
+
+/// Returns the per-model request limits of the given plan, from `model_request_limits`.
+pub(crate) fn model_request_limits(
+    config: &Config,
+    plan: zed_llm_client::Plan,
+) -> Vec<ModelRequestLimit> {
+    plan_model_request_entries(&config.model_request_limits, plan, "model request limit")
+        .map(|(model, limit)| ModelRequestLimit {
+            model: model.to_string(),
+            limit,
+        })
+        .collect()
+}
+
+/// Sets the limit and the remaining requests of each model usage that has a per-model limit.
+///
+/// A model's limit is shared by all of its modes and dimensions, so the remaining requests are the same for each of
+/// the model's usages.
+pub(crate) fn apply_model_request_limits(
+    model_request_usage: &mut [ModelRequestUsage],
+    limits: &[ModelRequestLimit],
+) {
+    let mut used_by_model = HashMap::<String, i32>::default();
+    for usage in model_request_usage.iter() {
+        let used = used_by_model.entry(usage.model.clone()).or_default();
+        *used = used.saturating_add(usage.weighted_requests.max(0));
+    }
+
+    for usage in model_request_usage.iter_mut() {
+        let Some(limit) = limits.iter().find(|limit| limit.model == usage.model) else {
+            continue;
+        };
+
+        let used = used_by_model.get(&usage.model).copied().unwrap_or_default();
+        usage.limit = Some(limit.limit);
+        usage.remaining = Some((limit.limit - used).max(0));
+    }
+}
+
+/// Returns whether the allotments are per-model, rather than a single pooled allotment.
+fn has_per_model_allotments(allotments: &[ModelRequestAllotment]) -> bool {
+    allotments.iter().any(|allotment| allotment.model.is_some())
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct GetCurrentUsageResponse {
+    /// The user's plan, for clients to match on, or `null` if they don't have a subscription.
+    pub plan: Option<SubscriptionKind>,
+    /// The localized name of the plan, for display.
+    pub plan_display: Option<String>,
+    /// The limits of the plan, so that we can show them even when `current_usage` isn't available.
+    pub limits: Option<UsageLimits>,
+    pub current_usage: Option<CurrentUsage>,
+    /// Whether we were able to load the user's usage.
+    ///
+    /// This is `false` when loading the usage failed, in which case `current_usage` is `null`.
+    pub usage_available: bool,
+    pub access_blocked_reason: Option<AccessBlockedReason>,
+    /// Whether we've stopped billing the user for their usage.
+    pub billing_suspended: bool,
+    /// Whether the user's payments need their attention.
+    pub payment_status: PaymentStatus,
+    /// The number of days left in the user's trial, if they're trialing Zed Pro.
+    pub trial_days_remaining: Option<i32>,
+    /// Whether the user's trial ends within [`TRIAL_EXPIRING_SOON_DAYS`], so that we can nudge them to add a payment
+    /// method.
+    pub trial_expiring_soon: bool,
+    /// The currency that the user is billed in, which any amounts are in.
+    pub currency: String,
+    /// The credit that the user has left, in cents, which Stripe applies to their invoices (overages included) before
+    /// charging them.
+    ///
+    /// This is `null` when we couldn't load it.
+    pub credits_remaining_in_cents: Option<i64>,
+}
+
+impl Default for GetCurrentUsageResponse {
+    fn default() -> Self {
+        Self {
+            plan: None,
+            plan_display: None,
+            limits: None,
+            current_usage: None,
+            usage_available: true,
+            access_blocked_reason: None,
+            billing_suspended: false,
+            payment_status: PaymentStatus::Current,
+            trial_days_remaining: None,
+            trial_expiring_soon: false,
+            currency: DEFAULT_BILLING_CURRENCY.to_string(),
+            credits_remaining_in_cents: None,
+        }
+    }
+}
+
+/// The number of days before a trial ends that we consider it to be expiring soon.
+pub(crate) const TRIAL_EXPIRING_SOON_DAYS: i32 = 3;
+
+/// Returns the number of days left in the subscription's trial, counting a partial day as a whole one, or `None` if
+/// it isn't a trial.
+pub(crate) fn trial_days_remaining(
+    subscription: &billing_subscription::Model,
+    now: DateTime<Utc>,
+) -> Option<i32> {
+    let remaining = subscription.trial_end_at()? - now;
+    if remaining <= chrono::Duration::zero() {
+        return Some(0);
+    }
+
+    let whole_days = remaining.num_days();
+    let days = if remaining > chrono::Duration::days(whole_days) {
+        whole_days + 1
+    } else {
+        whole_days
+    };
+    Some(days.try_into().unwrap_or(i32::MAX))
+}
+
+/// The state of a user's payments, so that the client can tell them when a payment needs their attention.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentStatus {
+    /// The user's payments are up to date.
+    #[default]
+    Current,
+    /// A payment failed, and we're waiting for it to be paid.
+    PastDue,
+    /// A payment is waiting on the user, e.g., for them to authorize it with their bank.
+    ActionRequired,
+    /// The user disputed a payment.
+    Disputed,
+}
+
+/// Returns the state of the user's payments, from their billing customer and their most recent subscription.
+///
+/// When more than one state applies, the one that needs the most attention wins.
+pub(crate) fn payment_status(
+    billing_customer: Option<&billing_customer::Model>,
+    subscription: Option<&billing_subscription::Model>,
+) -> PaymentStatus {
+    let has_overdue_invoices =
+        billing_customer.is_some_and(|billing_customer| billing_customer.has_overdue_invoices);
+    let Some(subscription) = subscription else {
+        return if has_overdue_invoices {
+            PaymentStatus::PastDue
+        } else {
+            PaymentStatus::Current
+        };
+    };
+
+    if subscription.stripe_cancellation_reason == Some(StripeCancellationReason::PaymentDisputed) {
+        return PaymentStatus::Disputed;
+    }
+
+    match subscription.stripe_subscription_status {
+        // The first payment of an incomplete subscription is pending, usually because it needs to be authorized.
+        StripeSubscriptionStatus::Incomplete => PaymentStatus::ActionRequired,
+        StripeSubscriptionStatus::PastDue | StripeSubscriptionStatus::Unpaid => {
+            PaymentStatus::PastDue
+        }
+        _ if has_overdue_invoices => PaymentStatus::PastDue,
+        _ => PaymentStatus::Current,
+    }
+}
+
+async fn get_current_usage(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<GetCurrentUsageParams>,
+    headers: HeaderMap,
+) -> Result<Json<GetCurrentUsageResponse>> {
+    let user = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let feature_flags = app.db.get_user_flags(user.id).await?;
+    let has_extended_trial = feature_flags
+        .iter()
+        .any(|flag| flag == AGENT_EXTENDED_TRIAL_FEATURE_FLAG);
+
+    let Some(llm_db) = app.llm_db.clone() else {
+        return Err(BillingError::LlmDatabaseNotAvailable.into());
+    };
+
+    let billing_customer = app.db.get_billing_customer_by_user_id(user.id).await?;
+    let billing_suspended = billing_customer
+        .as_ref()
+        .is_some_and(|billing_customer| billing_customer.billing_suspended);
+    let currency = billing_customer.as_ref().map_or_else(
+        || DEFAULT_BILLING_CURRENCY.to_string(),
+        |billing_customer| billing_customer.currency.clone(),
+    );
+    let credits_remaining_in_cents = match (app.stripe_client.as_ref(), &billing_customer) {
+        (Some(stripe_client), Some(billing_customer)) => {
+            credits_remaining_in_cents(stripe_client.as_ref(), billing_customer).await
+        }
+        // Users who have never checked out haven't been given any credit.
+        (Some(_), None) => Some(0),
+        (None, _) => None,
+    };
+
+    // A subscription with a failed payment isn't active, so we look at the user's most recent subscription.
+    let latest_subscription = app.db.get_billing_subscriptions(user.id).await?.pop();
+    let payment_status = payment_status(billing_customer.as_ref(), latest_subscription.as_ref());
+
+    let Some(subscription) = app.db.get_active_billing_subscription(user.id).await? else {
+        let access_blocked_reason =
+            is_blocked_after_trial(&app.config, latest_subscription.as_ref())
+                .then_some(AccessBlockedReason::TrialEnded);
+
+        return Ok(Json(GetCurrentUsageResponse {
+            access_blocked_reason,
+            billing_suspended,
+            payment_status,
+            currency,
+            credits_remaining_in_cents,
+            ..Default::default()
+        }));
+    };
+
+    let subscription_period = maybe!({
+        let period_start_at = subscription.current_period_start_at()?;
+        let period_end_at = subscription.current_period_end_at()?;
+
+        Some((period_start_at, period_end_at))
+    });
+
+    let Some((period_start_at, period_end_at)) = subscription_period else {
+        return Ok(Json(GetCurrentUsageResponse {
+            billing_suspended,
+            payment_status,
+            currency,
+            credits_remaining_in_cents,
+            ..Default::default()
+        }));
+    };
+
+    let plan = subscription
+        .kind
+        .map(Into::into)
+        .unwrap_or(zed_llm_client::Plan::ZedFree);
+
+    let limits = usage_limits(&app.config, plan, has_extended_trial);
+    let locale = Locale::for_request(params.locale.as_deref(), &headers);
+
+    let current_usage = current_usage_for_period(
+        &app,
+        &llm_db,
+        user.id,
+        plan,
+        &limits,
+        (period_start_at, period_end_at),
+        billing_suspended,
+    )
+    .await;
+
+    let trial_days_remaining = trial_days_remaining(&subscription, Utc::now());
+
+    Ok(Json(GetCurrentUsageResponse {
+        payment_status,
+        currency,
+        credits_remaining_in_cents,
+        trial_days_remaining,
+        trial_expiring_soon: trial_days_remaining
+            .is_some_and(|days| days <= TRIAL_EXPIRING_SOON_DAYS),
+        ..current_usage_response(plan, locale, limits, billing_suspended, current_usage)
+    }))
+}
+
+/// Returns the credit that the customer has left, in cents, or `None` if we couldn't load it.
+///
+/// Stripe keeps credit as a negative customer balance, so a customer who owes us something has no credit left.
+pub(crate) async fn credits_remaining_in_cents(
+    stripe_client: &dyn StripeClient,
+    billing_customer: &billing_customer::Model,
+) -> Option<i64> {
+    let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+    let balance = stripe_client
+        .get_customer_balance(&stripe_customer_id)
+        .await
+        .log_err()?;
+
+    Some((-balance).max(0))
+}
+
+/// Returns the usage limits of the plan.
+pub(crate) fn usage_limits(
+    config: &Config,
+    plan: zed_llm_client::Plan,
+    has_extended_trial: bool,
+) -> UsageLimits {
+    let model_requests_limit = match plan.model_requests_limit() {
+        zed_llm_client::UsageLimit::Limited(limit) => {
+            let limit = if plan == zed_llm_client::Plan::ZedProTrial && has_extended_trial {
+                1_000
+            } else {
+                limit
+            };
+
+            Some(limit)
+        }
+        zed_llm_client::UsageLimit::Unlimited => None,
+    };
+
+    let edit_predictions_limit = match plan.edit_predictions_limit() {
+        zed_llm_client::UsageLimit::Limited(limit) => Some(limit),
+        zed_llm_client::UsageLimit::Unlimited => None,
+    };
+
+    let model_request_allotments = model_request_allotments(config, plan, model_requests_limit);
+    UsageLimits {
+        model_requests: model_requests_limit
+            .filter(|_| !has_per_model_allotments(&model_request_allotments)),
+        model_request_allotments,
+        model_request_limits: model_request_limits(config, plan),
+        edit_predictions: edit_predictions_limit,
+    }
+}
+
+/// Builds the usage response for a user with a subscription.
+///
+/// When we fail to load the user's usage, we still return their plan and its limits, so that a blip in the LLM
+/// database doesn't keep them from seeing their plan.
+pub(crate) fn current_usage_response(
+    plan: zed_llm_client::Plan,
+    locale: Locale,
+    limits: UsageLimits,
+    billing_suspended: bool,
+    current_usage: Result<CurrentUsage>,
+) -> GetCurrentUsageResponse {
+    let current_usage = current_usage.log_err();
+
+    let plan = SubscriptionKind::from(plan);
+    GetCurrentUsageResponse {
+        plan: Some(plan),
+        plan_display: Some(plan_text(Some(plan), locale).name.to_string()),
+        limits: Some(limits),
+        usage_available: current_usage.is_some(),
+        current_usage,
+        access_blocked_reason: None,
+        billing_suspended,
+        payment_status: PaymentStatus::Current,
+        trial_days_remaining: None,
+        trial_expiring_soon: false,
+        currency: DEFAULT_BILLING_CURRENCY.to_string(),
+        credits_remaining_in_cents: None,
+    }
+}
+
+/// The most billing periods of usage history that we return at once.
+const MAX_USAGE_HISTORY_MONTHS: u64 = 12;
+
+#[derive(Debug, Deserialize)]
+struct GetUsageHistoryParams {
+    github_user_id: i32,
+    /// The number of billing periods to return, up to [`MAX_USAGE_HISTORY_MONTHS`].
+    months: Option<u64>,
+}
+
+/// The user's usage in one of their billing periods.
+#[derive(Debug, Serialize)]
+pub(crate) struct UsageHistoryPeriod {
+    pub period: BillingSubscriptionPeriodJson,
+    /// The plan that the user was on during the period, which may differ from their current plan.
+    pub plan: String,
+    /// The limits of the plan that the user was on during the period.
+    pub limits: UsageLimits,
+    pub model_requests: UsageCounts,
+    pub edit_predictions: UsageCounts,
+}
+
+#[derive(Debug, Serialize)]
+struct GetUsageHistoryResponse {
+    /// The user's usage in their most recent billing periods, most recent first.
+    ///
+    /// Periods in which the user didn't use anything are left out.
+    pub periods: Vec<UsageHistoryPeriod>,
+}
+
+async fn get_usage_history(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<GetUsageHistoryParams>,
+) -> Result<Json<GetUsageHistoryResponse>> {
+    let user = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let Some(llm_db) = app.llm_db.clone() else {
+        return Err(BillingError::LlmDatabaseNotAvailable.into());
+    };
+
+    let feature_flags = app.db.get_user_flags(user.id).await?;
+    let has_extended_trial = feature_flags
+        .iter()
+        .any(|flag| flag == AGENT_EXTENDED_TRIAL_FEATURE_FLAG);
+
+    let months = params
+        .months
+        .unwrap_or(MAX_USAGE_HISTORY_MONTHS)
+        .clamp(1, MAX_USAGE_HISTORY_MONTHS);
+    let usages = llm_db
+        .get_recent_subscription_usages(user.id, months)
+        .await?;
+
+    Ok(Json(GetUsageHistoryResponse {
+        periods: usages
+            .iter()
+            .filter_map(|usage| usage_history_period(&app.config, usage, has_extended_trial))
+            .collect(),
+    }))
+}
+
+/// Returns the usage recorded for a billing period, measured against the limits of the plan the user was on at the
+/// time.
+///
+/// The LLM service records the user's plan with their usage in each period, so we take the plan from there rather
+/// than from the user's current subscription, which may have since been upgraded or canceled.
+pub(crate) fn usage_history_period(
+    config: &Config,
+    usage: &subscription_usage::Model,
+    has_extended_trial: bool,
+) -> Option<UsageHistoryPeriod> {
+    let start_at =
+        DateTime::from_timestamp(usage.period_start_at.assume_utc().unix_timestamp(), 0)?;
+    let end_at = DateTime::from_timestamp(usage.period_end_at.assume_utc().unix_timestamp(), 0)?;
+
+    let plan: zed_llm_client::Plan = usage.plan.into();
+    let limits = usage_limits(config, plan, has_extended_trial);
+
+    Some(UsageHistoryPeriod {
+        period: BillingSubscriptionPeriodJson {
+            start_at: start_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            end_at: end_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        },
+        plan: plan.as_str().to_string(),
+        model_requests: UsageCounts::new(usage.model_requests, limits.model_requests, end_at),
+        edit_predictions: UsageCounts::new(usage.edit_predictions, limits.edit_predictions, end_at),
+        limits,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct AddTeamMemberBody {
+    /// The GitHub user ID of the team's billing owner.
+    github_user_id: i32,
+    subscription_id: BillingSubscriptionId,
+    /// The GitHub user ID of the user to add to the team.
+    member_github_user_id: i32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct AddTeamMemberResponse {
+    /// The number of seats taken, including the billing owner's.
+    pub(crate) seats_used: i32,
+    pub(crate) seats: i32,
+}
+
+async fn add_team_member(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<AddTeamMemberBody>,
+) -> Result<Json<AddTeamMemberResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let owner = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+    let member = app
+        .db
+        .get_user_by_github_user_id(body.member_github_user_id)
+        .await?
+        .context("member not found")?;
+
+    Ok(Json(
+        add_team_member_for_owner(&app, &owner, body.subscription_id, &member).await?,
+    ))
+}
+
+/// Adds a user to a team's subscription, taking one of its seats.
+///
+/// Only the team's billing owner can add members, and the billing owner takes a seat of their own.
+pub(crate) async fn add_team_member_for_owner(
+    app: &Arc<AppState>,
+    owner: &User,
+    subscription_id: BillingSubscriptionId,
+    member: &User,
+) -> Result<AddTeamMemberResponse> {
+    let (subscription, team_members) = team_members_for_owner(app, owner, subscription_id).await?;
+
+    if team_members
+        .iter()
+        .any(|team_member| team_member.id == member.id)
+    {
+        return Err(BillingError::AlreadyOnTeam.into());
+    }
+
+    let seats_used = team_members.len() as i32;
+    if seats_used >= subscription.seats {
+        return Err(BillingError::NoSeatsLeft.into());
+    }
+
+    app.db
+        .create_billing_subscription_member(&CreateBillingSubscriptionMemberParams {
+            billing_subscription_id: subscription.id,
+            user_id: member.id,
+        })
+        .await?;
+
+    log::info!(
+        "added user {member_id} to subscription {subscription_id} of user {owner_id}",
+        member_id = member.id,
+        subscription_id = subscription.id,
+        owner_id = owner.id,
+    );
+
+    Ok(AddTeamMemberResponse {
+        seats_used: seats_used + 1,
+        seats: subscription.seats,
+    })
+}
+
+/// Returns the owner's subscription and the users it covers, starting with the billing owner.
+///
+/// Subscriptions that don't belong to the owner are reported as not found.
+pub(crate) async fn team_members_for_owner(
+    app: &Arc<AppState>,
+    owner: &User,
+    subscription_id: BillingSubscriptionId,
+) -> Result<(billing_subscription::Model, Vec<User>)> {
+    let subscription = get_billing_subscription_for_user(app, owner, subscription_id).await?;
+
+    let members = app
+        .db
+        .get_billing_subscription_members(subscription.id)
+        .await?;
+    let mut users = app
+        .db
+        .get_users_by_ids(members.iter().map(|member| member.user_id).collect())
+        .await?
+        .into_iter()
+        .map(|user| (user.id, user))
+        .collect::<HashMap<_, _>>();
+
+    let mut team_members = vec![owner.clone()];
+    team_members.extend(
+        members
+            .iter()
+            .filter_map(|member| users.remove(&member.user_id)),
+    );
+
+    Ok((subscription, team_members))
+}
+
+#[derive(Debug, Deserialize)]
+struct GetTeamUsageParams {
+    /// The GitHub user ID of the team's billing owner.
+    github_user_id: i32,
+    subscription_id: BillingSubscriptionId,
+}
+
+/// A team member's usage in the team's current billing period.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct TeamMemberUsage {
+    pub github_login: String,
+    pub github_user_id: i32,
+    pub model_requests: i32,
+    pub edit_predictions: i32,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct GetTeamUsageResponse {
+    pub plan: String,
+    pub seats: i32,
+    pub period: BillingSubscriptionPeriodJson,
+    /// The team's total model requests, against the plan's limit pooled across every seat.
+    pub model_requests: UsageCounts,
+    /// The team's total edit predictions, against the plan's limit pooled across every seat.
+    pub edit_predictions: UsageCounts,
+    /// The usage of each team member, starting with the billing owner.
+    pub members: Vec<TeamMemberUsage>,
+}
+
+async fn get_team_usage(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<GetTeamUsageParams>,
+) -> Result<Json<GetTeamUsageResponse>> {
+    let owner = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let Some(llm_db) = app.llm_db.clone() else {
+        return Err(BillingError::LlmDatabaseNotAvailable.into());
+    };
+
+    let (subscription, team_members) =
+        team_members_for_owner(&app, &owner, params.subscription_id).await?;
+
+    let Some((period_start_at, period_end_at)) = maybe!({
+        let period_start_at = subscription.current_period_start_at()?;
+        let period_end_at = subscription.current_period_end_at()?;
+
+        Some((period_start_at, period_end_at))
+    }) else {
+        return Err(BillingError::NoCurrentBillingPeriod.into());
+    };
+
+    let mut member_usages = Vec::with_capacity(team_members.len());
+    for team_member in team_members {
+        let usage = llm_db
+            .get_subscription_usage_for_period(team_member.id, period_start_at, period_end_at)
+            .await?;
+        member_usages.push((team_member, usage));
+    }
+
+    let plan = subscription
+        .kind
+        .map(Into::into)
+        .unwrap_or(zed_llm_client::Plan::ZedFree);
+
+    Ok(Json(team_usage(
+        &app.config,
+        plan,
+        subscription.seats,
+        (period_start_at, period_end_at),
+        member_usages,
+    )))
+}
+
+/// Aggregates the usage of a team's members in the given period.
+///
+/// Each seat comes with the plan's limits, so the team's usage is measured against those limits pooled across every
+/// seat, rather than against each member's share of them.
+pub(crate) fn team_usage(
+    config: &Config,
+    plan: zed_llm_client::Plan,
+    seats: i32,
+    (period_start_at, period_end_at): (DateTime<Utc>, DateTime<Utc>),
+    member_usages: Vec<(User, Option<subscription_usage::Model>)>,
+) -> GetTeamUsageResponse {
+    let limits = usage_limits(config, plan, false);
+    let pooled_limit = |limit: Option<i32>| limit.map(|limit| limit.saturating_mul(seats.max(1)));
+
+    let members = member_usages
+        .into_iter()
+        .map(|(user, usage)| TeamMemberUsage {
+            github_login: user.github_login,
+            github_user_id: user.github_user_id,
+            model_requests: usage.as_ref().map_or(0, |usage| usage.model_requests),
+            edit_predictions: usage.as_ref().map_or(0, |usage| usage.edit_predictions),
+        })
+        .collect::<Vec<_>>();
+    let total = |count: fn(&TeamMemberUsage) -> i32| {
+        members
+            .iter()
+            .map(count)
+            .fold(0, |total: i32, count| total.saturating_add(count))
+    };
+
+    GetTeamUsageResponse {
+        plan: plan.as_str().to_string(),
+        seats,
+        period: BillingSubscriptionPeriodJson {
+            start_at: period_start_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            end_at: period_end_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+        },
+        model_requests: UsageCounts::new(
+            total(|member| member.model_requests),
+            pooled_limit(limits.model_requests),
+            period_end_at,
+        ),
+        edit_predictions: UsageCounts::new(
+            total(|member| member.edit_predictions),
+            pooled_limit(limits.edit_predictions),
+            period_end_at,
+        ),
+        members,
+    }
+}
+
+/// Returns the user's usage in the given subscription period.
+async fn current_usage_for_period(
+    app: &Arc<AppState>,
+    llm_db: &LlmDatabase,
+    user_id: UserId,
+    plan: zed_llm_client::Plan,
+    limits: &UsageLimits,
+    (period_start_at, period_end_at): (DateTime<Utc>, DateTime<Utc>),
+    billing_suspended: bool,
+) -> Result<CurrentUsage> {
+    let model_requests_limit = limits.model_requests;
+    let edit_predictions_limit = limits.edit_predictions;
+    let billing_table = model_request_billing_table(&app.config);
+
+    let model_request_overages_enabled = app
+        .db
+        .get_billing_preferences(user_id)
+        .await?
+        .is_some_and(|preferences| preferences.model_request_overages_enabled);
+    let overage_countdown = |model_requests: &UsageCounts| {
+        overage_begins_in_requests(
+            plan,
+            model_request_overages_enabled && !billing_suspended,
+            model_requests,
+            app.config.overage_grace(),
+        )
+    };
+
+    let now = Utc::now();
+    let model_requests_resets_at =
+        usage_reset_window(&app.config, plan, "model_requests").resets_at(period_end_at, now);
+    let edit_predictions_resets_at =
+        usage_reset_window(&app.config, plan, "edit_predictions").resets_at(period_end_at, now);
+
+    let usage = llm_db
+        .get_subscription_usage_for_period(user_id, period_start_at, period_end_at)
+        .await?;
+
+    let Some(usage) = usage else {
+        let model_requests = UsageCounts::new(0, model_requests_limit, model_requests_resets_at);
+        return Ok(CurrentUsage {
+            overage_begins_in_requests: overage_countdown(&model_requests),
+            model_requests,
+            model_request_allotments: model_request_allotment_usage(
+                &limits.model_request_allotments,
+                0,
+                &[],
+                model_requests_resets_at,
+            ),
+            model_request_usage: Vec::new(),
+            model_request_weights: model_request_weights(&billing_table),
+            edit_predictions: UsageCounts::new(
+                0,
+                edit_predictions_limit,
+                edit_predictions_resets_at,
+            ),
+            edit_prediction_overage: None,
+        });
+    };
+
+    let subscription_usage_meters = llm_db
+        .get_current_subscription_usage_meters_for_user(user_id, now)
+        .await?;
+
+    let mut model_request_usage = Vec::new();
+    let mut model_request_billings = Vec::new();
+    for (usage_meter, _usage) in subscription_usage_meters {
+        let Ok((provider, model)) = llm_db.model_by_id(usage_meter.model_id) else {
+            continue;
+        };
+
+        let dimensions = usage_meter.dimensions();
+        let billing = model_request_billing(
+            &billing_table,
+            provider,
+            &model.name,
+            usage_meter.mode,
+            &dimensions,
+        );
+
+        let weight = model_request_weight(
+            &billing_table,
+            provider,
+            &model.name,
+            usage_meter.mode,
+            &dimensions,
+        );
+        let weighted_requests = weighted_requests(usage_meter.requests, weight);
+
+        model_request_usage.push(ModelRequestUsage {
+            provider,
+            model: model.name.clone(),
+            mode: usage_meter.mode,
+            dimensions: dimensions.as_map().clone(),
+            requests: usage_meter.requests,
+            weight,
+            weighted_requests,
+            billed_requests: 0,
+            limit: None,
+            remaining: None,
+        });
+        model_request_billings.push((billing, weighted_requests));
+    }
+
+    // Only Zed Pro usage is synced to Stripe, so we mirror the sync to show what will be billed.
+    if plan == zed_llm_client::Plan::ZedPro && !billing_suspended {
+        let billed_requests = billed_model_requests(
+            &billing_table,
+            &model_request_billings,
+            &limits.model_request_allotments,
+            app.config.overage_grace(),
+        );
+        for (usage, billed_requests) in model_request_usage.iter_mut().zip(billed_requests) {
+            usage.billed_requests = billed_requests;
+        }
+    }
+
+    apply_model_request_limits(&mut model_request_usage, &limits.model_request_limits);
+
+    let model_requests = weighted_model_requests(usage.model_requests, &model_request_usage);
+
+    let edit_prediction_overage = if let Some(stripe_billing) = app
+        .stripe_billing
+        .as_ref()
+        .filter(|_| app.config.edit_prediction_overages_enabled() && !billing_suspended)
+    {
+        let billing_customer = app.db.get_billing_customer_by_user_id(user_id).await?;
+        let price = price_for_customer(
+            app,
+            stripe_billing,
+            billing_customer.as_ref(),
+            EDIT_PREDICTION_OVERAGE_METER_EVENT_NAME,
+            EDIT_PREDICTION_OVERAGE_PRICE_LOOKUP_KEY,
+        )
+        .await?;
+        let edit_predictions = billed_overage(
+            edit_prediction_overage(plan, usage.edit_predictions),
+            app.config.overage_grace(),
+        );
+
+        Some(EditPredictionOverage {
+            edit_predictions,
+            spend_in_cents: edit_predictions as i64 * price.unit_amount.unwrap_or(0),
+        })
+    } else {
+        None
+    };
+
+    let model_request_allotments = model_request_allotment_usage(
+        &limits.model_request_allotments,
+        model_requests,
+        &model_request_usage,
+        model_requests_resets_at,
+    );
+    let model_requests = UsageCounts::new(
+        model_requests,
+        model_requests_limit,
+        model_requests_resets_at,
+    );
+
+    Ok(CurrentUsage {
+        overage_begins_in_requests: overage_countdown(&model_requests),
+        model_requests,
+        model_request_allotments,
+        model_request_usage,
+        model_request_weights: model_request_weights(&billing_table),
+        edit_predictions: UsageCounts::new(
+            usage.edit_predictions,
+            edit_predictions_limit,
+            edit_predictions_resets_at,
+        ),
+        edit_prediction_overage,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct GetPlanRecommendationParams {
+    github_user_id: i32,
+}
+
+/// What a plan would cost a user each month at their recent usage.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct PlanCostEstimate {
+    pub(crate) plan: String,
+    /// The cost of the plan itself, in cents.
+    pub(crate) base_cost_in_cents: i64,
+    /// The cost of the model requests beyond the plan's limit, in cents.
+    pub(crate) overage_cost_in_cents: i64,
+    pub(crate) total_cost_in_cents: i64,
+    /// The model requests that the plan's hard limit wouldn't allow.
+    pub(crate) model_requests_over_limit: i32,
+    /// The edit predictions that the plan's hard limit wouldn't allow.
+    pub(crate) edit_predictions_over_limit: i32,
+}
+
+impl PlanCostEstimate {
+    /// Returns whether the plan allows all of the usage.
+    fn covers_usage(&self) -> bool {
+        self.model_requests_over_limit == 0 && self.edit_predictions_over_limit == 0
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct GetPlanRecommendationResponse {
+    pub(crate) recommended_plan: String,
+    /// The model requests that the estimates are based on.
+    pub(crate) model_requests: i32,
+    /// The edit predictions that the estimates are based on.
+    pub(crate) edit_predictions: i32,
+    pub(crate) plans: Vec<PlanCostEstimate>,
+}
+
+/// The plans that users can choose between.
+const RECOMMENDABLE_PLANS: [zed_llm_client::Plan; 2] =
+    [zed_llm_client::Plan::ZedFree, zed_llm_client::Plan::ZedPro];
+
+/// The lookup key of the price that we estimate model request overages at, since most requests are to this model.
+const PLAN_RECOMMENDATION_REQUEST_PRICE_LOOKUP_KEY: &str = "claude-sonnet-4-requests";
+
+/// Returns what each plan would cost the user each month at their recent usage, along with the plan we recommend.
+async fn get_plan_recommendation(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<GetPlanRecommendationParams>,
+) -> Result<Json<GetPlanRecommendationResponse>> {
+    let user = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let Some(stripe_billing) = app.stripe_billing.clone() else {
+        log::error!("failed to retrieve Stripe billing object");
+        Err(BillingError::NotSupported)?
+    };
+
+    let Some(llm_db) = app.llm_db.clone() else {
+        return Err(BillingError::LlmDatabaseNotAvailable.into());
+    };
+
+    let (model_requests, edit_predictions) =
+        recent_usage(&app, &llm_db, user.id, Utc::now()).await?;
+
+    let zed_pro_price = stripe_billing.find_price_by_lookup_key("zed-pro").await?;
+    let zed_pro_price_in_cents = monthly_recurring_amount_in_cents(&zed_pro_price, 1)
+        .or(zed_pro_price.unit_amount)
+        .unwrap_or(0);
+    let model_request_price_in_cents = stripe_billing
+        .find_price_by_lookup_key(PLAN_RECOMMENDATION_REQUEST_PRICE_LOOKUP_KEY)
+        .await?
+        .unit_amount
+        .unwrap_or(0);
+
+    Ok(Json(plan_recommendation(
+        model_requests,
+        edit_predictions,
+        zed_pro_price_in_cents,
+        model_request_price_in_cents,
+        app.config.overage_grace(),
+    )))
+}
+
+/// Returns the user's recent model requests and edit predictions.
+///
+/// We use the busier of the current and the previous subscription periods, so that a period that just started
+/// doesn't make the user's usage look lighter than it is.
+async fn recent_usage(
+    app: &AppState,
+    llm_db: &LlmDatabase,
+    user_id: UserId,
+    now: DateTime<Utc>,
+) -> Result<(i32, i32)> {
+    let period = app
+        .db
+        .get_active_billing_subscription(user_id)
+        .await?
+        .and_then(|subscription| {
+            subscription
+                .current_period_start_at()
+                .zip(subscription.current_period_end_at())
+        });
+
+    let current_usage = if let Some((period_start_at, period_end_at)) = period {
+        llm_db
+            .get_subscription_usage_for_period(user_id, period_start_at, period_end_at)
+            .await?
+    } else {
+        None
+    };
+    let previous_usage = llm_db
+        .get_previous_subscription_usage(
+            user_id,
+            period.map_or(now, |(period_start_at, _)| period_start_at),
+        )
+        .await?;
+
+    Ok([current_usage, previous_usage]
+        .into_iter()
+        .flatten()
+        .map(|usage| (usage.model_requests, usage.edit_predictions))
+        .max()
+        .unwrap_or((0, 0)))
+}
+
+/// Returns what each of the recommendable plans would cost at the given monthly usage, and recommends the cheapest
+/// plan that allows all of it.
+///
+/// Zed Pro bills model requests beyond its limit as overages, while Zed Free's limits are hard limits.
+pub(crate) fn plan_recommendation(
+    model_requests: i32,
+    edit_predictions: i32,
+    zed_pro_price_in_cents: i64,
+    model_request_price_in_cents: i64,
+    overage_grace: i32,
+) -> GetPlanRecommendationResponse {
+    let plans = RECOMMENDABLE_PLANS
+        .into_iter()
+        .map(|plan| {
+            let model_requests_beyond_limit = match plan.model_requests_limit() {
+                zed_llm_client::UsageLimit::Limited(limit) => (model_requests - limit).max(0),
+                zed_llm_client::UsageLimit::Unlimited => 0,
+            };
+            let (base_cost_in_cents, overage_cost_in_cents, model_requests_over_limit) =
+                if plan == zed_llm_client::Plan::ZedPro {
+                    let overage = billed_overage(model_requests_beyond_limit, overage_grace);
+                    (
+                        zed_pro_price_in_cents,
+                        overage as i64 * model_request_price_in_cents,
+                        0,
+                    )
+                } else {
+                    (0, 0, model_requests_beyond_limit)
+                };
+
+            PlanCostEstimate {
+                plan: plan.as_str().to_string(),
+                base_cost_in_cents,
+                overage_cost_in_cents,
+                total_cost_in_cents: base_cost_in_cents + overage_cost_in_cents,
+                model_requests_over_limit,
+                edit_predictions_over_limit: edit_prediction_overage(plan, edit_predictions),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Zed Pro allows any amount of usage, so there's always a plan to recommend.
+    let recommended_plan = plans
+        .iter()
+        .filter(|estimate| estimate.covers_usage())
+        .min_by_key(|estimate| estimate.total_cost_in_cents)
+        .map_or_else(
+            || zed_llm_client::Plan::ZedPro.as_str().to_string(),
+            |estimate| estimate.plan.clone(),
+        );
+
+    GetPlanRecommendationResponse {
+        recommended_plan,
+        model_requests,
+        edit_predictions,
+        plans,
+    }
+}
+
+/// Returns the amount that the given price contributes to monthly recurring
+/// revenue, in cents, for the given quantity.
+///
+/// Returns `None` for prices that are not part of MRR, such as one-off and
+/// usage-based prices.
+pub(crate) fn monthly_recurring_amount_in_cents(price: &StripePrice, quantity: u64) -> Option<i64> {
+    let recurring = price.recurring.as_ref()?;
+    if recurring.meter.is_some() {
+        return None;
+    }
+
+    let amount = price.unit_amount? * quantity as i64;
+
+    Some(monthly_amount_in_cents(amount, recurring))
+}
+
+/// Normalizes an amount charged every recurring interval to an amount per month.
+fn monthly_amount_in_cents(amount: i64, recurring: &StripePriceRecurring) -> i64 {
+    let interval_count = recurring.interval_count.max(1) as i64;
+
+    let (numerator, denominator) = match recurring.interval {
+        StripePriceRecurringInterval::Day => (365, 12 * interval_count),
+        StripePriceRecurringInterval::Week => (52, 12 * interval_count),
+        StripePriceRecurringInterval::Month => (1, interval_count),
+        StripePriceRecurringInterval::Year => (1, 12 * interval_count),
+    };
+
+    (amount * numerator + denominator / 2) / denominator
+}
+
+/// Returns what the user pays for their subscription each month, in cents.
+///
+/// This is the base cost of the plan, normalized to a month, after any active discount. It excludes usage, which is
+/// billed separately. Trials cost nothing until they end.
+pub(crate) fn effective_monthly_cost_in_cents(
+    stripe_subscription: &StripeSubscription,
+    now: DateTime<Utc>,
+) -> Option<i64> {
+    if stripe_subscription.status == SubscriptionStatus::Trialing {
+        return Some(0);
+    }
+
+    let price = plan_price(stripe_subscription)?;
+    let recurring = price.recurring.as_ref()?;
+    if recurring.meter.is_some() {
+        return None;
+    }
+
+    let amount = price.unit_amount? * i64::from(subscription_seats(stripe_subscription));
+    let amount = match stripe_subscription
+        .discount
+        .as_ref()
+        .filter(|discount| discount.end.map_or(true, |end| end > now.timestamp()))
+    {
+        Some(discount) => discounted_amount(amount, discount),
+        None => amount,
+    };
+
+    Some(monthly_amount_in_cents(amount, recurring))
+}
+
+#[derive(Debug, Deserialize)]
+struct GetMonthlyRecurringRevenueParams {
+    as_of: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct PlanMonthlyRecurringRevenue {
+    pub(crate) plan: String,
+    pub(crate) subscriptions: usize,
+    pub(crate) mrr_in_cents: i64,
+}
+
+#[derive(Debug, Serialize)]
+struct GetMonthlyRecurringRevenueResponse {
+    as_of: String,
+    mrr_in_cents: i64,
+    plans: Vec<PlanMonthlyRecurringRevenue>,
+}
+
+/// Returns the monthly recurring revenue across all paying subscriptions.
+///
+/// Each subscription contributes what it costs each month as of when we last synced it, so `as_of` determines which
+/// subscriptions are included, rather than what they cost at the time.
+async fn get_monthly_recurring_revenue(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<GetMonthlyRecurringRevenueParams>,
+) -> Result<Json<GetMonthlyRecurringRevenueResponse>> {
+    let Some(stripe_billing) = app.stripe_billing.clone() else {
+        log::error!("failed to retrieve Stripe billing object");
+        Err(BillingError::NotSupported)?
+    };
+
+    let as_of = params.as_of.unwrap_or_else(Utc::now);
+    let plans = monthly_recurring_revenue_by_plan(&app, &stripe_billing, as_of).await?;
+
+    Ok(Json(GetMonthlyRecurringRevenueResponse {
+        as_of: as_of.to_rfc3339_opts(SecondsFormat::Millis, true),
+        mrr_in_cents: plans.iter().map(|plan| plan.mrr_in_cents).sum(),
+        plans,
+    }))
+}
+
+pub(crate) async fn monthly_recurring_revenue_by_plan(
+    app: &Arc<AppState>,
+    stripe_billing: &Arc<StripeBilling>,
+    as_of: DateTime<Utc>,
+) -> Result<Vec<PlanMonthlyRecurringRevenue>> {
+    let subscriptions = app
+        .db
+        .get_paying_billing_subscriptions(as_of.naive_utc())
+        .await?;
+
+    let mut plans = Vec::new();
+    for kind in [SubscriptionKind::ZedPro, SubscriptionKind::ZedFree] {
+        let lookup_key = match kind {
+            SubscriptionKind::ZedPro => "zed-pro",
+            SubscriptionKind::ZedFree => "zed-free",
+            SubscriptionKind::ZedProTrial => continue,
+        };
+
+        let mut plan_subscriptions = 0;
+        let mut mrr_in_cents = 0;
+        for subscription in subscriptions
+            .iter()
+            .filter(|subscription| subscription.kind == Some(kind))
+        {
+            // Subscriptions that we haven't synced since we started recording what they cost are valued at the
+            // plan's current price until we next sync them.
+            let subscription_mrr_in_cents = match subscription.monthly_recurring_revenue_in_cents {
+                Some(mrr_in_cents) => mrr_in_cents,
+                None => {
+                    let price = stripe_billing.find_price_by_lookup_key(lookup_key).await?;
+                    monthly_recurring_amount_in_cents(&price, subscription.seats.max(1) as u64)
+                        .unwrap_or(0)
+                }
+            };
+
+            plan_subscriptions += 1;
+            mrr_in_cents += subscription_mrr_in_cents;
+        }
+
+        let plan: zed_llm_client::Plan = kind.into();
+        plans.push(PlanMonthlyRecurringRevenue {
+            plan: plan.as_str().to_string(),
+            subscriptions: plan_subscriptions,
+            mrr_in_cents,
+        });
+    }
+
+    Ok(plans)
+}
+
+/// Returns the billing metrics in the Prometheus text format.
+async fn get_billing_metrics(Extension(app): Extension<Arc<AppState>>) -> Result<String> {
+    render_billing_metrics(&app, Utc::now()).await
+}
+
+/// How likely a paying user is to churn.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ChurnRisk {
+    Low,
+    Medium,
+    High,
+}
+
+/// The signals that go into a user's [`ChurnRisk`].
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Serialize)]
+pub(crate) struct ChurnRiskSignals {
+    /// The user's usage is on track to be well below their usage in the previous period.
+    pub(crate) usage_declined: bool,
+    /// The user has failed to pay one or more invoices.
+    pub(crate) has_overdue_invoices: bool,
+    /// The subscription is scheduled to be canceled.
+    pub(crate) is_canceling: bool,
+    /// The user's trial is about to end and they haven't added a payment method.
+    pub(crate) trial_ending_without_payment_method: bool,
+}
+
+impl ChurnRiskSignals {
+    pub(crate) fn churn_risk(&self) -> ChurnRisk {
+        // Users who are canceling or not paying are already on their way out.
+        if self.is_canceling || self.has_overdue_invoices {
+            return ChurnRisk::High;
+        }
+
+        let warning_signs = [
+            self.usage_declined,
+            self.trial_ending_without_payment_method,
+        ]
+        .into_iter()
+        .filter(|signal| *signal)
+        .count();
+
+        match warning_signs {
+            0 => ChurnRisk::Low,
+            1 => ChurnRisk::Medium,
+            _ => ChurnRisk::High,
+        }
+    }
+}
+
+/// The portion of the current period that needs to have elapsed before we compare its usage to that of the previous
+/// period. Before that, projecting the usage forward is too noisy to be useful.
+const MIN_ELAPSED_PERIOD_FOR_USAGE_COMPARISON: f64 = 0.25;
+
+/// Returns whether the usage in the current period is on track to be less than half that of the previous period.
+///
+/// `elapsed_period` is the portion of the current period that has elapsed, from `0.0` to `1.0`.
+pub(crate) fn is_usage_declining(
+    current_requests: i32,
+    previous_requests: i32,
+    elapsed_period: f64,
+) -> bool {
+    if previous_requests <= 0 || elapsed_period < MIN_ELAPSED_PERIOD_FOR_USAGE_COMPARISON {
+        return false;
+    }
+
+    let projected_requests = current_requests as f64 / elapsed_period.min(1.0);
+    projected_requests < previous_requests as f64 / 2.
+}
+
+/// How far out from the end of a trial we start considering a missing payment method to be a risk.
+const TRIAL_ENDING_SOON: chrono::Duration = chrono::Duration::days(3);
+
+#[derive(Debug, Serialize)]
+struct ChurnRiskUserJson {
+    user_id: UserId,
+    github_login: String,
+    billing_subscription_id: BillingSubscriptionId,
+    churn_risk: ChurnRisk,
+    signals: ChurnRiskSignals,
+}
+
+#[derive(Debug, Serialize)]
+struct GetChurnRiskResponse {
+    users: Vec<ChurnRiskUserJson>,
+}
+
+/// Returns the paying and trialing users that are at risk of churning, from highest to lowest risk.
+async fn get_churn_risk(
+    Extension(app): Extension<Arc<AppState>>,
+) -> Result<Json<GetChurnRiskResponse>> {
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let Some(llm_db) = app.llm_db.clone() else {
+        return Err(BillingError::LlmDatabaseNotAvailable.into());
+    };
+
+    let now = Utc::now();
+    let subscriptions = app
+        .db
+        .get_active_zed_pro_and_trial_billing_subscriptions()
+        .await?;
+
+    let mut at_risk = Vec::new();
+    for (user_id, (billing_customer, billing_subscription)) in subscriptions {
+        let period = billing_subscription
+            .current_period_start_at()
+            .zip(billing_subscription.current_period_end_at());
+
+        let usage_declined = if let Some((period_start_at, period_end_at)) = period {
+            let period_length = (period_end_at - period_start_at).num_seconds().max(1);
+            let elapsed_period =
+                (now - period_start_at).num_seconds() as f64 / period_length as f64;
+
+            let current_requests = llm_db
+                .get_subscription_usage_for_period(user_id, period_start_at, period_end_at)
+                .await?
+                .map_or(0, |usage| usage.model_requests);
+            let previous_requests = llm_db
+                .get_previous_subscription_usage(user_id, period_start_at)
+                .await?
+                .map_or(0, |usage| usage.model_requests);
+
+            is_usage_declining(current_requests, previous_requests, elapsed_period)
+        } else {
+            false
+        };
+
+        let trial_ending_soon = billing_subscription.kind == Some(SubscriptionKind::ZedProTrial)
+            && period.is_some_and(|(_, period_end_at)| period_end_at - now <= TRIAL_ENDING_SOON);
+        let trial_ending_without_payment_method = if trial_ending_soon {
+            let customer_id = StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+            !stripe_client
+                .get_customer(&customer_id)
+                .await?
+                .has_default_payment_method
+        } else {
+            false
+        };
+
+        let signals = ChurnRiskSignals {
+            usage_declined,
+            has_overdue_invoices: billing_customer.has_overdue_invoices,
+            is_canceling: billing_subscription.stripe_cancel_at_period_end
+                || billing_subscription.stripe_cancel_at.is_some(),
+            trial_ending_without_payment_method,
+        };
+
+        let churn_risk = signals.churn_risk();
+        if churn_risk > ChurnRisk::Low {
+            at_risk.push((user_id, billing_subscription.id, churn_risk, signals));
+        }
+    }
+
+    let mut github_logins = HashMap::default();
+    let user_ids = at_risk
+        .iter()
+        .map(|(user_id, ..)| *user_id)
+        .collect::<Vec<_>>();
+    for user_ids in user_ids.chunks(1000) {
+        for user in app.db.get_users_by_ids(user_ids.to_vec()).await? {
+            github_logins.insert(user.id, user.github_login);
+        }
+    }
+
+    let mut users = at_risk
+        .into_iter()
+        .map(
+            |(user_id, billing_subscription_id, churn_risk, signals)| ChurnRiskUserJson {
+                user_id,
+                github_login: github_logins.remove(&user_id).unwrap_or_default(),
+                billing_subscription_id,
+                churn_risk,
+                signals,
+            },
+        )
+        .collect::<Vec<_>>();
+    users.sort_by_key(|user| (std::cmp::Reverse(user.churn_risk), user.user_id));
+
+    Ok(Json(GetChurnRiskResponse { users }))
+}
+
+/// The maximum number of subscriptions to migrate to a new price concurrently.
+const MIGRATE_PRICE_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct MigrateSubscriptionsToPriceBody {
+    pub(crate) old_price_id: String,
+    pub(crate) new_price_id: String,
+    /// Whether to prorate the change for the remainder of the current period.
+    ///
+    /// When `false`, the new price takes effect at the next renewal.
+    #[serde(default)]
+    pub(crate) prorate: bool,
+    /// Whether to only report on the subscriptions that would be migrated, without migrating them.
+    #[serde(default)]
+    pub(crate) dry_run: bool,
+    /// Whether to only migrate the subscriptions whose subscribers were given notice of the new price, once the
+    /// notice's effective date has passed.
+    #[serde(default)]
+    pub(crate) require_notice: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct FailedPriceMigrationJson {
+    pub(crate) stripe_subscription_id: String,
+    pub(crate) error: String,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(crate) struct MigrateSubscriptionsToPriceResponse {
+    pub(crate) dry_run: bool,
+    /// The Stripe subscriptions that were (or, for a dry run, would be) migrated.
+    pub(crate) migrated: Vec<String>,
+    /// The number of subscriptions that weren't on the old price.
+    pub(crate) skipped: usize,
+    /// The Stripe subscriptions that weren't migrated because notice of the new price is required and wasn't given.
+    pub(crate) awaiting_notice: Vec<String>,
+    pub(crate) failed: Vec<FailedPriceMigrationJson>,
+}
+
+/// Moves all Zed Pro subscriptions (including trials) from one Stripe price to another.
+async fn migrate_subscriptions_to_price(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<MigrateSubscriptionsToPriceBody>,
+) -> Result<Json<MigrateSubscriptionsToPriceResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let report = migrate_subscriptions_to_price_inner(&app, &stripe_client, &body).await?;
+
+    log::info!(
+        "migrated subscriptions from price {} to {} (dry run: {}): {} migrated, {} skipped, {} awaiting notice, {} failed",
+        body.old_price_id,
+        body.new_price_id,
+        report.dry_run,
+        report.migrated.len(),
+        report.skipped,
+        report.awaiting_notice.len(),
+        report.failed.len()
+    );
+
+    Ok(Json(report))
+}
+
+pub(crate) async fn migrate_subscriptions_to_price_inner(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    body: &MigrateSubscriptionsToPriceBody,
+) -> Result<MigrateSubscriptionsToPriceResponse> {
+    if body.old_price_id == body.new_price_id {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            "old and new prices must be different".into(),
+        ));
+    }
+
+    let old_price_id = StripePriceId(body.old_price_id.clone().into());
+    let new_price_id = StripePriceId(body.new_price_id.clone().into());
+
+    let prices = stripe_client.list_prices().await?;
+    if !prices.iter().any(|price| price.id == new_price_id) {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            format!("price {new_price_id} not found"),
+        ));
+    }
+
+    let proration_behavior = if body.prorate {
+        StripeProrationBehavior::CreateProrations
+    } else {
+        StripeProrationBehavior::None
+    };
+
+    enum Outcome {
+        Migrated,
+        Skipped,
+        AwaitingNotice,
+        Failed(anyhow::Error),
+    }
+
+    let subscriptions = app
+        .db
+        .get_active_zed_pro_and_trial_billing_subscriptions()
+        .await?;
+
+    let outcomes = futures::stream::iter(subscriptions.into_values())
+        .map(|(_billing_customer, billing_subscription)| {
+            let old_price_id = &old_price_id;
+            let new_price_id = &new_price_id;
+            async move {
+                let billing_subscription_id = billing_subscription.id;
+                let stripe_subscription_id =
+                    StripeSubscriptionId(billing_subscription.stripe_subscription_id.into());
+
+                let outcome = async {
+                    let subscription = stripe_client
+                        .get_subscription(&stripe_subscription_id)
+                        .await?;
+                    let Some(item) = subscription.items.iter().find(|item| {
+                        item.price
+                            .as_ref()
+                            .is_some_and(|price| &price.id == old_price_id)
+                    }) else {
+                        return anyhow::Ok(Outcome::Skipped);
+                    };
+
+                    if body.require_notice {
+                        let notice = app
+                            .db
+                            .get_billing_price_change_notice(
+                                billing_subscription_id,
+                                &body.new_price_id,
+                            )
+                            .await?;
+                        let notice_given = notice
+                            .is_some_and(|notice| notice.effective_at.and_utc() <= Utc::now());
+                        if !notice_given {
+                            return Ok(Outcome::AwaitingNotice);
+                        }
+                    }
+
+                    if !body.dry_run {
+                        stripe_client
+                            .update_subscription(
+                                &stripe_subscription_id,
+                                UpdateSubscriptionParams {
+                                    items: Some(vec![UpdateSubscriptionItems {
+                                        id: Some(item.id.clone()),
+                                        price: Some(new_price_id.clone()),
+                                    }]),
+                                    proration_behavior: Some(proration_behavior),
+                                    ..Default::default()
+                                },
+                            )
+                            .await?;
+                    }
+
+                    Ok(Outcome::Migrated)
+                }
+                .await
+                .unwrap_or_else(Outcome::Failed);
+
+                (stripe_subscription_id, outcome)
+            }
+        })
+        .buffer_unordered(MIGRATE_PRICE_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut report = MigrateSubscriptionsToPriceResponse {
+        dry_run: body.dry_run,
+        ..Default::default()
+    };
+    for (stripe_subscription_id, outcome) in outcomes {
+        match outcome {
+            Outcome::Migrated => report.migrated.push(stripe_subscription_id.to_string()),
+            Outcome::Skipped => report.skipped += 1,
+            Outcome::AwaitingNotice => report
+                .awaiting_notice
+                .push(stripe_subscription_id.to_string()),
+            Outcome::Failed(error) => {
+                log::error!(
+                    "failed to migrate subscription {stripe_subscription_id} to price {new_price_id}: {error:?}"
+                );
+                report.failed.push(FailedPriceMigrationJson {
+                    stripe_subscription_id: stripe_subscription_id.to_string(),
+                    error: error.to_string(),
+                });
+            }
+        }
+    }
+    report.migrated.sort();
+    report.awaiting_notice.sort();
+    report
+        .failed
+        .sort_by(|a, b| a.stripe_subscription_id.cmp(&b.stripe_subscription_id));
+
+    Ok(report)
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct NotifyPriceChangeBody {
+    pub(crate) old_price_id: String,
+    pub(crate) new_price_id: String,
+    /// When the new price takes effect.
+    pub(crate) effective_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub(crate) struct NotifyPriceChangeResponse {
+    /// The Stripe subscriptions whose subscribers were given notice.
+    pub(crate) notified: Vec<String>,
+    /// The number of subscriptions whose subscribers were already given notice of the new price.
+    pub(crate) already_notified: usize,
+    /// The number of subscriptions that weren't on the old price.
+    pub(crate) skipped: usize,
+    pub(crate) failed: Vec<FailedPriceMigrationJson>,
+}
+
+/// Gives the subscribers on one Stripe price notice that their subscription is moving to another.
+///
+/// We record each notice, so that `migrate_price` can check that notice was given before it re-prices a subscription.
+/// Subscribers are only notified once per price, so it's safe to run this again (e.g., after some of the
+/// notifications failed).
+async fn notify_price_change(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<Server>>,
+    extract::Json(body): extract::Json<NotifyPriceChangeBody>,
+) -> Result<Json<NotifyPriceChangeResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let (report, notifications) =
+        notify_price_change_inner(&app, &stripe_client, &body, Utc::now()).await?;
+
+    rpc_server.send_notifications(notifications);
+
+    log::info!(
+        "gave notice of the change from price {} to {}: {} notified, {} already notified, {} skipped, {} failed",
+        body.old_price_id,
+        body.new_price_id,
+        report.notified.len(),
+        report.already_notified,
+        report.skipped,
+        report.failed.len()
+    );
+
+    Ok(Json(report))
+}
+
+pub(crate) async fn notify_price_change_inner(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    body: &NotifyPriceChangeBody,
+    now: DateTime<Utc>,
+) -> Result<(NotifyPriceChangeResponse, NotificationBatch)> {
+    if body.old_price_id == body.new_price_id {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            "old and new prices must be different".into(),
+        ));
+    }
+
+    if body.effective_at <= now {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            "price changes must take effect in the future".into(),
+        ));
+    }
+
+    let old_price_id = StripePriceId(body.old_price_id.clone().into());
+    let new_price_id = StripePriceId(body.new_price_id.clone().into());
+
+    let prices = stripe_client.list_prices().await?;
+    let Some(new_amount_in_cents) = prices
+        .iter()
+        .find(|price| price.id == new_price_id)
+        .and_then(|price| price.unit_amount)
+    else {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            format!("price {new_price_id} not found"),
+        ));
+    };
+
+    let subscriptions = app
+        .db
+        .get_active_zed_pro_and_trial_billing_subscriptions()
+        .await?;
+
+    enum Outcome {
+        Notified(NotificationBatch),
+        AlreadyNotified,
+        Skipped,
+        Failed(anyhow::Error),
+    }
+
+    let mut report = NotifyPriceChangeResponse::default();
+    let mut notifications = NotificationBatch::new();
+    for (billing_customer, billing_subscription) in subscriptions.into_values() {
+        let stripe_subscription_id =
+            StripeSubscriptionId(billing_subscription.stripe_subscription_id.clone().into());
+
+        let outcome = async {
+            let subscription = stripe_client
+                .get_subscription(&stripe_subscription_id)
+                .await?;
+            let is_on_old_price = subscription.items.iter().any(|item| {
+                item.price
+                    .as_ref()
+                    .is_some_and(|price| price.id == old_price_id)
+            });
+            if !is_on_old_price {
+                return anyhow::Ok(Outcome::Skipped);
+            }
+
+            let notice = app
+                .db
+                .create_billing_price_change_notice(&CreateBillingPriceChangeNoticeParams {
+                    user_id: billing_customer.user_id,
+                    billing_subscription_id: billing_subscription.id,
+                    stripe_price_id: body.new_price_id.clone(),
+                    amount_in_cents: new_amount_in_cents,
+                    effective_at: body.effective_at.naive_utc(),
+                })
+                .await?;
+
+            Ok(match notice {
+                Some((_notice, notifications)) => Outcome::Notified(notifications),
+                None => Outcome::AlreadyNotified,
+            })
+        }
+        .await
+        .unwrap_or_else(Outcome::Failed);
+
+        match outcome {
+            Outcome::Notified(user_notifications) => {
+                report.notified.push(stripe_subscription_id.to_string());
+                notifications.extend(user_notifications);
+            }
+            Outcome::AlreadyNotified => report.already_notified += 1,
+            Outcome::Skipped => report.skipped += 1,
+            Outcome::Failed(error) => {
+                log::error!(
+                    "failed to give notice of price {new_price_id} for subscription {stripe_subscription_id}: {error:?}"
+                );
+                report.failed.push(FailedPriceMigrationJson {
+                    stripe_subscription_id: stripe_subscription_id.to_string(),
+                    error: error.to_string(),
+                });
+            }
+        }
+    }
+    report.notified.sort();
+    report
+        .failed
+        .sort_by(|a, b| a.stripe_subscription_id.cmp(&b.stripe_subscription_id));
+
+    Ok((report, notifications))
+}
+
+/// Returns how requests to the given model in the given mode are billed, for
+/// crediting them back.
+///
+/// The usage sync only bills the modes that have an entry of their own, so requests in any other mode were never
+/// billed, and there's nothing to credit for them.
+pub(crate) fn model_request_billing_for_credit<'a>(
+    billing_table: &'a [ModelRequestBilling],
+    model: &str,
+    mode: CompletionMode,
+) -> Option<&'a ModelRequestBilling> {
+    billing_table.iter().find(|billing| {
+        billing.model == model && billing.mode == mode && billing.dimensions.is_empty()
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdateBillingSuspensionBody {
+    github_user_id: i32,
+    suspended: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct UpdateBillingSuspensionResponse {
+    billing_suspended: bool,
+}
+
+/// Suspends or resumes billing for a user's usage, e.g., while we investigate a dispute.
+///
+/// While billing is suspended, the usage sync records what it would have billed the user, without reporting it to
+/// Stripe. A suspension doesn't expire, so billing has to be resumed explicitly.
+async fn update_billing_suspension(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<Server>>,
+    extract::Json(body): extract::Json<UpdateBillingSuspensionBody>,
+) -> Result<Json<UpdateBillingSuspensionResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    update_billing_suspension_for_user(&app, &user, body.suspended).await?;
+
+    rpc_server.refresh_llm_tokens_for_user(user.id).await;
+
+    Ok(Json(UpdateBillingSuspensionResponse {
+        billing_suspended: body.suspended,
+    }))
+}
+
+pub(crate) async fn update_billing_suspension_for_user(
+    app: &Arc<AppState>,
+    user: &User,
+    suspended: bool,
+) -> Result<()> {
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await?
+        .context("billing customer not found")?;
+
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                billing_suspended: ActiveValue::set(suspended),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    if suspended {
+        log::info!("suspended billing for user {}", user.id);
+    } else {
+        log::info!("resumed billing for user {}", user.id);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ClearOverdueInvoicesBody {
+    github_user_id: i32,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct ClearOverdueInvoicesResponse {
+    pub(crate) has_overdue_invoices: bool,
+    /// The IDs of the customer's invoices that Stripe still has open, which keep them overdue.
+    pub(crate) open_invoice_ids: Vec<String>,
+}
+
+/// Clears the overdue state of a customer who settled their invoices outside of a subscription payment (e.g., by
+/// paying an invoice directly).
+///
+/// We only clear it once Stripe confirms that none of the customer's invoices are still open, so the response reflects
+/// what Stripe reported, rather than what was asked for.
+async fn clear_overdue_invoices(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<ClearOverdueInvoicesBody>,
+) -> Result<Json<ClearOverdueInvoicesResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_client) = app.stripe_client.clone() else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let response = clear_overdue_invoices_for_user(&app, &stripe_client, &user).await?;
+
+    Ok(Json(response))
+}
+
+pub(crate) async fn clear_overdue_invoices_for_user(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+    user: &User,
+) -> Result<ClearOverdueInvoicesResponse> {
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await?
+        .context("billing customer not found")?;
+
+    let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+    let open_invoice_ids = stripe_client
+        .list_invoices_for_customer(&stripe_customer_id, MAX_INVOICES_LIMIT)
+        .await?
+        .into_iter()
+        .filter(|invoice| invoice.status == Some(StripeInvoiceStatus::Open))
+        .map(|invoice| invoice.id.to_string())
+        .collect::<Vec<_>>();
+
+    if !open_invoice_ids.is_empty() {
+        log::info!(
+            "not clearing overdue invoices for user {user_id}, as they still have open invoices {open_invoice_ids:?}",
+            user_id = user.id,
+        );
+        return Ok(ClearOverdueInvoicesResponse {
+            has_overdue_invoices: billing_customer.has_overdue_invoices,
+            open_invoice_ids,
+        });
+    }
+
+    if billing_customer.has_overdue_invoices {
+        app.db
+            .update_billing_customer(
+                billing_customer.id,
+                &UpdateBillingCustomerParams {
+                    has_overdue_invoices: ActiveValue::set(false),
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        log::info!("cleared overdue invoices for user {}", user.id);
+    }
+
+    Ok(ClearOverdueInvoicesResponse {
+        has_overdue_invoices: false,
+        open_invoice_ids,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreditModelRequestUsageBody {
+    pub(crate) github_user_id: i32,
+    pub(crate) model: String,
+    pub(crate) mode: CompletionMode,
+    pub(crate) requests: i32,
+    pub(crate) reason: String,
+    /// The key under which Stripe dedupes retries of this request.
+    pub(crate) idempotency_key: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CreditModelRequestUsageResponse {
+    pub(crate) amount_in_cents: i64,
+    pub(crate) stripe_balance_transaction_id: String,
+}
+
+/// Credits a user for disputed model requests.
+///
+/// The requests are weighted and priced the same way the usage sync billed
+/// them, and the credit is added to the customer's balance, where it offsets
+/// their next invoice.
+pub(crate) async fn credit_model_request_usage(
+    Extension(app): Extension<Arc<AppState>>,
+    extract::Json(body): extract::Json<CreditModelRequestUsageBody>,
+) -> Result<Json<CreditModelRequestUsageResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some(stripe_billing) = app.stripe_billing.clone() else {
+        log::error!("failed to retrieve Stripe billing object");
+        Err(BillingError::NotSupported)?
+    };
+
+    let user = app
+        .db
+        .get_user_by_github_user_id(body.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let adjustment =
+        credit_model_request_usage_for_user(&app, &stripe_billing, &user, &body).await?;
+
+    Ok(Json(CreditModelRequestUsageResponse {
+        amount_in_cents: adjustment.amount_in_cents,
+        stripe_balance_transaction_id: adjustment.stripe_balance_transaction_id,
+    }))
+}
+
+pub(crate) async fn credit_model_request_usage_for_user(
+    app: &Arc<AppState>,
+    stripe_billing: &Arc<StripeBilling>,
+    user: &User,
+    body: &CreditModelRequestUsageBody,
+) -> Result<billing_usage_adjustment::Model> {
+    if body.requests <= 0 {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            "requests must be positive".into(),
+        ));
+    }
+
+    if body.reason.trim().is_empty() {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            "a reason is required".into(),
+        ));
+    }
+
+    let billing_table = model_request_billing_table(&app.config);
+    let Some(billing) = model_request_billing_for_credit(&billing_table, &body.model, body.mode)
+    else {
+        return Err(Error::http(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "no price for {} requests in {} mode",
+                body.model,
+                body.mode.as_str()
+            ),
+        ));
+    };
+
+    let Some(billing_customer) = app.db.get_billing_customer_by_user_id(user.id).await? else {
+        return Err(BillingError::BillingCustomerNotFound.into());
+    };
+
+    // Credits are given at the price the customer is billed at, which may be a custom price.
+    let price = price_for_customer(
+        app,
+        stripe_billing,
+        Some(&billing_customer),
+        &billing.meter_event_name,
+        &billing.price_lookup_key,
+    )
+    .await?;
+
+    // Each request was billed as `request_weight` requests, so that's what we credit back.
+    let weighted_requests = weighted_requests(body.requests, billing.request_weight);
+
+    let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+    let transaction = stripe_billing
+        .credit_model_request_usage_at_price(
+            &stripe_customer_id,
+            &price,
+            weighted_requests,
+            &billing_customer.currency,
+            &format!(
+                "Credit for {} {} requests ({} mode): {}",
+                body.requests,
+                body.model,
+                body.mode.as_str(),
+                body.reason
+            ),
+            body.idempotency_key.as_deref(),
+        )
+        .await?;
+
+    let adjustment = app
+        .db
+        .create_billing_usage_adjustment(&CreateBillingUsageAdjustmentParams {
+            billing_customer_id: billing_customer.id,
+            model: body.model.clone(),
+            mode: body.mode.as_str().to_string(),
+            requests: weighted_requests,
+            amount_in_cents: transaction.amount,
+            reason: body.reason.clone(),
+            stripe_balance_transaction_id: transaction.id,
+        })
+        .await?;
+
+    Ok(adjustment)
+}
+
+#[derive(Debug, Deserialize)]
+struct ListMeterReportsParams {
+    github_user_id: i32,
+    /// The period to list the meter events for, as an ISO 8601 time interval (`<start>/<end>`).
+    ///
+    /// Defaults to the user's current billing period.
+    period: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ListMeterReportsResponse {
+    period_start_at: String,
+    period_end_at: String,
+    reports: Vec<MeterReport>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct MeterReport {
+    pub(crate) stripe_customer_id: String,
+    pub(crate) meter_event_name: String,
+    pub(crate) value: i32,
+    pub(crate) identifier: String,
+    pub(crate) reported_at: String,
+}
+
+/// Lists the usage meter events that were reported to Stripe for a user in a billing period.
+async fn list_meter_reports(
+    Extension(app): Extension<Arc<AppState>>,
+    Query(params): Query<ListMeterReportsParams>,
+) -> Result<Json<ListMeterReportsResponse>> {
+    let user = app
+        .db
+        .get_user_by_github_user_id(params.github_user_id)
+        .await?
+        .context("user not found")?;
+
+    let period = match params.period.as_deref() {
+        Some(period) => parse_period(period)?,
+        None => {
+            let period = app
+                .db
+                .get_active_billing_subscription(user.id)
+                .await?
+                .and_then(|subscription| {
+                    subscription
+                        .current_period_start_at()
+                        .zip(subscription.current_period_end_at())
+                });
+            let Some(period) = period else {
+                return Err(Error::http(
+                    StatusCode::BAD_REQUEST,
+                    "user has no current billing period, so a period is required".into(),
+                ));
+            };
+            period
+        }
+    };
+
+    let reports = meter_reports_for_user(&app, &user, period).await?;
+
+    Ok(Json(ListMeterReportsResponse {
+        period_start_at: period.0.to_rfc3339_opts(SecondsFormat::Millis, true),
+        period_end_at: period.1.to_rfc3339_opts(SecondsFormat::Millis, true),
+        reports,
+    }))
+}
+
+/// Parses an ISO 8601 time interval of the form `<start>/<end>`, where both ends are RFC 3339 timestamps.
+pub(crate) fn parse_period(period: &str) -> Result<(DateTime<Utc>, DateTime<Utc>)> {
+    let invalid_period = || {
+        Error::http(
+            StatusCode::BAD_REQUEST,
+            format!("invalid period: {period:?}, expected <start>/<end>"),
+        )
+    };
+
+    let (start_at, end_at) = period.split_once('/').ok_or_else(invalid_period)?;
+    let start_at = DateTime::parse_from_rfc3339(start_at).map_err(|_| invalid_period())?;
+    let end_at = DateTime::parse_from_rfc3339(end_at).map_err(|_| invalid_period())?;
+    if end_at <= start_at {
+        return Err(invalid_period());
+    }
+
+    Ok((start_at.to_utc(), end_at.to_utc()))
+}
+
+pub(crate) async fn meter_reports_for_user(
+    app: &AppState,
+    user: &User,
+    (period_start_at, period_end_at): (DateTime<Utc>, DateTime<Utc>),
+) -> Result<Vec<MeterReport>> {
+    let Some(billing_customer) = app.db.get_billing_customer_by_user_id(user.id).await? else {
+        return Ok(Vec::new());
+    };
+
+    let reports = app
+        .db
+        .get_billing_meter_reports(
+            billing_customer.id,
+            period_start_at.naive_utc(),
+            period_end_at.naive_utc(),
+        )
+        .await?;
+
+    Ok(reports
+        .into_iter()
+        .map(|report| MeterReport {
+            stripe_customer_id: billing_customer.stripe_customer_id.clone(),
+            meter_event_name: report.meter_event_name,
+            value: report.value,
+            identifier: report.identifier,
+            reported_at: report
+                .reported_at
+                .and_utc()
+                .to_rfc3339_opts(SecondsFormat::Millis, true),
+        })
+        .collect())
+}
+
+impl From<SubscriptionStatus> for StripeSubscriptionStatus {
+    fn from(value: SubscriptionStatus) -> Self {
+        match value {
+            SubscriptionStatus::Incomplete => Self::Incomplete,
+            SubscriptionStatus::IncompleteExpired => Self::IncompleteExpired,
+            SubscriptionStatus::Trialing => Self::Trialing,
+            SubscriptionStatus::Active => Self::Active,
+            SubscriptionStatus::PastDue => Self::PastDue,
+            SubscriptionStatus::Canceled => Self::Canceled,
+            SubscriptionStatus::Unpaid => Self::Unpaid,
+            SubscriptionStatus::Paused => Self::Paused,
+        }
+    }
+}
+
+impl From<CancellationDetailsReason> for StripeCancellationReason {
+    fn from(value: CancellationDetailsReason) -> Self {
+        match value {
+            CancellationDetailsReason::CancellationRequested => Self::CancellationRequested,
+            CancellationDetailsReason::PaymentDisputed => Self::PaymentDisputed,
+            CancellationDetailsReason::PaymentFailed => Self::PaymentFailed,
+        }
+    }
+}
+
+/// The most times we try to create a billing customer when it conflicts with one created concurrently.
+const FIND_OR_CREATE_BILLING_CUSTOMER_MAX_ATTEMPTS: u32 = 3;
+const FIND_OR_CREATE_BILLING_CUSTOMER_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// Finds or creates a billing customer using the provided customer.
 ///
-/// > We poll the Stripe /events endpoint every 500ms per account
-/// >
-/// > — https://blog.sequinstream.com/events-not-webhooks/
-const POLL_EVENTS_INTERVAL: Duration = Duration::from_secs(5);
+/// Creating the billing customer can race with a concurrent sync for the same customer, in which case we use the
+/// billing customer that the other sync created.
+pub async fn find_or_create_billing_customer(
+    app: &Arc<AppState>,
+    stripe_client: &dyn StripeClient,
+    customer_id: &StripeCustomerId,
+) -> anyhow::Result<Option<billing_customer::Model>> {
+    // If we already have a billing customer record associated with the Stripe customer,
+    // there's nothing more we need to do.
+    if let Some(billing_customer) = app
+        .db
+        .get_billing_customer_by_stripe_customer_id(customer_id.0.as_ref())
+        .await?
+    {
+        return Ok(Some(billing_customer));
+    }
 
-/// The maximum number of events to return per page.
+    let customer = stripe_client.get_customer(customer_id).await?;
+
+    let Some(email) = customer.email else {
+        return Ok(None);
+    };
+
+    let Some(user) = app.db.get_user_by_email(&email).await? else {
+        return Ok(None);
+    };
+
+    let params = CreateBillingCustomerParams {
+        user_id: user.id,
+        stripe_customer_id: customer.id.to_string(),
+    };
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let error = match app.db.create_billing_customer(&params).await {
+            Ok(billing_customer) => return Ok(Some(billing_customer)),
+            Err(error) if error.is_unique_violation() => error,
+            Err(error) => return Err(error.into()),
+        };
+
+        // A concurrent sync may have created the billing customer first, in which case we use the one it created.
+        if let Some(billing_customer) = app
+            .db
+            .get_billing_customer_by_stripe_customer_id(customer_id.0.as_ref())
+            .await?
+        {
+            return Ok(Some(billing_customer));
+        }
+
+        if attempt >= FIND_OR_CREATE_BILLING_CUSTOMER_MAX_ATTEMPTS {
+            return Err(anyhow::Error::from(error)).with_context(|| {
+                format!("failed to create billing customer for Stripe customer {customer_id}")
+            });
+        }
+        log::warn!(
+            "billing customer for Stripe customer {customer_id} conflicted with an existing one, retrying (attempt {attempt})"
+        );
+        app.executor
+            .sleep(FIND_OR_CREATE_BILLING_CUSTOMER_RETRY_DELAY * attempt)
+            .await;
+    }
+}
+
+const SYNC_LLM_REQUEST_USAGE_WITH_STRIPE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How requests to a model in a given mode are billed in Stripe.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ModelRequestBilling {
+    pub(crate) provider: LanguageModelProvider,
+    pub(crate) model: String,
+    pub(crate) mode: CompletionMode,
+    /// The additional dimensions that requests are billed by, if any.
+    ///
+    /// Requests with different dimensions are billed separately, so each combination needs its own entry.
+    pub(crate) dimensions: Vec<(String, String)>,
+    /// The number of requests that each request counts as against the plan's limit.
+    ///
+    /// Usage is reported to Stripe in weighted requests, so the price is per weighted request.
+    pub(crate) request_weight: i32,
+    pub(crate) price_lookup_key: String,
+    pub(crate) meter_event_name: String,
+}
+
+impl ModelRequestBilling {
+    pub(crate) fn dimensions(&self) -> UsageDimensions {
+        UsageDimensions::new(self.dimensions.iter().cloned())
+    }
+
+    /// Parses an entry of the `model_request_billing` setting.
+    pub(crate) fn parse(entry: &str) -> Option<Self> {
+        let mut fields = entry.split_whitespace();
+        let provider = LanguageModelProvider::from_str(fields.next()?).ok()?;
+        let model = fields.next()?.to_string();
+        let mode = match fields.next()? {
+            "normal" => CompletionMode::Normal,
+            "max" => CompletionMode::Max,
+            _ => return None,
+        };
+        let request_weight = fields
+            .next()?
+            .parse::<i32>()
+            .ok()
+            .filter(|weight| *weight >= 1)?;
+        let price_lookup_key = fields.next()?.to_string();
+        let meter_event_name = fields.next()?.to_string();
+        let dimensions = fields
+            .map(|dimension| {
+                let (key, value) = dimension.split_once('=')?;
+                Some((key.to_string(), value.to_string()))
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(Self {
+            provider,
+            model,
+            mode,
+            dimensions,
+            request_weight,
+            price_lookup_key,
+            meter_event_name,
+        })
+    }
+}
+
+/// Returns how requests to each model are billed: the `model_request_billing` setting when it's set, and
+/// [`default_model_request_billing`] otherwise.
 ///
-/// We set this to 100 (the max) so we have to make fewer requests to Stripe.
+/// Malformed entries are skipped, so that a typo in one entry doesn't stop us from billing the rest.
+pub(crate) fn model_request_billing_table(config: &Config) -> Vec<ModelRequestBilling> {
+    let Some(entries) = &config.model_request_billing else {
+        return default_model_request_billing();
+    };
+
+    entries
+        .iter()
+        .filter_map(|entry| {
+            let billing = ModelRequestBilling::parse(entry);
+            if billing.is_none() {
+                log::warn!("ignoring malformed model request billing: {entry:?}");
+            }
+            billing
+        })
+        .collect()
+}
+
+/// Returns how requests with the given provider, model, mode, and dimensions are billed, if they are.
+pub(crate) fn model_request_billing<'a>(
+    billing_table: &'a [ModelRequestBilling],
+    provider: LanguageModelProvider,
+    model: &str,
+    mode: CompletionMode,
+    dimensions: &UsageDimensions,
+) -> Option<&'a ModelRequestBilling> {
+    billing_table.iter().find(|billing| {
+        billing.provider == provider
+            && billing.model == model
+            && billing.mode == mode
+            && billing.dimensions() == *dimensions
+    })
+}
+
+/// Returns the number of requests that a request with the given provider, model, mode, and dimensions counts as.
 ///
-/// > Limit can range between 1 and 100, and the default is 10.
-const EVENTS_LIMIT_PER_PAGE: u64 = 100;
+/// Requests that we don't bill count as a single request.
+pub(crate) fn model_request_weight(
+    billing_table: &[ModelRequestBilling],
+    provider: LanguageModelProvider,
+    model: &str,
+    mode: CompletionMode,
+    dimensions: &UsageDimensions,
+) -> i32 {
+    model_request_billing(billing_table, provider, model, mode, dimensions)
+        .map_or(1, |billing| billing.request_weight)
+}
 
-/// The number of pages consisting entirely of already-processed events that we
-/// will see before we stop retrieving events.
+/// Returns the number of requests that the given requests count as, when each of them counts as `weight` requests.
 ///
-/// This is used to prevent over-fetching the Stripe events API for events we've
-/// already seen and processed.
-const NUMBER_OF_ALREADY_PROCESSED_PAGES_BEFORE_WE_STOP: usize = 4;
+/// The usage meters that the LLM service records count each request once, including the requests beyond the plan's
+/// limit, so this is the only place where we weight them. Everything that compares usage against limits or bills for
+/// it works with the weighted count.
+pub(crate) fn weighted_requests(requests: i32, weight: i32) -> i32 {
+    requests.saturating_mul(weight)
+}
 
-/// Polls the Stripe events API periodically to reconcile the records in our
-/// database with the data in Stripe.
-pub fn poll_stripe_events_periodically(app: Arc<AppState>, rpc_server: Arc<Server>) {
-    let Some(real_stripe_client) = app.real_stripe_client.clone() else {
-        log::warn!("failed to retrieve Stripe client");
+/// Returns the number of weighted requests to bill for each of the given weighted request counts, after the plan's
+/// per-model allotments and the free overage grace.
+///
+/// With a pooled allotment, the usage meters only count the requests beyond the plan's limit, so all of them are
+/// billed. With per-model allotments, the meters count all of the requests, and each model's requests are only
+/// billed once they've used up that model's allotment. Models without an allotment are billed for every request.
+///
+/// The allotments and the grace are used up in the order of the billing table, so that the sync and the usage we
+/// show agree on which requests are free. Requests that we don't bill neither use up the grace nor are billed.
+///
+/// The request counts are totals for the current billing period, so the allotments and the grace are only applied
+/// once per period, no matter how many times we sync.
+pub(crate) fn billed_model_requests(
+    billing_table: &[ModelRequestBilling],
+    model_requests: &[(Option<&ModelRequestBilling>, i32)],
+    allotments: &[ModelRequestAllotment],
+    overage_grace: i32,
+) -> Vec<i32> {
+    let mut order = (0..model_requests.len()).collect::<Vec<_>>();
+    order.sort_by_key(|ix| {
+        model_requests[*ix].0.and_then(|billing| {
+            billing_table
+                .iter()
+                .position(|entry| entry.meter_event_name == billing.meter_event_name)
+        })
+    });
+
+    let mut remaining_allotments = allotments
+        .iter()
+        .filter_map(|allotment| {
+            let model = allotment.model.as_deref()?;
+            Some((model, allotment.limit.unwrap_or(i32::MAX)))
+        })
+        .collect::<HashMap<_, _>>();
+    let mut remaining_grace = overage_grace.max(0);
+    let mut billed_requests = vec![0; model_requests.len()];
+    for ix in order {
+        let (Some(billing), requests) = model_requests[ix] else {
+            continue;
+        };
+
+        let mut weighted_requests = requests.max(0);
+        if let Some(remaining_allotment) = remaining_allotments.get_mut(billing.model.as_str()) {
+            let included_requests = weighted_requests.min(*remaining_allotment);
+            *remaining_allotment -= included_requests;
+            weighted_requests -= included_requests;
+        }
+
+        let free_requests = weighted_requests.min(remaining_grace);
+        remaining_grace -= free_requests;
+        billed_requests[ix] = weighted_requests - free_requests;
+    }
+
+    billed_requests
+}
+
+/// Returns the number of model requests that can be made before overages are charged for, given the requests used so
+/// far.
+///
+/// Only Zed Pro users with overages enabled are charged for overages, and only once they're past both the plan's
+/// pooled limit and the free overage grace.
+pub(crate) fn overage_begins_in_requests(
+    plan: zed_llm_client::Plan,
+    overages_enabled: bool,
+    model_requests: &UsageCounts,
+    overage_grace: i32,
+) -> Option<i32> {
+    if plan != zed_llm_client::Plan::ZedPro || !overages_enabled {
+        return None;
+    }
+
+    let limit = model_requests.limit?;
+    Some((limit + overage_grace - model_requests.used).max(0))
+}
+
+/// Returns the overage that is billed, after the free overage grace.
+pub(crate) fn billed_overage(overage: i32, overage_grace: i32) -> i32 {
+    (overage - overage_grace.max(0)).max(0)
+}
+
+/// Returns the models whose requests count as more than one request.
+pub(crate) fn model_request_weights(
+    billing_table: &[ModelRequestBilling],
+) -> Vec<ModelRequestWeight> {
+    billing_table
+        .iter()
+        .filter(|billing| billing.request_weight != 1)
+        .map(|billing| ModelRequestWeight {
+            provider: billing.provider,
+            model: billing.model.clone(),
+            mode: billing.mode,
+            weight: billing.request_weight,
+        })
+        .collect()
+}
+
+/// Returns the weighted number of model requests that count against the plan's limit.
+///
+/// `model_requests` is the number of requests made, each counted once, so each usage only adds what its weighting
+/// adds beyond that.
+pub(crate) fn weighted_model_requests(
+    model_requests: i32,
+    model_request_usage: &[ModelRequestUsage],
+) -> i32 {
+    model_request_usage
+        .iter()
+        .fold(model_requests, |total, usage| {
+            total.saturating_add(usage.weighted_requests.saturating_sub(usage.requests))
+        })
+}
+
+/// Returns the model requests used out of each of the given allotments.
+///
+/// A pooled allotment is used by all of the weighted `model_requests`, while a per-model allotment is only used by
+/// the weighted requests to its model.
+pub(crate) fn model_request_allotment_usage(
+    allotments: &[ModelRequestAllotment],
+    model_requests: i32,
+    model_request_usage: &[ModelRequestUsage],
+    resets_at: DateTime<Utc>,
+) -> Vec<ModelRequestAllotmentUsage> {
+    allotments
+        .iter()
+        .map(|allotment| {
+            let used = match &allotment.model {
+                Some(model) => model_request_usage
+                    .iter()
+                    .filter(|usage| usage.model == *model)
+                    .fold(0, |total: i32, usage| {
+                        total.saturating_add(usage.weighted_requests.max(0))
+                    }),
+                None => model_requests,
+            };
+
+            ModelRequestAllotmentUsage {
+                model: allotment.model.clone(),
+                requests: UsageCounts::new(used, allotment.limit, resets_at),
+            }
+        })
+        .collect()
+}
+
+/// The combination of model, mode, and dimensions that requests are billed by.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub(crate) struct UsageMeterKey {
+    pub(crate) model_id: ModelId,
+    pub(crate) mode: CompletionMode,
+    pub(crate) dimensions: UsageDimensions,
+}
+
+/// Sums the requests in the usage meters for each combination of model, mode, and dimensions.
+pub(crate) fn requests_by_usage_meter_key<'a>(
+    usage_meters: impl IntoIterator<Item = &'a subscription_usage_meter::Model>,
+) -> HashMap<UsageMeterKey, i32> {
+    let mut requests = HashMap::<UsageMeterKey, i32>::default();
+    for usage_meter in usage_meters {
+        let key = UsageMeterKey {
+            model_id: usage_meter.model_id,
+            mode: usage_meter.mode,
+            dimensions: usage_meter.dimensions(),
+        };
+        let total = requests.entry(key).or_default();
+        *total = total.saturating_add(usage_meter.requests);
+    }
+    requests
+}
+
+/// The models whose requests are billed by default, along with the Stripe prices and meter events they're billed
+/// with.
+///
+/// The `model_request_billing` setting replaces this table, so that billing a new model doesn't take a deploy.
+/// Entries for models that the LLM database doesn't know about are skipped by the usage sync.
+///
+/// Meter event names and price lookup keys must be unique across providers, so those of providers other than
+/// Anthropic are prefixed with the provider (e.g., `openai/gpt_4o/requests` and `openai-gpt-4o-requests`). Anthropic's
+/// predate other providers and are left unprefixed, since renaming them would orphan their existing Stripe meters.
+pub(crate) fn default_model_request_billing() -> Vec<ModelRequestBilling> {
+    let anthropic =
+        |model: &str, mode, price_lookup_key: &str, meter_event_name: &str| ModelRequestBilling {
+            provider: LanguageModelProvider::Anthropic,
+            model: model.to_string(),
+            mode,
+            dimensions: Vec::new(),
+            request_weight: 1,
+            price_lookup_key: price_lookup_key.to_string(),
+            meter_event_name: meter_event_name.to_string(),
+        };
+
+    vec![
+        anthropic(
+            "claude-opus-4",
+            CompletionMode::Max,
+            "claude-opus-4-requests-max",
+            "claude_opus_4/requests/max",
+        ),
+        anthropic(
+            "claude-opus-4",
+            CompletionMode::Normal,
+            "claude-opus-4-requests",
+            "claude_opus_4/requests",
+        ),
+        anthropic(
+            "claude-sonnet-4",
+            CompletionMode::Max,
+            "claude-sonnet-4-requests-max",
+            "claude_sonnet_4/requests/max",
+        ),
+        anthropic(
+            "claude-sonnet-4",
+            CompletionMode::Normal,
+            "claude-sonnet-4-requests",
+            "claude_sonnet_4/requests",
+        ),
+        anthropic(
+            "claude-3-7-sonnet",
+            CompletionMode::Max,
+            "claude-3-7-sonnet-requests-max",
+            "claude_3_7_sonnet/requests/max",
+        ),
+        anthropic(
+            "claude-3-7-sonnet",
+            CompletionMode::Normal,
+            "claude-3-7-sonnet-requests",
+            "claude_3_7_sonnet/requests",
+        ),
+        anthropic(
+            "claude-3-5-sonnet",
+            CompletionMode::Normal,
+            "claude-3-5-sonnet-requests",
+            "claude_3_5_sonnet/requests",
+        ),
+    ]
+}
+
+pub fn sync_llm_request_usage_with_stripe_periodically(
+    app: Arc<AppState>,
+    billing_tasks: &BillingTasks,
+) {
+    let Some(stripe_billing) = app.stripe_billing.clone() else {
+        log::warn!("failed to retrieve Stripe billing object");
         return;
     };
-    let Some(stripe_client) = app.stripe_client.clone() else {
-        log::warn!("failed to retrieve Stripe client");
+    let Some(llm_db) = app.llm_db.clone() else {
+        log::warn!("failed to retrieve LLM database");
         return;
     };
 
-    let executor = app.executor.clone();
-    executor.spawn_detached({
-        let executor = executor.clone();
-        async move {
-            loop {
-                poll_stripe_events(&app, &rpc_server, &stripe_client, &real_stripe_client)
-                    .await
-                    .log_err();
+    let circuit_breaker = Arc::new(parking_lot::Mutex::new(UsageSyncCircuitBreaker::default()));
+
+    spawn_periodic_billing_task(
+        app.executor.clone(),
+        billing_tasks,
+        "Stripe usage sync",
+        SYNC_LLM_REQUEST_USAGE_WITH_STRIPE_INTERVAL,
+        move |shutdown| {
+            let app = app.clone();
+            let llm_db = llm_db.clone();
+            let stripe_billing = stripe_billing.clone();
+            let circuit_breaker = circuit_breaker.clone();
+            async move {
+                if app.config.billing_read_only() {
+                    log::info!("Stripe usage sync: paused while billing is read-only");
+                    return;
+                }
 
-                executor.sleep(POLL_EVENTS_INTERVAL).await;
+                let dry_run = app.config.stripe_usage_sync_dry_run();
+                sync_model_request_usage_with_stripe(
+                    &app,
+                    &llm_db,
+                    &stripe_billing,
+                    &circuit_breaker,
+                    dry_run,
+                    &shutdown,
+                )
+                .await
+                .context("failed to sync LLM request usage to Stripe")
+                .trace_err();
             }
-        }
-    });
+        },
+    );
 }
 
-async fn poll_stripe_events(
+/// Syncs the model request usage of Zed Pro subscribers to Stripe.
+///
+/// Each call to Stripe is given at most [`USAGE_SYNC_STRIPE_TIMEOUT`], and users whose sync keeps failing are skipped
+/// for a while by the circuit breaker, so that a single bad account can't hold up everyone else's billing.
+///
+/// In a dry run, the meter events and price subscriptions that would be sent to Stripe are only logged, and nothing
+/// is recorded.
+async fn sync_model_request_usage_with_stripe(
     app: &Arc<AppState>,
-    rpc_server: &Arc<Server>,
-    stripe_client: &Arc<dyn StripeClient>,
-    real_stripe_client: &stripe::Client,
+    llm_db: &Arc<LlmDatabase>,
+    stripe_billing: &Arc<StripeBilling>,
+    circuit_breaker: &parking_lot::Mutex<UsageSyncCircuitBreaker>,
+    dry_run: bool,
+    shutdown: &ShutdownSignal,
 ) -> anyhow::Result<()> {
-    fn event_type_to_string(event_type: EventType) -> String {
-        // Calling `to_string` on `stripe::EventType` members gives us a quoted string,
-        // so we need to unquote it.
-        event_type.to_string().trim_matches('"').to_string()
-    }
-
-    let event_types = [
-        EventType::CustomerCreated,
-        EventType::CustomerUpdated,
-        EventType::CustomerSubscriptionCreated,
-        EventType::CustomerSubscriptionUpdated,
-        EventType::CustomerSubscriptionPaused,
-        EventType::CustomerSubscriptionResumed,
-        EventType::CustomerSubscriptionDeleted,
-    ]
-    .into_iter()
-    .map(event_type_to_string)
-    .collect::<Vec<_>>();
+    if dry_run {
+        log::info!("Stripe usage sync: Starting dry run");
+    } else {
+        log::info!("Stripe usage sync: Starting");
+    }
+    let started_at = Utc::now();
 
-    let mut pages_of_already_processed_events = 0;
-    let mut unprocessed_events = Vec::new();
+    let staff_users = app.db.get_staff_users().await?;
+    let staff_user_ids = staff_users
+        .iter()
+        .map(|user| user.id)
+        .collect::<HashSet<UserId>>();
+
+    let usage_meters = llm_db
+        .get_current_subscription_usage_meters(Utc::now())
+        .await?;
+    let mut usage_meters_by_user_id =
+        HashMap::<UserId, Vec<subscription_usage_meter::Model>>::default();
+    for (usage_meter, usage) in usage_meters {
+        let meters = usage_meters_by_user_id.entry(usage.user_id).or_default();
+        meters.push(usage_meter);
+    }
+    let requests_by_user_id = usage_meters_by_user_id
+        .into_iter()
+        .map(|(user_id, usage_meters)| (user_id, requests_by_usage_meter_key(&usage_meters)))
+        .collect::<HashMap<_, _>>();
 
+    log::info!("Stripe usage sync: Retrieving Zed Pro subscriptions");
+    let get_zed_pro_subscriptions_started_at = Utc::now();
+    let billing_subscriptions = app.db.get_active_zed_pro_billing_subscriptions().await?;
     log::info!(
-        "Stripe events: starting retrieval for {}",
-        event_types.join(", ")
+        "Stripe usage sync: Retrieved {} Zed Pro subscriptions in {}",
+        billing_subscriptions.len(),
+        Utc::now() - get_zed_pro_subscriptions_started_at
     );
-    let mut params = ListEvents::new();
-    params.types = Some(event_types.clone());
-    params.limit = Some(EVENTS_LIMIT_PER_PAGE);
-
-    let mut event_pages = stripe::Event::list(&real_stripe_client, &params)
-        .await?
-        .paginate(params);
 
-    loop {
-        let processed_event_ids = {
-            let event_ids = event_pages
-                .page
-                .data
-                .iter()
-                .map(|event| event.id.as_str())
-                .collect::<Vec<_>>();
-            app.db
-                .get_processed_stripe_events_by_event_ids(&event_ids)
-                .await?
-                .into_iter()
-                .map(|event| event.stripe_event_id)
-                .collect::<Vec<_>>()
+    // We resolve the billed models once per sync, so that a model we don't know about is only skipped once, rather
+    // than for every subscription.
+    let billing_table = model_request_billing_table(&app.config);
+    let mut model_request_prices = Vec::with_capacity(billing_table.len());
+    for billing in &billing_table {
+        let Ok(model) = llm_db.model(billing.provider, &billing.model) else {
+            log::warn!(
+                "Stripe usage sync: unknown model {}:{}, skipping",
+                billing.provider,
+                billing.model
+            );
+            continue;
         };
+        let price = stripe_billing
+            .find_price_by_lookup_key(&billing.price_lookup_key)
+            .await?;
+        model_request_prices.push((billing, model, price));
+    }
+
+    let edit_prediction_overage_price = if app.config.edit_prediction_overages_enabled() {
+        Some(
+            stripe_billing
+                .find_price_by_lookup_key(EDIT_PREDICTION_OVERAGE_PRICE_LOOKUP_KEY)
+                .await?,
+        )
+    } else {
+        None
+    };
+
+    let custom_price_overrides = app
+        .db
+        .get_custom_price_overrides_by_billing_customer()
+        .await?;
+
+    // We only sync Zed Pro subscriptions, and their pooled limit is already applied by the usage meters.
+    let model_request_allotments =
+        model_request_allotments(&app.config, zed_llm_client::Plan::ZedPro, None);
+
+    let billing_subscription_count = billing_subscriptions.len();
+
+    log::info!("Stripe usage sync: Syncing {billing_subscription_count} Zed Pro subscriptions");
 
-        let mut processed_events_in_page = 0;
-        let events_in_page = event_pages.page.data.len();
-        for event in &event_pages.page.data {
-            if processed_event_ids.contains(&event.id.to_string()) {
-                processed_events_in_page += 1;
-                log::debug!("Stripe events: already processed '{}', skipping", event.id);
-            } else {
-                unprocessed_events.push(event.clone());
-            }
+    for (user_id, (billing_customer, billing_subscription)) in billing_subscriptions {
+        if shutdown.is_requested() {
+            log::info!("Stripe usage sync: shutting down, remaining usage will be synced later");
+            break;
         }
 
-        if processed_events_in_page == events_in_page {
-            pages_of_already_processed_events += 1;
+        if staff_user_ids.contains(&user_id) {
+            continue;
         }
 
-        if event_pages.page.has_more {
-            if pages_of_already_processed_events >= NUMBER_OF_ALREADY_PROCESSED_PAGES_BEFORE_WE_STOP
-            {
-                log::info!(
-                    "Stripe events: stopping, saw {pages_of_already_processed_events} pages of already-processed events"
-                );
-                break;
-            } else {
-                log::info!("Stripe events: retrieving next page");
-                event_pages = event_pages.next(&real_stripe_client).await?;
-            }
-        } else {
-            break;
+        if circuit_breaker.lock().is_open(user_id, Utc::now()) {
+            log::info!(
+                "Stripe usage sync: skipping user {user_id} after repeated failures to sync their usage"
+            );
+            billing_metrics().usage_sync_users_skipped.inc();
+            continue;
         }
-    }
 
-    log::info!("Stripe events: unprocessed {}", unprocessed_events.len());
+        let user_started_at = Utc::now();
+        let sync_user = maybe!(async {
+            let stripe_customer_id =
+                StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+            let stripe_subscription_id =
+                StripeSubscriptionId(billing_subscription.stripe_subscription_id.clone().into());
 
-    // Sort all of the unprocessed events in ascending order, so we can handle them in the order they occurred.
-    unprocessed_events.sort_by(|a, b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+            let custom_prices = custom_prices(
+                stripe_billing,
+                custom_price_overrides
+                    .get(&billing_customer.id)
+                    .map_or(&[], Vec::as_slice),
+            )
+            .await?;
 
-    for event in unprocessed_events {
-        let event_id = event.id.clone();
-        let processed_event_params = CreateProcessedStripeEventParams {
-            stripe_event_id: event.id.to_string(),
-            stripe_event_type: event_type_to_string(event.type_),
-            stripe_event_created_timestamp: event.created,
-        };
+            // Users who have set up their billing preferences have chosen how much they're willing to spend on
+            // overages in a period, so we don't bill them for more than that.
+            let spend_limit_in_cents =
+                app.db
+                    .get_billing_preferences(user_id)
+                    .await?
+                    .map(|preferences| {
+                        i64::from(preferences.model_request_overages_spend_limit_in_cents)
+                    });
+
+            let requests_by_key = requests_by_user_id.get(&user_id);
+            let mut model_usage = Vec::new();
+            let mut synced_model_usage = Vec::new();
+            let mut negative_model_usage = Vec::new();
+
+            for (billing, model, price) in &model_request_prices {
+                let mode = &billing.mode;
+                let meter_event_name = &billing.meter_event_name;
+                let price = custom_prices.get(meter_event_name).unwrap_or(price);
+
+                let dimensions = billing.dimensions();
+                let model_requests = requests_by_key
+                    .and_then(|requests_by_key| {
+                        requests_by_key.get(&UsageMeterKey {
+                            model_id: model.id,
+                            mode: *mode,
+                            dimensions: dimensions.clone(),
+                        })
+                    })
+                    .copied()
+                    .unwrap_or(0);
 
-        // If the event has happened too far in the past, we don't want to
-        // process it and risk overwriting other more-recent updates.
-        //
-        // 1 day was chosen arbitrarily. This could be made longer or shorter.
-        let one_day = Duration::from_secs(24 * 60 * 60);
-        let a_day_ago = Utc::now() - one_day;
-        if a_day_ago.timestamp() > event.created {
-            log::info!(
-                "Stripe events: event '{}' is more than {one_day:?} old, marking as processed",
-                event_id
-            );
-            app.db
-                .create_processed_stripe_event(&processed_event_params)
-                .await?;
+                // A negative count means something went wrong when recording the usage, so we skip the meter
+                // rather than reporting a nonsensical quantity to Stripe.
+                if model_requests < 0 {
+                    log::error!(
+                        "Stripe usage sync: usage meter for user {user_id} has a negative request count of {model_requests} for {meter_event_name}, skipping"
+                    );
+                    negative_model_usage.push(SyncedModelUsage {
+                        model: model.name.clone(),
+                        mode: *mode,
+                        dimensions,
+                        requests: model_requests,
+                    });
+                    continue;
+                }
 
-            continue;
-        }
+                model_usage.push((
+                    *billing,
+                    price,
+                    SyncedModelUsage {
+                        model: model.name.clone(),
+                        mode: *mode,
+                        dimensions,
+                        requests: model_requests,
+                    },
+                ));
+            }
+
+            let billed_model_usage = bill_model_request_usage(
+                app,
+                stripe_billing,
+                &billing_customer,
+                &stripe_subscription_id,
+                billing_subscription.stripe_current_period_start,
+                &billing_table,
+                model_usage,
+                &model_request_allotments,
+                app.config.overage_grace(),
+                spend_limit_in_cents,
+                dry_run,
+            )
+            .await?;
+            // Model requests and edit predictions share the user's overage spend limit, and model requests use it
+            // up first.
+            let edit_prediction_spend_limit_in_cents =
+                spend_limit_in_cents.map(|spend_limit_in_cents| {
+                    spend_limit_in_cents - billed_model_usage.billed_in_cents
+                });
+            synced_model_usage.extend(billed_model_usage.synced);
+            let suspended_model_usage = billed_model_usage.suspended;
+            let over_spend_limit_model_usage = billed_model_usage.over_spend_limit;
+
+            let mut suspended_edit_predictions = 0;
+            if billing_customer.billing_suspended {
+                log::info!(
+                    "Stripe usage sync: billing is suspended for user {user_id}, skipping billing"
+                );
+                if edit_prediction_overage_price.is_some() {
+                    suspended_edit_predictions = edit_prediction_overage_for_period(
+                        llm_db,
+                        user_id,
+                        &billing_subscription,
+                        app.config.overage_grace(),
+                    )
+                    .await?;
+                    if suspended_edit_predictions > 0 {
+                        log::info!(
+                            "Stripe usage sync: not billing user {user_id} for {suspended_edit_predictions} edit predictions while billing is suspended"
+                        );
+                    }
+                }
+            } else if let Some(price) = &edit_prediction_overage_price {
+                let price = custom_prices
+                    .get(EDIT_PREDICTION_OVERAGE_METER_EVENT_NAME)
+                    .unwrap_or(price);
+                let over_spend_limit_edit_predictions = sync_edit_prediction_overage(
+                    &app.executor,
+                    llm_db,
+                    stripe_billing,
+                    user_id,
+                    &billing_subscription,
+                    &stripe_customer_id,
+                    price,
+                    app.config.overage_grace(),
+                    edit_prediction_spend_limit_in_cents,
+                    dry_run,
+                )
+                .await?;
+                if over_spend_limit_edit_predictions > 0 {
+                    log::info!(
+                        "Stripe usage sync: not billing user {user_id} for {over_spend_limit_edit_predictions} edit predictions past their spend limit"
+                    );
+                }
+            }
 
-        let process_result = match event.type_ {
-            EventType::CustomerCreated | EventType::CustomerUpdated => {
-                handle_customer_event(app, real_stripe_client, event).await
+            if dry_run {
+                return Ok(());
             }
-            EventType::CustomerSubscriptionCreated
-            | EventType::CustomerSubscriptionUpdated
-            | EventType::CustomerSubscriptionPaused
-            | EventType::CustomerSubscriptionResumed
-            | EventType::CustomerSubscriptionDeleted => {
-                handle_customer_subscription_event(app, rpc_server, stripe_client, event).await
+
+            if synced_model_usage.iter().any(|usage| usage.requests > 0)
+                || !negative_model_usage.is_empty()
+                || !suspended_model_usage.is_empty()
+                || suspended_edit_predictions > 0
+                || !over_spend_limit_model_usage.is_empty()
+            {
+                if let Some(user) = app.db.get_user_by_id(user_id).await.log_err().flatten() {
+                    let rows = [
+                        model_usage_synced_row(&user, &billing_subscription, &synced_model_usage),
+                        negative_usage_meter_row(
+                            &user,
+                            &billing_subscription,
+                            &negative_model_usage,
+                        ),
+                        billing_suspended_usage_row(
+                            &user,
+                            &billing_subscription,
+                            &suspended_model_usage,
+                            suspended_edit_predictions,
+                        ),
+                        spend_limit_reached_row(
+                            &user,
+                            &billing_subscription,
+                            spend_limit_in_cents,
+                            &over_spend_limit_model_usage,
+                        ),
+                    ];
+                    for row in rows.into_iter().flatten() {
+                        row.with_billing_request_id()
+                            .write(&app.kinesis_client, &app.config.kinesis_stream)
+                            .await
+                            .log_err();
+                    }
+                }
             }
-            _ => Ok(()),
-        };
 
-        if let Some(()) = process_result
-            .with_context(|| format!("failed to process event {event_id} successfully"))
-            .log_err()
-        {
-            app.db
-                .create_processed_stripe_event(&processed_event_params)
-                .await?;
+            anyhow::Ok(())
+        });
+        let result = sync_user
+            .await
+            .with_context(|| format!("failed to sync usage for user {user_id}"));
+
+        let user_duration = Utc::now() - user_started_at;
+        billing_metrics()
+            .usage_sync_user_duration
+            .observe(user_duration.num_milliseconds() as f64 / 1000.0);
+        if user_duration > SLOW_USAGE_SYNC_USER_DURATION {
+            log::warn!("Stripe usage sync: syncing user {user_id} took {user_duration}");
+        }
+
+        let tripped = circuit_breaker
+            .lock()
+            .record(user_id, result.is_ok(), Utc::now());
+        if tripped {
+            log::error!(
+                "Stripe usage sync: usage for user {user_id} failed to sync {USAGE_SYNC_MAX_CONSECUTIVE_FAILURES} times in a row, skipping them for {} minutes",
+                USAGE_SYNC_FAILURE_COOLDOWN.num_minutes()
+            );
         }
+        result.log_err();
     }
 
+    let duration = Utc::now() - started_at;
+    log::info!(
+        "Stripe usage sync: Synced {billing_subscription_count} Zed Pro subscriptions in {duration}"
+    );
+    billing_metrics()
+        .usage_sync_duration
+        .observe(duration.num_milliseconds() as f64 / 1000.0);
+    billing_metrics()
+        .usage_sync_users_failing
+        .set(circuit_breaker.lock().open_count(Utc::now()) as i64);
+
     Ok(())
 }
 
-async fn handle_customer_event(
-    app: &Arc<AppState>,
-    _stripe_client: &stripe::Client,
-    event: stripe::Event,
-) -> anyhow::Result<()> {
-    let EventObject::Customer(customer) = event.data.object else {
-        bail!("unexpected event payload for {}", event.id);
-    };
+/// How long the usage sync waits for a single call to Stripe before failing the user's sync.
+pub(crate) const USAGE_SYNC_STRIPE_TIMEOUT: Duration = Duration::from_secs(60);
 
-    log::info!("handling Stripe {} event: {}", event.type_, event.id);
+/// Waits at most [`USAGE_SYNC_STRIPE_TIMEOUT`] for a call to Stripe made by the usage sync.
+///
+/// Only the Stripe call itself is timed out. Timing out the whole sync would drop it part-way through, which could
+/// leave a meter event reported to Stripe without a record of it in `billing_meter_reports`.
+pub(crate) async fn with_usage_sync_timeout<T, E: Into<anyhow::Error>>(
+    executor: &Executor,
+    call: impl Future<Output = Result<T, E>>,
+) -> anyhow::Result<T> {
+    futures::select_biased! {
+        result = call.fuse() => result.map_err(Into::into),
+        _ = executor.sleep(USAGE_SYNC_STRIPE_TIMEOUT).fuse() => {
+            Err(anyhow!("Stripe didn't respond within {USAGE_SYNC_STRIPE_TIMEOUT:?}"))
+        }
+    }
+}
 
-    let Some(email) = customer.email else {
-        log::info!("Stripe customer has no email: skipping");
-        return Ok(());
-    };
+/// How long a single user's usage can take to sync before we log it as an outlier.
+const SLOW_USAGE_SYNC_USER_DURATION: chrono::Duration = chrono::Duration::seconds(10);
 
-    let Some(user) = app.db.get_user_by_email(&email).await? else {
-        log::info!("no user found for email: skipping");
-        return Ok(());
-    };
+/// The number of consecutive syncs that a user's usage can fail in before the usage sync skips them.
+pub(crate) const USAGE_SYNC_MAX_CONSECUTIVE_FAILURES: u32 = 3;
 
-    if let Some(existing_customer) = app
-        .db
-        .get_billing_customer_by_stripe_customer_id(&customer.id)
-        .await?
-    {
-        app.db
-            .update_billing_customer(
-                existing_customer.id,
-                &UpdateBillingCustomerParams {
-                    // For now we just leave the information as-is, as it is not
-                    // likely to change.
-                    ..Default::default()
-                },
-            )
-            .await?;
-    } else {
-        app.db
-            .create_billing_customer(&CreateBillingCustomerParams {
-                user_id: user.id,
-                stripe_customer_id: customer.id.to_string(),
-            })
-            .await?;
-    }
+/// How long the usage sync skips a user for once their usage has failed to sync too many times in a row.
+pub(crate) const USAGE_SYNC_FAILURE_COOLDOWN: chrono::Duration = chrono::Duration::minutes(30);
 
-    Ok(())
+/// Tracks the users whose usage keeps failing to sync to Stripe, so that the usage sync can skip them for a while.
+///
+/// Once the cooldown is over, the user's usage is synced again. If that fails too, they're skipped for another
+/// cooldown, and only a successful sync resets their failures. Usage is reported as running totals for the period, so
+/// nothing is lost while a user is skipped.
+#[derive(Debug, Default)]
+pub(crate) struct UsageSyncCircuitBreaker {
+    pub(crate) failures: HashMap<UserId, UsageSyncFailures>,
 }
 
-async fn sync_subscription(
-    app: &Arc<AppState>,
-    stripe_client: &Arc<dyn StripeClient>,
-    subscription: StripeSubscription,
-) -> anyhow::Result<billing_customer::Model> {
-    let subscription_kind = if let Some(stripe_billing) = &app.stripe_billing {
-        stripe_billing
-            .determine_subscription_kind(&subscription)
-            .await
-    } else {
-        None
-    };
+#[derive(Debug, Default)]
+pub(crate) struct UsageSyncFailures {
+    pub(crate) consecutive_failures: u32,
+    pub(crate) skipped_until: Option<DateTime<Utc>>,
+}
 
-    let billing_customer =
-        find_or_create_billing_customer(app, stripe_client.as_ref(), &subscription.customer)
-            .await?
-            .context("billing customer not found")?;
+impl UsageSyncCircuitBreaker {
+    /// Returns whether the usage sync should skip the user at the given time.
+    pub(crate) fn is_open(&self, user_id: UserId, now: DateTime<Utc>) -> bool {
+        self.failures
+            .get(&user_id)
+            .and_then(|failures| failures.skipped_until)
+            .is_some_and(|skipped_until| now < skipped_until)
+    }
 
-    if let Some(SubscriptionKind::ZedProTrial) = subscription_kind {
-        if subscription.status == SubscriptionStatus::Trialing {
-            let current_period_start =
-                DateTime::from_timestamp(subscription.current_period_start, 0)
-                    .context("No trial subscription period start")?;
+    /// Records whether the user's usage synced, and returns whether the user is now being skipped because of it.
+    pub(crate) fn record(&mut self, user_id: UserId, succeeded: bool, now: DateTime<Utc>) -> bool {
+        if succeeded {
+            self.failures.remove(&user_id);
+            return false;
+        }
 
-            app.db
-                .update_billing_customer(
-                    billing_customer.id,
-                    &UpdateBillingCustomerParams {
-                        trial_started_at: ActiveValue::set(Some(current_period_start.naive_utc())),
-                        ..Default::default()
-                    },
-                )
-                .await?;
+        let failures = self.failures.entry(user_id).or_default();
+        failures.consecutive_failures = failures.consecutive_failures.saturating_add(1);
+        if failures.consecutive_failures < USAGE_SYNC_MAX_CONSECUTIVE_FAILURES {
+            return false;
         }
+
+        failures.skipped_until = Some(now + USAGE_SYNC_FAILURE_COOLDOWN);
+        true
     }
 
-    let was_canceled_due_to_payment_failure = subscription.status == SubscriptionStatus::Canceled
-        && subscription
-            .cancellation_details
-            .as_ref()
-            .and_then(|details| details.reason)
-            .map_or(false, |reason| {
-                reason == StripeCancellationDetailsReason::PaymentFailed
-            });
+    /// Returns the number of users being skipped at the given time.
+    pub(crate) fn open_count(&self, now: DateTime<Utc>) -> usize {
+        self.failures
+            .keys()
+            .filter(|user_id| self.is_open(**user_id, now))
+            .count()
+    }
+}
 
-    if was_canceled_due_to_payment_failure {
-        app.db
-            .update_billing_customer(
-                billing_customer.id,
-                &UpdateBillingCustomerParams {
-                    has_overdue_invoices: ActiveValue::set(true),
-                    ..Default::default()
-                },
-            )
-            .await?;
+/// Returns the custom prices from the given price overrides, keyed by the meter event that they're billed for.
+pub(crate) async fn custom_prices(
+    stripe_billing: &StripeBilling,
+    price_overrides: &[custom_price_override::Model],
+) -> anyhow::Result<HashMap<String, StripePrice>> {
+    let mut custom_prices = HashMap::default();
+    for price_override in price_overrides {
+        let price_id = StripePriceId(price_override.stripe_price_id.clone().into());
+        let price = stripe_billing
+            .find_price_by_id(&price_id)
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to find custom price for billing customer {}: {price_id}",
+                    price_override.billing_customer_id
+                )
+            })?;
+        custom_prices.insert(price_override.meter_event_name.clone(), price);
     }
 
-    if let Some(existing_subscription) = app
-        .db
-        .get_billing_subscription_by_stripe_subscription_id(subscription.id.0.as_ref())
-        .await?
-    {
+    Ok(custom_prices)
+}
+
+/// Returns the price that the billing customer is billed at for the given meter event.
+///
+/// This is the customer's custom price, if they have one, and the catalog price with the given lookup key otherwise.
+async fn price_for_customer(
+    app: &AppState,
+    stripe_billing: &StripeBilling,
+    billing_customer: Option<&billing_customer::Model>,
+    meter_event_name: &str,
+    price_lookup_key: &str,
+) -> Result<StripePrice> {
+    let price_override = if let Some(billing_customer) = billing_customer {
         app.db
-            .update_billing_subscription(
-                existing_subscription.id,
-                &UpdateBillingSubscriptionParams {
-                    billing_customer_id: ActiveValue::set(billing_customer.id),
-                    kind: ActiveValue::set(subscription_kind),
-                    stripe_subscription_id: ActiveValue::set(subscription.id.to_string()),
-                    stripe_subscription_status: ActiveValue::set(subscription.status.into()),
-                    stripe_cancel_at: ActiveValue::set(
-                        subscription
-                            .cancel_at
-                            .and_then(|cancel_at| DateTime::from_timestamp(cancel_at, 0))
-                            .map(|time| time.naive_utc()),
-                    ),
-                    stripe_cancellation_reason: ActiveValue::set(
-                        subscription
-                            .cancellation_details
-                            .and_then(|details| details.reason)
-                            .map(|reason| reason.into()),
-                    ),
-                    stripe_current_period_start: ActiveValue::set(Some(
-                        subscription.current_period_start,
-                    )),
-                    stripe_current_period_end: ActiveValue::set(Some(
-                        subscription.current_period_end,
-                    )),
-                },
-            )
-            .await?;
-    } else {
-        if let Some(existing_subscription) = app
-            .db
-            .get_active_billing_subscription(billing_customer.user_id)
+            .get_custom_price_override(billing_customer.id, meter_event_name)
             .await?
-        {
-            if existing_subscription.kind == Some(SubscriptionKind::ZedFree)
-                && subscription_kind == Some(SubscriptionKind::ZedProTrial)
-            {
-                let stripe_subscription_id = StripeSubscriptionId(
-                    existing_subscription.stripe_subscription_id.clone().into(),
-                );
+    } else {
+        None
+    };
 
-                stripe_client
-                    .cancel_subscription(&stripe_subscription_id)
-                    .await?;
-            } else {
-                // If the user already has an active billing subscription, ignore the
-                // event and return an `Ok` to signal that it was processed
-                // successfully.
-                //
-                // There is the possibility that this could cause us to not create a
-                // subscription in the following scenario:
-                //
-                //   1. User has an active subscription A
-                //   2. User cancels subscription A
-                //   3. User creates a new subscription B
-                //   4. We process the new subscription B before the cancellation of subscription A
-                //   5. User ends up with no subscriptions
-                //
-                // In theory this situation shouldn't arise as we try to process the events in the order they occur.
+    match price_override {
+        Some(price_override) => {
+            stripe_billing
+                .find_price_by_id(&StripePriceId(price_override.stripe_price_id.into()))
+                .await
+        }
+        None => {
+            stripe_billing
+                .find_price_by_lookup_key(price_lookup_key)
+                .await
+        }
+    }
+}
+
+/// The model request usage that we billed a user for.
+#[derive(Debug, Default)]
+pub(crate) struct BilledModelUsage {
+    /// The usage that was synced to Stripe.
+    pub(crate) synced: Vec<SyncedModelUsage>,
+    /// The usage that we would have billed for, had billing not been suspended for the user.
+    pub(crate) suspended: Vec<SyncedModelUsage>,
+    /// The usage that we didn't bill for, as it would have taken the user past their overage spend limit.
+    pub(crate) over_spend_limit: Vec<SyncedModelUsage>,
+    /// What the billed usage costs, in cents, which counts towards the user's overage spend limit.
+    pub(crate) billed_in_cents: i64,
+}
+
+/// Reports the user's model request usage to Stripe, after the plan's per-model allotments and the free overage
+/// grace.
+///
+/// When billing is suspended for the user, nothing is reported to Stripe. Instead, we return the weighted requests
+/// that would have been billed, so that we keep a record of them.
+///
+/// Each meter event reported to Stripe is recorded in `billing_meter_reports`, so that we can reconcile the sync
+/// against Stripe.
+///
+/// When we know the start of the billing period, each meter event is reported with an idempotency key derived from
+/// it, so that a sync that overlaps a slow one doesn't report the same usage twice.
+///
+/// Calls to Stripe that time out fail the sync, and the next sync reports the same total with the same idempotency
+/// key, so a meter event that Stripe received after all isn't reported twice. Without a period start there's no key
+/// to report it again with, so we wait for Stripe to respond instead.
+///
+/// When the user has an overage spend limit, we only bill for as many requests as fit within it. The requests past the
+/// limit are returned instead, so that we can let the user know. Since we report the running total for the period,
+/// the limit applies to each period on its own. What's left of the limit goes to the edit prediction overage.
+///
+/// In a dry run, the calls that would be made to Stripe are logged instead, and nothing is recorded.
+pub(crate) async fn bill_model_request_usage(
+    app: &AppState,
+    stripe_billing: &StripeBilling,
+    billing_customer: &billing_customer::Model,
+    stripe_subscription_id: &StripeSubscriptionId,
+    period_start: Option<i64>,
+    billing_table: &[ModelRequestBilling],
+    model_usage: Vec<(&ModelRequestBilling, &StripePrice, SyncedModelUsage)>,
+    allotments: &[ModelRequestAllotment],
+    overage_grace: i32,
+    spend_limit_in_cents: Option<i64>,
+    dry_run: bool,
+) -> anyhow::Result<BilledModelUsage> {
+    let stripe_customer_id = StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+    let billed_requests = billed_model_requests(
+        billing_table,
+        &model_usage
+            .iter()
+            .map(|(billing, _, usage)| {
+                (
+                    Some(*billing),
+                    weighted_requests(usage.requests, billing.request_weight),
+                )
+            })
+            .collect::<Vec<_>>(),
+        allotments,
+        overage_grace,
+    );
+    let billed_requests = match spend_limit_in_cents {
+        Some(spend_limit_in_cents) => cap_billed_usage(
+            &model_usage
+                .iter()
+                .zip(&billed_requests)
+                .map(|((_, price, _), billed_requests)| {
+                    (*billed_requests, price.unit_amount.unwrap_or(0))
+                })
+                .collect::<Vec<_>>(),
+            spend_limit_in_cents,
+        ),
+        None => billed_requests
+            .into_iter()
+            .map(|billed_requests| (billed_requests, 0))
+            .collect(),
+    };
+
+    let mut billed_model_usage = BilledModelUsage::default();
+    billed_model_usage.billed_in_cents = model_usage
+        .iter()
+        .zip(&billed_requests)
+        .map(|((_, price, _), (billed_requests, _))| {
+            i64::from(*billed_requests) * price.unit_amount.unwrap_or(0)
+        })
+        .sum();
+    for ((billing, price, usage), (billed_requests, over_spend_limit_requests)) in
+        model_usage.into_iter().zip(billed_requests)
+    {
+        if over_spend_limit_requests > 0 && !billing_customer.billing_suspended {
+            billed_model_usage.over_spend_limit.push(SyncedModelUsage {
+                requests: over_spend_limit_requests,
+                ..usage.clone()
+            });
+        }
+
+        if billing_customer.billing_suspended {
+            if billed_requests > 0 {
+                billed_model_usage.suspended.push(SyncedModelUsage {
+                    requests: billed_requests,
+                    ..usage
+                });
+            }
+            continue;
+        }
 
+        let meter_event_name = &billing.meter_event_name;
+        let idempotency_key = period_start.map(|period_start| {
+            usage_idempotency_key(
+                billing_customer.user_id,
+                meter_event_name,
+                period_start,
+                billed_requests,
+            )
+        });
+
+        if dry_run {
+            if billed_requests > 0 {
                 log::info!(
-                    "user {user_id} already has an active subscription, skipping creation of subscription {subscription_id}",
-                    user_id = billing_customer.user_id,
-                    subscription_id = subscription.id
+                    "Stripe usage sync (dry run): would subscribe {stripe_subscription_id} to price {} ({:?})",
+                    price.id,
+                    price.lookup_key
                 );
-                return Ok(billing_customer);
             }
+            log::info!(
+                "Stripe usage sync (dry run): would bill {stripe_customer_id} for {billed_requests} of {meter_event_name} (idempotency key: {idempotency_key:?})"
+            );
+            billed_model_usage.synced.push(usage);
+            continue;
         }
 
-        app.db
-            .create_billing_subscription(&CreateBillingSubscriptionParams {
-                billing_customer_id: billing_customer.id,
-                kind: subscription_kind,
-                stripe_subscription_id: subscription.id.to_string(),
-                stripe_subscription_status: subscription.status.into(),
-                stripe_cancellation_reason: subscription
-                    .cancellation_details
-                    .and_then(|details| details.reason)
-                    .map(|reason| reason.into()),
-                stripe_current_period_start: Some(subscription.current_period_start),
-                stripe_current_period_end: Some(subscription.current_period_end),
-            })
+        if billed_requests > 0 {
+            with_usage_sync_timeout(
+                &app.executor,
+                stripe_billing.subscribe_to_price(stripe_subscription_id, price),
+            )
             .await?;
+        }
+        let report = stripe_billing.bill_model_request_usage(
+            &stripe_customer_id,
+            meter_event_name,
+            billed_requests,
+            idempotency_key.as_deref(),
+        );
+        let report = if idempotency_key.is_some() {
+            with_usage_sync_timeout(&app.executor, report).await
+        } else {
+            report.await.map_err(anyhow::Error::from)
+        };
+        let report = report.with_context(|| {
+            format!(
+                "Failed to bill model request usage of {billed_requests} for {stripe_customer_id}: {meter_event_name}",
+            )
+        })?;
+
+        // The usage has already been reported to Stripe, so failing to record it shouldn't keep us from billing the
+        // rest of the user's usage.
+        let params = CreateBillingMeterReportParams {
+            billing_customer_id: billing_customer.id,
+            meter_event_name: meter_event_name.to_string(),
+            value: billed_requests,
+            identifier: report.identifier,
+            reported_at: DateTime::from_timestamp(report.timestamp, 0)
+                .unwrap_or_else(Utc::now)
+                .naive_utc(),
+        };
+        retry_billing_db_write(app, "record billing meter report", || {
+            app.db.create_billing_meter_report(&params)
+        })
+        .await
+        .log_err();
+
+        billed_model_usage.synced.push(usage);
     }
 
-    if let Some(stripe_billing) = app.stripe_billing.as_ref() {
-        if subscription.status == SubscriptionStatus::Canceled
-            || subscription.status == SubscriptionStatus::Paused
-        {
-            let already_has_active_billing_subscription = app
-                .db
-                .has_active_billing_subscription(billing_customer.user_id)
-                .await?;
-            if !already_has_active_billing_subscription {
-                let stripe_customer_id =
-                    StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+    Ok(billed_model_usage)
+}
 
-                stripe_billing
-                    .subscribe_to_zed_free(stripe_customer_id)
-                    .await?;
+/// Caps the billed usage at what the spend limit covers, given the unit amount in cents of each kind of usage.
+///
+/// The spend limit is used up in the order that the usage is given in, so model requests should be in the order of
+/// the billing table.
+///
+/// Returns, for each kind of usage, the number of units that can be billed and the number that are over the spend
+/// limit.
+pub(crate) fn cap_billed_usage(
+    billed_usage: &[(i32, i64)],
+    spend_limit_in_cents: i64,
+) -> Vec<(i32, i32)> {
+    let mut remaining_spend_in_cents = spend_limit_in_cents.max(0);
+    billed_usage
+        .iter()
+        .map(|&(requests, unit_amount)| {
+            let requests = requests.max(0);
+            if unit_amount <= 0 {
+                return (requests, 0);
             }
-        }
+
+            let affordable_requests =
+                (remaining_spend_in_cents / unit_amount).min(i64::from(requests)) as i32;
+            remaining_spend_in_cents -= i64::from(affordable_requests) * unit_amount;
+            (affordable_requests, requests - affordable_requests)
+        })
+        .collect()
+}
+
+/// Returns the idempotency key for reporting the usage of a meter event in a billing period.
+///
+/// Since the usage we report is the running total for the period, a repeated report of the same total is a duplicate.
+fn usage_idempotency_key(
+    user_id: UserId,
+    meter_event_name: &str,
+    period_start: i64,
+    value: i32,
+) -> String {
+    format!("usage-{user_id}-{meter_event_name}-{period_start}-{value}")
+}
+
+/// The lookup key of the Stripe price for edit predictions beyond a plan's limit.
+pub(crate) const EDIT_PREDICTION_OVERAGE_PRICE_LOOKUP_KEY: &str = "edit-predictions-overage";
+
+/// The name of the Stripe meter event for edit predictions beyond a plan's limit.
+pub(crate) const EDIT_PREDICTION_OVERAGE_METER_EVENT_NAME: &str = "edit_predictions/overage";
+
+/// Returns the number of edit predictions beyond the plan's limit.
+pub(crate) fn edit_prediction_overage(plan: zed_llm_client::Plan, edit_predictions: i32) -> i32 {
+    match plan.edit_predictions_limit() {
+        zed_llm_client::UsageLimit::Limited(limit) => (edit_predictions - limit).max(0),
+        zed_llm_client::UsageLimit::Unlimited => 0,
     }
+}
 
-    Ok(billing_customer)
+/// Returns the number of edit predictions beyond the user's plan's limit in the subscription's current period, less the
+/// free overage grace.
+async fn edit_prediction_overage_for_period(
+    llm_db: &Arc<LlmDatabase>,
+    user_id: UserId,
+    billing_subscription: &billing_subscription::Model,
+    overage_grace: i32,
+) -> anyhow::Result<i32> {
+    let Some((period_start_at, period_end_at)) = billing_subscription
+        .current_period_start_at()
+        .zip(billing_subscription.current_period_end_at())
+    else {
+        return Ok(0);
+    };
+
+    let edit_predictions = llm_db
+        .get_subscription_usage_for_period(user_id, period_start_at, period_end_at)
+        .await?
+        .map_or(0, |usage| usage.edit_predictions);
+
+    let plan = billing_subscription
+        .kind
+        .map(Into::into)
+        .unwrap_or(zed_llm_client::Plan::ZedFree);
+    Ok(billed_overage(
+        edit_prediction_overage(plan, edit_predictions),
+        overage_grace,
+    ))
 }
 
-async fn handle_customer_subscription_event(
-    app: &Arc<AppState>,
-    rpc_server: &Arc<Server>,
-    stripe_client: &Arc<dyn StripeClient>,
-    event: stripe::Event,
-) -> anyhow::Result<()> {
-    let EventObject::Subscription(subscription) = event.data.object else {
-        bail!("unexpected event payload for {}", event.id);
+/// Reports the user's edit predictions beyond their plan's limit to Stripe.
+///
+/// Returns the number of edit predictions that weren't billed, as they would have taken the user past what's left of
+/// their overage spend limit.
+async fn sync_edit_prediction_overage(
+    executor: &Executor,
+    llm_db: &Arc<LlmDatabase>,
+    stripe_billing: &Arc<StripeBilling>,
+    user_id: UserId,
+    billing_subscription: &billing_subscription::Model,
+    stripe_customer_id: &StripeCustomerId,
+    price: &StripePrice,
+    overage_grace: i32,
+    spend_limit_in_cents: Option<i64>,
+    dry_run: bool,
+) -> anyhow::Result<i32> {
+    let (Some(period_start_at), Some(_)) = (
+        billing_subscription.current_period_start_at(),
+        billing_subscription.current_period_end_at(),
+    ) else {
+        return Ok(0);
     };
+    let overage =
+        edit_prediction_overage_for_period(llm_db, user_id, billing_subscription, overage_grace)
+            .await?;
 
-    log::info!("handling Stripe {} event: {}", event.type_, event.id);
+    bill_edit_prediction_overage(
+        executor,
+        stripe_billing,
+        user_id,
+        billing_subscription,
+        stripe_customer_id,
+        price,
+        period_start_at.timestamp(),
+        overage,
+        spend_limit_in_cents,
+        dry_run,
+    )
+    .await
+}
 
-    let billing_customer = sync_subscription(app, stripe_client, subscription.into()).await?;
+/// Reports the given edit prediction overage for the period to Stripe, capped at what the spend limit covers.
+///
+/// Returns the number of edit predictions that are over the spend limit.
+pub(crate) async fn bill_edit_prediction_overage(
+    executor: &Executor,
+    stripe_billing: &StripeBilling,
+    user_id: UserId,
+    billing_subscription: &billing_subscription::Model,
+    stripe_customer_id: &StripeCustomerId,
+    price: &StripePrice,
+    period_start: i64,
+    overage: i32,
+    spend_limit_in_cents: Option<i64>,
+    dry_run: bool,
+) -> anyhow::Result<i32> {
+    let (overage, over_spend_limit) = match spend_limit_in_cents {
+        Some(spend_limit_in_cents) => cap_billed_usage(
+            &[(overage, price.unit_amount.unwrap_or(0))],
+            spend_limit_in_cents,
+        )[0],
+        None => (overage, 0),
+    };
 
-    // When the user's subscription changes, push down any changes to their plan.
-    rpc_server
-        .update_plan_for_user(billing_customer.user_id)
-        .await
-        .trace_err();
+    let stripe_subscription_id =
+        StripeSubscriptionId(billing_subscription.stripe_subscription_id.clone().into());
+    let idempotency_key = usage_idempotency_key(
+        user_id,
+        EDIT_PREDICTION_OVERAGE_METER_EVENT_NAME,
+        period_start,
+        overage,
+    );
 
-    // When the user's subscription changes, we want to refresh their LLM tokens
-    // to either grant/revoke access.
-    rpc_server
-        .refresh_llm_tokens_for_user(billing_customer.user_id)
-        .await;
+    if dry_run {
+        if overage > 0 {
+            log::info!(
+                "Stripe usage sync (dry run): would subscribe {stripe_subscription_id} to price {} ({:?})",
+                price.id,
+                price.lookup_key
+            );
+        }
+        log::info!(
+            "Stripe usage sync (dry run): would bill {stripe_customer_id} for {overage} of {EDIT_PREDICTION_OVERAGE_METER_EVENT_NAME} (idempotency key: {idempotency_key})"
+        );
+        return Ok(over_spend_limit);
+    }
 
-    Ok(())
+    if overage > 0 {
+        with_usage_sync_timeout(
+            executor,
+            stripe_billing.subscribe_to_price(&stripe_subscription_id, price),
+        )
+        .await?;
+    }
+
+    with_usage_sync_timeout(
+        executor,
+        stripe_billing.bill_edit_prediction_usage(
+            stripe_customer_id,
+            EDIT_PREDICTION_OVERAGE_METER_EVENT_NAME,
+            overage,
+            Some(&idempotency_key),
+        ),
+    )
+    .await
+    .with_context(|| {
+        format!("Failed to bill edit prediction overage of {overage} for {stripe_customer_id}")
+    })?;
+
+    Ok(over_spend_limit)
 }
 
-#[derive(Debug, Deserialize)]
-struct GetCurrentUsageParams {
-    github_user_id: i32,
+/// The number of requests to a model that were synced to Stripe for a user.
+#[derive(Debug, Clone)]
+pub(crate) struct SyncedModelUsage {
+    pub(crate) model: String,
+    pub(crate) mode: CompletionMode,
+    pub(crate) dimensions: UsageDimensions,
+    pub(crate) requests: i32,
 }
 
-#[derive(Debug, Serialize)]
-struct UsageCounts {
-    pub used: i32,
-    pub limit: Option<i32>,
-    pub remaining: Option<i32>,
+impl SyncedModelUsage {
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        let mut json = json!({
+            "model": self.model,
+            "mode": self.mode.as_str(),
+            "requests": self.requests,
+        });
+        if !self.dimensions.is_empty() {
+            json["dimensions"] = json!(self.dimensions.as_map());
+        }
+        json
+    }
 }
 
-#[derive(Debug, Serialize)]
-struct ModelRequestUsage {
-    pub model: String,
-    pub mode: CompletionMode,
-    pub requests: i32,
+/// Returns a "Model Usage Synced" row with the user's per-model usage for the
+/// current billing period, or `None` if the user has no usage to report.
+///
+/// We emit a single row per user, rather than one per model, to keep the
+/// number of records we write to Kinesis on each sync down.
+pub(crate) fn model_usage_synced_row(
+    user: &User,
+    billing_subscription: &billing_subscription::Model,
+    model_usage: &[SyncedModelUsage],
+) -> Option<SnowflakeRow> {
+    let model_usage = model_usage
+        .iter()
+        .filter(|usage| usage.requests > 0)
+        .collect::<Vec<_>>();
+    if model_usage.is_empty() {
+        return None;
+    }
+
+    let period_timestamp = |timestamp: Option<i64>| {
+        timestamp
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::Millis, true))
+    };
+
+    Some(SnowflakeRow::new(
+        "Model Usage Synced",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "billing_subscription_id": billing_subscription.id,
+            "period_start_at": period_timestamp(billing_subscription.stripe_current_period_start),
+            "period_end_at": period_timestamp(billing_subscription.stripe_current_period_end),
+            "total_requests": model_usage.iter().map(|usage| usage.requests as i64).sum::<i64>(),
+            "models": model_usage
+                .iter()
+                .map(|usage| usage.to_json())
+                .collect::<Vec<_>>(),
+        }),
+    ))
 }
 
-#[derive(Debug, Serialize)]
-struct CurrentUsage {
-    pub model_requests: UsageCounts,
-    pub model_request_usage: Vec<ModelRequestUsage>,
-    pub edit_predictions: UsageCounts,
+/// Returns a "Negative Usage Meter Detected" row that alerts us to the user's
+/// usage meters with negative request counts, or `None` if there are none.
+pub(crate) fn negative_usage_meter_row(
+    user: &User,
+    billing_subscription: &billing_subscription::Model,
+    model_usage: &[SyncedModelUsage],
+) -> Option<SnowflakeRow> {
+    let negative_usage = model_usage
+        .iter()
+        .filter(|usage| usage.requests < 0)
+        .collect::<Vec<_>>();
+    if negative_usage.is_empty() {
+        return None;
+    }
+
+    Some(SnowflakeRow::new(
+        "Negative Usage Meter Detected",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "billing_subscription_id": billing_subscription.id,
+            "models": negative_usage
+                .iter()
+                .map(|usage| usage.to_json())
+                .collect::<Vec<_>>(),
+        }),
+    ))
 }
 
-#[derive(Debug, Default, Serialize)]
-struct GetCurrentUsageResponse {
-    pub plan: String,
-    pub current_usage: Option<CurrentUsage>,
+/// Returns the IDs of the prices that the Stripe subscription is subscribed to.
+fn subscription_price_ids(subscription: &StripeSubscription) -> Vec<String> {
+    subscription
+        .items
+        .iter()
+        .filter_map(|item| Some(item.price.as_ref()?.id.0.to_string()))
+        .collect()
 }
 
-async fn get_current_usage(
-    Extension(app): Extension<Arc<AppState>>,
-    Query(params): Query<GetCurrentUsageParams>,
-) -> Result<Json<GetCurrentUsageResponse>> {
-    let user = app
-        .db
-        .get_user_by_github_user_id(params.github_user_id)
-        .await?
-        .context("user not found")?;
+/// Returns an "Unclassified Subscription Detected" row that alerts us to a Stripe subscription that we couldn't
+/// determine the kind of.
+pub(crate) fn unclassified_subscription_row(
+    user: &User,
+    subscription: &StripeSubscription,
+    plan: UnclassifiedSubscriptionPlan,
+) -> SnowflakeRow {
+    SnowflakeRow::new(
+        "Unclassified Subscription Detected",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "stripe_subscription_id": subscription.id.to_string(),
+            "stripe_price_ids": subscription_price_ids(subscription),
+            "plan": plan.subscription_kind(),
+        }),
+    )
+}
 
-    let feature_flags = app.db.get_user_flags(user.id).await?;
-    let has_extended_trial = feature_flags
-        .iter()
-        .any(|flag| flag == AGENT_EXTENDED_TRIAL_FEATURE_FLAG);
+/// Returns a "Subscription Canceled" row for a subscription that has just been canceled, or `None` if it was already
+/// canceled the last time we synced it.
+pub(crate) fn subscription_canceled_row(
+    user: &User,
+    previous_status: Option<StripeSubscriptionStatus>,
+    subscription: &StripeSubscription,
+    subscription_kind: Option<SubscriptionKind>,
+) -> Option<SnowflakeRow> {
+    if subscription.status != SubscriptionStatus::Canceled
+        || previous_status == Some(StripeSubscriptionStatus::Canceled)
+    {
+        return None;
+    }
 
-    let Some(llm_db) = app.llm_db.clone() else {
-        return Err(Error::http(
-            StatusCode::NOT_IMPLEMENTED,
-            "LLM database not available".into(),
-        ));
+    let cancellation_reason = subscription
+        .cancellation_details
+        .as_ref()
+        .and_then(|details| details.reason)
+        .map(StripeCancellationReason::from);
+
+    Some(SnowflakeRow::new(
+        "Subscription Canceled",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "stripe_subscription_id": subscription.id.to_string(),
+            "plan": subscription_kind,
+            "cancellation_reason": cancellation_reason,
+            "payment_failed": cancellation_reason == Some(StripeCancellationReason::PaymentFailed),
+        }),
+    ))
+}
+
+/// Returns a "Stale Stripe Event Dropped" row for a Stripe event that we didn't process because it was too old.
+pub(crate) fn stale_stripe_event_row(
+    event_id: &str,
+    event_type: &str,
+    created: i64,
+) -> SnowflakeRow {
+    SnowflakeRow::new(
+        "Stale Stripe Event Dropped",
+        None,
+        false,
+        None,
+        json!({
+            "stripe_event_id": event_id,
+            "stripe_event_type": event_type,
+            "stripe_event_created_at": DateTime::from_timestamp(created, 0)
+                .map(|time| time.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        }),
+    )
+}
+
+fn plan_downgraded_row(
+    user: &User,
+    subscription: &StripeSubscription,
+    previous_kind: SubscriptionKind,
+    cleared_overdue_invoices: bool,
+) -> SnowflakeRow {
+    SnowflakeRow::new(
+        "Plan Downgraded",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "stripe_subscription_id": subscription.id.to_string(),
+            "previous_plan": previous_kind,
+            "plan": SubscriptionKind::ZedFree,
+            "cleared_overdue_invoices": cleared_overdue_invoices,
+        }),
+    )
+}
+
+/// Returns a "Billing Suspended Usage Skipped" row with the usage that we
+/// would have billed the user for in the current period, had billing not been
+/// suspended for them, or `None` if there is no such usage.
+///
+/// Like the usage we report to Stripe, this is the running total for the
+/// period, so the last row for a period is what to reconcile once billing is
+/// resumed.
+pub(crate) fn billing_suspended_usage_row(
+    user: &User,
+    billing_subscription: &billing_subscription::Model,
+    model_usage: &[SyncedModelUsage],
+    edit_predictions: i32,
+) -> Option<SnowflakeRow> {
+    if model_usage.is_empty() && edit_predictions == 0 {
+        return None;
+    }
+
+    let period_timestamp = |timestamp: Option<DateTime<Utc>>| {
+        timestamp.map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::Millis, true))
     };
 
-    let Some(subscription) = app.db.get_active_billing_subscription(user.id).await? else {
-        return Ok(Json(GetCurrentUsageResponse::default()));
+    Some(SnowflakeRow::new(
+        "Billing Suspended Usage Skipped",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "billing_subscription_id": billing_subscription.id,
+            "period_start_at": period_timestamp(billing_subscription.current_period_start_at()),
+            "period_end_at": period_timestamp(billing_subscription.current_period_end_at()),
+            "total_requests": model_usage.iter().map(|usage| usage.requests as i64).sum::<i64>(),
+            "edit_predictions": edit_predictions,
+            "models": model_usage
+                .iter()
+                .map(|usage| usage.to_json())
+                .collect::<Vec<_>>(),
+        }),
+    ))
+}
+
+/// Returns a "Model Request Spend Limit Reached" row with the usage that we
+/// didn't bill the user for because it's past their overage spend limit, or
+/// `None` if there is no such usage.
+///
+/// This is written on every sync while the user is past their limit, so
+/// notifications should be deduplicated by `period_start_at`.
+pub(crate) fn spend_limit_reached_row(
+    user: &User,
+    billing_subscription: &billing_subscription::Model,
+    spend_limit_in_cents: Option<i64>,
+    model_usage: &[SyncedModelUsage],
+) -> Option<SnowflakeRow> {
+    if model_usage.is_empty() {
+        return None;
+    }
+
+    let period_timestamp = |timestamp: Option<i64>| {
+        timestamp
+            .and_then(|timestamp| DateTime::from_timestamp(timestamp, 0))
+            .map(|timestamp| timestamp.to_rfc3339_opts(SecondsFormat::Millis, true))
     };
 
-    let subscription_period = maybe!({
-        let period_start_at = subscription.current_period_start_at()?;
-        let period_end_at = subscription.current_period_end_at()?;
+    Some(SnowflakeRow::new(
+        "Model Request Spend Limit Reached",
+        Some(user.metrics_id),
+        user.admin,
+        None,
+        json!({
+            "user_id": user.id,
+            "billing_subscription_id": billing_subscription.id,
+            "period_start_at": period_timestamp(billing_subscription.stripe_current_period_start),
+            "period_end_at": period_timestamp(billing_subscription.stripe_current_period_end),
+            "spend_limit_in_cents": spend_limit_in_cents,
+            "unbilled_requests": model_usage.iter().map(|usage| usage.requests as i64).sum::<i64>(),
+            "models": model_usage
+                .iter()
+                .map(|usage| usage.to_json())
+                .collect::<Vec<_>>(),
+        }),
+    ))
+}
 
-        Some((period_start_at, period_end_at))
-    });
+#[derive(Debug, Serialize)]
+struct GetBillingConfigManifestResponse {
+    /// The hash of the manifest, which can be compared across environments to detect configuration drift.
+    hash: String,
+    manifest: BillingConfigManifest,
+}
 
-    let Some((period_start_at, period_end_at)) = subscription_period else {
-        return Ok(Json(GetCurrentUsageResponse::default()));
+/// Returns a snapshot of the effective billing configuration, along with its hash.
+async fn get_billing_config_manifest(
+    Extension(app): Extension<Arc<AppState>>,
+) -> Result<Json<GetBillingConfigManifestResponse>> {
+    let Some(stripe_billing) = app.stripe_billing.clone() else {
+        log::error!("failed to retrieve Stripe billing object");
+        Err(BillingError::NotSupported)?
     };
 
-    let usage = llm_db
-        .get_subscription_usage_for_period(user.id, period_start_at, period_end_at)
-        .await?;
+    let prices = stripe_billing.prices().await;
+    let manifest = BillingConfigManifest::new(&app.config, &prices);
 
-    let plan = subscription
-        .kind
-        .map(Into::into)
-        .unwrap_or(zed_llm_client::Plan::ZedFree);
+    Ok(Json(GetBillingConfigManifestResponse {
+        hash: manifest.hash()?,
+        manifest,
+    }))
+}
+
+/// Returns the relative tier of a subscription kind, where a higher tier is worth more to the user.
+fn subscription_tier(kind: Option<SubscriptionKind>) -> u8 {
+    match kind {
+        Some(SubscriptionKind::ZedPro) => 2,
+        Some(SubscriptionKind::ZedProTrial) => 1,
+        Some(SubscriptionKind::ZedFree) | None => 0,
+    }
+}
+
+/// Returns the subscription that should be kept out of a user's overlapping subscriptions.
+pub(crate) fn subscription_to_keep(
+    subscriptions: &[billing_subscription::Model],
+    resolution: OverlappingSubscriptionResolution,
+) -> Option<&billing_subscription::Model> {
+    subscriptions.iter().max_by_key(|subscription| {
+        let tier = match resolution {
+            OverlappingSubscriptionResolution::KeepMostRecent => 0,
+            OverlappingSubscriptionResolution::KeepHighestTier => {
+                subscription_tier(subscription.kind)
+            }
+        };
 
-    let model_requests_limit = match plan.model_requests_limit() {
-        zed_llm_client::UsageLimit::Limited(limit) => {
-            let limit = if plan == zed_llm_client::Plan::ZedProTrial && has_extended_trial {
-                1_000
-            } else {
-                limit
-            };
+        (tier, subscription.created_at, subscription.id)
+    })
+}
 
-            Some(limit)
-        }
-        zed_llm_client::UsageLimit::Unlimited => None,
-    };
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct OverlappingSubscriptionsJson {
+    pub(crate) user_id: UserId,
+    /// The Stripe subscription that is kept.
+    pub(crate) kept: String,
+    /// The Stripe subscriptions that are canceled.
+    pub(crate) canceled: Vec<String>,
+}
 
-    let edit_predictions_limit = match plan.edit_predictions_limit() {
-        zed_llm_client::UsageLimit::Limited(limit) => Some(limit),
-        zed_llm_client::UsageLimit::Unlimited => None,
-    };
+#[derive(Debug, Serialize)]
+struct ListOverlappingSubscriptionsResponse {
+    users: Vec<OverlappingSubscriptionsJson>,
+}
 
-    let Some(usage) = usage else {
-        return Ok(Json(GetCurrentUsageResponse {
-            plan: plan.as_str().to_string(),
-            current_usage: Some(CurrentUsage {
-                model_requests: UsageCounts {
-                    used: 0,
-                    limit: model_requests_limit,
-                    remaining: model_requests_limit,
-                },
-                model_request_usage: Vec::new(),
-                edit_predictions: UsageCounts {
-                    used: 0,
-                    limit: edit_predictions_limit,
-                    remaining: edit_predictions_limit,
-                },
-            }),
-        }));
-    };
+/// Returns the users with more than one active subscription, along with how they will be reconciled.
+async fn list_overlapping_subscriptions(
+    Extension(app): Extension<Arc<AppState>>,
+) -> Result<Json<ListOverlappingSubscriptionsResponse>> {
+    let users = find_overlapping_subscriptions(&app).await?;
 
-    let subscription_usage_meters = llm_db
-        .get_current_subscription_usage_meters_for_user(user.id, Utc::now())
-        .await?;
+    Ok(Json(ListOverlappingSubscriptionsResponse { users }))
+}
 
-    let model_request_usage = subscription_usage_meters
-        .into_iter()
-        .filter_map(|(usage_meter, _usage)| {
-            let model = llm_db.model_by_id(usage_meter.model_id).ok()?;
+pub(crate) async fn find_overlapping_subscriptions(
+    app: &Arc<AppState>,
+) -> anyhow::Result<Vec<OverlappingSubscriptionsJson>> {
+    let resolution = app
+        .config
+        .overlapping_subscription_resolution
+        .unwrap_or_default();
 
-            Some(ModelRequestUsage {
-                model: model.name.clone(),
-                mode: usage_meter.mode,
-                requests: usage_meter.requests,
+    let mut users = app
+        .db
+        .get_overlapping_billing_subscriptions()
+        .await?
+        .into_iter()
+        .filter_map(|(user_id, subscriptions)| {
+            let kept = subscription_to_keep(&subscriptions, resolution)?;
+
+            Some(OverlappingSubscriptionsJson {
+                user_id,
+                kept: kept.stripe_subscription_id.clone(),
+                canceled: subscriptions
+                    .iter()
+                    .filter(|subscription| subscription.id != kept.id)
+                    .map(|subscription| subscription.stripe_subscription_id.clone())
+                    .collect(),
             })
         })
         .collect::<Vec<_>>();
+    users.sort_by_key(|user| user.user_id);
 
-    Ok(Json(GetCurrentUsageResponse {
-        plan: plan.as_str().to_string(),
-        current_usage: Some(CurrentUsage {
-            model_requests: UsageCounts {
-                used: usage.model_requests,
-                limit: model_requests_limit,
-                remaining: model_requests_limit.map(|limit| (limit - usage.model_requests).max(0)),
-            },
-            model_request_usage,
-            edit_predictions: UsageCounts {
-                used: usage.edit_predictions,
-                limit: edit_predictions_limit,
-                remaining: edit_predictions_limit
-                    .map(|limit| (limit - usage.edit_predictions).max(0)),
-            },
-        }),
-    }))
+    Ok(users)
 }
 
-impl From<SubscriptionStatus> for StripeSubscriptionStatus {
-    fn from(value: SubscriptionStatus) -> Self {
-        match value {
-            SubscriptionStatus::Incomplete => Self::Incomplete,
-            SubscriptionStatus::IncompleteExpired => Self::IncompleteExpired,
-            SubscriptionStatus::Trialing => Self::Trialing,
-            SubscriptionStatus::Active => Self::Active,
-            SubscriptionStatus::PastDue => Self::PastDue,
-            SubscriptionStatus::Canceled => Self::Canceled,
-            SubscriptionStatus::Unpaid => Self::Unpaid,
-            SubscriptionStatus::Paused => Self::Paused,
-        }
-    }
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct UnclassifiedSubscriptionJson {
+    pub(crate) user_id: UserId,
+    pub(crate) stripe_subscription_id: String,
+    pub(crate) status: StripeSubscriptionStatus,
+    /// The kind that we gave the subscription, per `unclassified_subscription_plan`.
+    pub(crate) kind: Option<SubscriptionKind>,
 }
 
-impl From<CancellationDetailsReason> for StripeCancellationReason {
-    fn from(value: CancellationDetailsReason) -> Self {
-        match value {
-            CancellationDetailsReason::CancellationRequested => Self::CancellationRequested,
-            CancellationDetailsReason::PaymentDisputed => Self::PaymentDisputed,
-            CancellationDetailsReason::PaymentFailed => Self::PaymentFailed,
-        }
-    }
+#[derive(Debug, Serialize)]
+struct ListUnclassifiedSubscriptionsResponse {
+    subscriptions: Vec<UnclassifiedSubscriptionJson>,
 }
 
-/// Finds or creates a billing customer using the provided customer.
-pub async fn find_or_create_billing_customer(
-    app: &Arc<AppState>,
-    stripe_client: &dyn StripeClient,
-    customer_id: &StripeCustomerId,
-) -> anyhow::Result<Option<billing_customer::Model>> {
-    // If we already have a billing customer record associated with the Stripe customer,
-    // there's nothing more we need to do.
-    if let Some(billing_customer) = app
+/// Returns the subscriptions that we couldn't determine the kind of from their Stripe prices.
+async fn list_unclassified_subscriptions(
+    Extension(app): Extension<Arc<AppState>>,
+) -> Result<Json<ListUnclassifiedSubscriptionsResponse>> {
+    let subscriptions = find_unclassified_subscriptions(&app).await?;
+
+    Ok(Json(ListUnclassifiedSubscriptionsResponse {
+        subscriptions,
+    }))
+}
+
+pub(crate) async fn find_unclassified_subscriptions(
+    app: &AppState,
+) -> anyhow::Result<Vec<UnclassifiedSubscriptionJson>> {
+    Ok(app
         .db
-        .get_billing_customer_by_stripe_customer_id(customer_id.0.as_ref())
+        .get_unclassified_billing_subscriptions()
         .await?
-    {
-        return Ok(Some(billing_customer));
-    }
+        .into_iter()
+        .map(|(user_id, subscription)| UnclassifiedSubscriptionJson {
+            user_id,
+            stripe_subscription_id: subscription.stripe_subscription_id,
+            status: subscription.stripe_subscription_status,
+            kind: subscription.kind,
+        })
+        .collect())
+}
 
-    let customer = stripe_client.get_customer(customer_id).await?;
+/// Cancels all but one of the active subscriptions for each user that has more than one.
+///
+/// Returns the IDs of the users whose subscriptions were reconciled, so that their plans can be updated.
+pub(crate) async fn reconcile_overlapping_subscriptions(
+    app: &Arc<AppState>,
+    stripe_client: &Arc<dyn StripeClient>,
+) -> anyhow::Result<Vec<UserId>> {
+    let overlapping_subscriptions = find_overlapping_subscriptions(app).await?;
+
+    let mut user_ids = Vec::new();
+    for user in overlapping_subscriptions {
+        log::error!(
+            "user {} has {} active subscriptions, keeping {} and canceling {}",
+            user.user_id,
+            user.canceled.len() + 1,
+            user.kept,
+            user.canceled.join(", ")
+        );
+
+        for stripe_subscription_id in user.canceled {
+            let stripe_subscription_id = StripeSubscriptionId(stripe_subscription_id.into());
+
+            async {
+                stripe_client
+                    .cancel_subscription(&stripe_subscription_id, None)
+                    .await?;
+                let subscription = stripe_client
+                    .get_subscription(&stripe_subscription_id)
+                    .await?;
+                sync_subscription(app, stripe_client, subscription).await?;
 
-    let Some(email) = customer.email else {
-        return Ok(None);
-    };
+                anyhow::Ok(())
+            }
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to cancel overlapping subscription {stripe_subscription_id} for user {}",
+                    user.user_id
+                )
+            })
+            .log_err();
+        }
 
-    let Some(user) = app.db.get_user_by_email(&email).await? else {
-        return Ok(None);
-    };
+        user_ids.push(user.user_id);
+    }
 
-    let billing_customer = app
-        .db
-        .create_billing_customer(&CreateBillingCustomerParams {
-            user_id: user.id,
-            stripe_customer_id: customer.id.to_string(),
-        })
-        .await?;
+    Ok(user_ids)
+}
 
-    Ok(Some(billing_customer))
+#[derive(Debug, Serialize)]
+struct StuckStripeEventJson {
+    stripe_event_id: String,
+    stripe_event_type: String,
+    attempts: i32,
+    last_error: String,
+    created_at: String,
+    first_failed_at: String,
+    last_failed_at: String,
+    age_in_seconds: i64,
 }
 
-const SYNC_LLM_REQUEST_USAGE_WITH_STRIPE_INTERVAL: Duration = Duration::from_secs(60);
+#[derive(Debug, Serialize)]
+struct ListStuckStripeEventsResponse {
+    events: Vec<StuckStripeEventJson>,
+}
 
-pub fn sync_llm_request_usage_with_stripe_periodically(app: Arc<AppState>) {
-    let Some(stripe_billing) = app.stripe_billing.clone() else {
-        log::warn!("failed to retrieve Stripe billing object");
-        return;
-    };
-    let Some(llm_db) = app.llm_db.clone() else {
-        log::warn!("failed to retrieve LLM database");
-        return;
-    };
+/// Returns the Stripe events that have failed to process and are still awaiting a successful attempt.
+async fn list_stuck_stripe_events(
+    Extension(app): Extension<Arc<AppState>>,
+) -> Result<Json<ListStuckStripeEventsResponse>> {
+    let now = Utc::now();
+    let events = app.db.get_stuck_stripe_events().await?;
 
-    let executor = app.executor.clone();
-    executor.spawn_detached({
-        let executor = executor.clone();
-        async move {
-            loop {
-                sync_model_request_usage_with_stripe(&app, &llm_db, &stripe_billing)
-                    .await
-                    .context("failed to sync LLM request usage to Stripe")
-                    .trace_err();
-                executor
-                    .sleep(SYNC_LLM_REQUEST_USAGE_WITH_STRIPE_INTERVAL)
-                    .await;
-            }
-        }
-    });
+    Ok(Json(ListStuckStripeEventsResponse {
+        events: events
+            .into_iter()
+            .map(|event| {
+                let created_at = DateTime::from_timestamp(event.stripe_event_created_timestamp, 0)
+                    .unwrap_or_default();
+
+                StuckStripeEventJson {
+                    stripe_event_id: event.stripe_event_id,
+                    stripe_event_type: event.stripe_event_type,
+                    attempts: event.attempts,
+                    last_error: event.last_error,
+                    created_at: created_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+                    first_failed_at: event
+                        .first_failed_at
+                        .and_utc()
+                        .to_rfc3339_opts(SecondsFormat::Millis, true),
+                    last_failed_at: event
+                        .last_failed_at
+                        .and_utc()
+                        .to_rfc3339_opts(SecondsFormat::Millis, true),
+                    age_in_seconds: (now - created_at).num_seconds(),
+                }
+            })
+            .collect(),
+    }))
 }
 
-async fn sync_model_request_usage_with_stripe(
-    app: &Arc<AppState>,
-    llm_db: &Arc<LlmDatabase>,
-    stripe_billing: &Arc<StripeBilling>,
-) -> anyhow::Result<()> {
-    log::info!("Stripe usage sync: Starting");
-    let started_at = Utc::now();
+#[derive(Debug, Serialize)]
+struct RetryStripeEventResponse {
+    processed: bool,
+    error: Option<String>,
+}
 
-    let staff_users = app.db.get_staff_users().await?;
-    let staff_user_ids = staff_users
-        .iter()
-        .map(|user| user.id)
-        .collect::<HashSet<UserId>>();
+/// Retrieves the Stripe event with the given ID and attempts to process it again.
+async fn retry_stripe_event(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<Server>>,
+    Path(event_id): Path<String>,
+) -> Result<Json<RetryStripeEventResponse>> {
+    ensure_billing_writable(&app.config)?;
+
+    let Some((stripe_client, real_stripe_client)) = app
+        .stripe_client
+        .clone()
+        .zip(app.real_stripe_client.clone())
+    else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
 
-    let usage_meters = llm_db
-        .get_current_subscription_usage_meters(Utc::now())
+    if app
+        .db
+        .get_failed_stripe_event_by_event_id(&event_id)
+        .await?
+        .is_none()
+    {
+        return Err(Error::http(
+            StatusCode::NOT_FOUND,
+            format!("no failed Stripe event found for {event_id}"),
+        ));
+    }
+
+    let processed_events = app
+        .db
+        .get_processed_stripe_events_by_event_ids(&[event_id.as_str()])
         .await?;
-    let mut usage_meters_by_user_id =
-        HashMap::<UserId, Vec<subscription_usage_meter::Model>>::default();
-    for (usage_meter, usage) in usage_meters {
-        let meters = usage_meters_by_user_id.entry(usage.user_id).or_default();
-        meters.push(usage_meter);
+    if !processed_events.is_empty() {
+        return Err(Error::http(
+            StatusCode::CONFLICT,
+            format!("Stripe event {event_id} has already been processed"),
+        ));
     }
 
-    log::info!("Stripe usage sync: Retrieving Zed Pro subscriptions");
-    let get_zed_pro_subscriptions_started_at = Utc::now();
-    let billing_subscriptions = app.db.get_active_zed_pro_billing_subscriptions().await?;
-    log::info!(
-        "Stripe usage sync: Retrieved {} Zed Pro subscriptions in {}",
-        billing_subscriptions.len(),
-        Utc::now() - get_zed_pro_subscriptions_started_at
-    );
+    let stripe_event_id =
+        stripe::EventId::from_str(&event_id).context("failed to parse Stripe event ID")?;
+    let event = stripe::Event::retrieve(&real_stripe_client, &stripe_event_id, &[])
+        .await
+        .context("failed to retrieve Stripe event")?;
+
+    let result = process_stripe_event(
+        &app,
+        &rpc_server,
+        &stripe_client,
+        &real_stripe_client,
+        event,
+    )
+    .await;
 
-    let claude_sonnet_4 = stripe_billing
-        .find_price_by_lookup_key("claude-sonnet-4-requests")
-        .await?;
-    let claude_sonnet_4_max = stripe_billing
-        .find_price_by_lookup_key("claude-sonnet-4-requests-max")
-        .await?;
-    let claude_opus_4 = stripe_billing
-        .find_price_by_lookup_key("claude-opus-4-requests")
-        .await?;
-    let claude_opus_4_max = stripe_billing
-        .find_price_by_lookup_key("claude-opus-4-requests-max")
-        .await?;
-    let claude_3_5_sonnet = stripe_billing
-        .find_price_by_lookup_key("claude-3-5-sonnet-requests")
-        .await?;
-    let claude_3_7_sonnet = stripe_billing
-        .find_price_by_lookup_key("claude-3-7-sonnet-requests")
-        .await?;
-    let claude_3_7_sonnet_max = stripe_billing
-        .find_price_by_lookup_key("claude-3-7-sonnet-requests-max")
-        .await?;
+    Ok(Json(RetryStripeEventResponse {
+        processed: result.is_ok(),
+        error: result.err().map(|error| format!("{error:#}")),
+    }))
+}
 
-    let model_mode_combinations = [
-        ("claude-opus-4", CompletionMode::Max),
-        ("claude-opus-4", CompletionMode::Normal),
-        ("claude-sonnet-4", CompletionMode::Max),
-        ("claude-sonnet-4", CompletionMode::Normal),
-        ("claude-3-7-sonnet", CompletionMode::Max),
-        ("claude-3-7-sonnet", CompletionMode::Normal),
-        ("claude-3-5-sonnet", CompletionMode::Normal),
-    ];
+/// The header containing the signature of a Stripe webhook request.
+const STRIPE_SIGNATURE_HEADER: &str = "Stripe-Signature";
 
-    let billing_subscription_count = billing_subscriptions.len();
+/// How far a Stripe webhook's timestamp may be from the current time, so that captured requests can't be replayed
+/// later. This matches the tolerance of Stripe's own libraries.
+pub(crate) const STRIPE_WEBHOOK_TOLERANCE: Duration = Duration::from_secs(5 * 60);
 
-    log::info!("Stripe usage sync: Syncing {billing_subscription_count} Zed Pro subscriptions");
+/// The fields common to every Stripe event, which we read before deserializing the rest of the event.
+#[derive(Debug, Deserialize)]
+struct StripeWebhookEventHeader {
+    id: String,
+    #[serde(rename = "type")]
+    event_type: String,
+}
 
-    for (user_id, (billing_customer, billing_subscription)) in billing_subscriptions {
-        maybe!(async {
-            if staff_user_ids.contains(&user_id) {
-                return anyhow::Ok(());
-            }
+/// Handles a Stripe event that was pushed to us, rather than waiting for the poller to retrieve it.
+///
+/// Events are recorded as processed just like the poller records them, so the two can run side by side without an
+/// event being handled twice.
+async fn handle_stripe_webhook(
+    Extension(app): Extension<Arc<AppState>>,
+    Extension(rpc_server): Extension<Arc<Server>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<StatusCode> {
+    let Some(secret) = app.config.stripe_webhook_secret.as_deref() else {
+        log::error!("failed to retrieve Stripe webhook secret");
+        Err(BillingError::NotSupported)?
+    };
 
-            let stripe_customer_id =
-                StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
-            let stripe_subscription_id =
-                StripeSubscriptionId(billing_subscription.stripe_subscription_id.clone().into());
+    let signature = headers
+        .get(STRIPE_SIGNATURE_HEADER)
+        .and_then(|signature| signature.to_str().ok())
+        .ok_or_else(|| {
+            Error::http(
+                StatusCode::BAD_REQUEST,
+                format!("missing {STRIPE_SIGNATURE_HEADER} header"),
+            )
+        })?;
+    verify_stripe_webhook_signature(secret, signature, &body, Utc::now())
+        .map_err(|error| Error::http(StatusCode::BAD_REQUEST, format!("{error:#}")))?;
 
-            let usage_meters = usage_meters_by_user_id.get(&user_id);
+    let event_header = serde_json::from_slice::<StripeWebhookEventHeader>(&body)
+        .map_err(|error| Error::http(StatusCode::BAD_REQUEST, error.to_string()))?;
 
-            for (model, mode) in &model_mode_combinations {
-                let Ok(model) =
-                    llm_db.model(LanguageModelProvider::Anthropic, model)
-                else {
-                    log::warn!("Failed to load model for user {user_id}: {model}");
-                    continue;
-                };
+    // Acknowledge the events we don't handle, so that Stripe stops retrying them.
+    if !HANDLED_STRIPE_EVENT_TYPES
+        .into_iter()
+        .any(|event_type| event_type_to_string(event_type) == event_header.event_type)
+    {
+        log::info!(
+            "Stripe webhook: ignoring '{}' of type {}",
+            event_header.id,
+            event_header.event_type
+        );
+        return Ok(StatusCode::OK);
+    }
 
-                let (price, meter_event_name) = match model.name.as_str() {
-                    "claude-opus-4" => match mode {
-                        CompletionMode::Normal => (&claude_opus_4, "claude_opus_4/requests"),
-                        CompletionMode::Max => (&claude_opus_4_max, "claude_opus_4/requests/max"),
-                    },
-                    "claude-sonnet-4" => match mode {
-                        CompletionMode::Normal => (&claude_sonnet_4, "claude_sonnet_4/requests"),
-                        CompletionMode::Max => {
-                            (&claude_sonnet_4_max, "claude_sonnet_4/requests/max")
-                        }
-                    },
-                    "claude-3-5-sonnet" => (&claude_3_5_sonnet, "claude_3_5_sonnet/requests"),
-                    "claude-3-7-sonnet" => match mode {
-                        CompletionMode::Normal => {
-                            (&claude_3_7_sonnet, "claude_3_7_sonnet/requests")
-                        }
-                        CompletionMode::Max => {
-                            (&claude_3_7_sonnet_max, "claude_3_7_sonnet/requests/max")
-                        }
-                    },
-                    model_name => {
-                        bail!("Attempted to sync usage meter for unsupported model: {model_name:?}")
-                    }
-                };
+    ensure_billing_writable(&app.config)?;
 
-                let model_requests = usage_meters
-                    .and_then(|usage_meters| {
-                        usage_meters
-                            .iter()
-                            .find(|meter| meter.model_id == model.id && meter.mode == *mode)
-                    })
-                    .map(|usage_meter| usage_meter.requests)
-                    .unwrap_or(0);
+    let Some((stripe_client, real_stripe_client)) = app
+        .stripe_client
+        .clone()
+        .zip(app.real_stripe_client.clone())
+    else {
+        log::error!("failed to retrieve Stripe client");
+        Err(BillingError::NotSupported)?
+    };
 
-                if model_requests > 0 {
-                    stripe_billing
-                        .subscribe_to_price(&stripe_subscription_id, price)
-                        .await?;
-                }
+    let processed_events = app
+        .db
+        .get_processed_stripe_events_by_event_ids(&[event_header.id.as_str()])
+        .await?;
+    if !processed_events.is_empty() {
+        log::debug!(
+            "Stripe webhook: already processed '{}', skipping",
+            event_header.id
+        );
+        return Ok(StatusCode::OK);
+    }
 
-                stripe_billing
-                    .bill_model_request_usage(&stripe_customer_id, meter_event_name, model_requests)
-                    .await
-                    .with_context(|| {
-                        format!(
-                            "Failed to bill model request usage of {model_requests} for {stripe_customer_id}: {meter_event_name}",
-                        )
-                    })?;
-            }
+    let event = serde_json::from_slice::<stripe::Event>(&body)
+        .with_context(|| format!("failed to deserialize Stripe event {}", event_header.id))?;
 
-            Ok(())
-        })
-        .await
-        .log_err();
+    if is_stale_stripe_event(&app.config, event.created, Utc::now()) {
+        report_stale_stripe_event(&app, &event).await;
+        return Ok(StatusCode::OK);
     }
 
-    log::info!(
-        "Stripe usage sync: Synced {billing_subscription_count} Zed Pro subscriptions in {}",
-        Utc::now() - started_at
-    );
+    // If processing fails, we return an error so that Stripe retries the event.
+    process_stripe_event(
+        &app,
+        &rpc_server,
+        &stripe_client,
+        &real_stripe_client,
+        event,
+    )
+    .await?;
+
+    Ok(StatusCode::OK)
+}
+
+/// Verifies the `Stripe-Signature` header of a webhook request.
+///
+/// The header has the form `t=<timestamp>,v1=<signature>,...`, where each `v1` signature is the hex-encoded
+/// HMAC-SHA256 of `<timestamp>.<payload>`. Stripe sends more than one `v1` signature while a secret is being rolled.
+pub(crate) fn verify_stripe_webhook_signature(
+    secret: &str,
+    signature_header: &str,
+    payload: &[u8],
+    now: DateTime<Utc>,
+) -> anyhow::Result<()> {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for (key, value) in signature_header
+        .split(',')
+        .filter_map(|part| part.trim().split_once('='))
+    {
+        match key {
+            "t" => timestamp = value.parse::<i64>().ok(),
+            "v1" => signatures.push(value),
+            _ => {}
+        }
+    }
+
+    let timestamp = timestamp.context("Stripe signature has no timestamp")?;
+    if (now.timestamp() - timestamp).unsigned_abs() > STRIPE_WEBHOOK_TOLERANCE.as_secs() {
+        bail!("Stripe signature timestamp is outside of the tolerance");
+    }
+
+    // `verify_slice` compares in constant time, so that the comparison doesn't leak how much of a signature matched.
+    let matches = |signature: &str| {
+        hex::decode(signature).is_ok_and(|signature| {
+            timestamped_mac(secret, timestamp, payload)
+                .verify_slice(&signature)
+                .is_ok()
+        })
+    };
+    if !signatures.into_iter().any(matches) {
+        bail!("Stripe signature does not match");
+    }
 
     Ok(())
 }
@@ -0,0 +1,188 @@
+//! Localized display text for billing plans.
+//!
+//! Only the text that we show to users is localized. The identifiers in our responses (e.g., subscription kinds and
+//! statuses) stay the same in every locale.
+
+use axum::http::{HeaderMap, header::ACCEPT_LANGUAGE};
+
+use crate::db::billing_subscription::SubscriptionKind;
+
+/// A locale that billing text can be displayed in.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Locale {
+    #[default]
+    English,
+    German,
+    French,
+    Spanish,
+}
+
+impl Locale {
+    /// Returns the locale for the given language tag (e.g., `de` or `de-CH`), if we support it.
+    pub fn from_language_tag(tag: &str) -> Option<Self> {
+        let language = tag.trim().split(['-', '_']).next()?;
+
+        match language.to_ascii_lowercase().as_str() {
+            "en" => Some(Self::English),
+            "de" => Some(Self::German),
+            "fr" => Some(Self::French),
+            "es" => Some(Self::Spanish),
+            _ => None,
+        }
+    }
+
+    /// Returns the locale to use for a request.
+    ///
+    /// An explicit `locale` parameter takes precedence over the `Accept-Language` header. When neither names a
+    /// locale we support, we fall back to English.
+    pub fn for_request(locale: Option<&str>, headers: &HeaderMap) -> Self {
+        locale
+            .and_then(Self::from_language_tag)
+            .or_else(|| {
+                let accept_language = headers.get(ACCEPT_LANGUAGE)?.to_str().ok()?;
+                Self::from_accept_language(accept_language)
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the most preferred supported locale in an `Accept-Language` header value.
+    fn from_accept_language(value: &str) -> Option<Self> {
+        let mut languages = value
+            .split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let tag = parts.next()?.trim();
+                let quality = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |quality| quality.trim().parse::<f32>().ok())?;
+
+                Some((tag, quality))
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect::<Vec<_>>();
+
+        // The sort is stable, so languages with the same quality keep the order they were listed in.
+        languages.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+
+        languages
+            .into_iter()
+            .find_map(|(tag, _)| Self::from_language_tag(tag))
+    }
+}
+
+/// The display text for a plan.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct PlanText {
+    pub name: &'static str,
+    pub description: &'static str,
+}
+
+/// Returns the display text for the plan of a subscription of the given kind.
+///
+/// Falls back to English for any text that hasn't been translated into the locale.
+pub fn plan_text(kind: Option<SubscriptionKind>, locale: Locale) -> PlanText {
+    TRANSLATED_PLAN_TEXT
+        .iter()
+        .find(|(translation_locale, translation_kind, _)| {
+            *translation_locale == locale && *translation_kind == kind
+        })
+        .map(|(_, _, text)| *text)
+        .unwrap_or_else(|| english_plan_text(kind))
+}
+
+fn english_plan_text(kind: Option<SubscriptionKind>) -> PlanText {
+    match kind {
+        Some(SubscriptionKind::ZedPro) => PlanText {
+            name: "Zed Pro",
+            description: "More prompts, unlimited edit predictions, and usage-based billing beyond your included prompts.",
+        },
+        Some(SubscriptionKind::ZedProTrial) => PlanText {
+            name: "Zed Pro (Trial)",
+            description: "Try everything in Zed Pro for free.",
+        },
+        Some(SubscriptionKind::ZedFree) => PlanText {
+            name: "Zed Free",
+            description: "A limited number of prompts and edit predictions each month.",
+        },
+        None => PlanText {
+            name: "Zed LLM Usage",
+            description: "Usage-based billing for language model requests.",
+        },
+    }
+}
+
+/// The plan text that has been translated out of English.
+const TRANSLATED_PLAN_TEXT: &[(Locale, Option<SubscriptionKind>, PlanText)] = &[
+    (
+        Locale::German,
+        Some(SubscriptionKind::ZedPro),
+        PlanText {
+            name: "Zed Pro",
+            description: "Mehr Prompts, unbegrenzte Edit Predictions und nutzungsbasierte Abrechnung über die enthaltenen Prompts hinaus.",
+        },
+    ),
+    (
+        Locale::German,
+        Some(SubscriptionKind::ZedProTrial),
+        PlanText {
+            name: "Zed Pro (Testversion)",
+            description: "Teste alle Funktionen von Zed Pro kostenlos.",
+        },
+    ),
+    (
+        Locale::German,
+        Some(SubscriptionKind::ZedFree),
+        PlanText {
+            name: "Zed Free",
+            description: "Eine begrenzte Anzahl an Prompts und Edit Predictions pro Monat.",
+        },
+    ),
+    (
+        Locale::French,
+        Some(SubscriptionKind::ZedPro),
+        PlanText {
+            name: "Zed Pro",
+            description: "Plus de prompts, des prédictions de modifications illimitées et une facturation à l'usage au-delà des prompts inclus.",
+        },
+    ),
+    (
+        Locale::French,
+        Some(SubscriptionKind::ZedProTrial),
+        PlanText {
+            name: "Zed Pro (essai)",
+            description: "Essayez gratuitement toutes les fonctionnalités de Zed Pro.",
+        },
+    ),
+    (
+        Locale::French,
+        Some(SubscriptionKind::ZedFree),
+        PlanText {
+            name: "Zed Free",
+            description: "Un nombre limité de prompts et de prédictions de modifications chaque mois.",
+        },
+    ),
+    (
+        Locale::Spanish,
+        Some(SubscriptionKind::ZedPro),
+        PlanText {
+            name: "Zed Pro",
+            description: "Más prompts, predicciones de edición ilimitadas y facturación por uso más allá de los prompts incluidos.",
+        },
+    ),
+    (
+        Locale::Spanish,
+        Some(SubscriptionKind::ZedProTrial),
+        PlanText {
+            name: "Zed Pro (prueba)",
+            description: "Prueba todas las funciones de Zed Pro gratis.",
+        },
+    ),
+    (
+        Locale::Spanish,
+        Some(SubscriptionKind::ZedFree),
+        PlanText {
+            name: "Zed Free",
+            description: "Un número limitado de prompts y predicciones de edición cada mes.",
+        },
+    ),
+];
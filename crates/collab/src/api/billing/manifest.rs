@@ -0,0 +1,221 @@
+//! A deterministic snapshot of the effective billing configuration.
+//!
+//! The manifest is hashed over its canonical serialization, so two environments running the same billing
+//! configuration produce the same hash, regardless of the order in which Stripe returned the prices.
+
+use collections::BTreeMap;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+use crate::stripe_client::StripePrice;
+use crate::{
+    Config, DuplicateCheckoutBehavior, OverlappingSubscriptionResolution, TrialEndBehavior,
+    TrialPaymentMethodCollection, UnclassifiedSubscriptionPlan,
+};
+
+use super::{
+    ModelRequestAllotment, ModelRequestLimit, POLL_EVENTS_INTERVAL,
+    SYNC_LLM_REQUEST_USAGE_WITH_STRIPE_INTERVAL, UsageResetWindow, model_request_allotments,
+    model_request_billing_table, model_request_limits, usage_reset_window,
+};
+
+#[derive(Debug, Serialize)]
+pub struct BillingConfigManifest {
+    prices: Vec<PriceManifest>,
+    plan_limits: Vec<PlanLimitsManifest>,
+    model_request_billing: Vec<ModelRequestBillingManifest>,
+    intervals: IntervalsManifest,
+    settings: SettingsManifest,
+}
+
+#[derive(Debug, Serialize)]
+struct PriceManifest {
+    lookup_key: Option<String>,
+    id: String,
+    unit_amount: Option<i64>,
+    interval: Option<&'static str>,
+    interval_count: Option<u64>,
+    meter: Option<String>,
+}
+
+/// The usage limits of a plan, where `None` means unlimited.
+#[derive(Debug, Serialize)]
+struct PlanLimitsManifest {
+    plan: String,
+    model_requests: Option<i32>,
+    model_request_allotments: Vec<ModelRequestAllotment>,
+    model_request_limits: Vec<ModelRequestLimit>,
+    edit_predictions: Option<i32>,
+    /// The day of the month that model requests reset on, where `None` means the end of the subscription period.
+    model_requests_reset_day: Option<u32>,
+    /// The day of the month that edit predictions reset on, where `None` means the end of the subscription period.
+    edit_predictions_reset_day: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+struct ModelRequestBillingManifest {
+    provider: String,
+    model: String,
+    mode: &'static str,
+    dimensions: BTreeMap<String, String>,
+    request_weight: i32,
+    price_lookup_key: String,
+    meter_event_name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct IntervalsManifest {
+    poll_stripe_events_seconds: u64,
+    sync_usage_with_stripe_seconds: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct SettingsManifest {
+    trial_end_behavior: TrialEndBehavior,
+    edit_prediction_overages_enabled: bool,
+    overlapping_subscription_resolution: OverlappingSubscriptionResolution,
+    duplicate_checkout_behavior: DuplicateCheckoutBehavior,
+    trial_payment_method_collection: TrialPaymentMethodCollection,
+    zed_pro_trial_days: u32,
+    zed_pro_extended_trial_days: u32,
+    overage_grace: i32,
+    winback_coupon_id: Option<String>,
+    unclassified_subscription_plan: UnclassifiedSubscriptionPlan,
+    alert_on_unclassified_subscriptions: bool,
+    free_downgrade_delay_seconds: u64,
+    past_due_grace_period_seconds: u64,
+    minimum_term_seconds: u64,
+    max_subscription_pauses_per_year: u32,
+    stale_stripe_event_age_seconds: u64,
+}
+
+impl BillingConfigManifest {
+    pub fn new(config: &Config, prices: &[StripePrice]) -> Self {
+        let mut prices = prices
+            .iter()
+            .map(|price| PriceManifest {
+                lookup_key: price.lookup_key.clone(),
+                id: price.id.to_string(),
+                unit_amount: price.unit_amount,
+                interval: price
+                    .recurring
+                    .as_ref()
+                    .map(|recurring| recurring.interval.as_str()),
+                interval_count: price
+                    .recurring
+                    .as_ref()
+                    .map(|recurring| recurring.interval_count),
+                meter: price
+                    .recurring
+                    .as_ref()
+                    .and_then(|recurring| recurring.meter.clone()),
+            })
+            .collect::<Vec<_>>();
+        prices.sort_by(|a, b| (&a.lookup_key, &a.id).cmp(&(&b.lookup_key, &b.id)));
+
+        let limit = |limit: zed_llm_client::UsageLimit| match limit {
+            zed_llm_client::UsageLimit::Limited(limit) => Some(limit),
+            zed_llm_client::UsageLimit::Unlimited => None,
+        };
+        let reset_day = |plan, counter| match usage_reset_window(config, plan, counter) {
+            UsageResetWindow::SubscriptionPeriod => None,
+            UsageResetWindow::Monthly { anchor_day } => Some(anchor_day),
+        };
+        let plan_limits = [
+            zed_llm_client::Plan::ZedFree,
+            zed_llm_client::Plan::ZedProTrial,
+            zed_llm_client::Plan::ZedPro,
+        ]
+        .into_iter()
+        .map(|plan| PlanLimitsManifest {
+            plan: plan.as_str().to_string(),
+            model_requests: limit(plan.model_requests_limit()),
+            model_request_allotments: model_request_allotments(
+                config,
+                plan,
+                limit(plan.model_requests_limit()),
+            ),
+            model_request_limits: model_request_limits(config, plan),
+            edit_predictions: limit(plan.edit_predictions_limit()),
+            model_requests_reset_day: reset_day(plan, "model_requests"),
+            edit_predictions_reset_day: reset_day(plan, "edit_predictions"),
+        })
+        .collect();
+
+        Self {
+            prices,
+            plan_limits,
+            model_request_billing: model_request_billing_table(config)
+                .into_iter()
+                .map(|billing| ModelRequestBillingManifest {
+                    provider: billing.provider.to_string(),
+                    model: billing.model,
+                    mode: billing.mode.as_str(),
+                    dimensions: billing.dimensions.into_iter().collect(),
+                    request_weight: billing.request_weight,
+                    price_lookup_key: billing.price_lookup_key,
+                    meter_event_name: billing.meter_event_name,
+                })
+                .collect(),
+            intervals: IntervalsManifest {
+                poll_stripe_events_seconds: POLL_EVENTS_INTERVAL.as_secs(),
+                sync_usage_with_stripe_seconds: SYNC_LLM_REQUEST_USAGE_WITH_STRIPE_INTERVAL
+                    .as_secs(),
+            },
+            settings: SettingsManifest {
+                trial_end_behavior: config.trial_end_behavior.unwrap_or_default(),
+                edit_prediction_overages_enabled: config.edit_prediction_overages_enabled(),
+                overlapping_subscription_resolution: config
+                    .overlapping_subscription_resolution
+                    .unwrap_or_default(),
+                duplicate_checkout_behavior: config.duplicate_checkout_behavior.unwrap_or_default(),
+                trial_payment_method_collection: config
+                    .trial_payment_method_collection
+                    .unwrap_or_default(),
+                zed_pro_trial_days: config.zed_pro_trial_days(),
+                zed_pro_extended_trial_days: config.zed_pro_extended_trial_days(),
+                overage_grace: config.overage_grace(),
+                winback_coupon_id: config.winback_coupon_id.clone(),
+                unclassified_subscription_plan: config
+                    .unclassified_subscription_plan
+                    .unwrap_or_default(),
+                alert_on_unclassified_subscriptions: config.alert_on_unclassified_subscriptions(),
+                free_downgrade_delay_seconds: config.free_downgrade_delay().as_secs(),
+                past_due_grace_period_seconds: config.past_due_grace_period().as_secs(),
+                minimum_term_seconds: config.minimum_term().as_secs(),
+                max_subscription_pauses_per_year: config.max_subscription_pauses_per_year(),
+                stale_stripe_event_age_seconds: config.stale_stripe_event_age().as_secs(),
+            },
+        }
+    }
+
+    /// Returns the hex-encoded SHA-256 hash of the manifest's canonical serialization.
+    pub fn hash(&self) -> anyhow::Result<String> {
+        let canonical_json = canonical_json(serde_json::to_value(self)?);
+        Ok(hex::encode(Sha256::digest(canonical_json.as_bytes())))
+    }
+}
+
+/// Serializes the value with the keys of every object sorted, and without any insignificant whitespace.
+pub fn canonical_json(value: Value) -> String {
+    canonicalize(value).to_string()
+}
+
+fn canonicalize(value: Value) -> Value {
+    match value {
+        Value::Object(object) => {
+            let mut entries = object.into_iter().collect::<Vec<_>>();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, canonicalize(value)))
+                    .collect::<Map<_, _>>(),
+            )
+        }
+        Value::Array(values) => Value::Array(values.into_iter().map(canonicalize).collect()),
+        value => value,
+    }
+}
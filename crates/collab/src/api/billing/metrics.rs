@@ -0,0 +1,231 @@
+use std::sync::OnceLock;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use prometheus::core::Collector;
+use prometheus::{
+    Histogram, HistogramOpts, IntCounter, IntGauge, IntGaugeVec, Opts, Registry,
+    exponential_buckets,
+};
+use sea_orm::{ActiveEnum as _, Iterable as _};
+
+use crate::db::billing_subscription::SubscriptionKind;
+use crate::{AppState, Result};
+
+/// The Prometheus metrics for billing.
+///
+/// The metrics that the billing tasks update are registered with the default
+/// registry, so they are also included in the `/metrics` endpoint alongside the
+/// other collab metrics. The gauges that are only refreshed when rendering the
+/// billing metrics are kept out of it, so that `/metrics` never serves stale
+/// values for them.
+pub struct BillingMetrics {
+    /// The registry containing only the billing metrics.
+    registry: Registry,
+    /// The number of Stripe events that were processed successfully.
+    pub stripe_events_processed: IntCounter,
+    /// The number of Stripe events that failed to process.
+    pub stripe_events_failed: IntCounter,
+    /// The Unix timestamp of when we last finished polling for Stripe events.
+    pub stripe_events_last_polled_at: IntGauge,
+    /// The number of unprocessed Stripe events found by the last poll.
+    pub stripe_events_unprocessed: IntGauge,
+    /// The number of pages of Stripe events walked by the last poll.
+    pub stripe_events_pages: IntGauge,
+    /// The age in seconds of the oldest unprocessed Stripe event found by the last poll, or 0 if there were none.
+    pub stripe_events_oldest_unprocessed_age: IntGauge,
+    /// The number of unprocessed Stripe events that we dropped for being too old to process.
+    pub stripe_events_stale_skipped: IntCounter,
+    /// The number of Stripe events dropped for being too old to process by the last poll.
+    pub stripe_events_stale_last_poll: IntGauge,
+    /// How long each pass of syncing LLM usage to Stripe took.
+    pub usage_sync_duration: Histogram,
+    /// How long syncing each user's LLM usage to Stripe took.
+    pub usage_sync_user_duration: Histogram,
+    /// The number of times that a user was skipped by the usage sync after repeatedly failing to sync.
+    pub usage_sync_users_skipped: IntCounter,
+    /// The number of users being skipped by the usage sync as of the last sync.
+    pub usage_sync_users_failing: IntGauge,
+    stripe_events_last_poll_age: IntGauge,
+    active_subscriptions: IntGaugeVec,
+    active_trials: IntGauge,
+    overdue_customers: IntGauge,
+}
+
+impl BillingMetrics {
+    fn new() -> anyhow::Result<Self> {
+        let metrics = Self {
+            registry: Registry::new(),
+            stripe_events_processed: IntCounter::new(
+                "billing_stripe_events_processed_total",
+                "number of Stripe events processed successfully",
+            )?,
+            stripe_events_failed: IntCounter::new(
+                "billing_stripe_events_failed_total",
+                "number of Stripe events that failed to process",
+            )?,
+            stripe_events_last_polled_at: IntGauge::new(
+                "billing_stripe_events_last_polled_at_seconds",
+                "Unix timestamp of the last completed poll for Stripe events",
+            )?,
+            stripe_events_unprocessed: IntGauge::new(
+                "billing_stripe_events_unprocessed",
+                "number of unprocessed Stripe events found by the last poll",
+            )?,
+            stripe_events_pages: IntGauge::new(
+                "billing_stripe_events_pages",
+                "number of pages of Stripe events walked by the last poll",
+            )?,
+            stripe_events_oldest_unprocessed_age: IntGauge::new(
+                "billing_stripe_events_oldest_unprocessed_age_seconds",
+                "age of the oldest unprocessed Stripe event found by the last poll",
+            )?,
+            stripe_events_stale_skipped: IntCounter::new(
+                "billing_stripe_events_stale_skipped_total",
+                "number of unprocessed Stripe events dropped for being too old to process",
+            )?,
+            stripe_events_stale_last_poll: IntGauge::new(
+                "billing_stripe_events_stale_last_poll",
+                "number of Stripe events dropped for being too old to process by the last poll",
+            )?,
+            usage_sync_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "billing_usage_sync_duration_seconds",
+                    "time spent syncing LLM usage to Stripe",
+                )
+                .buckets(exponential_buckets(1.0, 2.0, 10)?),
+            )?,
+            usage_sync_user_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "billing_usage_sync_user_duration_seconds",
+                    "time spent syncing a single user's LLM usage to Stripe",
+                )
+                .buckets(exponential_buckets(0.05, 2.0, 12)?),
+            )?,
+            usage_sync_users_skipped: IntCounter::new(
+                "billing_usage_sync_users_skipped_total",
+                "number of times a user was skipped by the usage sync after repeated failures",
+            )?,
+            usage_sync_users_failing: IntGauge::new(
+                "billing_usage_sync_users_failing",
+                "number of users skipped by the usage sync after repeated failures",
+            )?,
+            stripe_events_last_poll_age: IntGauge::new(
+                "billing_stripe_events_last_poll_age_seconds",
+                "seconds since the last completed poll for Stripe events",
+            )?,
+            active_subscriptions: IntGaugeVec::new(
+                Opts::new(
+                    "billing_active_subscriptions",
+                    "number of active billing subscriptions",
+                ),
+                &["kind"],
+            )?,
+            active_trials: IntGauge::new(
+                "billing_active_trials",
+                "number of active Zed Pro trials",
+            )?,
+            overdue_customers: IntGauge::new(
+                "billing_overdue_customers",
+                "number of billing customers with overdue invoices",
+            )?,
+        };
+
+        metrics.register(metrics.stripe_events_processed.clone())?;
+        metrics.register(metrics.stripe_events_failed.clone())?;
+        metrics.register(metrics.stripe_events_last_polled_at.clone())?;
+        metrics.register(metrics.stripe_events_unprocessed.clone())?;
+        metrics.register(metrics.stripe_events_pages.clone())?;
+        metrics.register(metrics.stripe_events_oldest_unprocessed_age.clone())?;
+        metrics.register(metrics.stripe_events_stale_skipped.clone())?;
+        metrics.register(metrics.stripe_events_stale_last_poll.clone())?;
+        metrics.register(metrics.usage_sync_duration.clone())?;
+        metrics.register(metrics.usage_sync_user_duration.clone())?;
+        metrics.register(metrics.usage_sync_users_skipped.clone())?;
+        metrics.register(metrics.usage_sync_users_failing.clone())?;
+        metrics.register_billing_only(metrics.stripe_events_last_poll_age.clone())?;
+        metrics.register_billing_only(metrics.active_subscriptions.clone())?;
+        metrics.register_billing_only(metrics.active_trials.clone())?;
+        metrics.register_billing_only(metrics.overdue_customers.clone())?;
+
+        Ok(metrics)
+    }
+
+    /// Registers the metric with both the billing registry and the default registry.
+    fn register(&self, collector: impl Collector + Clone + 'static) -> anyhow::Result<()> {
+        prometheus::register(Box::new(collector.clone()))?;
+        self.registry.register(Box::new(collector))?;
+        Ok(())
+    }
+
+    /// Registers the metric with only the billing registry, for the gauges that [`render_billing_metrics`] refreshes.
+    fn register_billing_only(&self, collector: impl Collector + 'static) -> anyhow::Result<()> {
+        self.registry.register(Box::new(collector))?;
+        Ok(())
+    }
+}
+
+static BILLING_METRICS: OnceLock<BillingMetrics> = OnceLock::new();
+
+/// Registers the billing metrics.
+///
+/// This is called at startup, so that a metric that can't be registered stops the server from starting, rather than
+/// failing whichever billing task or request first updates the metrics.
+pub fn init_billing_metrics() -> anyhow::Result<()> {
+    if BILLING_METRICS.get().is_none() {
+        BILLING_METRICS
+            .set(BillingMetrics::new()?)
+            .map_err(|_| anyhow!("billing metrics were already initialized"))?;
+    }
+
+    Ok(())
+}
+
+pub fn billing_metrics() -> &'static BillingMetrics {
+    BILLING_METRICS.get_or_init(|| {
+        BillingMetrics::new()
+            .expect("registering the billing metrics can't fail, as `init_billing_metrics` checks it at startup")
+    })
+}
+
+/// Updates the billing gauges from the database and renders all of the billing
+/// metrics in the Prometheus text format.
+pub async fn render_billing_metrics(app: &AppState, now: DateTime<Utc>) -> Result<String> {
+    let metrics = billing_metrics();
+
+    let active_subscriptions = app.db.count_active_billing_subscriptions_by_kind().await?;
+    for kind in SubscriptionKind::iter() {
+        let count = active_subscriptions.get(&kind).copied().unwrap_or(0);
+        metrics
+            .active_subscriptions
+            .with_label_values(&[kind.to_value().as_str()])
+            .set(count as _);
+    }
+    metrics.active_trials.set(
+        active_subscriptions
+            .get(&SubscriptionKind::ZedProTrial)
+            .copied()
+            .unwrap_or(0) as _,
+    );
+
+    let overdue_customers = app
+        .db
+        .count_billing_customers_with_overdue_invoices()
+        .await?;
+    metrics.overdue_customers.set(overdue_customers as _);
+
+    // Until we've polled at least once, there's no age to report.
+    let last_polled_at = metrics.stripe_events_last_polled_at.get();
+    if last_polled_at > 0 {
+        metrics
+            .stripe_events_last_poll_age
+            .set((now.timestamp() - last_polled_at).max(0));
+    }
+
+    let metric_families = metrics.registry.gather();
+    let encoder = prometheus::TextEncoder::new();
+    let encoded_metrics = encoder
+        .encode_to_string(&metric_families)
+        .map_err(|err| anyhow!("{err}"))?;
+    Ok(encoded_metrics)
+}
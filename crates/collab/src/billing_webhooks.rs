@@ -0,0 +1,159 @@
+//! Outbound webhooks that notify other services (e.g., provisioning or the CRM) of changes to a user's billing.
+//!
+//! Each request is signed with the configured secret, so that receivers can verify that it came from us. The signature
+//! is sent in the [`SIGNATURE_HEADER`] header as `t=<timestamp>,v1=<signature>`, where the signature is the hex-encoded
+//! HMAC-SHA256 of `<timestamp>.<body>`.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use sha2::Sha256;
+use util::ResultExt;
+
+use crate::AppState;
+use crate::db::UserId;
+use crate::db::billing_subscription::StripeSubscriptionStatus;
+use crate::executor::Executor;
+
+/// The header containing the signature of a billing webhook request.
+pub const SIGNATURE_HEADER: &str = "Zed-Signature";
+
+const MAX_DELIVERY_ATTEMPTS: u32 = 3;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The payload sent to billing webhook subscribers.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BillingWebhookPayload {
+    pub user_id: UserId,
+    pub plan: String,
+    pub status: StripeSubscriptionStatus,
+    /// The Stripe event that resulted in the change.
+    pub event: String,
+}
+
+/// Notifies all of the configured billing webhook subscribers of the change in the background.
+///
+/// Delivery is best-effort: failed requests are retried a few times and then dropped.
+pub fn notify_billing_webhook_subscribers(app: &Arc<AppState>, payload: &BillingWebhookPayload) {
+    let Some(secret) = app.config.billing_webhook_secret.clone() else {
+        return;
+    };
+    let Some(urls) = app.config.billing_webhook_urls.clone() else {
+        return;
+    };
+
+    let Some(body) = serde_json::to_vec(payload).log_err() else {
+        return;
+    };
+
+    for url in urls {
+        let executor = app.executor.clone();
+        let secret = secret.clone();
+        let body = body.clone();
+        app.executor.spawn_detached(async move {
+            deliver(&executor, &url, &secret, &body).await.log_err();
+        });
+    }
+}
+
+async fn deliver(executor: &Executor, url: &str, secret: &str, body: &[u8]) -> anyhow::Result<()> {
+    let client = reqwest::Client::new();
+
+    let mut retry_delay = INITIAL_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        let signature = sign_payload(secret, Utc::now().timestamp(), body);
+        let result = client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, signature)
+            .body(body.to_vec())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+
+        match result {
+            Ok(_) => return Ok(()),
+            Err(err) if attempt < MAX_DELIVERY_ATTEMPTS => {
+                log::warn!(
+                    "failed to deliver billing webhook to {url} (attempt {attempt}), retrying: {err}"
+                );
+                executor.sleep(retry_delay).await;
+                retry_delay *= 2;
+                attempt += 1;
+            }
+            Err(err) => {
+                return Err(anyhow!(err).context(format!(
+                    "failed to deliver billing webhook to {url} after {attempt} attempts"
+                )));
+            }
+        }
+    }
+}
+
+/// Returns the value of the [`SIGNATURE_HEADER`] header for a request with the given body.
+pub fn sign_payload(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let signature = timestamped_mac(secret, timestamp, body)
+        .finalize()
+        .into_bytes();
+    format!("t={timestamp},v1={}", hex::encode(signature))
+}
+
+/// Returns the HMAC-SHA256 of `<timestamp>.<body>`, which is what both our webhook signatures and Stripe's are
+/// computed over.
+pub(crate) fn timestamped_mac(secret: &str, timestamp: i64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body);
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_payload_shape() {
+        let payload = BillingWebhookPayload {
+            user_id: UserId(42),
+            plan: "zed_pro".into(),
+            status: StripeSubscriptionStatus::Active,
+            event: "customer.subscription.updated".into(),
+        };
+
+        assert_eq!(
+            serde_json::to_value(&payload).unwrap(),
+            json!({
+                "user_id": 42,
+                "plan": "zed_pro",
+                "status": "active",
+                "event": "customer.subscription.updated",
+            })
+        );
+    }
+
+    #[test]
+    fn test_sign_payload() {
+        let body = br#"{"user_id":42}"#;
+
+        let signature = sign_payload("secret", 1_750_000_000, body);
+        assert_eq!(
+            signature,
+            "t=1750000000,v1=5b5d0aca92ccce2ddb99eae82860c1a5be6de9a010ad0e2d8d40a6f83a10e498"
+        );
+
+        // The signature changes with the secret, the timestamp, and the body.
+        assert_ne!(signature, sign_payload("other-secret", 1_750_000_000, body));
+        assert_ne!(signature, sign_payload("secret", 1_750_000_001, body));
+        assert_ne!(signature, sign_payload("secret", 1_750_000_000, b"{}"));
+    }
+}
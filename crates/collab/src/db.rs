@@ -42,14 +42,23 @@ pub use tests::TestDb;
 
 pub use ids::*;
 pub use queries::billing_customers::{CreateBillingCustomerParams, UpdateBillingCustomerParams};
+pub use queries::billing_meter_reports::CreateBillingMeterReportParams;
+pub use queries::billing_payment_events::CreateBillingPaymentEventParams;
 pub use queries::billing_preferences::{
     CreateBillingPreferencesParams, UpdateBillingPreferencesParams,
 };
+pub use queries::billing_price_change_notices::CreateBillingPriceChangeNoticeParams;
+pub use queries::billing_subscription_members::CreateBillingSubscriptionMemberParams;
+pub use queries::billing_subscription_pauses::CreateBillingSubscriptionPauseParams;
 pub use queries::billing_subscriptions::{
-    CreateBillingSubscriptionParams, UpdateBillingSubscriptionParams,
+    CreateBillingSubscriptionParams, GetBillingSubscriptionsParams, UpdateBillingSubscriptionParams,
 };
+pub use queries::billing_usage_adjustments::CreateBillingUsageAdjustmentParams;
 pub use queries::contributors::ContributorSelector;
+pub use queries::custom_price_overrides::CreateCustomPriceOverrideParams;
+pub use queries::failed_stripe_events::RecordFailedStripeEventParams;
 pub use queries::processed_stripe_events::CreateProcessedStripeEventParams;
+pub use queries::stripe_event_cursors::UpdateStripeEventCursorParams;
 pub use sea_orm::ConnectOptions;
 pub use tables::user::Model as User;
 pub use tables::*;
@@ -71,7 +71,13 @@ macro_rules! id_type {
 
 id_type!(AccessTokenId);
 id_type!(BillingCustomerId);
+id_type!(BillingMeterReportId);
+id_type!(BillingPaymentEventId);
+id_type!(BillingPriceChangeNoticeId);
 id_type!(BillingSubscriptionId);
+id_type!(BillingSubscriptionMemberId);
+id_type!(BillingSubscriptionPauseId);
+id_type!(BillingUsageAdjustmentId);
 id_type!(BillingPreferencesId);
 id_type!(BufferId);
 id_type!(ChannelBufferCollaboratorId);
@@ -79,6 +85,7 @@ id_type!(ChannelChatParticipantId);
 id_type!(ChannelId);
 id_type!(ChannelMemberId);
 id_type!(ContactId);
+id_type!(CustomPriceOverrideId);
 id_type!(ExtensionId);
 id_type!(FlagId);
 id_type!(FollowerId);
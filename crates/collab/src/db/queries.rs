@@ -2,18 +2,28 @@ use super::*;
 
 pub mod access_tokens;
 pub mod billing_customers;
+pub mod billing_meter_reports;
+pub mod billing_payment_events;
 pub mod billing_preferences;
+pub mod billing_price_change_notices;
+pub mod billing_subscription_members;
+pub mod billing_subscription_pauses;
 pub mod billing_subscriptions;
+pub mod billing_usage_adjustments;
 pub mod buffers;
 pub mod channels;
 pub mod contacts;
 pub mod contributors;
+pub mod custom_price_overrides;
 pub mod embeddings;
 pub mod extensions;
+pub mod failed_stripe_events;
 pub mod messages;
 pub mod notifications;
+pub mod pending_billing_customer_links;
 pub mod processed_stripe_events;
 pub mod projects;
 pub mod rooms;
 pub mod servers;
+pub mod stripe_event_cursors;
 pub mod users;
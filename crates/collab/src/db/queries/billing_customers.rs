@@ -12,6 +12,15 @@ pub struct UpdateBillingCustomerParams {
     pub stripe_customer_id: ActiveValue<String>,
     pub has_overdue_invoices: ActiveValue<bool>,
     pub trial_started_at: ActiveValue<Option<DateTime>>,
+    pub billing_suspended: ActiveValue<bool>,
+    pub payment_method_expiry_reminded_at: ActiveValue<Option<DateTime>>,
+    pub winback_offered_at: ActiveValue<Option<DateTime>>,
+    pub stripe_email: ActiveValue<Option<String>>,
+    pub stripe_email_user_mismatch: ActiveValue<bool>,
+    pub tax_id_type: ActiveValue<Option<String>>,
+    pub tax_id: ActiveValue<Option<String>>,
+    pub currency: ActiveValue<String>,
+    pub free_downgrade_at: ActiveValue<Option<DateTime>>,
 }
 
 impl Database {
@@ -47,6 +56,15 @@ impl Database {
                 stripe_customer_id: params.stripe_customer_id.clone(),
                 has_overdue_invoices: params.has_overdue_invoices.clone(),
                 trial_started_at: params.trial_started_at.clone(),
+                billing_suspended: params.billing_suspended.clone(),
+                payment_method_expiry_reminded_at: params.payment_method_expiry_reminded_at.clone(),
+                winback_offered_at: params.winback_offered_at.clone(),
+                stripe_email: params.stripe_email.clone(),
+                stripe_email_user_mismatch: params.stripe_email_user_mismatch.clone(),
+                tax_id_type: params.tax_id_type.clone(),
+                tax_id: params.tax_id.clone(),
+                currency: params.currency.clone(),
+                free_downgrade_at: params.free_downgrade_at.clone(),
                 created_at: ActiveValue::not_set(),
             })
             .exec(&*tx)
@@ -97,4 +115,32 @@ impl Database {
         })
         .await
     }
+
+    /// Returns the billing customers that are due to be subscribed to Zed Free as of `now`.
+    pub async fn get_billing_customers_with_free_downgrade_due(
+        &self,
+        now: DateTime,
+    ) -> Result<Vec<billing_customer::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_customer::Entity::find()
+                .filter(billing_customer::Column::FreeDowngradeAt.lte(now))
+                .order_by_asc(billing_customer::Column::FreeDowngradeAt)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+
+    /// Returns the number of billing customers with overdue invoices.
+    pub async fn count_billing_customers_with_overdue_invoices(&self) -> Result<usize> {
+        self.transaction(|tx| async move {
+            let count = billing_customer::Entity::find()
+                .filter(billing_customer::Column::HasOverdueInvoices.eq(true))
+                .count(&*tx)
+                .await?;
+
+            Ok(count as usize)
+        })
+        .await
+    }
 }
@@ -0,0 +1,67 @@
+use super::*;
+
+#[derive(Debug)]
+pub struct CreateBillingMeterReportParams {
+    pub billing_customer_id: BillingCustomerId,
+    pub meter_event_name: String,
+    pub value: i32,
+    pub identifier: String,
+    pub reported_at: DateTime,
+}
+
+impl Database {
+    /// Records a usage meter event that was reported to Stripe.
+    ///
+    /// A meter event reported again under the same identifier was deduplicated by Stripe, so the existing report is
+    /// returned instead.
+    pub async fn create_billing_meter_report(
+        &self,
+        params: &CreateBillingMeterReportParams,
+    ) -> Result<billing_meter_report::Model> {
+        self.transaction(|tx| async move {
+            billing_meter_report::Entity::insert(billing_meter_report::ActiveModel {
+                billing_customer_id: ActiveValue::set(params.billing_customer_id),
+                meter_event_name: ActiveValue::set(params.meter_event_name.clone()),
+                value: ActiveValue::set(params.value),
+                identifier: ActiveValue::set(params.identifier.clone()),
+                reported_at: ActiveValue::set(params.reported_at),
+                ..Default::default()
+            })
+            .on_conflict(
+                OnConflict::column(billing_meter_report::Column::Identifier)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&*tx)
+            .await?;
+
+            Ok(billing_meter_report::Entity::find()
+                .filter(billing_meter_report::Column::Identifier.eq(params.identifier.as_str()))
+                .one(&*tx)
+                .await?
+                .context("billing meter report not found")?)
+        })
+        .await
+    }
+
+    /// Returns the usage meter events reported to Stripe for the specified billing customer, with timestamps in the
+    /// given range.
+    pub async fn get_billing_meter_reports(
+        &self,
+        billing_customer_id: BillingCustomerId,
+        reported_at_or_after: DateTime,
+        reported_before: DateTime,
+    ) -> Result<Vec<billing_meter_report::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_meter_report::Entity::find()
+                .filter(billing_meter_report::Column::BillingCustomerId.eq(billing_customer_id))
+                .filter(billing_meter_report::Column::ReportedAt.gte(reported_at_or_after))
+                .filter(billing_meter_report::Column::ReportedAt.lt(reported_before))
+                .order_by_asc(billing_meter_report::Column::ReportedAt)
+                .order_by_asc(billing_meter_report::Column::Id)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+}
@@ -0,0 +1,61 @@
+use crate::db::billing_payment_event::PaymentEventKind;
+
+use super::*;
+
+#[derive(Debug)]
+pub struct CreateBillingPaymentEventParams {
+    pub billing_customer_id: BillingCustomerId,
+    pub kind: PaymentEventKind,
+    pub stripe_event_id: String,
+    pub stripe_invoice_id: String,
+    pub amount_in_cents: i64,
+    pub occurred_at: DateTime,
+}
+
+impl Database {
+    /// Records a successful or failed invoice payment.
+    ///
+    /// Recording the same Stripe event again does nothing.
+    pub async fn create_billing_payment_event(
+        &self,
+        params: &CreateBillingPaymentEventParams,
+    ) -> Result<()> {
+        self.transaction(|tx| async move {
+            billing_payment_event::Entity::insert(billing_payment_event::ActiveModel {
+                billing_customer_id: ActiveValue::set(params.billing_customer_id),
+                kind: ActiveValue::set(params.kind),
+                stripe_event_id: ActiveValue::set(params.stripe_event_id.clone()),
+                stripe_invoice_id: ActiveValue::set(params.stripe_invoice_id.clone()),
+                amount_in_cents: ActiveValue::set(params.amount_in_cents),
+                occurred_at: ActiveValue::set(params.occurred_at),
+                ..Default::default()
+            })
+            .on_conflict(
+                OnConflict::column(billing_payment_event::Column::StripeEventId)
+                    .do_nothing()
+                    .to_owned(),
+            )
+            .exec_without_returning(&*tx)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Returns the invoice payments of the specified billing customer, in the order they happened.
+    pub async fn get_billing_payment_events(
+        &self,
+        billing_customer_id: BillingCustomerId,
+    ) -> Result<Vec<billing_payment_event::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_payment_event::Entity::find()
+                .filter(billing_payment_event::Column::BillingCustomerId.eq(billing_customer_id))
+                .order_by_asc(billing_payment_event::Column::OccurredAt)
+                .order_by_asc(billing_payment_event::Column::Id)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+}
@@ -0,0 +1,88 @@
+use super::*;
+
+#[derive(Debug)]
+pub struct CreateBillingPriceChangeNoticeParams {
+    pub user_id: UserId,
+    pub billing_subscription_id: BillingSubscriptionId,
+    pub stripe_price_id: String,
+    pub amount_in_cents: i64,
+    pub effective_at: DateTime,
+}
+
+impl Database {
+    /// Records that a subscriber was given notice of a price change, and notifies them of it.
+    ///
+    /// Returns `None` if the subscriber was already given notice of the change to this price.
+    pub async fn create_billing_price_change_notice(
+        &self,
+        params: &CreateBillingPriceChangeNoticeParams,
+    ) -> Result<Option<(billing_price_change_notice::Model, NotificationBatch)>> {
+        self.transaction(|tx| async move {
+            let existing_notice = billing_price_change_notice::Entity::find()
+                .filter(
+                    billing_price_change_notice::Column::BillingSubscriptionId
+                        .eq(params.billing_subscription_id)
+                        .and(
+                            billing_price_change_notice::Column::StripePriceId
+                                .eq(params.stripe_price_id.clone()),
+                        ),
+                )
+                .one(&*tx)
+                .await?;
+            if existing_notice.is_some() {
+                return Ok(None);
+            }
+
+            let notice = billing_price_change_notice::Entity::insert(
+                billing_price_change_notice::ActiveModel {
+                    billing_subscription_id: ActiveValue::set(params.billing_subscription_id),
+                    stripe_price_id: ActiveValue::set(params.stripe_price_id.clone()),
+                    amount_in_cents: ActiveValue::set(params.amount_in_cents),
+                    effective_at: ActiveValue::set(params.effective_at),
+                    ..Default::default()
+                },
+            )
+            .exec_with_returning(&*tx)
+            .await?;
+
+            let notifications = self
+                .create_notification(
+                    params.user_id,
+                    rpc::Notification::BillingPriceChange {
+                        billing_subscription_id: params.billing_subscription_id.to_proto(),
+                        new_amount_in_cents: params.amount_in_cents,
+                        effective_at: params.effective_at.and_utc().timestamp(),
+                    },
+                    false,
+                    &tx,
+                )
+                .await?
+                .into_iter()
+                .collect();
+
+            Ok(Some((notice, notifications)))
+        })
+        .await
+    }
+
+    /// Returns the notice that was given for moving the billing subscription to the given price, if any.
+    pub async fn get_billing_price_change_notice(
+        &self,
+        billing_subscription_id: BillingSubscriptionId,
+        stripe_price_id: &str,
+    ) -> Result<Option<billing_price_change_notice::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_price_change_notice::Entity::find()
+                .filter(
+                    billing_price_change_notice::Column::BillingSubscriptionId
+                        .eq(billing_subscription_id)
+                        .and(
+                            billing_price_change_notice::Column::StripePriceId.eq(stripe_price_id),
+                        ),
+                )
+                .one(&*tx)
+                .await?)
+        })
+        .await
+    }
+}
@@ -0,0 +1,46 @@
+use super::*;
+
+#[derive(Debug)]
+pub struct CreateBillingSubscriptionMemberParams {
+    pub billing_subscription_id: BillingSubscriptionId,
+    pub user_id: UserId,
+}
+
+impl Database {
+    /// Adds a user to a team's billing subscription.
+    pub async fn create_billing_subscription_member(
+        &self,
+        params: &CreateBillingSubscriptionMemberParams,
+    ) -> Result<billing_subscription_member::Model> {
+        self.transaction(|tx| async move {
+            Ok(billing_subscription_member::Entity::insert(
+                billing_subscription_member::ActiveModel {
+                    billing_subscription_id: ActiveValue::set(params.billing_subscription_id),
+                    user_id: ActiveValue::set(params.user_id),
+                    ..Default::default()
+                },
+            )
+            .exec_with_returning(&*tx)
+            .await?)
+        })
+        .await
+    }
+
+    /// Returns the members of the specified billing subscription, in the order they were added.
+    pub async fn get_billing_subscription_members(
+        &self,
+        billing_subscription_id: BillingSubscriptionId,
+    ) -> Result<Vec<billing_subscription_member::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_subscription_member::Entity::find()
+                .filter(
+                    billing_subscription_member::Column::BillingSubscriptionId
+                        .eq(billing_subscription_id),
+                )
+                .order_by_asc(billing_subscription_member::Column::Id)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+}
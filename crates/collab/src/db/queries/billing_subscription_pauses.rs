@@ -0,0 +1,93 @@
+use super::*;
+
+#[derive(Debug)]
+pub struct CreateBillingSubscriptionPauseParams {
+    pub billing_subscription_id: BillingSubscriptionId,
+    pub paused_at: DateTime,
+    pub resumes_at: Option<DateTime>,
+}
+
+impl Database {
+    /// Records that a billing subscription was paused.
+    pub async fn create_billing_subscription_pause(
+        &self,
+        params: &CreateBillingSubscriptionPauseParams,
+    ) -> Result<billing_subscription_pause::Model> {
+        self.transaction(|tx| async move {
+            Ok(billing_subscription_pause::Entity::insert(
+                billing_subscription_pause::ActiveModel {
+                    billing_subscription_id: ActiveValue::set(params.billing_subscription_id),
+                    paused_at: ActiveValue::set(params.paused_at),
+                    resumes_at: ActiveValue::set(params.resumes_at),
+                    ..Default::default()
+                },
+            )
+            .exec_with_returning(&*tx)
+            .await?)
+        })
+        .await
+    }
+
+    /// Returns the pauses of the specified billing subscriptions since the given time, oldest first.
+    pub async fn get_billing_subscription_pauses_since(
+        &self,
+        billing_subscription_ids: &[BillingSubscriptionId],
+        paused_at_or_after: DateTime,
+    ) -> Result<Vec<billing_subscription_pause::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_subscription_pause::Entity::find()
+                .filter(
+                    billing_subscription_pause::Column::BillingSubscriptionId
+                        .is_in(billing_subscription_ids.iter().copied()),
+                )
+                .filter(billing_subscription_pause::Column::PausedAt.gte(paused_at_or_after))
+                .order_by_asc(billing_subscription_pause::Column::PausedAt)
+                .order_by_asc(billing_subscription_pause::Column::Id)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+
+    /// Returns the most recent pause of the specified billing subscription.
+    pub async fn get_latest_billing_subscription_pause(
+        &self,
+        billing_subscription_id: BillingSubscriptionId,
+    ) -> Result<Option<billing_subscription_pause::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_subscription_pause::Entity::find()
+                .filter(
+                    billing_subscription_pause::Column::BillingSubscriptionId
+                        .eq(billing_subscription_id),
+                )
+                .order_by_desc(billing_subscription_pause::Column::PausedAt)
+                .order_by_desc(billing_subscription_pause::Column::Id)
+                .one(&*tx)
+                .await?)
+        })
+        .await
+    }
+
+    /// Records when the specified pause ends on its own.
+    ///
+    /// This is `None` once the pause no longer ends on its own, either because it was extended until the subscription
+    /// is resumed, or because the subscription was resumed early.
+    pub async fn set_billing_subscription_pause_resumes_at(
+        &self,
+        id: BillingSubscriptionPauseId,
+        resumes_at: Option<DateTime>,
+    ) -> Result<()> {
+        self.transaction(|tx| async move {
+            billing_subscription_pause::Entity::update(billing_subscription_pause::ActiveModel {
+                id: ActiveValue::unchanged(id),
+                resumes_at: ActiveValue::set(resumes_at),
+                ..Default::default()
+            })
+            .exec(&*tx)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+}
@@ -10,23 +10,43 @@ use super::*;
 pub struct CreateBillingSubscriptionParams {
     pub billing_customer_id: BillingCustomerId,
     pub kind: Option<SubscriptionKind>,
+    pub unclassified: bool,
     pub stripe_subscription_id: String,
     pub stripe_subscription_status: StripeSubscriptionStatus,
     pub stripe_cancellation_reason: Option<StripeCancellationReason>,
     pub stripe_current_period_start: Option<i64>,
     pub stripe_current_period_end: Option<i64>,
+    pub seats: i32,
+    pub monthly_recurring_revenue_in_cents: Option<i64>,
+    pub ended_at: Option<DateTime>,
+}
+
+/// The filters and page to apply when listing a user's billing subscriptions.
+#[derive(Debug, Default)]
+pub struct GetBillingSubscriptionsParams {
+    pub status: Option<StripeSubscriptionStatus>,
+    /// Whether to only include subscriptions that are active (or trialing).
+    pub active_only: bool,
+    pub limit: Option<u64>,
+    pub offset: Option<u64>,
 }
 
 #[derive(Debug, Default)]
 pub struct UpdateBillingSubscriptionParams {
     pub billing_customer_id: ActiveValue<BillingCustomerId>,
     pub kind: ActiveValue<Option<SubscriptionKind>>,
+    pub unclassified: ActiveValue<bool>,
     pub stripe_subscription_id: ActiveValue<String>,
     pub stripe_subscription_status: ActiveValue<StripeSubscriptionStatus>,
     pub stripe_cancel_at: ActiveValue<Option<DateTime>>,
+    pub stripe_cancel_at_period_end: ActiveValue<bool>,
     pub stripe_cancellation_reason: ActiveValue<Option<StripeCancellationReason>>,
     pub stripe_current_period_start: ActiveValue<Option<i64>>,
     pub stripe_current_period_end: ActiveValue<Option<i64>>,
+    pub past_due_since: ActiveValue<Option<DateTime>>,
+    pub seats: ActiveValue<i32>,
+    pub monthly_recurring_revenue_in_cents: ActiveValue<Option<i64>>,
+    pub ended_at: ActiveValue<Option<DateTime>>,
 }
 
 impl Database {
@@ -39,11 +59,17 @@ impl Database {
             let id = billing_subscription::Entity::insert(billing_subscription::ActiveModel {
                 billing_customer_id: ActiveValue::set(params.billing_customer_id),
                 kind: ActiveValue::set(params.kind),
+                unclassified: ActiveValue::set(params.unclassified),
                 stripe_subscription_id: ActiveValue::set(params.stripe_subscription_id.clone()),
                 stripe_subscription_status: ActiveValue::set(params.stripe_subscription_status),
                 stripe_cancellation_reason: ActiveValue::set(params.stripe_cancellation_reason),
                 stripe_current_period_start: ActiveValue::set(params.stripe_current_period_start),
                 stripe_current_period_end: ActiveValue::set(params.stripe_current_period_end),
+                seats: ActiveValue::set(params.seats),
+                monthly_recurring_revenue_in_cents: ActiveValue::set(
+                    params.monthly_recurring_revenue_in_cents,
+                ),
+                ended_at: ActiveValue::set(params.ended_at),
                 ..Default::default()
             })
             .exec(&*tx)
@@ -69,12 +95,20 @@ impl Database {
                 id: ActiveValue::set(id),
                 billing_customer_id: params.billing_customer_id.clone(),
                 kind: params.kind.clone(),
+                unclassified: params.unclassified.clone(),
                 stripe_subscription_id: params.stripe_subscription_id.clone(),
                 stripe_subscription_status: params.stripe_subscription_status.clone(),
                 stripe_cancel_at: params.stripe_cancel_at.clone(),
+                stripe_cancel_at_period_end: params.stripe_cancel_at_period_end.clone(),
                 stripe_cancellation_reason: params.stripe_cancellation_reason.clone(),
                 stripe_current_period_start: params.stripe_current_period_start.clone(),
                 stripe_current_period_end: params.stripe_current_period_end.clone(),
+                past_due_since: params.past_due_since.clone(),
+                seats: params.seats.clone(),
+                monthly_recurring_revenue_in_cents: params
+                    .monthly_recurring_revenue_in_cents
+                    .clone(),
+                ended_at: params.ended_at.clone(),
                 created_at: ActiveValue::not_set(),
             })
             .exec(&*tx)
@@ -165,6 +199,44 @@ impl Database {
         .await
     }
 
+    /// Returns a page of the user's billing subscriptions that match the filters, oldest first, along with the total
+    /// number of subscriptions that match them.
+    pub async fn get_billing_subscriptions_page(
+        &self,
+        user_id: UserId,
+        params: &GetBillingSubscriptionsParams,
+    ) -> Result<(Vec<billing_subscription::Model>, u64)> {
+        self.transaction(|tx| async move {
+            let mut condition = Condition::all().add(billing_customer::Column::UserId.eq(user_id));
+            if let Some(status) = params.status {
+                condition = condition
+                    .add(billing_subscription::Column::StripeSubscriptionStatus.eq(status));
+            }
+            if params.active_only {
+                condition = condition.add(
+                    billing_subscription::Column::StripeSubscriptionStatus.is_in([
+                        StripeSubscriptionStatus::Active,
+                        StripeSubscriptionStatus::Trialing,
+                    ]),
+                );
+            }
+
+            let query = billing_subscription::Entity::find()
+                .inner_join(billing_customer::Entity)
+                .filter(condition);
+            let total = query.clone().count(&*tx).await?;
+            let subscriptions = query
+                .order_by_asc(billing_subscription::Column::Id)
+                .offset(params.offset)
+                .limit(params.limit)
+                .all(&*tx)
+                .await?;
+
+            Ok((subscriptions, total))
+        })
+        .await
+    }
+
     pub async fn get_active_billing_subscriptions(
         &self,
         user_ids: HashSet<UserId>,
@@ -256,6 +328,122 @@ impl Database {
         .await
     }
 
+    /// Returns the user's past due billing subscription, if it became past due at or after the given time.
+    pub async fn get_past_due_billing_subscription_since(
+        &self,
+        user_id: UserId,
+        past_due_since: DateTime,
+    ) -> Result<Option<billing_subscription::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_subscription::Entity::find()
+                .inner_join(billing_customer::Entity)
+                .filter(billing_customer::Column::UserId.eq(user_id))
+                .filter(
+                    billing_subscription::Column::StripeSubscriptionStatus
+                        .eq(StripeSubscriptionStatus::PastDue),
+                )
+                .filter(billing_subscription::Column::Kind.is_not_null())
+                .filter(billing_subscription::Column::PastDueSince.gte(past_due_since))
+                .one(&*tx)
+                .await?)
+        })
+        .await
+    }
+
+    /// Returns the Zed Pro and Zed Pro trial billing subscriptions that are still active, including those that are
+    /// past due.
+    pub async fn get_active_zed_pro_and_trial_billing_subscriptions(
+        &self,
+    ) -> Result<HashMap<UserId, (billing_customer::Model, billing_subscription::Model)>> {
+        self.transaction(|tx| async move {
+            let mut rows = billing_subscription::Entity::find()
+                .inner_join(billing_customer::Entity)
+                .select_also(billing_customer::Entity)
+                .filter(
+                    billing_subscription::Column::StripeSubscriptionStatus.is_in([
+                        StripeSubscriptionStatus::Active,
+                        StripeSubscriptionStatus::Trialing,
+                        StripeSubscriptionStatus::PastDue,
+                    ]),
+                )
+                .filter(
+                    billing_subscription::Column::Kind
+                        .is_in([SubscriptionKind::ZedPro, SubscriptionKind::ZedProTrial]),
+                )
+                .order_by_asc(billing_subscription::Column::Id)
+                .stream(&*tx)
+                .await?;
+
+            let mut subscriptions = HashMap::default();
+            while let Some(row) = rows.next().await {
+                if let (subscription, Some(customer)) = row? {
+                    subscriptions.insert(customer.user_id, (customer, subscription));
+                }
+            }
+            Ok(subscriptions)
+        })
+        .await
+    }
+
+    /// Returns the active billing subscriptions of the users that have more than one, oldest first.
+    ///
+    /// Each user should have at most one active billing subscription, but if we process the Stripe events out of
+    /// order (or a user manages to check out twice), they can end up with several.
+    pub async fn get_overlapping_billing_subscriptions(
+        &self,
+    ) -> Result<HashMap<UserId, Vec<billing_subscription::Model>>> {
+        self.transaction(|tx| async move {
+            let mut rows = billing_subscription::Entity::find()
+                .inner_join(billing_customer::Entity)
+                .select_also(billing_customer::Entity)
+                .filter(
+                    billing_subscription::Column::StripeSubscriptionStatus.is_in([
+                        StripeSubscriptionStatus::Active,
+                        StripeSubscriptionStatus::Trialing,
+                    ]),
+                )
+                .filter(billing_subscription::Column::Kind.is_not_null())
+                .order_by_asc(billing_subscription::Column::Id)
+                .stream(&*tx)
+                .await?;
+
+            let mut subscriptions_by_user = HashMap::<UserId, Vec<_>>::default();
+            while let Some(row) = rows.next().await {
+                if let (subscription, Some(customer)) = row? {
+                    subscriptions_by_user
+                        .entry(customer.user_id)
+                        .or_default()
+                        .push(subscription);
+                }
+            }
+            subscriptions_by_user.retain(|_, subscriptions| subscriptions.len() > 1);
+
+            Ok(subscriptions_by_user)
+        })
+        .await
+    }
+
+    /// Returns the billing subscriptions that we couldn't determine the kind of, along with the IDs of their users.
+    pub async fn get_unclassified_billing_subscriptions(
+        &self,
+    ) -> Result<Vec<(UserId, billing_subscription::Model)>> {
+        self.transaction(|tx| async move {
+            let rows = billing_subscription::Entity::find()
+                .inner_join(billing_customer::Entity)
+                .select_also(billing_customer::Entity)
+                .filter(billing_subscription::Column::Unclassified.eq(true))
+                .order_by_asc(billing_subscription::Column::Id)
+                .all(&*tx)
+                .await?;
+
+            Ok(rows
+                .into_iter()
+                .filter_map(|(subscription, customer)| Some((customer?.user_id, subscription)))
+                .collect())
+        })
+        .await
+    }
+
     /// Returns whether the user has an active billing subscription.
     pub async fn has_active_billing_subscription(&self, user_id: UserId) -> Result<bool> {
         Ok(self.count_active_billing_subscriptions(user_id).await? > 0)
@@ -281,4 +469,60 @@ impl Database {
         })
         .await
     }
+
+    /// Returns the number of active (or trialing) billing subscriptions of each kind.
+    pub async fn count_active_billing_subscriptions_by_kind(
+        &self,
+    ) -> Result<HashMap<SubscriptionKind, usize>> {
+        self.transaction(|tx| async move {
+            let rows = billing_subscription::Entity::find()
+                .select_only()
+                .column(billing_subscription::Column::Kind)
+                .column_as(Expr::col(billing_subscription::Column::Id).count(), "count")
+                .filter(
+                    billing_subscription::Column::StripeSubscriptionStatus
+                        .eq(StripeSubscriptionStatus::Active)
+                        .or(billing_subscription::Column::StripeSubscriptionStatus
+                            .eq(StripeSubscriptionStatus::Trialing)),
+                )
+                .filter(billing_subscription::Column::Kind.is_not_null())
+                .group_by(billing_subscription::Column::Kind)
+                .into_tuple::<(SubscriptionKind, i64)>()
+                .all(&*tx)
+                .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|(kind, count)| (kind, count as usize))
+                .collect())
+        })
+        .await
+    }
+
+    /// Returns the billing subscriptions that were being paid for at the given time.
+    ///
+    /// These are the subscriptions that had been created by then and are still paying, or have since ended. Trials
+    /// are not included, as they haven't been paid for yet.
+    pub async fn get_paying_billing_subscriptions(
+        &self,
+        as_of: DateTime,
+    ) -> Result<Vec<billing_subscription::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_subscription::Entity::find()
+                .filter(
+                    billing_subscription::Column::StripeSubscriptionStatus
+                        .eq(StripeSubscriptionStatus::Active)
+                        .or(billing_subscription::Column::StripeSubscriptionStatus
+                            .eq(StripeSubscriptionStatus::PastDue))
+                        .or(billing_subscription::Column::EndedAt.gt(as_of)),
+                )
+                .filter(billing_subscription::Column::Kind.is_not_null())
+                .filter(billing_subscription::Column::Kind.ne(SubscriptionKind::ZedProTrial))
+                .filter(billing_subscription::Column::CreatedAt.lte(as_of))
+                .order_by_asc(billing_subscription::Column::Id)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
 }
@@ -0,0 +1,56 @@
+use super::*;
+
+#[derive(Debug)]
+pub struct CreateBillingUsageAdjustmentParams {
+    pub billing_customer_id: BillingCustomerId,
+    pub model: String,
+    pub mode: String,
+    pub requests: i32,
+    pub amount_in_cents: i64,
+    pub reason: String,
+    pub stripe_balance_transaction_id: String,
+}
+
+impl Database {
+    /// Records a new billing usage adjustment.
+    pub async fn create_billing_usage_adjustment(
+        &self,
+        params: &CreateBillingUsageAdjustmentParams,
+    ) -> Result<billing_usage_adjustment::Model> {
+        self.transaction(|tx| async move {
+            let adjustment =
+                billing_usage_adjustment::Entity::insert(billing_usage_adjustment::ActiveModel {
+                    billing_customer_id: ActiveValue::set(params.billing_customer_id),
+                    model: ActiveValue::set(params.model.clone()),
+                    mode: ActiveValue::set(params.mode.clone()),
+                    requests: ActiveValue::set(params.requests),
+                    amount_in_cents: ActiveValue::set(params.amount_in_cents),
+                    reason: ActiveValue::set(params.reason.clone()),
+                    stripe_balance_transaction_id: ActiveValue::set(
+                        params.stripe_balance_transaction_id.clone(),
+                    ),
+                    ..Default::default()
+                })
+                .exec_with_returning(&*tx)
+                .await?;
+
+            Ok(adjustment)
+        })
+        .await
+    }
+
+    /// Returns the billing usage adjustments for the specified billing customer.
+    pub async fn get_billing_usage_adjustments(
+        &self,
+        billing_customer_id: BillingCustomerId,
+    ) -> Result<Vec<billing_usage_adjustment::Model>> {
+        self.transaction(|tx| async move {
+            Ok(billing_usage_adjustment::Entity::find()
+                .filter(billing_usage_adjustment::Column::BillingCustomerId.eq(billing_customer_id))
+                .order_by_asc(billing_usage_adjustment::Column::Id)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+}
@@ -0,0 +1,70 @@
+use super::*;
+
+#[derive(Debug)]
+pub struct CreateCustomPriceOverrideParams {
+    pub billing_customer_id: BillingCustomerId,
+    pub meter_event_name: String,
+    pub stripe_price_id: String,
+}
+
+impl Database {
+    /// Creates a custom price override for the specified billing customer.
+    pub async fn create_custom_price_override(
+        &self,
+        params: &CreateCustomPriceOverrideParams,
+    ) -> Result<custom_price_override::Model> {
+        self.transaction(|tx| async move {
+            let price_override =
+                custom_price_override::Entity::insert(custom_price_override::ActiveModel {
+                    billing_customer_id: ActiveValue::set(params.billing_customer_id),
+                    meter_event_name: ActiveValue::set(params.meter_event_name.clone()),
+                    stripe_price_id: ActiveValue::set(params.stripe_price_id.clone()),
+                    ..Default::default()
+                })
+                .exec_with_returning(&*tx)
+                .await?;
+
+            Ok(price_override)
+        })
+        .await
+    }
+
+    /// Returns the custom price override for the specified billing customer and meter event, if there is one.
+    pub async fn get_custom_price_override(
+        &self,
+        billing_customer_id: BillingCustomerId,
+        meter_event_name: &str,
+    ) -> Result<Option<custom_price_override::Model>> {
+        self.transaction(|tx| async move {
+            Ok(custom_price_override::Entity::find()
+                .filter(custom_price_override::Column::BillingCustomerId.eq(billing_customer_id))
+                .filter(custom_price_override::Column::MeterEventName.eq(meter_event_name))
+                .one(&*tx)
+                .await?)
+        })
+        .await
+    }
+
+    /// Returns all of the custom price overrides, grouped by billing customer.
+    pub async fn get_custom_price_overrides_by_billing_customer(
+        &self,
+    ) -> Result<HashMap<BillingCustomerId, Vec<custom_price_override::Model>>> {
+        self.transaction(|tx| async move {
+            let price_overrides = custom_price_override::Entity::find()
+                .order_by_asc(custom_price_override::Column::Id)
+                .all(&*tx)
+                .await?;
+
+            let mut price_overrides_by_billing_customer = HashMap::<_, Vec<_>>::default();
+            for price_override in price_overrides {
+                price_overrides_by_billing_customer
+                    .entry(price_override.billing_customer_id)
+                    .or_default()
+                    .push(price_override);
+            }
+
+            Ok(price_overrides_by_billing_customer)
+        })
+        .await
+    }
+}
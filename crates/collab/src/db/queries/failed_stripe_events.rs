@@ -0,0 +1,89 @@
+use super::*;
+use sea_orm::sea_query::Query;
+
+#[derive(Debug)]
+pub struct RecordFailedStripeEventParams {
+    pub stripe_event_id: String,
+    pub stripe_event_type: String,
+    pub stripe_event_created_timestamp: i64,
+    pub error: String,
+}
+
+impl Database {
+    /// Records a failed attempt to process a Stripe event.
+    pub async fn record_failed_stripe_event(
+        &self,
+        params: &RecordFailedStripeEventParams,
+    ) -> Result<failed_stripe_event::Model> {
+        self.transaction(|tx| async move {
+            let now = chrono::Utc::now().naive_utc();
+
+            let existing_event =
+                failed_stripe_event::Entity::find_by_id(params.stripe_event_id.clone())
+                    .one(&*tx)
+                    .await?;
+
+            let event = if let Some(existing_event) = existing_event {
+                failed_stripe_event::Entity::update(failed_stripe_event::ActiveModel {
+                    stripe_event_id: ActiveValue::unchanged(existing_event.stripe_event_id),
+                    attempts: ActiveValue::set(existing_event.attempts + 1),
+                    last_error: ActiveValue::set(params.error.clone()),
+                    last_failed_at: ActiveValue::set(now),
+                    ..Default::default()
+                })
+                .exec(&*tx)
+                .await?
+            } else {
+                failed_stripe_event::Entity::insert(failed_stripe_event::ActiveModel {
+                    stripe_event_id: ActiveValue::set(params.stripe_event_id.clone()),
+                    stripe_event_type: ActiveValue::set(params.stripe_event_type.clone()),
+                    stripe_event_created_timestamp: ActiveValue::set(
+                        params.stripe_event_created_timestamp,
+                    ),
+                    attempts: ActiveValue::set(1),
+                    last_error: ActiveValue::set(params.error.clone()),
+                    first_failed_at: ActiveValue::set(now),
+                    last_failed_at: ActiveValue::set(now),
+                })
+                .exec_with_returning(&*tx)
+                .await?
+            };
+
+            Ok(event)
+        })
+        .await
+    }
+
+    /// Returns the Stripe events that have failed to process and haven't been processed since, oldest first.
+    pub async fn get_stuck_stripe_events(&self) -> Result<Vec<failed_stripe_event::Model>> {
+        self.transaction(|tx| async move {
+            Ok(failed_stripe_event::Entity::find()
+                .filter(failed_stripe_event::Column::Attempts.gte(1))
+                .filter(
+                    failed_stripe_event::Column::StripeEventId.not_in_subquery(
+                        Query::select()
+                            .column(processed_stripe_event::Column::StripeEventId)
+                            .from(processed_stripe_event::Entity)
+                            .to_owned(),
+                    ),
+                )
+                .order_by_asc(failed_stripe_event::Column::StripeEventCreatedTimestamp)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+
+    /// Returns the failed Stripe event with the specified event ID.
+    pub async fn get_failed_stripe_event_by_event_id(
+        &self,
+        event_id: &str,
+    ) -> Result<Option<failed_stripe_event::Model>> {
+        self.transaction(|tx| async move {
+            Ok(failed_stripe_event::Entity::find_by_id(event_id)
+                .one(&*tx)
+                .await?)
+        })
+        .await
+    }
+}
@@ -0,0 +1,78 @@
+use super::*;
+
+impl Database {
+    /// Records that the Stripe customer has an email address that doesn't belong to any user yet.
+    pub async fn record_pending_billing_customer_link(
+        &self,
+        stripe_customer_id: &str,
+        email: &str,
+    ) -> Result<pending_billing_customer_link::Model> {
+        self.transaction(|tx| async move {
+            let now = chrono::Utc::now().naive_utc();
+
+            let existing_link =
+                pending_billing_customer_link::Entity::find_by_id(stripe_customer_id)
+                    .one(&*tx)
+                    .await?;
+
+            let link = if let Some(existing_link) = existing_link {
+                pending_billing_customer_link::Entity::update(
+                    pending_billing_customer_link::ActiveModel {
+                        stripe_customer_id: ActiveValue::unchanged(
+                            existing_link.stripe_customer_id,
+                        ),
+                        email: ActiveValue::set(email.to_string()),
+                        last_seen_at: ActiveValue::set(now),
+                        ..Default::default()
+                    },
+                )
+                .exec(&*tx)
+                .await?
+            } else {
+                pending_billing_customer_link::Entity::insert(
+                    pending_billing_customer_link::ActiveModel {
+                        stripe_customer_id: ActiveValue::set(stripe_customer_id.to_string()),
+                        email: ActiveValue::set(email.to_string()),
+                        first_seen_at: ActiveValue::set(now),
+                        last_seen_at: ActiveValue::set(now),
+                    },
+                )
+                .exec_with_returning(&*tx)
+                .await?
+            };
+
+            Ok(link)
+        })
+        .await
+    }
+
+    /// Returns the pending link for the Stripe customer, if there is one.
+    pub async fn get_pending_billing_customer_link(
+        &self,
+        stripe_customer_id: &str,
+    ) -> Result<Option<pending_billing_customer_link::Model>> {
+        self.transaction(|tx| async move {
+            Ok(
+                pending_billing_customer_link::Entity::find_by_id(stripe_customer_id)
+                    .one(&*tx)
+                    .await?,
+            )
+        })
+        .await
+    }
+
+    /// Deletes the pending link for the Stripe customer, once it has been linked to a user.
+    pub async fn delete_pending_billing_customer_link(
+        &self,
+        stripe_customer_id: &str,
+    ) -> Result<()> {
+        self.transaction(|tx| async move {
+            pending_billing_customer_link::Entity::delete_by_id(stripe_customer_id)
+                .exec(&*tx)
+                .await?;
+
+            Ok(())
+        })
+        .await
+    }
+}
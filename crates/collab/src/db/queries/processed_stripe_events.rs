@@ -44,17 +44,51 @@ impl Database {
     }
 
     /// Returns the processed Stripe events with the specified event IDs.
+    ///
+    /// The event IDs are looked up in chunks, so that any number of them stays within the database's limit on the
+    /// number of query parameters.
     pub async fn get_processed_stripe_events_by_event_ids(
         &self,
         event_ids: &[&str],
     ) -> Result<Vec<processed_stripe_event::Model>> {
+        const MAX_EVENT_IDS_PER_QUERY: usize = 1_000;
+
+        self.transaction(|tx| async move {
+            let mut events = Vec::new();
+            for event_ids in event_ids.chunks(MAX_EVENT_IDS_PER_QUERY) {
+                events.extend(
+                    processed_stripe_event::Entity::find()
+                        .filter(
+                            processed_stripe_event::Column::StripeEventId
+                                .is_in(event_ids.iter().copied()),
+                        )
+                        .all(&*tx)
+                        .await?,
+                );
+            }
+
+            Ok(events)
+        })
+        .await
+    }
+
+    /// Deletes the processed Stripe events that were created before the specified timestamp.
+    ///
+    /// Returns the number of events that were deleted.
+    pub async fn delete_processed_stripe_events_created_before(
+        &self,
+        stripe_event_created_timestamp: i64,
+    ) -> Result<u64> {
         self.transaction(|tx| async move {
-            Ok(processed_stripe_event::Entity::find()
+            let result = processed_stripe_event::Entity::delete_many()
                 .filter(
-                    processed_stripe_event::Column::StripeEventId.is_in(event_ids.iter().copied()),
+                    processed_stripe_event::Column::StripeEventCreatedTimestamp
+                        .lt(stripe_event_created_timestamp),
                 )
-                .all(&*tx)
-                .await?)
+                .exec(&*tx)
+                .await?;
+
+            Ok(result.rows_affected)
         })
         .await
     }
@@ -0,0 +1,55 @@
+use super::*;
+
+/// The ID of the one and only Stripe event cursor.
+const STRIPE_EVENT_CURSOR_ID: i32 = 1;
+
+#[derive(Debug)]
+pub struct UpdateStripeEventCursorParams {
+    pub stripe_event_id: String,
+    pub stripe_event_created_timestamp: i64,
+}
+
+impl Database {
+    /// Returns the Stripe event cursor, if we've recorded one.
+    pub async fn get_stripe_event_cursor(&self) -> Result<Option<stripe_event_cursor::Model>> {
+        self.transaction(|tx| async move {
+            Ok(
+                stripe_event_cursor::Entity::find_by_id(STRIPE_EVENT_CURSOR_ID)
+                    .one(&*tx)
+                    .await?,
+            )
+        })
+        .await
+    }
+
+    /// Moves the Stripe event cursor to the specified event.
+    pub async fn update_stripe_event_cursor(
+        &self,
+        params: &UpdateStripeEventCursorParams,
+    ) -> Result<()> {
+        self.transaction(|tx| async move {
+            stripe_event_cursor::Entity::insert(stripe_event_cursor::ActiveModel {
+                id: ActiveValue::set(STRIPE_EVENT_CURSOR_ID),
+                stripe_event_id: ActiveValue::set(params.stripe_event_id.clone()),
+                stripe_event_created_timestamp: ActiveValue::set(
+                    params.stripe_event_created_timestamp,
+                ),
+                updated_at: ActiveValue::set(chrono::Utc::now().naive_utc()),
+            })
+            .on_conflict(
+                OnConflict::column(stripe_event_cursor::Column::Id)
+                    .update_columns([
+                        stripe_event_cursor::Column::StripeEventId,
+                        stripe_event_cursor::Column::StripeEventCreatedTimestamp,
+                        stripe_event_cursor::Column::UpdatedAt,
+                    ])
+                    .to_owned(),
+            )
+            .exec_without_returning(&*tx)
+            .await?;
+
+            Ok(())
+        })
+        .await
+    }
+}
@@ -1,7 +1,13 @@
 pub mod access_token;
 pub mod billing_customer;
+pub mod billing_meter_report;
+pub mod billing_payment_event;
 pub mod billing_preference;
+pub mod billing_price_change_notice;
 pub mod billing_subscription;
+pub mod billing_subscription_member;
+pub mod billing_subscription_pause;
+pub mod billing_usage_adjustment;
 pub mod buffer;
 pub mod buffer_operation;
 pub mod buffer_snapshot;
@@ -13,9 +19,11 @@ pub mod channel_message;
 pub mod channel_message_mention;
 pub mod contact;
 pub mod contributor;
+pub mod custom_price_override;
 pub mod embedding;
 pub mod extension;
 pub mod extension_version;
+pub mod failed_stripe_event;
 pub mod feature_flag;
 pub mod follower;
 pub mod language_server;
@@ -23,6 +31,7 @@ pub mod notification;
 pub mod notification_kind;
 pub mod observed_buffer_edits;
 pub mod observed_channel_messages;
+pub mod pending_billing_customer_link;
 pub mod processed_stripe_event;
 pub mod project;
 pub mod project_collaborator;
@@ -32,6 +41,7 @@ pub mod room;
 pub mod room_participant;
 pub mod server;
 pub mod signup;
+pub mod stripe_event_cursor;
 pub mod user;
 pub mod user_feature;
 pub mod worktree;
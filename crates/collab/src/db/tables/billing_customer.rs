@@ -11,6 +11,24 @@ pub struct Model {
     pub stripe_customer_id: String,
     pub has_overdue_invoices: bool,
     pub trial_started_at: Option<DateTime>,
+    /// Whether we've stopped billing the customer for their usage, e.g., while we investigate a dispute.
+    pub billing_suspended: bool,
+    /// When we last reminded the customer that their payment method is about to expire.
+    pub payment_method_expiry_reminded_at: Option<DateTime>,
+    /// When we last offered the customer a discount to win them back after they canceled Zed Pro.
+    pub winback_offered_at: Option<DateTime>,
+    /// The latest email address that Stripe has for the customer.
+    pub stripe_email: Option<String>,
+    /// Whether the customer's email address in Stripe belongs to a different user than the one they're billed for.
+    pub stripe_email_user_mismatch: bool,
+    /// The type of the tax ID the customer gave us (e.g., `eu_vat`), as Stripe names it.
+    pub tax_id_type: Option<String>,
+    /// The customer's tax ID, e.g., their VAT number.
+    pub tax_id: Option<String>,
+    /// The currency that the customer is billed in, as a lowercase ISO code (e.g., `usd`).
+    pub currency: String,
+    /// When the customer, whose paid subscription has ended, is due to be subscribed to Zed Free.
+    pub free_downgrade_at: Option<DateTime>,
     pub created_at: DateTime,
 }
 
@@ -0,0 +1,36 @@
+use crate::db::{BillingCustomerId, BillingMeterReportId};
+use sea_orm::entity::prelude::*;
+
+/// A usage meter event that we reported to Stripe for a customer.
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "billing_meter_reports")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: BillingMeterReportId,
+    pub billing_customer_id: BillingCustomerId,
+    pub meter_event_name: String,
+    pub value: i32,
+    /// The identifier of the meter event, which Stripe uses to deduplicate it.
+    pub identifier: String,
+    /// The timestamp of the meter event, which Stripe attributes the usage to.
+    pub reported_at: DateTime,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::billing_customer::Entity",
+        from = "Column::BillingCustomerId",
+        to = "super::billing_customer::Column::Id"
+    )]
+    BillingCustomer,
+}
+
+impl Related<super::billing_customer::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::BillingCustomer.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,53 @@
+use crate::db::{BillingCustomerId, BillingPaymentEventId};
+use sea_orm::entity::prelude::*;
+use serde::Serialize;
+
+/// An attempt to pay one of a customer's invoices.
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "billing_payment_events")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: BillingPaymentEventId,
+    pub billing_customer_id: BillingCustomerId,
+    pub kind: PaymentEventKind,
+    /// The ID of the Stripe event that we recorded this from, so that we only record each event once.
+    pub stripe_event_id: String,
+    pub stripe_invoice_id: String,
+    /// The amount that was paid, or that failed to be paid.
+    pub amount_in_cents: i64,
+    /// When the payment succeeded or failed, according to Stripe.
+    pub occurred_at: DateTime,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::billing_customer::Entity",
+        from = "Column::BillingCustomerId",
+        to = "super::billing_customer::Column::Id"
+    )]
+    BillingCustomer,
+}
+
+impl Related<super::billing_customer::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::BillingCustomer.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
+
+/// Whether an invoice payment succeeded or failed.
+#[derive(
+    Eq, PartialEq, Copy, Clone, Debug, EnumIter, DeriveActiveEnum, Default, Hash, Serialize,
+)]
+#[sea_orm(rs_type = "String", db_type = "String(StringLen::None)")]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentEventKind {
+    #[default]
+    #[sea_orm(string_value = "failed")]
+    Failed,
+    #[sea_orm(string_value = "succeeded")]
+    Succeeded,
+}
@@ -0,0 +1,36 @@
+use crate::db::{BillingPriceChangeNoticeId, BillingSubscriptionId};
+use sea_orm::entity::prelude::*;
+
+/// A notice that we gave a subscriber ahead of moving their subscription to a new price.
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "billing_price_change_notices")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: BillingPriceChangeNoticeId,
+    pub billing_subscription_id: BillingSubscriptionId,
+    /// The ID of the Stripe price that the subscription is moving to.
+    pub stripe_price_id: String,
+    /// The new amount, in cents.
+    pub amount_in_cents: i64,
+    /// When the new price takes effect.
+    pub effective_at: DateTime,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::billing_subscription::Entity",
+        from = "Column::BillingSubscriptionId",
+        to = "super::billing_subscription::Column::Id"
+    )]
+    BillingSubscription,
+}
+
+impl Related<super::billing_subscription::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::BillingSubscription.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -2,7 +2,7 @@ use crate::db::{BillingCustomerId, BillingSubscriptionId};
 use crate::stripe_client;
 use chrono::{Datelike as _, NaiveDate, Utc};
 use sea_orm::entity::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 /// A billing subscription.
 #[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel)]
@@ -12,12 +12,27 @@ pub struct Model {
     pub id: BillingSubscriptionId,
     pub billing_customer_id: BillingCustomerId,
     pub kind: Option<SubscriptionKind>,
+    /// Whether we couldn't determine the kind of the subscription from its Stripe prices.
+    ///
+    /// When this is set, `kind` is the plan configured by `unclassified_subscription_plan`.
+    pub unclassified: bool,
     pub stripe_subscription_id: String,
     pub stripe_subscription_status: StripeSubscriptionStatus,
     pub stripe_cancel_at: Option<DateTime>,
+    pub stripe_cancel_at_period_end: bool,
     pub stripe_cancellation_reason: Option<StripeCancellationReason>,
     pub stripe_current_period_start: Option<i64>,
     pub stripe_current_period_end: Option<i64>,
+    /// When the subscription became past due, if it still is.
+    pub past_due_since: Option<DateTime>,
+    /// The number of seats on the subscription, taken from the quantity of its plan.
+    pub seats: i32,
+    /// What the subscription contributes to monthly recurring revenue, in cents, as of when we last synced it.
+    ///
+    /// This is its plan's price for every seat, after any discount, normalized to a month.
+    pub monthly_recurring_revenue_in_cents: Option<i64>,
+    /// When the subscription was canceled, if it has been.
+    pub ended_at: Option<DateTime>,
     pub created_at: DateTime,
 }
 
@@ -32,6 +47,15 @@ impl Model {
         chrono::DateTime::from_timestamp(period_end, 0)
     }
 
+    /// Returns when the trial ends, if this is a Zed Pro trial.
+    pub fn trial_end_at(&self) -> Option<DateTimeUtc> {
+        if self.kind != Some(SubscriptionKind::ZedProTrial) {
+            return None;
+        }
+
+        self.current_period_end_at()
+    }
+
     pub fn current_period(
         subscription: Option<Self>,
         is_staff: bool,
@@ -105,11 +129,31 @@ impl From<SubscriptionKind> for zed_llm_client::Plan {
     }
 }
 
+impl From<zed_llm_client::Plan> for SubscriptionKind {
+    fn from(value: zed_llm_client::Plan) -> Self {
+        match value {
+            zed_llm_client::Plan::ZedPro => Self::ZedPro,
+            zed_llm_client::Plan::ZedProTrial => Self::ZedProTrial,
+            zed_llm_client::Plan::ZedFree => Self::ZedFree,
+        }
+    }
+}
+
 /// The status of a Stripe subscription.
 ///
 /// [Stripe docs](https://docs.stripe.com/api/subscriptions/object#subscription_object-status)
 #[derive(
-    Eq, PartialEq, Copy, Clone, Debug, EnumIter, DeriveActiveEnum, Default, Hash, Serialize,
+    Eq,
+    PartialEq,
+    Copy,
+    Clone,
+    Debug,
+    EnumIter,
+    DeriveActiveEnum,
+    Default,
+    Hash,
+    Serialize,
+    Deserialize,
 )]
 #[sea_orm(rs_type = "String", db_type = "String(StringLen::None)")]
 #[serde(rename_all = "snake_case")]
@@ -0,0 +1,43 @@
+use crate::db::{BillingSubscriptionId, BillingSubscriptionMemberId, UserId};
+use sea_orm::entity::prelude::*;
+
+/// A user who is covered by a team's billing subscription, besides the team's billing owner.
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "billing_subscription_members")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: BillingSubscriptionMemberId,
+    pub billing_subscription_id: BillingSubscriptionId,
+    pub user_id: UserId,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::billing_subscription::Entity",
+        from = "Column::BillingSubscriptionId",
+        to = "super::billing_subscription::Column::Id"
+    )]
+    BillingSubscription,
+    #[sea_orm(
+        belongs_to = "super::user::Entity",
+        from = "Column::UserId",
+        to = "super::user::Column::Id"
+    )]
+    User,
+}
+
+impl Related<super::billing_subscription::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::BillingSubscription.def()
+    }
+}
+
+impl Related<super::user::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::User.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,33 @@
+use crate::db::{BillingSubscriptionId, BillingSubscriptionPauseId};
+use sea_orm::entity::prelude::*;
+
+/// A time that a subscription was paused, which we keep so that we can limit how often it can be paused.
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "billing_subscription_pauses")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: BillingSubscriptionPauseId,
+    pub billing_subscription_id: BillingSubscriptionId,
+    pub paused_at: DateTime,
+    /// When the subscription resumes on its own, if it was paused until a given time.
+    pub resumes_at: Option<DateTime>,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::billing_subscription::Entity",
+        from = "Column::BillingSubscriptionId",
+        to = "super::billing_subscription::Column::Id"
+    )]
+    BillingSubscription,
+}
+
+impl Related<super::billing_subscription::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::BillingSubscription.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,41 @@
+use crate::db::{BillingCustomerId, BillingUsageAdjustmentId};
+use sea_orm::entity::prelude::*;
+
+/// An adjustment to a customer's billed usage, such as a credit issued for
+/// disputed model requests.
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "billing_usage_adjustments")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: BillingUsageAdjustmentId,
+    pub billing_customer_id: BillingCustomerId,
+    pub model: String,
+    pub mode: String,
+    /// The number of requests that were adjusted, weighted by how many requests each of them was billed as.
+    pub requests: i32,
+    /// The amount of the adjustment, in cents.
+    ///
+    /// Credits are negative, following Stripe's convention for customer balances.
+    pub amount_in_cents: i64,
+    pub reason: String,
+    pub stripe_balance_transaction_id: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::billing_customer::Entity",
+        from = "Column::BillingCustomerId",
+        to = "super::billing_customer::Column::Id"
+    )]
+    BillingCustomer,
+}
+
+impl Related<super::billing_customer::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::BillingCustomer.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,34 @@
+use crate::db::{BillingCustomerId, CustomPriceOverrideId};
+use sea_orm::entity::prelude::*;
+
+/// A Stripe price that a customer is billed at for a usage meter, in place of the catalog price (e.g., a rate
+/// negotiated as part of an enterprise contract).
+#[derive(Clone, Debug, Default, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "custom_price_overrides")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: CustomPriceOverrideId,
+    pub billing_customer_id: BillingCustomerId,
+    /// The name of the meter event that the price is billed for.
+    pub meter_event_name: String,
+    pub stripe_price_id: String,
+    pub created_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {
+    #[sea_orm(
+        belongs_to = "super::billing_customer::Entity",
+        from = "Column::BillingCustomerId",
+        to = "super::billing_customer::Column::Id"
+    )]
+    BillingCustomer,
+}
+
+impl Related<super::billing_customer::Entity> for Entity {
+    fn to() -> RelationDef {
+        Relation::BillingCustomer.def()
+    }
+}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,23 @@
+use sea_orm::entity::prelude::*;
+
+/// A Stripe event that we failed to process.
+///
+/// These are kept around so that events which keep failing are visible,
+/// rather than being silently retried on every poll.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "failed_stripe_events")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub stripe_event_id: String,
+    pub stripe_event_type: String,
+    pub stripe_event_created_timestamp: i64,
+    pub attempts: i32,
+    pub last_error: String,
+    pub first_failed_at: DateTime,
+    pub last_failed_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
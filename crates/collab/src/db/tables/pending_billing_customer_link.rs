@@ -0,0 +1,20 @@
+use sea_orm::entity::prelude::*;
+
+/// A Stripe customer whose email address doesn't belong to any user yet.
+///
+/// These are kept around so that support can link the customer to a user by
+/// hand. The link is removed once a Stripe event links the customer to a user.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "pending_billing_customer_links")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub stripe_customer_id: String,
+    pub email: String,
+    pub first_seen_at: DateTime,
+    pub last_seen_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,19 @@
+use sea_orm::entity::prelude::*;
+
+/// The Stripe event that we've processed every event up to, so that polling can resume from it.
+///
+/// There is only ever a single cursor.
+#[derive(Clone, Debug, PartialEq, DeriveEntityModel)]
+#[sea_orm(table_name = "stripe_event_cursors")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: i32,
+    pub stripe_event_id: String,
+    pub stripe_event_created_timestamp: i64,
+    pub updated_at: DateTime,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
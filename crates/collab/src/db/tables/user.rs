@@ -60,6 +60,13 @@ impl Model {
     pub fn account_age(&self) -> chrono::Duration {
         chrono::Utc::now().naive_utc() - self.account_created_at()
     }
+
+    /// Returns whether the user is Zed staff, who aren't billed for or limited in their usage.
+    ///
+    /// These are the users returned by `Database::get_staff_users`.
+    pub fn is_staff(&self) -> bool {
+        self.admin
+    }
 }
 
 impl Related<super::access_token::Entity> for Entity {
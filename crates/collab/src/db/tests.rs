@@ -7,9 +7,12 @@ mod db_tests;
 #[cfg(target_os = "macos")]
 mod embedding_tests;
 mod extension_tests;
+mod failed_stripe_event_tests;
 mod feature_flag_tests;
 mod message_tests;
+mod pending_billing_customer_link_tests;
 mod processed_stripe_event_tests;
+mod stripe_event_cursor_tests;
 mod user_tests;
 
 use crate::migrations::run_database_migrations;
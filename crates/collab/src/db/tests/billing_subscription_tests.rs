@@ -1,8 +1,10 @@
 use std::sync::Arc;
 
-use crate::db::billing_subscription::StripeSubscriptionStatus;
+use crate::db::billing_subscription::{StripeSubscriptionStatus, SubscriptionKind};
 use crate::db::tests::new_test_user;
-use crate::db::{CreateBillingCustomerParams, CreateBillingSubscriptionParams};
+use crate::db::{
+    CreateBillingCustomerParams, CreateBillingSubscriptionParams, GetBillingSubscriptionsParams,
+};
 use crate::test_both_dbs;
 
 use super::Database;
@@ -40,11 +42,15 @@ async fn test_get_active_billing_subscriptions(db: &Arc<Database>) {
         db.create_billing_subscription(&CreateBillingSubscriptionParams {
             billing_customer_id: customer.id,
             kind: None,
+            unclassified: false,
             stripe_subscription_id: "sub_active_user".into(),
             stripe_subscription_status: StripeSubscriptionStatus::Active,
             stripe_cancellation_reason: None,
             stripe_current_period_start: None,
             stripe_current_period_end: None,
+            seats: 1,
+            monthly_recurring_revenue_in_cents: None,
+            ended_at: None,
         })
         .await
         .unwrap();
@@ -78,11 +84,15 @@ async fn test_get_active_billing_subscriptions(db: &Arc<Database>) {
         db.create_billing_subscription(&CreateBillingSubscriptionParams {
             billing_customer_id: customer.id,
             kind: None,
+            unclassified: false,
             stripe_subscription_id: "sub_past_due_user".into(),
             stripe_subscription_status: StripeSubscriptionStatus::PastDue,
             stripe_cancellation_reason: None,
             stripe_current_period_start: None,
             stripe_current_period_end: None,
+            seats: 1,
+            monthly_recurring_revenue_in_cents: None,
+            ended_at: None,
         })
         .await
         .unwrap();
@@ -94,3 +104,338 @@ async fn test_get_active_billing_subscriptions(db: &Arc<Database>) {
         assert_eq!(subscription_count, 0);
     }
 }
+
+test_both_dbs!(
+    test_get_paying_billing_subscriptions,
+    test_get_paying_billing_subscriptions_postgres,
+    test_get_paying_billing_subscriptions_sqlite
+);
+
+async fn test_get_paying_billing_subscriptions(db: &Arc<Database>) {
+    let now = chrono::Utc::now().naive_utc();
+    let subscriptions = [
+        (
+            SubscriptionKind::ZedPro,
+            StripeSubscriptionStatus::Active,
+            None,
+        ),
+        (
+            SubscriptionKind::ZedPro,
+            StripeSubscriptionStatus::PastDue,
+            None,
+        ),
+        (
+            SubscriptionKind::ZedPro,
+            StripeSubscriptionStatus::Canceled,
+            None,
+        ),
+        (
+            SubscriptionKind::ZedPro,
+            StripeSubscriptionStatus::Canceled,
+            Some(now + chrono::Duration::minutes(30)),
+        ),
+        (
+            SubscriptionKind::ZedProTrial,
+            StripeSubscriptionStatus::Trialing,
+            None,
+        ),
+        (
+            SubscriptionKind::ZedFree,
+            StripeSubscriptionStatus::Active,
+            None,
+        ),
+    ];
+
+    for (ix, (kind, status, ended_at)) in subscriptions.into_iter().enumerate() {
+        let user_id = new_test_user(db, &format!("user-{ix}@example.com")).await;
+        let customer = db
+            .create_billing_customer(&CreateBillingCustomerParams {
+                user_id,
+                stripe_customer_id: format!("cus_{ix}"),
+            })
+            .await
+            .unwrap();
+
+        db.create_billing_subscription(&CreateBillingSubscriptionParams {
+            billing_customer_id: customer.id,
+            kind: Some(kind),
+            unclassified: false,
+            stripe_subscription_id: format!("sub_{ix}"),
+            stripe_subscription_status: status,
+            stripe_cancellation_reason: None,
+            stripe_current_period_start: None,
+            stripe_current_period_end: None,
+            seats: 1,
+            monthly_recurring_revenue_in_cents: None,
+            ended_at,
+        })
+        .await
+        .unwrap();
+    }
+
+    let paying_subscription_ids = |as_of| async move {
+        db.get_paying_billing_subscriptions(as_of)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|subscription| subscription.stripe_subscription_id)
+            .collect::<Vec<_>>()
+    };
+
+    // Subscriptions that ended since are still counted as paying at the time.
+    assert_eq!(
+        paying_subscription_ids(now + chrono::Duration::minutes(10)).await,
+        vec!["sub_0", "sub_1", "sub_3", "sub_5"]
+    );
+
+    // Once they've ended, they aren't.
+    assert_eq!(
+        paying_subscription_ids(now + chrono::Duration::hours(1)).await,
+        vec!["sub_0", "sub_1", "sub_5"]
+    );
+
+    // Subscriptions created after the cutoff aren't counted.
+    assert_eq!(
+        paying_subscription_ids(now - chrono::Duration::hours(1)).await,
+        Vec::<String>::new()
+    );
+}
+
+test_both_dbs!(
+    test_get_active_zed_pro_and_trial_billing_subscriptions,
+    test_get_active_zed_pro_and_trial_billing_subscriptions_postgres,
+    test_get_active_zed_pro_and_trial_billing_subscriptions_sqlite
+);
+
+async fn test_get_active_zed_pro_and_trial_billing_subscriptions(db: &Arc<Database>) {
+    let subscriptions = [
+        (SubscriptionKind::ZedPro, StripeSubscriptionStatus::Active),
+        (SubscriptionKind::ZedPro, StripeSubscriptionStatus::PastDue),
+        (SubscriptionKind::ZedPro, StripeSubscriptionStatus::Canceled),
+        (
+            SubscriptionKind::ZedProTrial,
+            StripeSubscriptionStatus::Trialing,
+        ),
+        (
+            SubscriptionKind::ZedProTrial,
+            StripeSubscriptionStatus::Canceled,
+        ),
+        (SubscriptionKind::ZedFree, StripeSubscriptionStatus::Active),
+    ];
+
+    let mut user_ids = Vec::new();
+    for (ix, (kind, status)) in subscriptions.into_iter().enumerate() {
+        let user_id = new_test_user(db, &format!("user-{ix}@example.com")).await;
+        let customer = db
+            .create_billing_customer(&CreateBillingCustomerParams {
+                user_id,
+                stripe_customer_id: format!("cus_{ix}"),
+            })
+            .await
+            .unwrap();
+
+        db.create_billing_subscription(&CreateBillingSubscriptionParams {
+            billing_customer_id: customer.id,
+            kind: Some(kind),
+            unclassified: false,
+            stripe_subscription_id: format!("sub_{ix}"),
+            stripe_subscription_status: status,
+            stripe_cancellation_reason: None,
+            stripe_current_period_start: None,
+            stripe_current_period_end: None,
+            seats: 1,
+            monthly_recurring_revenue_in_cents: None,
+            ended_at: None,
+        })
+        .await
+        .unwrap();
+
+        user_ids.push(user_id);
+    }
+
+    let subscriptions = db
+        .get_active_zed_pro_and_trial_billing_subscriptions()
+        .await
+        .unwrap();
+
+    let mut subscription_ids = subscriptions
+        .into_iter()
+        .map(|(user_id, (_customer, subscription))| (user_id, subscription.stripe_subscription_id))
+        .collect::<Vec<_>>();
+    subscription_ids.sort();
+    assert_eq!(
+        subscription_ids,
+        vec![
+            (user_ids[0], "sub_0".to_string()),
+            (user_ids[1], "sub_1".to_string()),
+            (user_ids[3], "sub_3".to_string()),
+        ]
+    );
+}
+
+test_both_dbs!(
+    test_get_overlapping_billing_subscriptions,
+    test_get_overlapping_billing_subscriptions_postgres,
+    test_get_overlapping_billing_subscriptions_sqlite
+);
+
+async fn test_get_overlapping_billing_subscriptions(db: &Arc<Database>) {
+    let users = [
+        // A user with a single active subscription.
+        vec![(SubscriptionKind::ZedPro, StripeSubscriptionStatus::Active)],
+        // A user who ended up with two active subscriptions.
+        vec![
+            (SubscriptionKind::ZedPro, StripeSubscriptionStatus::Active),
+            (
+                SubscriptionKind::ZedProTrial,
+                StripeSubscriptionStatus::Trialing,
+            ),
+        ],
+        // A user whose previous subscription was canceled.
+        vec![
+            (SubscriptionKind::ZedPro, StripeSubscriptionStatus::Canceled),
+            (SubscriptionKind::ZedPro, StripeSubscriptionStatus::Active),
+        ],
+    ];
+
+    let mut user_ids = Vec::new();
+    for (user_ix, subscriptions) in users.into_iter().enumerate() {
+        let user_id = new_test_user(db, &format!("user-{user_ix}@example.com")).await;
+        let customer = db
+            .create_billing_customer(&CreateBillingCustomerParams {
+                user_id,
+                stripe_customer_id: format!("cus_{user_ix}"),
+            })
+            .await
+            .unwrap();
+
+        for (subscription_ix, (kind, status)) in subscriptions.into_iter().enumerate() {
+            db.create_billing_subscription(&CreateBillingSubscriptionParams {
+                billing_customer_id: customer.id,
+                kind: Some(kind),
+                unclassified: false,
+                stripe_subscription_id: format!("sub_{user_ix}_{subscription_ix}"),
+                stripe_subscription_status: status,
+                stripe_cancellation_reason: None,
+                stripe_current_period_start: None,
+                stripe_current_period_end: None,
+                seats: 1,
+                monthly_recurring_revenue_in_cents: None,
+                ended_at: None,
+            })
+            .await
+            .unwrap();
+        }
+
+        user_ids.push(user_id);
+    }
+
+    let overlapping_subscriptions = db.get_overlapping_billing_subscriptions().await.unwrap();
+    assert_eq!(overlapping_subscriptions.len(), 1);
+    assert_eq!(
+        overlapping_subscriptions[&user_ids[1]]
+            .iter()
+            .map(|subscription| subscription.stripe_subscription_id.as_str())
+            .collect::<Vec<_>>(),
+        vec!["sub_1_0", "sub_1_1"]
+    );
+}
+
+test_both_dbs!(
+    test_get_billing_subscriptions_page,
+    test_get_billing_subscriptions_page_postgres,
+    test_get_billing_subscriptions_page_sqlite
+);
+
+async fn test_get_billing_subscriptions_page(db: &Arc<Database>) {
+    let user_id = new_test_user(db, "long-history-user@example.com").await;
+    let customer = db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id,
+            stripe_customer_id: "cus_long_history_user".into(),
+        })
+        .await
+        .unwrap();
+    for (ix, status) in [
+        StripeSubscriptionStatus::Canceled,
+        StripeSubscriptionStatus::Canceled,
+        StripeSubscriptionStatus::PastDue,
+        StripeSubscriptionStatus::Trialing,
+        StripeSubscriptionStatus::Active,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        db.create_billing_subscription(&CreateBillingSubscriptionParams {
+            billing_customer_id: customer.id,
+            kind: Some(SubscriptionKind::ZedPro),
+            unclassified: false,
+            stripe_subscription_id: format!("sub_long_history_{ix}"),
+            stripe_subscription_status: status,
+            stripe_cancellation_reason: None,
+            stripe_current_period_start: None,
+            stripe_current_period_end: None,
+            seats: 1,
+            monthly_recurring_revenue_in_cents: None,
+            ended_at: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    let page = |params: GetBillingSubscriptionsParams| async move {
+        let (subscriptions, total) = db
+            .get_billing_subscriptions_page(user_id, &params)
+            .await
+            .unwrap();
+        let ids = subscriptions
+            .into_iter()
+            .map(|subscription| subscription.stripe_subscription_id)
+            .collect::<Vec<_>>();
+        (ids, total)
+    };
+
+    // Without any filters, all of the subscriptions are listed.
+    let (ids, total) = page(GetBillingSubscriptionsParams::default()).await;
+    assert_eq!(total, 5);
+    assert_eq!(
+        ids,
+        (0..5)
+            .map(|ix| format!("sub_long_history_{ix}"))
+            .collect::<Vec<_>>()
+    );
+
+    let (ids, total) = page(GetBillingSubscriptionsParams {
+        status: Some(StripeSubscriptionStatus::Canceled),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(total, 2);
+    assert_eq!(ids, ["sub_long_history_0", "sub_long_history_1"]);
+
+    let (ids, total) = page(GetBillingSubscriptionsParams {
+        active_only: true,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(total, 2);
+    assert_eq!(ids, ["sub_long_history_3", "sub_long_history_4"]);
+
+    // The total counts every subscription that matches, not just the ones on the page.
+    let (ids, total) = page(GetBillingSubscriptionsParams {
+        limit: Some(2),
+        offset: Some(1),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(total, 5);
+    assert_eq!(ids, ["sub_long_history_1", "sub_long_history_2"]);
+
+    let (ids, total) = page(GetBillingSubscriptionsParams {
+        offset: Some(5),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(total, 5);
+    assert!(ids.is_empty());
+}
@@ -0,0 +1,105 @@
+use std::sync::Arc;
+
+use crate::test_both_dbs;
+
+use super::{CreateProcessedStripeEventParams, Database, RecordFailedStripeEventParams};
+
+test_both_dbs!(
+    test_record_failed_stripe_event,
+    test_record_failed_stripe_event_postgres,
+    test_record_failed_stripe_event_sqlite
+);
+
+async fn test_record_failed_stripe_event(db: &Arc<Database>) {
+    let event_id = "evt_1PiJOuRxOf7d5PNaw2zzWiyO".to_string();
+
+    let params = RecordFailedStripeEventParams {
+        stripe_event_id: event_id.clone(),
+        stripe_event_type: "customer.subscription.updated".into(),
+        stripe_event_created_timestamp: 1722355968,
+        error: "unexpected event payload".into(),
+    };
+
+    let failed_event = db.record_failed_stripe_event(&params).await.unwrap();
+    assert_eq!(failed_event.attempts, 1);
+    assert_eq!(failed_event.last_error, "unexpected event payload");
+
+    let failed_event = db
+        .record_failed_stripe_event(&RecordFailedStripeEventParams {
+            error: "failed to sync subscription".into(),
+            ..params
+        })
+        .await
+        .unwrap();
+    assert_eq!(failed_event.attempts, 2);
+    assert_eq!(failed_event.last_error, "failed to sync subscription");
+    assert_eq!(
+        failed_event.stripe_event_type,
+        "customer.subscription.updated"
+    );
+
+    assert_eq!(
+        db.get_failed_stripe_event_by_event_id(&event_id)
+            .await
+            .unwrap(),
+        Some(failed_event)
+    );
+    assert_eq!(
+        db.get_failed_stripe_event_by_event_id("evt_unknown")
+            .await
+            .unwrap(),
+        None
+    );
+}
+
+test_both_dbs!(
+    test_get_stuck_stripe_events,
+    test_get_stuck_stripe_events_postgres,
+    test_get_stuck_stripe_events_sqlite
+);
+
+async fn test_get_stuck_stripe_events(db: &Arc<Database>) {
+    let failing_event = RecordFailedStripeEventParams {
+        stripe_event_id: "evt_failing".into(),
+        stripe_event_type: "customer.subscription.updated".into(),
+        stripe_event_created_timestamp: 1722355968,
+        error: "failed to sync subscription".into(),
+    };
+    let recovered_event = RecordFailedStripeEventParams {
+        stripe_event_id: "evt_recovered".into(),
+        stripe_event_type: "customer.updated".into(),
+        stripe_event_created_timestamp: 1722355900,
+        error: "failed to update customer".into(),
+    };
+
+    // An event that keeps failing is stuck.
+    for _ in 0..3 {
+        db.record_failed_stripe_event(&failing_event).await.unwrap();
+    }
+    db.record_failed_stripe_event(&recovered_event)
+        .await
+        .unwrap();
+
+    let stuck_events = db.get_stuck_stripe_events().await.unwrap();
+    assert_eq!(
+        stuck_events
+            .iter()
+            .map(|event| (event.stripe_event_id.as_str(), event.attempts))
+            .collect::<Vec<_>>(),
+        vec![("evt_recovered", 1), ("evt_failing", 3)]
+    );
+
+    // Once an event has been processed, it's no longer stuck.
+    db.create_processed_stripe_event(&CreateProcessedStripeEventParams {
+        stripe_event_id: recovered_event.stripe_event_id.clone(),
+        stripe_event_type: recovered_event.stripe_event_type.clone(),
+        stripe_event_created_timestamp: recovered_event.stripe_event_created_timestamp,
+    })
+    .await
+    .unwrap();
+
+    let stuck_events = db.get_stuck_stripe_events().await.unwrap();
+    assert_eq!(stuck_events.len(), 1);
+    assert_eq!(stuck_events[0].stripe_event_id, "evt_failing");
+    assert_eq!(stuck_events[0].last_error, "failed to sync subscription");
+}
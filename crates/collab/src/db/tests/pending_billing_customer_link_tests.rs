@@ -0,0 +1,45 @@
+use std::sync::Arc;
+
+use crate::test_both_dbs;
+
+use super::Database;
+
+test_both_dbs!(
+    test_pending_billing_customer_links,
+    test_pending_billing_customer_links_postgres,
+    test_pending_billing_customer_links_sqlite
+);
+
+async fn test_pending_billing_customer_links(db: &Arc<Database>) {
+    let link = db
+        .record_pending_billing_customer_link("cus_1", "old@example.com")
+        .await
+        .unwrap();
+    assert_eq!(link.email, "old@example.com");
+    assert_eq!(link.first_seen_at, link.last_seen_at);
+
+    // Seeing the customer again keeps when we first saw them, and updates their email address.
+    let updated_link = db
+        .record_pending_billing_customer_link("cus_1", "new@example.com")
+        .await
+        .unwrap();
+    assert_eq!(updated_link.email, "new@example.com");
+    assert_eq!(updated_link.first_seen_at, link.first_seen_at);
+    assert_eq!(
+        db.get_pending_billing_customer_link("cus_1").await.unwrap(),
+        Some(updated_link)
+    );
+
+    db.delete_pending_billing_customer_link("cus_1")
+        .await
+        .unwrap();
+    assert_eq!(
+        db.get_pending_billing_customer_link("cus_1").await.unwrap(),
+        None
+    );
+
+    // Deleting a link that doesn't exist is fine.
+    db.delete_pending_billing_customer_link("cus_unknown")
+        .await
+        .unwrap();
+}
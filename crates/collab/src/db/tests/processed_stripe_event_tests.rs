@@ -36,3 +36,74 @@ async fn test_already_processed_stripe_event(db: &Arc<Database>) {
         "Expected {unprocessed_event_id} to be unprocessed"
     );
 }
+
+test_both_dbs!(
+    test_delete_processed_stripe_events_created_before,
+    test_delete_processed_stripe_events_created_before_postgres,
+    test_delete_processed_stripe_events_created_before_sqlite
+);
+
+async fn test_delete_processed_stripe_events_created_before(db: &Arc<Database>) {
+    for (event_id, created_timestamp) in [("evt_old", 1722355968), ("evt_new", 1722442368)] {
+        db.create_processed_stripe_event(&CreateProcessedStripeEventParams {
+            stripe_event_id: event_id.into(),
+            stripe_event_type: "customer.created".into(),
+            stripe_event_created_timestamp: created_timestamp,
+        })
+        .await
+        .unwrap();
+    }
+
+    let deleted = db
+        .delete_processed_stripe_events_created_before(1722442368)
+        .await
+        .unwrap();
+    assert_eq!(deleted, 1);
+
+    assert!(!db.already_processed_stripe_event("evt_old").await.unwrap());
+    assert!(db.already_processed_stripe_event("evt_new").await.unwrap());
+}
+
+test_both_dbs!(
+    test_get_processed_stripe_events_by_event_ids,
+    test_get_processed_stripe_events_by_event_ids_postgres,
+    test_get_processed_stripe_events_by_event_ids_sqlite
+);
+
+async fn test_get_processed_stripe_events_by_event_ids(db: &Arc<Database>) {
+    // Enough events to need more than one query.
+    let event_ids = (0..2_500).map(|ix| format!("evt_{ix}")).collect::<Vec<_>>();
+    for event_id in event_ids.iter().step_by(100) {
+        db.create_processed_stripe_event(&CreateProcessedStripeEventParams {
+            stripe_event_id: event_id.clone(),
+            stripe_event_type: "customer.created".into(),
+            stripe_event_created_timestamp: 1722355968,
+        })
+        .await
+        .unwrap();
+    }
+
+    let event_ids = event_ids.iter().map(String::as_str).collect::<Vec<_>>();
+    let mut processed_event_ids = db
+        .get_processed_stripe_events_by_event_ids(&event_ids)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|event| event.stripe_event_id)
+        .collect::<Vec<_>>();
+    processed_event_ids.sort_by_key(|event_id| event_id["evt_".len()..].parse::<usize>().unwrap());
+    assert_eq!(
+        processed_event_ids,
+        (0..2_500)
+            .step_by(100)
+            .map(|ix| format!("evt_{ix}"))
+            .collect::<Vec<_>>()
+    );
+
+    assert!(
+        db.get_processed_stripe_events_by_event_ids(&[])
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
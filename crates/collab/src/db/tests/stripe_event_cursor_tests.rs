@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use crate::test_both_dbs;
+
+use super::{Database, UpdateStripeEventCursorParams};
+
+test_both_dbs!(
+    test_stripe_event_cursor,
+    test_stripe_event_cursor_postgres,
+    test_stripe_event_cursor_sqlite
+);
+
+async fn test_stripe_event_cursor(db: &Arc<Database>) {
+    assert_eq!(db.get_stripe_event_cursor().await.unwrap(), None);
+
+    db.update_stripe_event_cursor(&UpdateStripeEventCursorParams {
+        stripe_event_id: "evt_1".into(),
+        stripe_event_created_timestamp: 1722355968,
+    })
+    .await
+    .unwrap();
+
+    let cursor = db.get_stripe_event_cursor().await.unwrap().unwrap();
+    assert_eq!(cursor.stripe_event_id, "evt_1");
+    assert_eq!(cursor.stripe_event_created_timestamp, 1722355968);
+
+    // Moving the cursor replaces it, rather than adding another one.
+    db.update_stripe_event_cursor(&UpdateStripeEventCursorParams {
+        stripe_event_id: "evt_2".into(),
+        stripe_event_created_timestamp: 1722442368,
+    })
+    .await
+    .unwrap();
+
+    let cursor = db.get_stripe_event_cursor().await.unwrap().unwrap();
+    assert_eq!(cursor.stripe_event_id, "evt_2");
+    assert_eq!(cursor.stripe_event_created_timestamp, 1722442368);
+}
@@ -1,5 +1,6 @@
 pub mod api;
 pub mod auth;
+pub mod billing_webhooks;
 pub mod db;
 pub mod env;
 pub mod executor;
@@ -20,10 +21,11 @@ use axum::{
     http::{HeaderMap, StatusCode},
     response::IntoResponse,
 };
+use db::billing_subscription::SubscriptionKind;
 use db::{ChannelId, Database};
 use executor::Executor;
 use llm::db::LlmDatabase;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{path::PathBuf, sync::Arc};
 use util::ResultExt;
 
@@ -79,6 +81,17 @@ impl Error {
     fn http(code: StatusCode, message: String) -> Self {
         Self::Http(code, message, HeaderMap::default())
     }
+
+    /// Returns whether this error was caused by a write that violated a unique constraint.
+    fn is_unique_violation(&self) -> bool {
+        match self {
+            Self::Database(error) => matches!(
+                error.sql_err(),
+                Some(sea_orm::SqlErr::UniqueConstraintViolation(_))
+            ),
+            _ => false,
+        }
+    }
 }
 
 impl IntoResponse for Error {
@@ -182,6 +195,75 @@ pub struct Config {
     pub stripe_api_key: Option<String>,
     pub supermaven_admin_api_key: Option<Arc<str>>,
     pub user_backfiller_github_access_token: Option<Arc<str>>,
+    pub trial_end_behavior: Option<TrialEndBehavior>,
+    pub overlapping_subscription_resolution: Option<OverlappingSubscriptionResolution>,
+    pub duplicate_checkout_behavior: Option<DuplicateCheckoutBehavior>,
+    pub trial_payment_method_collection: Option<TrialPaymentMethodCollection>,
+    /// How many days a Zed Pro trial lasts.
+    pub zed_pro_trial_days: Option<u32>,
+    /// How many days a Zed Pro trial lasts for users with the extended trial feature flag.
+    pub zed_pro_extended_trial_days: Option<u32>,
+    /// Whether billing is in read-only maintenance mode, where reads are served but all changes are rejected.
+    pub billing_read_only: Option<bool>,
+    /// Whether to bill for edit predictions beyond the plan's limit.
+    pub edit_prediction_overages_enabled: Option<bool>,
+    /// The number of model requests and edit predictions beyond the plan's limit that are free each billing period.
+    pub overage_grace: Option<i32>,
+    /// The URLs to notify of changes to users' billing.
+    pub billing_webhook_urls: Option<Vec<String>>,
+    /// The secret used to sign billing webhook requests.
+    pub billing_webhook_secret: Option<String>,
+    /// The secret used to verify the signatures of the webhooks Stripe sends us.
+    pub stripe_webhook_secret: Option<String>,
+    /// How many seconds old a Stripe event can be before we drop it rather than process it, as processing it would
+    /// risk overwriting more recent updates.
+    pub stale_stripe_event_age_seconds: Option<u64>,
+    /// How many days to keep records of processed Stripe events before pruning them.
+    pub processed_stripe_event_retention_days: Option<u32>,
+    /// How many times to retry billing database writes that fail with a transient error.
+    pub billing_db_write_retries: Option<u32>,
+    /// The Stripe coupon to offer users who canceled Zed Pro, to win them back.
+    ///
+    /// Win-back offers are disabled when this isn't set.
+    pub winback_coupon_id: Option<String>,
+    /// The model requests that plans include for individual models, rather than pooled across all models.
+    ///
+    /// Each entry is of the form `<plan>:<model>=<requests>`, e.g. `zed_pro:claude-sonnet-4=300`. Plans without
+    /// any entries keep their pooled model request limit.
+    pub model_request_allotments: Option<Vec<String>>,
+    /// The most model requests that plans allow for individual models, on top of the plan's model request limit.
+    ///
+    /// Each entry is of the form `<plan>:<model>=<requests>`, e.g. `zed_pro:claude-opus-4=100`. Models without an
+    /// entry are only limited by the plan's model request limit.
+    pub model_request_limits: Option<Vec<String>>,
+    /// How requests to each model are billed in Stripe, replacing the built-in table when set.
+    ///
+    /// Each entry is of the form `<provider> <model> <mode> <weight> <price lookup key> <meter event name>`, followed
+    /// by any `<dimension>=<value>` pairs, e.g.
+    /// `anthropic claude-opus-4 max 1 claude-opus-4-requests-max claude_opus_4/requests/max`.
+    pub model_request_billing: Option<Vec<String>>,
+    /// The plan to give users whose Stripe subscription we can't determine the kind of.
+    pub unclassified_subscription_plan: Option<UnclassifiedSubscriptionPlan>,
+    /// Whether to emit an alert when we sync a Stripe subscription that we can't determine the kind of.
+    pub alert_on_unclassified_subscriptions: Option<bool>,
+    /// The usage counters that reset on a day of the month, rather than at the end of the subscription period.
+    ///
+    /// Each entry is of the form `<plan>:<counter>=<day>`, e.g. `zed_free:edit_predictions=1`, where the counter is
+    /// `model_requests` or `edit_predictions` and the day is between 1 and 28.
+    pub usage_reset_windows: Option<Vec<String>>,
+    /// How many seconds to wait after a user's paid subscription ends before subscribing them to Zed Free, so that
+    /// they have a chance to reactivate it.
+    pub free_downgrade_delay_seconds: Option<u64>,
+    /// How many seconds a user keeps the access of their plan after their subscription becomes past due, while Stripe
+    /// retries the payment.
+    pub past_due_grace_period_seconds: Option<u64>,
+    /// How many days a Zed Pro subscription has to run before it can be canceled. Trials can always be canceled.
+    pub minimum_term_days: Option<u32>,
+    /// How many times a subscription can be paused in a rolling year.
+    pub max_subscription_pauses_per_year: Option<u32>,
+    /// Whether the Stripe usage sync only logs the meter events and price subscriptions it would send to Stripe,
+    /// rather than sending them.
+    pub stripe_usage_sync_dry_run: Option<bool>,
 }
 
 impl Config {
@@ -189,6 +271,60 @@ impl Config {
         self.zed_environment == "development".into()
     }
 
+    pub fn edit_prediction_overages_enabled(&self) -> bool {
+        self.edit_prediction_overages_enabled.unwrap_or(false)
+    }
+
+    pub fn billing_read_only(&self) -> bool {
+        self.billing_read_only.unwrap_or(false)
+    }
+
+    pub fn overage_grace(&self) -> i32 {
+        self.overage_grace.unwrap_or(0).max(0)
+    }
+
+    pub fn billing_db_write_retries(&self) -> u32 {
+        self.billing_db_write_retries.unwrap_or(2)
+    }
+
+    pub fn zed_pro_trial_days(&self) -> u32 {
+        self.zed_pro_trial_days.unwrap_or(14).max(1)
+    }
+
+    pub fn zed_pro_extended_trial_days(&self) -> u32 {
+        self.zed_pro_extended_trial_days.unwrap_or(60).max(1)
+    }
+
+    pub fn stale_stripe_event_age(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.stale_stripe_event_age_seconds.unwrap_or(24 * 60 * 60))
+    }
+
+    pub fn free_downgrade_delay(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.free_downgrade_delay_seconds.unwrap_or(0))
+    }
+
+    pub fn past_due_grace_period(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.past_due_grace_period_seconds.unwrap_or(0))
+    }
+
+    pub fn minimum_term(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(
+            u64::from(self.minimum_term_days.unwrap_or(0)) * 24 * 60 * 60,
+        )
+    }
+
+    pub fn max_subscription_pauses_per_year(&self) -> u32 {
+        self.max_subscription_pauses_per_year.unwrap_or(2)
+    }
+
+    pub fn stripe_usage_sync_dry_run(&self) -> bool {
+        self.stripe_usage_sync_dry_run.unwrap_or(false)
+    }
+
+    pub fn alert_on_unclassified_subscriptions(&self) -> bool {
+        self.alert_on_unclassified_subscriptions.unwrap_or(false)
+    }
+
     /// Returns the base `zed.dev` URL.
     pub fn zed_dot_dev_url(&self) -> &str {
         match self.zed_environment.as_ref() {
@@ -241,6 +377,93 @@ impl Config {
             kinesis_access_key: None,
             kinesis_secret_key: None,
             kinesis_stream: None,
+            trial_end_behavior: None,
+            overlapping_subscription_resolution: None,
+            duplicate_checkout_behavior: None,
+            trial_payment_method_collection: None,
+            zed_pro_trial_days: None,
+            zed_pro_extended_trial_days: None,
+            billing_read_only: None,
+            edit_prediction_overages_enabled: None,
+            overage_grace: None,
+            billing_webhook_urls: None,
+            billing_webhook_secret: None,
+            stripe_webhook_secret: None,
+            stale_stripe_event_age_seconds: None,
+            processed_stripe_event_retention_days: None,
+            billing_db_write_retries: None,
+            winback_coupon_id: None,
+            model_request_allotments: None,
+            model_request_limits: None,
+            model_request_billing: None,
+            unclassified_subscription_plan: None,
+            alert_on_unclassified_subscriptions: None,
+            usage_reset_windows: None,
+            free_downgrade_delay_seconds: None,
+            past_due_grace_period_seconds: None,
+            minimum_term_days: None,
+            max_subscription_pauses_per_year: None,
+            stripe_usage_sync_dry_run: None,
+        }
+    }
+}
+
+/// What happens to a user when their trial ends without them subscribing.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
+pub enum TrialEndBehavior {
+    /// The user is moved to Zed Free.
+    #[default]
+    DowngradeToFree,
+    /// The user loses access until they subscribe.
+    BlockAccess,
+}
+
+/// Which subscription we keep when a user ends up with more than one active subscription.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
+pub enum OverlappingSubscriptionResolution {
+    /// The most recently created subscription is kept.
+    #[default]
+    KeepMostRecent,
+    /// The subscription to the highest tier is kept, falling back to the most recent one.
+    KeepHighestTier,
+}
+
+/// What happens when a user completes checkout for a plan they're already subscribed to (e.g., in two tabs).
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
+pub enum DuplicateCheckoutBehavior {
+    /// The newer subscription is canceled and its charge is refunded, keeping the one we already had.
+    #[default]
+    CancelAndRefund,
+    /// The newer subscription is left alone, to be resolved by hand.
+    Ignore,
+}
+
+/// Whether we collect a card when a user starts a Zed Pro trial.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
+pub enum TrialPaymentMethodCollection {
+    /// No card is collected, so the user has to add one before the trial ends for it to convert to Zed Pro.
+    #[default]
+    WithoutCard,
+    /// A card is collected up front, so the trial converts to Zed Pro automatically when it ends.
+    WithCard,
+}
+
+/// The plan we give a user whose Stripe subscription doesn't have any of the prices we know the kind of.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
+pub enum UnclassifiedSubscriptionPlan {
+    /// The subscription is stored without a kind, so none of the plan logic applies to it.
+    #[default]
+    Unclassified,
+    /// The subscription is treated as Zed Free.
+    ZedFree,
+}
+
+impl UnclassifiedSubscriptionPlan {
+    /// Returns the kind to store for an unclassified subscription.
+    pub fn subscription_kind(self) -> Option<SubscriptionKind> {
+        match self {
+            Self::Unclassified => None,
+            Self::ZedFree => Some(SubscriptionKind::ZedFree),
         }
     }
 }
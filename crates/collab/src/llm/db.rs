@@ -96,11 +96,13 @@ impl LlmDatabase {
             .with_context(|| format!("unknown model {provider:?}:{name}"))?)
     }
 
-    pub fn model_by_id(&self, id: ModelId) -> Result<&model::Model> {
+    /// Returns the model with the given ID, along with its provider.
+    pub fn model_by_id(&self, id: ModelId) -> Result<(LanguageModelProvider, &model::Model)> {
         Ok(self
             .models
-            .values()
-            .find(|model| model.id == id)
+            .iter()
+            .find(|(_, model)| model.id == id)
+            .map(|((provider, _), model)| (*provider, model))
             .with_context(|| format!("no model for ID {id:?}"))?)
     }
 
@@ -1,3 +1,4 @@
+use sea_orm::{QueryOrder, QuerySelect};
 use time::PrimitiveDateTime;
 
 use crate::db::UserId;
@@ -42,6 +43,42 @@ impl LlmDatabase {
         .await
     }
 
+    /// Returns the user's most recent subscription usage for a period that ended at or before the given time.
+    pub async fn get_previous_subscription_usage(
+        &self,
+        user_id: UserId,
+        period_start_at: DateTimeUtc,
+    ) -> Result<Option<subscription_usage::Model>> {
+        let period_start_at = convert_chrono_to_time(period_start_at)?;
+
+        self.transaction(|tx| async move {
+            Ok(subscription_usage::Entity::find()
+                .filter(subscription_usage::Column::UserId.eq(user_id))
+                .filter(subscription_usage::Column::PeriodEndAt.lte(period_start_at))
+                .order_by_desc(subscription_usage::Column::PeriodEndAt)
+                .one(&*tx)
+                .await?)
+        })
+        .await
+    }
+
+    /// Returns the user's subscription usage for their most recent periods, most recent first.
+    pub async fn get_recent_subscription_usages(
+        &self,
+        user_id: UserId,
+        limit: u64,
+    ) -> Result<Vec<subscription_usage::Model>> {
+        self.transaction(|tx| async move {
+            Ok(subscription_usage::Entity::find()
+                .filter(subscription_usage::Column::UserId.eq(user_id))
+                .order_by_desc(subscription_usage::Column::PeriodStartAt)
+                .limit(limit)
+                .all(&*tx)
+                .await?)
+        })
+        .await
+    }
+
     async fn get_subscription_usage_for_period_in_tx(
         &self,
         user_id: UserId,
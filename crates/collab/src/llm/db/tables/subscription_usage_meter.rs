@@ -1,5 +1,8 @@
+use std::fmt;
+
+use collections::BTreeMap;
 use sea_orm::entity::prelude::*;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 use crate::llm::db::ModelId;
 
@@ -11,9 +14,19 @@ pub struct Model {
     pub subscription_usage_id: Uuid,
     pub model_id: ModelId,
     pub mode: CompletionMode,
+    /// The additional billing dimensions of the meter, in their canonical form.
+    ///
+    /// See [`UsageDimensions`].
+    pub dimensions: String,
     pub requests: i32,
 }
 
+impl Model {
+    pub fn dimensions(&self) -> UsageDimensions {
+        UsageDimensions::parse(&self.dimensions)
+    }
+}
+
 #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
 pub enum Relation {
     #[sea_orm(
@@ -44,7 +57,9 @@ impl Related<super::model::Entity> for Entity {
 
 impl ActiveModelBehavior for ActiveModel {}
 
-#[derive(Eq, PartialEq, Copy, Clone, Debug, EnumIter, DeriveActiveEnum, Hash, Serialize)]
+#[derive(
+    Eq, PartialEq, Copy, Clone, Debug, EnumIter, DeriveActiveEnum, Hash, Serialize, Deserialize,
+)]
 #[sea_orm(rs_type = "String", db_type = "String(StringLen::None)")]
 #[serde(rename_all = "snake_case")]
 pub enum CompletionMode {
@@ -53,3 +68,63 @@ pub enum CompletionMode {
     #[sea_orm(string_value = "max")]
     Max,
 }
+
+impl CompletionMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Max => "max",
+        }
+    }
+}
+
+/// The billing dimensions of a usage meter beyond its model and mode (e.g., the context length tier).
+///
+/// Dimensions are stored as comma-separated `key=value` pairs sorted by key, so that each combination of
+/// dimensions has a single canonical form. Meters that are only billed by model and mode have no dimensions.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct UsageDimensions(BTreeMap<String, String>);
+
+impl UsageDimensions {
+    pub fn new<K: Into<String>, V: Into<String>>(
+        dimensions: impl IntoIterator<Item = (K, V)>,
+    ) -> Self {
+        Self(
+            dimensions
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        )
+    }
+
+    /// Parses dimensions from their canonical form, ignoring any malformed pairs.
+    pub fn parse(dimensions: &str) -> Self {
+        Self::new(
+            dimensions
+                .split(',')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| (key.trim(), value.trim())),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn as_map(&self) -> &BTreeMap<String, String> {
+        &self.0
+    }
+}
+
+impl fmt::Display for UsageDimensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (ix, (key, value)) in self.0.iter().enumerate() {
+            if ix > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{key}={value}")?;
+        }
+
+        Ok(())
+    }
+}
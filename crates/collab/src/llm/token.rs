@@ -34,6 +34,8 @@ pub struct LlmTokenClaims {
     pub can_use_web_search_tool: bool,
     #[serde(default)]
     pub has_overdue_invoices: bool,
+    #[serde(default)]
+    pub billing_suspended: bool,
 }
 
 const LLM_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
@@ -103,6 +105,7 @@ impl LlmTokenClaims {
                     preferences.model_request_overages_spend_limit_in_cents as u32
                 }),
             has_overdue_invoices: billing_customer.has_overdue_invoices,
+            billing_suspended: billing_customer.billing_suspended,
         };
 
         Ok(jsonwebtoken::encode(
@@ -8,7 +8,12 @@ use axum::{
 };
 
 use collab::api::CloudflareIpCountryHeader;
-use collab::api::billing::sync_llm_request_usage_with_stripe_periodically;
+use collab::api::billing::{
+    BillingTasks, apply_free_downgrades_periodically, detect_status_drift_periodically,
+    init_billing_metrics, prune_processed_stripe_events_periodically,
+    remind_users_of_expiring_payment_methods_periodically,
+    sync_llm_request_usage_with_stripe_periodically,
+};
 use collab::llm::db::LlmDatabase;
 use collab::migrations::run_database_migrations;
 use collab::user_backfiller::spawn_user_backfiller;
@@ -36,6 +41,11 @@ use util::{ResultExt as _, maybe};
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 const REVISION: Option<&'static str> = option_env!("GITHUB_SHA");
 
+/// How long to wait for the billing tasks to shut down.
+///
+/// Kubernetes gives terminated pods 10s to shut down, so this needs to be less than that.
+const BILLING_TASKS_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(8);
+
 #[expect(clippy::result_large_err)]
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -96,10 +106,12 @@ async fn main() -> Result<()> {
                 .expect("failed to bind TCP listener");
 
             let mut on_shutdown = None;
+            let billing_tasks = BillingTasks::new();
 
             if mode.is_collab() || mode.is_api() {
                 setup_app_database(&config).await?;
                 setup_llm_database(&config).await?;
+                init_billing_metrics()?;
 
                 let state = AppState::new(config, Executor::Production).await?;
 
@@ -120,7 +132,18 @@ async fn main() -> Result<()> {
                     let rpc_server = collab::rpc::Server::new(epoch, state.clone());
                     rpc_server.start().await?;
 
-                    poll_stripe_events_periodically(state.clone(), rpc_server.clone());
+                    poll_stripe_events_periodically(
+                        state.clone(),
+                        rpc_server.clone(),
+                        &billing_tasks,
+                    );
+                    prune_processed_stripe_events_periodically(state.clone(), &billing_tasks);
+                    detect_status_drift_periodically(
+                        state.clone(),
+                        rpc_server.clone(),
+                        &billing_tasks,
+                    );
+                    apply_free_downgrades_periodically(state.clone(), &billing_tasks);
 
                     app = app
                         .merge(collab::api::routes(rpc_server.clone()))
@@ -132,6 +155,10 @@ async fn main() -> Result<()> {
                 if mode.is_api() {
                     fetch_extensions_from_blob_store_periodically(state.clone());
                     spawn_user_backfiller(state.clone());
+                    remind_users_of_expiring_payment_methods_periodically(
+                        state.clone(),
+                        &billing_tasks,
+                    );
 
                     let llm_db = maybe!(async {
                         let database_url = state
@@ -153,7 +180,10 @@ async fn main() -> Result<()> {
 
                     if let Some(mut llm_db) = llm_db {
                         llm_db.initialize().await?;
-                        sync_llm_request_usage_with_stripe_periodically(state.clone());
+                        sync_llm_request_usage_with_stripe_periodically(
+                            state.clone(),
+                            &billing_tasks,
+                        );
                     }
 
                     app = app
@@ -238,6 +268,18 @@ async fn main() -> Result<()> {
                     if let Some(on_shutdown) = on_shutdown {
                         on_shutdown();
                     }
+
+                    // Let the billing tasks finish what they're doing, so we don't leave Stripe
+                    // and our database out of sync.
+                    if tokio::time::timeout(
+                        BILLING_TASKS_SHUTDOWN_TIMEOUT,
+                        billing_tasks.shutdown(),
+                    )
+                    .await
+                    .is_err()
+                    {
+                        tracing::error!("timed out waiting for billing tasks to shut down");
+                    }
                 })
                 .await
                 .map_err(|e| anyhow!(e))?;
@@ -1,6 +1,8 @@
 mod connection_pool;
 
-use crate::api::billing::find_or_create_billing_customer;
+use crate::api::billing::{
+    find_or_create_billing_customer, get_entitled_billing_subscription, llm_access_blocked_reason,
+};
 use crate::api::{CloudflareIpCountryHeader, SystemIdHeader};
 use crate::db::billing_subscription::SubscriptionKind;
 use crate::llm::db::LlmDatabase;
@@ -10,7 +12,7 @@ use crate::llm::{
 };
 use crate::stripe_client::StripeCustomerId;
 use crate::{
-    AppState, Error, Result, auth,
+    AppState, Config, Error, Result, auth,
     db::{
         self, BufferId, Capability, Channel, ChannelId, ChannelRole, ChannelsForUser,
         CreatedChannelMessage, Database, InviteMemberResult, MembershipUpdated, MessageId,
@@ -200,7 +202,7 @@ impl Session {
 
     fn is_staff(&self) -> bool {
         match &self.principal {
-            Principal::User(user) => user.admin,
+            Principal::User(user) => user.is_staff(),
             Principal::Impersonated { .. } => true,
         }
     }
@@ -1014,6 +1016,7 @@ impl Server {
             &user,
             user.admin,
             &self.app_state.db,
+            &self.app_state.config,
             self.app_state.llm_db.clone(),
         )
         .await?;
@@ -1028,6 +1031,11 @@ impl Server {
         Ok(())
     }
 
+    pub fn send_notifications(self: &Arc<Self>, notifications: db::NotificationBatch) {
+        let pool = self.connection_pool.lock();
+        send_notifications(&pool, &self.peer, notifications);
+    }
+
     pub async fn refresh_llm_tokens_for_user(self: &Arc<Self>, user_id: UserId) {
         let pool = self.connection_pool.lock();
         for connection_id in pool.user_connection_ids(user_id) {
@@ -2758,12 +2766,17 @@ fn should_auto_subscribe_to_channels(version: ZedVersion) -> bool {
     version.0.minor() < 139
 }
 
-async fn current_plan(db: &Arc<Database>, user_id: UserId, is_staff: bool) -> Result<proto::Plan> {
+async fn current_plan(
+    db: &Arc<Database>,
+    config: &Config,
+    user_id: UserId,
+    is_staff: bool,
+) -> Result<proto::Plan> {
     if is_staff {
         return Ok(proto::Plan::ZedPro);
     }
 
-    let subscription = db.get_active_billing_subscription(user_id).await?;
+    let subscription = get_entitled_billing_subscription(db, config, user_id).await?;
     let subscription_kind = subscription.and_then(|subscription| subscription.kind);
 
     let plan = if let Some(subscription_kind) = subscription_kind {
@@ -2783,15 +2796,16 @@ async fn make_update_user_plan_message(
     user: &User,
     is_staff: bool,
     db: &Arc<Database>,
+    config: &Config,
     llm_db: Option<Arc<LlmDatabase>>,
 ) -> Result<proto::UpdateUserPlan> {
     let feature_flags = db.get_user_flags(user.id).await?;
-    let plan = current_plan(db, user.id, is_staff).await?;
+    let plan = current_plan(db, config, user.id, is_staff).await?;
     let billing_customer = db.get_billing_customer_by_user_id(user.id).await?;
     let billing_preferences = db.get_billing_preferences(user.id).await?;
 
     let (subscription_period, usage) = if let Some(llm_db) = llm_db {
-        let subscription = db.get_active_billing_subscription(user.id).await?;
+        let subscription = get_entitled_billing_subscription(db, config, user.id).await?;
 
         let subscription_period =
             crate::db::billing_subscription::Model::current_period(subscription, is_staff);
@@ -2954,6 +2968,7 @@ async fn update_user_plan(session: &Session) -> Result<()> {
         session.principal.user(),
         session.is_staff(),
         &db.0,
+        &session.app_state.config,
         session.app_state.llm_db.clone(),
     )
     .await?;
@@ -4185,8 +4200,22 @@ async fn get_llm_api_token(
         .await?
         .with_context(|| format!("user {user_id} not found"))?;
 
-    if user.accepted_tos_at.is_none() {
-        Err(anyhow!("terms of service not accepted"))?
+    let existing_billing_customer = db.get_billing_customer_by_user_id(user.id).await?;
+    let active_subscription =
+        get_entitled_billing_subscription(&db, &session.app_state.config, user.id).await?;
+    let latest_subscription = db.get_billing_subscriptions(user.id).await?.pop();
+    if let Some(reason) = llm_access_blocked_reason(
+        &session.app_state.config,
+        &user,
+        session.is_staff(),
+        &flags,
+        existing_billing_customer.as_ref(),
+        active_subscription.as_ref(),
+        latest_subscription.as_ref(),
+    )
+    .filter(|reason| reason.denies_llm_token())
+    {
+        Err(anyhow!(reason.message()))?
     }
 
     let stripe_client = session
@@ -4201,13 +4230,11 @@ async fn get_llm_api_token(
         .as_ref()
         .context("failed to retrieve Stripe billing object")?;
 
-    let billing_customer = if let Some(billing_customer) =
-        db.get_billing_customer_by_user_id(user.id).await?
-    {
+    let billing_customer = if let Some(billing_customer) = existing_billing_customer {
         billing_customer
     } else {
         let customer_id = stripe_billing
-            .find_or_create_customer_by_email(user.email_address.as_deref())
+            .find_or_create_customer_by_zed_user(user.id, user.email_address.as_deref())
             .await?;
 
         find_or_create_billing_customer(&session.app_state, stripe_client.as_ref(), &customer_id)
@@ -4215,28 +4242,31 @@ async fn get_llm_api_token(
             .context("billing customer not found")?
     };
 
-    let billing_subscription =
-        if let Some(billing_subscription) = db.get_active_billing_subscription(user.id).await? {
-            billing_subscription
-        } else {
-            let stripe_customer_id =
-                StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
-
-            let stripe_subscription = stripe_billing
-                .subscribe_to_zed_free(stripe_customer_id)
-                .await?;
-
-            db.create_billing_subscription(&db::CreateBillingSubscriptionParams {
-                billing_customer_id: billing_customer.id,
-                kind: Some(SubscriptionKind::ZedFree),
-                stripe_subscription_id: stripe_subscription.id.to_string(),
-                stripe_subscription_status: stripe_subscription.status.into(),
-                stripe_cancellation_reason: None,
-                stripe_current_period_start: Some(stripe_subscription.current_period_start),
-                stripe_current_period_end: Some(stripe_subscription.current_period_end),
-            })
-            .await?
-        };
+    let billing_subscription = if let Some(billing_subscription) = active_subscription {
+        billing_subscription
+    } else {
+        let stripe_customer_id =
+            StripeCustomerId(billing_customer.stripe_customer_id.clone().into());
+
+        let stripe_subscription = stripe_billing
+            .subscribe_to_zed_free(stripe_customer_id)
+            .await?;
+
+        db.create_billing_subscription(&db::CreateBillingSubscriptionParams {
+            billing_customer_id: billing_customer.id,
+            kind: Some(SubscriptionKind::ZedFree),
+            unclassified: false,
+            stripe_subscription_id: stripe_subscription.id.to_string(),
+            stripe_subscription_status: stripe_subscription.status.into(),
+            stripe_cancellation_reason: None,
+            stripe_current_period_start: Some(stripe_subscription.current_period_start),
+            stripe_current_period_end: Some(stripe_subscription.current_period_end),
+            seats: 1,
+            monthly_recurring_revenue_in_cents: None,
+            ended_at: None,
+        })
+        .await?
+    };
 
     let billing_preferences = db.get_billing_preferences(user.id).await?;
 
@@ -1,28 +1,69 @@
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context as _, anyhow};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use collections::HashMap;
+use sha2::{Digest as _, Sha256};
 use stripe::SubscriptionStatus;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, RwLockReadGuard};
 use uuid::Uuid;
 
 use crate::Result;
+use crate::db::UserId;
 use crate::db::billing_subscription::SubscriptionKind;
 use crate::llm::AGENT_EXTENDED_TRIAL_FEATURE_FLAG;
 use crate::stripe_client::{
     RealStripeClient, StripeBillingAddressCollection, StripeCheckoutSessionMode,
     StripeCheckoutSessionPaymentMethodCollection, StripeClient,
-    StripeCreateCheckoutSessionLineItems, StripeCreateCheckoutSessionParams,
-    StripeCreateCheckoutSessionSubscriptionData, StripeCreateMeterEventParams,
-    StripeCreateMeterEventPayload, StripeCreateSubscriptionItems, StripeCreateSubscriptionParams,
-    StripeCustomerId, StripeCustomerUpdate, StripeCustomerUpdateAddress, StripeCustomerUpdateName,
-    StripeMeter, StripePrice, StripePriceId, StripeSubscription, StripeSubscriptionId,
-    StripeSubscriptionTrialSettings, StripeSubscriptionTrialSettingsEndBehavior,
+    StripeCreateCheckoutSessionDiscounts, StripeCreateCheckoutSessionLineItems,
+    StripeCreateCheckoutSessionParams, StripeCreateCheckoutSessionSubscriptionData,
+    StripeCreateCustomerBalanceTransactionParams, StripeCreateMeterEventParams,
+    StripeCreateMeterEventPayload, StripeCreateRefundParams, StripeCreateSubscriptionItems,
+    StripeCreateSubscriptionParams, StripeCustomerBalanceTransaction, StripeCustomerId,
+    StripeCustomerUpdate, StripeCustomerUpdateAddress, StripeCustomerUpdateName,
+    StripeIncompleteSubscription, StripeMeter, StripePrice, StripePriceId, StripePromotionCodeId,
+    StripeSubscription, StripeSubscriptionId, StripeSubscriptionTrialSettings,
+    StripeSubscriptionTrialSettingsEndBehavior,
     StripeSubscriptionTrialSettingsEndBehaviorMissingPaymentMethod, StripeTaxIdCollection,
     UpdateSubscriptionItems, UpdateSubscriptionParams,
 };
 
+/// The key in a Stripe customer's metadata that holds the ID of the corresponding Zed user.
+pub const ZED_USER_ID_METADATA_KEY: &str = "zed_user_id";
+
+/// How often Zed Pro is billed.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ZedProBillingInterval {
+    Monthly,
+    /// Billed yearly, at a discount to paying monthly.
+    Annual,
+}
+
+impl ZedProBillingInterval {
+    /// Returns the lookup key of the Zed Pro price that is billed at this interval.
+    pub fn price_lookup_key(&self) -> &'static str {
+        match self {
+            Self::Monthly => "zed-pro",
+            Self::Annual => "zed-pro-annual",
+        }
+    }
+}
+
+/// A usage meter event that was reported to Stripe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StripeMeterEventReport {
+    /// The identifier of the meter event, which Stripe uses to deduplicate it.
+    pub identifier: String,
+    /// The Unix timestamp of the meter event.
+    pub timestamp: i64,
+}
+
+/// How long we use the prices that we loaded from Stripe before loading them again.
+///
+/// Prices rarely change, so this lets us pick up changes without a restart and without fetching them for every lookup.
+const PRICE_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+
 pub struct StripeBilling {
     state: RwLock<StripeBillingState>,
     client: Arc<dyn StripeClient>,
@@ -33,6 +74,38 @@ struct StripeBillingState {
     meters_by_event_name: HashMap<String, StripeMeter>,
     price_ids_by_meter_id: HashMap<String, StripePriceId>,
     prices_by_lookup_key: HashMap<String, StripePrice>,
+    prices_by_id: HashMap<StripePriceId, StripePrice>,
+    /// When we last loaded the prices from Stripe, or `None` if they need to be loaded on the next lookup.
+    prices_loaded_at: Option<Instant>,
+}
+
+impl StripeBillingState {
+    fn are_prices_stale(&self) -> bool {
+        self.prices_loaded_at
+            .is_none_or(|loaded_at| loaded_at.elapsed() >= PRICE_CACHE_TTL)
+    }
+
+    fn set_prices(&mut self, prices: Vec<StripePrice>) {
+        self.prices_by_lookup_key.clear();
+        self.prices_by_id.clear();
+        self.price_ids_by_meter_id.clear();
+
+        for price in prices {
+            if let Some(lookup_key) = price.lookup_key.clone() {
+                self.prices_by_lookup_key.insert(lookup_key, price.clone());
+            }
+
+            self.prices_by_id.insert(price.id.clone(), price.clone());
+
+            if let Some(recurring) = price.recurring {
+                if let Some(meter) = recurring.meter {
+                    self.price_ids_by_meter_id.insert(meter, price.id);
+                }
+            }
+        }
+
+        self.prices_loaded_at = Some(Instant::now());
+    }
 }
 
 impl StripeBilling {
@@ -69,25 +142,52 @@ impl StripeBilling {
                 .insert(meter.event_name.clone(), meter);
         }
 
-        for price in prices {
-            if let Some(lookup_key) = price.lookup_key.clone() {
-                state.prices_by_lookup_key.insert(lookup_key, price.clone());
-            }
-
-            if let Some(recurring) = price.recurring {
-                if let Some(meter) = recurring.meter {
-                    state.price_ids_by_meter_id.insert(meter, price.id);
-                }
-            }
-        }
+        state.set_prices(prices);
 
         log::info!("StripeBilling: initialized");
 
         Ok(())
     }
 
+    /// Makes the next price lookup load the prices from Stripe again, e.g., after a price was changed.
+    pub async fn clear_price_cache(&self) {
+        self.state.write().await.prices_loaded_at = None;
+    }
+
+    /// Returns the state, first loading the prices from Stripe again if they're older than [`PRICE_CACHE_TTL`].
+    ///
+    /// When loading the prices fails, we keep using the ones we have rather than failing the lookup.
+    async fn state_with_fresh_prices(&self) -> RwLockReadGuard<'_, StripeBillingState> {
+        let state = self.state.read().await;
+        if !state.are_prices_stale() {
+            return state;
+        }
+        drop(state);
+
+        let mut state = self.state.write().await;
+        // Another lookup may have loaded the prices while we were waiting for the lock.
+        if state.are_prices_stale() {
+            match self.client.list_prices().await {
+                Ok(prices) => state.set_prices(prices),
+                Err(error) => {
+                    log::error!("StripeBilling: failed to load prices: {error:?}");
+                    // We don't try again until the TTL has passed, so that every lookup doesn't hit Stripe while
+                    // it's failing.
+                    state.prices_loaded_at = Some(Instant::now());
+                }
+            }
+        }
+        state.downgrade()
+    }
+
     pub async fn zed_pro_price_id(&self) -> Result<StripePriceId> {
-        self.find_price_id_by_lookup_key("zed-pro").await
+        self.find_price_id_by_lookup_key(ZedProBillingInterval::Monthly.price_lookup_key())
+            .await
+    }
+
+    pub async fn zed_pro_annual_price_id(&self) -> Result<StripePriceId> {
+        self.find_price_id_by_lookup_key(ZedProBillingInterval::Annual.price_lookup_key())
+            .await
     }
 
     pub async fn zed_free_price_id(&self) -> Result<StripePriceId> {
@@ -95,8 +195,7 @@ impl StripeBilling {
     }
 
     pub async fn find_price_id_by_lookup_key(&self, lookup_key: &str) -> Result<StripePriceId> {
-        self.state
-            .read()
+        self.state_with_fresh_prices()
             .await
             .prices_by_lookup_key
             .get(lookup_key)
@@ -104,9 +203,18 @@ impl StripeBilling {
             .ok_or_else(|| crate::Error::Internal(anyhow!("no price ID found for {lookup_key:?}")))
     }
 
+    /// Returns all of the prices that have a lookup key.
+    pub async fn prices(&self) -> Vec<StripePrice> {
+        self.state_with_fresh_prices()
+            .await
+            .prices_by_lookup_key
+            .values()
+            .cloned()
+            .collect()
+    }
+
     pub async fn find_price_by_lookup_key(&self, lookup_key: &str) -> Result<StripePrice> {
-        self.state
-            .read()
+        self.state_with_fresh_prices()
             .await
             .prices_by_lookup_key
             .get(lookup_key)
@@ -114,17 +222,30 @@ impl StripeBilling {
             .ok_or_else(|| crate::Error::Internal(anyhow!("no price found for {lookup_key:?}")))
     }
 
+    /// Returns the price with the given ID, including prices that don't have a lookup key (e.g., custom prices for
+    /// individual customers).
+    pub async fn find_price_by_id(&self, price_id: &StripePriceId) -> Result<StripePrice> {
+        self.state_with_fresh_prices()
+            .await
+            .prices_by_id
+            .get(price_id)
+            .cloned()
+            .ok_or_else(|| crate::Error::Internal(anyhow!("no price found for {price_id}")))
+    }
+
     pub async fn determine_subscription_kind(
         &self,
         subscription: &StripeSubscription,
     ) -> Option<SubscriptionKind> {
         let zed_pro_price_id = self.zed_pro_price_id().await.ok()?;
         let zed_free_price_id = self.zed_free_price_id().await.ok()?;
+        // Not every environment offers annual billing, so we don't require the annual price to exist.
+        let zed_pro_annual_price_id = self.zed_pro_annual_price_id().await.ok();
 
         subscription.items.iter().find_map(|item| {
             let price = item.price.as_ref()?;
 
-            if price.id == zed_pro_price_id {
+            if price.id == zed_pro_price_id || Some(&price.id) == zed_pro_annual_price_id.as_ref() {
                 Some(if subscription.status == SubscriptionStatus::Trialing {
                     SubscriptionKind::ZedProTrial
                 } else {
@@ -161,6 +282,7 @@ impl StripeBilling {
                 .client
                 .create_customer(crate::stripe_client::CreateCustomerParams {
                     email: email_address,
+                    ..Default::default()
                 })
                 .await?;
 
@@ -170,6 +292,52 @@ impl StripeBilling {
         Ok(customer_id)
     }
 
+    /// Returns the Stripe customer associated with the provided Zed user, or creates a new customer, if one does not
+    /// already exist.
+    ///
+    /// Customers are looked up by the `zed_user_id` in their metadata, which is stamped onto the customer when it is
+    /// created.
+    pub async fn find_or_create_customer_by_zed_user(
+        &self,
+        user_id: UserId,
+        email_address: Option<&str>,
+    ) -> Result<StripeCustomerId> {
+        let user_id = user_id.to_string();
+
+        let customers = self
+            .client
+            .search_customers_by_metadata(ZED_USER_ID_METADATA_KEY, &user_id)
+            .await?;
+        if let Some(existing_customer) = customers.first() {
+            return Ok(existing_customer.id.clone());
+        }
+
+        // Stripe's search index can lag behind customer creation, so we also pass an idempotency key to ensure that
+        // concurrent requests for the same user don't each create a customer.
+        //
+        // Stripe rejects a key that is reused with different parameters, so the key includes the email address, which
+        // can change between requests. We hash it to keep the key short.
+        let email_hash = hex::encode(Sha256::digest(email_address.unwrap_or_default().as_bytes()));
+        let idempotency_key = format!(
+            "create-customer-for-zed-user-{user_id}-{}",
+            &email_hash[..16]
+        );
+        let customer = self
+            .client
+            .create_customer(crate::stripe_client::CreateCustomerParams {
+                email: email_address,
+                metadata: Some(
+                    [(ZED_USER_ID_METADATA_KEY.to_string(), user_id)]
+                        .into_iter()
+                        .collect(),
+                ),
+                idempotency_key: Some(&idempotency_key),
+            })
+            .await?;
+
+        Ok(customer.id)
+    }
+
     pub async fn subscribe_to_price(
         &self,
         subscription_id: &StripeSubscriptionId,
@@ -186,11 +354,19 @@ impl StripeBilling {
         let price_per_unit = price.unit_amount.unwrap_or_default();
         let _units_for_billing_threshold = BILLING_THRESHOLD_IN_CENTS / price_per_unit;
 
+        // The key changes whenever the subscription's items do, so that a retry doesn't add the price twice.
+        let idempotency_key = format!(
+            "subscribe-{subscription_id}-to-{}-with-{}-items",
+            price.id,
+            subscription.items.len()
+        );
+
         self.client
             .update_subscription(
                 subscription_id,
                 UpdateSubscriptionParams {
                     items: Some(vec![UpdateSubscriptionItems {
+                        id: None,
                         price: Some(price.id.clone()),
                     }]),
                     trial_settings: Some(StripeSubscriptionTrialSettings {
@@ -198,6 +374,8 @@ impl StripeBilling {
                             missing_payment_method: StripeSubscriptionTrialSettingsEndBehaviorMissingPaymentMethod::Cancel
                         },
                     }),
+                    idempotency_key: Some(idempotency_key),
+                    ..Default::default()
                 },
             )
             .await?;
@@ -205,37 +383,253 @@ impl StripeBilling {
         Ok(())
     }
 
+    /// Reports the model requests to Stripe.
+    ///
+    /// Repeated reports with the same idempotency key are only counted once.
     pub async fn bill_model_request_usage(
         &self,
         customer_id: &StripeCustomerId,
         event_name: &str,
         requests: i32,
-    ) -> Result<()> {
+        idempotency_key: Option<&str>,
+    ) -> Result<StripeMeterEventReport> {
+        self.create_usage_meter_event(
+            "model_requests",
+            customer_id,
+            event_name,
+            requests,
+            idempotency_key,
+        )
+        .await
+    }
+
+    /// Reports the edit predictions to Stripe.
+    ///
+    /// Repeated reports with the same idempotency key are only counted once.
+    pub async fn bill_edit_prediction_usage(
+        &self,
+        customer_id: &StripeCustomerId,
+        event_name: &str,
+        edit_predictions: i32,
+        idempotency_key: Option<&str>,
+    ) -> Result<StripeMeterEventReport> {
+        self.create_usage_meter_event(
+            "edit_predictions",
+            customer_id,
+            event_name,
+            edit_predictions,
+            idempotency_key,
+        )
+        .await
+    }
+
+    async fn create_usage_meter_event(
+        &self,
+        identifier_prefix: &str,
+        customer_id: &StripeCustomerId,
+        event_name: &str,
+        value: i32,
+        idempotency_key: Option<&str>,
+    ) -> Result<StripeMeterEventReport> {
+        // A negative value can only come from a bug on our end, and reporting it would corrupt the customer's bill.
+        if value < 0 {
+            return Err(crate::Error::Internal(anyhow!(
+                "refusing to report negative {identifier_prefix} usage of {value} for {customer_id}"
+            )));
+        }
+
+        // Stripe rejects a repeated idempotency key with different parameters, so when we have one, we derive the
+        // identifier from it and let Stripe timestamp the event when it first receives it.
         let timestamp = Utc::now().timestamp();
-        let idempotency_key = Uuid::new_v4();
+        let identifier = match idempotency_key {
+            Some(idempotency_key) => format!("{identifier_prefix}/{idempotency_key}"),
+            None => format!("{identifier_prefix}/{}", Uuid::new_v4()),
+        };
 
         self.client
             .create_meter_event(StripeCreateMeterEventParams {
-                identifier: &format!("model_requests/{}", idempotency_key),
+                identifier: &identifier,
                 event_name,
                 payload: StripeCreateMeterEventPayload {
-                    value: requests as u64,
+                    value: value as u64,
                     stripe_customer_id: customer_id,
                 },
-                timestamp: Some(timestamp),
+                timestamp: idempotency_key.is_none().then_some(timestamp),
+                idempotency_key,
             })
             .await?;
 
-        Ok(())
+        Ok(StripeMeterEventReport {
+            identifier,
+            timestamp,
+        })
+    }
+
+    /// Credits the customer's balance for the given number of requests at the
+    /// price with the given lookup key.
+    ///
+    /// The credit is applied to the customer's next invoice.
+    pub async fn credit_model_request_usage(
+        &self,
+        customer_id: &StripeCustomerId,
+        price_lookup_key: &str,
+        requests: i32,
+        currency: &str,
+        description: &str,
+        idempotency_key: Option<&str>,
+    ) -> Result<StripeCustomerBalanceTransaction> {
+        let price = self.find_price_by_lookup_key(price_lookup_key).await?;
+        self.credit_model_request_usage_at_price(
+            customer_id,
+            &price,
+            requests,
+            currency,
+            description,
+            idempotency_key,
+        )
+        .await
+    }
+
+    /// Credits the customer's balance for the given number of requests at the
+    /// given price.
+    ///
+    /// The credit is applied to the customer's next invoice, so it has to be in
+    /// the currency that the customer is billed in.
+    pub async fn credit_model_request_usage_at_price(
+        &self,
+        customer_id: &StripeCustomerId,
+        price: &StripePrice,
+        requests: i32,
+        currency: &str,
+        description: &str,
+        idempotency_key: Option<&str>,
+    ) -> Result<StripeCustomerBalanceTransaction> {
+        let unit_amount = price.unit_amount.ok_or_else(|| {
+            crate::Error::Internal(anyhow!("price {} has no unit amount", price.id))
+        })?;
+
+        let transaction = self
+            .client
+            .create_customer_balance_transaction(
+                customer_id,
+                StripeCreateCustomerBalanceTransactionParams {
+                    amount: -(unit_amount * requests as i64),
+                    currency,
+                    description: Some(description),
+                    idempotency_key,
+                },
+            )
+            .await?;
+
+        Ok(transaction)
+    }
+
+    /// Refunds the unused portion of what the subscription's latest invoice charged for Zed Pro in the current period,
+    /// prorated by the time left in it.
+    ///
+    /// Only the invoice's Zed Pro lines count towards the refund, as the invoice can also bill the usage from the
+    /// previous period, which the customer has already used. Whatever of the payment has already been refunded is
+    /// subtracted, so that repeated requests don't refund the subscription twice.
+    ///
+    /// Returns the amount of the payment that has been refunded, in cents.
+    pub async fn refund_unused_time(
+        &self,
+        subscription: &StripeSubscription,
+        now: DateTime<Utc>,
+    ) -> Result<i64> {
+        let Some(payment) = self
+            .client
+            .get_latest_invoice_payment(&subscription.id)
+            .await?
+        else {
+            return Ok(0);
+        };
+
+        let zed_pro_price_id = self.zed_pro_price_id().await?;
+        // Not every environment offers annual billing, so we don't require the annual price to exist.
+        let zed_pro_annual_price_id = self.zed_pro_annual_price_id().await.ok();
+        let unused_zed_pro_amount = payment
+            .lines
+            .iter()
+            .filter(|line| {
+                line.price_id.as_ref().is_some_and(|price_id| {
+                    *price_id == zed_pro_price_id
+                        || Some(price_id) == zed_pro_annual_price_id.as_ref()
+                })
+            })
+            .filter(|line| {
+                line.period_start >= subscription.current_period_start
+                    && line.period_end <= subscription.current_period_end
+            })
+            .map(|line| {
+                unused_amount(
+                    line.amount,
+                    line.period_start,
+                    line.period_end,
+                    now.timestamp(),
+                )
+            })
+            .sum::<i64>();
+
+        let amount = unused_zed_pro_amount
+            .min(payment.amount_paid)
+            .saturating_sub(payment.amount_refunded);
+        if amount <= 0 {
+            return Ok(payment.amount_refunded);
+        }
+
+        let idempotency_key = format!(
+            "refund-unused-{}-{}-{}",
+            subscription.id, payment.payment_intent_id, payment.amount_refunded
+        );
+        let refund = self
+            .client
+            .create_refund(StripeCreateRefundParams {
+                payment_intent_id: &payment.payment_intent_id,
+                amount: Some(amount),
+                idempotency_key: Some(&idempotency_key),
+            })
+            .await?;
+
+        Ok(payment.amount_refunded + refund.amount)
+    }
+
+    /// Returns the ID of the promotion code that customers enter as `code`, if it can still be redeemed.
+    pub async fn find_redeemable_promotion_code(
+        &self,
+        code: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<StripePromotionCodeId>> {
+        let Some(promotion_code) = self.client.find_promotion_code(code).await? else {
+            return Ok(None);
+        };
+
+        let expired = promotion_code
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= now.timestamp());
+        let used_up = promotion_code
+            .max_redemptions
+            .is_some_and(|max_redemptions| promotion_code.times_redeemed >= max_redemptions);
+        if expired || used_up {
+            return Ok(None);
+        }
+
+        Ok(Some(promotion_code.id))
     }
 
     pub async fn checkout_with_zed_pro(
         &self,
         customer_id: &StripeCustomerId,
         github_login: &str,
+        billing_interval: ZedProBillingInterval,
+        seats: u32,
         success_url: &str,
+        promotion_code: Option<&StripePromotionCodeId>,
+        idempotency_key: Option<&str>,
     ) -> Result<String> {
-        let zed_pro_price_id = self.zed_pro_price_id().await?;
+        let zed_pro_price_id = self
+            .find_price_id_by_lookup_key(billing_interval.price_lookup_key())
+            .await?;
 
         let mut params = StripeCreateCheckoutSessionParams::default();
         params.mode = Some(StripeCheckoutSessionMode::Subscription);
@@ -243,7 +637,7 @@ impl StripeBilling {
         params.client_reference_id = Some(github_login);
         params.line_items = Some(vec![StripeCreateCheckoutSessionLineItems {
             price: Some(zed_pro_price_id.to_string()),
-            quantity: Some(1),
+            quantity: Some(seats.into()),
         }]);
         params.success_url = Some(success_url);
         params.billing_address_collection = Some(StripeBillingAddressCollection::Required);
@@ -253,17 +647,51 @@ impl StripeBilling {
             shipping: None,
         });
         params.tax_id_collection = Some(StripeTaxIdCollection { enabled: true });
+        params.discounts = promotion_code.map(|promotion_code| {
+            vec![StripeCreateCheckoutSessionDiscounts {
+                promotion_code: Some(promotion_code.to_string()),
+            }]
+        });
+        params.idempotency_key = idempotency_key;
 
         let session = self.client.create_checkout_session(params).await?;
         Ok(session.url.context("no checkout session URL")?)
     }
 
+    /// Subscribes the customer to Zed Pro, with the first payment confirmed in the client instead of through Stripe
+    /// Checkout.
+    ///
+    /// The subscription stays incomplete until the payment succeeds.
+    pub async fn subscribe_to_zed_pro_with_payment_intent(
+        &self,
+        customer_id: StripeCustomerId,
+        idempotency_key: Option<&str>,
+    ) -> Result<StripeIncompleteSubscription> {
+        let zed_pro_price_id = self.zed_pro_price_id().await?;
+
+        let params = StripeCreateSubscriptionParams {
+            customer: customer_id,
+            items: vec![StripeCreateSubscriptionItems {
+                price: Some(zed_pro_price_id),
+                quantity: Some(1),
+            }],
+            idempotency_key: idempotency_key.map(|key| key.to_string()),
+        };
+
+        let subscription = self.client.create_incomplete_subscription(params).await?;
+        Ok(subscription)
+    }
+
     pub async fn checkout_with_zed_pro_trial(
         &self,
         customer_id: &StripeCustomerId,
         github_login: &str,
         feature_flags: Vec<String>,
+        trial_period_days: u32,
+        extended_trial_period_days: u32,
+        payment_method_collection: StripeCheckoutSessionPaymentMethodCollection,
         success_url: &str,
+        idempotency_key: Option<&str>,
     ) -> Result<String> {
         let zed_pro_price_id = self.zed_pro_price_id().await?;
 
@@ -271,7 +699,11 @@ impl StripeBilling {
             .iter()
             .any(|flag| flag == AGENT_EXTENDED_TRIAL_FEATURE_FLAG);
 
-        let trial_period_days = if eligible_for_extended_trial { 60 } else { 14 };
+        let trial_period_days = if eligible_for_extended_trial {
+            extended_trial_period_days
+        } else {
+            trial_period_days
+        };
 
         let mut subscription_metadata = std::collections::HashMap::new();
         if eligible_for_extended_trial {
@@ -297,8 +729,7 @@ impl StripeBilling {
             },
         });
         params.mode = Some(StripeCheckoutSessionMode::Subscription);
-        params.payment_method_collection =
-            Some(StripeCheckoutSessionPaymentMethodCollection::IfRequired);
+        params.payment_method_collection = Some(payment_method_collection);
         params.customer = Some(customer_id);
         params.client_reference_id = Some(github_login);
         params.line_items = Some(vec![StripeCreateCheckoutSessionLineItems {
@@ -313,6 +744,7 @@ impl StripeBilling {
             shipping: None,
         });
         params.tax_id_collection = Some(StripeTaxIdCollection { enabled: true });
+        params.idempotency_key = idempotency_key;
 
         let session = self.client.create_checkout_session(params).await?;
         Ok(session.url.context("no checkout session URL")?)
@@ -329,6 +761,13 @@ impl StripeBilling {
             .list_subscriptions_for_customer(&customer_id)
             .await?;
 
+        // The key changes whenever the customer gets a new subscription, so that concurrent requests only create one
+        // Zed Free subscription, while a customer who later cancels it can still be subscribed again.
+        let idempotency_key = format!(
+            "subscribe-{customer_id}-to-zed-free-after-{}-subscriptions",
+            existing_subscriptions.len()
+        );
+
         let existing_active_subscription =
             existing_subscriptions.into_iter().find(|subscription| {
                 subscription.status == SubscriptionStatus::Active
@@ -344,6 +783,7 @@ impl StripeBilling {
                 price: Some(zed_free_price_id),
                 quantity: Some(1),
             }],
+            idempotency_key: Some(idempotency_key),
         };
 
         let subscription = self.client.create_subscription(params).await?;
@@ -352,6 +792,18 @@ impl StripeBilling {
     }
 }
 
+/// Returns the portion of the amount charged for a period that covers the rest of the period after `now`, rounded
+/// towards zero to the cent.
+fn unused_amount(amount_paid: i64, period_start: i64, period_end: i64, now: i64) -> i64 {
+    let period = period_end - period_start;
+    if period <= 0 {
+        return 0;
+    }
+
+    let unused = (period_end - now).clamp(0, period);
+    (i128::from(amount_paid) * i128::from(unused) / i128::from(period)) as i64
+}
+
 fn subscription_contains_price(
     subscription: &StripeSubscription,
     price_id: &StripePriceId,
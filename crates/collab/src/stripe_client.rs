@@ -20,16 +20,165 @@ pub struct StripeCustomerId(pub Arc<str>);
 pub struct StripeCustomer {
     pub id: StripeCustomerId,
     pub email: Option<String>,
+    pub metadata: HashMap<String, String>,
+    /// Whether the customer has a default payment method for invoices.
+    pub has_default_payment_method: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct CreateCustomerParams<'a> {
     pub email: Option<&'a str>,
+    pub metadata: Option<HashMap<String, String>>,
+    /// The idempotency key to send with the request.
+    ///
+    /// Stripe returns the originally-created customer for repeated requests
+    /// with the same key, rather than creating a new one.
+    pub idempotency_key: Option<&'a str>,
 }
 
 #[derive(Debug)]
 pub struct UpdateCustomerParams<'a> {
     pub email: Option<&'a str>,
+    pub tax_exempt: Option<StripeTaxExempt>,
+    /// A tax ID to add to the customer, so that it appears on their invoices.
+    pub tax_id: Option<&'a StripeTaxId>,
+    /// The idempotency key to send with the request.
+    pub idempotency_key: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StripeTaxExempt {
+    None,
+    Exempt,
+    Reverse,
+}
+
+/// The kinds of tax ID that we accept from customers.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StripeTaxIdKind {
+    EuVat,
+    GbVat,
+    AuAbn,
+    CaBn,
+    InGst,
+}
+
+impl StripeTaxIdKind {
+    /// Returns the tax ID type, as Stripe names it.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::EuVat => "eu_vat",
+            Self::GbVat => "gb_vat",
+            Self::AuAbn => "au_abn",
+            Self::CaBn => "ca_bn",
+            Self::InGst => "in_gst",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StripeTaxId {
+    pub kind: StripeTaxIdKind,
+    pub value: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, derive_more::Display)]
+pub struct StripeRefundId(pub Arc<str>);
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripeRefund {
+    pub id: StripeRefundId,
+    /// The amount refunded, in cents.
+    pub amount: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, derive_more::Display)]
+pub struct StripePaymentIntentId(pub Arc<str>);
+
+/// The payment for an invoice.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripeInvoicePayment {
+    pub payment_intent_id: StripePaymentIntentId,
+    /// The amount paid, in cents.
+    pub amount_paid: i64,
+    /// The amount of the payment that has been refunded so far, in cents.
+    pub amount_refunded: i64,
+    /// The lines of the invoice that was paid.
+    pub lines: Vec<StripeInvoiceLine>,
+}
+
+/// A line on an invoice.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripeInvoiceLine {
+    pub price_id: Option<StripePriceId>,
+    /// The amount of the line, in cents, which is negative for credits.
+    pub amount: i64,
+    /// The start of the period that the line covers, as a Unix timestamp.
+    pub period_start: i64,
+    /// The end of the period that the line covers, as a Unix timestamp.
+    pub period_end: i64,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, derive_more::Display)]
+pub struct StripeInvoiceId(pub Arc<str>);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StripeInvoiceStatus {
+    Draft,
+    Open,
+    Paid,
+    Uncollectible,
+    Void,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripeInvoice {
+    pub id: StripeInvoiceId,
+    /// The number shown on the invoice, which is only assigned once the invoice is finalized.
+    pub number: Option<String>,
+    /// The total of the invoice, in the smallest unit of its currency.
+    pub total: i64,
+    pub currency: String,
+    pub status: Option<StripeInvoiceStatus>,
+    pub period_start: i64,
+    pub period_end: i64,
+    pub created: i64,
+    /// The URL of the page where the customer can view and pay the invoice.
+    pub hosted_invoice_url: Option<String>,
+    /// The URL of the invoice's PDF.
+    pub invoice_pdf: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct StripeCreateRefundParams<'a> {
+    pub payment_intent_id: &'a StripePaymentIntentId,
+    /// The amount to refund, in cents.
+    ///
+    /// When `None`, the rest of the payment is refunded.
+    pub amount: Option<i64>,
+    /// The idempotency key to send with the request.
+    pub idempotency_key: Option<&'a str>,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, derive_more::Display)]
+pub struct StripePaymentMethodId(pub Arc<str>);
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripePaymentMethod {
+    pub id: StripePaymentMethodId,
+    /// The card details, if the payment method is a card.
+    pub card: Option<StripeCard>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripeCard {
+    pub brand: String,
+    pub last4: String,
+    pub exp_month: u32,
+    pub exp_year: i32,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, derive_more::Display)]
@@ -45,7 +194,20 @@ pub struct StripeSubscription {
     pub current_period_start: i64,
     pub items: Vec<StripeSubscriptionItem>,
     pub cancel_at: Option<i64>,
+    pub cancel_at_period_end: bool,
     pub cancellation_details: Option<StripeCancellationDetails>,
+    pub discount: Option<StripeDiscount>,
+    /// The currency that the subscription is billed in, as a lowercase ISO code (e.g., `usd`).
+    pub currency: String,
+    /// Set while collecting payment for the subscription is paused.
+    pub pause_collection: Option<StripePauseCollection>,
+}
+
+/// How collecting payment for a subscription is paused.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StripePauseCollection {
+    /// When the subscription resumes on its own, as a Unix timestamp, if ever.
+    pub resumes_at: Option<i64>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, derive_more::Display)]
@@ -55,6 +217,21 @@ pub struct StripeSubscriptionItemId(pub Arc<str>);
 pub struct StripeSubscriptionItem {
     pub id: StripeSubscriptionItemId,
     pub price: Option<StripePrice>,
+    /// The number of units of the price (e.g., seats), which metered prices don't have.
+    pub quantity: Option<u64>,
+}
+
+/// A discount applied to a subscription through a coupon or promotion code.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripeDiscount {
+    pub coupon_name: Option<String>,
+    /// The customer-facing promotion code, if the discount was applied with one.
+    pub promotion_code: Option<String>,
+    pub percent_off: Option<f64>,
+    /// The amount that is taken off, in cents.
+    pub amount_off: Option<i64>,
+    /// When the discount ends, or `None` if it applies forever.
+    pub end: Option<i64>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -73,6 +250,19 @@ pub enum StripeCancellationDetailsReason {
 pub struct StripeCreateSubscriptionParams {
     pub customer: StripeCustomerId,
     pub items: Vec<StripeCreateSubscriptionItems>,
+    /// The idempotency key to send with the request.
+    ///
+    /// Stripe returns the originally-created subscription for repeated requests with the same key, rather than
+    /// creating a new one.
+    pub idempotency_key: Option<String>,
+}
+
+/// A subscription that remains incomplete until the payment for its first invoice is confirmed.
+#[derive(Debug, Clone)]
+pub struct StripeIncompleteSubscription {
+    pub subscription: StripeSubscription,
+    /// The client secret of the first invoice's payment intent, used to confirm the payment in the client.
+    pub client_secret: String,
 }
 
 #[derive(Debug)]
@@ -81,17 +271,65 @@ pub struct StripeCreateSubscriptionItems {
     pub quantity: Option<u64>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Default, Clone)]
 pub struct UpdateSubscriptionParams {
     pub items: Option<Vec<UpdateSubscriptionItems>>,
     pub trial_settings: Option<StripeSubscriptionTrialSettings>,
+    pub cancel_at_period_end: Option<bool>,
+    pub proration_behavior: Option<StripeProrationBehavior>,
+    /// When the subscription's trial ends, as a Unix timestamp.
+    pub trial_end: Option<i64>,
+    /// The idempotency key to send with the request.
+    pub idempotency_key: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct UpdateSubscriptionItems {
+    /// The ID of the existing subscription item to update.
+    ///
+    /// When `None`, a new item is added to the subscription.
+    pub id: Option<StripeSubscriptionItemId>,
     pub price: Option<StripePriceId>,
 }
 
+/// How Stripe should handle prorations when a subscription is updated.
+///
+/// [Stripe docs](https://docs.stripe.com/api/subscriptions/update#update_subscription-proration_behavior)
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StripeProrationBehavior {
+    CreateProrations,
+    None,
+}
+
+/// The parameters for previewing a subscription update.
+///
+/// [Stripe docs](https://docs.stripe.com/api/invoices/upcoming)
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripePreviewSubscriptionUpdateParams {
+    pub items: Vec<UpdateSubscriptionItems>,
+    pub proration_behavior: StripeProrationBehavior,
+    /// The time at which the proration is calculated.
+    ///
+    /// When `None`, Stripe uses the current time.
+    pub proration_date: Option<i64>,
+}
+
+/// A preview of what a customer will be charged after a subscription update.
+///
+/// Amounts are in the smallest unit of `currency` (e.g., cents).
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StripeSubscriptionUpdatePreview {
+    /// The one-time amount for the unused and newly-added time in the current period.
+    ///
+    /// This is negative when the customer is credited.
+    pub proration_amount: i64,
+    /// The amount the customer will be charged each period after the update.
+    pub recurring_amount: i64,
+    pub currency: String,
+    /// When the customer will next be charged.
+    pub next_charge_at: i64,
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct StripeSubscriptionTrialSettings {
     pub end_behavior: StripeSubscriptionTrialSettingsEndBehavior,
@@ -122,9 +360,30 @@ pub struct StripePrice {
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct StripePriceRecurring {
+    pub interval: StripePriceRecurringInterval,
+    pub interval_count: u64,
     pub meter: Option<String>,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StripePriceRecurringInterval {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+impl StripePriceRecurringInterval {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Day => "day",
+            Self::Week => "week",
+            Self::Month => "month",
+            Self::Year => "year",
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, derive_more::Display, Deserialize)]
 pub struct StripeMeterId(pub Arc<str>);
 
@@ -140,6 +399,12 @@ pub struct StripeCreateMeterEventParams<'a> {
     pub event_name: &'a str,
     pub payload: StripeCreateMeterEventPayload<'a>,
     pub timestamp: Option<i64>,
+    /// The idempotency key to send with the request.
+    ///
+    /// Stripe only accepts a repeated key for a request with the same parameters, so requests with a key should
+    /// have a deterministic `identifier`, and leave the `timestamp` to Stripe.
+    #[serde(skip)]
+    pub idempotency_key: Option<&'a str>,
 }
 
 #[derive(Debug, Serialize)]
@@ -148,6 +413,28 @@ pub struct StripeCreateMeterEventPayload<'a> {
     pub stripe_customer_id: &'a StripeCustomerId,
 }
 
+#[derive(Debug, Serialize)]
+pub struct StripeCreateCustomerBalanceTransactionParams<'a> {
+    /// The amount, in cents. Negative amounts are credited to the customer and
+    /// applied to their next invoice.
+    pub amount: i64,
+    pub currency: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<&'a str>,
+    /// The idempotency key to send with the request.
+    ///
+    /// Stripe returns the originally-created transaction for repeated requests with the same key, rather than
+    /// crediting the customer again.
+    #[serde(skip)]
+    pub idempotency_key: Option<&'a str>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StripeCustomerBalanceTransaction {
+    pub id: String,
+    pub amount: i64,
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum StripeBillingAddressCollection {
     Auto,
@@ -191,6 +478,9 @@ pub struct StripeCreateCheckoutSessionParams<'a> {
     pub billing_address_collection: Option<StripeBillingAddressCollection>,
     pub customer_update: Option<StripeCustomerUpdate>,
     pub tax_id_collection: Option<StripeTaxIdCollection>,
+    pub discounts: Option<Vec<StripeCreateCheckoutSessionDiscounts>>,
+    /// The idempotency key to send with the request, so that a repeated request returns the same session.
+    pub idempotency_key: Option<&'a str>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -224,6 +514,25 @@ pub struct StripeTaxIdCollection {
     pub enabled: bool,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripeCreateCheckoutSessionDiscounts {
+    pub promotion_code: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq, Hash, Clone, derive_more::Display)]
+pub struct StripePromotionCodeId(pub Arc<str>);
+
+/// A code that customers can enter to redeem a coupon.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StripePromotionCode {
+    pub id: StripePromotionCodeId,
+    pub code: String,
+    pub active: bool,
+    pub expires_at: Option<i64>,
+    pub max_redemptions: Option<i64>,
+    pub times_redeemed: i64,
+}
+
 #[derive(Debug)]
 pub struct StripeCheckoutSession {
     pub url: Option<String>,
@@ -233,6 +542,12 @@ pub struct StripeCheckoutSession {
 pub trait StripeClient: Send + Sync {
     async fn list_customers_by_email(&self, email: &str) -> Result<Vec<StripeCustomer>>;
 
+    async fn search_customers_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<StripeCustomer>>;
+
     async fn get_customer(&self, customer_id: &StripeCustomerId) -> Result<StripeCustomer>;
 
     async fn create_customer(&self, params: CreateCustomerParams<'_>) -> Result<StripeCustomer>;
@@ -243,6 +558,18 @@ pub trait StripeClient: Send + Sync {
         params: UpdateCustomerParams<'_>,
     ) -> Result<StripeCustomer>;
 
+    /// Returns the customer's default payment method for invoices, if they have one.
+    async fn get_default_payment_method(
+        &self,
+        customer_id: &StripeCustomerId,
+    ) -> Result<Option<StripePaymentMethod>>;
+
+    /// Returns the payment methods that the customer has saved.
+    async fn list_payment_methods(
+        &self,
+        customer_id: &StripeCustomerId,
+    ) -> Result<Vec<StripePaymentMethod>>;
+
     async fn list_subscriptions_for_customer(
         &self,
         customer_id: &StripeCustomerId,
@@ -258,13 +585,77 @@ pub trait StripeClient: Send + Sync {
         params: StripeCreateSubscriptionParams,
     ) -> Result<StripeSubscription>;
 
+    /// Creates a subscription whose first invoice is paid by confirming its payment intent, rather than through Stripe
+    /// Checkout.
+    async fn create_incomplete_subscription(
+        &self,
+        params: StripeCreateSubscriptionParams,
+    ) -> Result<StripeIncompleteSubscription>;
+
     async fn update_subscription(
         &self,
         subscription_id: &StripeSubscriptionId,
         params: UpdateSubscriptionParams,
     ) -> Result<()>;
 
-    async fn cancel_subscription(&self, subscription_id: &StripeSubscriptionId) -> Result<()>;
+    /// Stops the subscription from being canceled, whether it's scheduled to be canceled at the end of the period or at
+    /// a given time, and returns the updated subscription.
+    async fn stop_cancellation(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        idempotency_key: Option<&str>,
+    ) -> Result<StripeSubscription>;
+
+    async fn cancel_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        idempotency_key: Option<&str>,
+    ) -> Result<()>;
+
+    /// Pauses collecting payment for the subscription, voiding its invoices while it's paused.
+    ///
+    /// The subscription resumes on its own at `resumes_at`, as a Unix timestamp, when given. Stripe leaves the
+    /// subscription's status as it was and sets its [`StripeSubscription::pause_collection`], replacing any pause that
+    /// it already had.
+    async fn pause_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        resumes_at: Option<i64>,
+        idempotency_key: Option<&str>,
+    ) -> Result<()>;
+
+    /// Resumes collecting payment for a subscription that was paused with [`StripeClient::pause_subscription`].
+    async fn resume_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        idempotency_key: Option<&str>,
+    ) -> Result<()>;
+
+    /// Refunds the payment for the subscription's latest invoice in full.
+    ///
+    /// Returns `None` if the latest invoice wasn't paid (e.g., because it was for nothing).
+    async fn refund_latest_invoice_payment(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        idempotency_key: Option<&str>,
+    ) -> Result<Option<StripeRefund>>;
+
+    /// Returns the payment for the subscription's latest invoice, along with the invoice's lines.
+    ///
+    /// Returns `None` if the latest invoice wasn't paid.
+    async fn get_latest_invoice_payment(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+    ) -> Result<Option<StripeInvoicePayment>>;
+
+    /// Returns the customer's most recent invoices, newest first, up to `limit` of them.
+    async fn list_invoices_for_customer(
+        &self,
+        customer_id: &StripeCustomerId,
+        limit: u64,
+    ) -> Result<Vec<StripeInvoice>>;
+
+    async fn create_refund(&self, params: StripeCreateRefundParams<'_>) -> Result<StripeRefund>;
 
     async fn list_prices(&self) -> Result<Vec<StripePrice>>;
 
@@ -272,8 +663,31 @@ pub trait StripeClient: Send + Sync {
 
     async fn create_meter_event(&self, params: StripeCreateMeterEventParams<'_>) -> Result<()>;
 
+    async fn create_customer_balance_transaction(
+        &self,
+        customer_id: &StripeCustomerId,
+        params: StripeCreateCustomerBalanceTransactionParams<'_>,
+    ) -> Result<StripeCustomerBalanceTransaction>;
+
+    /// Returns the customer's balance, in cents.
+    ///
+    /// A negative balance is credit that Stripe applies to the customer's next invoices, while a positive balance is
+    /// added to them.
+    async fn get_customer_balance(&self, customer_id: &StripeCustomerId) -> Result<i64>;
+
     async fn create_checkout_session(
         &self,
         params: StripeCreateCheckoutSessionParams<'_>,
     ) -> Result<StripeCheckoutSession>;
+
+    /// Returns the active promotion code that customers enter as `code`, if there is one.
+    async fn find_promotion_code(&self, code: &str) -> Result<Option<StripePromotionCode>>;
+
+    /// Returns what the customer would be charged if the subscription were updated with `params`,
+    /// without updating it.
+    async fn preview_subscription_update(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        params: StripePreviewSubscriptionUpdateParams,
+    ) -> Result<StripeSubscriptionUpdatePreview>;
 }
@@ -8,13 +8,20 @@ use parking_lot::Mutex;
 use uuid::Uuid;
 
 use crate::stripe_client::{
-    CreateCustomerParams, StripeBillingAddressCollection, StripeCheckoutSession,
-    StripeCheckoutSessionMode, StripeCheckoutSessionPaymentMethodCollection, StripeClient,
-    StripeCreateCheckoutSessionLineItems, StripeCreateCheckoutSessionParams,
-    StripeCreateCheckoutSessionSubscriptionData, StripeCreateMeterEventParams,
-    StripeCreateSubscriptionParams, StripeCustomer, StripeCustomerId, StripeCustomerUpdate,
-    StripeMeter, StripeMeterId, StripePrice, StripePriceId, StripeSubscription,
-    StripeSubscriptionId, StripeSubscriptionItem, StripeSubscriptionItemId, StripeTaxIdCollection,
+    CreateCustomerParams, StripeBillingAddressCollection, StripeCancellationDetails,
+    StripeCancellationDetailsReason, StripeCheckoutSession, StripeCheckoutSessionMode,
+    StripeCheckoutSessionPaymentMethodCollection, StripeClient,
+    StripeCreateCheckoutSessionDiscounts, StripeCreateCheckoutSessionLineItems,
+    StripeCreateCheckoutSessionParams, StripeCreateCheckoutSessionSubscriptionData,
+    StripeCreateCustomerBalanceTransactionParams, StripeCreateMeterEventParams,
+    StripeCreateRefundParams, StripeCreateSubscriptionParams, StripeCustomer,
+    StripeCustomerBalanceTransaction, StripeCustomerId, StripeCustomerUpdate,
+    StripeIncompleteSubscription, StripeInvoice, StripeInvoicePayment, StripeMeter, StripeMeterId,
+    StripePauseCollection, StripePaymentIntentId, StripePaymentMethod,
+    StripePreviewSubscriptionUpdateParams, StripePrice, StripePriceId, StripePromotionCode,
+    StripePromotionCodeId, StripeProrationBehavior, StripeRefund, StripeRefundId,
+    StripeSubscription, StripeSubscriptionId, StripeSubscriptionItem, StripeSubscriptionItemId,
+    StripeSubscriptionUpdatePreview, StripeTaxExempt, StripeTaxId, StripeTaxIdCollection,
     UpdateCustomerParams, UpdateSubscriptionParams,
 };
 
@@ -25,6 +32,16 @@ pub struct StripeCreateMeterEventCall {
     pub value: u64,
     pub stripe_customer_id: StripeCustomerId,
     pub timestamp: Option<i64>,
+    pub idempotency_key: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StripeCreateCustomerBalanceTransactionCall {
+    pub customer_id: StripeCustomerId,
+    pub amount: i64,
+    pub currency: String,
+    pub description: Option<String>,
+    pub idempotency_key: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -39,17 +56,45 @@ pub struct StripeCreateCheckoutSessionCall {
     pub billing_address_collection: Option<StripeBillingAddressCollection>,
     pub customer_update: Option<StripeCustomerUpdate>,
     pub tax_id_collection: Option<StripeTaxIdCollection>,
+    pub discounts: Option<Vec<StripeCreateCheckoutSessionDiscounts>>,
+    pub idempotency_key: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct StripeCreateRefundCall {
+    pub payment_intent_id: StripePaymentIntentId,
+    pub amount: Option<i64>,
+    pub idempotency_key: Option<String>,
 }
 
 pub struct FakeStripeClient {
     pub customers: Arc<Mutex<HashMap<StripeCustomerId, StripeCustomer>>>,
     pub subscriptions: Arc<Mutex<HashMap<StripeSubscriptionId, StripeSubscription>>>,
+    pub get_subscription_calls: Arc<Mutex<Vec<StripeSubscriptionId>>>,
+    pub default_payment_methods: Arc<Mutex<HashMap<StripeCustomerId, StripePaymentMethod>>>,
+    pub payment_methods: Arc<Mutex<HashMap<StripeCustomerId, Vec<StripePaymentMethod>>>>,
+    pub refund_latest_invoice_payment_calls: Arc<Mutex<Vec<StripeSubscriptionId>>>,
+    pub latest_invoice_payments: Arc<Mutex<HashMap<StripeSubscriptionId, StripeInvoicePayment>>>,
+    pub invoices: Arc<Mutex<HashMap<StripeCustomerId, Vec<StripeInvoice>>>>,
+    pub create_refund_calls: Arc<Mutex<Vec<StripeCreateRefundCall>>>,
     pub update_subscription_calls:
         Arc<Mutex<Vec<(StripeSubscriptionId, UpdateSubscriptionParams)>>>,
+    pub stop_cancellation_calls: Arc<Mutex<Vec<StripeSubscriptionId>>>,
+    /// The subscriptions that were paused, along with when they resume, if ever.
+    pub pause_subscription_calls: Arc<Mutex<Vec<(StripeSubscriptionId, Option<i64>)>>>,
     pub prices: Arc<Mutex<HashMap<StripePriceId, StripePrice>>>,
     pub meters: Arc<Mutex<HashMap<StripeMeterId, StripeMeter>>>,
     pub create_meter_event_calls: Arc<Mutex<Vec<StripeCreateMeterEventCall>>>,
+    pub customer_balances: Arc<Mutex<HashMap<StripeCustomerId, i64>>>,
+    pub create_customer_balance_transaction_calls:
+        Arc<Mutex<Vec<StripeCreateCustomerBalanceTransactionCall>>>,
     pub create_checkout_session_calls: Arc<Mutex<Vec<StripeCreateCheckoutSessionCall>>>,
+    pub customer_ids_by_idempotency_key: Arc<Mutex<HashMap<String, StripeCustomerId>>>,
+    pub promotion_codes: Arc<Mutex<HashMap<StripePromotionCodeId, StripePromotionCode>>>,
+    pub customer_tax_exempt: Arc<Mutex<HashMap<StripeCustomerId, StripeTaxExempt>>>,
+    pub customer_tax_ids: Arc<Mutex<HashMap<StripeCustomerId, Vec<StripeTaxId>>>>,
+    pub preview_subscription_update_calls:
+        Arc<Mutex<Vec<(StripeSubscriptionId, StripePreviewSubscriptionUpdateParams)>>>,
 }
 
 impl FakeStripeClient {
@@ -57,13 +102,80 @@ impl FakeStripeClient {
         Self {
             customers: Arc::new(Mutex::new(HashMap::default())),
             subscriptions: Arc::new(Mutex::new(HashMap::default())),
+            get_subscription_calls: Arc::new(Mutex::new(Vec::new())),
+            default_payment_methods: Arc::new(Mutex::new(HashMap::default())),
+            payment_methods: Arc::new(Mutex::new(HashMap::default())),
+            refund_latest_invoice_payment_calls: Arc::new(Mutex::new(Vec::new())),
+            latest_invoice_payments: Arc::new(Mutex::new(HashMap::default())),
+            invoices: Arc::new(Mutex::new(HashMap::default())),
+            create_refund_calls: Arc::new(Mutex::new(Vec::new())),
             update_subscription_calls: Arc::new(Mutex::new(Vec::new())),
+            stop_cancellation_calls: Arc::new(Mutex::new(Vec::new())),
+            pause_subscription_calls: Arc::new(Mutex::new(Vec::new())),
             prices: Arc::new(Mutex::new(HashMap::default())),
             meters: Arc::new(Mutex::new(HashMap::default())),
             create_meter_event_calls: Arc::new(Mutex::new(Vec::new())),
+            customer_balances: Arc::new(Mutex::new(HashMap::default())),
+            create_customer_balance_transaction_calls: Arc::new(Mutex::new(Vec::new())),
             create_checkout_session_calls: Arc::new(Mutex::new(Vec::new())),
+            customer_ids_by_idempotency_key: Arc::new(Mutex::new(HashMap::default())),
+            promotion_codes: Arc::new(Mutex::new(HashMap::default())),
+            customer_tax_exempt: Arc::new(Mutex::new(HashMap::default())),
+            customer_tax_ids: Arc::new(Mutex::new(HashMap::default())),
+            preview_subscription_update_calls: Arc::new(Mutex::new(Vec::new())),
         }
     }
+
+    fn insert_subscription(
+        &self,
+        params: StripeCreateSubscriptionParams,
+        status: stripe::SubscriptionStatus,
+    ) -> StripeSubscription {
+        let now = Utc::now();
+
+        let subscription = StripeSubscription {
+            id: StripeSubscriptionId(format!("sub_{}", Uuid::new_v4()).into()),
+            customer: params.customer,
+            status,
+            current_period_start: now.timestamp(),
+            current_period_end: (now + Duration::days(30)).timestamp(),
+            items: params
+                .items
+                .into_iter()
+                .map(|item| StripeSubscriptionItem {
+                    id: StripeSubscriptionItemId(format!("si_{}", Uuid::new_v4()).into()),
+                    price: item
+                        .price
+                        .and_then(|price_id| self.prices.lock().get(&price_id).cloned()),
+                    quantity: item.quantity,
+                })
+                .collect(),
+            cancel_at: None,
+            cancel_at_period_end: false,
+            cancellation_details: None,
+            discount: None,
+            currency: "usd".into(),
+            pause_collection: None,
+        };
+
+        self.subscriptions
+            .lock()
+            .insert(subscription.id.clone(), subscription.clone());
+
+        subscription
+    }
+
+    /// Cancels the subscription the way that Stripe does once it gives up on collecting a failed payment.
+    pub fn cancel_subscription_for_payment_failure(&self, subscription_id: &StripeSubscriptionId) {
+        let mut subscriptions = self.subscriptions.lock();
+        let subscription = subscriptions
+            .get_mut(subscription_id)
+            .expect("subscription not found");
+        subscription.status = stripe::SubscriptionStatus::Canceled;
+        subscription.cancellation_details = Some(StripeCancellationDetails {
+            reason: Some(StripeCancellationDetailsReason::PaymentFailed),
+        });
+    }
 }
 
 #[async_trait]
@@ -78,6 +190,20 @@ impl StripeClient for FakeStripeClient {
             .collect())
     }
 
+    async fn search_customers_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<StripeCustomer>> {
+        Ok(self
+            .customers
+            .lock()
+            .values()
+            .filter(|customer| customer.metadata.get(key).map(String::as_str) == Some(value))
+            .cloned()
+            .collect())
+    }
+
     async fn get_customer(&self, customer_id: &StripeCustomerId) -> Result<StripeCustomer> {
         self.customers
             .lock()
@@ -87,14 +213,37 @@ impl StripeClient for FakeStripeClient {
     }
 
     async fn create_customer(&self, params: CreateCustomerParams<'_>) -> Result<StripeCustomer> {
+        let mut customers = self.customers.lock();
+        let mut customer_ids_by_idempotency_key = self.customer_ids_by_idempotency_key.lock();
+
+        if let Some(idempotency_key) = params.idempotency_key {
+            if let Some(customer_id) = customer_ids_by_idempotency_key.get(idempotency_key) {
+                let customer = customers
+                    .get(customer_id)
+                    .cloned()
+                    .ok_or_else(|| anyhow!("no customer found for {customer_id:?}"))?;
+                // Like Stripe, we reject a key that is reused with different parameters.
+                if customer.email.as_deref() != params.email {
+                    return Err(anyhow!(
+                        "idempotency key {idempotency_key:?} was reused with different parameters"
+                    ));
+                }
+                return Ok(customer);
+            }
+        }
+
         let customer = StripeCustomer {
             id: StripeCustomerId(format!("cus_{}", Uuid::new_v4()).into()),
             email: params.email.map(|email| email.to_string()),
+            metadata: params.metadata.unwrap_or_default(),
+            has_default_payment_method: false,
         };
 
-        self.customers
-            .lock()
-            .insert(customer.id.clone(), customer.clone());
+        if let Some(idempotency_key) = params.idempotency_key {
+            customer_ids_by_idempotency_key
+                .insert(idempotency_key.to_string(), customer.id.clone());
+        }
+        customers.insert(customer.id.clone(), customer.clone());
 
         Ok(customer)
     }
@@ -109,12 +258,55 @@ impl StripeClient for FakeStripeClient {
             if let Some(email) = params.email {
                 customer.email = Some(email.to_string());
             }
+            if let Some(tax_exempt) = params.tax_exempt {
+                self.customer_tax_exempt
+                    .lock()
+                    .insert(customer_id.clone(), tax_exempt);
+            }
+            if let Some(tax_id) = params.tax_id {
+                self.customer_tax_ids
+                    .lock()
+                    .entry(customer_id.clone())
+                    .or_default()
+                    .push(tax_id.clone());
+            }
             Ok(customer.clone())
         } else {
             Err(anyhow!("no customer found for {customer_id:?}"))
         }
     }
 
+    async fn get_default_payment_method(
+        &self,
+        customer_id: &StripeCustomerId,
+    ) -> Result<Option<StripePaymentMethod>> {
+        if !self.customers.lock().contains_key(customer_id) {
+            return Err(anyhow!("no customer found for {customer_id:?}"));
+        }
+
+        Ok(self
+            .default_payment_methods
+            .lock()
+            .get(customer_id)
+            .cloned())
+    }
+
+    async fn list_payment_methods(
+        &self,
+        customer_id: &StripeCustomerId,
+    ) -> Result<Vec<StripePaymentMethod>> {
+        if !self.customers.lock().contains_key(customer_id) {
+            return Err(anyhow!("no customer found for {customer_id:?}"));
+        }
+
+        Ok(self
+            .payment_methods
+            .lock()
+            .get(customer_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
     async fn list_subscriptions_for_customer(
         &self,
         customer_id: &StripeCustomerId,
@@ -134,6 +326,10 @@ impl StripeClient for FakeStripeClient {
         &self,
         subscription_id: &StripeSubscriptionId,
     ) -> Result<StripeSubscription> {
+        self.get_subscription_calls
+            .lock()
+            .push(subscription_id.clone());
+
         self.subscriptions
             .lock()
             .get(subscription_id)
@@ -145,33 +341,19 @@ impl StripeClient for FakeStripeClient {
         &self,
         params: StripeCreateSubscriptionParams,
     ) -> Result<StripeSubscription> {
-        let now = Utc::now();
-
-        let subscription = StripeSubscription {
-            id: StripeSubscriptionId(format!("sub_{}", Uuid::new_v4()).into()),
-            customer: params.customer,
-            status: stripe::SubscriptionStatus::Active,
-            current_period_start: now.timestamp(),
-            current_period_end: (now + Duration::days(30)).timestamp(),
-            items: params
-                .items
-                .into_iter()
-                .map(|item| StripeSubscriptionItem {
-                    id: StripeSubscriptionItemId(format!("si_{}", Uuid::new_v4()).into()),
-                    price: item
-                        .price
-                        .and_then(|price_id| self.prices.lock().get(&price_id).cloned()),
-                })
-                .collect(),
-            cancel_at: None,
-            cancellation_details: None,
-        };
+        Ok(self.insert_subscription(params, stripe::SubscriptionStatus::Active))
+    }
 
-        self.subscriptions
-            .lock()
-            .insert(subscription.id.clone(), subscription.clone());
+    async fn create_incomplete_subscription(
+        &self,
+        params: StripeCreateSubscriptionParams,
+    ) -> Result<StripeIncompleteSubscription> {
+        let subscription = self.insert_subscription(params, stripe::SubscriptionStatus::Incomplete);
 
-        Ok(subscription)
+        Ok(StripeIncompleteSubscription {
+            client_secret: format!("pi_{}_secret_{}", Uuid::new_v4(), subscription.id),
+            subscription,
+        })
     }
 
     async fn update_subscription(
@@ -181,6 +363,13 @@ impl StripeClient for FakeStripeClient {
     ) -> Result<()> {
         let subscription = self.get_subscription(subscription_id).await?;
 
+        // A trialing subscription's current period ends when its trial does.
+        if let Some(trial_end) = params.trial_end {
+            if let Some(subscription) = self.subscriptions.lock().get_mut(subscription_id) {
+                subscription.current_period_end = trial_end;
+            }
+        }
+
         self.update_subscription_calls
             .lock()
             .push((subscription.id, params));
@@ -188,13 +377,171 @@ impl StripeClient for FakeStripeClient {
         Ok(())
     }
 
-    async fn cancel_subscription(&self, subscription_id: &StripeSubscriptionId) -> Result<()> {
-        // TODO: Implement fake subscription cancellation.
-        let _ = subscription_id;
+    async fn stop_cancellation(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        _idempotency_key: Option<&str>,
+    ) -> Result<StripeSubscription> {
+        let mut subscriptions = self.subscriptions.lock();
+        let subscription = subscriptions
+            .get_mut(subscription_id)
+            .ok_or_else(|| anyhow!("no subscription found for {subscription_id:?}"))?;
+        subscription.cancel_at = None;
+        subscription.cancel_at_period_end = false;
+
+        self.stop_cancellation_calls
+            .lock()
+            .push(subscription_id.clone());
+
+        Ok(subscription.clone())
+    }
+
+    async fn cancel_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        _idempotency_key: Option<&str>,
+    ) -> Result<()> {
+        let mut subscriptions = self.subscriptions.lock();
+        let subscription = subscriptions
+            .get_mut(subscription_id)
+            .ok_or_else(|| anyhow!("no subscription found for {subscription_id:?}"))?;
+        subscription.status = stripe::SubscriptionStatus::Canceled;
+
+        Ok(())
+    }
+
+    async fn pause_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        resumes_at: Option<i64>,
+        _idempotency_key: Option<&str>,
+    ) -> Result<()> {
+        let mut subscriptions = self.subscriptions.lock();
+        let subscription = subscriptions
+            .get_mut(subscription_id)
+            .ok_or_else(|| anyhow!("no subscription found for {subscription_id:?}"))?;
+        subscription.pause_collection = Some(StripePauseCollection { resumes_at });
+
+        self.pause_subscription_calls
+            .lock()
+            .push((subscription_id.clone(), resumes_at));
+
+        Ok(())
+    }
+
+    async fn resume_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        _idempotency_key: Option<&str>,
+    ) -> Result<()> {
+        let mut subscriptions = self.subscriptions.lock();
+        let subscription = subscriptions
+            .get_mut(subscription_id)
+            .ok_or_else(|| anyhow!("no subscription found for {subscription_id:?}"))?;
+        if subscription.pause_collection.take().is_none() {
+            return Err(anyhow!("subscription {subscription_id} is not paused"));
+        }
 
         Ok(())
     }
 
+    async fn refund_latest_invoice_payment(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        _idempotency_key: Option<&str>,
+    ) -> Result<Option<StripeRefund>> {
+        let subscriptions = self.subscriptions.lock();
+        let subscription = subscriptions
+            .get(subscription_id)
+            .ok_or_else(|| anyhow!("no subscription found for {subscription_id:?}"))?;
+
+        // The latest invoice is for one period of each of the subscription's items, unless its payment was given.
+        let amount = match self.latest_invoice_payments.lock().get_mut(subscription_id) {
+            Some(payment) => {
+                let amount = payment.amount_paid - payment.amount_refunded;
+                payment.amount_refunded = payment.amount_paid;
+                amount
+            }
+            None => subscription
+                .items
+                .iter()
+                .filter_map(|item| item.price.as_ref()?.unit_amount)
+                .sum::<i64>(),
+        };
+
+        self.refund_latest_invoice_payment_calls
+            .lock()
+            .push(subscription_id.clone());
+
+        if amount == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(StripeRefund {
+            id: StripeRefundId(format!("re_{}", Uuid::new_v4()).into()),
+            amount,
+        }))
+    }
+
+    async fn get_latest_invoice_payment(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+    ) -> Result<Option<StripeInvoicePayment>> {
+        Ok(self
+            .latest_invoice_payments
+            .lock()
+            .get(subscription_id)
+            .cloned())
+    }
+
+    async fn list_invoices_for_customer(
+        &self,
+        customer_id: &StripeCustomerId,
+        limit: u64,
+    ) -> Result<Vec<StripeInvoice>> {
+        let mut invoices = self
+            .invoices
+            .lock()
+            .get(customer_id)
+            .cloned()
+            .unwrap_or_default();
+        invoices.sort_by_key(|invoice| std::cmp::Reverse(invoice.created));
+        invoices.truncate(limit as usize);
+
+        Ok(invoices)
+    }
+
+    async fn create_refund(&self, params: StripeCreateRefundParams<'_>) -> Result<StripeRefund> {
+        self.create_refund_calls
+            .lock()
+            .push(StripeCreateRefundCall {
+                payment_intent_id: params.payment_intent_id.clone(),
+                amount: params.amount,
+                idempotency_key: params.idempotency_key.map(|key| key.to_string()),
+            });
+
+        let mut payments = self.latest_invoice_payments.lock();
+        let payment = payments
+            .values_mut()
+            .find(|payment| payment.payment_intent_id == *params.payment_intent_id)
+            .ok_or_else(|| anyhow!("no payment found for {:?}", params.payment_intent_id))?;
+
+        let refundable = payment.amount_paid - payment.amount_refunded;
+        let amount = params.amount.unwrap_or(refundable);
+        if amount <= 0 || amount > refundable {
+            return Err(anyhow!(
+                "cannot refund {amount} of {refundable} for {:?}",
+                params.payment_intent_id
+            ));
+        }
+        payment.amount_refunded += amount;
+
+        Ok(StripeRefund {
+            id: StripeRefundId(format!("re_{}", Uuid::new_v4()).into()),
+            amount,
+        })
+    }
+
     async fn list_prices(&self) -> Result<Vec<StripePrice>> {
         let prices = self.prices.lock().values().cloned().collect();
 
@@ -216,11 +563,53 @@ impl StripeClient for FakeStripeClient {
                 value: params.payload.value,
                 stripe_customer_id: params.payload.stripe_customer_id.clone(),
                 timestamp: params.timestamp,
+                idempotency_key: params.idempotency_key.map(|key| key.to_string()),
             });
 
         Ok(())
     }
 
+    async fn create_customer_balance_transaction(
+        &self,
+        customer_id: &StripeCustomerId,
+        params: StripeCreateCustomerBalanceTransactionParams<'_>,
+    ) -> Result<StripeCustomerBalanceTransaction> {
+        self.create_customer_balance_transaction_calls.lock().push(
+            StripeCreateCustomerBalanceTransactionCall {
+                customer_id: customer_id.clone(),
+                amount: params.amount,
+                currency: params.currency.to_string(),
+                description: params
+                    .description
+                    .map(|description| description.to_string()),
+                idempotency_key: params.idempotency_key.map(|key| key.to_string()),
+            },
+        );
+        *self
+            .customer_balances
+            .lock()
+            .entry(customer_id.clone())
+            .or_default() += params.amount;
+
+        Ok(StripeCustomerBalanceTransaction {
+            id: format!("cbtxn_{}", Uuid::new_v4()),
+            amount: params.amount,
+        })
+    }
+
+    async fn get_customer_balance(&self, customer_id: &StripeCustomerId) -> Result<i64> {
+        if !self.customers.lock().contains_key(customer_id) {
+            return Err(anyhow!("no customer found for {customer_id:?}"));
+        }
+
+        Ok(self
+            .customer_balances
+            .lock()
+            .get(customer_id)
+            .copied()
+            .unwrap_or_default())
+    }
+
     async fn create_checkout_session(
         &self,
         params: StripeCreateCheckoutSessionParams<'_>,
@@ -238,10 +627,95 @@ impl StripeClient for FakeStripeClient {
                 billing_address_collection: params.billing_address_collection,
                 customer_update: params.customer_update,
                 tax_id_collection: params.tax_id_collection,
+                discounts: params.discounts,
+                idempotency_key: params.idempotency_key.map(|key| key.to_string()),
             });
 
         Ok(StripeCheckoutSession {
             url: Some("https://checkout.stripe.com/c/pay/cs_test_1".to_string()),
         })
     }
+
+    async fn find_promotion_code(&self, code: &str) -> Result<Option<StripePromotionCode>> {
+        Ok(self
+            .promotion_codes
+            .lock()
+            .values()
+            .find(|promotion_code| {
+                promotion_code.active && promotion_code.code.eq_ignore_ascii_case(code)
+            })
+            .cloned())
+    }
+
+    async fn preview_subscription_update(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        params: StripePreviewSubscriptionUpdateParams,
+    ) -> Result<StripeSubscriptionUpdatePreview> {
+        let subscription = self.get_subscription(subscription_id).await?;
+
+        let unit_amount = |price_id: &StripePriceId| -> Result<i64> {
+            self.prices
+                .lock()
+                .get(price_id)
+                .and_then(|price| price.unit_amount)
+                .ok_or_else(|| anyhow!("no price found for {price_id:?}"))
+        };
+
+        let mut changed_amount = 0;
+        let mut recurring_amount = 0;
+        for item in &subscription.items {
+            let old_amount = item
+                .price
+                .as_ref()
+                .and_then(|price| price.unit_amount)
+                .unwrap_or_default();
+            let new_price = params
+                .items
+                .iter()
+                .find(|update| update.id.as_ref() == Some(&item.id))
+                .and_then(|update| update.price.as_ref());
+            let new_amount = match new_price {
+                Some(price_id) => unit_amount(price_id)?,
+                None => old_amount,
+            };
+
+            changed_amount += new_amount - old_amount;
+            recurring_amount += new_amount;
+        }
+        for price_id in params
+            .items
+            .iter()
+            .filter(|update| update.id.is_none())
+            .filter_map(|update| update.price.as_ref())
+        {
+            let new_amount = unit_amount(price_id)?;
+            changed_amount += new_amount;
+            recurring_amount += new_amount;
+        }
+
+        // Prorate the difference over the time left in the current period.
+        let proration_amount = match params.proration_behavior {
+            StripeProrationBehavior::CreateProrations => {
+                let proration_date = params.proration_date.unwrap_or(Utc::now().timestamp());
+                let period_length =
+                    (subscription.current_period_end - subscription.current_period_start).max(1);
+                let remaining =
+                    (subscription.current_period_end - proration_date).clamp(0, period_length);
+                changed_amount * remaining / period_length
+            }
+            StripeProrationBehavior::None => 0,
+        };
+
+        self.preview_subscription_update_calls
+            .lock()
+            .push((subscription.id, params));
+
+        Ok(StripeSubscriptionUpdatePreview {
+            proration_amount,
+            recurring_amount,
+            currency: "usd".to_string(),
+            next_charge_at: subscription.current_period_end,
+        })
+    }
 }
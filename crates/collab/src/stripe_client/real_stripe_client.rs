@@ -6,29 +6,41 @@ use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use stripe::{
     CancellationDetails, CancellationDetailsReason, CheckoutSession, CheckoutSessionMode,
-    CheckoutSessionPaymentMethodCollection, CreateCheckoutSession, CreateCheckoutSessionLineItems,
-    CreateCheckoutSessionSubscriptionData, CreateCheckoutSessionSubscriptionDataTrialSettings,
+    CheckoutSessionPaymentMethodCollection, CreateCheckoutSession, CreateCheckoutSessionDiscounts,
+    CreateCheckoutSessionLineItems, CreateCheckoutSessionSubscriptionData,
+    CreateCheckoutSessionSubscriptionDataTrialSettings,
     CreateCheckoutSessionSubscriptionDataTrialSettingsEndBehavior,
     CreateCheckoutSessionSubscriptionDataTrialSettingsEndBehaviorMissingPaymentMethod,
-    CreateCustomer, Customer, CustomerId, ListCustomers, Price, PriceId, Recurring, Subscription,
-    SubscriptionId, SubscriptionItem, SubscriptionItemId, UpdateCustomer, UpdateSubscriptionItems,
-    UpdateSubscriptionTrialSettings, UpdateSubscriptionTrialSettingsEndBehavior,
+    CreateCustomer, CreateRefund, Customer, CustomerId, CustomerSearchParams, Discount, Invoice,
+    InvoiceStatus, ListCustomers, ListInvoices, ListPaymentMethods, ListPromotionCodes,
+    PaymentMethod, Price, PriceId, PromotionCode, Recurring, RecurringInterval, Refund,
+    Subscription, SubscriptionId, SubscriptionItem, SubscriptionItemId, UpdateCustomer,
+    UpdateSubscriptionItems, UpdateSubscriptionTrialSettings,
+    UpdateSubscriptionTrialSettingsEndBehavior,
     UpdateSubscriptionTrialSettingsEndBehaviorMissingPaymentMethod,
 };
 
 use crate::stripe_client::{
     CreateCustomerParams, StripeBillingAddressCollection, StripeCancellationDetails,
-    StripeCancellationDetailsReason, StripeCheckoutSession, StripeCheckoutSessionMode,
+    StripeCancellationDetailsReason, StripeCard, StripeCheckoutSession, StripeCheckoutSessionMode,
     StripeCheckoutSessionPaymentMethodCollection, StripeClient,
-    StripeCreateCheckoutSessionLineItems, StripeCreateCheckoutSessionParams,
-    StripeCreateCheckoutSessionSubscriptionData, StripeCreateMeterEventParams,
-    StripeCreateSubscriptionParams, StripeCustomer, StripeCustomerId, StripeCustomerUpdate,
+    StripeCreateCheckoutSessionDiscounts, StripeCreateCheckoutSessionLineItems,
+    StripeCreateCheckoutSessionParams, StripeCreateCheckoutSessionSubscriptionData,
+    StripeCreateCustomerBalanceTransactionParams, StripeCreateMeterEventParams,
+    StripeCreateRefundParams, StripeCreateSubscriptionParams, StripeCustomer,
+    StripeCustomerBalanceTransaction, StripeCustomerId, StripeCustomerUpdate,
     StripeCustomerUpdateAddress, StripeCustomerUpdateName, StripeCustomerUpdateShipping,
-    StripeMeter, StripePrice, StripePriceId, StripePriceRecurring, StripeSubscription,
+    StripeDiscount, StripeIncompleteSubscription, StripeInvoice, StripeInvoiceId,
+    StripeInvoiceLine, StripeInvoicePayment, StripeInvoiceStatus, StripeMeter,
+    StripePauseCollection, StripePaymentIntentId, StripePaymentMethod, StripePaymentMethodId,
+    StripePreviewSubscriptionUpdateParams, StripePrice, StripePriceId, StripePriceRecurring,
+    StripePriceRecurringInterval, StripePromotionCode, StripePromotionCodeId,
+    StripeProrationBehavior, StripeRefund, StripeRefundId, StripeSubscription,
     StripeSubscriptionId, StripeSubscriptionItem, StripeSubscriptionItemId,
     StripeSubscriptionTrialSettings, StripeSubscriptionTrialSettingsEndBehavior,
-    StripeSubscriptionTrialSettingsEndBehaviorMissingPaymentMethod, StripeTaxIdCollection,
-    UpdateCustomerParams, UpdateSubscriptionParams,
+    StripeSubscriptionTrialSettingsEndBehaviorMissingPaymentMethod,
+    StripeSubscriptionUpdatePreview, StripeTaxExempt, StripeTaxIdCollection, UpdateCustomerParams,
+    UpdateSubscriptionParams,
 };
 
 pub struct RealStripeClient {
@@ -39,6 +51,20 @@ impl RealStripeClient {
     pub fn new(client: Arc<stripe::Client>) -> Self {
         Self { client }
     }
+
+    /// Returns a client that sends the given idempotency key with its requests, if there is one.
+    fn client_with_idempotency_key(&self, idempotency_key: Option<&str>) -> stripe::Client {
+        match idempotency_key {
+            Some(idempotency_key) => {
+                (*self.client)
+                    .clone()
+                    .with_strategy(stripe::RequestStrategy::Idempotent(
+                        idempotency_key.to_string(),
+                    ))
+            }
+            None => (*self.client).clone(),
+        }
+    }
 }
 
 #[async_trait]
@@ -60,6 +86,31 @@ impl StripeClient for RealStripeClient {
             .collect())
     }
 
+    async fn search_customers_by_metadata(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<Vec<StripeCustomer>> {
+        let response = Customer::search(
+            &self.client,
+            CustomerSearchParams {
+                query: format!(
+                    "metadata['{}']:'{}'",
+                    escape_search_query_value(key),
+                    escape_search_query_value(value)
+                ),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        Ok(response
+            .data
+            .into_iter()
+            .map(StripeCustomer::from)
+            .collect())
+    }
+
     async fn get_customer(&self, customer_id: &StripeCustomerId) -> Result<StripeCustomer> {
         let customer_id = customer_id.try_into()?;
 
@@ -69,10 +120,13 @@ impl StripeClient for RealStripeClient {
     }
 
     async fn create_customer(&self, params: CreateCustomerParams<'_>) -> Result<StripeCustomer> {
+        let client = self.client_with_idempotency_key(params.idempotency_key);
+
         let customer = Customer::create(
-            &self.client,
+            &client,
             CreateCustomer {
                 email: params.email,
+                metadata: params.metadata,
                 ..Default::default()
             },
         )
@@ -86,19 +140,88 @@ impl StripeClient for RealStripeClient {
         customer_id: &StripeCustomerId,
         params: UpdateCustomerParams<'_>,
     ) -> Result<StripeCustomer> {
+        let client = self.client_with_idempotency_key(params.idempotency_key);
+
         let customer = Customer::update(
-            &self.client,
+            &client,
             &customer_id.try_into()?,
             UpdateCustomer {
                 email: params.email,
+                tax_exempt: params.tax_exempt.map(Into::into),
                 ..Default::default()
             },
         )
         .await?;
 
+        if let Some(tax_id) = params.tax_id {
+            #[derive(Serialize)]
+            struct CreateTaxId<'a> {
+                #[serde(rename = "type")]
+                kind: &'a str,
+                value: &'a str,
+            }
+
+            // Idempotency keys can't be reused across endpoints, so derive a separate one for the tax ID.
+            let idempotency_key = params
+                .idempotency_key
+                .map(|idempotency_key| format!("{idempotency_key}-tax-id"));
+            self.client_with_idempotency_key(idempotency_key.as_deref())
+                .post_form::<stripe::TaxId, _>(
+                    &format!("/customers/{customer_id}/tax_ids"),
+                    CreateTaxId {
+                        kind: tax_id.kind.as_str(),
+                        value: &tax_id.value,
+                    },
+                )
+                .await?;
+        }
+
         Ok(StripeCustomer::from(customer))
     }
 
+    async fn get_default_payment_method(
+        &self,
+        customer_id: &StripeCustomerId,
+    ) -> Result<Option<StripePaymentMethod>> {
+        let customer_id = customer_id.try_into()?;
+
+        let customer = Customer::retrieve(
+            &self.client,
+            &customer_id,
+            &["invoice_settings.default_payment_method"],
+        )
+        .await?;
+
+        Ok(customer
+            .invoice_settings
+            .and_then(|settings| settings.default_payment_method)
+            .and_then(|payment_method| payment_method.into_object())
+            .map(StripePaymentMethod::from))
+    }
+
+    async fn list_payment_methods(
+        &self,
+        customer_id: &StripeCustomerId,
+    ) -> Result<Vec<StripePaymentMethod>> {
+        let customer_id = customer_id.try_into()?;
+
+        let payment_methods = PaymentMethod::list(
+            &self.client,
+            &ListPaymentMethods {
+                customer: Some(customer_id),
+                limit: Some(100),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        Ok(payment_methods
+            .data
+            .into_iter()
+            .map(StripePaymentMethod::from)
+            .collect())
+    }
+
     async fn list_subscriptions_for_customer(
         &self,
         customer_id: &StripeCustomerId,
@@ -128,7 +251,10 @@ impl StripeClient for RealStripeClient {
     ) -> Result<StripeSubscription> {
         let subscription_id = subscription_id.try_into()?;
 
-        let subscription = Subscription::retrieve(&self.client, &subscription_id, &[]).await?;
+        // We expand the promotion code so that we can show the code the customer entered.
+        let subscription =
+            Subscription::retrieve(&self.client, &subscription_id, &["discount.promotion_code"])
+                .await?;
 
         Ok(StripeSubscription::from(subscription))
     }
@@ -137,6 +263,7 @@ impl StripeClient for RealStripeClient {
         &self,
         params: StripeCreateSubscriptionParams,
     ) -> Result<StripeSubscription> {
+        let client = self.client_with_idempotency_key(params.idempotency_key.as_deref());
         let customer_id = params.customer.try_into()?;
 
         let mut create_subscription = stripe::CreateSubscription::new(customer_id);
@@ -152,32 +279,82 @@ impl StripeClient for RealStripeClient {
                 .collect(),
         );
 
-        let subscription = Subscription::create(&self.client, create_subscription).await?;
+        let subscription = Subscription::create(&client, create_subscription).await?;
 
         Ok(StripeSubscription::from(subscription))
     }
 
+    async fn create_incomplete_subscription(
+        &self,
+        params: StripeCreateSubscriptionParams,
+    ) -> Result<StripeIncompleteSubscription> {
+        let client = self.client_with_idempotency_key(params.idempotency_key.as_deref());
+        let customer_id = params.customer.try_into()?;
+
+        let mut create_subscription = stripe::CreateSubscription::new(customer_id);
+        create_subscription.items = Some(
+            params
+                .items
+                .into_iter()
+                .map(|item| stripe::CreateSubscriptionItems {
+                    price: item.price.map(|price| price.to_string()),
+                    quantity: item.quantity,
+                    ..Default::default()
+                })
+                .collect(),
+        );
+        create_subscription.payment_behavior =
+            Some(stripe::SubscriptionPaymentBehavior::DefaultIncomplete);
+        create_subscription.payment_settings = Some(stripe::CreateSubscriptionPaymentSettings {
+            save_default_payment_method: Some(
+                stripe::CreateSubscriptionPaymentSettingsSaveDefaultPaymentMethod::OnSubscription,
+            ),
+            ..Default::default()
+        });
+        create_subscription.expand = &["latest_invoice.payment_intent"];
+
+        let subscription = Subscription::create(&client, create_subscription).await?;
+        let client_secret = subscription
+            .latest_invoice
+            .as_ref()
+            .and_then(|invoice| invoice.as_object())
+            .and_then(|invoice| invoice.payment_intent.as_ref())
+            .and_then(|payment_intent| payment_intent.as_object())
+            .and_then(|payment_intent| payment_intent.client_secret.clone())
+            .context("no payment intent client secret for subscription")?;
+
+        Ok(StripeIncompleteSubscription {
+            subscription: StripeSubscription::from(subscription),
+            client_secret,
+        })
+    }
+
     async fn update_subscription(
         &self,
         subscription_id: &StripeSubscriptionId,
         params: UpdateSubscriptionParams,
     ) -> Result<()> {
+        let client = self.client_with_idempotency_key(params.idempotency_key.as_deref());
         let subscription_id = subscription_id.try_into()?;
 
         stripe::Subscription::update(
-            &self.client,
+            &client,
             &subscription_id,
             stripe::UpdateSubscription {
                 items: params.items.map(|items| {
                     items
                         .into_iter()
                         .map(|item| UpdateSubscriptionItems {
+                            id: item.id.map(|id| id.to_string()),
                             price: item.price.map(|price| price.to_string()),
                             ..Default::default()
                         })
                         .collect()
                 }),
                 trial_settings: params.trial_settings.map(Into::into),
+                cancel_at_period_end: params.cancel_at_period_end,
+                proration_behavior: params.proration_behavior.map(Into::into),
+                trial_end: params.trial_end.map(stripe::Scheduled::at),
                 ..Default::default()
             },
         )
@@ -186,11 +363,42 @@ impl StripeClient for RealStripeClient {
         Ok(())
     }
 
-    async fn cancel_subscription(&self, subscription_id: &StripeSubscriptionId) -> Result<()> {
+    async fn stop_cancellation(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        idempotency_key: Option<&str>,
+    ) -> Result<StripeSubscription> {
+        #[derive(Serialize)]
+        struct Params {
+            cancel_at_period_end: bool,
+            /// Stripe unsets the cancellation time when it's given as an empty string.
+            cancel_at: &'static str,
+        }
+
+        let client = self.client_with_idempotency_key(idempotency_key);
+        let subscription = client
+            .post_form::<Subscription, _>(
+                &format!("/subscriptions/{subscription_id}"),
+                Params {
+                    cancel_at_period_end: false,
+                    cancel_at: "",
+                },
+            )
+            .await?;
+
+        Ok(subscription.into())
+    }
+
+    async fn cancel_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        idempotency_key: Option<&str>,
+    ) -> Result<()> {
+        let client = self.client_with_idempotency_key(idempotency_key);
         let subscription_id = subscription_id.try_into()?;
 
         Subscription::cancel(
-            &self.client,
+            &client,
             &subscription_id,
             stripe::CancelSubscription {
                 invoice_now: None,
@@ -202,6 +410,199 @@ impl StripeClient for RealStripeClient {
         Ok(())
     }
 
+    async fn pause_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        resumes_at: Option<i64>,
+        idempotency_key: Option<&str>,
+    ) -> Result<()> {
+        #[derive(Serialize)]
+        struct Params {
+            #[serde(rename = "pause_collection[behavior]")]
+            behavior: &'static str,
+            #[serde(
+                rename = "pause_collection[resumes_at]",
+                skip_serializing_if = "Option::is_none"
+            )]
+            resumes_at: Option<i64>,
+        }
+
+        let client = self.client_with_idempotency_key(idempotency_key);
+        client
+            .post_form::<Subscription, _>(
+                &format!("/subscriptions/{subscription_id}"),
+                Params {
+                    behavior: "void",
+                    resumes_at,
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn resume_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        idempotency_key: Option<&str>,
+    ) -> Result<()> {
+        #[derive(Serialize)]
+        struct Params {
+            /// Stripe unsets the pause when it's given as an empty string.
+            pause_collection: &'static str,
+        }
+
+        let client = self.client_with_idempotency_key(idempotency_key);
+        client
+            .post_form::<Subscription, _>(
+                &format!("/subscriptions/{subscription_id}"),
+                Params {
+                    pause_collection: "",
+                },
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn refund_latest_invoice_payment(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        idempotency_key: Option<&str>,
+    ) -> Result<Option<StripeRefund>> {
+        let subscription_id = subscription_id.try_into()?;
+
+        let subscription =
+            Subscription::retrieve(&self.client, &subscription_id, &["latest_invoice"]).await?;
+        let Some(payment_intent_id) = subscription
+            .latest_invoice
+            .and_then(|invoice| invoice.into_object())
+            .filter(|invoice| invoice.paid.unwrap_or(false))
+            .and_then(|invoice| invoice.payment_intent)
+            .map(|payment_intent| payment_intent.id())
+        else {
+            return Ok(None);
+        };
+
+        let mut params = CreateRefund::new();
+        params.payment_intent = Some(payment_intent_id);
+        let client = self.client_with_idempotency_key(idempotency_key);
+        let refund = Refund::create(&client, params).await?;
+
+        Ok(Some(StripeRefund {
+            id: StripeRefundId(refund.id.as_str().into()),
+            amount: refund.amount,
+        }))
+    }
+
+    async fn get_latest_invoice_payment(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+    ) -> Result<Option<StripeInvoicePayment>> {
+        let subscription_id = subscription_id.try_into()?;
+
+        let subscription =
+            Subscription::retrieve(&self.client, &subscription_id, &["latest_invoice.charge"])
+                .await?;
+        let Some(invoice) = subscription
+            .latest_invoice
+            .and_then(|invoice| invoice.into_object())
+            .filter(|invoice| invoice.paid.unwrap_or(false))
+        else {
+            return Ok(None);
+        };
+        let Some(payment_intent) = invoice.payment_intent else {
+            return Ok(None);
+        };
+
+        #[derive(Serialize)]
+        struct Params {
+            limit: u64,
+        }
+
+        #[derive(Deserialize)]
+        struct InvoiceLine {
+            amount: i64,
+            period: InvoiceLinePeriod,
+            price: Option<InvoiceLinePrice>,
+        }
+
+        #[derive(Deserialize)]
+        struct InvoiceLinePeriod {
+            start: i64,
+            end: i64,
+        }
+
+        #[derive(Deserialize)]
+        struct InvoiceLinePrice {
+            id: String,
+        }
+
+        let lines = self
+            .client
+            .get_query::<stripe::List<InvoiceLine>, _>(
+                &format!("/invoices/{}/lines", invoice.id),
+                Params { limit: 100 },
+            )
+            .await?;
+
+        Ok(Some(StripeInvoicePayment {
+            payment_intent_id: StripePaymentIntentId(payment_intent.id().as_str().into()),
+            amount_paid: invoice.amount_paid.unwrap_or_default(),
+            amount_refunded: invoice
+                .charge
+                .and_then(|charge| charge.into_object())
+                .map_or(0, |charge| charge.amount_refunded),
+            lines: lines
+                .data
+                .into_iter()
+                .map(|line| StripeInvoiceLine {
+                    price_id: line.price.map(|price| StripePriceId(price.id.into())),
+                    amount: line.amount,
+                    period_start: line.period.start,
+                    period_end: line.period.end,
+                })
+                .collect(),
+        }))
+    }
+
+    async fn list_invoices_for_customer(
+        &self,
+        customer_id: &StripeCustomerId,
+        limit: u64,
+    ) -> Result<Vec<StripeInvoice>> {
+        let customer_id = customer_id.try_into()?;
+
+        let invoices = Invoice::list(
+            &self.client,
+            &ListInvoices {
+                customer: Some(customer_id),
+                limit: Some(limit),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        Ok(invoices.data.into_iter().map(StripeInvoice::from).collect())
+    }
+
+    async fn create_refund(&self, params: StripeCreateRefundParams<'_>) -> Result<StripeRefund> {
+        let client = self.client_with_idempotency_key(params.idempotency_key);
+
+        let mut create_refund = CreateRefund::new();
+        create_refund.payment_intent = Some(
+            stripe::PaymentIntentId::from_str(&params.payment_intent_id.0)
+                .context("failed to parse Stripe payment intent ID")?,
+        );
+        create_refund.amount = params.amount;
+        let refund = Refund::create(&client, create_refund).await?;
+
+        Ok(StripeRefund {
+            id: StripeRefundId(refund.id.as_str().into()),
+            amount: refund.amount,
+        })
+    }
+
     async fn list_prices(&self) -> Result<Vec<StripePrice>> {
         let response = stripe::Price::list(
             &self.client,
@@ -239,9 +640,9 @@ impl StripeClient for RealStripeClient {
             pub identifier: String,
         }
 
+        let client = self.client_with_idempotency_key(params.idempotency_key);
         let identifier = params.identifier;
-        match self
-            .client
+        match client
             .post_form::<StripeMeterEvent, _>("/billing/meter_events", params)
             .await
         {
@@ -262,15 +663,129 @@ impl StripeClient for RealStripeClient {
         }
     }
 
+    async fn create_customer_balance_transaction(
+        &self,
+        customer_id: &StripeCustomerId,
+        params: StripeCreateCustomerBalanceTransactionParams<'_>,
+    ) -> Result<StripeCustomerBalanceTransaction> {
+        let client = self.client_with_idempotency_key(params.idempotency_key);
+        let transaction = client
+            .post_form::<StripeCustomerBalanceTransaction, _>(
+                &format!("/customers/{customer_id}/balance_transactions"),
+                params,
+            )
+            .await?;
+
+        Ok(transaction)
+    }
+
+    async fn get_customer_balance(&self, customer_id: &StripeCustomerId) -> Result<i64> {
+        let customer_id = customer_id.try_into()?;
+
+        let customer = Customer::retrieve(&self.client, &customer_id, &[]).await?;
+
+        Ok(customer.balance.unwrap_or(0))
+    }
+
     async fn create_checkout_session(
         &self,
         params: StripeCreateCheckoutSessionParams<'_>,
     ) -> Result<StripeCheckoutSession> {
+        let client = self.client_with_idempotency_key(params.idempotency_key);
         let params = params.try_into()?;
-        let session = CheckoutSession::create(&self.client, params).await?;
+        let session = CheckoutSession::create(&client, params).await?;
 
         Ok(session.into())
     }
+
+    async fn find_promotion_code(&self, code: &str) -> Result<Option<StripePromotionCode>> {
+        // Stripe only requires codes to be unique among active promotion codes, so we only look at those.
+        let response = PromotionCode::list(
+            &self.client,
+            &ListPromotionCodes {
+                code: Some(code),
+                active: Some(true),
+                ..Default::default()
+            },
+        )
+        .await?;
+
+        Ok(response
+            .data
+            .into_iter()
+            .next()
+            .map(StripePromotionCode::from))
+    }
+
+    async fn preview_subscription_update(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        params: StripePreviewSubscriptionUpdateParams,
+    ) -> Result<StripeSubscriptionUpdatePreview> {
+        #[derive(Serialize)]
+        struct Params {
+            subscription: String,
+            subscription_items: Vec<SubscriptionItemParams>,
+            subscription_proration_behavior: stripe::SubscriptionProrationBehavior,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            subscription_proration_date: Option<i64>,
+        }
+
+        #[derive(Serialize)]
+        struct SubscriptionItemParams {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            id: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            price: Option<String>,
+        }
+
+        #[derive(Deserialize)]
+        struct UpcomingInvoice {
+            currency: String,
+            next_payment_attempt: Option<i64>,
+            period_end: i64,
+            lines: stripe::List<UpcomingInvoiceLine>,
+        }
+
+        #[derive(Deserialize)]
+        struct UpcomingInvoiceLine {
+            amount: i64,
+            proration: bool,
+        }
+
+        let invoice = self
+            .client
+            .get_query::<UpcomingInvoice, _>(
+                "/invoices/upcoming",
+                Params {
+                    subscription: subscription_id.0.to_string(),
+                    subscription_items: params
+                        .items
+                        .into_iter()
+                        .map(|item| SubscriptionItemParams {
+                            id: item.id.map(|id| id.0.to_string()),
+                            price: item.price.map(|price| price.0.to_string()),
+                        })
+                        .collect(),
+                    subscription_proration_behavior: params.proration_behavior.into(),
+                    subscription_proration_date: params.proration_date,
+                },
+            )
+            .await?;
+
+        let (proration_lines, recurring_lines): (Vec<_>, Vec<_>) = invoice
+            .lines
+            .data
+            .into_iter()
+            .partition(|line| line.proration);
+
+        Ok(StripeSubscriptionUpdatePreview {
+            proration_amount: proration_lines.iter().map(|line| line.amount).sum(),
+            recurring_amount: recurring_lines.iter().map(|line| line.amount).sum(),
+            currency: invoice.currency,
+            next_charge_at: invoice.next_payment_attempt.unwrap_or(invoice.period_end),
+        })
+    }
 }
 
 impl From<CustomerId> for StripeCustomerId {
@@ -300,10 +815,36 @@ impl From<Customer> for StripeCustomer {
         StripeCustomer {
             id: value.id.into(),
             email: value.email,
+            metadata: value.metadata.unwrap_or_default(),
+            has_default_payment_method: value
+                .invoice_settings
+                .is_some_and(|settings| settings.default_payment_method.is_some())
+                || value.default_source.is_some(),
+        }
+    }
+}
+
+impl From<PaymentMethod> for StripePaymentMethod {
+    fn from(value: PaymentMethod) -> Self {
+        Self {
+            id: StripePaymentMethodId(value.id.as_str().into()),
+            card: value.card.map(|card| StripeCard {
+                brand: card.brand,
+                last4: card.last4,
+                exp_month: card.exp_month as u32,
+                exp_year: card.exp_year as i32,
+            }),
         }
     }
 }
 
+/// Escapes a value for use inside a quoted string in a Stripe search query.
+///
+/// https://docs.stripe.com/search#search-query-language
+fn escape_search_query_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
 impl From<SubscriptionId> for StripeSubscriptionId {
     fn from(value: SubscriptionId) -> Self {
         Self(value.as_str().into())
@@ -328,7 +869,29 @@ impl From<Subscription> for StripeSubscription {
             current_period_end: value.current_period_end,
             items: value.items.data.into_iter().map(Into::into).collect(),
             cancel_at: value.cancel_at,
+            cancel_at_period_end: value.cancel_at_period_end,
             cancellation_details: value.cancellation_details.map(Into::into),
+            discount: value.discount.map(Into::into),
+            currency: value.currency.to_string(),
+            pause_collection: value.pause_collection.map(|pause_collection| {
+                StripePauseCollection {
+                    resumes_at: pause_collection.resumes_at,
+                }
+            }),
+        }
+    }
+}
+
+impl From<Discount> for StripeDiscount {
+    fn from(value: Discount) -> Self {
+        Self {
+            coupon_name: value.coupon.name,
+            promotion_code: value.promotion_code.and_then(|promotion_code| {
+                promotion_code.as_object().map(|code| code.code.clone())
+            }),
+            percent_off: value.coupon.percent_off,
+            amount_off: value.coupon.amount_off,
+            end: value.end,
         }
     }
 }
@@ -351,6 +914,57 @@ impl From<CancellationDetailsReason> for StripeCancellationDetailsReason {
     }
 }
 
+impl From<StripeProrationBehavior> for stripe::SubscriptionProrationBehavior {
+    fn from(value: StripeProrationBehavior) -> Self {
+        match value {
+            StripeProrationBehavior::CreateProrations => Self::CreateProrations,
+            StripeProrationBehavior::None => Self::None,
+        }
+    }
+}
+
+impl From<Invoice> for StripeInvoice {
+    fn from(value: Invoice) -> Self {
+        Self {
+            id: StripeInvoiceId(value.id.as_str().into()),
+            number: value.number,
+            total: value.total.unwrap_or_default(),
+            currency: value
+                .currency
+                .map(|currency| currency.to_string())
+                .unwrap_or_default(),
+            status: value.status.map(StripeInvoiceStatus::from),
+            period_start: value.period_start.unwrap_or_default(),
+            period_end: value.period_end.unwrap_or_default(),
+            created: value.created.unwrap_or_default(),
+            hosted_invoice_url: value.hosted_invoice_url,
+            invoice_pdf: value.invoice_pdf,
+        }
+    }
+}
+
+impl From<InvoiceStatus> for StripeInvoiceStatus {
+    fn from(value: InvoiceStatus) -> Self {
+        match value {
+            InvoiceStatus::Draft => Self::Draft,
+            InvoiceStatus::Open => Self::Open,
+            InvoiceStatus::Paid => Self::Paid,
+            InvoiceStatus::Uncollectible => Self::Uncollectible,
+            InvoiceStatus::Void => Self::Void,
+        }
+    }
+}
+
+impl From<StripeTaxExempt> for stripe::CustomerTaxExemptFilter {
+    fn from(value: StripeTaxExempt) -> Self {
+        match value {
+            StripeTaxExempt::None => Self::None,
+            StripeTaxExempt::Exempt => Self::Exempt,
+            StripeTaxExempt::Reverse => Self::Reverse,
+        }
+    }
+}
+
 impl From<SubscriptionItemId> for StripeSubscriptionItemId {
     fn from(value: SubscriptionItemId) -> Self {
         Self(value.as_str().into())
@@ -362,6 +976,7 @@ impl From<SubscriptionItem> for StripeSubscriptionItem {
         Self {
             id: value.id.into(),
             price: value.price.map(Into::into),
+            quantity: value.quantity,
         }
     }
 }
@@ -425,7 +1040,22 @@ impl From<Price> for StripePrice {
 
 impl From<Recurring> for StripePriceRecurring {
     fn from(value: Recurring) -> Self {
-        Self { meter: value.meter }
+        Self {
+            interval: value.interval.into(),
+            interval_count: value.interval_count,
+            meter: value.meter,
+        }
+    }
+}
+
+impl From<RecurringInterval> for StripePriceRecurringInterval {
+    fn from(value: RecurringInterval) -> Self {
+        match value {
+            RecurringInterval::Day => Self::Day,
+            RecurringInterval::Week => Self::Week,
+            RecurringInterval::Month => Self::Month,
+            RecurringInterval::Year => Self::Year,
+        }
     }
 }
 
@@ -449,11 +1079,36 @@ impl<'a> TryFrom<StripeCreateCheckoutSessionParams<'a>> for CreateCheckoutSessio
             billing_address_collection: value.billing_address_collection.map(Into::into),
             customer_update: value.customer_update.map(Into::into),
             tax_id_collection: value.tax_id_collection.map(Into::into),
+            discounts: value
+                .discounts
+                .map(|discounts| discounts.into_iter().map(Into::into).collect()),
             ..Default::default()
         })
     }
 }
 
+impl From<StripeCreateCheckoutSessionDiscounts> for CreateCheckoutSessionDiscounts {
+    fn from(value: StripeCreateCheckoutSessionDiscounts) -> Self {
+        Self {
+            promotion_code: value.promotion_code,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<PromotionCode> for StripePromotionCode {
+    fn from(value: PromotionCode) -> Self {
+        Self {
+            id: StripePromotionCodeId(value.id.as_str().into()),
+            code: value.code,
+            active: value.active,
+            expires_at: value.expires_at,
+            max_redemptions: value.max_redemptions,
+            times_redeemed: value.times_redeemed,
+        }
+    }
+}
+
 impl From<StripeCheckoutSessionMode> for CheckoutSessionMode {
     fn from(value: StripeCheckoutSessionMode) -> Self {
         match value {
@@ -4,6 +4,15 @@ use call::Room;
 use client::ChannelId;
 use gpui::{Entity, TestAppContext};
 
+mod billing_api_tests;
+mod billing_checkout_tests;
+mod billing_customer_tests;
+mod billing_reporting_tests;
+mod billing_stripe_events_tests;
+mod billing_subscription_changes_tests;
+mod billing_subscription_sync_tests;
+mod billing_test_app;
+mod billing_usage_tests;
 mod channel_buffer_tests;
 mod channel_guest_tests;
 mod channel_message_tests;
@@ -0,0 +1,834 @@
+use std::time::Duration;
+
+use axum::Extension;
+use axum::extract::{self, Query};
+use axum::http::{HeaderMap, Request};
+use chrono::Utc;
+use collections::HashSet;
+use gpui::TestAppContext;
+use pretty_assertions::assert_eq;
+use reqwest::StatusCode;
+use serde_json::json;
+use stripe::SubscriptionStatus;
+use uuid::Uuid;
+
+use crate::api::billing::localization::Locale;
+use crate::api::billing::manifest::{self, BillingConfigManifest};
+use crate::api::billing::{
+    BILLING_MAINTENANCE_ERROR_CODE, BILLING_REQUEST_ID, BillingError, BillingErrorJson,
+    BillingRequestId, BillingSnowflakeRowExt as _, BillingSubscriptionJson,
+    CreateBillingSubscriptionBody, CreditModelRequestUsageBody, ERROR_CODE_HEADER,
+    ListBillingSubscriptionsParams, MAX_REQUEST_ID_LEN, ManageBillingSubscriptionResponse,
+    ManageBillingSubscriptionResult, ProductCode, REQUEST_ID_HEADER, create_billing_subscription,
+    credit_model_request_usage, list_billing_subscriptions, router, validate_redirect_to,
+};
+use crate::api::events::SnowflakeRow;
+use crate::db::billing_subscription::{self, StripeSubscriptionStatus, SubscriptionKind};
+use crate::llm::db::subscription_usage_meter::CompletionMode;
+use crate::stripe_client::{
+    StripePrice, StripePriceId, StripePriceRecurring, StripePriceRecurringInterval,
+};
+use crate::tests::billing_test_app::{make_test_app, make_test_app_with_config};
+use crate::{Config, Error, OverlappingSubscriptionResolution, TrialEndBehavior};
+
+#[test]
+fn test_billing_subscription_json_auto_renews() {
+    let now = Utc::now();
+    let period_end_at = now + chrono::Duration::days(30);
+
+    let renewing = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedPro),
+        stripe_subscription_status: StripeSubscriptionStatus::Active,
+        stripe_current_period_start: Some(now.timestamp()),
+        stripe_current_period_end: Some(period_end_at.timestamp()),
+        ..Default::default()
+    };
+    let json = BillingSubscriptionJson::new(renewing.clone(), Locale::default(), Duration::ZERO);
+    assert!(json.auto_renews);
+    assert!(json.is_cancelable);
+
+    // Canceling at the end of the period sets both `cancel_at_period_end` and `cancel_at`.
+    let canceling_at_period_end = billing_subscription::Model {
+        stripe_cancel_at_period_end: true,
+        stripe_cancel_at: Some(period_end_at.naive_utc()),
+        ..renewing.clone()
+    };
+    let json =
+        BillingSubscriptionJson::new(canceling_at_period_end, Locale::default(), Duration::ZERO);
+    assert!(!json.auto_renews);
+    assert!(!json.is_cancelable);
+
+    // A subscription scheduled to be canceled later doesn't renew either.
+    let scheduled_to_cancel = billing_subscription::Model {
+        stripe_cancel_at: Some((period_end_at + chrono::Duration::days(30)).naive_utc()),
+        ..renewing.clone()
+    };
+    let json = BillingSubscriptionJson::new(scheduled_to_cancel, Locale::default(), Duration::ZERO);
+    assert!(!json.auto_renews);
+    assert!(!json.is_cancelable);
+
+    // Canceled subscriptions don't renew.
+    let canceled = billing_subscription::Model {
+        stripe_subscription_status: StripeSubscriptionStatus::Canceled,
+        ..renewing
+    };
+    let json = BillingSubscriptionJson::new(canceled, Locale::default(), Duration::ZERO);
+    assert!(!json.auto_renews);
+    assert!(!json.is_cancelable);
+
+    // Zed Free renews, but can't be canceled.
+    let free = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedFree),
+        stripe_subscription_status: StripeSubscriptionStatus::Active,
+        ..Default::default()
+    };
+    let json = BillingSubscriptionJson::new(free, Locale::default(), Duration::ZERO);
+    assert!(json.auto_renews);
+    assert!(!json.is_cancelable);
+}
+
+#[test]
+fn test_locale_for_request() {
+    let headers_with_accept_language = |value: &'static str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            axum::http::header::ACCEPT_LANGUAGE,
+            axum::http::HeaderValue::from_static(value),
+        );
+        headers
+    };
+
+    // The `locale` parameter takes precedence over the header.
+    assert_eq!(
+        Locale::for_request(Some("fr"), &headers_with_accept_language("de-DE")),
+        Locale::French
+    );
+    assert_eq!(
+        Locale::for_request(None, &headers_with_accept_language("de-DE")),
+        Locale::German
+    );
+
+    // The most preferred supported language in the header is used.
+    assert_eq!(
+        Locale::for_request(
+            None,
+            &headers_with_accept_language("ja;q=0.9, es-MX;q=0.8, de;q=0.5")
+        ),
+        Locale::Spanish
+    );
+
+    // Unsupported locales fall back to English.
+    assert_eq!(
+        Locale::for_request(Some("ja"), &headers_with_accept_language("ko, zh;q=0.9")),
+        Locale::English
+    );
+    assert_eq!(
+        Locale::for_request(None, &HeaderMap::new()),
+        Locale::English
+    );
+}
+
+#[test]
+fn test_billing_subscription_json_localization() {
+    let zed_pro = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedProTrial),
+        stripe_subscription_status: StripeSubscriptionStatus::Trialing,
+        ..Default::default()
+    };
+
+    let json = BillingSubscriptionJson::new(zed_pro.clone(), Locale::German, Duration::ZERO);
+    assert_eq!(json.name, "Zed Pro (Testversion)");
+    assert_eq!(
+        json.description,
+        "Teste alle Funktionen von Zed Pro kostenlos."
+    );
+
+    let json = BillingSubscriptionJson::new(zed_pro, Locale::English, Duration::ZERO);
+    assert_eq!(json.name, "Zed Pro (Trial)");
+    assert_eq!(json.description, "Try everything in Zed Pro for free.");
+
+    // The plan stays the same regardless of the locale, so that clients can match on it.
+    let json = serde_json::to_value(BillingSubscriptionJson::new(
+        billing_subscription::Model {
+            kind: Some(SubscriptionKind::ZedProTrial),
+            ..Default::default()
+        },
+        Locale::German,
+        Duration::ZERO,
+    ))
+    .unwrap();
+    assert_eq!(json["plan"], json!("zed_pro_trial"));
+    assert_eq!(json["name"], json!("Zed Pro (Testversion)"));
+
+    // Plans without a translation fall back to English.
+    let legacy_usage = billing_subscription::Model {
+        kind: None,
+        ..Default::default()
+    };
+    let json = BillingSubscriptionJson::new(legacy_usage, Locale::German, Duration::ZERO);
+    assert_eq!(json.name, "Zed LLM Usage");
+    assert_eq!(
+        json.description,
+        "Usage-based billing for language model requests."
+    );
+}
+
+#[test]
+fn test_billing_config_manifest_hash() {
+    let prices = vec![
+        StripePrice {
+            id: StripePriceId("price_zed_pro".into()),
+            unit_amount: Some(2_000),
+            lookup_key: Some("zed-pro".into()),
+            recurring: None,
+        },
+        StripePrice {
+            id: StripePriceId("price_claude_sonnet_4".into()),
+            unit_amount: Some(4),
+            lookup_key: Some("claude-sonnet-4-requests".into()),
+            recurring: Some(StripePriceRecurring {
+                interval: StripePriceRecurringInterval::Month,
+                interval_count: 1,
+                meter: Some("meter_claude_sonnet_4".into()),
+            }),
+        },
+    ];
+    let hash = BillingConfigManifest::new(&Config::test(), &prices)
+        .hash()
+        .unwrap();
+
+    // The hash is stable across runs, regardless of the order of the prices.
+    assert_eq!(
+        BillingConfigManifest::new(&Config::test(), &prices)
+            .hash()
+            .unwrap(),
+        hash
+    );
+    let reversed_prices = prices.iter().rev().cloned().collect::<Vec<_>>();
+    assert_eq!(
+        BillingConfigManifest::new(&Config::test(), &reversed_prices)
+            .hash()
+            .unwrap(),
+        hash
+    );
+
+    // Changing any price changes the hash.
+    let mut changed_prices = prices.clone();
+    changed_prices[1].unit_amount = Some(5);
+    assert_ne!(
+        BillingConfigManifest::new(&Config::test(), &changed_prices)
+            .hash()
+            .unwrap(),
+        hash
+    );
+    assert_ne!(
+        BillingConfigManifest::new(&Config::test(), &prices[..1])
+            .hash()
+            .unwrap(),
+        hash
+    );
+
+    // Changing any billing setting changes the hash.
+    for config in [
+        Config {
+            trial_end_behavior: Some(TrialEndBehavior::BlockAccess),
+            ..Config::test()
+        },
+        Config {
+            edit_prediction_overages_enabled: Some(true),
+            ..Config::test()
+        },
+        Config {
+            overlapping_subscription_resolution: Some(
+                OverlappingSubscriptionResolution::KeepHighestTier,
+            ),
+            ..Config::test()
+        },
+        Config {
+            model_request_allotments: Some(vec!["zed_pro:claude-sonnet-4=300".into()]),
+            ..Config::test()
+        },
+        Config {
+            usage_reset_windows: Some(vec!["zed_free:edit_predictions=1".into()]),
+            ..Config::test()
+        },
+        Config {
+            model_request_billing: Some(vec![
+                "anthropic claude-sonnet-4 normal 2 claude-sonnet-4-requests claude_sonnet_4/requests"
+                    .into(),
+            ]),
+            ..Config::test()
+        },
+        Config {
+            max_subscription_pauses_per_year: Some(1),
+            ..Config::test()
+        },
+    ] {
+        assert_ne!(
+            BillingConfigManifest::new(&config, &prices).hash().unwrap(),
+            hash
+        );
+    }
+}
+
+#[test]
+fn test_canonical_json() {
+    let value = json!({
+        "b": 1,
+        "a": {
+            "d": [{ "f": 1, "e": 2 }],
+            "c": null,
+        },
+    });
+
+    assert_eq!(
+        manifest::canonical_json(value),
+        r#"{"a":{"c":null,"d":[{"e":2,"f":1}]},"b":1}"#
+    );
+}
+
+#[gpui::test]
+async fn test_billing_read_only(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            billing_read_only: Some(true),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+    let user = test_app.create_user("user1", 1).await;
+
+    // Reads are still served.
+    let response = list_billing_subscriptions(
+        Extension(app.clone()),
+        Query(ListBillingSubscriptionsParams {
+            github_user_id: user.github_user_id,
+            locale: None,
+            status: None,
+            active_only: false,
+            limit: None,
+            offset: None,
+        }),
+        HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert!(response.subscriptions.is_empty());
+
+    // Writes are rejected.
+    let assert_billing_maintenance = |error: Error| match error {
+        Error::Http(code, _message, headers) => {
+            assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(
+                headers.get(ERROR_CODE_HEADER).unwrap(),
+                BILLING_MAINTENANCE_ERROR_CODE
+            );
+        }
+        error => panic!("expected a billing maintenance error, got {error:?}"),
+    };
+    assert_billing_maintenance(
+        create_billing_subscription(
+            Extension(app.clone()),
+            extract::Json(CreateBillingSubscriptionBody {
+                github_user_id: user.github_user_id,
+                product: ProductCode::ZedPro,
+                promotion_code: None,
+                idempotency_key: None,
+            }),
+        )
+        .await
+        .unwrap_err(),
+    );
+    assert_billing_maintenance(
+        credit_model_request_usage(
+            Extension(app.clone()),
+            extract::Json(CreditModelRequestUsageBody {
+                github_user_id: user.github_user_id,
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Normal,
+                requests: 10,
+                reason: "Refund".into(),
+                idempotency_key: None,
+            }),
+        )
+        .await
+        .unwrap_err(),
+    );
+    assert!(
+        test_app
+            .stripe_client
+            .create_checkout_session_calls
+            .lock()
+            .is_empty()
+    );
+    assert!(
+        test_app
+            .stripe_client
+            .create_customer_balance_transaction_calls
+            .lock()
+            .is_empty()
+    );
+}
+
+#[gpui::test]
+async fn test_versioned_billing_routes(cx: &mut TestAppContext) {
+    use tower::ServiceExt as _;
+
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            billing_read_only: Some(true),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+    let user = test_app.create_user("user1", 1).await;
+
+    let manage_subscription = |path: &str| {
+        let body = json!({
+            "github_user_id": user.github_user_id,
+            "intent": "manage_subscription",
+            "subscription_id": 1,
+        });
+
+        router().layer(Extension(app.clone())).oneshot(
+            Request::post(path)
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+
+    // The unversioned routes are aliases for v1, which returns the error message as plain text.
+    for path in [
+        "/billing/subscriptions/manage",
+        "/billing/v1/subscriptions/manage",
+    ] {
+        let response = manage_subscription(path).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(ERROR_CODE_HEADER).unwrap(),
+            BILLING_MAINTENANCE_ERROR_CODE
+        );
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&body),
+            "BillingMaintenance: billing is read-only during maintenance"
+        );
+    }
+
+    // v2 returns the error as JSON, with the error code in the body.
+    let response = manage_subscription("/billing/v2/subscriptions/manage")
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(
+        response.headers().get(ERROR_CODE_HEADER).unwrap(),
+        BILLING_MAINTENANCE_ERROR_CODE
+    );
+    let request_id = response.headers()[REQUEST_ID_HEADER]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        serde_json::from_slice::<BillingErrorJson>(&body).unwrap(),
+        BillingErrorJson {
+            code: Some(BILLING_MAINTENANCE_ERROR_CODE.to_string()),
+            message: "billing is read-only during maintenance".to_string(),
+            request_id: Some(request_id),
+        }
+    );
+}
+
+#[gpui::test]
+async fn test_billing_error_codes_in_responses(cx: &mut TestAppContext) {
+    use tower::ServiceExt as _;
+
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let user = test_app.create_user("user1", 1).await;
+
+    let error_code = async |path: &str, body: serde_json::Value| {
+        let response = router()
+            .layer(Extension(app.clone()))
+            .oneshot(
+                Request::post(path)
+                    .header("content-type", "application/json")
+                    .body(axum::body::Body::from(body.to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let error = serde_json::from_slice::<BillingErrorJson>(&body).unwrap();
+        (status, error.code)
+    };
+
+    assert_eq!(
+        error_code(
+            "/billing/v2/subscriptions",
+            json!({
+                "github_user_id": user.github_user_id,
+                "product": "zed_pro",
+                "seats": 0,
+            }),
+        )
+        .await,
+        (
+            StatusCode::BAD_REQUEST,
+            Some("InvalidSeatCount".to_string())
+        )
+    );
+    assert_eq!(
+        error_code(
+            "/billing/v2/subscriptions/manage",
+            json!({
+                "github_user_id": user.github_user_id,
+                "intent": "manage_subscription",
+                "subscription_id": 1,
+                "redirect_to": "https://example.com",
+            }),
+        )
+        .await,
+        (StatusCode::BAD_REQUEST, Some("InvalidRedirect".to_string()))
+    );
+    assert_eq!(
+        error_code(
+            "/billing/v2/subscriptions/manage",
+            json!({
+                "github_user_id": user.github_user_id,
+                "intent": "manage_subscription",
+                "subscription_id": 1,
+            }),
+        )
+        .await,
+        (
+            StatusCode::NOT_IMPLEMENTED,
+            Some("NotSupported".to_string())
+        )
+    );
+    assert_eq!(
+        error_code(
+            "/billing/v2/subscriptions/preview-change",
+            json!({
+                "github_user_id": user.github_user_id,
+                "subscription_id": 1,
+                "product": "zed_pro_trial",
+            }),
+        )
+        .await,
+        (
+            StatusCode::BAD_REQUEST,
+            Some("CannotChangeToTrial".to_string())
+        )
+    );
+    assert_eq!(
+        error_code(
+            "/billing/v2/subscriptions/preview-change",
+            json!({
+                "github_user_id": user.github_user_id,
+                "subscription_id": 1,
+                "product": "zed_pro",
+            }),
+        )
+        .await,
+        (
+            StatusCode::NOT_FOUND,
+            Some("SubscriptionNotFound".to_string())
+        )
+    );
+}
+
+#[gpui::test]
+async fn test_billing_request_id(cx: &mut TestAppContext) {
+    use tower::ServiceExt as _;
+
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            billing_read_only: Some(true),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+    let user = test_app.create_user("user1", 1).await;
+
+    let manage_subscription = |request_id: Option<&str>| {
+        let body = json!({
+            "github_user_id": user.github_user_id,
+            "intent": "manage_subscription",
+            "subscription_id": 1,
+        });
+
+        let mut request = Request::post("/billing/v2/subscriptions/manage")
+            .header("content-type", "application/json");
+        if let Some(request_id) = request_id {
+            request = request.header(REQUEST_ID_HEADER, request_id);
+        }
+        router().layer(Extension(app.clone())).oneshot(
+            request
+                .body(axum::body::Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+
+    // The ID that the client sent is used, so that both sides can find the request by it.
+    let response = manage_subscription(Some("req_123")).await.unwrap();
+    assert_eq!(response.headers()[REQUEST_ID_HEADER], "req_123");
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(
+        serde_json::from_slice::<BillingErrorJson>(&body)
+            .unwrap()
+            .request_id
+            .as_deref(),
+        Some("req_123")
+    );
+
+    // Otherwise, an ID is generated.
+    for request_id in [None, Some(""), Some(&*"x".repeat(MAX_REQUEST_ID_LEN + 1))] {
+        let response = manage_subscription(request_id).await.unwrap();
+        let request_id = response.headers()[REQUEST_ID_HEADER].to_str().unwrap();
+        assert!(Uuid::parse_str(request_id).is_ok(), "{request_id}");
+    }
+
+    // Snowflake rows are tagged with the ID of the request they were recorded for.
+    let row = || SnowflakeRow::new("Test Event", None, false, None, json!({ "key": "value" }));
+    assert_eq!(
+        row().with_billing_request_id().event_properties,
+        json!({ "key": "value" })
+    );
+    let tagged_row = BILLING_REQUEST_ID
+        .scope(BillingRequestId("req_123".into()), async {
+            row().with_billing_request_id()
+        })
+        .await;
+    assert_eq!(
+        tagged_row.event_properties,
+        json!({ "key": "value", "request_id": "req_123" })
+    );
+}
+
+#[test]
+fn test_validate_redirect_to() {
+    for redirect_to in [
+        "/account",
+        "/account/billing",
+        "/account/usage?tab=models",
+        "/account#payment-method",
+    ] {
+        assert_eq!(validate_redirect_to(redirect_to).unwrap(), redirect_to);
+    }
+
+    for redirect_to in [
+        "",
+        "account",
+        "/",
+        "/settings",
+        "/account/",
+        "/accounts",
+        "/account/../admin",
+        "//example.com/account",
+        "/\\example.com",
+        "https://example.com/account",
+        "@example.com/account",
+        ".example.com/account",
+        "/account\r\nLocation: https://example.com",
+        " /account",
+    ] {
+        let error = validate_redirect_to(redirect_to).unwrap_err();
+        assert!(
+            matches!(error, Error::Http(StatusCode::BAD_REQUEST, _, _)),
+            "expected {redirect_to:?} to be rejected"
+        );
+    }
+}
+
+#[test]
+fn test_billing_error() {
+    let error = Error::from(BillingError::TrialAlreadyUsed);
+    let Error::Http(status, message, headers) = error else {
+        panic!("expected an HTTP error, got {error:?}");
+    };
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(message, "TrialAlreadyUsed: user already used free trial");
+    assert_eq!(headers.get(ERROR_CODE_HEADER).unwrap(), "TrialAlreadyUsed");
+
+    // Errors that carry a reason keep their code, no matter the reason.
+    let error = Error::from(BillingError::InvalidPromotionCode(
+        "promotion code \"SAVE\" is invalid or has expired".into(),
+    ));
+    let Error::Http(status, message, headers) = error else {
+        panic!("expected an HTTP error, got {error:?}");
+    };
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        message,
+        "InvalidPromotionCode: promotion code \"SAVE\" is invalid or has expired"
+    );
+    assert_eq!(
+        headers.get(ERROR_CODE_HEADER).unwrap(),
+        "InvalidPromotionCode"
+    );
+
+    // Codes are unique, so that clients can tell the errors apart.
+    let errors = [
+        BillingError::BillingMaintenance,
+        BillingError::AlreadySubscribed,
+        BillingError::OverdueInvoices,
+        BillingError::TrialAlreadyUsed,
+        BillingError::MissingPaymentMethod,
+        BillingError::FreeNotCancelable,
+        BillingError::InvalidPromotionCode(String::new()),
+        BillingError::SubscriptionNotPaused,
+        BillingError::MinimumTerm(Utc::now()),
+        BillingError::PauseLimitReached(2),
+        BillingError::InvalidSeatCount(String::new()),
+        BillingError::InvalidRedirect(String::new()),
+        BillingError::InvalidPauseUntil(String::new()),
+        BillingError::SubscriptionNotFound,
+        BillingError::BillingCustomerNotFound,
+        BillingError::NoScheduledChange,
+        BillingError::CannotChangeToTrial,
+        BillingError::PlanChangeNotAllowed,
+        BillingError::AlreadyOnPlan,
+        BillingError::NotRefundable,
+        BillingError::NotPausable,
+        BillingError::InvalidTaxId(String::new()),
+        BillingError::SubscriptionNotCanceled,
+        BillingError::ReactivationUnavailable(String::new()),
+        BillingError::WinbackNotEligible,
+        BillingError::AlreadyOnTeam,
+        BillingError::NoSeatsLeft,
+        BillingError::NoCurrentBillingPeriod,
+        BillingError::NotSupported,
+        BillingError::LlmDatabaseNotAvailable,
+    ];
+    let codes = errors
+        .iter()
+        .map(BillingError::code)
+        .collect::<HashSet<_>>();
+    assert_eq!(codes.len(), errors.len());
+}
+
+#[test]
+fn test_manage_billing_subscription_result_versions() {
+    assert_eq!(
+        serde_json::to_value(ManageBillingSubscriptionResponse::from(
+            ManageBillingSubscriptionResult::BillingPortalSession {
+                url: "https://billing.stripe.com/session".to_string(),
+            }
+        ))
+        .unwrap(),
+        json!({ "billing_portal_session_url": "https://billing.stripe.com/session" })
+    );
+    assert_eq!(
+        serde_json::to_value(ManageBillingSubscriptionResult::BillingPortalSession {
+            url: "https://billing.stripe.com/session".to_string(),
+        })
+        .unwrap(),
+        json!({
+            "result": "billing_portal_session",
+            "url": "https://billing.stripe.com/session",
+        })
+    );
+
+    assert_eq!(
+        serde_json::to_value(ManageBillingSubscriptionResponse::from(
+            ManageBillingSubscriptionResult::SubscriptionUpdated
+        ))
+        .unwrap(),
+        json!({ "billing_portal_session_url": null })
+    );
+    assert_eq!(
+        serde_json::to_value(ManageBillingSubscriptionResult::SubscriptionUpdated).unwrap(),
+        json!({ "result": "subscription_updated" })
+    );
+
+    assert_eq!(
+        serde_json::to_value(ManageBillingSubscriptionResponse::from(
+            ManageBillingSubscriptionResult::SubscriptionResumed {
+                status: StripeSubscriptionStatus::Active,
+            }
+        ))
+        .unwrap(),
+        json!({ "billing_portal_session_url": null, "subscription_status": "active" })
+    );
+    assert_eq!(
+        serde_json::to_value(ManageBillingSubscriptionResult::SubscriptionResumed {
+            status: StripeSubscriptionStatus::Active,
+        })
+        .unwrap(),
+        json!({ "result": "subscription_resumed", "status": "active" })
+    );
+}
+
+#[gpui::test]
+async fn test_list_billing_subscriptions_only_fetches_active_subscription(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+
+    // The user has canceled Zed Pro a few times before subscribing again.
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    for id in ["sub_1", "sub_2"] {
+        let subscription_id = test_app.create_stripe_subscription(
+            id,
+            &customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Active,
+        );
+        test_app.sync_stripe_subscription(&subscription_id).await;
+        test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Canceled);
+        test_app.sync_stripe_subscription(&subscription_id).await;
+    }
+    let active_subscription_id = test_app.create_stripe_subscription(
+        "sub_3",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    test_app
+        .sync_stripe_subscription(&active_subscription_id)
+        .await;
+    test_app.stripe_client.get_subscription_calls.lock().clear();
+
+    let response = list_billing_subscriptions(
+        Extension(app.clone()),
+        Query(ListBillingSubscriptionsParams {
+            github_user_id: user.github_user_id,
+            locale: None,
+            status: None,
+            active_only: false,
+            limit: None,
+            offset: None,
+        }),
+        HeaderMap::new(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(response.subscriptions.len(), 3);
+
+    // Only the active subscription is fetched from Stripe, however many subscriptions the user has had.
+    assert_eq!(
+        *test_app.stripe_client.get_subscription_calls.lock(),
+        vec![active_subscription_id]
+    );
+    let active_subscription = app
+        .db
+        .get_active_billing_subscription(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    for subscription in &response.subscriptions {
+        if subscription.id != active_subscription.id {
+            assert_eq!(subscription.status, StripeSubscriptionStatus::Canceled);
+            assert_eq!(subscription.effective_monthly_cost_cents, None);
+        }
+    }
+}
@@ -0,0 +1,486 @@
+use std::sync::Arc;
+
+use axum::{Extension, extract};
+use chrono::Utc;
+use gpui::TestAppContext;
+use pretty_assertions::assert_eq;
+use reqwest::StatusCode;
+use stripe::SubscriptionStatus;
+
+use crate::api::billing::localization::Locale;
+use crate::api::billing::{
+    CheckoutDiscountJson, CheckoutReceiptJson, CreateBillingSubscriptionBody, NextChargeJson,
+    ProductCode, checkout_receipt, checkout_with_zed_pro_trial, create_billing_subscription,
+    create_billing_subscription_intent_for_user, discounted_amount,
+    sync_subscriptions_for_checkout,
+};
+use crate::db::billing_subscription::{StripeSubscriptionStatus, SubscriptionKind};
+use crate::db::{CreateBillingCustomerParams, CreateBillingSubscriptionParams};
+use crate::stripe_client::{
+    StripeCheckoutSessionPaymentMethodCollection, StripeClient,
+    StripeCreateCheckoutSessionDiscounts, StripeDiscount, StripePrice, StripePriceId,
+    StripePriceRecurring, StripePriceRecurringInterval, StripePromotionCode, StripePromotionCodeId,
+    StripeSubscriptionId, StripeSubscriptionItem, StripeSubscriptionItemId,
+};
+use crate::tests::billing_test_app::{format_timestamp, make_test_app, make_test_app_with_config};
+use crate::{Config, Error, TrialPaymentMethodCollection};
+
+#[gpui::test]
+async fn test_checkout_receipt(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let subscription = sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut stripe_subscription = stripe_client
+        .get_subscription(&subscription_id)
+        .await
+        .unwrap();
+    let period_end_at = format_timestamp(stripe_subscription.current_period_end);
+
+    // A subscription that is charged right away is next charged when it renews.
+    assert_eq!(
+        checkout_receipt(&subscription, &stripe_subscription, Locale::default()),
+        CheckoutReceiptJson {
+            plan_name: "Zed Pro".into(),
+            price: Some(2_000),
+            interval: None,
+            interval_count: None,
+            trial_end_at: None,
+            next_charge: Some(NextChargeJson {
+                charge_at: period_end_at.clone(),
+                amount: Some(2_000),
+            }),
+            discount: None,
+        }
+    );
+
+    // A discount that ends before the renewal doesn't apply to the next charge.
+    let discount_end = stripe_subscription.current_period_end - 60;
+    stripe_subscription.discount = Some(StripeDiscount {
+        coupon_name: Some("Launch Week".into()),
+        promotion_code: Some("LAUNCH".into()),
+        percent_off: None,
+        amount_off: Some(500),
+        end: Some(discount_end),
+    });
+    let receipt = checkout_receipt(&subscription, &stripe_subscription, Locale::default());
+    assert_eq!(
+        receipt.next_charge,
+        Some(NextChargeJson {
+            charge_at: period_end_at.clone(),
+            amount: Some(2_000),
+        })
+    );
+    assert_eq!(
+        receipt.discount,
+        Some(CheckoutDiscountJson {
+            name: Some("Launch Week".into()),
+            promotion_code: Some("LAUNCH".into()),
+            percent_off: None,
+            amount_off: Some(500),
+            end_at: Some(format_timestamp(discount_end)),
+        })
+    );
+
+    // A subscription that is set to cancel has no next charge.
+    stripe_subscription.discount = None;
+    stripe_subscription.cancel_at_period_end = true;
+    let receipt = checkout_receipt(&subscription, &stripe_subscription, Locale::default());
+    assert_eq!(receipt.next_charge, None);
+}
+
+#[gpui::test]
+async fn test_checkout_receipt_for_trial(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Trialing,
+    );
+    let subscription = sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let mut stripe_subscription = stripe_client
+        .get_subscription(&subscription_id)
+        .await
+        .unwrap();
+    stripe_subscription.discount = Some(StripeDiscount {
+        coupon_name: Some("Half Off".into()),
+        promotion_code: None,
+        percent_off: Some(50.0),
+        amount_off: None,
+        end: None,
+    });
+    let trial_end_at = format_timestamp(stripe_subscription.current_period_end);
+
+    // A trial is first charged once the trial ends, with the discount applied.
+    assert_eq!(
+        checkout_receipt(&subscription, &stripe_subscription, Locale::default()),
+        CheckoutReceiptJson {
+            plan_name: "Zed Pro (Trial)".into(),
+            price: Some(2_000),
+            interval: None,
+            interval_count: None,
+            trial_end_at: Some(trial_end_at.clone()),
+            next_charge: Some(NextChargeJson {
+                charge_at: trial_end_at,
+                amount: Some(1_000),
+            }),
+            discount: Some(CheckoutDiscountJson {
+                name: Some("Half Off".into()),
+                promotion_code: None,
+                percent_off: Some(50.0),
+                amount_off: None,
+                end_at: None,
+            }),
+        }
+    );
+
+    // The plan's price is the one that isn't metered.
+    let metered_price = StripePrice {
+        id: StripePriceId("price_metered".into()),
+        unit_amount: Some(4),
+        lookup_key: None,
+        recurring: Some(StripePriceRecurring {
+            interval: StripePriceRecurringInterval::Month,
+            interval_count: 1,
+            meter: Some("mtr_1".into()),
+        }),
+    };
+    stripe_subscription.items.insert(
+        0,
+        StripeSubscriptionItem {
+            id: StripeSubscriptionItemId("si_metered".into()),
+            price: Some(metered_price),
+            quantity: None,
+        },
+    );
+    let receipt = checkout_receipt(&subscription, &stripe_subscription, Locale::default());
+    assert_eq!(receipt.price, Some(2_000));
+}
+
+#[test]
+fn test_discounted_amount() {
+    let discount = |percent_off, amount_off| StripeDiscount {
+        coupon_name: None,
+        promotion_code: None,
+        percent_off,
+        amount_off,
+        end: None,
+    };
+
+    assert_eq!(discounted_amount(2_000, &discount(Some(25.0), None)), 1_500);
+    assert_eq!(discounted_amount(2_000, &discount(Some(33.3), None)), 1_334);
+    assert_eq!(discounted_amount(2_000, &discount(None, Some(500))), 1_500);
+    assert_eq!(discounted_amount(2_000, &discount(None, Some(5_000))), 0);
+    assert_eq!(discounted_amount(2_000, &discount(None, None)), 2_000);
+}
+
+#[gpui::test]
+async fn test_create_billing_subscription_intent(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let response = create_billing_subscription_intent_for_user(app, &stripe_billing, &user)
+        .await
+        .unwrap();
+    assert!(!response.client_secret.is_empty());
+
+    // The subscription stays incomplete until the payment is confirmed in the client.
+    let subscription = test_app.stripe_client.subscriptions.lock()
+        [&StripeSubscriptionId(response.stripe_subscription_id.into())]
+        .clone();
+    assert_eq!(subscription.status, SubscriptionStatus::Incomplete);
+    assert_eq!(
+        subscription
+            .items
+            .iter()
+            .map(|item| item.price.as_ref().unwrap().id.to_string())
+            .collect::<Vec<_>>(),
+        vec!["price_zed_pro".to_string()]
+    );
+    let customer = test_app.stripe_client.customers.lock()[&subscription.customer].clone();
+    assert_eq!(customer.email.as_deref(), Some("user1@example.com"));
+
+    // Users with an active subscription can't subscribe again.
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: subscription.customer.to_string(),
+        })
+        .await
+        .unwrap();
+    app.db
+        .create_billing_subscription(&CreateBillingSubscriptionParams {
+            billing_customer_id: billing_customer.id,
+            kind: Some(SubscriptionKind::ZedPro),
+            unclassified: false,
+            stripe_subscription_id: subscription.id.to_string(),
+            stripe_subscription_status: StripeSubscriptionStatus::Active,
+            stripe_cancellation_reason: None,
+            stripe_current_period_start: None,
+            stripe_current_period_end: None,
+            seats: 1,
+            monthly_recurring_revenue_in_cents: None,
+            ended_at: None,
+        })
+        .await
+        .unwrap();
+    let error = create_billing_subscription_intent_for_user(app, &stripe_billing, &user)
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::CONFLICT, _, _)));
+}
+
+#[gpui::test]
+async fn test_zed_pro_trial_days(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            zed_pro_trial_days: Some(7),
+            zed_pro_extended_trial_days: Some(30),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+
+    // Trials last as long as configured.
+    checkout_with_zed_pro_trial(
+        app,
+        &stripe_billing,
+        None,
+        &customer_id,
+        &user,
+        "https://zed.dev/account",
+        None,
+    )
+    .await
+    .unwrap();
+    let call = test_app
+        .stripe_client
+        .create_checkout_session_calls
+        .lock()
+        .pop()
+        .unwrap();
+    assert_eq!(
+        call.subscription_data
+            .and_then(|subscription_data| subscription_data.trial_period_days),
+        Some(7)
+    );
+
+    assert_eq!(Config::test().zed_pro_trial_days(), 14);
+    assert_eq!(Config::test().zed_pro_extended_trial_days(), 60);
+}
+
+#[gpui::test]
+async fn test_trial_payment_method_collection(cx: &mut TestAppContext) {
+    for trial_payment_method_collection in [
+        TrialPaymentMethodCollection::WithoutCard,
+        TrialPaymentMethodCollection::WithCard,
+    ] {
+        let test_app = make_test_app_with_config(
+            cx,
+            Config {
+                trial_payment_method_collection: Some(trial_payment_method_collection),
+                ..Config::test()
+            },
+        )
+        .await;
+        let app = &test_app.app;
+        let stripe_billing = app.stripe_billing.clone().unwrap();
+
+        let user = test_app.create_user("user1", 1).await;
+        let customer_id = test_app.create_stripe_customer("cus_1", &user);
+
+        let (_checkout_session_url, applied) = checkout_with_zed_pro_trial(
+            app,
+            &stripe_billing,
+            None,
+            &customer_id,
+            &user,
+            "https://zed.dev/account",
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(applied, trial_payment_method_collection);
+        let call = test_app
+            .stripe_client
+            .create_checkout_session_calls
+            .lock()
+            .pop()
+            .unwrap();
+        assert_eq!(
+            call.payment_method_collection,
+            Some(match trial_payment_method_collection {
+                TrialPaymentMethodCollection::WithoutCard => {
+                    StripeCheckoutSessionPaymentMethodCollection::IfRequired
+                }
+                TrialPaymentMethodCollection::WithCard => {
+                    StripeCheckoutSessionPaymentMethodCollection::Always
+                }
+            })
+        );
+
+        // The user completes checkout and starts their trial.
+        let subscription_id = test_app.create_stripe_subscription(
+            "sub_trial",
+            &customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Trialing,
+        );
+        test_app.sync_stripe_subscription(&subscription_id).await;
+        let trial = app
+            .db
+            .get_active_billing_subscription(user.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(trial.kind, Some(SubscriptionKind::ZedProTrial));
+
+        // At the end of the trial, Stripe converts it when there's a card and cancels it otherwise.
+        let status_at_trial_end = match trial_payment_method_collection {
+            TrialPaymentMethodCollection::WithoutCard => SubscriptionStatus::Canceled,
+            TrialPaymentMethodCollection::WithCard => SubscriptionStatus::Active,
+        };
+        test_app.set_stripe_subscription_status(&subscription_id, status_at_trial_end);
+        test_app.sync_stripe_subscription(&subscription_id).await;
+
+        let subscription = app
+            .db
+            .get_billing_subscription_by_stripe_subscription_id("sub_trial")
+            .await
+            .unwrap()
+            .unwrap();
+        let zed_free_subscriptions = test_app
+            .stripe_subscriptions_for_customer(&customer_id)
+            .into_iter()
+            .filter(|subscription| {
+                subscription.items.iter().any(|item| {
+                    item.price
+                        .as_ref()
+                        .is_some_and(|price| price.id.0.as_ref() == "price_zed_free")
+                })
+            })
+            .count();
+        match trial_payment_method_collection {
+            TrialPaymentMethodCollection::WithoutCard => {
+                assert_eq!(subscription.kind, Some(SubscriptionKind::ZedProTrial));
+                assert_eq!(
+                    subscription.stripe_subscription_status,
+                    StripeSubscriptionStatus::Canceled
+                );
+                assert_eq!(zed_free_subscriptions, 1);
+            }
+            TrialPaymentMethodCollection::WithCard => {
+                assert_eq!(subscription.kind, Some(SubscriptionKind::ZedPro));
+                assert_eq!(
+                    subscription.stripe_subscription_status,
+                    StripeSubscriptionStatus::Active
+                );
+                assert_eq!(zed_free_subscriptions, 0);
+            }
+        }
+    }
+}
+
+#[gpui::test]
+async fn test_create_billing_subscription_with_promotion_code(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+
+    let user = test_app.create_user("user1", 1).await;
+    let promotion_code = StripePromotionCode {
+        id: StripePromotionCodeId("promo_launch".into()),
+        code: "LAUNCH".into(),
+        active: true,
+        expires_at: Some((Utc::now() - chrono::Duration::days(1)).timestamp()),
+        max_redemptions: None,
+        times_redeemed: 0,
+    };
+    test_app
+        .stripe_client
+        .promotion_codes
+        .lock()
+        .insert(promotion_code.id.clone(), promotion_code);
+
+    let create_subscription = |product: ProductCode, promotion_code: &str| {
+        create_billing_subscription(
+            Extension(app.clone()),
+            extract::Json(CreateBillingSubscriptionBody {
+                github_user_id: user.github_user_id,
+                product,
+                promotion_code: Some(promotion_code.into()),
+                idempotency_key: None,
+            }),
+        )
+    };
+
+    // Unknown and expired codes are rejected before a Checkout session is created.
+    for code in ["UNKNOWN", "LAUNCH"] {
+        let error = create_subscription(ProductCode::ZedPro, code)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Http(StatusCode::BAD_REQUEST, _, _)));
+    }
+
+    // Promotion codes only apply to Zed Pro.
+    test_app
+        .stripe_client
+        .promotion_codes
+        .lock()
+        .get_mut(&StripePromotionCodeId("promo_launch".into()))
+        .unwrap()
+        .expires_at = None;
+    let error = create_subscription(ProductCode::ZedProTrial, "LAUNCH")
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::BAD_REQUEST, _, _)));
+    assert!(
+        test_app
+            .stripe_client
+            .create_checkout_session_calls
+            .lock()
+            .is_empty()
+    );
+
+    // A redeemable code is applied to the Checkout session.
+    create_subscription(ProductCode::ZedPro, "launch")
+        .await
+        .unwrap();
+    assert_eq!(
+        test_app.stripe_client.create_checkout_session_calls.lock()[0].discounts,
+        Some(vec![StripeCreateCheckoutSessionDiscounts {
+            promotion_code: Some("promo_launch".into()),
+        }])
+    );
+}
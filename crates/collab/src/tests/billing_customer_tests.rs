@@ -0,0 +1,1041 @@
+use std::sync::Arc;
+
+use axum::Extension;
+use axum::extract::Query;
+use axum::http::HeaderMap;
+use chrono::{DateTime, Datelike as _, SecondsFormat, Utc};
+use gpui::TestAppContext;
+use pretty_assertions::assert_eq;
+use reqwest::StatusCode;
+use sea_orm::ActiveValue;
+use serde_json::json;
+use stripe::SubscriptionStatus;
+
+use crate::api::billing::{
+    AddTeamMemberResponse, BillingPreferencesValues, BillingTasks,
+    BulkUpdateBillingPreferencesBody, BulkUpdateBillingPreferencesResponse,
+    ClearOverdueInvoicesResponse, FailedBillingPreferencesUpdateJson,
+    ListBillingSubscriptionsParams, PaymentMethodJson, PaymentStatus, add_team_member_for_owner,
+    bulk_update_billing_preferences_inner, card_expires_at, clear_overdue_invoices_for_user,
+    invoices_for_user, is_card_expiring_soon, list_billing_subscriptions, normalize_tax_id,
+    payment_events_for_customer, payment_methods_for_user, payment_status, record_payment_event,
+    remind_users_of_expiring_payment_methods, restore_access_after_payment, sync_subscription,
+    sync_subscriptions_for_checkout, team_members_for_owner, team_usage, update_tax_id_for_user,
+    usage_limits,
+};
+use crate::db::billing_payment_event::PaymentEventKind;
+use crate::db::billing_subscription::{
+    self, StripeCancellationReason, StripeSubscriptionStatus, SubscriptionKind,
+};
+use crate::db::{
+    CreateBillingCustomerParams, CreateBillingSubscriptionParams, UpdateBillingCustomerParams,
+    User, UserId, billing_customer,
+};
+use crate::llm::db::subscription_usage;
+use crate::stripe_client::{
+    StripeCard, StripeClient, StripeCustomerId, StripeInvoice, StripeInvoiceId,
+    StripeInvoiceStatus, StripePaymentMethod, StripePaymentMethodId, StripeTaxExempt, StripeTaxId,
+    StripeTaxIdKind,
+};
+use crate::tests::billing_test_app::make_test_app;
+use crate::{Config, Error};
+
+#[gpui::test]
+async fn test_add_team_member(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let owner = test_app.create_user("owner", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &owner);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        subscription.items[0].quantity = Some(3);
+        subscription.clone()
+    };
+    sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+    let billing_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_1")
+        .await
+        .unwrap()
+        .unwrap();
+
+    let member1 = test_app.create_user("member1", 2).await;
+    let member2 = test_app.create_user("member2", 3).await;
+    let member3 = test_app.create_user("member3", 4).await;
+
+    // Only the billing owner can add members to the team.
+    let error = add_team_member_for_owner(app, &member1, billing_subscription.id, &member2)
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::NOT_FOUND, _, _)));
+
+    assert_eq!(
+        add_team_member_for_owner(app, &owner, billing_subscription.id, &member1)
+            .await
+            .unwrap(),
+        AddTeamMemberResponse {
+            seats_used: 2,
+            seats: 3,
+        }
+    );
+
+    // Users can't be added to a team they're already on, including the billing owner.
+    for user in [&member1, &owner] {
+        let error = add_team_member_for_owner(app, &owner, billing_subscription.id, user)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Http(StatusCode::CONFLICT, _, _)));
+    }
+
+    assert_eq!(
+        add_team_member_for_owner(app, &owner, billing_subscription.id, &member2)
+            .await
+            .unwrap(),
+        AddTeamMemberResponse {
+            seats_used: 3,
+            seats: 3,
+        }
+    );
+
+    // Once every seat is taken, no more members can be added.
+    let error = add_team_member_for_owner(app, &owner, billing_subscription.id, &member3)
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::CONFLICT, _, _)));
+
+    let (_, team_members) = team_members_for_owner(app, &owner, billing_subscription.id)
+        .await
+        .unwrap();
+    assert_eq!(
+        team_members
+            .iter()
+            .map(|user| user.github_login.as_str())
+            .collect::<Vec<_>>(),
+        vec!["owner", "member1", "member2"]
+    );
+}
+
+#[test]
+fn test_team_usage() {
+    let to_primitive_date_time = |date_time: DateTime<Utc>| {
+        let date_time = time::OffsetDateTime::from_unix_timestamp(date_time.timestamp()).unwrap();
+        time::PrimitiveDateTime::new(date_time.date(), date_time.time())
+    };
+    let period_start_at = "2025-07-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let period_end_at = "2025-08-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let member = |github_login: &str, github_user_id: i32| User {
+        id: UserId(github_user_id),
+        github_login: github_login.into(),
+        github_user_id,
+        ..Default::default()
+    };
+    let usage = |user_id: i32, model_requests: i32, edit_predictions: i32| {
+        Some(subscription_usage::Model {
+            id: Default::default(),
+            user_id: UserId(user_id),
+            period_start_at: to_primitive_date_time(period_start_at),
+            period_end_at: to_primitive_date_time(period_end_at),
+            plan: SubscriptionKind::ZedPro,
+            model_requests,
+            edit_predictions,
+        })
+    };
+
+    let team_usage = team_usage(
+        &Config::test(),
+        zed_llm_client::Plan::ZedPro,
+        4,
+        (period_start_at, period_end_at),
+        vec![
+            (member("owner", 1), usage(1, 300, 20)),
+            (member("member1", 2), usage(2, 450, 0)),
+            // Members who haven't used anything this period count as zero.
+            (member("member2", 3), None),
+            (member("member3", 4), usage(4, 50, 5)),
+        ],
+    );
+
+    assert_eq!(team_usage.plan, "zed_pro");
+    assert_eq!(team_usage.seats, 4);
+    assert_eq!(team_usage.period.start_at, "2025-07-01T00:00:00.000Z");
+    assert_eq!(team_usage.period.end_at, "2025-08-01T00:00:00.000Z");
+    assert_eq!(
+        team_usage
+            .members
+            .iter()
+            .map(|member| (
+                member.github_login.as_str(),
+                member.model_requests,
+                member.edit_predictions
+            ))
+            .collect::<Vec<_>>(),
+        vec![
+            ("owner", 300, 20),
+            ("member1", 450, 0),
+            ("member2", 0, 0),
+            ("member3", 50, 5),
+        ]
+    );
+
+    // The team's total is measured against the plan's limit pooled across every seat.
+    let limits = usage_limits(&Config::test(), zed_llm_client::Plan::ZedPro, false);
+    let pooled_limit = limits.model_requests.map(|limit| limit * 4);
+    assert_eq!(team_usage.model_requests.used, 800);
+    assert_eq!(team_usage.model_requests.limit, pooled_limit);
+    assert_eq!(
+        team_usage.model_requests.remaining,
+        pooled_limit.map(|limit| (limit - 800).max(0))
+    );
+    assert_eq!(
+        team_usage.model_requests.resets_at,
+        "2025-08-01T00:00:00.000Z"
+    );
+    assert_eq!(team_usage.edit_predictions.used, 25);
+    assert_eq!(
+        team_usage.edit_predictions.limit,
+        limits.edit_predictions.map(|limit| limit * 4)
+    );
+}
+
+#[gpui::test]
+async fn test_restore_access_after_payment(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::PastDue,
+    );
+    sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap();
+
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                has_overdue_invoices: ActiveValue::set(true),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    // The payment succeeds, and Stripe moves the subscription out of `past_due`.
+    test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Active);
+
+    // Handling the same payment twice (e.g., from the poller and a retry) has the same outcome.
+    for _ in 0..2 {
+        let restored_customer =
+            restore_access_after_payment(app, &stripe_client, &customer_id, Some(&subscription_id))
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(restored_customer.user_id, user.id);
+
+        let billing_customer = app
+            .db
+            .get_billing_customer_by_user_id(user.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!billing_customer.has_overdue_invoices);
+
+        let subscription = app
+            .db
+            .get_active_billing_subscription(user.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            subscription.stripe_subscription_status,
+            StripeSubscriptionStatus::Active
+        );
+    }
+
+    // Payments from customers we don't know about are ignored.
+    let restored_customer = restore_access_after_payment(
+        app,
+        &stripe_client,
+        &StripeCustomerId("cus_unknown".into()),
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(restored_customer, None);
+}
+
+#[gpui::test]
+async fn test_payment_events(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let start = Utc::now() - chrono::Duration::days(3);
+    let record = async |event_id: &str, kind, invoice_id: &str, days| {
+        record_payment_event(
+            app,
+            &customer_id,
+            kind,
+            event_id,
+            invoice_id,
+            2_000,
+            start + chrono::Duration::days(days),
+        )
+        .await
+        .unwrap();
+    };
+
+    // A payment fails twice before it's recovered. The events can arrive out of order.
+    record("evt_1", PaymentEventKind::Failed, "in_1", 0).await;
+    record("evt_3", PaymentEventKind::Succeeded, "in_1", 2).await;
+    record("evt_2", PaymentEventKind::Failed, "in_1", 1).await;
+
+    // Replaying an event doesn't record it again.
+    record("evt_1", PaymentEventKind::Failed, "in_1", 0).await;
+
+    // Payments from customers we don't know about aren't recorded.
+    record_payment_event(
+        app,
+        &StripeCustomerId("cus_unknown".into()),
+        PaymentEventKind::Failed,
+        "evt_4",
+        "in_2",
+        2_000,
+        start,
+    )
+    .await
+    .unwrap();
+
+    let payment_events = payment_events_for_customer(app, &billing_customer)
+        .await
+        .unwrap();
+    assert_eq!(
+        payment_events
+            .iter()
+            .map(|payment_event| (payment_event.kind, payment_event.stripe_invoice_id.as_str()))
+            .collect::<Vec<_>>(),
+        vec![
+            (PaymentEventKind::Failed, "in_1"),
+            (PaymentEventKind::Failed, "in_1"),
+            (PaymentEventKind::Succeeded, "in_1"),
+        ]
+    );
+    assert_eq!(
+        payment_events
+            .iter()
+            .map(|payment_event| payment_event.occurred_at.clone())
+            .collect::<Vec<_>>(),
+        (0..3)
+            .map(|days| {
+                (start + chrono::Duration::days(days)).to_rfc3339_opts(SecondsFormat::Millis, true)
+            })
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn test_payment_status() {
+    let customer = |has_overdue_invoices| billing_customer::Model {
+        has_overdue_invoices,
+        ..Default::default()
+    };
+    let subscription = |status, cancellation_reason| billing_subscription::Model {
+        stripe_subscription_status: status,
+        stripe_cancellation_reason: cancellation_reason,
+        ..Default::default()
+    };
+
+    // Users who have never subscribed are only past due if they have overdue invoices.
+    assert_eq!(payment_status(None, None), PaymentStatus::Current);
+    assert_eq!(
+        payment_status(Some(&customer(false)), None),
+        PaymentStatus::Current
+    );
+    assert_eq!(
+        payment_status(Some(&customer(true)), None),
+        PaymentStatus::PastDue
+    );
+
+    for (has_overdue_invoices, status, cancellation_reason, expected) in [
+        (
+            false,
+            StripeSubscriptionStatus::Active,
+            None,
+            PaymentStatus::Current,
+        ),
+        (
+            false,
+            StripeSubscriptionStatus::Trialing,
+            None,
+            PaymentStatus::Current,
+        ),
+        (
+            false,
+            StripeSubscriptionStatus::Canceled,
+            Some(StripeCancellationReason::CancellationRequested),
+            PaymentStatus::Current,
+        ),
+        (
+            true,
+            StripeSubscriptionStatus::Active,
+            None,
+            PaymentStatus::PastDue,
+        ),
+        (
+            false,
+            StripeSubscriptionStatus::PastDue,
+            None,
+            PaymentStatus::PastDue,
+        ),
+        (
+            false,
+            StripeSubscriptionStatus::Unpaid,
+            None,
+            PaymentStatus::PastDue,
+        ),
+        (
+            false,
+            StripeSubscriptionStatus::Incomplete,
+            None,
+            PaymentStatus::ActionRequired,
+        ),
+        (
+            true,
+            StripeSubscriptionStatus::Incomplete,
+            None,
+            PaymentStatus::ActionRequired,
+        ),
+        (
+            false,
+            StripeSubscriptionStatus::Canceled,
+            Some(StripeCancellationReason::PaymentDisputed),
+            PaymentStatus::Disputed,
+        ),
+        (
+            true,
+            StripeSubscriptionStatus::Canceled,
+            Some(StripeCancellationReason::PaymentDisputed),
+            PaymentStatus::Disputed,
+        ),
+    ] {
+        assert_eq!(
+            payment_status(
+                Some(&customer(has_overdue_invoices)),
+                Some(&subscription(status, cancellation_reason)),
+            ),
+            expected,
+            "has_overdue_invoices: {has_overdue_invoices}, status: {status:?}, cancellation_reason: {cancellation_reason:?}"
+        );
+    }
+
+    assert_eq!(
+        serde_json::to_value(PaymentStatus::ActionRequired).unwrap(),
+        json!("action_required")
+    );
+}
+
+#[test]
+fn test_is_card_expiring_soon() {
+    let card = |exp_month, exp_year| StripeCard {
+        brand: "visa".into(),
+        last4: "4242".into(),
+        exp_month,
+        exp_year,
+    };
+    let now = "2025-07-15T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+    // Cards are valid through the end of their expiry month.
+    assert_eq!(
+        card_expires_at(&card(8, 2025)),
+        Some("2025-09-01T00:00:00Z".parse().unwrap())
+    );
+    assert_eq!(
+        card_expires_at(&card(12, 2025)),
+        Some("2026-01-01T00:00:00Z".parse().unwrap())
+    );
+
+    // Within the window.
+    assert!(is_card_expiring_soon(&card(7, 2025), now));
+    assert!(is_card_expiring_soon(&card(8, 2025), now));
+
+    // Beyond the window.
+    assert!(!is_card_expiring_soon(&card(9, 2025), now));
+    assert!(!is_card_expiring_soon(&card(12, 2025), now));
+    assert!(!is_card_expiring_soon(&card(8, 2026), now));
+
+    // Already expired.
+    assert!(is_card_expiring_soon(&card(6, 2025), now));
+}
+
+#[gpui::test]
+async fn test_remind_users_of_expiring_payment_methods(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+    let billing_tasks = BillingTasks::new();
+    let shutdown = billing_tasks.shutdown_signal();
+
+    // User 1's card is about to expire, while user 2's card is good for another year.
+    let now = Utc::now();
+    let soon = now + chrono::Duration::days(10);
+    let next_year = now + chrono::Duration::days(365);
+
+    let mut users = Vec::new();
+    for (ix, card_expiry) in [soon, next_year].into_iter().enumerate() {
+        let user = test_app
+            .create_user(&format!("user{ix}"), ix as i32 + 1)
+            .await;
+        let customer_id = test_app.create_stripe_customer(&format!("cus_{ix}"), &user);
+        test_app
+            .stripe_client
+            .default_payment_methods
+            .lock()
+            .insert(
+                customer_id.clone(),
+                StripePaymentMethod {
+                    id: StripePaymentMethodId(format!("pm_{ix}").into()),
+                    card: Some(StripeCard {
+                        brand: "visa".into(),
+                        last4: "4242".into(),
+                        exp_month: card_expiry.month(),
+                        exp_year: card_expiry.year(),
+                    }),
+                },
+            );
+
+        let billing_customer = app
+            .db
+            .create_billing_customer(&CreateBillingCustomerParams {
+                user_id: user.id,
+                stripe_customer_id: customer_id.to_string(),
+            })
+            .await
+            .unwrap();
+        app.db
+            .create_billing_subscription(&CreateBillingSubscriptionParams {
+                billing_customer_id: billing_customer.id,
+                kind: Some(SubscriptionKind::ZedPro),
+                unclassified: false,
+                stripe_subscription_id: format!("sub_{ix}"),
+                stripe_subscription_status: StripeSubscriptionStatus::Active,
+                stripe_cancellation_reason: None,
+                stripe_current_period_start: None,
+                stripe_current_period_end: None,
+                seats: 1,
+                monthly_recurring_revenue_in_cents: None,
+                ended_at: None,
+            })
+            .await
+            .unwrap();
+
+        users.push(user);
+    }
+
+    // The card that expires soon is flagged when listing subscriptions.
+    for (user, expiring_soon) in users.iter().zip([true, false]) {
+        let response = list_billing_subscriptions(
+            Extension(app.clone()),
+            Query(ListBillingSubscriptionsParams {
+                github_user_id: user.github_user_id,
+                locale: None,
+                status: None,
+                active_only: false,
+                limit: None,
+                offset: None,
+            }),
+            HeaderMap::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(response.payment_method_expiring_soon, expiring_soon);
+    }
+
+    // Only the user whose card expires soon is reminded.
+    let reminded = remind_users_of_expiring_payment_methods(app, &stripe_client, now, &shutdown)
+        .await
+        .unwrap();
+    assert_eq!(reminded, vec![users[0].id]);
+
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(users[0].id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(billing_customer.payment_method_expiry_reminded_at.is_some());
+
+    // Users aren't reminded again within the same warning period.
+    let reminded = remind_users_of_expiring_payment_methods(
+        app,
+        &stripe_client,
+        now + chrono::Duration::days(1),
+        &shutdown,
+    )
+    .await
+    .unwrap();
+    assert_eq!(reminded, Vec::<UserId>::new());
+}
+
+#[gpui::test]
+async fn test_bulk_update_billing_preferences(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+
+    let user_without_preferences = test_app.create_user("user1", 1).await;
+    let user_with_preferences = test_app.create_user("user2", 2).await;
+    app.db
+        .create_billing_preferences(
+            user_with_preferences.id,
+            &crate::db::CreateBillingPreferencesParams {
+                max_monthly_llm_usage_spending_in_cents: 1_000,
+                model_request_overages_enabled: false,
+                model_request_overages_spend_limit_in_cents: 0,
+            },
+        )
+        .await
+        .unwrap();
+
+    let (report, user_ids) = bulk_update_billing_preferences_inner(
+        app,
+        &BulkUpdateBillingPreferencesBody {
+            github_user_ids: vec![1, 2, 3],
+            preferences: BillingPreferencesValues {
+                max_monthly_llm_usage_spending_in_cents: 5_000,
+                model_request_overages_enabled: true,
+                model_request_overages_spend_limit_in_cents: 2_000,
+            },
+        },
+    )
+    .await;
+
+    // The unknown user fails without affecting the others.
+    assert_eq!(
+        report,
+        BulkUpdateBillingPreferencesResponse {
+            created: vec![1],
+            updated: vec![2],
+            failed: vec![FailedBillingPreferencesUpdateJson {
+                github_user_id: 3,
+                error: "user not found".into(),
+            }],
+        }
+    );
+    assert_eq!(
+        user_ids,
+        vec![user_without_preferences.id, user_with_preferences.id]
+    );
+
+    for user in [&user_without_preferences, &user_with_preferences] {
+        let preferences = app
+            .db
+            .get_billing_preferences(user.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(preferences.max_monthly_llm_usage_spending_in_cents, 5_000);
+        assert!(preferences.model_request_overages_enabled);
+        assert_eq!(
+            preferences.model_request_overages_spend_limit_in_cents,
+            2_000
+        );
+    }
+}
+
+#[test]
+fn test_normalize_tax_id() {
+    assert_eq!(
+        normalize_tax_id(StripeTaxIdKind::EuVat, "de 123.456.789"),
+        Some("DE123456789".to_string())
+    );
+    assert_eq!(
+        normalize_tax_id(StripeTaxIdKind::EuVat, "EL123456789"),
+        Some("EL123456789".to_string())
+    );
+    assert_eq!(
+        normalize_tax_id(StripeTaxIdKind::EuVat, "US123456789"),
+        None
+    );
+    assert_eq!(normalize_tax_id(StripeTaxIdKind::EuVat, "DE"), None);
+    assert_eq!(
+        normalize_tax_id(StripeTaxIdKind::GbVat, "GB 980 7806 84"),
+        Some("GB980780684".to_string())
+    );
+    assert_eq!(normalize_tax_id(StripeTaxIdKind::GbVat, "GB98078068"), None);
+    assert_eq!(
+        normalize_tax_id(StripeTaxIdKind::AuAbn, "12-345-678-912"),
+        Some("12345678912".to_string())
+    );
+    assert_eq!(normalize_tax_id(StripeTaxIdKind::CaBn, "12345678A"), None);
+    assert_eq!(
+        normalize_tax_id(StripeTaxIdKind::InGst, "12abcde1234f1z5"),
+        Some("12ABCDE1234F1Z5".to_string())
+    );
+    assert_eq!(
+        normalize_tax_id(StripeTaxIdKind::InGst, "12ABCDE1234F1Y5"),
+        None
+    );
+    assert_eq!(
+        normalize_tax_id(StripeTaxIdKind::EuVat, "DE123456789!"),
+        None
+    );
+}
+
+#[gpui::test]
+async fn test_update_tax_id(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    app.db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    // Malformed tax IDs are rejected without calling Stripe.
+    let error = update_tax_id_for_user(
+        app,
+        &stripe_client,
+        &user,
+        StripeTaxIdKind::EuVat,
+        "not a vat number",
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::BAD_REQUEST, _, _)));
+    assert!(test_app.stripe_client.customer_tax_ids.lock().is_empty());
+
+    let tax_id = update_tax_id_for_user(
+        app,
+        &stripe_client,
+        &user,
+        StripeTaxIdKind::EuVat,
+        "de 123 456 789",
+        Some(StripeTaxExempt::Reverse),
+    )
+    .await
+    .unwrap();
+    let expected_tax_id = StripeTaxId {
+        kind: StripeTaxIdKind::EuVat,
+        value: "DE123456789".to_string(),
+    };
+    assert_eq!(tax_id, expected_tax_id);
+    assert_eq!(
+        test_app
+            .stripe_client
+            .customer_tax_ids
+            .lock()
+            .get(&customer_id),
+        Some(&vec![expected_tax_id.clone()])
+    );
+    assert_eq!(
+        test_app
+            .stripe_client
+            .customer_tax_exempt
+            .lock()
+            .get(&customer_id),
+        Some(&StripeTaxExempt::Reverse)
+    );
+
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(billing_customer.tax_id_type.as_deref(), Some("eu_vat"));
+    assert_eq!(billing_customer.tax_id.as_deref(), Some("DE123456789"));
+
+    // Submitting the same tax ID again doesn't add it to the Stripe customer twice.
+    update_tax_id_for_user(
+        app,
+        &stripe_client,
+        &user,
+        StripeTaxIdKind::EuVat,
+        "DE123456789",
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        test_app
+            .stripe_client
+            .customer_tax_ids
+            .lock()
+            .get(&customer_id),
+        Some(&vec![expected_tax_id])
+    );
+}
+
+#[gpui::test]
+async fn test_invoices_for_user(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    // Users who have never checked out don't have any invoices.
+    let user = test_app.create_user("user1", 1).await;
+    let invoices = invoices_for_user(app, &stripe_client, &user, 10)
+        .await
+        .unwrap();
+    assert!(invoices.is_empty());
+
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    app.db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let month = 30 * 24 * 60 * 60;
+    let period_start = 1_750_000_000;
+    test_app.stripe_client.invoices.lock().insert(
+        customer_id.clone(),
+        (0..3)
+            .map(|ix| StripeInvoice {
+                id: StripeInvoiceId(format!("in_{ix}").into()),
+                number: Some(format!("ZED-000{ix}")),
+                total: 2_000,
+                currency: "usd".to_string(),
+                status: Some(if ix == 2 {
+                    StripeInvoiceStatus::Open
+                } else {
+                    StripeInvoiceStatus::Paid
+                }),
+                period_start: period_start + ix * month,
+                period_end: period_start + (ix + 1) * month,
+                created: period_start + (ix + 1) * month,
+                hosted_invoice_url: Some(format!("https://invoice.stripe.com/i/in_{ix}")),
+                invoice_pdf: Some(format!("https://pay.stripe.com/invoice/in_{ix}/pdf")),
+            })
+            .collect(),
+    );
+
+    // The newest invoices are listed first, up to the limit.
+    let invoices = invoices_for_user(app, &stripe_client, &user, 2)
+        .await
+        .unwrap();
+    assert_eq!(
+        invoices
+            .iter()
+            .map(|invoice| invoice.stripe_invoice_id.as_str())
+            .collect::<Vec<_>>(),
+        vec!["in_2", "in_1"]
+    );
+
+    let invoice = &invoices[0];
+    assert_eq!(invoice.number.as_deref(), Some("ZED-0002"));
+    assert_eq!(invoice.amount_in_cents, 2_000);
+    assert_eq!(invoice.currency, "usd");
+    assert_eq!(invoice.status, Some(StripeInvoiceStatus::Open));
+    assert_eq!(invoice.period.start_at, "2025-08-14T15:06:40.000Z");
+    assert_eq!(invoice.period.end_at, "2025-09-13T15:06:40.000Z");
+    assert_eq!(
+        invoice.invoice_pdf_url.as_deref(),
+        Some("https://pay.stripe.com/invoice/in_2/pdf")
+    );
+}
+
+#[gpui::test]
+async fn test_payment_methods_for_user(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    // Users who have never checked out don't have any payment methods.
+    let user = test_app.create_user("user1", 1).await;
+    let payment_methods = payment_methods_for_user(app, &stripe_client, &user)
+        .await
+        .unwrap();
+    assert!(payment_methods.is_empty());
+
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    app.db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let card = |id: &str, brand: &str, last4: &str| StripePaymentMethod {
+        id: StripePaymentMethodId(id.into()),
+        card: Some(StripeCard {
+            brand: brand.into(),
+            last4: last4.into(),
+            exp_month: 12,
+            exp_year: 2030,
+        }),
+    };
+    test_app.stripe_client.payment_methods.lock().insert(
+        customer_id.clone(),
+        vec![
+            card("pm_1", "visa", "4242"),
+            card("pm_2", "mastercard", "4444"),
+            // Payment methods other than cards aren't listed.
+            StripePaymentMethod {
+                id: StripePaymentMethodId("pm_3".into()),
+                card: None,
+            },
+        ],
+    );
+    test_app
+        .stripe_client
+        .default_payment_methods
+        .lock()
+        .insert(customer_id.clone(), card("pm_2", "mastercard", "4444"));
+
+    let payment_methods = payment_methods_for_user(app, &stripe_client, &user)
+        .await
+        .unwrap();
+    assert_eq!(
+        payment_methods,
+        vec![
+            PaymentMethodJson {
+                brand: "visa".into(),
+                last4: "4242".into(),
+                exp_month: 12,
+                exp_year: 2030,
+                is_default: false,
+            },
+            PaymentMethodJson {
+                brand: "mastercard".into(),
+                last4: "4444".into(),
+                exp_month: 12,
+                exp_year: 2030,
+                is_default: true,
+            },
+        ]
+    );
+}
+
+#[gpui::test]
+async fn test_clear_overdue_invoices(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                has_overdue_invoices: ActiveValue::set(true),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    let invoice = |id: &str, status| StripeInvoice {
+        id: StripeInvoiceId(id.into()),
+        number: None,
+        total: 2_000,
+        currency: "usd".to_string(),
+        status: Some(status),
+        period_start: 1_750_000_000,
+        period_end: 1_752_592_000,
+        created: 1_752_592_000,
+        hosted_invoice_url: None,
+        invoice_pdf: None,
+    };
+    test_app.stripe_client.invoices.lock().insert(
+        customer_id.clone(),
+        vec![
+            invoice("in_1", StripeInvoiceStatus::Paid),
+            invoice("in_2", StripeInvoiceStatus::Open),
+        ],
+    );
+    let has_overdue_invoices = || async {
+        app.db
+            .get_billing_customer_by_user_id(user.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .has_overdue_invoices
+    };
+
+    // The customer still owes an invoice, so they stay overdue.
+    let response = clear_overdue_invoices_for_user(app, &stripe_client, &user)
+        .await
+        .unwrap();
+    assert_eq!(
+        response,
+        ClearOverdueInvoicesResponse {
+            has_overdue_invoices: true,
+            open_invoice_ids: vec!["in_2".to_string()],
+        }
+    );
+    assert!(has_overdue_invoices().await);
+
+    // Once the invoice is paid out-of-band, the overdue state is cleared.
+    test_app
+        .stripe_client
+        .invoices
+        .lock()
+        .get_mut(&customer_id)
+        .unwrap()[1]
+        .status = Some(StripeInvoiceStatus::Paid);
+    for _ in 0..2 {
+        let response = clear_overdue_invoices_for_user(app, &stripe_client, &user)
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            ClearOverdueInvoicesResponse {
+                has_overdue_invoices: false,
+                open_invoice_ids: Vec::new(),
+            }
+        );
+    }
+    assert!(!has_overdue_invoices().await);
+}
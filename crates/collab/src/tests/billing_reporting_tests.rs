@@ -0,0 +1,855 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use gpui::TestAppContext;
+use pretty_assertions::assert_eq;
+use sea_orm::ActiveValue;
+use serde_json::json;
+use stripe::SubscriptionStatus;
+
+use crate::api::billing::metrics::render_billing_metrics;
+use crate::api::billing::{
+    ChurnRisk, ChurnRiskSignals, PlanCostEstimate, PlanMonthlyRecurringRevenue, SyncedModelUsage,
+    effective_monthly_cost_in_cents, is_usage_declining, model_usage_synced_row,
+    monthly_recurring_amount_in_cents, monthly_recurring_revenue_by_plan, negative_usage_meter_row,
+    plan_recommendation, subscription_canceled_row, sync_subscription,
+    sync_subscriptions_for_checkout,
+};
+use crate::db::billing_subscription::{self, StripeSubscriptionStatus, SubscriptionKind};
+use crate::db::{
+    CreateBillingCustomerParams, CreateBillingSubscriptionParams, UpdateBillingCustomerParams,
+};
+use crate::llm::db::subscription_usage_meter::{CompletionMode, UsageDimensions};
+use crate::stripe_client::{
+    StripeCancellationDetails, StripeCancellationDetailsReason, StripeClient, StripeCustomerId,
+    StripeDiscount, StripePrice, StripePriceId, StripePriceRecurring, StripePriceRecurringInterval,
+    StripeSubscription, StripeSubscriptionId, StripeSubscriptionItem, StripeSubscriptionItemId,
+};
+use crate::tests::billing_test_app::make_test_app;
+
+fn recurring_price(
+    unit_amount: i64,
+    interval: StripePriceRecurringInterval,
+    interval_count: u64,
+) -> StripePrice {
+    StripePrice {
+        id: StripePriceId("price_1".into()),
+        unit_amount: Some(unit_amount),
+        lookup_key: None,
+        recurring: Some(StripePriceRecurring {
+            interval,
+            interval_count,
+            meter: None,
+        }),
+    }
+}
+
+#[test]
+fn test_monthly_recurring_amount_in_cents() {
+    // Monthly prices contribute their full amount.
+    let monthly = recurring_price(2_000, StripePriceRecurringInterval::Month, 1);
+    assert_eq!(monthly_recurring_amount_in_cents(&monthly, 1), Some(2_000));
+
+    // Annual prices are spread across twelve months.
+    let annual = recurring_price(19_200, StripePriceRecurringInterval::Year, 1);
+    assert_eq!(monthly_recurring_amount_in_cents(&annual, 1), Some(1_600));
+
+    // Quarterly prices are spread across three months.
+    let quarterly = recurring_price(5_400, StripePriceRecurringInterval::Month, 3);
+    assert_eq!(
+        monthly_recurring_amount_in_cents(&quarterly, 1),
+        Some(1_800)
+    );
+
+    // Per-seat prices are multiplied by the number of seats.
+    assert_eq!(monthly_recurring_amount_in_cents(&monthly, 5), Some(10_000));
+    assert_eq!(monthly_recurring_amount_in_cents(&annual, 5), Some(8_000));
+
+    // Usage-based prices don't contribute.
+    let metered = StripePrice {
+        recurring: Some(StripePriceRecurring {
+            interval: StripePriceRecurringInterval::Month,
+            interval_count: 1,
+            meter: Some("meter_1".to_string()),
+        }),
+        ..monthly.clone()
+    };
+    assert_eq!(monthly_recurring_amount_in_cents(&metered, 1), None);
+
+    // One-off prices don't contribute.
+    let one_off = StripePrice {
+        recurring: None,
+        ..monthly
+    };
+    assert_eq!(monthly_recurring_amount_in_cents(&one_off, 1), None);
+}
+
+#[gpui::test]
+async fn test_monthly_recurring_revenue_by_plan(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    for (id, unit_amount) in [("price_zed_pro", 2_000), ("price_zed_free", 0)] {
+        let mut prices = test_app.stripe_client.prices.lock();
+        let price = prices.get_mut(&StripePriceId(id.into())).unwrap();
+        price.unit_amount = Some(unit_amount);
+        price.recurring = Some(StripePriceRecurring {
+            interval: StripePriceRecurringInterval::Month,
+            interval_count: 1,
+            meter: None,
+        });
+    }
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+    stripe_billing.initialize().await.unwrap();
+
+    for (ix, status) in [
+        SubscriptionStatus::Active,
+        SubscriptionStatus::Active,
+        SubscriptionStatus::Trialing,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let user = test_app.create_user(&format!("user{ix}"), ix as i32).await;
+        let customer_id = test_app.create_stripe_customer(&format!("cus_{ix}"), &user);
+        test_app.create_stripe_subscription(
+            &format!("sub_{ix}"),
+            &customer_id,
+            "price_zed_pro",
+            status,
+        );
+        sync_subscriptions_for_checkout(app, &stripe_client, &user)
+            .await
+            .unwrap();
+    }
+
+    let plans = monthly_recurring_revenue_by_plan(
+        app,
+        &stripe_billing,
+        Utc::now() + chrono::Duration::hours(1),
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        plans,
+        vec![
+            PlanMonthlyRecurringRevenue {
+                plan: zed_llm_client::Plan::ZedPro.as_str().to_string(),
+                subscriptions: 2,
+                mrr_in_cents: 4_000,
+            },
+            PlanMonthlyRecurringRevenue {
+                plan: zed_llm_client::Plan::ZedFree.as_str().to_string(),
+                subscriptions: 0,
+                mrr_in_cents: 0,
+            },
+        ]
+    );
+}
+
+#[gpui::test]
+async fn test_monthly_recurring_revenue_per_subscription(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    {
+        let mut prices = test_app.stripe_client.prices.lock();
+        let price = prices
+            .get_mut(&StripePriceId("price_zed_pro".into()))
+            .unwrap();
+        price.recurring = Some(StripePriceRecurring {
+            interval: StripePriceRecurringInterval::Month,
+            interval_count: 1,
+            meter: None,
+        });
+        let annual_price = StripePrice {
+            id: StripePriceId("price_zed_pro_annual".into()),
+            unit_amount: Some(19_200),
+            lookup_key: Some("zed-pro-annual".into()),
+            recurring: Some(StripePriceRecurring {
+                interval: StripePriceRecurringInterval::Year,
+                interval_count: 1,
+                meter: None,
+            }),
+        };
+        prices.insert(annual_price.id.clone(), annual_price);
+    }
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+    stripe_billing.initialize().await.unwrap();
+
+    let subscribe = async |ix: i32, price_id: &str, seats: u64, percent_off: Option<f64>| {
+        let user = test_app.create_user(&format!("user{ix}"), ix).await;
+        let customer_id = test_app.create_stripe_customer(&format!("cus_{ix}"), &user);
+        let subscription_id = test_app.create_stripe_subscription(
+            &format!("sub_{ix}"),
+            &customer_id,
+            price_id,
+            SubscriptionStatus::Active,
+        );
+        {
+            let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+            let subscription = subscriptions.get_mut(&subscription_id).unwrap();
+            subscription.items[0].quantity = Some(seats);
+            subscription.discount = percent_off.map(|percent_off| StripeDiscount {
+                coupon_name: None,
+                promotion_code: None,
+                percent_off: Some(percent_off),
+                amount_off: None,
+                end: None,
+            });
+        }
+        sync_subscriptions_for_checkout(app, &stripe_client, &user)
+            .await
+            .unwrap()
+            .unwrap();
+        subscription_id
+    };
+
+    // Each subscription contributes its own price for every seat, normalized to a month, after its discount.
+    subscribe(1, "price_zed_pro", 1, None).await;
+    subscribe(2, "price_zed_pro_annual", 1, None).await;
+    subscribe(3, "price_zed_pro", 3, None).await;
+    subscribe(4, "price_zed_pro", 1, Some(50.0)).await;
+    let canceled_subscription_id = subscribe(5, "price_zed_pro", 1, None).await;
+
+    // Subscriptions that haven't been synced since we started recording their cost are valued at the plan's price.
+    let user = test_app.create_user("user6", 6).await;
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: "cus_6".into(),
+        })
+        .await
+        .unwrap();
+    app.db
+        .create_billing_subscription(&CreateBillingSubscriptionParams {
+            billing_customer_id: billing_customer.id,
+            kind: Some(SubscriptionKind::ZedPro),
+            unclassified: false,
+            stripe_subscription_id: "sub_6".into(),
+            stripe_subscription_status: StripeSubscriptionStatus::Active,
+            stripe_cancellation_reason: None,
+            stripe_current_period_start: None,
+            stripe_current_period_end: None,
+            seats: 2,
+            monthly_recurring_revenue_in_cents: None,
+            ended_at: None,
+        })
+        .await
+        .unwrap();
+
+    let zed_pro_mrr = async || {
+        let plans = monthly_recurring_revenue_by_plan(
+            app,
+            &stripe_billing,
+            Utc::now() + chrono::Duration::hours(1),
+        )
+        .await
+        .unwrap();
+        let plan = plans
+            .into_iter()
+            .find(|plan| plan.plan == zed_llm_client::Plan::ZedPro.as_str())
+            .unwrap();
+        (plan.subscriptions, plan.mrr_in_cents)
+    };
+    assert_eq!(
+        zed_pro_mrr().await,
+        (6, 2_000 + 1_600 + 6_000 + 1_000 + 2_000 + 4_000)
+    );
+
+    // Once a subscription is canceled, it no longer contributes, and we record when it ended.
+    let canceled_subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let subscription = subscriptions.get_mut(&canceled_subscription_id).unwrap();
+        subscription.status = SubscriptionStatus::Canceled;
+        subscription.clone()
+    };
+    sync_subscription(app, &stripe_client, canceled_subscription)
+        .await
+        .unwrap();
+    let canceled_billing_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_5")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(
+        canceled_billing_subscription
+            .ended_at
+            .is_some_and(|ended_at| ended_at <= Utc::now().naive_utc())
+    );
+    assert_eq!(
+        zed_pro_mrr().await,
+        (5, 2_000 + 1_600 + 6_000 + 1_000 + 4_000)
+    );
+}
+
+#[gpui::test]
+async fn test_model_usage_synced_row(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let user = test_app.create_user("user", 1).await;
+
+    let period_start_at = DateTime::from_timestamp(1_750_000_000, 0).unwrap();
+    let period_end_at = period_start_at + chrono::Duration::days(30);
+    let billing_subscription = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedPro),
+        stripe_subscription_status: StripeSubscriptionStatus::Active,
+        stripe_current_period_start: Some(period_start_at.timestamp()),
+        stripe_current_period_end: Some(period_end_at.timestamp()),
+        ..Default::default()
+    };
+
+    // No row is emitted for users without any usage.
+    let row = model_usage_synced_row(
+        &user,
+        &billing_subscription,
+        &[
+            SyncedModelUsage {
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Normal,
+                dimensions: UsageDimensions::default(),
+                requests: 0,
+            },
+            SyncedModelUsage {
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Max,
+                dimensions: UsageDimensions::default(),
+                requests: 0,
+            },
+        ],
+    );
+    assert!(row.is_none());
+
+    // Users with usage get a single row with the breakdown of their nonzero usage.
+    let row = model_usage_synced_row(
+        &user,
+        &billing_subscription,
+        &[
+            SyncedModelUsage {
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Normal,
+                dimensions: UsageDimensions::default(),
+                requests: 12,
+            },
+            SyncedModelUsage {
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Max,
+                dimensions: UsageDimensions::default(),
+                requests: 0,
+            },
+            SyncedModelUsage {
+                model: "claude-opus-4".into(),
+                mode: CompletionMode::Max,
+                dimensions: UsageDimensions::default(),
+                requests: 3,
+            },
+        ],
+    )
+    .unwrap();
+    assert_eq!(row.event_type, "Model Usage Synced");
+    assert_eq!(row.user_id, Some(user.metrics_id.to_string()));
+    assert_eq!(
+        row.event_properties,
+        json!({
+            "user_id": user.id,
+            "billing_subscription_id": billing_subscription.id,
+            "period_start_at": period_start_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "period_end_at": period_end_at.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "total_requests": 15,
+            "models": [
+                { "model": "claude-sonnet-4", "mode": "normal", "requests": 12 },
+                { "model": "claude-opus-4", "mode": "max", "requests": 3 },
+            ],
+        })
+    );
+}
+
+#[gpui::test]
+async fn test_negative_usage_meter_row(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let user = test_app.create_user("user", 1).await;
+    let billing_subscription = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedPro),
+        stripe_subscription_status: StripeSubscriptionStatus::Active,
+        ..Default::default()
+    };
+
+    // No alert is emitted when all of the meters are valid.
+    let row = negative_usage_meter_row(
+        &user,
+        &billing_subscription,
+        &[SyncedModelUsage {
+            model: "claude-sonnet-4".into(),
+            mode: CompletionMode::Normal,
+            dimensions: UsageDimensions::default(),
+            requests: 12,
+        }],
+    );
+    assert!(row.is_none());
+
+    // A negative meter is reported, without the valid ones alongside it.
+    let row = negative_usage_meter_row(
+        &user,
+        &billing_subscription,
+        &[
+            SyncedModelUsage {
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Normal,
+                dimensions: UsageDimensions::default(),
+                requests: 12,
+            },
+            SyncedModelUsage {
+                model: "claude-opus-4".into(),
+                mode: CompletionMode::Max,
+                dimensions: UsageDimensions::default(),
+                requests: -4,
+            },
+        ],
+    )
+    .unwrap();
+    assert_eq!(row.event_type, "Negative Usage Meter Detected");
+    assert_eq!(row.user_id, Some(user.metrics_id.to_string()));
+    assert_eq!(
+        row.event_properties,
+        json!({
+            "user_id": user.id,
+            "billing_subscription_id": billing_subscription.id,
+            "models": [
+                { "model": "claude-opus-4", "mode": "max", "requests": -4 },
+            ],
+        })
+    );
+}
+
+#[test]
+fn test_churn_risk() {
+    let none = ChurnRiskSignals::default();
+    let cases = [
+        (none, ChurnRisk::Low),
+        (
+            ChurnRiskSignals {
+                usage_declined: true,
+                ..none
+            },
+            ChurnRisk::Medium,
+        ),
+        (
+            ChurnRiskSignals {
+                trial_ending_without_payment_method: true,
+                ..none
+            },
+            ChurnRisk::Medium,
+        ),
+        (
+            ChurnRiskSignals {
+                usage_declined: true,
+                trial_ending_without_payment_method: true,
+                ..none
+            },
+            ChurnRisk::High,
+        ),
+        (
+            ChurnRiskSignals {
+                has_overdue_invoices: true,
+                ..none
+            },
+            ChurnRisk::High,
+        ),
+        (
+            ChurnRiskSignals {
+                is_canceling: true,
+                ..none
+            },
+            ChurnRisk::High,
+        ),
+        (
+            ChurnRiskSignals {
+                usage_declined: true,
+                has_overdue_invoices: true,
+                is_canceling: true,
+                trial_ending_without_payment_method: true,
+            },
+            ChurnRisk::High,
+        ),
+    ];
+
+    for (signals, expected_risk) in cases {
+        assert_eq!(
+            signals.churn_risk(),
+            expected_risk,
+            "unexpected risk for {signals:?}"
+        );
+    }
+}
+
+#[test]
+fn test_is_usage_declining() {
+    // Usage on track to be less than half of the previous period is declining.
+    assert!(is_usage_declining(10, 100, 0.5));
+    assert!(is_usage_declining(0, 100, 0.9));
+
+    // Usage on track to be at least half of the previous period is not declining.
+    assert!(!is_usage_declining(25, 100, 0.5));
+    assert!(!is_usage_declining(120, 100, 1.0));
+
+    // It's too early in the period to tell.
+    assert!(!is_usage_declining(0, 100, 0.1));
+
+    // There's no previous usage to compare against.
+    assert!(!is_usage_declining(10, 0, 0.5));
+}
+
+#[test]
+fn test_plan_recommendation() {
+    let limit = |limit: zed_llm_client::UsageLimit| match limit {
+        zed_llm_client::UsageLimit::Limited(limit) => limit,
+        zed_llm_client::UsageLimit::Unlimited => i32::MAX,
+    };
+    let free_model_requests = limit(zed_llm_client::Plan::ZedFree.model_requests_limit());
+    let free_edit_predictions = limit(zed_llm_client::Plan::ZedFree.edit_predictions_limit());
+    let pro_model_requests = limit(zed_llm_client::Plan::ZedPro.model_requests_limit());
+
+    // Light usage fits within Zed Free, which costs nothing.
+    let recommendation = plan_recommendation(free_model_requests / 2, 100, 2_000, 4, 0);
+    assert_eq!(recommendation.recommended_plan, "zed_free");
+    assert_eq!(
+        recommendation
+            .plans
+            .iter()
+            .map(|estimate| (estimate.plan.as_str(), estimate.total_cost_in_cents))
+            .collect::<Vec<_>>(),
+        vec![("zed_free", 0), ("zed_pro", 2_000)]
+    );
+
+    // Heavy usage goes beyond Zed Free's hard limit, and Zed Pro bills the requests beyond its limit as overages.
+    let recommendation = plan_recommendation(pro_model_requests + 100, 100, 2_000, 4, 0);
+    assert_eq!(recommendation.recommended_plan, "zed_pro");
+    assert_eq!(
+        recommendation.plans,
+        vec![
+            PlanCostEstimate {
+                plan: "zed_free".into(),
+                base_cost_in_cents: 0,
+                overage_cost_in_cents: 0,
+                total_cost_in_cents: 0,
+                model_requests_over_limit: pro_model_requests + 100 - free_model_requests,
+                edit_predictions_over_limit: 0,
+            },
+            PlanCostEstimate {
+                plan: "zed_pro".into(),
+                base_cost_in_cents: 2_000,
+                overage_cost_in_cents: 400,
+                total_cost_in_cents: 2_400,
+                model_requests_over_limit: 0,
+                edit_predictions_over_limit: 0,
+            },
+        ]
+    );
+
+    // The free overage grace isn't billed.
+    let recommendation = plan_recommendation(pro_model_requests + 100, 100, 2_000, 4, 30);
+    assert_eq!(recommendation.plans[1].overage_cost_in_cents, 280);
+
+    // Going beyond Zed Free's edit prediction limit also calls for Zed Pro.
+    let recommendation = plan_recommendation(0, free_edit_predictions + 1, 2_000, 4, 0);
+    assert_eq!(recommendation.recommended_plan, "zed_pro");
+    assert_eq!(recommendation.plans[0].edit_predictions_over_limit, 1);
+}
+
+#[test]
+fn test_effective_monthly_cost_in_cents() {
+    let now = Utc::now();
+    let subscription =
+        |unit_amount: i64,
+         interval: StripePriceRecurringInterval,
+         discount: Option<StripeDiscount>| StripeSubscription {
+            id: StripeSubscriptionId("sub_1".into()),
+            customer: StripeCustomerId("cus_1".into()),
+            status: SubscriptionStatus::Active,
+            current_period_start: now.timestamp(),
+            current_period_end: (now + chrono::Duration::days(30)).timestamp(),
+            items: vec![
+                StripeSubscriptionItem {
+                    id: StripeSubscriptionItemId("si_usage".into()),
+                    price: Some(StripePrice {
+                        id: StripePriceId("price_usage".into()),
+                        unit_amount: Some(4),
+                        lookup_key: None,
+                        recurring: Some(StripePriceRecurring {
+                            interval: StripePriceRecurringInterval::Month,
+                            interval_count: 1,
+                            meter: Some("mtr_1".into()),
+                        }),
+                    }),
+                    quantity: None,
+                },
+                StripeSubscriptionItem {
+                    id: StripeSubscriptionItemId("si_plan".into()),
+                    price: Some(StripePrice {
+                        id: StripePriceId("price_plan".into()),
+                        unit_amount: Some(unit_amount),
+                        lookup_key: None,
+                        recurring: Some(StripePriceRecurring {
+                            interval,
+                            interval_count: 1,
+                            meter: None,
+                        }),
+                    }),
+                    quantity: Some(1),
+                },
+            ],
+            cancel_at: None,
+            cancel_at_period_end: false,
+            cancellation_details: None,
+            discount,
+            currency: "usd".into(),
+            pause_collection: None,
+        };
+    let discount = |percent_off: Option<f64>, amount_off: Option<i64>, end: Option<i64>| {
+        Some(StripeDiscount {
+            coupon_name: None,
+            promotion_code: None,
+            percent_off,
+            amount_off,
+            end,
+        })
+    };
+
+    // A plain monthly subscription costs its price, without any usage.
+    assert_eq!(
+        effective_monthly_cost_in_cents(
+            &subscription(2_000, StripePriceRecurringInterval::Month, None),
+            now
+        ),
+        Some(2_000)
+    );
+
+    // An annual subscription is amortized over the year.
+    assert_eq!(
+        effective_monthly_cost_in_cents(
+            &subscription(19_200, StripePriceRecurringInterval::Year, None),
+            now
+        ),
+        Some(1_600)
+    );
+
+    // Active discounts are taken off before normalizing.
+    assert_eq!(
+        effective_monthly_cost_in_cents(
+            &subscription(
+                2_000,
+                StripePriceRecurringInterval::Month,
+                discount(Some(25.0), None, None)
+            ),
+            now
+        ),
+        Some(1_500)
+    );
+    assert_eq!(
+        effective_monthly_cost_in_cents(
+            &subscription(
+                19_200,
+                StripePriceRecurringInterval::Year,
+                discount(
+                    None,
+                    Some(1_200),
+                    Some((now + chrono::Duration::days(1)).timestamp())
+                )
+            ),
+            now
+        ),
+        Some(1_500)
+    );
+
+    // Discounts that have ended no longer apply.
+    assert_eq!(
+        effective_monthly_cost_in_cents(
+            &subscription(
+                2_000,
+                StripePriceRecurringInterval::Month,
+                discount(
+                    Some(25.0),
+                    None,
+                    Some((now - chrono::Duration::days(1)).timestamp())
+                )
+            ),
+            now
+        ),
+        Some(2_000)
+    );
+
+    // Trials cost nothing until they end.
+    let mut trial = subscription(2_000, StripePriceRecurringInterval::Month, None);
+    trial.status = SubscriptionStatus::Trialing;
+    assert_eq!(effective_monthly_cost_in_cents(&trial, now), Some(0));
+}
+
+#[gpui::test]
+async fn test_subscription_canceled_row(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let mut subscription = test_app.stripe_client.subscriptions.lock()[&subscription_id].clone();
+
+    // Subscriptions that aren't canceled aren't reported.
+    assert!(
+        subscription_canceled_row(&user, None, &subscription, Some(SubscriptionKind::ZedPro))
+            .is_none()
+    );
+
+    // A subscription that becomes canceled is reported with why it was canceled.
+    subscription.status = SubscriptionStatus::Canceled;
+    subscription.cancellation_details = Some(StripeCancellationDetails {
+        reason: Some(StripeCancellationDetailsReason::PaymentFailed),
+    });
+    for previous_status in [None, Some(StripeSubscriptionStatus::Active)] {
+        let row = subscription_canceled_row(
+            &user,
+            previous_status,
+            &subscription,
+            Some(SubscriptionKind::ZedPro),
+        )
+        .unwrap();
+        assert_eq!(row.event_type, "Subscription Canceled");
+        assert_eq!(row.user_id, Some(user.metrics_id.to_string()));
+        assert_eq!(
+            row.event_properties,
+            json!({
+                "user_id": user.id,
+                "stripe_subscription_id": "sub_1",
+                "plan": "zed_pro",
+                "cancellation_reason": "payment_failed",
+                "payment_failed": true,
+            })
+        );
+    }
+
+    subscription.cancellation_details = Some(StripeCancellationDetails {
+        reason: Some(StripeCancellationDetailsReason::CancellationRequested),
+    });
+    let row = subscription_canceled_row(
+        &user,
+        Some(StripeSubscriptionStatus::Trialing),
+        &subscription,
+        Some(SubscriptionKind::ZedProTrial),
+    )
+    .unwrap();
+    assert_eq!(
+        row.event_properties,
+        json!({
+            "user_id": user.id,
+            "stripe_subscription_id": "sub_1",
+            "plan": "zed_pro_trial",
+            "cancellation_reason": "cancellation_requested",
+            "payment_failed": false,
+        })
+    );
+
+    // Re-syncing a subscription that was already canceled doesn't report it again.
+    assert!(
+        subscription_canceled_row(
+            &user,
+            Some(StripeSubscriptionStatus::Canceled),
+            &subscription,
+            Some(SubscriptionKind::ZedPro)
+        )
+        .is_none()
+    );
+}
+
+#[gpui::test]
+async fn test_billing_metrics(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+
+    let metrics = render_billing_metrics(app, Utc::now()).await.unwrap();
+    for metric_name in [
+        "billing_active_subscriptions",
+        "billing_active_trials",
+        "billing_overdue_customers",
+        "billing_stripe_events_processed_total",
+        "billing_stripe_events_failed_total",
+        "billing_stripe_events_last_polled_at_seconds",
+        "billing_stripe_events_last_poll_age_seconds",
+        "billing_usage_sync_duration_seconds",
+        "billing_usage_sync_user_duration_seconds",
+        "billing_usage_sync_users_skipped_total",
+        "billing_usage_sync_users_failing",
+    ] {
+        assert!(
+            metrics.contains(&format!("# TYPE {metric_name} ")),
+            "missing {metric_name} in:\n{metrics}"
+        );
+    }
+    assert!(metrics.contains("billing_active_subscriptions{kind=\"zed_pro\"} 0\n"));
+    assert!(metrics.contains("billing_active_trials 0\n"));
+    assert!(metrics.contains("billing_overdue_customers 0\n"));
+
+    // One user is subscribed to Zed Pro and another is trialing it.
+    for (github_login, github_user_id, status) in [
+        ("user1", 1, SubscriptionStatus::Active),
+        ("user2", 2, SubscriptionStatus::Trialing),
+    ] {
+        let user = test_app.create_user(github_login, github_user_id).await;
+        let customer_id = test_app.create_stripe_customer(&format!("cus_{github_login}"), &user);
+        let subscription_id = test_app.create_stripe_subscription(
+            &format!("sub_{github_login}"),
+            &customer_id,
+            "price_zed_pro",
+            status,
+        );
+        test_app.sync_stripe_subscription(&subscription_id).await;
+    }
+
+    // The subscribed user has an overdue invoice.
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_stripe_customer_id("cus_user1")
+        .await
+        .unwrap()
+        .unwrap();
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                has_overdue_invoices: ActiveValue::set(true),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    let metrics = render_billing_metrics(app, Utc::now()).await.unwrap();
+    assert!(metrics.contains("billing_active_subscriptions{kind=\"zed_pro\"} 1\n"));
+    assert!(metrics.contains("billing_active_subscriptions{kind=\"zed_pro_trial\"} 1\n"));
+    assert!(metrics.contains("billing_active_subscriptions{kind=\"zed_free\"} 0\n"));
+    assert!(metrics.contains("billing_active_trials 1\n"));
+    assert!(metrics.contains("billing_overdue_customers 1\n"));
+
+    // The gauges that are only refreshed here aren't served by `/metrics`, which would report them as of whenever the
+    // billing metrics were last rendered.
+    let default_metrics = prometheus::TextEncoder::new()
+        .encode_to_string(&prometheus::gather())
+        .unwrap();
+    assert!(default_metrics.contains("# TYPE billing_stripe_events_processed_total "));
+    for metric_name in [
+        "billing_active_subscriptions",
+        "billing_active_trials",
+        "billing_overdue_customers",
+        "billing_stripe_events_last_poll_age_seconds",
+    ] {
+        assert!(
+            !default_metrics.contains(&format!("# TYPE {metric_name} ")),
+            "unexpected {metric_name} in:\n{default_metrics}"
+        );
+    }
+}
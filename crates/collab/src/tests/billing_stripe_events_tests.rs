@@ -0,0 +1,585 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::time::Duration;
+
+use anyhow::{Context as _, anyhow};
+use chrono::{DateTime, Utc};
+use collections::HashSet;
+use gpui::TestAppContext;
+use pretty_assertions::assert_eq;
+use sea_orm::{DbErr, RuntimeErr};
+use serde_json::json;
+
+use crate::Config;
+use crate::api::billing::{
+    BillingTasks, DEFAULT_PROCESSED_STRIPE_EVENT_RETENTION, MAX_POLL_EVENTS_BACKOFF,
+    POLL_EVENTS_INTERVAL, PollEventsBackoff, STRIPE_WEBHOOK_TOLERANCE, StripeEventPosition,
+    advanced_stripe_event_cursor, is_stale_stripe_event, oldest_stripe_event_age,
+    processed_stripe_event_retention, prune_processed_stripe_events, retry_billing_db_write,
+    spawn_periodic_billing_task, stale_stripe_event_row, stripe_api_version_mismatch,
+    stripe_events_created_since, verify_stripe_webhook_signature,
+};
+use crate::db::billing_subscription::{StripeSubscriptionStatus, SubscriptionKind};
+use crate::db::{
+    CreateBillingCustomerParams, CreateBillingSubscriptionParams, CreateProcessedStripeEventParams,
+    stripe_event_cursor,
+};
+use crate::executor::Executor;
+use crate::tests::billing_test_app::{make_test_app, make_test_app_with_config};
+
+#[test]
+fn test_stripe_api_version_mismatch() {
+    assert_eq!(
+        stripe_api_version_mismatch(Some("2023-10-16"), "2023-10-16"),
+        None
+    );
+    assert_eq!(stripe_api_version_mismatch(None, "2023-10-16"), None);
+    assert_eq!(
+        stripe_api_version_mismatch(Some("2024-06-20"), "2023-10-16"),
+        Some("has Stripe API version 2024-06-20, but we expect 2023-10-16".to_string())
+    );
+}
+
+#[gpui::test]
+async fn test_billing_tasks_shutdown(cx: &mut TestAppContext) {
+    let executor = Executor::Deterministic(cx.executor());
+    let billing_tasks = BillingTasks::new();
+    let log = Arc::new(parking_lot::Mutex::new(Vec::new()));
+
+    spawn_periodic_billing_task(
+        executor.clone(),
+        &billing_tasks,
+        "test",
+        Duration::from_secs(60),
+        {
+            let log = log.clone();
+            let executor = executor.clone();
+            move |shutdown| {
+                let log = log.clone();
+                let executor = executor.clone();
+                async move {
+                    for step in 0..3 {
+                        if shutdown.is_requested() {
+                            break;
+                        }
+
+                        // Simulate making a change in Stripe and then recording it in the database.
+                        log.lock().push(format!("update Stripe {step}"));
+                        executor.sleep(Duration::from_secs(1)).await;
+                        log.lock().push(format!("commit {step}"));
+                    }
+                }
+            }
+        },
+    );
+
+    cx.executor().run_until_parked();
+    assert_eq!(*log.lock(), vec!["update Stripe 0"]);
+
+    // Request a shutdown partway through a step.
+    let is_shut_down = Arc::new(AtomicBool::new(false));
+    cx.executor()
+        .spawn({
+            let is_shut_down = is_shut_down.clone();
+            async move {
+                billing_tasks.shutdown().await;
+                is_shut_down.store(true, SeqCst);
+            }
+        })
+        .detach();
+    cx.executor().run_until_parked();
+    assert!(!is_shut_down.load(SeqCst));
+
+    // The step in progress is completed, but no further steps are started.
+    cx.executor().advance_clock(Duration::from_secs(1));
+    cx.executor().run_until_parked();
+    assert_eq!(*log.lock(), vec!["update Stripe 0", "commit 0"]);
+    assert!(is_shut_down.load(SeqCst));
+
+    // The task doesn't run again.
+    cx.executor().advance_clock(Duration::from_secs(120));
+    cx.executor().run_until_parked();
+    assert_eq!(*log.lock(), vec!["update Stripe 0", "commit 0"]);
+}
+
+#[gpui::test]
+async fn test_prune_processed_stripe_events(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            processed_stripe_event_retention_days: Some(7),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+
+    let now = Utc::now();
+    let events = [
+        ("evt_last_month", now - chrono::Duration::days(30)),
+        ("evt_last_week", now - chrono::Duration::days(8)),
+        ("evt_few_days_ago", now - chrono::Duration::days(3)),
+        ("evt_just_now", now),
+    ];
+    for (event_id, created_at) in events {
+        app.db
+            .create_processed_stripe_event(&CreateProcessedStripeEventParams {
+                stripe_event_id: event_id.into(),
+                stripe_event_type: "customer.subscription.updated".into(),
+                stripe_event_created_timestamp: created_at.timestamp(),
+            })
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(prune_processed_stripe_events(app, now).await.unwrap(), 2);
+
+    let remaining = app
+        .db
+        .get_processed_stripe_events_by_event_ids(&events.map(|(event_id, _)| event_id))
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|event| event.stripe_event_id)
+        .collect::<HashSet<_>>();
+    assert_eq!(
+        remaining,
+        HashSet::from_iter(["evt_few_days_ago".to_string(), "evt_just_now".to_string()])
+    );
+
+    // The pruned events would be skipped as stale if Stripe returned them
+    // again, so they won't be reprocessed.
+    for (event_id, created_at) in events {
+        if !remaining.contains(event_id) {
+            assert!(
+                is_stale_stripe_event(&app.config, created_at.timestamp(), now),
+                "{event_id}"
+            );
+        }
+    }
+}
+
+#[test]
+fn test_stripe_events_created_since() {
+    let now = Utc::now();
+    let config = Config::test();
+    let stale_before = (now - chrono::Duration::days(1)).timestamp();
+    let cursor = |created: DateTime<Utc>| stripe_event_cursor::Model {
+        id: 1,
+        stripe_event_id: "evt_1".into(),
+        stripe_event_created_timestamp: created.timestamp(),
+        updated_at: now.naive_utc(),
+    };
+
+    // On a cold start, we only look back as far as the events we'd still process.
+    assert_eq!(
+        stripe_events_created_since(&config, None, now),
+        stale_before
+    );
+
+    // Otherwise, we resume from the cursor.
+    let recent = now - chrono::Duration::minutes(5);
+    assert_eq!(
+        stripe_events_created_since(&config, Some(&cursor(recent)), now),
+        recent.timestamp()
+    );
+
+    // A cursor from before the processing window doesn't make us look back any further.
+    assert_eq!(
+        stripe_events_created_since(&config, Some(&cursor(now - chrono::Duration::days(3))), now),
+        stale_before
+    );
+
+    // The processing window can be widened, e.g., to catch up after an outage.
+    let config = Config {
+        stale_stripe_event_age_seconds: Some(7 * 24 * 60 * 60),
+        ..Config::test()
+    };
+    let three_days_ago = now - chrono::Duration::days(3);
+    assert_eq!(
+        stripe_events_created_since(&config, Some(&cursor(three_days_ago)), now),
+        three_days_ago.timestamp()
+    );
+    assert!(!is_stale_stripe_event(
+        &config,
+        three_days_ago.timestamp(),
+        now
+    ));
+    assert!(is_stale_stripe_event(
+        &Config::test(),
+        three_days_ago.timestamp(),
+        now
+    ));
+}
+
+#[test]
+fn test_stale_stripe_event_row() {
+    let row = stale_stripe_event_row("evt_1", "customer.subscription.updated", 1_750_000_000);
+    assert_eq!(row.event_type, "Stale Stripe Event Dropped");
+    assert_eq!(row.user_id, None);
+    assert_eq!(
+        row.event_properties,
+        json!({
+            "stripe_event_id": "evt_1",
+            "stripe_event_type": "customer.subscription.updated",
+            "stripe_event_created_at": "2025-06-15T15:06:40.000Z",
+        })
+    );
+}
+
+#[test]
+fn test_poll_events_backoff() {
+    let rate_limited = || -> anyhow::Result<()> {
+        Err(
+            anyhow::Error::new(stripe::StripeError::Stripe(stripe::RequestError {
+                http_status: 429,
+                ..Default::default()
+            }))
+            .context("failed to list Stripe events"),
+        )
+    };
+    let failed = || -> anyhow::Result<()> {
+        Err(anyhow::Error::new(stripe::StripeError::Stripe(
+            stripe::RequestError {
+                http_status: 500,
+                ..Default::default()
+            },
+        )))
+    };
+
+    let mut backoff = PollEventsBackoff::default();
+    assert_eq!(backoff.delay_after(&Ok(())), POLL_EVENTS_INTERVAL);
+
+    // Each consecutive rate limit doubles the delay, up to the cap.
+    assert_eq!(
+        backoff.delay_after(&rate_limited()),
+        POLL_EVENTS_INTERVAL * 2
+    );
+    assert_eq!(
+        backoff.delay_after(&rate_limited()),
+        POLL_EVENTS_INTERVAL * 4
+    );
+
+    // Other errors don't back off, but don't reset the backoff either.
+    assert_eq!(backoff.delay_after(&failed()), POLL_EVENTS_INTERVAL);
+    assert_eq!(
+        backoff.delay_after(&rate_limited()),
+        POLL_EVENTS_INTERVAL * 8
+    );
+
+    for _ in 0..40 {
+        backoff.delay_after(&rate_limited());
+    }
+    assert_eq!(
+        backoff.delay_after(&rate_limited()),
+        MAX_POLL_EVENTS_BACKOFF
+    );
+
+    // A successful poll resets the backoff.
+    assert_eq!(backoff.delay_after(&Ok(())), POLL_EVENTS_INTERVAL);
+    assert_eq!(
+        backoff.delay_after(&rate_limited()),
+        POLL_EVENTS_INTERVAL * 2
+    );
+}
+
+#[test]
+fn test_oldest_stripe_event_age() {
+    let now = Utc::now();
+    let created = |age: chrono::Duration| (now - age).timestamp();
+
+    assert_eq!(oldest_stripe_event_age([], now), 0);
+    assert_eq!(
+        oldest_stripe_event_age(
+            [
+                created(chrono::Duration::minutes(1)),
+                created(chrono::Duration::hours(2)),
+                created(chrono::Duration::seconds(5)),
+            ],
+            now
+        ),
+        2 * 60 * 60
+    );
+    // Events stamped slightly in the future, due to clock skew, don't make the age negative.
+    assert_eq!(
+        oldest_stripe_event_age([created(chrono::Duration::seconds(-5))], now),
+        0
+    );
+}
+
+#[test]
+fn test_advanced_stripe_event_cursor() {
+    let position = |id: &str, created| StripeEventPosition {
+        id: id.into(),
+        created,
+    };
+    let done = |ids: &[&str]| ids.iter().map(|id| id.to_string()).collect::<HashSet<_>>();
+
+    // The pages are newest first, as Stripe returns them.
+    let pages = vec![
+        vec![position("evt_6", 60), position("evt_5", 50)],
+        vec![position("evt_4", 40), position("evt_3", 30)],
+        vec![position("evt_2", 20), position("evt_1", 10)],
+    ];
+
+    // The cursor moves to the newest event once every page is done.
+    assert_eq!(
+        advanced_stripe_event_cursor(
+            &pages,
+            &done(&["evt_1", "evt_2", "evt_3", "evt_4", "evt_5", "evt_6"])
+        ),
+        Some(&position("evt_6", 60))
+    );
+
+    // An event that isn't done holds the cursor back to the end of the last page before it.
+    assert_eq!(
+        advanced_stripe_event_cursor(
+            &pages,
+            &done(&["evt_1", "evt_2", "evt_3", "evt_5", "evt_6"])
+        ),
+        Some(&position("evt_2", 20))
+    );
+
+    // The cursor doesn't move partway through a page, nor past an unfinished page to the newer ones after it.
+    assert_eq!(
+        advanced_stripe_event_cursor(
+            &pages,
+            &done(&["evt_2", "evt_3", "evt_4", "evt_5", "evt_6"])
+        ),
+        None
+    );
+
+    assert_eq!(advanced_stripe_event_cursor(&[], &done(&[])), None);
+}
+
+#[test]
+fn test_processed_stripe_event_retention() {
+    let retention_days = |days| {
+        processed_stripe_event_retention(&Config {
+            processed_stripe_event_retention_days: days,
+            ..Config::test()
+        })
+    };
+
+    assert_eq!(
+        retention_days(None),
+        DEFAULT_PROCESSED_STRIPE_EVENT_RETENTION
+    );
+    assert_eq!(
+        retention_days(Some(7)),
+        Duration::from_secs(7 * 24 * 60 * 60)
+    );
+
+    // We never prune events that are recent enough to still be processed.
+    assert_eq!(
+        retention_days(Some(0)),
+        Config::test().stale_stripe_event_age()
+    );
+}
+
+#[gpui::test]
+async fn test_retry_billing_db_write(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+
+    let user = test_app.create_user("user1", 1).await;
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: "cus_1".into(),
+        })
+        .await
+        .unwrap();
+    let params = CreateBillingSubscriptionParams {
+        billing_customer_id: billing_customer.id,
+        kind: Some(SubscriptionKind::ZedPro),
+        unclassified: false,
+        stripe_subscription_id: "sub_1".into(),
+        stripe_subscription_status: StripeSubscriptionStatus::Active,
+        stripe_cancellation_reason: None,
+        stripe_current_period_start: None,
+        stripe_current_period_end: None,
+        seats: 1,
+        monthly_recurring_revenue_in_cents: None,
+        ended_at: None,
+    };
+
+    // The first attempt fails with a transient error, and the retry succeeds.
+    let attempts = AtomicUsize::new(0);
+    retry_billing_db_write(app, "create billing subscription", || {
+        let attempt = attempts.fetch_add(1, SeqCst);
+        async {
+            if attempt == 0 {
+                return Err(DbErr::Conn(RuntimeErr::Internal("connection reset".into())).into());
+            }
+            app.db.create_billing_subscription(&params).await
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(attempts.load(SeqCst), 2);
+    assert!(
+        app.db
+            .get_billing_subscription_by_stripe_subscription_id("sub_1")
+            .await
+            .unwrap()
+            .is_some()
+    );
+
+    // Errors that aren't transient aren't retried.
+    let attempts = AtomicUsize::new(0);
+    let result = retry_billing_db_write(app, "create billing subscription", || {
+        attempts.fetch_add(1, SeqCst);
+        app.db.create_billing_subscription(&params)
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(attempts.load(SeqCst), 1);
+
+    // We give up once we're out of retries.
+    let attempts = AtomicUsize::new(0);
+    let result = retry_billing_db_write(app, "create billing subscription", || {
+        attempts.fetch_add(1, SeqCst);
+        async { Err::<(), _>(DbErr::Conn(RuntimeErr::Internal("connection reset".into())).into()) }
+    })
+    .await;
+    assert!(result.is_err());
+    assert_eq!(
+        attempts.load(SeqCst),
+        app.config.billing_db_write_retries() as usize + 1
+    );
+}
+
+#[test]
+fn test_verify_stripe_webhook_signature() {
+    use crate::billing_webhooks::sign_payload;
+
+    let now = "2025-07-21T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let payload = br#"{"id":"evt_1","type":"customer.subscription.updated"}"#;
+    let timestamp = now.timestamp();
+
+    // Stripe signs webhooks the same way we sign our own.
+    let signature = sign_payload("whsec_test", timestamp, payload);
+    assert!(verify_stripe_webhook_signature("whsec_test", &signature, payload, now).is_ok());
+
+    // Signatures for other schemes are ignored, and any `v1` signature may match while the secret is being rolled.
+    let signature_with_extras = format!(
+        "t={timestamp},v1={},{},v0=deadbeef",
+        "0".repeat(64),
+        signature.split_once(',').unwrap().1
+    );
+    assert!(
+        verify_stripe_webhook_signature("whsec_test", &signature_with_extras, payload, now).is_ok()
+    );
+
+    // The signature must be for this secret and payload.
+    assert!(verify_stripe_webhook_signature("whsec_other", &signature, payload, now).is_err());
+    assert!(verify_stripe_webhook_signature("whsec_test", &signature, b"{}", now).is_err());
+
+    // The timestamp must be present and recent.
+    assert!(
+        verify_stripe_webhook_signature(
+            "whsec_test",
+            signature.split_once(',').unwrap().1,
+            payload,
+            now
+        )
+        .is_err()
+    );
+    assert!(
+        verify_stripe_webhook_signature(
+            "whsec_test",
+            &signature,
+            payload,
+            now + chrono::Duration::minutes(4)
+        )
+        .is_ok()
+    );
+    let old_signature = sign_payload("whsec_test", timestamp - 10 * 60, payload);
+    assert!(verify_stripe_webhook_signature("whsec_test", &old_signature, payload, now).is_err());
+}
+
+#[test]
+fn test_verify_stripe_webhook_signature_rejects_forgeries() {
+    use crate::billing_webhooks::sign_payload;
+
+    let now = "2025-07-21T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+    let payload = br#"{"id":"evt_1","type":"customer.subscription.updated"}"#;
+    let timestamp = now.timestamp();
+    let signature = sign_payload("whsec_test", timestamp, payload);
+    let (_, v1) = signature.split_once(",v1=").unwrap();
+
+    // Tampering with any part of the signature invalidates it.
+    let mut tampered = v1.to_string();
+    let last = if tampered.ends_with('0') { "1" } else { "0" };
+    tampered.replace_range(tampered.len() - 1.., last);
+    assert!(
+        verify_stripe_webhook_signature(
+            "whsec_test",
+            &format!("t={timestamp},v1={tampered}"),
+            payload,
+            now
+        )
+        .is_err()
+    );
+    assert!(
+        verify_stripe_webhook_signature(
+            "whsec_test",
+            &format!("t={timestamp},v1={}", &v1[..v1.len() - 2]),
+            payload,
+            now
+        )
+        .is_err()
+    );
+    assert!(
+        verify_stripe_webhook_signature(
+            "whsec_test",
+            &format!("t={timestamp},v1=not-hex"),
+            payload,
+            now
+        )
+        .is_err()
+    );
+
+    // The signature covers the timestamp, so a replayed request can't be given a fresh one.
+    assert!(
+        verify_stripe_webhook_signature(
+            "whsec_test",
+            &format!("t={},v1={v1}", timestamp + 1),
+            payload,
+            now
+        )
+        .is_err()
+    );
+
+    // Timestamps are rejected once they are outside of the tolerance in either direction.
+    let tolerance = chrono::Duration::from_std(STRIPE_WEBHOOK_TOLERANCE).unwrap();
+    let at_edge = sign_payload("whsec_test", (now - tolerance).timestamp(), payload);
+    assert!(verify_stripe_webhook_signature("whsec_test", &at_edge, payload, now).is_ok());
+    let too_old = sign_payload("whsec_test", (now - tolerance).timestamp() - 1, payload);
+    assert!(verify_stripe_webhook_signature("whsec_test", &too_old, payload, now).is_err());
+    let too_new = sign_payload("whsec_test", (now + tolerance).timestamp() + 1, payload);
+    assert!(verify_stripe_webhook_signature("whsec_test", &too_new, payload, now).is_err());
+
+    // With several `v1` signatures, at least one of them has to match.
+    assert!(
+        verify_stripe_webhook_signature(
+            "whsec_test",
+            &format!("t={timestamp},v1={},v1={tampered}", "0".repeat(64)),
+            payload,
+            now
+        )
+        .is_err()
+    );
+    assert!(
+        verify_stripe_webhook_signature(
+            "whsec_test",
+            &format!("t={timestamp},v1={tampered},v1={v1},v1={}", "0".repeat(64)),
+            payload,
+            now
+        )
+        .is_ok()
+    );
+}
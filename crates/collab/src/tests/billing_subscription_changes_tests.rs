@@ -0,0 +1,2052 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use gpui::TestAppContext;
+use pretty_assertions::assert_eq;
+use reqwest::StatusCode;
+use sea_orm::ActiveValue;
+use stripe::SubscriptionStatus;
+
+use crate::api::billing::localization::Locale;
+use crate::api::billing::{
+    BillingError, BillingSubscriptionJson, CancelableStatus, ERROR_CODE_HEADER,
+    FailedPriceMigrationJson, LocalScheduledChanges, MAX_TRIAL_EXTENSION_DAYS,
+    MigrateSubscriptionsToPriceBody, MigrateSubscriptionsToPriceResponse, NotifyPriceChangeBody,
+    NotifyPriceChangeResponse, ProductCode, REACTIVATION_WINDOW, ScheduledChangeKind,
+    WINBACK_WINDOW, WinbackOffer, cancel_scheduled_change_for_subscription,
+    ensure_minimum_term_ended, extend_trial_for_user, get_billing_subscription_for_user,
+    local_scheduled_changes, migrate_subscriptions_to_price_inner, notify_price_change_inner,
+    pause_billing_subscription_for_user, preview_billing_subscription_change_for_user,
+    reactivate_billing_subscription_for_user, record_winback_offer_for_user,
+    refund_billing_subscription_for_user, resume_billing_subscription_for_user, scheduled_changes,
+    subscription_pauses_remaining, sync_subscription, sync_subscriptions_for_checkout,
+    trial_days_remaining, winback_offer, winback_offer_for_user,
+};
+use crate::db::billing_subscription::{self, StripeSubscriptionStatus, SubscriptionKind};
+use crate::db::{
+    CreateBillingCustomerParams, CreateBillingSubscriptionParams,
+    CreateBillingSubscriptionPauseParams, UpdateBillingCustomerParams, billing_customer,
+    billing_subscription_pause,
+};
+use crate::stripe_client::{
+    StripeClient, StripeInvoiceLine, StripeInvoicePayment, StripePauseCollection,
+    StripePaymentIntentId, StripePaymentMethod, StripePaymentMethodId,
+    StripePreviewSubscriptionUpdateParams, StripePrice, StripePriceId, StripeProrationBehavior,
+    StripeSubscriptionId, StripeSubscriptionItemId, UpdateSubscriptionItems,
+};
+use crate::tests::billing_test_app::{format_timestamp, make_test_app, make_test_app_with_config};
+use crate::{Config, Error, Result};
+
+#[test]
+fn test_trial_days_remaining() {
+    let now = Utc::now();
+    let trial = |trial_end_at: DateTime<Utc>| billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedProTrial),
+        stripe_subscription_status: StripeSubscriptionStatus::Trialing,
+        stripe_current_period_start: Some((trial_end_at - chrono::Duration::days(14)).timestamp()),
+        stripe_current_period_end: Some(trial_end_at.timestamp()),
+        ..Default::default()
+    };
+    // Stripe's timestamps are in whole seconds.
+    let now = DateTime::from_timestamp(now.timestamp(), 0).unwrap();
+
+    assert_eq!(
+        trial_days_remaining(&trial(now + chrono::Duration::days(14)), now),
+        Some(14)
+    );
+
+    // A partial day counts as a whole one.
+    assert_eq!(
+        trial_days_remaining(
+            &trial(now + chrono::Duration::days(2) + chrono::Duration::hours(1)),
+            now
+        ),
+        Some(3)
+    );
+    assert_eq!(
+        trial_days_remaining(&trial(now + chrono::Duration::minutes(5)), now),
+        Some(1)
+    );
+
+    // A trial that has ended has no days left.
+    assert_eq!(trial_days_remaining(&trial(now), now), Some(0));
+    assert_eq!(
+        trial_days_remaining(&trial(now - chrono::Duration::days(1)), now),
+        Some(0)
+    );
+
+    // Only trials have days remaining.
+    let subscription = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedPro),
+        stripe_subscription_status: StripeSubscriptionStatus::Active,
+        ..trial(now + chrono::Duration::days(14))
+    };
+    assert_eq!(trial_days_remaining(&subscription, now), None);
+}
+
+#[test]
+fn test_scheduled_changes() {
+    let now = Utc::now();
+    let trial_end_at = now + chrono::Duration::days(14);
+    let cancel_at = now + chrono::Duration::days(30);
+
+    let trial = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedProTrial),
+        stripe_subscription_status: StripeSubscriptionStatus::Trialing,
+        stripe_current_period_start: Some(now.timestamp()),
+        stripe_current_period_end: Some(trial_end_at.timestamp()),
+        ..Default::default()
+    };
+
+    let kinds_with = |subscription: &billing_subscription::Model, local: &LocalScheduledChanges| {
+        scheduled_changes(subscription, local, now)
+            .into_iter()
+            .map(|change| (change.kind, change.is_cancelable))
+            .collect::<Vec<_>>()
+    };
+    let kinds = |subscription: &billing_subscription::Model| {
+        kinds_with(subscription, &LocalScheduledChanges::default())
+    };
+
+    assert_eq!(kinds(&trial), vec![(ScheduledChangeKind::EndTrial, false)]);
+
+    // A trial that is canceled after it converts has both changes pending, in order.
+    let canceled_after_trial = billing_subscription::Model {
+        stripe_cancel_at: Some(cancel_at.naive_utc()),
+        ..trial.clone()
+    };
+    assert_eq!(
+        kinds(&canceled_after_trial),
+        vec![
+            (ScheduledChangeKind::EndTrial, false),
+            (ScheduledChangeKind::DowngradeToFree, true),
+        ]
+    );
+
+    // A trial that is canceled when it ends never converts.
+    let canceled_at_trial_end = billing_subscription::Model {
+        stripe_cancel_at: Some(trial_end_at.naive_utc()),
+        ..trial.clone()
+    };
+    assert_eq!(
+        kinds(&canceled_at_trial_end),
+        vec![(ScheduledChangeKind::DowngradeToFree, true)]
+    );
+
+    let free = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedFree),
+        stripe_subscription_status: StripeSubscriptionStatus::Active,
+        ..Default::default()
+    };
+    assert_eq!(kinds(&free), vec![]);
+
+    // Once the period rolls over, changes that took effect in Stripe are no longer listed, even before we've synced
+    // the subscription.
+    let after_trial_end = trial_end_at + chrono::Duration::seconds(1);
+    assert_eq!(
+        scheduled_changes(
+            &canceled_after_trial,
+            &LocalScheduledChanges::default(),
+            after_trial_end
+        )
+        .into_iter()
+        .map(|change| (change.kind, change.is_cancelable))
+        .collect::<Vec<_>>(),
+        vec![(ScheduledChangeKind::DowngradeToFree, true)]
+    );
+    assert_eq!(
+        scheduled_changes(
+            &canceled_after_trial,
+            &LocalScheduledChanges::default(),
+            cancel_at + chrono::Duration::seconds(1)
+        ),
+        vec![]
+    );
+    assert_eq!(
+        scheduled_changes(
+            &canceled_at_trial_end,
+            &LocalScheduledChanges::default(),
+            after_trial_end
+        ),
+        vec![]
+    );
+
+    // The changes we schedule ourselves are listed alongside the ones in Stripe.
+    let resumes_at = now + chrono::Duration::days(20);
+    let paused = LocalScheduledChanges {
+        latest_pause: Some(billing_subscription_pause::Model {
+            paused_at: now.naive_utc(),
+            resumes_at: Some(resumes_at.naive_utc()),
+            ..Default::default()
+        }),
+        free_downgrade_at: None,
+    };
+    assert_eq!(
+        scheduled_changes(&canceled_after_trial, &paused, now)
+            .into_iter()
+            .map(|change| change.kind)
+            .collect::<Vec<_>>(),
+        vec![
+            ScheduledChangeKind::EndTrial,
+            ScheduledChangeKind::ResumeFromPause,
+            ScheduledChangeKind::DowngradeToFree,
+        ]
+    );
+
+    // A pause that already ended, or was extended until the user resumes, isn't listed.
+    assert_eq!(
+        scheduled_changes(
+            &canceled_after_trial,
+            &paused,
+            resumes_at + chrono::Duration::seconds(1)
+        )
+        .into_iter()
+        .map(|change| change.kind)
+        .collect::<Vec<_>>(),
+        vec![ScheduledChangeKind::DowngradeToFree]
+    );
+    let paused_indefinitely = LocalScheduledChanges {
+        latest_pause: Some(billing_subscription_pause::Model {
+            paused_at: now.naive_utc(),
+            resumes_at: None,
+            ..Default::default()
+        }),
+        free_downgrade_at: None,
+    };
+    assert_eq!(
+        kinds_with(&trial, &paused_indefinitely),
+        vec![(ScheduledChangeKind::EndTrial, false)]
+    );
+
+    // Once the subscription has ended, only the delayed move to Zed Free is left.
+    let ended = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedPro),
+        stripe_subscription_status: StripeSubscriptionStatus::Canceled,
+        stripe_cancel_at: Some(now.naive_utc()),
+        ..Default::default()
+    };
+    let ended_with_downgrade_pending = LocalScheduledChanges {
+        free_downgrade_at: Some(now + chrono::Duration::days(3)),
+        ..paused.clone()
+    };
+    assert_eq!(
+        kinds_with(&ended, &ended_with_downgrade_pending),
+        vec![(ScheduledChangeKind::DelayedDowngradeToFree, true)]
+    );
+}
+
+#[gpui::test]
+async fn test_cancel_scheduled_change(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let subscription = sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // The end of a trial can't be canceled, and nothing else is scheduled.
+    for kind in [
+        ScheduledChangeKind::EndTrial,
+        ScheduledChangeKind::DowngradeToFree,
+    ] {
+        let result = cancel_scheduled_change_for_subscription(
+            app,
+            &stripe_client,
+            subscription.clone(),
+            kind,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+    assert!(
+        test_app
+            .stripe_client
+            .stop_cancellation_calls
+            .lock()
+            .is_empty()
+    );
+
+    // The subscription is scheduled to be canceled at a given time, rather than at the end of the period.
+    let stripe_subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let stripe_subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        stripe_subscription.cancel_at = Some((Utc::now() + chrono::Duration::days(7)).timestamp());
+        stripe_subscription.cancel_at_period_end = false;
+        stripe_subscription.clone()
+    };
+    sync_subscription(app, &stripe_client, stripe_subscription)
+        .await
+        .unwrap();
+    let subscription = get_billing_subscription_for_user(app, &user, subscription.id)
+        .await
+        .unwrap();
+    assert_eq!(
+        scheduled_changes(&subscription, &LocalScheduledChanges::default(), Utc::now()).len(),
+        1
+    );
+
+    let subscription = cancel_scheduled_change_for_subscription(
+        app,
+        &stripe_client,
+        subscription,
+        ScheduledChangeKind::DowngradeToFree,
+    )
+    .await
+    .unwrap();
+    assert_eq!(subscription.stripe_cancel_at, None);
+    assert!(!subscription.stripe_cancel_at_period_end);
+    assert_eq!(
+        scheduled_changes(&subscription, &LocalScheduledChanges::default(), Utc::now()),
+        vec![]
+    );
+
+    // The cancellation time is cleared in Stripe too, so that Stripe doesn't still cancel the subscription.
+    assert_eq!(
+        *test_app.stripe_client.stop_cancellation_calls.lock(),
+        vec![subscription_id.clone()]
+    );
+    assert_eq!(
+        test_app.stripe_client.subscriptions.lock()[&subscription_id].cancel_at,
+        None
+    );
+
+    // Other users can't see the subscription.
+    let other_user = test_app.create_user("user2", 2).await;
+    assert!(
+        get_billing_subscription_for_user(app, &other_user, subscription.id)
+            .await
+            .is_err()
+    );
+}
+
+#[gpui::test]
+async fn test_cancel_scheduled_change_after_period_rollover(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let period_end_at = Utc::now() + chrono::Duration::days(1);
+    let stripe_subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let stripe_subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        stripe_subscription.current_period_end = period_end_at.timestamp();
+        stripe_subscription.cancel_at = Some(period_end_at.timestamp());
+        stripe_subscription.cancel_at_period_end = true;
+        stripe_subscription.clone()
+    };
+    sync_subscription(app, &stripe_client, stripe_subscription)
+        .await
+        .unwrap();
+    let subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        scheduled_changes(&subscription, &LocalScheduledChanges::default(), Utc::now())
+            .into_iter()
+            .map(|change| change.kind)
+            .collect::<Vec<_>>(),
+        vec![ScheduledChangeKind::DowngradeToFree]
+    );
+
+    // The period ends and Stripe cancels the subscription, but we haven't processed the webhook yet, so our copy of
+    // the subscription still has the downgrade pending.
+    {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let stripe_subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        stripe_subscription.status = SubscriptionStatus::Canceled;
+    }
+
+    // Canceling the downgrade checks with Stripe, finds that it already happened, and leaves the subscription alone.
+    let error = cancel_scheduled_change_for_subscription(
+        app,
+        &stripe_client,
+        subscription.clone(),
+        ScheduledChangeKind::DowngradeToFree,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::BAD_REQUEST, _, _)));
+    assert!(
+        test_app
+            .stripe_client
+            .stop_cancellation_calls
+            .lock()
+            .is_empty()
+    );
+    assert!(
+        test_app
+            .stripe_client
+            .get_subscription_calls
+            .lock()
+            .contains(&subscription_id)
+    );
+
+    // Our copy of the subscription has caught up with Stripe.
+    let subscription = app
+        .db
+        .get_billing_subscription_by_id(subscription.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::Canceled
+    );
+    assert_eq!(
+        scheduled_changes(&subscription, &LocalScheduledChanges::default(), Utc::now()),
+        vec![]
+    );
+}
+
+#[gpui::test]
+async fn test_cancel_concurrent_scheduled_changes(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            free_downgrade_delay_seconds: Some(60 * 60),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let stripe_subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let stripe_subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        stripe_subscription.cancel_at = Some((Utc::now() + chrono::Duration::days(20)).timestamp());
+        stripe_subscription.cancel_at_period_end = true;
+        stripe_subscription.clone()
+    };
+    sync_subscription(app, &stripe_client, stripe_subscription)
+        .await
+        .unwrap();
+    let subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_1")
+        .await
+        .unwrap()
+        .unwrap();
+
+    let now = Utc::now();
+    pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        subscription.id,
+        Some(now + chrono::Duration::days(10)),
+        None,
+        now,
+    )
+    .await
+    .unwrap();
+
+    let listed_kinds = |subscription: billing_subscription::Model| async move {
+        let local = local_scheduled_changes(app, &subscription).await.unwrap();
+        scheduled_changes(&subscription, &local, Utc::now())
+            .into_iter()
+            .map(|change| change.kind)
+            .collect::<Vec<_>>()
+    };
+    // The user is moved to Zed Free once the delay has passed while the subscription is paused.
+    assert_eq!(
+        listed_kinds(subscription.clone()).await,
+        vec![
+            ScheduledChangeKind::DelayedDowngradeToFree,
+            ScheduledChangeKind::ResumeFromPause,
+            ScheduledChangeKind::DowngradeToFree,
+        ]
+    );
+
+    // Canceling the resume keeps the subscription paused until the user resumes it, and leaves the downgrades alone.
+    let subscription = cancel_scheduled_change_for_subscription(
+        app,
+        &stripe_client,
+        subscription,
+        ScheduledChangeKind::ResumeFromPause,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        test_app
+            .stripe_client
+            .pause_subscription_calls
+            .lock()
+            .last()
+            .cloned(),
+        Some((subscription_id.clone(), None))
+    );
+    assert_eq!(
+        app.db
+            .get_latest_billing_subscription_pause(subscription.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .resumes_at,
+        None
+    );
+    assert_eq!(
+        test_app.stripe_client.subscriptions.lock()[&subscription_id].pause_collection,
+        Some(StripePauseCollection { resumes_at: None })
+    );
+    assert_eq!(
+        listed_kinds(subscription.clone()).await,
+        vec![
+            ScheduledChangeKind::DelayedDowngradeToFree,
+            ScheduledChangeKind::DowngradeToFree,
+        ]
+    );
+    assert!(
+        cancel_scheduled_change_for_subscription(
+            app,
+            &stripe_client,
+            subscription.clone(),
+            ScheduledChangeKind::ResumeFromPause,
+        )
+        .await
+        .is_err()
+    );
+
+    // The subscription ends, so the user will be moved to Zed Free once the delay has passed, unless they cancel it.
+    test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Canceled);
+    test_app.sync_stripe_subscription(&subscription_id).await;
+    let subscription = app
+        .db
+        .get_billing_subscription_by_id(subscription.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        listed_kinds(subscription.clone()).await,
+        vec![ScheduledChangeKind::DelayedDowngradeToFree]
+    );
+
+    let subscription = cancel_scheduled_change_for_subscription(
+        app,
+        &stripe_client,
+        subscription,
+        ScheduledChangeKind::DelayedDowngradeToFree,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        app.db
+            .get_billing_customer_by_user_id(user.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .free_downgrade_at,
+        None
+    );
+    assert_eq!(listed_kinds(subscription).await, vec![]);
+}
+
+#[test]
+fn test_cancelable_status() {
+    let now = Utc::now();
+
+    let zed_pro = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedPro),
+        stripe_subscription_status: StripeSubscriptionStatus::Active,
+        ..Default::default()
+    };
+
+    let cases = [
+        (zed_pro.clone(), CancelableStatus::Cancelable),
+        (
+            billing_subscription::Model {
+                kind: Some(SubscriptionKind::ZedProTrial),
+                stripe_subscription_status: StripeSubscriptionStatus::Trialing,
+                ..Default::default()
+            },
+            CancelableStatus::Cancelable,
+        ),
+        (
+            billing_subscription::Model {
+                kind: Some(SubscriptionKind::ZedFree),
+                ..zed_pro.clone()
+            },
+            CancelableStatus::FreePlan,
+        ),
+        (
+            billing_subscription::Model {
+                stripe_cancel_at: Some(now.naive_utc()),
+                stripe_cancel_at_period_end: true,
+                ..zed_pro.clone()
+            },
+            CancelableStatus::AlreadyCanceling,
+        ),
+        (
+            billing_subscription::Model {
+                stripe_subscription_status: StripeSubscriptionStatus::Canceled,
+                ..zed_pro.clone()
+            },
+            CancelableStatus::IneligibleStatus,
+        ),
+        (
+            billing_subscription::Model {
+                stripe_subscription_status: StripeSubscriptionStatus::Incomplete,
+                ..zed_pro.clone()
+            },
+            CancelableStatus::IneligibleStatus,
+        ),
+        // Zed Free takes precedence over other reasons.
+        (
+            billing_subscription::Model {
+                kind: Some(SubscriptionKind::ZedFree),
+                stripe_subscription_status: StripeSubscriptionStatus::Canceled,
+                ..zed_pro.clone()
+            },
+            CancelableStatus::FreePlan,
+        ),
+    ];
+
+    for (subscription, expected_status) in cases {
+        let json =
+            BillingSubscriptionJson::new(subscription.clone(), Locale::default(), Duration::ZERO);
+        assert_eq!(
+            json.cancelable_status, expected_status,
+            "unexpected status for {subscription:?}"
+        );
+        assert_eq!(
+            json.is_cancelable,
+            expected_status == CancelableStatus::Cancelable
+        );
+    }
+}
+
+#[test]
+fn test_minimum_term() {
+    let now = Utc::now();
+    let minimum_term = Duration::from_secs(30 * 24 * 60 * 60);
+
+    let zed_pro = |created_days_ago: i64| billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedPro),
+        stripe_subscription_status: StripeSubscriptionStatus::Active,
+        created_at: (now - chrono::Duration::days(created_days_ago)).naive_utc(),
+        ..Default::default()
+    };
+
+    // Zed Pro can't be canceled until the minimum term has ended.
+    let recent = zed_pro(10);
+    assert_eq!(
+        CancelableStatus::for_subscription(&recent, minimum_term, now),
+        CancelableStatus::MinimumTerm
+    );
+    let ends_at = recent.created_at.and_utc() + chrono::Duration::days(30);
+    assert_eq!(
+        ensure_minimum_term_ended(&recent, minimum_term, now),
+        Err(BillingError::MinimumTerm(ends_at))
+    );
+    assert_eq!(
+        ensure_minimum_term_ended(&recent, minimum_term, ends_at),
+        Ok(())
+    );
+    assert_eq!(
+        CancelableStatus::for_subscription(&recent, minimum_term, ends_at),
+        CancelableStatus::Cancelable
+    );
+
+    let old = zed_pro(45);
+    assert_eq!(
+        CancelableStatus::for_subscription(&old, minimum_term, now),
+        CancelableStatus::Cancelable
+    );
+    assert_eq!(ensure_minimum_term_ended(&old, minimum_term, now), Ok(()));
+
+    // Subscriptions are returned to clients with the same minimum term, so they don't offer a cancellation that would
+    // be rejected.
+    let json = BillingSubscriptionJson::new(recent.clone(), Locale::default(), minimum_term);
+    assert!(!json.is_cancelable);
+    assert_eq!(json.cancelable_status, CancelableStatus::MinimumTerm);
+
+    // Without a minimum term, Zed Pro can be canceled right away.
+    assert_eq!(
+        CancelableStatus::for_subscription(&recent, Duration::ZERO, now),
+        CancelableStatus::Cancelable
+    );
+    assert_eq!(
+        ensure_minimum_term_ended(&recent, Duration::ZERO, now),
+        Ok(())
+    );
+
+    // Trials can always be canceled.
+    let trial = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedProTrial),
+        stripe_subscription_status: StripeSubscriptionStatus::Trialing,
+        ..recent.clone()
+    };
+    assert_eq!(
+        CancelableStatus::for_subscription(&trial, minimum_term, now),
+        CancelableStatus::Cancelable
+    );
+    assert_eq!(ensure_minimum_term_ended(&trial, minimum_term, now), Ok(()));
+
+    // Other reasons take precedence over the minimum term.
+    let canceling = billing_subscription::Model {
+        stripe_cancel_at: Some(now.naive_utc()),
+        ..recent.clone()
+    };
+    assert_eq!(
+        CancelableStatus::for_subscription(&canceling, minimum_term, now),
+        CancelableStatus::AlreadyCanceling
+    );
+
+    assert_eq!(
+        serde_json::to_value(CancelableStatus::MinimumTerm).unwrap(),
+        "minimum_term"
+    );
+}
+
+#[gpui::test]
+async fn test_migrate_subscriptions_to_price(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let new_price = StripePrice {
+        id: StripePriceId("price_zed_pro_v2".into()),
+        unit_amount: Some(2_500),
+        lookup_key: None,
+        recurring: None,
+    };
+    test_app
+        .stripe_client
+        .prices
+        .lock()
+        .insert(new_price.id.clone(), new_price.clone());
+
+    // Users 1 and 2 are on the old price, user 3 is already on the new price, and user 4's
+    // subscription is missing from Stripe.
+    let subscriptions = [
+        ("sub_1", Some("price_zed_pro")),
+        ("sub_2", Some("price_zed_pro")),
+        ("sub_3", Some("price_zed_pro_v2")),
+        ("sub_4", None),
+    ];
+    for (ix, (subscription_id, price_id)) in subscriptions.into_iter().enumerate() {
+        let user = test_app
+            .create_user(&format!("user{ix}"), ix as i32 + 1)
+            .await;
+        let customer_id = test_app.create_stripe_customer(&format!("cus_{ix}"), &user);
+        if let Some(price_id) = price_id {
+            test_app.create_stripe_subscription(
+                subscription_id,
+                &customer_id,
+                price_id,
+                SubscriptionStatus::Active,
+            );
+        }
+
+        let billing_customer = app
+            .db
+            .create_billing_customer(&CreateBillingCustomerParams {
+                user_id: user.id,
+                stripe_customer_id: customer_id.to_string(),
+            })
+            .await
+            .unwrap();
+        app.db
+            .create_billing_subscription(&CreateBillingSubscriptionParams {
+                billing_customer_id: billing_customer.id,
+                kind: Some(SubscriptionKind::ZedPro),
+                unclassified: false,
+                stripe_subscription_id: subscription_id.to_string(),
+                stripe_subscription_status: StripeSubscriptionStatus::Active,
+                stripe_cancellation_reason: None,
+                stripe_current_period_start: None,
+                stripe_current_period_end: None,
+                seats: 1,
+                monthly_recurring_revenue_in_cents: None,
+                ended_at: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    let body = MigrateSubscriptionsToPriceBody {
+        old_price_id: "price_zed_pro".into(),
+        new_price_id: "price_zed_pro_v2".into(),
+        prorate: false,
+        dry_run: true,
+        require_notice: false,
+    };
+
+    let expected_report = MigrateSubscriptionsToPriceResponse {
+        dry_run: true,
+        migrated: vec!["sub_1".into(), "sub_2".into()],
+        skipped: 1,
+        awaiting_notice: Vec::new(),
+        failed: vec![FailedPriceMigrationJson {
+            stripe_subscription_id: "sub_4".into(),
+            error: "no subscription found for StripeSubscriptionId(\"sub_4\")".into(),
+        }],
+    };
+
+    // A dry run reports what would be migrated, without updating any subscriptions.
+    let report = migrate_subscriptions_to_price_inner(app, &stripe_client, &body)
+        .await
+        .unwrap();
+    assert_eq!(report, expected_report);
+    assert!(
+        test_app
+            .stripe_client
+            .update_subscription_calls
+            .lock()
+            .is_empty()
+    );
+
+    let report = migrate_subscriptions_to_price_inner(
+        app,
+        &stripe_client,
+        &MigrateSubscriptionsToPriceBody {
+            dry_run: false,
+            ..body
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        report,
+        MigrateSubscriptionsToPriceResponse {
+            dry_run: false,
+            ..expected_report
+        }
+    );
+
+    let mut calls = test_app
+        .stripe_client
+        .update_subscription_calls
+        .lock()
+        .clone();
+    calls.sort_by(|(a, _), (b, _)| a.0.cmp(&b.0));
+    assert_eq!(
+        calls
+            .into_iter()
+            .map(|(subscription_id, params)| (
+                subscription_id,
+                params.items,
+                params.proration_behavior
+            ))
+            .collect::<Vec<_>>(),
+        vec![
+            (
+                StripeSubscriptionId("sub_1".into()),
+                Some(vec![UpdateSubscriptionItems {
+                    id: Some(StripeSubscriptionItemId("sub_1_item".into())),
+                    price: Some(new_price.id.clone()),
+                }]),
+                Some(StripeProrationBehavior::None),
+            ),
+            (
+                StripeSubscriptionId("sub_2".into()),
+                Some(vec![UpdateSubscriptionItems {
+                    id: Some(StripeSubscriptionItemId("sub_2_item".into())),
+                    price: Some(new_price.id.clone()),
+                }]),
+                Some(StripeProrationBehavior::None),
+            ),
+        ]
+    );
+
+    // Migrating to an unknown price is rejected.
+    let result = migrate_subscriptions_to_price_inner(
+        app,
+        &stripe_client,
+        &MigrateSubscriptionsToPriceBody {
+            old_price_id: "price_zed_pro".into(),
+            new_price_id: "price_unknown".into(),
+            prorate: false,
+            dry_run: true,
+            require_notice: false,
+        },
+    )
+    .await;
+    assert!(result.is_err());
+}
+
+#[gpui::test]
+async fn test_notify_price_change(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let new_price = StripePrice {
+        id: StripePriceId("price_zed_pro_v2".into()),
+        unit_amount: Some(2_500),
+        lookup_key: None,
+        recurring: None,
+    };
+    test_app
+        .stripe_client
+        .prices
+        .lock()
+        .insert(new_price.id.clone(), new_price.clone());
+
+    let create_subscription = async |ix: i32, subscription_id: &str, price_id: &str| {
+        let user = test_app.create_user(&format!("user{ix}"), ix).await;
+        let customer_id = test_app.create_stripe_customer(&format!("cus_{ix}"), &user);
+        test_app.create_stripe_subscription(
+            subscription_id,
+            &customer_id,
+            price_id,
+            SubscriptionStatus::Active,
+        );
+
+        let billing_customer = app
+            .db
+            .create_billing_customer(&CreateBillingCustomerParams {
+                user_id: user.id,
+                stripe_customer_id: customer_id.to_string(),
+            })
+            .await
+            .unwrap();
+        let billing_subscription = app
+            .db
+            .create_billing_subscription(&CreateBillingSubscriptionParams {
+                billing_customer_id: billing_customer.id,
+                kind: Some(SubscriptionKind::ZedPro),
+                unclassified: false,
+                stripe_subscription_id: subscription_id.to_string(),
+                stripe_subscription_status: StripeSubscriptionStatus::Active,
+                stripe_cancellation_reason: None,
+                stripe_current_period_start: None,
+                stripe_current_period_end: None,
+                seats: 1,
+                monthly_recurring_revenue_in_cents: None,
+                ended_at: None,
+            })
+            .await
+            .unwrap();
+
+        (user, billing_subscription)
+    };
+
+    // Users 1 and 2 are on the old price, and user 3 is already on the new price.
+    let (user_1, subscription_1) = create_subscription(1, "sub_1", "price_zed_pro").await;
+    let (user_2, subscription_2) = create_subscription(2, "sub_2", "price_zed_pro").await;
+    let (_user_3, subscription_3) = create_subscription(3, "sub_3", "price_zed_pro_v2").await;
+
+    // The notice was given a month before the new price took effect.
+    let effective_at = Utc::now() - chrono::Duration::days(1);
+    let notified_at = effective_at - chrono::Duration::days(30);
+    let body = NotifyPriceChangeBody {
+        old_price_id: "price_zed_pro".into(),
+        new_price_id: "price_zed_pro_v2".into(),
+        effective_at,
+    };
+
+    let (report, notifications) =
+        notify_price_change_inner(app, &stripe_client, &body, notified_at)
+            .await
+            .unwrap();
+    assert_eq!(
+        report,
+        NotifyPriceChangeResponse {
+            notified: vec!["sub_1".into(), "sub_2".into()],
+            already_notified: 0,
+            skipped: 1,
+            failed: Vec::new(),
+        }
+    );
+
+    let mut notified_users = notifications
+        .iter()
+        .map(|(user_id, _)| *user_id)
+        .collect::<Vec<_>>();
+    notified_users.sort();
+    assert_eq!(notified_users, vec![user_1.id, user_2.id]);
+    for (_, notification) in &notifications {
+        assert_eq!(notification.kind, "BillingPriceChange");
+    }
+
+    // The notice is recorded for each of the users on the old price.
+    for subscription in [&subscription_1, &subscription_2] {
+        let notice = app
+            .db
+            .get_billing_price_change_notice(subscription.id, "price_zed_pro_v2")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(notice.amount_in_cents, 2_500);
+        assert_eq!(
+            notice.effective_at.and_utc().timestamp(),
+            effective_at.timestamp()
+        );
+    }
+    assert!(
+        app.db
+            .get_billing_price_change_notice(subscription_3.id, "price_zed_pro_v2")
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // Users are only notified once.
+    let (report, notifications) =
+        notify_price_change_inner(app, &stripe_client, &body, notified_at)
+            .await
+            .unwrap();
+    assert_eq!(report.notified, Vec::<String>::new());
+    assert_eq!(report.already_notified, 2);
+    assert!(notifications.is_empty());
+
+    // When notice is required, subscriptions whose subscribers weren't given notice aren't migrated.
+    create_subscription(4, "sub_4", "price_zed_pro").await;
+    let report = migrate_subscriptions_to_price_inner(
+        app,
+        &stripe_client,
+        &MigrateSubscriptionsToPriceBody {
+            old_price_id: "price_zed_pro".into(),
+            new_price_id: "price_zed_pro_v2".into(),
+            prorate: false,
+            dry_run: true,
+            require_notice: true,
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        report.migrated,
+        vec!["sub_1".to_string(), "sub_2".to_string()]
+    );
+    assert_eq!(report.awaiting_notice, vec!["sub_4".to_string()]);
+
+    // Price changes have to be announced ahead of time.
+    let result = notify_price_change_inner(app, &stripe_client, &body, Utc::now()).await;
+    assert!(result.is_err());
+}
+
+#[gpui::test]
+async fn test_winback_eligibility(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            winback_coupon_id: Some("coupon_winback".into()),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+    let now = Utc::now();
+    let canceled_at = now - chrono::Duration::days(10);
+
+    let create_subscription = async |billing_customer: &billing_customer::Model,
+                                     kind: SubscriptionKind,
+                                     status: StripeSubscriptionStatus,
+                                     period_end: DateTime<Utc>| {
+        app.db
+            .create_billing_subscription(&CreateBillingSubscriptionParams {
+                billing_customer_id: billing_customer.id,
+                kind: Some(kind),
+                unclassified: false,
+                stripe_subscription_id: format!(
+                    "sub_{}_{kind:?}_{status:?}",
+                    billing_customer.stripe_customer_id
+                ),
+                stripe_subscription_status: status,
+                stripe_cancellation_reason: None,
+                stripe_current_period_start: Some(
+                    (period_end - chrono::Duration::days(30)).timestamp(),
+                ),
+                stripe_current_period_end: Some(period_end.timestamp()),
+                seats: 1,
+                monthly_recurring_revenue_in_cents: None,
+                ended_at: None,
+            })
+            .await
+            .unwrap();
+    };
+
+    // Creates a user whose Zed Pro subscription ended at the given time.
+    let create_churned_user =
+        async |github_login: &str, github_user_id: i32, canceled_at: DateTime<Utc>| {
+            let user = test_app.create_user(github_login, github_user_id).await;
+            let billing_customer = app
+                .db
+                .create_billing_customer(&CreateBillingCustomerParams {
+                    user_id: user.id,
+                    stripe_customer_id: format!("cus_{github_login}"),
+                })
+                .await
+                .unwrap();
+            create_subscription(
+                &billing_customer,
+                SubscriptionKind::ZedPro,
+                StripeSubscriptionStatus::Canceled,
+                canceled_at,
+            )
+            .await;
+            (user, billing_customer)
+        };
+
+    // A user who recently canceled Zed Pro and is now on Zed Free is eligible.
+    let (churned_user, billing_customer) =
+        create_churned_user("churned-user", 1, canceled_at).await;
+    create_subscription(
+        &billing_customer,
+        SubscriptionKind::ZedFree,
+        StripeSubscriptionStatus::Active,
+        now + chrono::Duration::days(20),
+    )
+    .await;
+    let expected_offer = WinbackOffer {
+        stripe_coupon_id: "coupon_winback".into(),
+        expires_at: (DateTime::from_timestamp(canceled_at.timestamp(), 0).unwrap()
+            + WINBACK_WINDOW)
+            .to_rfc3339_opts(SecondsFormat::Millis, true),
+    };
+    assert_eq!(
+        winback_offer_for_user(app, &churned_user, now)
+            .await
+            .unwrap(),
+        Some(expected_offer)
+    );
+
+    // Once they've been sent the offer, they aren't eligible for another one.
+    record_winback_offer_for_user(app, &churned_user, now)
+        .await
+        .unwrap();
+    let later = now + chrono::Duration::days(1);
+    assert_eq!(
+        winback_offer_for_user(app, &churned_user, later)
+            .await
+            .unwrap(),
+        None
+    );
+    assert!(
+        record_winback_offer_for_user(app, &churned_user, later)
+            .await
+            .is_err()
+    );
+
+    // A user who was recently offered a win-back isn't eligible, even after canceling again.
+    let (recently_offered_user, billing_customer) =
+        create_churned_user("recently-offered-user", 2, canceled_at).await;
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                winback_offered_at: ActiveValue::set(Some(
+                    (now - chrono::Duration::days(60)).naive_utc(),
+                )),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        winback_offer_for_user(app, &recently_offered_user, now)
+            .await
+            .unwrap(),
+        None
+    );
+
+    // A user who canceled too long ago isn't eligible.
+    let (long_churned_user, _) = create_churned_user(
+        "long-churned-user",
+        3,
+        now - WINBACK_WINDOW - chrono::Duration::days(1),
+    )
+    .await;
+    assert_eq!(
+        winback_offer_for_user(app, &long_churned_user, now)
+            .await
+            .unwrap(),
+        None
+    );
+
+    // A user who resubscribed to Zed Pro isn't eligible.
+    let (resubscribed_user, billing_customer) =
+        create_churned_user("resubscribed-user", 4, canceled_at).await;
+    create_subscription(
+        &billing_customer,
+        SubscriptionKind::ZedPro,
+        StripeSubscriptionStatus::Active,
+        now + chrono::Duration::days(20),
+    )
+    .await;
+    assert_eq!(
+        winback_offer_for_user(app, &resubscribed_user, now)
+            .await
+            .unwrap(),
+        None
+    );
+
+    // There are no offers when win-back offers aren't configured.
+    let billing_customer = billing_customer::Model::default();
+    let subscriptions = app
+        .db
+        .get_billing_subscriptions(churned_user.id)
+        .await
+        .unwrap();
+    assert!(winback_offer(&app.config, Some(&billing_customer), &subscriptions, now).is_some());
+    assert_eq!(
+        winback_offer(
+            &Config::test(),
+            Some(&billing_customer),
+            &subscriptions,
+            now
+        ),
+        None
+    );
+}
+
+#[gpui::test]
+async fn test_extend_trial(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Trialing,
+    );
+    let subscription = test_app.stripe_client.subscriptions.lock()[&subscription_id].clone();
+    let trial_end = subscription.current_period_end;
+    sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+
+    // Trials can only be extended by a reasonable number of days.
+    for additional_days in [0, MAX_TRIAL_EXTENSION_DAYS + 1] {
+        let error = extend_trial_for_user(app, &stripe_client, &user, additional_days)
+            .await
+            .unwrap_err();
+        assert!(matches!(error, Error::Http(StatusCode::BAD_REQUEST, _, _)));
+    }
+    assert!(
+        test_app
+            .stripe_client
+            .update_subscription_calls
+            .lock()
+            .is_empty()
+    );
+
+    // The trial is pushed back in Stripe, and the new end is synced to our record.
+    let billing_subscription = extend_trial_for_user(app, &stripe_client, &user, 7)
+        .await
+        .unwrap();
+    let extended_trial_end = trial_end + 7 * 24 * 60 * 60;
+    assert_eq!(
+        test_app.stripe_client.update_subscription_calls.lock()[0]
+            .1
+            .trial_end,
+        Some(extended_trial_end)
+    );
+    assert_eq!(
+        billing_subscription.stripe_current_period_end,
+        Some(extended_trial_end)
+    );
+    assert_eq!(
+        BillingSubscriptionJson::new(billing_subscription, Locale::English, Duration::ZERO)
+            .trial_end_at,
+        Some(format_timestamp(extended_trial_end))
+    );
+
+    // Subscriptions that aren't trialing can't be extended.
+    test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Active);
+    test_app.sync_stripe_subscription(&subscription_id).await;
+    let error = extend_trial_for_user(app, &stripe_client, &user, 7)
+        .await
+        .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::CONFLICT, _, _)));
+    assert_eq!(
+        test_app
+            .stripe_client
+            .update_subscription_calls
+            .lock()
+            .len(),
+        1
+    );
+}
+
+#[gpui::test]
+async fn test_reactivate_billing_subscription(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap();
+
+    // Once Zed Pro is canceled, the user is moved to Zed Free.
+    stripe_client
+        .cancel_subscription(&subscription_id, None)
+        .await
+        .unwrap();
+    test_app.sync_stripe_subscription(&subscription_id).await;
+    let free_subscription = test_app
+        .stripe_subscriptions_for_customer(&customer_id)
+        .into_iter()
+        .find(|subscription| subscription.status == SubscriptionStatus::Active)
+        .unwrap();
+    sync_subscription(app, &stripe_client, free_subscription)
+        .await
+        .unwrap();
+    let canceled_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_1")
+        .await
+        .unwrap()
+        .unwrap();
+    let free_subscription = app
+        .db
+        .get_active_billing_subscription(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(free_subscription.kind, Some(SubscriptionKind::ZedFree));
+
+    let reactivate = async |subscription_id, now| {
+        reactivate_billing_subscription_for_user(app, &stripe_client, &user, subscription_id, now)
+            .await
+    };
+    let is_conflict =
+        |result: Result<_>| matches!(result, Err(Error::Http(StatusCode::CONFLICT, _, _)));
+
+    // Subscriptions that weren't canceled can't be reactivated.
+    assert!(is_conflict(
+        reactivate(free_subscription.id, Utc::now()).await
+    ));
+
+    // Reactivating charges the payment method on file, so the user needs to go through checkout without one.
+    assert!(is_conflict(
+        reactivate(canceled_subscription.id, Utc::now()).await
+    ));
+    test_app
+        .stripe_client
+        .default_payment_methods
+        .lock()
+        .insert(
+            customer_id.clone(),
+            StripePaymentMethod {
+                id: StripePaymentMethodId("pm_1".into()),
+                card: None,
+            },
+        );
+
+    // Subscriptions that were canceled too long ago can't be reactivated.
+    assert!(is_conflict(
+        reactivate(
+            canceled_subscription.id,
+            Utc::now() + chrono::Duration::days(31) + REACTIVATION_WINDOW
+        )
+        .await
+    ));
+    assert_eq!(
+        test_app
+            .stripe_subscriptions_for_customer(&customer_id)
+            .len(),
+        2
+    );
+
+    // The reactivated subscription replaces Zed Free.
+    let reactivated_subscription = reactivate(canceled_subscription.id, Utc::now())
+        .await
+        .unwrap();
+    assert_eq!(
+        reactivated_subscription.kind,
+        Some(SubscriptionKind::ZedPro)
+    );
+    assert_eq!(
+        reactivated_subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::Active
+    );
+    assert_ne!(
+        reactivated_subscription.stripe_subscription_id,
+        canceled_subscription.stripe_subscription_id
+    );
+    assert_eq!(
+        app.db
+            .get_billing_subscription_by_id(free_subscription.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .stripe_subscription_status,
+        StripeSubscriptionStatus::Canceled
+    );
+    assert_eq!(
+        app.db
+            .count_active_billing_subscriptions(user.id)
+            .await
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        test_app
+            .stripe_subscriptions_for_customer(&customer_id)
+            .len(),
+        3
+    );
+
+    // Reactivating again doesn't give the user a second Zed Pro subscription.
+    assert!(is_conflict(
+        reactivate(canceled_subscription.id, Utc::now()).await
+    ));
+}
+
+#[gpui::test]
+async fn test_resume_billing_subscription(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let billing_subscription = test_app.sync_stripe_subscription(&subscription_id).await;
+
+    // Subscriptions that aren't paused can't be resumed.
+    let error = resume_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::CONFLICT, _, _)));
+
+    let subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        subscription.pause_collection = Some(StripePauseCollection { resumes_at: None });
+        subscription.clone()
+    };
+    sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+
+    // Subscriptions belonging to other users can't be resumed.
+    let other_user = test_app.create_user("user2", 2).await;
+    let error = resume_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &other_user,
+        billing_subscription.id,
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::NOT_FOUND, _, _)));
+
+    // Resuming the subscription syncs its new status.
+    let billing_subscription = resume_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        billing_subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::Active
+    );
+    assert_eq!(
+        test_app.stripe_client.subscriptions.lock()[&subscription_id].pause_collection,
+        None
+    );
+}
+
+#[gpui::test]
+async fn test_pause_billing_subscription_limit(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            max_subscription_pauses_per_year: Some(2),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let subscription = test_app.stripe_client.subscriptions.lock()[&subscription_id].clone();
+    sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+    let billing_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_1")
+        .await
+        .unwrap()
+        .unwrap();
+    // Stripe clears the pause once the subscription resumes on its own.
+    let resume = || {
+        test_app
+            .stripe_client
+            .subscriptions
+            .lock()
+            .get_mut(&subscription_id)
+            .unwrap()
+            .pause_collection = None;
+    };
+    let pauses_remaining = |now| async move {
+        subscription_pauses_remaining(app, &[billing_subscription.id], now)
+            .await
+            .unwrap()[&billing_subscription.id]
+    };
+
+    let now = Utc::now();
+
+    // Pauses from more than a year ago don't count towards the limit.
+    app.db
+        .create_billing_subscription_pause(&CreateBillingSubscriptionPauseParams {
+            billing_subscription_id: billing_subscription.id,
+            paused_at: (now - chrono::Duration::days(400)).naive_utc(),
+            resumes_at: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(pauses_remaining(now).await, 2);
+
+    // Subscriptions belonging to other users can't be paused.
+    let other_user = test_app.create_user("user2", 2).await;
+    let error = pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &other_user,
+        billing_subscription.id,
+        None,
+        None,
+        now,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::NOT_FOUND, _, _)));
+
+    // Subscriptions can't be paused until a time that has already passed.
+    let error = pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        Some(now - chrono::Duration::days(1)),
+        None,
+        now,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::BAD_REQUEST, _, _)));
+    assert!(
+        test_app
+            .stripe_client
+            .pause_subscription_calls
+            .lock()
+            .is_empty()
+    );
+
+    let resumes_at = now + chrono::Duration::days(30);
+    pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        Some(resumes_at),
+        None,
+        now - chrono::Duration::days(200),
+    )
+    .await
+    .unwrap();
+    assert_eq!(pauses_remaining(now).await, 1);
+    resume();
+    pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        None,
+        None,
+        now - chrono::Duration::days(100),
+    )
+    .await
+    .unwrap();
+    assert_eq!(pauses_remaining(now).await, 0);
+    resume();
+
+    // The third pause within a year is rejected, without pausing the subscription in Stripe.
+    let error = pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        None,
+        None,
+        now,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        error,
+        Error::Http(StatusCode::FORBIDDEN, _, ref headers)
+            if headers.get(ERROR_CODE_HEADER).unwrap() == "PauseLimitReached"
+    ));
+    assert_eq!(
+        *test_app.stripe_client.pause_subscription_calls.lock(),
+        vec![
+            (subscription_id.clone(), Some(resumes_at.timestamp())),
+            (subscription_id.clone(), None),
+        ]
+    );
+
+    // Once the earliest of those pauses is more than a year old, the subscription can be paused again.
+    let later = now + chrono::Duration::days(170);
+    assert_eq!(pauses_remaining(later).await, 1);
+    pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        None,
+        None,
+        later,
+    )
+    .await
+    .unwrap();
+    assert_eq!(pauses_remaining(later).await, 0);
+}
+
+#[gpui::test]
+async fn test_pause_and_resume_billing_subscription(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let subscription = test_app.stripe_client.subscriptions.lock()[&subscription_id].clone();
+    sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+    let billing_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_1")
+        .await
+        .unwrap()
+        .unwrap();
+    let pauses_remaining = |now| async move {
+        subscription_pauses_remaining(app, &[billing_subscription.id], now)
+            .await
+            .unwrap()[&billing_subscription.id]
+    };
+
+    let now = Utc::now();
+    let max_pauses = app.config.max_subscription_pauses_per_year();
+
+    // Pausing the subscription pauses collecting payment for it, and the user loses Zed Pro until it resumes.
+    let resumes_at = now + chrono::Duration::days(30);
+    pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        Some(resumes_at),
+        None,
+        now,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        test_app.stripe_client.subscriptions.lock()[&subscription_id].pause_collection,
+        Some(StripePauseCollection {
+            resumes_at: Some(resumes_at.timestamp())
+        })
+    );
+    assert_eq!(
+        app.db
+            .get_billing_subscription_by_id(billing_subscription.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .stripe_subscription_status,
+        StripeSubscriptionStatus::Paused
+    );
+    assert_eq!(pauses_remaining(now).await, max_pauses - 1);
+
+    // The user is moved to Zed Free in the meantime.
+    let free_subscription = test_app
+        .stripe_subscriptions_for_customer(&customer_id)
+        .into_iter()
+        .find(|subscription| subscription.id != subscription_id)
+        .unwrap();
+    let free_subscription_id = free_subscription.id.clone();
+    sync_subscription(app, &stripe_client, free_subscription)
+        .await
+        .unwrap();
+    let free_billing_subscription = app
+        .db
+        .get_active_billing_subscription(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        free_billing_subscription.kind,
+        Some(SubscriptionKind::ZedFree)
+    );
+
+    // Only Zed Pro subscriptions can be paused.
+    let error = pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        free_billing_subscription.id,
+        None,
+        None,
+        now,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(
+        error,
+        Error::Http(StatusCode::BAD_REQUEST, _, ref headers)
+            if headers.get(ERROR_CODE_HEADER).unwrap() == "NotPausable"
+    ));
+
+    // Pausing the subscription again while it's paused only changes when it resumes.
+    pause_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        None,
+        None,
+        now,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        test_app.stripe_client.subscriptions.lock()[&subscription_id].pause_collection,
+        Some(StripePauseCollection { resumes_at: None })
+    );
+    assert_eq!(
+        app.db
+            .get_latest_billing_subscription_pause(billing_subscription.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .resumes_at,
+        None
+    );
+    assert_eq!(pauses_remaining(now).await, max_pauses - 1);
+
+    // Resuming the subscription gives the user Zed Pro again, in place of Zed Free.
+    let billing_subscription = resume_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        None,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        billing_subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::Active
+    );
+    assert_eq!(
+        test_app.stripe_client.subscriptions.lock()[&subscription_id].pause_collection,
+        None
+    );
+    assert_eq!(
+        test_app.stripe_client.subscriptions.lock()[&free_subscription_id].status,
+        SubscriptionStatus::Canceled
+    );
+
+    // Once it has resumed, it can't be resumed again.
+    let error = resume_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &user,
+        billing_subscription.id,
+        None,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::CONFLICT, _, _)));
+}
+
+#[gpui::test]
+async fn test_refund_billing_subscription(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        let now = Utc::now();
+        subscription.current_period_start = (now - chrono::Duration::days(10)).timestamp();
+        subscription.current_period_end = (now + chrono::Duration::days(20)).timestamp();
+        subscription.clone()
+    };
+    sync_subscription(app, &stripe_client, subscription.clone())
+        .await
+        .unwrap();
+    test_app
+        .stripe_client
+        .latest_invoice_payments
+        .lock()
+        .insert(
+            subscription_id.clone(),
+            StripeInvoicePayment {
+                payment_intent_id: StripePaymentIntentId("pi_1".into()),
+                amount_paid: 3_000,
+                amount_refunded: 0,
+                lines: vec![StripeInvoiceLine {
+                    price_id: Some(StripePriceId("price_zed_pro".into())),
+                    amount: 3_000,
+                    period_start: subscription.current_period_start,
+                    period_end: subscription.current_period_end,
+                }],
+            },
+        );
+    let billing_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_1")
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Subscriptions belonging to other users can't be refunded.
+    let other_user = test_app.create_user("user2", 2).await;
+    let error = refund_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &stripe_billing,
+        &other_user,
+        billing_subscription.id,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::NOT_FOUND, _, _)));
+
+    // The unused two thirds of the period are refunded, and the subscription is canceled right away.
+    let refunded_amount = refund_billing_subscription_for_user(
+        app,
+        &stripe_client,
+        &stripe_billing,
+        &user,
+        billing_subscription.id,
+    )
+    .await
+    .unwrap();
+    assert!((1_999..=2_000).contains(&refunded_amount));
+    assert_eq!(
+        test_app.stripe_client.create_refund_calls.lock()[0].amount,
+        Some(refunded_amount)
+    );
+    let billing_subscription = app
+        .db
+        .get_billing_subscription_by_id(billing_subscription.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        billing_subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::Canceled
+    );
+
+    // Refunding the subscription again returns the earlier refund instead of refunding twice.
+    assert_eq!(
+        refund_billing_subscription_for_user(
+            app,
+            &stripe_client,
+            &stripe_billing,
+            &user,
+            billing_subscription.id,
+        )
+        .await
+        .unwrap(),
+        refunded_amount
+    );
+    assert_eq!(test_app.stripe_client.create_refund_calls.lock().len(), 1);
+}
+
+#[gpui::test]
+async fn test_preview_billing_subscription_change(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let annual_price = StripePrice {
+        id: StripePriceId("price_zed_pro_annual".into()),
+        unit_amount: Some(19_200),
+        lookup_key: Some("zed-pro-annual".to_string()),
+        recurring: None,
+    };
+    test_app
+        .stripe_client
+        .prices
+        .lock()
+        .insert(annual_price.id.clone(), annual_price);
+    stripe_billing.initialize().await.unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        let now = Utc::now();
+        subscription.current_period_start = (now - chrono::Duration::days(10)).timestamp();
+        subscription.current_period_end = (now + chrono::Duration::days(20)).timestamp();
+        subscription.clone()
+    };
+    sync_subscription(app, &stripe_client, subscription.clone())
+        .await
+        .unwrap();
+    let billing_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_1")
+        .await
+        .unwrap()
+        .unwrap();
+
+    // Subscriptions belonging to other users can't be previewed.
+    let other_user = test_app.create_user("user2", 2).await;
+    let error = preview_billing_subscription_change_for_user(
+        app,
+        &stripe_client,
+        &stripe_billing,
+        &other_user,
+        billing_subscription.id,
+        ProductCode::ZedProAnnual,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::NOT_FOUND, _, _)));
+
+    // Switching to the plan the subscription is already on isn't a change.
+    let error = preview_billing_subscription_change_for_user(
+        app,
+        &stripe_client,
+        &stripe_billing,
+        &user,
+        billing_subscription.id,
+        ProductCode::ZedPro,
+    )
+    .await
+    .unwrap_err();
+    assert!(matches!(error, Error::Http(StatusCode::CONFLICT, _, _)));
+
+    // The difference is prorated over the remaining two thirds of the period, separately from the new recurring
+    // amount.
+    let preview = preview_billing_subscription_change_for_user(
+        app,
+        &stripe_client,
+        &stripe_billing,
+        &user,
+        billing_subscription.id,
+        ProductCode::ZedProAnnual,
+    )
+    .await
+    .unwrap();
+    assert!((11_466..=11_467).contains(&preview.proration_amount));
+    assert_eq!(preview.recurring_amount, 19_200);
+    assert_eq!(preview.next_charge_at, subscription.current_period_end);
+    assert_eq!(
+        test_app
+            .stripe_client
+            .preview_subscription_update_calls
+            .lock()[0]
+            .1,
+        StripePreviewSubscriptionUpdateParams {
+            items: vec![UpdateSubscriptionItems {
+                id: Some(StripeSubscriptionItemId("sub_1_item".into())),
+                price: Some(StripePriceId("price_zed_pro_annual".into())),
+            }],
+            proration_behavior: StripeProrationBehavior::CreateProrations,
+            proration_date: None,
+        }
+    );
+
+    // Previewing the change doesn't make it.
+    assert!(
+        test_app
+            .stripe_client
+            .update_subscription_calls
+            .lock()
+            .is_empty()
+    );
+}
@@ -0,0 +1,1514 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use collections::HashMap;
+use gpui::TestAppContext;
+use pretty_assertions::assert_eq;
+use sea_orm::ActiveValue;
+use serde_json::json;
+use stripe::SubscriptionStatus;
+
+use crate::api::billing::localization::Locale;
+use crate::api::billing::{
+    BillingSubscriptionJson, BillingTasks, OverlappingSubscriptionsJson,
+    UnclassifiedSubscriptionJson, apply_free_downgrades, detect_status_drift,
+    effective_monthly_cost_in_cents, find_overlapping_subscriptions,
+    find_unclassified_subscriptions, get_entitled_billing_subscription, is_blocked_after_trial,
+    reconcile_overlapping_subscriptions, reconcile_stripe_customer, subscription_to_keep,
+    sync_one_billing_subscription_for_id, sync_subscription, sync_subscriptions_for_checkout,
+    unclassified_subscription_row,
+};
+use crate::db::billing_subscription::{
+    self, StripeCancellationReason, StripeSubscriptionStatus, SubscriptionKind,
+};
+use crate::db::{
+    BillingSubscriptionId, CreateBillingCustomerParams, CreateBillingSubscriptionParams,
+    UpdateBillingCustomerParams, UpdateBillingSubscriptionParams, UserId,
+};
+use crate::stripe_client::{
+    StripeClient, StripeCustomerId, StripeInvoicePayment, StripePaymentIntentId, StripePrice,
+    StripePriceId, StripeSubscriptionId,
+};
+use crate::tests::billing_test_app::{make_test_app, make_test_app_with_config};
+use crate::{
+    Config, DuplicateCheckoutBehavior, OverlappingSubscriptionResolution, TrialEndBehavior,
+    UnclassifiedSubscriptionPlan,
+};
+
+#[gpui::test]
+async fn test_sync_subscriptions_for_checkout(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+
+    // A user that has never been to Checkout has nothing to sync.
+    let subscription = sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap();
+    assert_eq!(subscription, None);
+
+    // The subscription created by Checkout is picked up before we've seen any
+    // Stripe events for it.
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+
+    let subscription = sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(subscription.kind, Some(SubscriptionKind::ZedPro));
+    assert_eq!(
+        subscription.stripe_subscription_id,
+        subscription_id.0.as_ref()
+    );
+    assert_eq!(
+        subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::Active
+    );
+
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(billing_customer.stripe_customer_id, customer_id.0.as_ref());
+}
+
+#[gpui::test]
+async fn test_sync_one_billing_subscription(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let other_subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_free",
+        SubscriptionStatus::Active,
+    );
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_2",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+
+    // Only the given subscription is synced.
+    let (billing_customer, subscription) =
+        sync_one_billing_subscription_for_id(app, &stripe_client, &subscription_id)
+            .await
+            .unwrap();
+    assert_eq!(billing_customer.user_id, user.id);
+    assert_eq!(subscription.stripe_subscription_id, "sub_2");
+    assert_eq!(subscription.kind, Some(SubscriptionKind::ZedPro));
+    assert_eq!(
+        subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::Active
+    );
+    assert_eq!(
+        app.db
+            .get_billing_subscription_by_stripe_subscription_id(&other_subscription_id.0)
+            .await
+            .unwrap(),
+        None
+    );
+
+    // Syncing it again picks up changes made in Stripe.
+    test_app
+        .stripe_client
+        .subscriptions
+        .lock()
+        .get_mut(&subscription_id)
+        .unwrap()
+        .status = SubscriptionStatus::PastDue;
+    let (_, subscription) =
+        sync_one_billing_subscription_for_id(app, &stripe_client, &subscription_id)
+            .await
+            .unwrap();
+    assert_eq!(
+        subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::PastDue
+    );
+
+    // Subscriptions that don't exist in Stripe can't be synced.
+    assert!(
+        sync_one_billing_subscription_for_id(
+            app,
+            &stripe_client,
+            &StripeSubscriptionId("sub_missing".into())
+        )
+        .await
+        .is_err()
+    );
+}
+
+#[gpui::test]
+async fn test_trial_end_behavior(cx: &mut TestAppContext) {
+    for trial_end_behavior in [
+        TrialEndBehavior::DowngradeToFree,
+        TrialEndBehavior::BlockAccess,
+    ] {
+        let test_app = make_test_app_with_config(
+            cx,
+            Config {
+                trial_end_behavior: Some(trial_end_behavior),
+                ..Config::test()
+            },
+        )
+        .await;
+        let app = &test_app.app;
+        let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+        let user = test_app.create_user("user1", 1).await;
+        let customer_id = test_app.create_stripe_customer("cus_1", &user);
+        let subscription_id = test_app.create_stripe_subscription(
+            "sub_1",
+            &customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Trialing,
+        );
+
+        let subscription = sync_subscriptions_for_checkout(app, &stripe_client, &user)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(subscription.kind, Some(SubscriptionKind::ZedProTrial));
+
+        let is_blocked = async || {
+            is_blocked_after_trial(
+                &app.config,
+                app.db
+                    .get_billing_subscriptions(user.id)
+                    .await
+                    .unwrap()
+                    .last(),
+            )
+        };
+        assert!(!is_blocked().await);
+
+        // The trial ends without the user adding a payment method.
+        test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Canceled);
+        let subscription = sync_subscriptions_for_checkout(app, &stripe_client, &user)
+            .await
+            .unwrap();
+        assert_eq!(subscription, None);
+
+        let trial = app
+            .db
+            .get_billing_subscription_by_stripe_subscription_id(&subscription_id.0)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(trial.kind, Some(SubscriptionKind::ZedProTrial));
+        assert_eq!(
+            trial.stripe_subscription_status,
+            StripeSubscriptionStatus::Canceled
+        );
+
+        let zed_free_subscriptions = test_app
+            .stripe_subscriptions_for_customer(&customer_id)
+            .into_iter()
+            .filter(|subscription| subscription.id != subscription_id)
+            .collect::<Vec<_>>();
+
+        match trial_end_behavior {
+            TrialEndBehavior::DowngradeToFree => {
+                assert_eq!(zed_free_subscriptions.len(), 1);
+                assert_eq!(
+                    zed_free_subscriptions[0].items[0]
+                        .price
+                        .as_ref()
+                        .map(|price| price.id.clone()),
+                    Some(StripePriceId("price_zed_free".into()))
+                );
+                assert!(!is_blocked().await);
+            }
+            TrialEndBehavior::BlockAccess => {
+                assert_eq!(zed_free_subscriptions.len(), 0);
+                assert!(is_blocked().await);
+            }
+        }
+
+        // Processing the cancellation again doesn't change the outcome.
+        sync_subscriptions_for_checkout(app, &stripe_client, &user)
+            .await
+            .unwrap();
+        let stripe_subscription_count = test_app
+            .stripe_subscriptions_for_customer(&customer_id)
+            .len();
+        match trial_end_behavior {
+            TrialEndBehavior::DowngradeToFree => assert_eq!(stripe_subscription_count, 2),
+            TrialEndBehavior::BlockAccess => assert_eq!(stripe_subscription_count, 1),
+        }
+    }
+}
+
+#[gpui::test]
+async fn test_not_blocked_after_paying_following_trial(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            trial_end_behavior: Some(TrialEndBehavior::BlockAccess),
+            free_downgrade_delay_seconds: Some(60 * 60),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let sync = async |subscription_id: &StripeSubscriptionId, status| {
+        test_app.set_stripe_subscription_status(subscription_id, status);
+        test_app.sync_stripe_subscription(subscription_id).await;
+    };
+    let is_blocked = async || {
+        is_blocked_after_trial(
+            &app.config,
+            app.db
+                .get_billing_subscriptions(user.id)
+                .await
+                .unwrap()
+                .last(),
+        )
+    };
+
+    // The trial ends without the user adding a payment method, so they're blocked.
+    let trial_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Trialing,
+    );
+    sync(&trial_id, SubscriptionStatus::Trialing).await;
+    sync(&trial_id, SubscriptionStatus::Canceled).await;
+    assert!(is_blocked().await);
+
+    // The user goes on to pay for Zed Pro.
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_2",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    sync(&subscription_id, SubscriptionStatus::Active).await;
+    assert!(!is_blocked().await);
+
+    // Once they cancel Zed Pro, they're waiting to be moved to Zed Free rather than blocked for their old trial.
+    sync(&subscription_id, SubscriptionStatus::Canceled).await;
+    assert_eq!(
+        get_entitled_billing_subscription(&app.db, &app.config, user.id)
+            .await
+            .unwrap(),
+        None
+    );
+    assert!(
+        app.db
+            .get_billing_customer_by_user_id(user.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .free_downgrade_at
+            .is_some()
+    );
+    assert!(!is_blocked().await);
+}
+
+#[gpui::test]
+async fn test_past_due_grace_period(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            past_due_grace_period_seconds: Some(3 * 24 * 60 * 60),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let sync = async |status| {
+        test_app.set_stripe_subscription_status(&subscription_id, status);
+        test_app.sync_stripe_subscription(&subscription_id).await
+    };
+    let entitled_kind = async || {
+        get_entitled_billing_subscription(&app.db, &app.config, user.id)
+            .await
+            .unwrap()
+            .and_then(|subscription| subscription.kind)
+    };
+
+    let subscription = sync(SubscriptionStatus::Active).await;
+    assert_eq!(subscription.past_due_since, None);
+
+    // The user keeps Zed Pro while Stripe retries the payment, and syncing again doesn't extend the grace period.
+    let subscription = sync(SubscriptionStatus::PastDue).await;
+    let past_due_since = subscription.past_due_since.unwrap();
+    assert_eq!(entitled_kind().await, Some(SubscriptionKind::ZedPro));
+    let subscription = sync(SubscriptionStatus::PastDue).await;
+    assert_eq!(subscription.past_due_since, Some(past_due_since));
+
+    // Once the grace period is over, the user loses Zed Pro.
+    app.db
+        .update_billing_subscription(
+            subscription.id,
+            &UpdateBillingSubscriptionParams {
+                past_due_since: ActiveValue::set(Some(past_due_since - chrono::Duration::days(4))),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(entitled_kind().await, None);
+
+    // Recovering from being past due clears it.
+    let subscription = sync(SubscriptionStatus::Active).await;
+    assert_eq!(subscription.past_due_since, None);
+    assert_eq!(entitled_kind().await, Some(SubscriptionKind::ZedPro));
+}
+
+#[gpui::test]
+async fn test_free_fallback_for_ended_subscriptions(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+
+    for (ix, status, falls_back_to_free) in [
+        (1, SubscriptionStatus::Canceled, true),
+        (2, SubscriptionStatus::Paused, true),
+        (3, SubscriptionStatus::Unpaid, true),
+        (4, SubscriptionStatus::IncompleteExpired, true),
+        // Stripe is still retrying the payment, so the subscription can recover.
+        (5, SubscriptionStatus::PastDue, false),
+    ] {
+        let user = test_app.create_user(&format!("user{ix}"), ix).await;
+        let customer_id = test_app.create_stripe_customer(&format!("cus_{ix}"), &user);
+        let subscription_id = test_app.create_stripe_subscription(
+            &format!("sub_{ix}"),
+            &customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Active,
+        );
+        test_app.sync_stripe_subscription(&subscription_id).await;
+
+        test_app.set_stripe_subscription_status(&subscription_id, status);
+        test_app.sync_stripe_subscription(&subscription_id).await;
+        cx.executor().run_until_parked();
+
+        let has_zed_free_subscription = test_app
+            .stripe_subscriptions_for_customer(&customer_id)
+            .iter()
+            .any(|subscription| {
+                subscription.items.iter().any(|item| {
+                    item.price
+                        .as_ref()
+                        .is_some_and(|price| price.id.as_ref() == "price_zed_free")
+                })
+            });
+        assert_eq!(has_zed_free_subscription, falls_back_to_free, "{status:?}");
+    }
+}
+
+#[gpui::test]
+async fn test_free_downgrade_delay(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            free_downgrade_delay_seconds: Some(60),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+
+    let zed_free_subscriptions = |customer_id: &StripeCustomerId| {
+        test_app
+            .stripe_subscriptions_for_customer(customer_id)
+            .into_iter()
+            .filter(|subscription| {
+                subscription.items.iter().any(|item| {
+                    item.price
+                        .as_ref()
+                        .is_some_and(|price| price.id.as_ref() == "price_zed_free")
+                })
+            })
+            .count()
+    };
+
+    let free_downgrade_at = async |user_id: UserId| {
+        app.db
+            .get_billing_customer_by_user_id(user_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .free_downgrade_at
+    };
+    let shutdown = BillingTasks::new().shutdown_signal();
+
+    // The user reactivates within the window, so they're never placed on Zed Free.
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    test_app.sync_stripe_subscription(&subscription_id).await;
+
+    test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Paused);
+    test_app.sync_stripe_subscription(&subscription_id).await;
+    let now = Utc::now();
+    assert!(free_downgrade_at(user.id).await.is_some());
+    assert_eq!(
+        apply_free_downgrades(app, now, &shutdown).await.unwrap(),
+        Vec::<UserId>::new()
+    );
+    assert_eq!(zed_free_subscriptions(&customer_id), 0);
+
+    test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Active);
+    test_app.sync_stripe_subscription(&subscription_id).await;
+
+    assert_eq!(
+        apply_free_downgrades(app, now + chrono::Duration::seconds(90), &shutdown)
+            .await
+            .unwrap(),
+        Vec::<UserId>::new()
+    );
+    assert_eq!(zed_free_subscriptions(&customer_id), 0);
+    assert_eq!(free_downgrade_at(user.id).await, None);
+    assert_eq!(
+        app.db
+            .get_active_billing_subscription(user.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .kind,
+        Some(SubscriptionKind::ZedPro)
+    );
+
+    // The user doesn't reactivate, so they're placed on Zed Free once the window has passed.
+    let user = test_app.create_user("user2", 2).await;
+    let customer_id = test_app.create_stripe_customer("cus_2", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_2",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    test_app.sync_stripe_subscription(&subscription_id).await;
+
+    test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Canceled);
+    test_app.sync_stripe_subscription(&subscription_id).await;
+    let now = Utc::now();
+
+    // Syncing the canceled subscription again doesn't postpone the downgrade.
+    let scheduled_free_downgrade_at = free_downgrade_at(user.id).await;
+    assert!(scheduled_free_downgrade_at.is_some());
+    test_app.sync_stripe_subscription(&subscription_id).await;
+    test_app.sync_stripe_subscription(&subscription_id).await;
+    assert_eq!(
+        free_downgrade_at(user.id).await,
+        scheduled_free_downgrade_at
+    );
+
+    assert_eq!(
+        apply_free_downgrades(app, now + chrono::Duration::seconds(30), &shutdown)
+            .await
+            .unwrap(),
+        Vec::<UserId>::new()
+    );
+    assert_eq!(zed_free_subscriptions(&customer_id), 0);
+
+    // Nothing waits on the downgrade in memory, so it's only applied by the periodic task.
+    cx.executor().advance_clock(Duration::from_secs(120));
+    cx.executor().run_until_parked();
+    assert_eq!(zed_free_subscriptions(&customer_id), 0);
+
+    assert_eq!(
+        apply_free_downgrades(app, now + chrono::Duration::seconds(61), &shutdown)
+            .await
+            .unwrap(),
+        vec![user.id]
+    );
+    assert_eq!(zed_free_subscriptions(&customer_id), 1);
+    assert_eq!(free_downgrade_at(user.id).await, None);
+
+    // Once applied, the downgrade isn't applied again.
+    assert_eq!(
+        apply_free_downgrades(app, now + chrono::Duration::seconds(120), &shutdown)
+            .await
+            .unwrap(),
+        Vec::<UserId>::new()
+    );
+    assert_eq!(zed_free_subscriptions(&customer_id), 1);
+}
+
+#[test]
+fn test_subscription_to_keep() {
+    let now = Utc::now().naive_utc();
+    let subscriptions = [
+        (SubscriptionKind::ZedPro, now - chrono::Duration::days(2)),
+        (SubscriptionKind::ZedFree, now - chrono::Duration::days(1)),
+        (SubscriptionKind::ZedProTrial, now),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(ix, (kind, created_at))| billing_subscription::Model {
+        id: BillingSubscriptionId(ix as i32 + 1),
+        kind: Some(kind),
+        stripe_subscription_id: format!("sub_{ix}"),
+        created_at,
+        ..Default::default()
+    })
+    .collect::<Vec<_>>();
+
+    let kept = |resolution| {
+        subscription_to_keep(&subscriptions, resolution)
+            .map(|subscription| subscription.stripe_subscription_id.as_str())
+    };
+    assert_eq!(
+        kept(OverlappingSubscriptionResolution::KeepMostRecent),
+        Some("sub_2")
+    );
+    assert_eq!(
+        kept(OverlappingSubscriptionResolution::KeepHighestTier),
+        Some("sub_0")
+    );
+    assert_eq!(
+        subscription_to_keep(&[], OverlappingSubscriptionResolution::KeepMostRecent),
+        None
+    );
+}
+
+#[gpui::test]
+async fn test_reconcile_overlapping_subscriptions(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    // The user somehow ended up with two active Zed Pro subscriptions.
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+    for subscription_id in ["sub_1", "sub_2"] {
+        test_app.create_stripe_subscription(
+            subscription_id,
+            &customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Active,
+        );
+        app.db
+            .create_billing_subscription(&CreateBillingSubscriptionParams {
+                billing_customer_id: billing_customer.id,
+                kind: Some(SubscriptionKind::ZedPro),
+                unclassified: false,
+                stripe_subscription_id: subscription_id.to_string(),
+                stripe_subscription_status: StripeSubscriptionStatus::Active,
+                stripe_cancellation_reason: None,
+                stripe_current_period_start: None,
+                stripe_current_period_end: None,
+                seats: 1,
+                monthly_recurring_revenue_in_cents: None,
+                ended_at: None,
+            })
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        find_overlapping_subscriptions(app).await.unwrap(),
+        vec![OverlappingSubscriptionsJson {
+            user_id: user.id,
+            kept: "sub_2".into(),
+            canceled: vec!["sub_1".into()],
+        }]
+    );
+
+    let user_ids = reconcile_overlapping_subscriptions(app, &stripe_client)
+        .await
+        .unwrap();
+    assert_eq!(user_ids, vec![user.id]);
+
+    // The older subscription is canceled in both Stripe and our database.
+    let stripe_statuses = test_app
+        .stripe_subscriptions_for_customer(&customer_id)
+        .into_iter()
+        .map(|subscription| (subscription.id.to_string(), subscription.status))
+        .collect::<HashMap<_, _>>();
+    assert_eq!(stripe_statuses["sub_1"], SubscriptionStatus::Canceled);
+    assert_eq!(stripe_statuses["sub_2"], SubscriptionStatus::Active);
+
+    let subscription = app
+        .db
+        .get_active_billing_subscription(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(subscription.stripe_subscription_id, "sub_2");
+
+    // Once reconciled, there is nothing left to do.
+    assert_eq!(find_overlapping_subscriptions(app).await.unwrap(), vec![]);
+    assert_eq!(
+        reconcile_overlapping_subscriptions(app, &stripe_client)
+            .await
+            .unwrap(),
+        vec![]
+    );
+}
+
+#[gpui::test]
+async fn test_subscription_canceled_for_payment_failure(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let has_overdue_invoices = |user_id| async move {
+        app.db
+            .get_billing_customer_by_user_id(user_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .has_overdue_invoices
+    };
+
+    // Subscriptions that the user canceled don't leave them with overdue invoices.
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap();
+    test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Canceled);
+    test_app.sync_stripe_subscription(&subscription_id).await;
+    assert!(!has_overdue_invoices(user.id).await);
+
+    // Subscriptions that Stripe canceled because the payment failed do.
+    let user = test_app.create_user("user2", 2).await;
+    let customer_id = test_app.create_stripe_customer("cus_2", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_2",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap();
+    test_app
+        .stripe_client
+        .cancel_subscription_for_payment_failure(&subscription_id);
+    test_app.sync_stripe_subscription(&subscription_id).await;
+    assert!(has_overdue_invoices(user.id).await);
+
+    let subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_2")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::Canceled
+    );
+    assert_eq!(
+        subscription.stripe_cancellation_reason,
+        Some(StripeCancellationReason::PaymentFailed)
+    );
+}
+
+#[gpui::test]
+async fn test_concurrent_syncs_create_one_billing_customer(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_ids = [
+        test_app.create_stripe_subscription(
+            "sub_1",
+            &customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Active,
+        ),
+        test_app.create_stripe_subscription(
+            "sub_2",
+            &customer_id,
+            "price_zed_free",
+            SubscriptionStatus::Active,
+        ),
+    ];
+    let [subscription_1, subscription_2] = subscription_ids.map(|subscription_id| {
+        test_app.stripe_client.subscriptions.lock()[&subscription_id].clone()
+    });
+
+    // Both syncs find that there's no billing customer yet, and race to create it.
+    let (billing_customer_1, billing_customer_2) = futures::join!(
+        sync_subscription(app, &stripe_client, subscription_1),
+        sync_subscription(app, &stripe_client, subscription_2),
+    );
+    let billing_customer_1 = billing_customer_1.unwrap();
+    let billing_customer_2 = billing_customer_2.unwrap();
+    assert_eq!(billing_customer_1.id, billing_customer_2.id);
+
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(billing_customer.id, billing_customer_1.id);
+    assert_eq!(billing_customer.stripe_customer_id, "cus_1");
+}
+
+#[gpui::test]
+async fn test_sync_subscription_records_currency(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+
+    // Customers are billed in USD until we learn otherwise.
+    let subscription = test_app.stripe_client.subscriptions.lock()[&subscription_id].clone();
+    let billing_customer = sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+    assert_eq!(billing_customer.currency, "usd");
+
+    let subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        subscription.currency = "eur".into();
+        subscription.clone()
+    };
+    let billing_customer = sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+    assert_eq!(billing_customer.currency, "eur");
+    assert_eq!(
+        app.db
+            .get_billing_customer_by_user_id(user.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .currency,
+        "eur"
+    );
+}
+
+#[gpui::test]
+async fn test_sync_subscription_records_seats(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let single_seat_subscription =
+        test_app.stripe_client.subscriptions.lock()[&subscription_id].clone();
+
+    // The team adds seats to their subscription.
+    let subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        subscription.items[0].quantity = Some(5);
+        subscription.clone()
+    };
+    sync_subscription(app, &stripe_client, single_seat_subscription.clone())
+        .await
+        .unwrap();
+    sync_subscription(app, &stripe_client, subscription.clone())
+        .await
+        .unwrap();
+
+    let billing_subscriptions = app.db.get_billing_subscriptions(user.id).await.unwrap();
+    assert_eq!(billing_subscriptions.len(), 1);
+    assert_eq!(billing_subscriptions[0].seats, 5);
+    assert_eq!(
+        BillingSubscriptionJson::new(
+            billing_subscriptions[0].clone(),
+            Locale::default(),
+            Duration::ZERO
+        )
+        .seats,
+        5
+    );
+
+    // The cost covers every seat.
+    let now = Utc::now();
+    assert_eq!(
+        effective_monthly_cost_in_cents(&subscription, now),
+        effective_monthly_cost_in_cents(&single_seat_subscription, now).map(|cost| cost * 5)
+    );
+}
+
+#[gpui::test]
+async fn test_sync_subscription_downgraded_to_free(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    sync_subscriptions_for_checkout(app, &stripe_client, &user)
+        .await
+        .unwrap();
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                has_overdue_invoices: ActiveValue::set(true),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    // The user switches their subscription to Zed Free without canceling it.
+    let free_price =
+        test_app.stripe_client.prices.lock()[&StripePriceId("price_zed_free".into())].clone();
+    let subscription = {
+        let mut subscriptions = test_app.stripe_client.subscriptions.lock();
+        let subscription = subscriptions.get_mut(&subscription_id).unwrap();
+        subscription.items[0].price = Some(free_price);
+        subscription.clone()
+    };
+    for _ in 0..2 {
+        sync_subscription(app, &stripe_client, subscription.clone())
+            .await
+            .unwrap();
+    }
+
+    let billing_subscriptions = app.db.get_billing_subscriptions(user.id).await.unwrap();
+    assert_eq!(billing_subscriptions.len(), 1);
+    assert_eq!(
+        billing_subscriptions[0].kind,
+        Some(SubscriptionKind::ZedFree)
+    );
+    assert!(
+        !app.db
+            .get_billing_customer_by_user_id(user.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .has_overdue_invoices
+    );
+
+    // The downgraded subscription is the user's Zed Free subscription, so they aren't subscribed to it again.
+    assert_eq!(
+        test_app
+            .stripe_subscriptions_for_customer(&customer_id)
+            .len(),
+        1
+    );
+}
+
+#[gpui::test]
+async fn test_reconcile_stripe_customer(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+
+    // Customers without an email address are skipped.
+    reconcile_stripe_customer(app, "cus_1", None).await.unwrap();
+    assert!(
+        app.db
+            .get_billing_customer_by_stripe_customer_id("cus_1")
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // Customers whose email address doesn't belong to any user are recorded as pending.
+    reconcile_stripe_customer(app, "cus_1", Some("user1@example.com"))
+        .await
+        .unwrap();
+    assert!(
+        app.db
+            .get_billing_customer_by_stripe_customer_id("cus_1")
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(
+        app.db
+            .get_pending_billing_customer_link("cus_1")
+            .await
+            .unwrap()
+            .unwrap()
+            .email,
+        "user1@example.com"
+    );
+
+    // Once the email address belongs to a user, the customer is linked to them.
+    let user1 = test_app.create_user("user1", 1).await;
+    reconcile_stripe_customer(app, "cus_1", Some("user1@example.com"))
+        .await
+        .unwrap();
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_stripe_customer_id("cus_1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(billing_customer.user_id, user1.id);
+    assert_eq!(
+        billing_customer.stripe_email.as_deref(),
+        Some("user1@example.com")
+    );
+    assert!(!billing_customer.stripe_email_user_mismatch);
+    assert!(
+        app.db
+            .get_pending_billing_customer_link("cus_1")
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // When the email address changes to one that belongs to another user, the customer stays with the user they're
+    // billed as, and is flagged.
+    test_app.create_user("user2", 2).await;
+    reconcile_stripe_customer(app, "cus_1", Some("user2@example.com"))
+        .await
+        .unwrap();
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_stripe_customer_id("cus_1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(billing_customer.user_id, user1.id);
+    assert_eq!(
+        billing_customer.stripe_email.as_deref(),
+        Some("user2@example.com")
+    );
+    assert!(billing_customer.stripe_email_user_mismatch);
+
+    // Changing it to an email address that doesn't belong to anyone clears the flag.
+    reconcile_stripe_customer(app, "cus_1", Some("other@example.com"))
+        .await
+        .unwrap();
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_stripe_customer_id("cus_1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        billing_customer.stripe_email.as_deref(),
+        Some("other@example.com")
+    );
+    assert!(!billing_customer.stripe_email_user_mismatch);
+}
+
+#[gpui::test]
+async fn test_sync_duplicate_subscription(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    // The user completed checkout for Zed Pro in two tabs at about the same time.
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_ids = ["sub_1", "sub_2"].map(|subscription_id| {
+        test_app.create_stripe_subscription(
+            subscription_id,
+            &customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Active,
+        )
+    });
+    let subscription_created_events = subscription_ids.each_ref().map(|subscription_id| {
+        test_app.stripe_client.subscriptions.lock()[subscription_id].clone()
+    });
+    test_app
+        .stripe_client
+        .latest_invoice_payments
+        .lock()
+        .insert(
+            subscription_ids[1].clone(),
+            StripeInvoicePayment {
+                payment_intent_id: StripePaymentIntentId("pi_2".into()),
+                amount_paid: 2_000,
+                amount_refunded: 0,
+                lines: Vec::new(),
+            },
+        );
+
+    for subscription in subscription_created_events.clone() {
+        sync_subscription(app, &stripe_client, subscription)
+            .await
+            .unwrap();
+    }
+
+    // The newer subscription is canceled and refunded, and the first one is kept.
+    let stripe_statuses = test_app
+        .stripe_subscriptions_for_customer(&customer_id)
+        .into_iter()
+        .map(|subscription| (subscription.id.to_string(), subscription.status))
+        .collect::<HashMap<_, _>>();
+    assert_eq!(stripe_statuses["sub_1"], SubscriptionStatus::Active);
+    assert_eq!(stripe_statuses["sub_2"], SubscriptionStatus::Canceled);
+    assert_eq!(
+        *test_app
+            .stripe_client
+            .refund_latest_invoice_payment_calls
+            .lock(),
+        vec![subscription_ids[1].clone()]
+    );
+
+    let subscription = app
+        .db
+        .get_active_billing_subscription(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(subscription.stripe_subscription_id, "sub_1");
+    assert_eq!(
+        app.db
+            .get_billing_subscription_by_stripe_subscription_id("sub_2")
+            .await
+            .unwrap(),
+        None
+    );
+
+    // Replaying the duplicate's event doesn't refund it again.
+    sync_subscription(app, &stripe_client, subscription_created_events[1].clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        test_app
+            .stripe_client
+            .refund_latest_invoice_payment_calls
+            .lock()
+            .len(),
+        1
+    );
+}
+
+#[gpui::test]
+async fn test_sync_duplicate_subscription_after_failed_refund(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_ids = ["sub_1", "sub_2"].map(|subscription_id| {
+        test_app.create_stripe_subscription(
+            subscription_id,
+            &customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Active,
+        )
+    });
+    let subscription_created_events = subscription_ids.each_ref().map(|subscription_id| {
+        test_app.stripe_client.subscriptions.lock()[subscription_id].clone()
+    });
+    sync_subscription(app, &stripe_client, subscription_created_events[0].clone())
+        .await
+        .unwrap();
+
+    // An earlier attempt canceled the duplicate, but failed to refund it.
+    test_app
+        .stripe_client
+        .subscriptions
+        .lock()
+        .get_mut(&subscription_ids[1])
+        .unwrap()
+        .status = SubscriptionStatus::Canceled;
+    test_app
+        .stripe_client
+        .latest_invoice_payments
+        .lock()
+        .insert(
+            subscription_ids[1].clone(),
+            StripeInvoicePayment {
+                payment_intent_id: StripePaymentIntentId("pi_2".into()),
+                amount_paid: 2_000,
+                amount_refunded: 0,
+                lines: Vec::new(),
+            },
+        );
+
+    // Replaying the duplicate's event refunds it.
+    sync_subscription(app, &stripe_client, subscription_created_events[1].clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        *test_app
+            .stripe_client
+            .refund_latest_invoice_payment_calls
+            .lock(),
+        vec![subscription_ids[1].clone()]
+    );
+    assert_eq!(
+        test_app.stripe_client.latest_invoice_payments.lock()[&subscription_ids[1]].amount_refunded,
+        2_000
+    );
+
+    // Once it's refunded, replaying the event again doesn't refund it twice.
+    sync_subscription(app, &stripe_client, subscription_created_events[1].clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        test_app
+            .stripe_client
+            .refund_latest_invoice_payment_calls
+            .lock()
+            .len(),
+        1
+    );
+}
+
+#[gpui::test]
+async fn test_sync_duplicate_subscription_when_ignored(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            duplicate_checkout_behavior: Some(DuplicateCheckoutBehavior::Ignore),
+            ..Config::test()
+        },
+    )
+    .await;
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    for subscription_id in ["sub_1", "sub_2"] {
+        let subscription_id = test_app.create_stripe_subscription(
+            subscription_id,
+            &customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Active,
+        );
+        test_app.sync_stripe_subscription(&subscription_id).await;
+    }
+
+    // Both subscriptions are left alone in Stripe.
+    assert!(
+        test_app
+            .stripe_subscriptions_for_customer(&customer_id)
+            .iter()
+            .all(|subscription| subscription.status == SubscriptionStatus::Active)
+    );
+    assert!(
+        test_app
+            .stripe_client
+            .refund_latest_invoice_payment_calls
+            .lock()
+            .is_empty()
+    );
+}
+
+#[gpui::test]
+async fn test_detect_status_drift(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+    let shutdown = BillingTasks::new().shutdown_signal();
+
+    // We missed the cancellation of the first user's subscription.
+    let mut users = Vec::new();
+    for (ix, stripe_status) in [SubscriptionStatus::Canceled, SubscriptionStatus::Active]
+        .into_iter()
+        .enumerate()
+    {
+        let ix = ix as i32 + 1;
+        let user = test_app.create_user(&format!("user{ix}"), ix).await;
+        let customer_id = test_app.create_stripe_customer(&format!("cus_{ix}"), &user);
+        let billing_customer = app
+            .db
+            .create_billing_customer(&CreateBillingCustomerParams {
+                user_id: user.id,
+                stripe_customer_id: customer_id.to_string(),
+            })
+            .await
+            .unwrap();
+        let subscription_id = test_app.create_stripe_subscription(
+            &format!("sub_{ix}"),
+            &customer_id,
+            "price_zed_pro",
+            stripe_status,
+        );
+        app.db
+            .create_billing_subscription(&CreateBillingSubscriptionParams {
+                billing_customer_id: billing_customer.id,
+                kind: Some(SubscriptionKind::ZedPro),
+                unclassified: false,
+                stripe_subscription_id: subscription_id.to_string(),
+                stripe_subscription_status: StripeSubscriptionStatus::Active,
+                stripe_cancellation_reason: None,
+                stripe_current_period_start: None,
+                stripe_current_period_end: None,
+                seats: 1,
+                monthly_recurring_revenue_in_cents: None,
+                ended_at: None,
+            })
+            .await
+            .unwrap();
+        users.push(user);
+    }
+
+    let corrected = detect_status_drift(app, &stripe_client, 10, &shutdown)
+        .await
+        .unwrap();
+    assert_eq!(corrected, vec![users[0].id]);
+
+    // The canceled subscription is corrected, and the user is subscribed to Zed Free.
+    let canceled_subscription = app
+        .db
+        .get_billing_subscription_by_stripe_subscription_id("sub_1")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        canceled_subscription.stripe_subscription_status,
+        StripeSubscriptionStatus::Canceled
+    );
+    assert!(
+        !app.db
+            .has_active_billing_subscription(users[0].id)
+            .await
+            .unwrap()
+    );
+    let zed_free_subscriptions = test_app
+        .stripe_subscriptions_for_customer(&StripeCustomerId("cus_1".into()))
+        .into_iter()
+        .filter(|subscription| {
+            subscription.status == SubscriptionStatus::Active
+                && subscription.items.iter().any(|item| {
+                    item.price
+                        .as_ref()
+                        .is_some_and(|price| price.id.0.as_ref() == "price_zed_free")
+                })
+        })
+        .count();
+    assert_eq!(zed_free_subscriptions, 1);
+
+    // The subscription that's still active in Stripe is left alone.
+    let active_subscription = app
+        .db
+        .get_active_billing_subscription(users[1].id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(active_subscription.stripe_subscription_id, "sub_2");
+
+    // Once corrected, there's no drift left to detect.
+    assert_eq!(
+        detect_status_drift(app, &stripe_client, 10, &shutdown)
+            .await
+            .unwrap(),
+        vec![]
+    );
+}
+
+#[gpui::test]
+async fn test_unclassified_subscription(cx: &mut TestAppContext) {
+    for (plan, expected_kind) in [
+        (None, None),
+        (
+            Some(UnclassifiedSubscriptionPlan::ZedFree),
+            Some(SubscriptionKind::ZedFree),
+        ),
+    ] {
+        let test_app = make_test_app_with_config(
+            cx,
+            Config {
+                unclassified_subscription_plan: plan,
+                ..Config::test()
+            },
+        )
+        .await;
+        let app = &test_app.app;
+        let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+        let unknown_price = StripePrice {
+            id: StripePriceId("price_unknown".into()),
+            unit_amount: Some(1_000),
+            lookup_key: Some("unknown".to_string()),
+            recurring: None,
+        };
+        test_app
+            .stripe_client
+            .prices
+            .lock()
+            .insert(unknown_price.id.clone(), unknown_price);
+
+        let user = test_app.create_user("user1", 1).await;
+        let customer_id = test_app.create_stripe_customer("cus_1", &user);
+        let subscription_id = test_app.create_stripe_subscription(
+            "sub_1",
+            &customer_id,
+            "price_unknown",
+            SubscriptionStatus::Active,
+        );
+        let subscription = test_app.stripe_client.subscriptions.lock()[&subscription_id].clone();
+
+        // The subscription is given the configured plan, and flagged as unclassified.
+        sync_subscription(app, &stripe_client, subscription.clone())
+            .await
+            .unwrap();
+        let billing_subscription = app
+            .db
+            .get_billing_subscription_by_stripe_subscription_id("sub_1")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(billing_subscription.kind, expected_kind);
+        assert!(billing_subscription.unclassified);
+        assert_eq!(
+            find_unclassified_subscriptions(app).await.unwrap(),
+            vec![UnclassifiedSubscriptionJson {
+                user_id: user.id,
+                stripe_subscription_id: "sub_1".into(),
+                status: StripeSubscriptionStatus::Active,
+                kind: expected_kind,
+            }]
+        );
+
+        // The alert includes the prices that we couldn't classify the subscription by.
+        let row = unclassified_subscription_row(&user, &subscription, plan.unwrap_or_default());
+        assert_eq!(row.event_type, "Unclassified Subscription Detected");
+        assert_eq!(
+            row.event_properties,
+            json!({
+                "user_id": user.id,
+                "stripe_subscription_id": "sub_1",
+                "stripe_price_ids": ["price_unknown"],
+                "plan": expected_kind,
+            })
+        );
+
+        // Subscriptions that we can classify aren't flagged.
+        let other_user = test_app.create_user("user2", 2).await;
+        let other_customer_id = test_app.create_stripe_customer("cus_2", &other_user);
+        let other_subscription_id = test_app.create_stripe_subscription(
+            "sub_2",
+            &other_customer_id,
+            "price_zed_pro",
+            SubscriptionStatus::Active,
+        );
+        let other_subscription =
+            test_app.stripe_client.subscriptions.lock()[&other_subscription_id].clone();
+        sync_subscription(app, &stripe_client, other_subscription)
+            .await
+            .unwrap();
+        assert_eq!(find_unclassified_subscriptions(app).await.unwrap().len(), 1);
+    }
+}
+
+#[gpui::test]
+async fn test_sync_subscription_clears_overdue_invoices_when_active_again(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_client: Arc<dyn StripeClient> = test_app.stripe_client.clone();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::PastDue,
+    );
+    let subscription = stripe_client
+        .get_subscription(&subscription_id)
+        .await
+        .unwrap();
+    let billing_customer = sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                has_overdue_invoices: ActiveValue::set(true),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    // Syncing the subscription while it's still past due leaves the customer overdue.
+    let subscription = stripe_client
+        .get_subscription(&subscription_id)
+        .await
+        .unwrap();
+    let billing_customer = sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+    assert!(billing_customer.has_overdue_invoices);
+
+    test_app.set_stripe_subscription_status(&subscription_id, SubscriptionStatus::Active);
+    let subscription = stripe_client
+        .get_subscription(&subscription_id)
+        .await
+        .unwrap();
+    let billing_customer = sync_subscription(app, &stripe_client, subscription)
+        .await
+        .unwrap();
+    assert!(!billing_customer.has_overdue_invoices);
+    assert!(
+        !app.db
+            .get_billing_customer_by_user_id(user.id)
+            .await
+            .unwrap()
+            .unwrap()
+            .has_overdue_invoices
+    );
+}
@@ -0,0 +1,196 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use gpui::TestAppContext;
+use stripe::SubscriptionStatus;
+
+use crate::api::billing::sync_subscription;
+use crate::db::{NewUserParams, TestDb, User, billing_subscription};
+use crate::executor::Executor;
+use crate::stripe_billing::StripeBilling;
+use crate::stripe_client::{
+    FakeStripeClient, StripeClient, StripeCustomer, StripeCustomerId, StripePrice, StripePriceId,
+    StripeSubscription, StripeSubscriptionId, StripeSubscriptionItem, StripeSubscriptionItemId,
+};
+use crate::{AppState, Config};
+
+/// The app state that the billing tests run against, backed by a test database and a fake Stripe client.
+pub struct TestApp {
+    pub app: Arc<AppState>,
+    pub stripe_client: Arc<FakeStripeClient>,
+    _test_db: TestDb,
+}
+
+pub async fn make_test_app(cx: &mut TestAppContext) -> TestApp {
+    make_test_app_with_config(cx, Config::test()).await
+}
+
+pub async fn make_test_app_with_config(cx: &mut TestAppContext, config: Config) -> TestApp {
+    let test_db = TestDb::sqlite(cx.executor());
+    let stripe_client = Arc::new(FakeStripeClient::new());
+
+    for (id, lookup_key, unit_amount) in [
+        ("price_zed_pro", "zed-pro", 2_000),
+        ("price_zed_free", "zed-free", 0),
+    ] {
+        let price = StripePrice {
+            id: StripePriceId(id.into()),
+            unit_amount: Some(unit_amount),
+            lookup_key: Some(lookup_key.to_string()),
+            recurring: None,
+        };
+        stripe_client.prices.lock().insert(price.id.clone(), price);
+    }
+
+    let stripe_billing = Arc::new(StripeBilling::test(stripe_client.clone()));
+    stripe_billing.initialize().await.unwrap();
+
+    let app = Arc::new(AppState {
+        db: test_db.db().clone(),
+        llm_db: None,
+        livekit_client: None,
+        blob_store_client: None,
+        real_stripe_client: None,
+        stripe_client: Some(stripe_client.clone()),
+        stripe_billing: Some(stripe_billing),
+        executor: Executor::Deterministic(cx.executor()),
+        kinesis_client: None,
+        config,
+    });
+
+    TestApp {
+        app,
+        stripe_client,
+        _test_db: test_db,
+    }
+}
+
+impl TestApp {
+    pub async fn create_user(&self, github_login: &str, github_user_id: i32) -> User {
+        let user_id = self
+            .app
+            .db
+            .create_user(
+                &format!("{github_login}@example.com"),
+                None,
+                false,
+                NewUserParams {
+                    github_login: github_login.into(),
+                    github_user_id,
+                },
+            )
+            .await
+            .unwrap()
+            .user_id;
+
+        self.app.db.get_user_by_id(user_id).await.unwrap().unwrap()
+    }
+
+    pub fn create_stripe_customer(&self, id: &str, user: &User) -> StripeCustomerId {
+        let customer = StripeCustomer {
+            id: StripeCustomerId(id.into()),
+            email: user.email_address.clone(),
+            metadata: Default::default(),
+            has_default_payment_method: false,
+        };
+        let customer_id = customer.id.clone();
+        self.stripe_client
+            .customers
+            .lock()
+            .insert(customer_id.clone(), customer);
+
+        customer_id
+    }
+
+    pub fn create_stripe_subscription(
+        &self,
+        id: &str,
+        customer_id: &StripeCustomerId,
+        price_id: &str,
+        status: SubscriptionStatus,
+    ) -> StripeSubscriptionId {
+        let price = self
+            .stripe_client
+            .prices
+            .lock()
+            .get(&StripePriceId(price_id.into()))
+            .cloned();
+        let now = Utc::now();
+        let subscription = StripeSubscription {
+            id: StripeSubscriptionId(id.into()),
+            customer: customer_id.clone(),
+            status,
+            current_period_start: now.timestamp(),
+            current_period_end: (now + chrono::Duration::days(30)).timestamp(),
+            items: vec![StripeSubscriptionItem {
+                id: StripeSubscriptionItemId(format!("{id}_item").into()),
+                price,
+                quantity: Some(1),
+            }],
+            cancel_at: None,
+            cancel_at_period_end: false,
+            cancellation_details: None,
+            discount: None,
+            currency: "usd".into(),
+            pause_collection: None,
+        };
+        let subscription_id = subscription.id.clone();
+        self.stripe_client
+            .subscriptions
+            .lock()
+            .insert(subscription_id.clone(), subscription);
+
+        subscription_id
+    }
+
+    pub fn set_stripe_subscription_status(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+        status: SubscriptionStatus,
+    ) {
+        self.stripe_client
+            .subscriptions
+            .lock()
+            .get_mut(subscription_id)
+            .unwrap()
+            .status = status;
+    }
+
+    /// Syncs the current state of the subscription in Stripe, returning the billing subscription that we stored.
+    pub async fn sync_stripe_subscription(
+        &self,
+        subscription_id: &StripeSubscriptionId,
+    ) -> billing_subscription::Model {
+        let stripe_client: Arc<dyn StripeClient> = self.stripe_client.clone();
+        let subscription = self.stripe_client.subscriptions.lock()[subscription_id].clone();
+        sync_subscription(&self.app, &stripe_client, subscription)
+            .await
+            .unwrap();
+
+        self.app
+            .db
+            .get_billing_subscription_by_stripe_subscription_id(&subscription_id.0)
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    pub fn stripe_subscriptions_for_customer(
+        &self,
+        customer_id: &StripeCustomerId,
+    ) -> Vec<StripeSubscription> {
+        self.stripe_client
+            .subscriptions
+            .lock()
+            .values()
+            .filter(|subscription| subscription.customer == *customer_id)
+            .cloned()
+            .collect()
+    }
+}
+
+pub fn format_timestamp(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .unwrap()
+        .to_rfc3339_opts(SecondsFormat::Millis, true)
+}
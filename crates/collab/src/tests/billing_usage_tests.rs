@@ -0,0 +1,2552 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
+use collections::{BTreeMap, HashSet};
+use gpui::TestAppContext;
+use pretty_assertions::assert_eq;
+use reqwest::StatusCode;
+use sea_orm::ActiveValue;
+use serde_json::json;
+use stripe::SubscriptionStatus;
+use zed_llm_client::LanguageModelProvider;
+
+use crate::api::billing::localization::Locale;
+use crate::api::billing::manifest::BillingConfigManifest;
+use crate::api::billing::{
+    AccessBlockedReason, CreditModelRequestUsageBody, CurrentUsage, DEFAULT_BILLING_CURRENCY,
+    EDIT_PREDICTION_OVERAGE_METER_EVENT_NAME, EDIT_PREDICTION_OVERAGE_PRICE_LOOKUP_KEY,
+    GetCurrentUsageResponse, ModelRequestAllotment, ModelRequestBilling, ModelRequestLimit,
+    ModelRequestUsage, SyncedModelUsage, USAGE_SYNC_FAILURE_COOLDOWN, USAGE_SYNC_STRIPE_TIMEOUT,
+    UsageCounts, UsageLimits, UsageMeterKey, UsageResetWindow, UsageSyncCircuitBreaker,
+    access_status_for_user, apply_model_request_limits, bill_edit_prediction_overage,
+    bill_model_request_usage, billed_model_requests, billed_overage, billing_suspended_usage_row,
+    cap_billed_usage, credit_model_request_usage_for_user, credits_remaining_in_cents,
+    current_usage_response, custom_prices, default_model_request_billing, edit_prediction_overage,
+    meter_reports_for_user, model_request_allotment_usage, model_request_allotments,
+    model_request_billing, model_request_billing_for_credit, model_request_billing_table,
+    model_request_limits, model_request_weight, model_request_weights, overage_begins_in_requests,
+    parse_period, requests_by_usage_meter_key, spend_limit_reached_row,
+    update_billing_suspension_for_user, usage_history_period, usage_limits, usage_reset_window,
+    weighted_model_requests, weighted_requests, with_usage_sync_timeout,
+};
+use crate::db::billing_subscription::{self, StripeSubscriptionStatus, SubscriptionKind};
+use crate::db::{
+    CreateBillingCustomerParams, CreateBillingSubscriptionParams, CreateCustomPriceOverrideParams,
+    NewUserParams, UpdateBillingCustomerParams, User, UserId, billing_customer,
+};
+use crate::executor::Executor;
+use crate::llm::BYPASS_ACCOUNT_AGE_CHECK_FEATURE_FLAG;
+use crate::llm::db::subscription_usage_meter::{self, CompletionMode, UsageDimensions};
+use crate::llm::db::{ModelId, subscription_usage};
+use crate::stripe_client::{
+    StripeCreateCustomerBalanceTransactionParams, StripePrice, StripePriceId,
+};
+use crate::tests::billing_test_app::{make_test_app, make_test_app_with_config};
+use crate::{Config, Error, TrialEndBehavior};
+
+#[gpui::test]
+async fn test_credit_model_request_usage(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let price = StripePrice {
+        id: StripePriceId("price_claude_sonnet_4".into()),
+        unit_amount: Some(4),
+        lookup_key: Some("claude-sonnet-4-requests".to_string()),
+        recurring: None,
+    };
+    test_app
+        .stripe_client
+        .prices
+        .lock()
+        .insert(price.id.clone(), price);
+    stripe_billing.initialize().await.unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let body = CreditModelRequestUsageBody {
+        github_user_id: user.github_user_id,
+        model: "claude-sonnet-4".to_string(),
+        mode: CompletionMode::Normal,
+        requests: 25,
+        reason: "requests made after the account was compromised".to_string(),
+        idempotency_key: Some("credit-compromised-requests".to_string()),
+    };
+
+    let adjustment = credit_model_request_usage_for_user(app, &stripe_billing, &user, &body)
+        .await
+        .unwrap();
+    assert_eq!(adjustment.amount_in_cents, -100);
+    assert_eq!(adjustment.requests, 25);
+    assert_eq!(adjustment.mode, "normal");
+
+    let calls = test_app
+        .stripe_client
+        .create_customer_balance_transaction_calls
+        .lock()
+        .clone();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].customer_id, customer_id);
+    assert_eq!(calls[0].amount, -100);
+    assert_eq!(calls[0].currency, "usd");
+    assert_eq!(
+        calls[0].idempotency_key.as_deref(),
+        Some("credit-compromised-requests")
+    );
+
+    assert_eq!(
+        app.db
+            .get_billing_usage_adjustments(billing_customer.id)
+            .await
+            .unwrap(),
+        vec![adjustment]
+    );
+
+    // Requests in a mode that has no price are rejected.
+    let result = credit_model_request_usage_for_user(
+        app,
+        &stripe_billing,
+        &user,
+        &CreditModelRequestUsageBody {
+            mode: CompletionMode::Max,
+            ..body
+        },
+    )
+    .await;
+    assert!(result.is_err());
+    assert_eq!(
+        test_app
+            .stripe_client
+            .create_customer_balance_transaction_calls
+            .lock()
+            .len(),
+        1
+    );
+
+    // Credits are given in the currency that the customer is billed in.
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                currency: ActiveValue::set("eur".to_string()),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    credit_model_request_usage_for_user(
+        app,
+        &stripe_billing,
+        &user,
+        &CreditModelRequestUsageBody {
+            github_user_id: user.github_user_id,
+            model: "claude-sonnet-4".to_string(),
+            mode: CompletionMode::Normal,
+            requests: 25,
+            reason: "requests that failed during an outage".to_string(),
+            idempotency_key: None,
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        test_app
+            .stripe_client
+            .create_customer_balance_transaction_calls
+            .lock()
+            .last()
+            .map(|call| call.currency.clone()),
+        Some("eur".to_string())
+    );
+}
+
+#[gpui::test]
+async fn test_credit_weighted_model_request_usage(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            model_request_billing: Some(vec![
+                "anthropic claude-opus-4 max 5 claude-opus-4-requests-max claude_opus_4/requests/max"
+                    .into(),
+            ]),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let price = StripePrice {
+        id: StripePriceId("price_claude_opus_4_max".into()),
+        unit_amount: Some(4),
+        lookup_key: Some("claude-opus-4-requests-max".to_string()),
+        recurring: None,
+    };
+    test_app
+        .stripe_client
+        .prices
+        .lock()
+        .insert(price.id.clone(), price);
+    stripe_billing.initialize().await.unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    app.db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    // Each Max mode request was billed as 5 requests, so it's credited as 5 requests.
+    let adjustment = credit_model_request_usage_for_user(
+        app,
+        &stripe_billing,
+        &user,
+        &CreditModelRequestUsageBody {
+            github_user_id: user.github_user_id,
+            model: "claude-opus-4".to_string(),
+            mode: CompletionMode::Max,
+            requests: 10,
+            reason: "requests that failed during an outage".to_string(),
+            idempotency_key: None,
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(adjustment.requests, 50);
+    assert_eq!(adjustment.amount_in_cents, -200);
+    assert_eq!(
+        test_app
+            .stripe_client
+            .create_customer_balance_transaction_calls
+            .lock()
+            .iter()
+            .map(|call| call.amount)
+            .collect::<Vec<_>>(),
+        vec![-200]
+    );
+
+    // Normal mode requests aren't billed for this model, so there's nothing to credit.
+    let result = credit_model_request_usage_for_user(
+        app,
+        &stripe_billing,
+        &user,
+        &CreditModelRequestUsageBody {
+            github_user_id: user.github_user_id,
+            model: "claude-opus-4".to_string(),
+            mode: CompletionMode::Normal,
+            requests: 10,
+            reason: "requests that failed during an outage".to_string(),
+            idempotency_key: None,
+        },
+    )
+    .await;
+    assert!(matches!(
+        result,
+        Err(Error::Http(StatusCode::BAD_REQUEST, _, _))
+    ));
+}
+
+#[gpui::test]
+async fn test_custom_price_override(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let catalog_price = StripePrice {
+        id: StripePriceId("price_claude_sonnet_4".into()),
+        unit_amount: Some(4),
+        lookup_key: Some("claude-sonnet-4-requests".to_string()),
+        recurring: None,
+    };
+    // Custom prices aren't part of the catalog, so they don't have a lookup key.
+    let custom_price = StripePrice {
+        id: StripePriceId("price_claude_sonnet_4_custom".into()),
+        unit_amount: Some(2),
+        lookup_key: None,
+        recurring: None,
+    };
+    for price in [&catalog_price, &custom_price] {
+        test_app
+            .stripe_client
+            .prices
+            .lock()
+            .insert(price.id.clone(), price.clone());
+    }
+    stripe_billing.initialize().await.unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+    app.db
+        .create_custom_price_override(&CreateCustomPriceOverrideParams {
+            billing_customer_id: billing_customer.id,
+            meter_event_name: "claude_sonnet_4/requests".to_string(),
+            stripe_price_id: custom_price.id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let price_overrides = app
+        .db
+        .get_custom_price_overrides_by_billing_customer()
+        .await
+        .unwrap();
+    let custom_prices = custom_prices(&stripe_billing, &price_overrides[&billing_customer.id])
+        .await
+        .unwrap();
+    assert_eq!(
+        custom_prices.get("claude_sonnet_4/requests"),
+        Some(&custom_price)
+    );
+
+    // The customer's usage is billed at the custom price, rather than the catalog price.
+    let billing_table = default_model_request_billing();
+    let sonnet = billing_table
+        .iter()
+        .find(|billing| billing.meter_event_name == "claude_sonnet_4/requests")
+        .unwrap();
+    let price = custom_prices
+        .get(sonnet.meter_event_name.as_str())
+        .unwrap_or(&catalog_price);
+    bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        None,
+        &billing_table,
+        vec![(
+            sonnet,
+            price,
+            SyncedModelUsage {
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Normal,
+                dimensions: UsageDimensions::default(),
+                requests: 10,
+            },
+        )],
+        &[],
+        0,
+        None,
+        false,
+    )
+    .await
+    .unwrap();
+    let update_subscription_calls = test_app
+        .stripe_client
+        .update_subscription_calls
+        .lock()
+        .clone();
+    assert_eq!(update_subscription_calls.len(), 1);
+    assert_eq!(update_subscription_calls[0].0, subscription_id);
+    assert_eq!(
+        update_subscription_calls[0]
+            .1
+            .items
+            .iter()
+            .flatten()
+            .map(|item| item.price.clone())
+            .collect::<Vec<_>>(),
+        vec![Some(custom_price.id.clone())]
+    );
+
+    // Credits are given at the custom price, too.
+    let body = CreditModelRequestUsageBody {
+        github_user_id: user.github_user_id,
+        model: "claude-sonnet-4".to_string(),
+        mode: CompletionMode::Normal,
+        requests: 25,
+        reason: "requests that failed during an outage".to_string(),
+        idempotency_key: None,
+    };
+    let adjustment = credit_model_request_usage_for_user(app, &stripe_billing, &user, &body)
+        .await
+        .unwrap();
+    assert_eq!(adjustment.amount_in_cents, -50);
+
+    // Customers without an override are still billed at the catalog price.
+    let other_user = test_app.create_user("user2", 2).await;
+    let other_customer_id = test_app.create_stripe_customer("cus_2", &other_user);
+    app.db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: other_user.id,
+            stripe_customer_id: other_customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+    let adjustment = credit_model_request_usage_for_user(
+        app,
+        &stripe_billing,
+        &other_user,
+        &CreditModelRequestUsageBody {
+            github_user_id: other_user.github_user_id,
+            ..body
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(adjustment.amount_in_cents, -100);
+}
+
+#[test]
+fn test_edit_prediction_overage() {
+    for plan in [
+        zed_llm_client::Plan::ZedFree,
+        zed_llm_client::Plan::ZedPro,
+        zed_llm_client::Plan::ZedProTrial,
+    ] {
+        match plan.edit_predictions_limit() {
+            zed_llm_client::UsageLimit::Limited(limit) => {
+                assert_eq!(edit_prediction_overage(plan, 0), 0);
+                assert_eq!(edit_prediction_overage(plan, limit), 0);
+                assert_eq!(edit_prediction_overage(plan, limit + 1), 1);
+                assert_eq!(edit_prediction_overage(plan, limit + 250), 250);
+            }
+            zed_llm_client::UsageLimit::Unlimited => {
+                assert_eq!(edit_prediction_overage(plan, 0), 0);
+                assert_eq!(edit_prediction_overage(plan, i32::MAX), 0);
+            }
+        }
+    }
+}
+
+#[gpui::test]
+async fn test_usage_sync_stripe_timeout(cx: &mut TestAppContext) {
+    let executor = Executor::Deterministic(cx.executor());
+
+    // Calls that respond in time return their result.
+    assert_eq!(
+        with_usage_sync_timeout(&executor, async { anyhow::Ok(7) })
+            .await
+            .unwrap(),
+        7
+    );
+
+    // Calls that don't respond in time fail once the timeout is up, and not before.
+    let result = Arc::new(parking_lot::Mutex::new(None));
+    cx.executor()
+        .spawn({
+            let result = result.clone();
+            async move {
+                let call_result = with_usage_sync_timeout(
+                    &executor,
+                    futures::future::pending::<anyhow::Result<()>>(),
+                )
+                .await;
+                *result.lock() = Some(call_result);
+            }
+        })
+        .detach();
+    cx.executor()
+        .advance_clock(USAGE_SYNC_STRIPE_TIMEOUT - Duration::from_secs(1));
+    cx.executor().run_until_parked();
+    assert!(result.lock().is_none());
+    cx.executor().advance_clock(Duration::from_secs(1));
+    cx.executor().run_until_parked();
+    assert!(result.lock().take().unwrap().is_err());
+}
+
+#[test]
+fn test_usage_dimensions() {
+    let dimensions = UsageDimensions::parse("tools=true, context=long");
+    assert_eq!(
+        dimensions,
+        UsageDimensions::new([("context", "long"), ("tools", "true")])
+    );
+    assert_eq!(dimensions.to_string(), "context=long,tools=true");
+
+    assert!(UsageDimensions::parse("").is_empty());
+    assert_eq!(UsageDimensions::default().to_string(), "");
+}
+
+#[test]
+fn test_requests_by_usage_meter_key() {
+    let model_id = ModelId(1);
+    let usage_meter =
+        |mode: CompletionMode, dimensions: &str, requests: i32| subscription_usage_meter::Model {
+            id: uuid::Uuid::new_v4(),
+            subscription_usage_id: uuid::Uuid::nil(),
+            model_id,
+            mode,
+            dimensions: dimensions.into(),
+            requests,
+        };
+
+    // A model that is billed by context length and tool use, in addition to model and mode.
+    let usage_meters = [
+        usage_meter(CompletionMode::Normal, "", 10),
+        usage_meter(CompletionMode::Normal, "context=long", 4),
+        usage_meter(CompletionMode::Normal, "context=long,tools=true", 3),
+        usage_meter(CompletionMode::Normal, "tools=true,context=long", 2),
+        usage_meter(CompletionMode::Max, "context=long", 1),
+    ];
+    let requests_by_key = requests_by_usage_meter_key(&usage_meters);
+
+    let requests_for = |mode: CompletionMode, dimensions: &[(&str, &str)]| {
+        let billing = ModelRequestBilling {
+            provider: LanguageModelProvider::Anthropic,
+            model: "some-model".into(),
+            mode,
+            dimensions: dimensions
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            request_weight: 1,
+            price_lookup_key: "some-model-requests".into(),
+            meter_event_name: "some_model/requests".into(),
+        };
+
+        requests_by_key
+            .get(&UsageMeterKey {
+                model_id,
+                mode: billing.mode,
+                dimensions: billing.dimensions(),
+            })
+            .copied()
+            .unwrap_or(0)
+    };
+
+    // Requests without any dimensions are billed by model and mode, as before.
+    assert_eq!(requests_for(CompletionMode::Normal, &[]), 10);
+    assert_eq!(requests_for(CompletionMode::Max, &[]), 0);
+
+    // Each combination of dimensions is billed separately, regardless of the order they were recorded in.
+    assert_eq!(
+        requests_for(CompletionMode::Normal, &[("context", "long")]),
+        4
+    );
+    assert_eq!(
+        requests_for(
+            CompletionMode::Normal,
+            &[("tools", "true"), ("context", "long")]
+        ),
+        5
+    );
+    assert_eq!(requests_for(CompletionMode::Max, &[("context", "long")]), 1);
+
+    // The dimensions are included when reporting the synced usage.
+    let synced_usage = SyncedModelUsage {
+        model: "some-model".into(),
+        mode: CompletionMode::Max,
+        dimensions: UsageDimensions::new([("context", "long")]),
+        requests: 1,
+    };
+    assert_eq!(
+        synced_usage.to_json(),
+        json!({
+            "model": "some-model",
+            "mode": "max",
+            "requests": 1,
+            "dimensions": { "context": "long" },
+        })
+    );
+}
+
+#[test]
+fn test_weighted_model_requests() {
+    let usage = |mode: CompletionMode, requests: i32, weight: i32| ModelRequestUsage {
+        provider: LanguageModelProvider::Anthropic,
+        model: "claude-sonnet-4".into(),
+        mode,
+        dimensions: BTreeMap::default(),
+        requests,
+        weight,
+        weighted_requests: weighted_requests(requests, weight),
+        billed_requests: 0,
+        limit: None,
+        remaining: None,
+    };
+
+    assert_eq!(weighted_model_requests(0, &[]), 0);
+
+    // Without any weighted models, each request counts once.
+    assert_eq!(
+        weighted_model_requests(
+            12,
+            &[
+                usage(CompletionMode::Normal, 10, 1),
+                usage(CompletionMode::Max, 2, 1),
+            ]
+        ),
+        12
+    );
+
+    // Weighted requests count for their full weight, while requests to models that aren't metered still count once.
+    assert_eq!(
+        weighted_model_requests(
+            15,
+            &[
+                usage(CompletionMode::Normal, 10, 1),
+                usage(CompletionMode::Max, 2, 5),
+            ]
+        ),
+        23
+    );
+
+    // The weighted count saturates rather than overflowing.
+    assert_eq!(
+        weighted_model_requests(i32::MAX - 1, &[usage(CompletionMode::Max, 2, 5)]),
+        i32::MAX
+    );
+}
+
+#[test]
+fn test_model_request_weight() {
+    let billing_table = default_model_request_billing();
+    assert!(
+        billing_table
+            .iter()
+            .all(|billing| billing.request_weight >= 1)
+    );
+
+    for billing in &billing_table {
+        assert_eq!(
+            model_request_weight(
+                &billing_table,
+                billing.provider,
+                &billing.model,
+                billing.mode,
+                &billing.dimensions()
+            ),
+            billing.request_weight
+        );
+    }
+    assert_eq!(
+        model_request_weights(&billing_table).len(),
+        billing_table
+            .iter()
+            .filter(|billing| billing.request_weight != 1)
+            .count()
+    );
+
+    // Requests that we don't bill count once.
+    assert_eq!(
+        model_request_weight(
+            &billing_table,
+            LanguageModelProvider::Anthropic,
+            "unknown-model",
+            CompletionMode::Max,
+            &UsageDimensions::default()
+        ),
+        1
+    );
+
+    // Requests are only billed for the provider that the model is billed for.
+    assert_eq!(
+        model_request_weight(
+            &billing_table,
+            LanguageModelProvider::OpenAi,
+            "claude-sonnet-4",
+            CompletionMode::Normal,
+            &UsageDimensions::default()
+        ),
+        1
+    );
+    assert!(
+        model_request_billing(
+            &billing_table,
+            LanguageModelProvider::OpenAi,
+            "claude-sonnet-4",
+            CompletionMode::Normal,
+            &UsageDimensions::default()
+        )
+        .is_none()
+    );
+}
+
+#[test]
+fn test_model_request_billing_names() {
+    let billing_table = default_model_request_billing();
+    let meter_event_names = billing_table
+        .iter()
+        .map(|billing| billing.meter_event_name.as_str())
+        .collect::<HashSet<_>>();
+    assert_eq!(meter_event_names.len(), billing_table.len());
+    let price_lookup_keys = billing_table
+        .iter()
+        .map(|billing| billing.price_lookup_key.as_str())
+        .collect::<HashSet<_>>();
+    assert_eq!(price_lookup_keys.len(), billing_table.len());
+
+    // Providers other than Anthropic prefix their meter event names and price lookup keys with the provider.
+    for billing in &billing_table {
+        let prefix = match billing.provider {
+            LanguageModelProvider::Anthropic => continue,
+            LanguageModelProvider::OpenAi => "openai",
+            LanguageModelProvider::Google => "google",
+        };
+        assert!(
+            billing.meter_event_name.starts_with(&format!("{prefix}/")),
+            "{}",
+            billing.meter_event_name
+        );
+        assert!(
+            billing.price_lookup_key.starts_with(&format!("{prefix}-")),
+            "{}",
+            billing.price_lookup_key
+        );
+    }
+}
+
+#[test]
+fn test_model_request_billing_table() {
+    // Without the setting, we bill with the built-in table.
+    assert_eq!(
+        model_request_billing_table(&Config::test()),
+        default_model_request_billing()
+    );
+
+    // The setting replaces the built-in table, skipping malformed entries.
+    let config = Config {
+        model_request_billing: Some(vec![
+            "anthropic claude-opus-4 max 5 claude-opus-4-requests-max claude_opus_4/requests/max"
+                .into(),
+            "openai gpt-4o normal 1 openai-gpt-4o-requests openai/gpt_4o/requests context=long"
+                .into(),
+            "anthropic claude-sonnet-4 fast 1 claude-sonnet-4-requests claude_sonnet_4/requests"
+                .into(),
+            "anthropic claude-sonnet-4 normal 0 claude-sonnet-4-requests claude_sonnet_4/requests"
+                .into(),
+            "anthropic claude-sonnet-4 normal 1 claude-sonnet-4-requests".into(),
+        ]),
+        ..Config::test()
+    };
+    assert_eq!(
+        model_request_billing_table(&config),
+        vec![
+            ModelRequestBilling {
+                provider: LanguageModelProvider::Anthropic,
+                model: "claude-opus-4".into(),
+                mode: CompletionMode::Max,
+                dimensions: Vec::new(),
+                request_weight: 5,
+                price_lookup_key: "claude-opus-4-requests-max".into(),
+                meter_event_name: "claude_opus_4/requests/max".into(),
+            },
+            ModelRequestBilling {
+                provider: LanguageModelProvider::OpenAi,
+                model: "gpt-4o".into(),
+                mode: CompletionMode::Normal,
+                dimensions: vec![("context".into(), "long".into())],
+                request_weight: 1,
+                price_lookup_key: "openai-gpt-4o-requests".into(),
+                meter_event_name: "openai/gpt_4o/requests".into(),
+            },
+        ]
+    );
+
+    // Requests are only credited in the modes that they're billed in.
+    let billing_table = default_model_request_billing();
+    let credited_price_lookup_key = |model, mode| {
+        model_request_billing_for_credit(&billing_table, model, mode)
+            .map(|billing| billing.price_lookup_key.as_str())
+    };
+    assert_eq!(
+        credited_price_lookup_key("claude-3-5-sonnet", CompletionMode::Normal),
+        Some("claude-3-5-sonnet-requests")
+    );
+    assert_eq!(
+        credited_price_lookup_key("claude-3-5-sonnet", CompletionMode::Max),
+        None
+    );
+    assert_eq!(
+        credited_price_lookup_key("claude-opus-4", CompletionMode::Max),
+        Some("claude-opus-4-requests-max")
+    );
+    assert_eq!(
+        credited_price_lookup_key("unknown-model", CompletionMode::Normal),
+        None
+    );
+}
+
+#[test]
+fn test_billed_model_requests() {
+    let billing_table = default_model_request_billing();
+    let billing = |meter_event_name: &str| {
+        billing_table
+            .iter()
+            .find(|billing| billing.meter_event_name == meter_event_name)
+            .unwrap()
+    };
+    let opus = billing("claude_opus_4/requests");
+    let sonnet = billing("claude_sonnet_4/requests");
+
+    // Without a grace, every request is billed, while requests that we don't bill are never billed.
+    assert_eq!(
+        billed_model_requests(
+            &billing_table,
+            &[(Some(sonnet), 7), (None, 3), (Some(opus), 2)],
+            &[],
+            0
+        ),
+        vec![7, 0, 2]
+    );
+
+    // The grace is used up in the order of the billing table, regardless of the order of the usage.
+    assert_eq!(
+        billed_model_requests(
+            &billing_table,
+            &[(Some(sonnet), 7), (None, 3), (Some(opus), 2)],
+            &[],
+            5
+        ),
+        vec![4, 0, 0]
+    );
+    assert_eq!(
+        billed_model_requests(
+            &billing_table,
+            &[(Some(sonnet), 7), (Some(opus), 2)],
+            &[],
+            20
+        ),
+        vec![0, 0]
+    );
+
+    // Negative counts are never billed and don't use up the grace.
+    assert_eq!(
+        billed_model_requests(
+            &billing_table,
+            &[(Some(opus), -4), (Some(sonnet), 7)],
+            &[],
+            5
+        ),
+        vec![0, 2]
+    );
+}
+
+#[test]
+fn test_overage_grace_is_applied_once_per_period() {
+    let billing_table = default_model_request_billing();
+    let sonnet = billing_table
+        .iter()
+        .find(|billing| billing.meter_event_name == "claude_sonnet_4/requests")
+        .unwrap();
+    let overage_grace = 10;
+
+    // Each sync reports the total for the period, so the grace is only taken off once, however often we sync.
+    let billed_per_sync = [4, 12, 12, 25]
+        .into_iter()
+        .map(|requests| {
+            billed_model_requests(
+                &billing_table,
+                &[(Some(sonnet), requests)],
+                &[],
+                overage_grace,
+            )
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(billed_per_sync, vec![vec![0], vec![2], vec![2], vec![15]]);
+
+    // The request counts start over with the next period, and so does the grace.
+    assert_eq!(
+        billed_model_requests(&billing_table, &[(Some(sonnet), 12)], &[], overage_grace),
+        vec![2]
+    );
+
+    assert_eq!(billed_overage(0, overage_grace), 0);
+    assert_eq!(billed_overage(8, overage_grace), 0);
+    assert_eq!(billed_overage(15, overage_grace), 5);
+    assert_eq!(billed_overage(15, 0), 15);
+    assert_eq!(billed_overage(15, -5), 15);
+}
+
+#[test]
+fn test_overage_begins_in_requests() {
+    let pro = zed_llm_client::Plan::ZedPro;
+    let counts = |used, limit| UsageCounts::new(used, limit, Utc::now());
+
+    // Below, at, and above the included limit.
+    assert_eq!(
+        overage_begins_in_requests(pro, true, &counts(120, Some(500)), 0),
+        Some(380)
+    );
+    assert_eq!(
+        overage_begins_in_requests(pro, true, &counts(500, Some(500)), 0),
+        Some(0)
+    );
+    assert_eq!(
+        overage_begins_in_requests(pro, true, &counts(650, Some(500)), 0),
+        Some(0)
+    );
+
+    // The free overage grace pushes back when charges begin.
+    assert_eq!(
+        overage_begins_in_requests(pro, true, &counts(500, Some(500)), 10),
+        Some(10)
+    );
+
+    // There's no countdown when overages are disabled, the limit is unlimited, or the plan isn't billed for overages.
+    assert_eq!(
+        overage_begins_in_requests(pro, false, &counts(120, Some(500)), 0),
+        None
+    );
+    assert_eq!(
+        overage_begins_in_requests(pro, true, &counts(120, None), 0),
+        None
+    );
+    assert_eq!(
+        overage_begins_in_requests(
+            zed_llm_client::Plan::ZedProTrial,
+            true,
+            &counts(120, Some(150)),
+            0
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_cap_billed_usage() {
+    // Requests are billed in order until the spend limit runs out.
+    assert_eq!(
+        cap_billed_usage(&[(5, 4), (10, 10), (3, 1)], 45),
+        [(5, 0), (2, 8), (3, 0)]
+    );
+
+    // Requests without a price don't count towards the limit.
+    assert_eq!(cap_billed_usage(&[(5, 0), (5, 4)], 8), [(5, 0), (2, 3)]);
+
+    // Nothing is billed once the limit is reached.
+    assert_eq!(cap_billed_usage(&[(5, 4), (5, 4)], 0), [(0, 5), (0, 5)]);
+    assert_eq!(cap_billed_usage(&[(5, 4)], -10), [(0, 5)]);
+}
+
+#[test]
+fn test_per_model_allotments() {
+    let config = Config {
+        model_request_allotments: Some(vec![
+            "zed_pro:claude-sonnet-4=300".into(),
+            "zed_pro:claude-opus-4=50".into(),
+            "zed_free:claude-sonnet-4=10".into(),
+            "zed_pro:claude-3-7-sonnet".into(),
+        ]),
+        ..Config::test()
+    };
+    let allotments = model_request_allotments(&config, zed_llm_client::Plan::ZedPro, Some(500));
+    assert_eq!(
+        allotments,
+        vec![
+            ModelRequestAllotment {
+                model: Some("claude-sonnet-4".into()),
+                limit: Some(300),
+            },
+            ModelRequestAllotment {
+                model: Some("claude-opus-4".into()),
+                limit: Some(50),
+            },
+        ]
+    );
+
+    // Plans without per-model allotments keep their pooled limit as a single allotment.
+    let pooled_allotments =
+        model_request_allotments(&config, zed_llm_client::Plan::ZedProTrial, Some(150));
+    assert_eq!(
+        pooled_allotments,
+        vec![ModelRequestAllotment {
+            model: None,
+            limit: Some(150),
+        }]
+    );
+
+    let billing_table = default_model_request_billing();
+    let billing = |meter_event_name: &str| {
+        billing_table
+            .iter()
+            .find(|billing| billing.meter_event_name == meter_event_name)
+            .unwrap()
+    };
+    let opus = billing("claude_opus_4/requests");
+    let sonnet = billing("claude_sonnet_4/requests");
+    let sonnet_max = billing("claude_sonnet_4/requests/max");
+    let claude_3_7_sonnet = billing("claude_3_7_sonnet/requests");
+
+    // Requests within a model's allotment aren't billed.
+    assert_eq!(
+        billed_model_requests(
+            &billing_table,
+            &[(Some(sonnet), 250), (Some(opus), 40)],
+            &allotments,
+            0
+        ),
+        vec![0, 0]
+    );
+
+    // Once a model's allotment is used up, only that model's requests beyond it are billed. A model's allotment is
+    // shared by all of its modes, and models without an allotment are billed for every request.
+    assert_eq!(
+        billed_model_requests(
+            &billing_table,
+            &[
+                (Some(sonnet), 250),
+                (Some(sonnet_max), 80),
+                (Some(opus), 60),
+                (Some(claude_3_7_sonnet), 5),
+            ],
+            &allotments,
+            0
+        ),
+        vec![30, 0, 10, 5]
+    );
+
+    // The grace only applies to the overage beyond the allotments.
+    assert_eq!(
+        billed_model_requests(
+            &billing_table,
+            &[
+                (Some(sonnet), 250),
+                (Some(sonnet_max), 80),
+                (Some(opus), 60),
+                (Some(claude_3_7_sonnet), 5),
+            ],
+            &allotments,
+            12
+        ),
+        vec![28, 0, 0, 5]
+    );
+
+    // A pooled allotment has already been applied to the usage meters, so it doesn't change what's billed.
+    assert_eq!(
+        billed_model_requests(
+            &billing_table,
+            &[(Some(sonnet), 7), (Some(opus), 2)],
+            &pooled_allotments,
+            5
+        ),
+        billed_model_requests(
+            &billing_table,
+            &[(Some(sonnet), 7), (Some(opus), 2)],
+            &[],
+            5
+        )
+    );
+
+    // Each per-model allotment reports the usage of its own model.
+    let model_request_usage =
+        |model: &str, mode: CompletionMode, requests: i32| ModelRequestUsage {
+            provider: LanguageModelProvider::Anthropic,
+            model: model.into(),
+            mode,
+            dimensions: BTreeMap::default(),
+            requests,
+            weight: 1,
+            weighted_requests: requests,
+            billed_requests: 0,
+            limit: None,
+            remaining: None,
+        };
+    let allotment_usage = model_request_allotment_usage(
+        &allotments,
+        390,
+        &[
+            model_request_usage("claude-sonnet-4", CompletionMode::Normal, 250),
+            model_request_usage("claude-sonnet-4", CompletionMode::Max, 80),
+            model_request_usage("claude-opus-4", CompletionMode::Normal, 60),
+        ],
+        Utc::now(),
+    )
+    .into_iter()
+    .map(|usage| {
+        (
+            usage.model,
+            usage.requests.used,
+            usage.requests.limit,
+            usage.requests.remaining,
+        )
+    })
+    .collect::<Vec<_>>();
+    assert_eq!(
+        allotment_usage,
+        vec![
+            (Some("claude-sonnet-4".into()), 330, Some(300), Some(0)),
+            (Some("claude-opus-4".into()), 60, Some(50), Some(0)),
+        ]
+    );
+
+    // A pooled allotment reports all of the model requests.
+    let allotment_usage = model_request_allotment_usage(&pooled_allotments, 90, &[], Utc::now());
+    assert_eq!(allotment_usage.len(), 1);
+    assert_eq!(allotment_usage[0].model, None);
+    assert_eq!(allotment_usage[0].requests.used, 90);
+    assert_eq!(allotment_usage[0].requests.remaining, Some(60));
+}
+
+#[test]
+fn test_per_model_limits() {
+    let config = Config {
+        model_request_limits: Some(vec![
+            "zed_pro:claude-opus-4=100".into(),
+            "zed_free:claude-opus-4=5".into(),
+            "zed_pro:claude-sonnet-4".into(),
+        ]),
+        ..Config::test()
+    };
+    let limits = model_request_limits(&config, zed_llm_client::Plan::ZedPro);
+    assert_eq!(
+        limits,
+        vec![ModelRequestLimit {
+            model: "claude-opus-4".into(),
+            limit: 100,
+        }]
+    );
+
+    // Per-model limits are on top of the plan's pooled limit, rather than replacing it.
+    let plan_limits = usage_limits(&config, zed_llm_client::Plan::ZedPro, false);
+    assert!(plan_limits.model_requests.is_some());
+    assert_eq!(plan_limits.model_request_limits, limits);
+
+    let usage = |model: &str, mode: CompletionMode, requests: i32, weight: i32| ModelRequestUsage {
+        provider: LanguageModelProvider::Anthropic,
+        model: model.into(),
+        mode,
+        dimensions: BTreeMap::default(),
+        requests,
+        weight,
+        weighted_requests: weighted_requests(requests, weight),
+        billed_requests: 0,
+        limit: None,
+        remaining: None,
+    };
+    let mut model_request_usage = vec![
+        usage("claude-opus-4", CompletionMode::Normal, 30, 1),
+        usage("claude-opus-4", CompletionMode::Max, 20, 2),
+        usage("claude-sonnet-4", CompletionMode::Normal, 200, 1),
+    ];
+    apply_model_request_limits(&mut model_request_usage, &limits);
+
+    // A model's limit is shared by its modes, with each weighted request counting against it.
+    let limits_and_remaining = model_request_usage
+        .iter()
+        .map(|usage| (usage.limit, usage.remaining))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        limits_and_remaining,
+        vec![(Some(100), Some(30)), (Some(100), Some(30)), (None, None)]
+    );
+
+    // Going over the limit leaves no requests remaining.
+    let mut model_request_usage = vec![usage("claude-opus-4", CompletionMode::Normal, 150, 1)];
+    apply_model_request_limits(&mut model_request_usage, &limits);
+    assert_eq!(model_request_usage[0].remaining, Some(0));
+
+    // Plans without per-model limits only have the aggregate limit.
+    assert!(model_request_limits(&config, zed_llm_client::Plan::ZedProTrial).is_empty());
+}
+
+#[test]
+fn test_overage_grace_config() {
+    let mut config = Config::test();
+    assert_eq!(config.overage_grace(), 0);
+
+    config.overage_grace = Some(10);
+    assert_eq!(config.overage_grace(), 10);
+
+    config.overage_grace = Some(-10);
+    assert_eq!(config.overage_grace(), 0);
+
+    // The grace is part of the billing configuration.
+    let hash = |config: &Config| BillingConfigManifest::new(config, &[]).hash().unwrap();
+    assert_ne!(hash(&Config::test()), hash(&config));
+}
+
+#[test]
+fn test_usage_reset_windows() {
+    let config = Config {
+        usage_reset_windows: Some(vec![
+            "zed_free:edit_predictions=1".into(),
+            "zed_pro:model_requests=15".into(),
+            "zed_pro:edit_predictions=31".into(),
+            "not a reset window".into(),
+        ]),
+        ..Config::test()
+    };
+    let free = zed_llm_client::Plan::ZedFree;
+    let pro = zed_llm_client::Plan::ZedPro;
+    assert_eq!(
+        usage_reset_window(&config, free, "edit_predictions"),
+        UsageResetWindow::Monthly { anchor_day: 1 }
+    );
+    assert_eq!(
+        usage_reset_window(&config, free, "model_requests"),
+        UsageResetWindow::SubscriptionPeriod
+    );
+    assert_eq!(
+        usage_reset_window(&config, pro, "model_requests"),
+        UsageResetWindow::Monthly { anchor_day: 15 }
+    );
+    // Days that don't fall in every month are ignored.
+    assert_eq!(
+        usage_reset_window(&config, pro, "edit_predictions"),
+        UsageResetWindow::SubscriptionPeriod
+    );
+    assert_eq!(
+        usage_reset_window(&Config::test(), pro, "model_requests"),
+        UsageResetWindow::SubscriptionPeriod
+    );
+
+    let at = |timestamp: &str| timestamp.parse::<DateTime<Utc>>().unwrap();
+    let period_end_at = at("2025-07-20T12:00:00Z");
+    let now = at("2025-07-10T08:00:00Z");
+
+    // Period-based resets happen at the end of the subscription period.
+    assert_eq!(
+        UsageResetWindow::SubscriptionPeriod.resets_at(period_end_at, now),
+        period_end_at
+    );
+
+    // Monthly resets happen at the next month boundary.
+    assert_eq!(
+        UsageResetWindow::Monthly { anchor_day: 1 }.resets_at(period_end_at, now),
+        at("2025-08-01T00:00:00Z")
+    );
+    assert_eq!(
+        UsageResetWindow::Monthly { anchor_day: 15 }.resets_at(period_end_at, now),
+        at("2025-07-15T00:00:00Z")
+    );
+    assert_eq!(
+        UsageResetWindow::Monthly { anchor_day: 15 }
+            .resets_at(period_end_at, at("2025-07-15T00:00:00Z")),
+        at("2025-08-15T00:00:00Z")
+    );
+    assert_eq!(
+        UsageResetWindow::Monthly { anchor_day: 1 }
+            .resets_at(period_end_at, at("2025-12-20T00:00:00Z")),
+        at("2026-01-01T00:00:00Z")
+    );
+
+    let counts = UsageCounts::new(
+        10,
+        Some(50),
+        UsageResetWindow::Monthly { anchor_day: 1 }.resets_at(period_end_at, now),
+    );
+    assert_eq!(counts.resets_at, "2025-08-01T00:00:00.000Z");
+    assert_eq!(
+        UsageCounts::new(10, Some(50), period_end_at).resets_at,
+        "2025-07-20T12:00:00.000Z"
+    );
+}
+
+#[gpui::test]
+async fn test_suspended_user_is_not_billed(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    app.db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let billing_table = default_model_request_billing();
+    let billing = billing_table
+        .iter()
+        .find(|billing| billing.meter_event_name == "claude_sonnet_4/requests")
+        .unwrap();
+    let price = StripePrice {
+        id: StripePriceId("price_claude_sonnet_4".into()),
+        unit_amount: Some(4),
+        lookup_key: Some(billing.price_lookup_key.to_string()),
+        recurring: None,
+    };
+    let model_usage = || {
+        vec![(
+            billing,
+            &price,
+            SyncedModelUsage {
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Normal,
+                dimensions: UsageDimensions::default(),
+                requests: 12,
+            },
+        )]
+    };
+    update_billing_suspension_for_user(app, &user, true)
+        .await
+        .unwrap();
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(billing_customer.billing_suspended);
+
+    // While billing is suspended, nothing is reported to Stripe, but we keep track of what would have been billed.
+    let billed_model_usage = bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        None,
+        &billing_table,
+        model_usage(),
+        &[],
+        2,
+        None,
+        false,
+    )
+    .await
+    .unwrap();
+    assert!(billed_model_usage.synced.is_empty());
+    assert_eq!(billed_model_usage.suspended.len(), 1);
+    assert_eq!(billed_model_usage.suspended[0].requests, 10);
+    assert!(
+        test_app
+            .stripe_client
+            .create_meter_event_calls
+            .lock()
+            .is_empty()
+    );
+    assert_eq!(
+        test_app.stripe_subscriptions_for_customer(&customer_id)[0]
+            .items
+            .len(),
+        1
+    );
+
+    // The skipped usage is recorded for the period, edit predictions included, so that it can be reconciled later.
+    let billing_subscription = billing_subscription::Model {
+        stripe_current_period_start: Some(1_750_000_000),
+        stripe_current_period_end: Some(1_752_592_000),
+        ..Default::default()
+    };
+    let row = billing_suspended_usage_row(
+        &user,
+        &billing_subscription,
+        &billed_model_usage.suspended,
+        7,
+    )
+    .unwrap();
+    assert_eq!(row.event_type, "Billing Suspended Usage Skipped");
+    assert_eq!(row.event_properties["total_requests"], 10);
+    assert_eq!(row.event_properties["edit_predictions"], 7);
+    assert_eq!(
+        row.event_properties["period_start_at"],
+        "2025-06-15T15:06:40.000Z"
+    );
+    assert!(billing_suspended_usage_row(&user, &billing_subscription, &[], 3).is_some());
+    assert!(billing_suspended_usage_row(&user, &billing_subscription, &[], 0).is_none());
+
+    // Once billing is resumed, the usage is billed again.
+    update_billing_suspension_for_user(app, &user, false)
+        .await
+        .unwrap();
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(!billing_customer.billing_suspended);
+
+    let billed_model_usage = bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        None,
+        &billing_table,
+        model_usage(),
+        &[],
+        2,
+        None,
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(billed_model_usage.synced.len(), 1);
+    assert!(billed_model_usage.suspended.is_empty());
+
+    let create_meter_event_calls = test_app.stripe_client.create_meter_event_calls.lock();
+    assert_eq!(create_meter_event_calls.len(), 1);
+    assert_eq!(
+        create_meter_event_calls[0].event_name.as_ref(),
+        "claude_sonnet_4/requests"
+    );
+    assert_eq!(create_meter_event_calls[0].value, 10);
+}
+
+#[gpui::test]
+async fn test_weighted_usage_is_billed_once(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    // Each Max mode request to Claude Opus 4 counts as 5 requests.
+    let billing_table = model_request_billing_table(&Config {
+        model_request_billing: Some(vec![
+            "anthropic claude-opus-4 max 5 claude-opus-4-requests-max claude_opus_4/requests/max"
+                .into(),
+        ]),
+        ..Config::test()
+    });
+    let opus_max = &billing_table[0];
+    let price = StripePrice {
+        id: StripePriceId("price_claude_opus_4_max".into()),
+        unit_amount: Some(4),
+        lookup_key: Some(opus_max.price_lookup_key.clone()),
+        recurring: None,
+    };
+
+    // The usage meters count each request once, so the 3 requests beyond the plan's limit are billed as 15 weighted
+    // requests, less the grace.
+    let billed_model_usage = bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        None,
+        &billing_table,
+        vec![(
+            opus_max,
+            &price,
+            SyncedModelUsage {
+                model: "claude-opus-4".into(),
+                mode: CompletionMode::Max,
+                dimensions: UsageDimensions::default(),
+                requests: 3,
+            },
+        )],
+        &[],
+        2,
+        None,
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(billed_model_usage.synced.len(), 1);
+
+    let create_meter_event_calls = test_app.stripe_client.create_meter_event_calls.lock();
+    assert_eq!(create_meter_event_calls.len(), 1);
+    assert_eq!(
+        create_meter_event_calls[0].event_name.as_ref(),
+        "claude_opus_4/requests/max"
+    );
+    assert_eq!(create_meter_event_calls[0].value, 13);
+
+    // The weighted counts are only billed as given, so the weight isn't applied a second time.
+    assert_eq!(
+        billed_model_requests(
+            &billing_table,
+            &[(
+                Some(opus_max),
+                weighted_requests(3, opus_max.request_weight)
+            )],
+            &[],
+            2
+        ),
+        vec![13]
+    );
+}
+
+#[gpui::test]
+async fn test_meter_reports(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let price = |billing: &ModelRequestBilling| StripePrice {
+        id: StripePriceId(format!("price_{}", billing.price_lookup_key).into()),
+        unit_amount: Some(4),
+        lookup_key: Some(billing.price_lookup_key.to_string()),
+        recurring: None,
+    };
+    let usage = |model: &str, requests| SyncedModelUsage {
+        model: model.into(),
+        mode: CompletionMode::Normal,
+        dimensions: UsageDimensions::default(),
+        requests,
+    };
+    let billing_table = default_model_request_billing();
+    let sonnet = billing_table
+        .iter()
+        .find(|billing| billing.meter_event_name == "claude_sonnet_4/requests")
+        .unwrap();
+    let opus = billing_table
+        .iter()
+        .find(|billing| billing.meter_event_name == "claude_opus_4/requests")
+        .unwrap();
+    let (sonnet_price, opus_price) = (price(sonnet), price(opus));
+
+    let start_at = Utc::now() - chrono::Duration::minutes(1);
+    bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        None,
+        &billing_table,
+        vec![
+            (sonnet, &sonnet_price, usage("claude-sonnet-4", 7)),
+            (opus, &opus_price, usage("claude-opus-4", 3)),
+        ],
+        &[],
+        0,
+        None,
+        false,
+    )
+    .await
+    .unwrap();
+    let end_at = Utc::now() + chrono::Duration::minutes(1);
+
+    // Each meter event that was reported to Stripe is recorded with the identifier it was reported with.
+    let create_meter_event_calls = test_app
+        .stripe_client
+        .create_meter_event_calls
+        .lock()
+        .clone();
+    assert_eq!(create_meter_event_calls.len(), 2);
+    let reports = meter_reports_for_user(app, &user, (start_at, end_at))
+        .await
+        .unwrap();
+    assert_eq!(
+        reports
+            .iter()
+            .map(|report| (
+                report.stripe_customer_id.as_str(),
+                report.meter_event_name.as_str(),
+                report.value,
+                report.identifier.as_str(),
+            ))
+            .collect::<Vec<_>>(),
+        create_meter_event_calls
+            .iter()
+            .map(|call| (
+                "cus_1",
+                call.event_name.as_ref(),
+                call.value as i32,
+                call.identifier.as_ref(),
+            ))
+            .collect::<Vec<_>>()
+    );
+
+    // Reports outside of the period aren't listed.
+    let reports = meter_reports_for_user(
+        app,
+        &user,
+        (
+            start_at - chrono::Duration::days(30),
+            start_at - chrono::Duration::days(1),
+        ),
+    )
+    .await
+    .unwrap();
+    assert!(reports.is_empty());
+
+    // Nothing is recorded while billing is suspended, since nothing is reported to Stripe.
+    update_billing_suspension_for_user(app, &user, true)
+        .await
+        .unwrap();
+    let billing_customer = app
+        .db
+        .get_billing_customer_by_user_id(user.id)
+        .await
+        .unwrap()
+        .unwrap();
+    bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        None,
+        &billing_table,
+        vec![(sonnet, &sonnet_price, usage("claude-sonnet-4", 9))],
+        &[],
+        0,
+        None,
+        false,
+    )
+    .await
+    .unwrap();
+    let reports = meter_reports_for_user(
+        app,
+        &user,
+        (start_at, Utc::now() + chrono::Duration::minutes(1)),
+    )
+    .await
+    .unwrap();
+    assert_eq!(reports.len(), 2);
+}
+
+#[gpui::test]
+async fn test_bill_model_request_usage_idempotency(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let billing_table = default_model_request_billing();
+    let sonnet = billing_table
+        .iter()
+        .find(|billing| billing.meter_event_name == "claude_sonnet_4/requests")
+        .unwrap();
+    let price = StripePrice {
+        id: StripePriceId(format!("price_{}", sonnet.price_lookup_key).into()),
+        unit_amount: Some(4),
+        lookup_key: Some(sonnet.price_lookup_key.to_string()),
+        recurring: None,
+    };
+    let usage = |requests| SyncedModelUsage {
+        model: "claude-sonnet-4".into(),
+        mode: CompletionMode::Normal,
+        dimensions: UsageDimensions::default(),
+        requests,
+    };
+    let period_start = 1_750_000_000;
+
+    let start_at = Utc::now() - chrono::Duration::minutes(1);
+    for requests in [7, 7, 9] {
+        bill_model_request_usage(
+            app,
+            &stripe_billing,
+            &billing_customer,
+            &subscription_id,
+            Some(period_start),
+            &billing_table,
+            vec![(sonnet, &price, usage(requests))],
+            &[],
+            0,
+            None,
+            false,
+        )
+        .await
+        .unwrap();
+    }
+    let end_at = Utc::now() + chrono::Duration::minutes(1);
+
+    // A repeated report of the same usage in the period is sent with the same idempotency key and identifier, so
+    // that Stripe only counts it once.
+    let create_meter_event_calls = test_app
+        .stripe_client
+        .create_meter_event_calls
+        .lock()
+        .clone();
+    let expected_key = |requests| {
+        format!(
+            "usage-{}-claude_sonnet_4/requests-{period_start}-{requests}",
+            user.id
+        )
+    };
+    assert_eq!(
+        create_meter_event_calls
+            .iter()
+            .map(|call| (
+                call.identifier.to_string(),
+                call.idempotency_key.clone(),
+                call.timestamp,
+            ))
+            .collect::<Vec<_>>(),
+        [7, 7, 9]
+            .into_iter()
+            .map(|requests| (
+                format!("model_requests/{}", expected_key(requests)),
+                Some(expected_key(requests)),
+                None,
+            ))
+            .collect::<Vec<_>>()
+    );
+
+    // The repeated report is only recorded once.
+    let reports = meter_reports_for_user(app, &user, (start_at, end_at))
+        .await
+        .unwrap();
+    assert_eq!(
+        reports
+            .iter()
+            .map(|report| report.value)
+            .collect::<Vec<_>>(),
+        vec![7, 9]
+    );
+}
+
+#[gpui::test]
+async fn test_bill_model_request_usage_dry_run(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let billing_table = default_model_request_billing();
+    let sonnet = billing_table
+        .iter()
+        .find(|billing| billing.meter_event_name == "claude_sonnet_4/requests")
+        .unwrap();
+    let price = StripePrice {
+        id: StripePriceId(format!("price_{}", sonnet.price_lookup_key).into()),
+        unit_amount: Some(4),
+        lookup_key: Some(sonnet.price_lookup_key.to_string()),
+        recurring: None,
+    };
+
+    let start_at = Utc::now() - chrono::Duration::minutes(1);
+    let billed_model_usage = bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        Some(1_750_000_000),
+        &billing_table,
+        vec![(
+            sonnet,
+            &price,
+            SyncedModelUsage {
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Normal,
+                dimensions: UsageDimensions::default(),
+                requests: 10,
+            },
+        )],
+        &[],
+        0,
+        None,
+        true,
+    )
+    .await
+    .unwrap();
+    let end_at = Utc::now() + chrono::Duration::minutes(1);
+
+    // The usage is reported as it would have been synced, but nothing is sent to Stripe or recorded.
+    assert_eq!(billed_model_usage.synced.len(), 1);
+    assert_eq!(billed_model_usage.synced[0].requests, 10);
+    assert!(
+        test_app
+            .stripe_client
+            .update_subscription_calls
+            .lock()
+            .is_empty()
+    );
+    assert!(
+        test_app
+            .stripe_client
+            .create_meter_event_calls
+            .lock()
+            .is_empty()
+    );
+    assert!(
+        meter_reports_for_user(app, &user, (start_at, end_at))
+            .await
+            .unwrap()
+            .is_empty()
+    );
+}
+
+#[gpui::test]
+async fn test_bill_model_request_usage_spend_limit(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    let billing_table = default_model_request_billing();
+    let sonnet = billing_table
+        .iter()
+        .find(|billing| billing.meter_event_name == "claude_sonnet_4/requests")
+        .unwrap();
+    let price = StripePrice {
+        id: StripePriceId(format!("price_{}", sonnet.price_lookup_key).into()),
+        unit_amount: Some(4),
+        lookup_key: Some(sonnet.price_lookup_key.to_string()),
+        recurring: None,
+    };
+    let usage = |requests| SyncedModelUsage {
+        model: "claude-sonnet-4".into(),
+        mode: CompletionMode::Normal,
+        dimensions: UsageDimensions::default(),
+        requests,
+    };
+
+    // Only the requests that fit within the spend limit are billed.
+    let billed_model_usage = bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        Some(1_750_000_000),
+        &billing_table,
+        vec![(sonnet, &price, usage(10))],
+        &[],
+        0,
+        Some(30),
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        test_app.stripe_client.create_meter_event_calls.lock()[0].value,
+        7
+    );
+    assert_eq!(billed_model_usage.over_spend_limit.len(), 1);
+    assert_eq!(billed_model_usage.over_spend_limit[0].requests, 3);
+
+    let billing_subscription = billing_subscription::Model {
+        stripe_current_period_start: Some(1_750_000_000),
+        ..Default::default()
+    };
+    let row = spend_limit_reached_row(
+        &user,
+        &billing_subscription,
+        Some(30),
+        &billed_model_usage.over_spend_limit,
+    )
+    .unwrap();
+    assert_eq!(row.event_type, "Model Request Spend Limit Reached");
+    assert_eq!(row.event_properties["unbilled_requests"], 3);
+    assert_eq!(row.event_properties["spend_limit_in_cents"], 30);
+
+    // The limit starts over in the next period.
+    let billed_model_usage = bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        Some(1_752_000_000),
+        &billing_table,
+        vec![(sonnet, &price, usage(5))],
+        &[],
+        0,
+        Some(30),
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        test_app.stripe_client.create_meter_event_calls.lock()[1].value,
+        5
+    );
+    assert!(billed_model_usage.over_spend_limit.is_empty());
+    assert!(
+        spend_limit_reached_row(
+            &user,
+            &billing_subscription,
+            Some(30),
+            &billed_model_usage.over_spend_limit,
+        )
+        .is_none()
+    );
+}
+
+#[gpui::test]
+async fn test_overage_spend_limit_shared_with_edit_predictions(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+    let stripe_billing = app.stripe_billing.clone().unwrap();
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let subscription_id = test_app.create_stripe_subscription(
+        "sub_1",
+        &customer_id,
+        "price_zed_pro",
+        SubscriptionStatus::Active,
+    );
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+    let billing_subscription = billing_subscription::Model {
+        kind: Some(SubscriptionKind::ZedPro),
+        stripe_subscription_id: subscription_id.to_string(),
+        stripe_current_period_start: Some(1_750_000_000),
+        ..Default::default()
+    };
+
+    let billing_table = default_model_request_billing();
+    let sonnet = billing_table
+        .iter()
+        .find(|billing| billing.meter_event_name == "claude_sonnet_4/requests")
+        .unwrap();
+    let model_request_price = StripePrice {
+        id: StripePriceId(format!("price_{}", sonnet.price_lookup_key).into()),
+        unit_amount: Some(4),
+        lookup_key: Some(sonnet.price_lookup_key.to_string()),
+        recurring: None,
+    };
+    let edit_prediction_price = StripePrice {
+        id: StripePriceId("price_edit_predictions_overage".into()),
+        unit_amount: Some(3),
+        lookup_key: Some(EDIT_PREDICTION_OVERAGE_PRICE_LOOKUP_KEY.to_string()),
+        recurring: None,
+    };
+    let spend_limit_in_cents = 30;
+
+    // The model requests cost 20 cents, which leaves 10 cents of the spend limit.
+    let billed_model_usage = bill_model_request_usage(
+        app,
+        &stripe_billing,
+        &billing_customer,
+        &subscription_id,
+        Some(1_750_000_000),
+        &billing_table,
+        vec![(
+            sonnet,
+            &model_request_price,
+            SyncedModelUsage {
+                model: "claude-sonnet-4".into(),
+                mode: CompletionMode::Normal,
+                dimensions: UsageDimensions::default(),
+                requests: 5,
+            },
+        )],
+        &[],
+        0,
+        Some(spend_limit_in_cents),
+        false,
+    )
+    .await
+    .unwrap();
+    assert!(billed_model_usage.over_spend_limit.is_empty());
+    assert_eq!(billed_model_usage.billed_in_cents, 20);
+
+    // Only as many edit predictions as fit in what's left of the limit are billed.
+    let over_spend_limit = bill_edit_prediction_overage(
+        &app.executor,
+        &stripe_billing,
+        user.id,
+        &billing_subscription,
+        &customer_id,
+        &edit_prediction_price,
+        1_750_000_000,
+        8,
+        Some(spend_limit_in_cents - billed_model_usage.billed_in_cents),
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(over_spend_limit, 5);
+
+    // Once the model requests have used up the limit, no edit predictions are billed.
+    let over_spend_limit = bill_edit_prediction_overage(
+        &app.executor,
+        &stripe_billing,
+        user.id,
+        &billing_subscription,
+        &customer_id,
+        &edit_prediction_price,
+        1_750_000_000,
+        8,
+        Some(spend_limit_in_cents - 32),
+        false,
+    )
+    .await
+    .unwrap();
+    assert_eq!(over_spend_limit, 8);
+
+    assert_eq!(
+        test_app
+            .stripe_client
+            .create_meter_event_calls
+            .lock()
+            .iter()
+            .map(|call| (call.event_name.to_string(), call.value))
+            .collect::<Vec<_>>(),
+        vec![
+            ("claude_sonnet_4/requests".to_string(), 5),
+            (EDIT_PREDICTION_OVERAGE_METER_EVENT_NAME.to_string(), 3),
+            (EDIT_PREDICTION_OVERAGE_METER_EVENT_NAME.to_string(), 0),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_period() {
+    assert_eq!(
+        parse_period("2025-07-01T00:00:00Z/2025-08-01T00:00:00+02:00").unwrap(),
+        (
+            "2025-07-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap(),
+            "2025-07-31T22:00:00Z".parse::<DateTime<Utc>>().unwrap(),
+        )
+    );
+    assert!(parse_period("2025-07-01T00:00:00Z").is_err());
+    assert!(parse_period("2025-07-01/2025-08-01").is_err());
+    assert!(parse_period("2025-08-01T00:00:00Z/2025-07-01T00:00:00Z").is_err());
+}
+
+#[test]
+fn test_current_usage_response_when_usage_is_unavailable() {
+    let limits = || UsageLimits {
+        model_requests: Some(500),
+        model_request_allotments: vec![ModelRequestAllotment {
+            model: None,
+            limit: Some(500),
+        }],
+        model_request_limits: Vec::new(),
+        edit_predictions: None,
+    };
+
+    // A failed LLM database query still returns the plan and its limits.
+    let response = current_usage_response(
+        zed_llm_client::Plan::ZedPro,
+        Locale::default(),
+        limits(),
+        false,
+        Err(anyhow::anyhow!("connection closed").into()),
+    );
+    assert_eq!(response.plan, Some(SubscriptionKind::ZedPro));
+    assert_eq!(response.plan_display.as_deref(), Some("Zed Pro"));
+    assert_eq!(
+        serde_json::to_value(&response).unwrap()["plan"],
+        json!("zed_pro")
+    );
+    assert_eq!(response.limits, Some(limits()));
+    assert!(response.current_usage.is_none());
+    assert!(!response.usage_available);
+    assert_eq!(
+        serde_json::to_value(&response).unwrap()["current_usage"],
+        serde_json::Value::Null
+    );
+
+    let response = current_usage_response(
+        zed_llm_client::Plan::ZedPro,
+        Locale::default(),
+        limits(),
+        false,
+        Ok(CurrentUsage {
+            model_requests: UsageCounts::new(10, Some(500), Utc::now()),
+            model_request_allotments: Vec::new(),
+            model_request_usage: Vec::new(),
+            model_request_weights: model_request_weights(&default_model_request_billing()),
+            overage_begins_in_requests: None,
+            edit_predictions: UsageCounts::new(0, None, Utc::now()),
+            edit_prediction_overage: None,
+        }),
+    );
+    assert!(response.usage_available);
+    assert_eq!(
+        response
+            .current_usage
+            .map(|current_usage| current_usage.model_requests.used),
+        Some(10)
+    );
+
+    // Users without a subscription have no usage to load.
+    assert!(GetCurrentUsageResponse::default().usage_available);
+}
+
+#[test]
+fn test_usage_sync_circuit_breaker() {
+    let now = Utc::now();
+    let user_1 = UserId(1);
+    let user_2 = UserId(2);
+    let mut circuit_breaker = UsageSyncCircuitBreaker::default();
+    assert!(!circuit_breaker.is_open(user_1, now));
+
+    // A user is only skipped once their usage fails to sync enough times in a row.
+    for _ in 1..USAGE_SYNC_MAX_CONSECUTIVE_FAILURES {
+        assert!(!circuit_breaker.record(user_1, false, now));
+        assert!(!circuit_breaker.is_open(user_1, now));
+    }
+    assert!(!circuit_breaker.record(user_2, false, now));
+    assert!(circuit_breaker.record(user_1, false, now));
+    assert!(circuit_breaker.is_open(user_1, now));
+    assert!(!circuit_breaker.is_open(user_2, now));
+    assert_eq!(circuit_breaker.open_count(now), 1);
+
+    // Once the cooldown is over, the user is synced again, and skipped right away if that fails too.
+    let after_cooldown = now + USAGE_SYNC_FAILURE_COOLDOWN;
+    assert!(!circuit_breaker.is_open(user_1, after_cooldown));
+    assert_eq!(circuit_breaker.open_count(after_cooldown), 0);
+    assert!(circuit_breaker.record(user_1, false, after_cooldown));
+    assert!(circuit_breaker.is_open(user_1, after_cooldown));
+
+    // A successful sync resets the user's failures.
+    let after_cooldown = after_cooldown + USAGE_SYNC_FAILURE_COOLDOWN;
+    assert!(!circuit_breaker.record(user_1, true, after_cooldown));
+    assert!(!circuit_breaker.is_open(user_1, after_cooldown));
+    assert!(!circuit_breaker.record(user_1, false, after_cooldown));
+    assert!(!circuit_breaker.is_open(user_1, after_cooldown));
+}
+
+#[gpui::test]
+async fn test_access_status(cx: &mut TestAppContext) {
+    let test_app = make_test_app_with_config(
+        cx,
+        Config {
+            trial_end_behavior: Some(TrialEndBehavior::BlockAccess),
+            ..Config::test()
+        },
+    )
+    .await;
+    let app = &test_app.app;
+
+    let reason = async |user: &User| {
+        let user = app.db.get_user_by_id(user.id).await.unwrap().unwrap();
+        let status = access_status_for_user(app, &user).await.unwrap();
+        assert_eq!(status.allowed, status.reason.is_none());
+        assert_eq!(
+            status.details.as_deref(),
+            status.reason.map(|reason| reason.message())
+        );
+        status.reason
+    };
+
+    let user = test_app.create_user("user1", 1).await;
+    assert_eq!(
+        reason(&user).await,
+        Some(AccessBlockedReason::TermsOfServiceNotAccepted)
+    );
+
+    app.db
+        .set_user_accepted_tos_at(user.id, Some(Utc::now().naive_utc()))
+        .await
+        .unwrap();
+    assert_eq!(
+        reason(&user).await,
+        Some(AccessBlockedReason::AccountTooYoung)
+    );
+
+    let flag = app
+        .db
+        .create_user_flag(BYPASS_ACCOUNT_AGE_CHECK_FEATURE_FLAG, false)
+        .await
+        .unwrap();
+    app.db.add_user_flag(user.id, flag).await.unwrap();
+    assert_eq!(reason(&user).await, None);
+
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: "cus_1".into(),
+        })
+        .await
+        .unwrap();
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                trial_started_at: ActiveValue::set(Some(Utc::now().naive_utc())),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(reason(&user).await, Some(AccessBlockedReason::TrialEnded));
+
+    app.db
+        .create_billing_subscription(&CreateBillingSubscriptionParams {
+            billing_customer_id: billing_customer.id,
+            kind: Some(SubscriptionKind::ZedPro),
+            unclassified: false,
+            stripe_subscription_id: "sub_1".into(),
+            stripe_subscription_status: StripeSubscriptionStatus::Active,
+            stripe_cancellation_reason: None,
+            stripe_current_period_start: None,
+            stripe_current_period_end: None,
+            seats: 1,
+            monthly_recurring_revenue_in_cents: None,
+            ended_at: None,
+        })
+        .await
+        .unwrap();
+    assert_eq!(reason(&user).await, None);
+
+    app.db
+        .update_billing_customer(
+            billing_customer.id,
+            &UpdateBillingCustomerParams {
+                has_overdue_invoices: ActiveValue::set(true),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        reason(&user).await,
+        Some(AccessBlockedReason::OverdueInvoices)
+    );
+
+    // Staff aren't blocked once their trial ends, or for their account's age, like when the token issuer checks them.
+    let staff_user_id = app
+        .db
+        .create_user(
+            "staff@example.com",
+            None,
+            true,
+            NewUserParams {
+                github_login: "staff".into(),
+                github_user_id: 2,
+            },
+        )
+        .await
+        .unwrap()
+        .user_id;
+    let staff_user = app.db.get_user_by_id(staff_user_id).await.unwrap().unwrap();
+    assert!(staff_user.is_staff());
+    assert!(
+        app.db
+            .get_staff_users()
+            .await
+            .unwrap()
+            .iter()
+            .any(|user| user.id == staff_user.id)
+    );
+    app.db
+        .set_user_accepted_tos_at(staff_user.id, Some(Utc::now().naive_utc()))
+        .await
+        .unwrap();
+    let staff_billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: staff_user.id,
+            stripe_customer_id: "cus_staff".into(),
+        })
+        .await
+        .unwrap();
+    app.db
+        .update_billing_customer(
+            staff_billing_customer.id,
+            &UpdateBillingCustomerParams {
+                trial_started_at: ActiveValue::set(Some(Utc::now().naive_utc())),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(reason(&staff_user).await, None);
+
+    // Only the reasons that the token issuer enforces itself deny a token.
+    assert!(AccessBlockedReason::TermsOfServiceNotAccepted.denies_llm_token());
+    assert!(AccessBlockedReason::TrialEnded.denies_llm_token());
+    assert!(!AccessBlockedReason::OverdueInvoices.denies_llm_token());
+    assert!(!AccessBlockedReason::AccountTooYoung.denies_llm_token());
+}
+
+#[test]
+fn test_usage_history_period() {
+    let to_primitive_date_time = |timestamp: &str| {
+        let timestamp = timestamp.parse::<DateTime<Utc>>().unwrap().timestamp();
+        let date_time = time::OffsetDateTime::from_unix_timestamp(timestamp).unwrap();
+        time::PrimitiveDateTime::new(date_time.date(), date_time.time())
+    };
+    let usage = |plan: SubscriptionKind, model_requests: i32, edit_predictions: i32| {
+        subscription_usage::Model {
+            id: Default::default(),
+            user_id: UserId(1),
+            period_start_at: to_primitive_date_time("2025-05-15T00:00:00Z"),
+            period_end_at: to_primitive_date_time("2025-06-15T00:00:00Z"),
+            plan,
+            model_requests,
+            edit_predictions,
+        }
+    };
+    let limit = |limit: zed_llm_client::UsageLimit| match limit {
+        zed_llm_client::UsageLimit::Limited(limit) => Some(limit),
+        zed_llm_client::UsageLimit::Unlimited => None,
+    };
+
+    // Usage is measured against the limits of the plan the user was on during the period.
+    let period = usage_history_period(
+        &Config::test(),
+        &usage(SubscriptionKind::ZedPro, 600, 20),
+        false,
+    )
+    .unwrap();
+    assert_eq!(period.period.start_at, "2025-05-15T00:00:00.000Z");
+    assert_eq!(period.period.end_at, "2025-06-15T00:00:00.000Z");
+    assert_eq!(period.plan, "zed_pro");
+    assert_eq!(period.model_requests.used, 600);
+    assert_eq!(
+        period.model_requests.limit,
+        limit(zed_llm_client::Plan::ZedPro.model_requests_limit())
+    );
+    assert_eq!(period.model_requests.resets_at, "2025-06-15T00:00:00.000Z");
+    assert_eq!(period.edit_predictions.used, 20);
+
+    let period = usage_history_period(
+        &Config::test(),
+        &usage(SubscriptionKind::ZedFree, 10, 20),
+        false,
+    )
+    .unwrap();
+    assert_eq!(period.plan, "zed_free");
+    assert_eq!(
+        period.model_requests.limit,
+        limit(zed_llm_client::Plan::ZedFree.model_requests_limit())
+    );
+    assert_eq!(
+        period.edit_predictions.limit,
+        limit(zed_llm_client::Plan::ZedFree.edit_predictions_limit())
+    );
+
+    // The extended trial applies to trial periods.
+    let period = usage_history_period(
+        &Config::test(),
+        &usage(SubscriptionKind::ZedProTrial, 10, 20),
+        true,
+    )
+    .unwrap();
+    assert_eq!(period.model_requests.limit, Some(1_000));
+}
+
+#[test]
+fn test_usage_history_after_upgrade() {
+    let to_primitive_date_time = |timestamp: &str| {
+        let timestamp = timestamp.parse::<DateTime<Utc>>().unwrap().timestamp();
+        let date_time = time::OffsetDateTime::from_unix_timestamp(timestamp).unwrap();
+        time::PrimitiveDateTime::new(date_time.date(), date_time.time())
+    };
+    let usage = |plan: SubscriptionKind, period_start_at: &str, period_end_at: &str| {
+        subscription_usage::Model {
+            id: Default::default(),
+            user_id: UserId(1),
+            period_start_at: to_primitive_date_time(period_start_at),
+            period_end_at: to_primitive_date_time(period_end_at),
+            plan,
+            model_requests: 40,
+            edit_predictions: 100,
+        }
+    };
+
+    // The user was on Zed Free for two periods, trialed Zed Pro, and then upgraded to Zed Pro.
+    let config = Config {
+        model_request_allotments: Some(vec!["zed_pro:claude-opus-4=100".into()]),
+        ..Config::test()
+    };
+    let usages = [
+        usage(
+            SubscriptionKind::ZedPro,
+            "2025-07-01T00:00:00Z",
+            "2025-08-01T00:00:00Z",
+        ),
+        usage(
+            SubscriptionKind::ZedProTrial,
+            "2025-06-15T00:00:00Z",
+            "2025-07-01T00:00:00Z",
+        ),
+        usage(
+            SubscriptionKind::ZedFree,
+            "2025-05-15T00:00:00Z",
+            "2025-06-15T00:00:00Z",
+        ),
+        usage(
+            SubscriptionKind::ZedFree,
+            "2025-04-15T00:00:00Z",
+            "2025-05-15T00:00:00Z",
+        ),
+    ];
+    let periods = usages
+        .iter()
+        .map(|usage| usage_history_period(&config, usage, false).unwrap())
+        .collect::<Vec<_>>();
+
+    // Each period reports the plan and limits that were in effect at the time, not the user's current ones.
+    assert_eq!(
+        periods
+            .iter()
+            .map(|period| (period.period.start_at.as_str(), period.plan.as_str()))
+            .collect::<Vec<_>>(),
+        vec![
+            ("2025-07-01T00:00:00.000Z", "zed_pro"),
+            ("2025-06-15T00:00:00.000Z", "zed_pro_trial"),
+            ("2025-05-15T00:00:00.000Z", "zed_free"),
+            ("2025-04-15T00:00:00.000Z", "zed_free"),
+        ]
+    );
+    for (period, plan) in periods.iter().zip([
+        zed_llm_client::Plan::ZedPro,
+        zed_llm_client::Plan::ZedProTrial,
+        zed_llm_client::Plan::ZedFree,
+        zed_llm_client::Plan::ZedFree,
+    ]) {
+        let limits = usage_limits(&config, plan, false);
+        assert_eq!(period.model_requests.limit, limits.model_requests);
+        assert_eq!(period.edit_predictions.limit, limits.edit_predictions);
+        assert_eq!(period.limits, limits);
+    }
+
+    // Only the Zed Pro period includes the Zed Pro allotments.
+    assert_eq!(
+        periods[0].limits.model_request_allotments,
+        usage_limits(&config, zed_llm_client::Plan::ZedPro, false).model_request_allotments
+    );
+    assert_ne!(
+        periods[2].limits.model_request_allotments,
+        periods[0].limits.model_request_allotments
+    );
+}
+
+#[gpui::test]
+async fn test_credits_remaining_in_cents(cx: &mut TestAppContext) {
+    let test_app = make_test_app(cx).await;
+    let app = &test_app.app;
+
+    let user = test_app.create_user("user1", 1).await;
+    let customer_id = test_app.create_stripe_customer("cus_1", &user);
+    let billing_customer = app
+        .db
+        .create_billing_customer(&CreateBillingCustomerParams {
+            user_id: user.id,
+            stripe_customer_id: customer_id.to_string(),
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(
+        credits_remaining_in_cents(test_app.stripe_client.as_ref(), &billing_customer).await,
+        Some(0)
+    );
+
+    // Credit is a negative balance.
+    test_app
+        .stripe_client
+        .create_customer_balance_transaction(
+            &customer_id,
+            StripeCreateCustomerBalanceTransactionParams {
+                amount: -500,
+                currency: DEFAULT_BILLING_CURRENCY,
+                description: None,
+                idempotency_key: None,
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        credits_remaining_in_cents(test_app.stripe_client.as_ref(), &billing_customer).await,
+        Some(500)
+    );
+
+    // Customers who owe us something don't have any credit left.
+    test_app
+        .stripe_client
+        .customer_balances
+        .lock()
+        .insert(customer_id.clone(), 1_200);
+    assert_eq!(
+        credits_remaining_in_cents(test_app.stripe_client.as_ref(), &billing_customer).await,
+        Some(0)
+    );
+
+    // We don't report any credit when we can't load the balance.
+    let unknown_customer = billing_customer::Model {
+        stripe_customer_id: "cus_unknown".into(),
+        ..billing_customer
+    };
+    assert_eq!(
+        credits_remaining_in_cents(test_app.stripe_client.as_ref(), &unknown_customer).await,
+        None
+    );
+}
@@ -3,14 +3,18 @@ use std::sync::Arc;
 use chrono::{Duration, Utc};
 use pretty_assertions::assert_eq;
 
+use crate::db::UserId;
+use crate::db::billing_subscription::SubscriptionKind;
 use crate::llm::AGENT_EXTENDED_TRIAL_FEATURE_FLAG;
-use crate::stripe_billing::StripeBilling;
+use crate::stripe_billing::{StripeBilling, ZED_USER_ID_METADATA_KEY, ZedProBillingInterval};
 use crate::stripe_client::{
     FakeStripeClient, StripeBillingAddressCollection, StripeCheckoutSessionMode,
-    StripeCheckoutSessionPaymentMethodCollection, StripeCreateCheckoutSessionLineItems,
-    StripeCreateCheckoutSessionSubscriptionData, StripeCustomerId, StripeCustomerUpdate,
-    StripeCustomerUpdateAddress, StripeCustomerUpdateName, StripeMeter, StripeMeterId, StripePrice,
-    StripePriceId, StripePriceRecurring, StripeSubscription, StripeSubscriptionId,
+    StripeCheckoutSessionPaymentMethodCollection, StripeCreateCheckoutSessionDiscounts,
+    StripeCreateCheckoutSessionLineItems, StripeCreateCheckoutSessionSubscriptionData,
+    StripeCustomerId, StripeCustomerUpdate, StripeCustomerUpdateAddress, StripeCustomerUpdateName,
+    StripeInvoiceLine, StripeInvoicePayment, StripeMeter, StripeMeterId, StripePaymentIntentId,
+    StripePrice, StripePriceId, StripePriceRecurring, StripePriceRecurringInterval,
+    StripePromotionCode, StripePromotionCodeId, StripeSubscription, StripeSubscriptionId,
     StripeSubscriptionItem, StripeSubscriptionItemId, StripeSubscriptionTrialSettings,
     StripeSubscriptionTrialSettingsEndBehavior,
     StripeSubscriptionTrialSettingsEndBehaviorMissingPaymentMethod, UpdateSubscriptionItems,
@@ -63,6 +67,8 @@ async fn test_initialize() {
         unit_amount: Some(500),
         lookup_key: None,
         recurring: Some(StripePriceRecurring {
+            interval: StripePriceRecurringInterval::Month,
+            interval_count: 1,
             meter: Some("meter_1".to_string()),
         }),
     };
@@ -104,6 +110,76 @@ async fn test_initialize() {
     assert!(result.is_err());
 }
 
+#[gpui::test]
+async fn test_price_cache() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    let zed_pro_price = StripePrice {
+        id: StripePriceId("price_1".into()),
+        unit_amount: Some(2_000),
+        lookup_key: Some("zed-pro".to_string()),
+        recurring: None,
+    };
+    stripe_client
+        .prices
+        .lock()
+        .insert(zed_pro_price.id.clone(), zed_pro_price.clone());
+    stripe_billing.initialize().await.unwrap();
+
+    // Changes to the prices in Stripe aren't picked up while the cached prices are fresh.
+    let annual_price = StripePrice {
+        id: StripePriceId("price_2".into()),
+        unit_amount: Some(19_200),
+        lookup_key: Some("zed-pro-annual".to_string()),
+        recurring: None,
+    };
+    stripe_client
+        .prices
+        .lock()
+        .insert(annual_price.id.clone(), annual_price);
+    stripe_client
+        .prices
+        .lock()
+        .get_mut(&zed_pro_price.id)
+        .unwrap()
+        .unit_amount = Some(2_500);
+    assert!(stripe_billing.zed_pro_annual_price_id().await.is_err());
+    assert_eq!(
+        stripe_billing
+            .find_price_by_lookup_key("zed-pro")
+            .await
+            .unwrap()
+            .unit_amount,
+        Some(2_000)
+    );
+
+    // Clearing the cache loads the prices again on the next lookup.
+    stripe_billing.clear_price_cache().await;
+    assert_eq!(
+        stripe_billing.zed_pro_annual_price_id().await.unwrap(),
+        StripePriceId("price_2".into())
+    );
+    assert_eq!(
+        stripe_billing
+            .find_price_by_lookup_key("zed-pro")
+            .await
+            .unwrap()
+            .unit_amount,
+        Some(2_500)
+    );
+
+    // Prices that were removed from Stripe are dropped from the cache.
+    stripe_client.prices.lock().remove(&zed_pro_price.id);
+    stripe_billing.clear_price_cache().await;
+    assert!(stripe_billing.zed_pro_price_id().await.is_err());
+    assert!(
+        stripe_billing
+            .find_price_by_id(&zed_pro_price.id)
+            .await
+            .is_err()
+    );
+}
+
 #[gpui::test]
 async fn test_find_or_create_customer_by_email() {
     let (stripe_billing, stripe_client) = make_stripe_billing();
@@ -151,6 +227,114 @@ async fn test_find_or_create_customer_by_email() {
     }
 }
 
+#[gpui::test]
+async fn test_find_or_create_customer_by_zed_user() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    // Create a customer for a user that doesn't yet have one.
+    {
+        let user_id = UserId(1);
+        let email = "user@example.com";
+
+        let customer_id = stripe_billing
+            .find_or_create_customer_by_zed_user(user_id, Some(email))
+            .await
+            .unwrap();
+
+        let customer = stripe_client
+            .customers
+            .lock()
+            .get(&customer_id)
+            .unwrap()
+            .clone();
+        assert_eq!(customer.email.as_deref(), Some(email));
+        assert_eq!(
+            customer
+                .metadata
+                .get(ZED_USER_ID_METADATA_KEY)
+                .map(String::as_str),
+            Some("1")
+        );
+    }
+
+    // Find the existing customer for a user, even if their email address has changed.
+    {
+        let user_id = UserId(2);
+
+        let existing_customer_id = stripe_billing
+            .find_or_create_customer_by_zed_user(user_id, Some("user2@example.com"))
+            .await
+            .unwrap();
+
+        let customer_id = stripe_billing
+            .find_or_create_customer_by_zed_user(user_id, Some("user2-new@example.com"))
+            .await
+            .unwrap();
+        assert_eq!(customer_id, existing_customer_id);
+    }
+
+    // A customer with the same email address but no Zed user ID is not reused.
+    {
+        let email = "user3@example.com";
+
+        let email_customer_id = stripe_billing
+            .find_or_create_customer_by_email(Some(email))
+            .await
+            .unwrap();
+
+        let customer_id = stripe_billing
+            .find_or_create_customer_by_zed_user(UserId(3), Some(email))
+            .await
+            .unwrap();
+        assert_ne!(customer_id, email_customer_id);
+    }
+
+    // A customer is created for a user whose email address changed before Stripe's search index caught up with the
+    // customer created for their old one.
+    {
+        let user_id = UserId(4);
+
+        let old_customer_id = stripe_billing
+            .find_or_create_customer_by_zed_user(user_id, Some("user4@example.com"))
+            .await
+            .unwrap();
+        stripe_client
+            .customers
+            .lock()
+            .get_mut(&old_customer_id)
+            .unwrap()
+            .metadata
+            .clear();
+
+        let customer_id = stripe_billing
+            .find_or_create_customer_by_zed_user(user_id, Some("user4-new@example.com"))
+            .await
+            .unwrap();
+        assert_ne!(customer_id, old_customer_id);
+        assert_eq!(
+            stripe_client.customers.lock()[&customer_id]
+                .email
+                .as_deref(),
+            Some("user4-new@example.com")
+        );
+    }
+}
+
+#[gpui::test]
+async fn test_find_or_create_customer_by_zed_user_concurrently() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    let user_id = UserId(1);
+    let email = Some("user@example.com");
+
+    let (customer_id_a, customer_id_b) = futures::join!(
+        stripe_billing.find_or_create_customer_by_zed_user(user_id, email),
+        stripe_billing.find_or_create_customer_by_zed_user(user_id, email),
+    );
+    assert_eq!(customer_id_a.unwrap(), customer_id_b.unwrap());
+    assert_eq!(stripe_client.customers.lock().len(), 1);
+}
+
 #[gpui::test]
 async fn test_subscribe_to_price() {
     let (stripe_billing, stripe_client) = make_stripe_billing();
@@ -175,7 +359,11 @@ async fn test_subscribe_to_price() {
         current_period_end: (now + Duration::days(30)).timestamp(),
         items: vec![],
         cancel_at: None,
+        cancel_at_period_end: false,
         cancellation_details: None,
+        discount: None,
+        currency: "usd".into(),
+        pause_collection: None,
     };
     stripe_client
         .subscriptions
@@ -198,9 +386,14 @@ async fn test_subscribe_to_price() {
     assert_eq!(
         update_subscription_calls[0].1.items,
         Some(vec![UpdateSubscriptionItems {
+            id: None,
             price: Some(price.id.clone())
         }])
     );
+    assert_eq!(
+        update_subscription_calls[0].1.idempotency_key.as_deref(),
+        Some("subscribe-sub_test-to-price_test-with-0-items")
+    );
 
     // Subscribing to a price that is already on the subscription is a no-op.
     {
@@ -214,9 +407,14 @@ async fn test_subscribe_to_price() {
             items: vec![StripeSubscriptionItem {
                 id: StripeSubscriptionItemId("si_test".into()),
                 price: Some(price.clone()),
+                quantity: Some(1),
             }],
             cancel_at: None,
+            cancel_at_period_end: false,
             cancellation_details: None,
+            discount: None,
+            currency: "usd".into(),
+            pause_collection: None,
         };
         stripe_client
             .subscriptions
@@ -285,9 +483,14 @@ async fn test_subscribe_to_zed_free() {
             items: vec![StripeSubscriptionItem {
                 id: StripeSubscriptionItemId("si_test".into()),
                 price: Some(zed_pro_price.clone()),
+                quantity: Some(1),
             }],
             cancel_at: None,
+            cancel_at_period_end: false,
             cancellation_details: None,
+            discount: None,
+            currency: "usd".into(),
+            pause_collection: None,
         };
         stripe_client.subscriptions.lock().insert(
             existing_subscription.id.clone(),
@@ -316,9 +519,14 @@ async fn test_subscribe_to_zed_free() {
             items: vec![StripeSubscriptionItem {
                 id: StripeSubscriptionItemId("si_test".into()),
                 price: Some(zed_pro_price.clone()),
+                quantity: Some(1),
             }],
             cancel_at: None,
+            cancel_at_period_end: false,
             cancellation_details: None,
+            discount: None,
+            currency: "usd".into(),
+            pause_collection: None,
         };
         stripe_client.subscriptions.lock().insert(
             existing_subscription.id.clone(),
@@ -340,8 +548,8 @@ async fn test_bill_model_request_usage() {
 
     let customer_id = StripeCustomerId("cus_test".into());
 
-    stripe_billing
-        .bill_model_request_usage(&customer_id, "some_model/requests", 73)
+    let report = stripe_billing
+        .bill_model_request_usage(&customer_id, "some_model/requests", 73, None)
         .await
         .unwrap();
 
@@ -357,12 +565,298 @@ async fn test_bill_model_request_usage() {
             .identifier
             .starts_with("model_requests/")
     );
+    assert_eq!(
+        create_meter_event_calls[0].identifier.as_ref(),
+        report.identifier
+    );
+    assert_eq!(
+        create_meter_event_calls[0].timestamp,
+        Some(report.timestamp)
+    );
     assert_eq!(create_meter_event_calls[0].stripe_customer_id, customer_id);
     assert_eq!(
         create_meter_event_calls[0].event_name.as_ref(),
         "some_model/requests"
     );
     assert_eq!(create_meter_event_calls[0].value, 73);
+    assert_eq!(create_meter_event_calls[0].idempotency_key, None);
+
+    // With an idempotency key, the identifier is derived from the key and Stripe timestamps the event, so that a
+    // retried request has the same parameters.
+    stripe_client.create_meter_event_calls.lock().clear();
+    for _ in 0..2 {
+        let report = stripe_billing
+            .bill_model_request_usage(
+                &customer_id,
+                "some_model/requests",
+                73,
+                Some("usage-1-some_model/requests-1750000000-73"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            report.identifier,
+            "model_requests/usage-1-some_model/requests-1750000000-73"
+        );
+    }
+
+    let create_meter_event_calls = stripe_client
+        .create_meter_event_calls
+        .lock()
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(create_meter_event_calls.len(), 2);
+    for call in create_meter_event_calls {
+        assert_eq!(
+            call.identifier.as_ref(),
+            "model_requests/usage-1-some_model/requests-1750000000-73"
+        );
+        assert_eq!(
+            call.idempotency_key.as_deref(),
+            Some("usage-1-some_model/requests-1750000000-73")
+        );
+        assert_eq!(call.timestamp, None);
+    }
+}
+
+#[gpui::test]
+async fn test_bill_negative_model_request_usage() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    let customer_id = StripeCustomerId("cus_test".into());
+
+    let result = stripe_billing
+        .bill_model_request_usage(&customer_id, "some_model/requests", -5, None)
+        .await;
+    assert!(result.is_err());
+    assert!(stripe_client.create_meter_event_calls.lock().is_empty());
+}
+
+#[gpui::test]
+async fn test_bill_edit_prediction_usage() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    let customer_id = StripeCustomerId("cus_test".into());
+
+    stripe_billing
+        .bill_edit_prediction_usage(&customer_id, "edit_predictions/overage", 42, None)
+        .await
+        .unwrap();
+
+    let create_meter_event_calls = stripe_client
+        .create_meter_event_calls
+        .lock()
+        .iter()
+        .cloned()
+        .collect::<Vec<_>>();
+    assert_eq!(create_meter_event_calls.len(), 1);
+    assert!(
+        create_meter_event_calls[0]
+            .identifier
+            .starts_with("edit_predictions/")
+    );
+    assert_eq!(create_meter_event_calls[0].stripe_customer_id, customer_id);
+    assert_eq!(
+        create_meter_event_calls[0].event_name.as_ref(),
+        "edit_predictions/overage"
+    );
+    assert_eq!(create_meter_event_calls[0].value, 42);
+}
+
+#[gpui::test]
+async fn test_credit_model_request_usage() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    let customer_id = StripeCustomerId("cus_test".into());
+
+    // It returns an error when the price doesn't exist.
+    {
+        let result = stripe_billing
+            .credit_model_request_usage(
+                &customer_id,
+                "some-model-requests",
+                10,
+                "usd",
+                "disputed",
+                None,
+            )
+            .await;
+
+        assert!(result.is_err());
+        assert!(
+            stripe_client
+                .create_customer_balance_transaction_calls
+                .lock()
+                .is_empty()
+        );
+    }
+
+    let price = StripePrice {
+        id: StripePriceId("price_1".into()),
+        unit_amount: Some(4),
+        lookup_key: Some("some-model-requests".to_string()),
+        recurring: None,
+    };
+    stripe_client.prices.lock().insert(price.id.clone(), price);
+    stripe_billing.initialize().await.unwrap();
+
+    // It credits the customer for the requests at the price's unit amount, in the given currency.
+    {
+        let transaction = stripe_billing
+            .credit_model_request_usage(
+                &customer_id,
+                "some-model-requests",
+                25,
+                "eur",
+                "disputed",
+                Some("credit-1"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(transaction.amount, -100);
+
+        let calls = stripe_client
+            .create_customer_balance_transaction_calls
+            .lock()
+            .drain(..)
+            .collect::<Vec<_>>();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].customer_id, customer_id);
+        assert_eq!(calls[0].amount, -100);
+        assert_eq!(calls[0].currency, "eur");
+        assert_eq!(calls[0].description.as_deref(), Some("disputed"));
+        assert_eq!(calls[0].idempotency_key.as_deref(), Some("credit-1"));
+    }
+}
+
+#[gpui::test]
+async fn test_refund_unused_time() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    let zed_pro_price = StripePrice {
+        id: StripePriceId("price_zed_pro".into()),
+        unit_amount: Some(20_000),
+        lookup_key: Some("zed-pro".to_string()),
+        recurring: None,
+    };
+    stripe_client
+        .prices
+        .lock()
+        .insert(zed_pro_price.id.clone(), zed_pro_price.clone());
+    stripe_billing.initialize().await.unwrap();
+
+    let now = Utc::now();
+    let subscription = StripeSubscription {
+        id: StripeSubscriptionId("sub_test".into()),
+        customer: StripeCustomerId("cus_test".into()),
+        status: stripe::SubscriptionStatus::Active,
+        current_period_start: (now - Duration::days(100)).timestamp(),
+        current_period_end: (now + Duration::days(300)).timestamp(),
+        items: vec![],
+        cancel_at: None,
+        cancel_at_period_end: false,
+        cancellation_details: None,
+        discount: None,
+        currency: "usd".into(),
+        pause_collection: None,
+    };
+
+    // Nothing is refunded when the latest invoice wasn't paid.
+    assert_eq!(
+        stripe_billing
+            .refund_unused_time(&subscription, now)
+            .await
+            .unwrap(),
+        0
+    );
+    assert!(stripe_client.create_refund_calls.lock().is_empty());
+
+    // The invoice bills Zed Pro for the current period, along with the previous period's overage.
+    let payment_intent_id = StripePaymentIntentId("pi_test".into());
+    let payment = StripeInvoicePayment {
+        payment_intent_id: payment_intent_id.clone(),
+        amount_paid: 25_000,
+        amount_refunded: 0,
+        lines: vec![
+            StripeInvoiceLine {
+                price_id: Some(zed_pro_price.id.clone()),
+                amount: 20_000,
+                period_start: subscription.current_period_start,
+                period_end: subscription.current_period_end,
+            },
+            StripeInvoiceLine {
+                price_id: Some(StripePriceId("price_overage".into())),
+                amount: 5_000,
+                period_start: (now - Duration::days(130)).timestamp(),
+                period_end: subscription.current_period_start,
+            },
+        ],
+    };
+    stripe_client
+        .latest_invoice_payments
+        .lock()
+        .insert(subscription.id.clone(), payment.clone());
+
+    // Only the Zed Pro line is refunded, in proportion to the time left in the period.
+    assert_eq!(
+        stripe_billing
+            .refund_unused_time(&subscription, now)
+            .await
+            .unwrap(),
+        15_000
+    );
+    let calls = stripe_client.create_refund_calls.lock().clone();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].payment_intent_id, payment_intent_id);
+    assert_eq!(calls[0].amount, Some(15_000));
+    assert_eq!(
+        calls[0].idempotency_key.as_deref(),
+        Some("refund-unused-sub_test-pi_test-0")
+    );
+
+    // Refunding again doesn't refund the payment twice.
+    assert_eq!(
+        stripe_billing
+            .refund_unused_time(&subscription, now + Duration::days(1))
+            .await
+            .unwrap(),
+        15_000
+    );
+    assert_eq!(stripe_client.create_refund_calls.lock().len(), 1);
+
+    // Earlier refunds of the payment are subtracted from the refund.
+    stripe_client.latest_invoice_payments.lock().insert(
+        subscription.id.clone(),
+        StripeInvoicePayment {
+            amount_refunded: 5_000,
+            ..payment.clone()
+        },
+    );
+    assert_eq!(
+        stripe_billing
+            .refund_unused_time(&subscription, now)
+            .await
+            .unwrap(),
+        15_000
+    );
+    let calls = stripe_client.create_refund_calls.lock().clone();
+    assert_eq!(calls.len(), 2);
+    assert_eq!(calls[1].amount, Some(10_000));
+
+    // Nothing is refunded once the period is over.
+    stripe_client
+        .latest_invoice_payments
+        .lock()
+        .insert(subscription.id.clone(), payment);
+    assert_eq!(
+        stripe_billing
+            .refund_unused_time(&subscription, now + Duration::days(301))
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(stripe_client.create_refund_calls.lock().len(), 2);
 }
 
 #[gpui::test]
@@ -376,7 +870,15 @@ async fn test_checkout_with_zed_pro() {
     // It returns an error when the Zed Pro price doesn't exist.
     {
         let result = stripe_billing
-            .checkout_with_zed_pro(&customer_id, github_login, success_url)
+            .checkout_with_zed_pro(
+                &customer_id,
+                github_login,
+                ZedProBillingInterval::Monthly,
+                1,
+                success_url,
+                None,
+                None,
+            )
             .await;
 
         assert!(result.is_err());
@@ -402,7 +904,15 @@ async fn test_checkout_with_zed_pro() {
         stripe_billing.initialize().await.unwrap();
 
         let checkout_url = stripe_billing
-            .checkout_with_zed_pro(&customer_id, github_login, success_url)
+            .checkout_with_zed_pro(
+                &customer_id,
+                github_login,
+                ZedProBillingInterval::Monthly,
+                1,
+                success_url,
+                None,
+                Some("checkout-1"),
+            )
             .await
             .unwrap();
 
@@ -440,7 +950,292 @@ async fn test_checkout_with_zed_pro() {
                 shipping: None,
             })
         );
+        assert_eq!(call.discounts, None);
+        assert_eq!(call.idempotency_key.as_deref(), Some("checkout-1"));
+    }
+
+    // Teams check out with a seat for each member.
+    {
+        stripe_billing
+            .checkout_with_zed_pro(
+                &StripeCustomerId("cus_test".into()),
+                github_login,
+                ZedProBillingInterval::Monthly,
+                5,
+                success_url,
+                None,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let create_checkout_session_calls = stripe_client
+            .create_checkout_session_calls
+            .lock()
+            .drain(..)
+            .collect::<Vec<_>>();
+        assert_eq!(create_checkout_session_calls.len(), 1);
+        assert_eq!(
+            create_checkout_session_calls[0].line_items,
+            Some(vec![StripeCreateCheckoutSessionLineItems {
+                price: Some("price_1".to_string()),
+                quantity: Some(5)
+            }])
+        );
+    }
+}
+
+#[gpui::test]
+async fn test_checkout_with_zed_pro_annual() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    let customer_id = StripeCustomerId("cus_test".into());
+    let github_login = "zeduser1";
+    let success_url = "https://example.com/success";
+
+    let monthly_price = StripePrice {
+        id: StripePriceId("price_monthly".into()),
+        unit_amount: Some(2_000),
+        lookup_key: Some("zed-pro".to_string()),
+        recurring: None,
+    };
+    stripe_client
+        .prices
+        .lock()
+        .insert(monthly_price.id.clone(), monthly_price);
+    stripe_billing.initialize().await.unwrap();
+
+    // It returns an error when the annual Zed Pro price doesn't exist.
+    {
+        let result = stripe_billing
+            .checkout_with_zed_pro(
+                &customer_id,
+                github_login,
+                ZedProBillingInterval::Annual,
+                1,
+                success_url,
+                None,
+                None,
+            )
+            .await;
+
+        assert_eq!(
+            result.err().unwrap().to_string(),
+            r#"no price ID found for "zed-pro-annual""#
+        );
+    }
+
+    let annual_price = StripePrice {
+        id: StripePriceId("price_annual".into()),
+        unit_amount: Some(19_200),
+        lookup_key: Some("zed-pro-annual".to_string()),
+        recurring: None,
+    };
+    stripe_client
+        .prices
+        .lock()
+        .insert(annual_price.id.clone(), annual_price.clone());
+    stripe_billing.initialize().await.unwrap();
+
+    stripe_billing
+        .checkout_with_zed_pro(
+            &customer_id,
+            github_login,
+            ZedProBillingInterval::Annual,
+            1,
+            success_url,
+            None,
+            None,
+        )
+        .await
+        .unwrap();
+
+    let create_checkout_session_calls = stripe_client
+        .create_checkout_session_calls
+        .lock()
+        .drain(..)
+        .collect::<Vec<_>>();
+    assert_eq!(create_checkout_session_calls.len(), 1);
+    assert_eq!(
+        create_checkout_session_calls[0].line_items,
+        Some(vec![StripeCreateCheckoutSessionLineItems {
+            price: Some(annual_price.id.to_string()),
+            quantity: Some(1)
+        }])
+    );
+}
+
+#[gpui::test]
+async fn test_checkout_with_zed_pro_promotion_code() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    let now = Utc::now();
+    let promotion_code = |id: &str, code: &str| StripePromotionCode {
+        id: StripePromotionCodeId(id.into()),
+        code: code.to_string(),
+        active: true,
+        expires_at: None,
+        max_redemptions: None,
+        times_redeemed: 0,
+    };
+    for promotion_code in [
+        promotion_code("promo_launch", "LAUNCH"),
+        StripePromotionCode {
+            active: false,
+            ..promotion_code("promo_inactive", "INACTIVE")
+        },
+        StripePromotionCode {
+            expires_at: Some((now - Duration::days(1)).timestamp()),
+            ..promotion_code("promo_expired", "EXPIRED")
+        },
+        StripePromotionCode {
+            max_redemptions: Some(10),
+            times_redeemed: 10,
+            ..promotion_code("promo_used_up", "USEDUP")
+        },
+    ] {
+        stripe_client
+            .promotion_codes
+            .lock()
+            .insert(promotion_code.id.clone(), promotion_code);
+    }
+
+    // Only codes that can still be redeemed are found, regardless of case.
+    assert_eq!(
+        stripe_billing
+            .find_redeemable_promotion_code("launch", now)
+            .await
+            .unwrap(),
+        Some(StripePromotionCodeId("promo_launch".into()))
+    );
+    for code in ["UNKNOWN", "INACTIVE", "EXPIRED", "USEDUP"] {
+        assert_eq!(
+            stripe_billing
+                .find_redeemable_promotion_code(code, now)
+                .await
+                .unwrap(),
+            None,
+            "{code}"
+        );
     }
+
+    // The promotion code is applied to the Checkout session.
+    let price = StripePrice {
+        id: StripePriceId("price_1".into()),
+        unit_amount: Some(2_000),
+        lookup_key: Some("zed-pro".to_string()),
+        recurring: None,
+    };
+    stripe_client.prices.lock().insert(price.id.clone(), price);
+    stripe_billing.initialize().await.unwrap();
+
+    stripe_billing
+        .checkout_with_zed_pro(
+            &StripeCustomerId("cus_test".into()),
+            "zeduser1",
+            ZedProBillingInterval::Monthly,
+            1,
+            "https://example.com/success",
+            Some(&StripePromotionCodeId("promo_launch".into())),
+            None,
+        )
+        .await
+        .unwrap();
+
+    let create_checkout_session_calls = stripe_client.create_checkout_session_calls.lock();
+    assert_eq!(create_checkout_session_calls.len(), 1);
+    assert_eq!(
+        create_checkout_session_calls[0].discounts,
+        Some(vec![StripeCreateCheckoutSessionDiscounts {
+            promotion_code: Some("promo_launch".to_string()),
+        }])
+    );
+}
+
+#[gpui::test]
+async fn test_determine_subscription_kind() {
+    let (stripe_billing, stripe_client) = make_stripe_billing();
+
+    let price = |id: &str, lookup_key: &str| StripePrice {
+        id: StripePriceId(id.into()),
+        unit_amount: Some(0),
+        lookup_key: Some(lookup_key.to_string()),
+        recurring: None,
+    };
+    for price in [
+        price("price_monthly", "zed-pro"),
+        price("price_annual", "zed-pro-annual"),
+        price("price_free", "zed-free"),
+        price("price_other", "other"),
+    ] {
+        stripe_client.prices.lock().insert(price.id.clone(), price);
+    }
+    stripe_billing.initialize().await.unwrap();
+
+    let now = Utc::now();
+    let subscription = |price_id: &str, status: stripe::SubscriptionStatus| StripeSubscription {
+        id: StripeSubscriptionId("sub_test".into()),
+        customer: StripeCustomerId("cus_test".into()),
+        status,
+        current_period_start: now.timestamp(),
+        current_period_end: (now + Duration::days(30)).timestamp(),
+        items: vec![StripeSubscriptionItem {
+            id: StripeSubscriptionItemId("si_test".into()),
+            price: stripe_client
+                .prices
+                .lock()
+                .get(&StripePriceId(price_id.into()))
+                .cloned(),
+            quantity: Some(1),
+        }],
+        cancel_at: None,
+        cancel_at_period_end: false,
+        cancellation_details: None,
+        discount: None,
+        currency: "usd".into(),
+        pause_collection: None,
+    };
+
+    // Both the monthly and the annual Zed Pro prices are Zed Pro.
+    for price_id in ["price_monthly", "price_annual"] {
+        assert_eq!(
+            stripe_billing
+                .determine_subscription_kind(&subscription(
+                    price_id,
+                    stripe::SubscriptionStatus::Active
+                ))
+                .await,
+            Some(SubscriptionKind::ZedPro)
+        );
+        assert_eq!(
+            stripe_billing
+                .determine_subscription_kind(&subscription(
+                    price_id,
+                    stripe::SubscriptionStatus::Trialing
+                ))
+                .await,
+            Some(SubscriptionKind::ZedProTrial)
+        );
+    }
+
+    assert_eq!(
+        stripe_billing
+            .determine_subscription_kind(&subscription(
+                "price_free",
+                stripe::SubscriptionStatus::Active
+            ))
+            .await,
+        Some(SubscriptionKind::ZedFree)
+    );
+    assert_eq!(
+        stripe_billing
+            .determine_subscription_kind(&subscription(
+                "price_other",
+                stripe::SubscriptionStatus::Active
+            ))
+            .await,
+        None
+    );
 }
 
 #[gpui::test]
@@ -454,7 +1249,16 @@ async fn test_checkout_with_zed_pro_trial() {
     // It returns an error when the Zed Pro price doesn't exist.
     {
         let result = stripe_billing
-            .checkout_with_zed_pro_trial(&customer_id, github_login, Vec::new(), success_url)
+            .checkout_with_zed_pro_trial(
+                &customer_id,
+                github_login,
+                Vec::new(),
+                14,
+                60,
+                StripeCheckoutSessionPaymentMethodCollection::IfRequired,
+                success_url,
+                None,
+            )
             .await;
 
         assert!(result.is_err());
@@ -480,7 +1284,16 @@ async fn test_checkout_with_zed_pro_trial() {
     // Successful checkout.
     {
         let checkout_url = stripe_billing
-            .checkout_with_zed_pro_trial(&customer_id, github_login, Vec::new(), success_url)
+            .checkout_with_zed_pro_trial(
+                &customer_id,
+                github_login,
+                Vec::new(),
+                14,
+                60,
+                StripeCheckoutSessionPaymentMethodCollection::IfRequired,
+                success_url,
+                None,
+            )
             .await
             .unwrap();
 
@@ -542,7 +1355,11 @@ async fn test_checkout_with_zed_pro_trial() {
                 &customer_id,
                 github_login,
                 vec![AGENT_EXTENDED_TRIAL_FEATURE_FLAG.to_string()],
+                14,
+                60,
+                StripeCheckoutSessionPaymentMethodCollection::IfRequired,
                 success_url,
+                None,
             )
             .await
             .unwrap();
@@ -556,7 +1373,7 @@ async fn test_checkout_with_zed_pro_trial() {
             .collect::<Vec<_>>();
         assert_eq!(create_checkout_session_calls.len(), 1);
         let call = create_checkout_session_calls.into_iter().next().unwrap();
-        assert_eq!(call.customer, Some(customer_id));
+        assert_eq!(call.customer.as_ref(), Some(&customer_id));
         assert_eq!(call.client_reference_id.as_deref(), Some(github_login));
         assert_eq!(call.mode, Some(StripeCheckoutSessionMode::Subscription));
         assert_eq!(
@@ -600,4 +1417,38 @@ async fn test_checkout_with_zed_pro_trial() {
             })
         );
     }
+
+    // Successful checkout that collects a card up front.
+    {
+        stripe_billing
+            .checkout_with_zed_pro_trial(
+                &customer_id,
+                github_login,
+                Vec::new(),
+                14,
+                60,
+                StripeCheckoutSessionPaymentMethodCollection::Always,
+                success_url,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let create_checkout_session_calls = stripe_client
+            .create_checkout_session_calls
+            .lock()
+            .drain(..)
+            .collect::<Vec<_>>();
+        assert_eq!(create_checkout_session_calls.len(), 1);
+        let call = create_checkout_session_calls.into_iter().next().unwrap();
+        assert_eq!(
+            call.payment_method_collection,
+            Some(StripeCheckoutSessionPaymentMethodCollection::Always)
+        );
+        assert_eq!(
+            call.subscription_data
+                .and_then(|subscription_data| subscription_data.trial_period_days),
+            Some(14)
+        );
+    }
 }
@@ -570,6 +570,33 @@ impl TestServer {
                 kinesis_stream: None,
                 kinesis_access_key: None,
                 kinesis_secret_key: None,
+                trial_end_behavior: None,
+                overlapping_subscription_resolution: None,
+                duplicate_checkout_behavior: None,
+                trial_payment_method_collection: None,
+                zed_pro_trial_days: None,
+                zed_pro_extended_trial_days: None,
+                billing_read_only: None,
+                edit_prediction_overages_enabled: None,
+                overage_grace: None,
+                billing_webhook_urls: None,
+                billing_webhook_secret: None,
+                stripe_webhook_secret: None,
+                stale_stripe_event_age_seconds: None,
+                processed_stripe_event_retention_days: None,
+                billing_db_write_retries: None,
+                winback_coupon_id: None,
+                model_request_allotments: None,
+                model_request_limits: None,
+                model_request_billing: None,
+                unclassified_subscription_plan: None,
+                alert_on_unclassified_subscriptions: None,
+                usage_reset_windows: None,
+                free_downgrade_delay_seconds: None,
+                past_due_grace_period_seconds: None,
+                minimum_term_days: None,
+                max_subscription_pauses_per_year: None,
+                stripe_usage_sync_dry_run: None,
             },
         })
     }
@@ -431,6 +431,25 @@ impl NotificationPanel {
                     can_navigate: true,
                 })
             }
+            Notification::BillingPriceChange {
+                new_amount_in_cents,
+                effective_at,
+                ..
+            } => {
+                let effective_at = OffsetDateTime::from_unix_timestamp(effective_at).ok()?;
+                Some(NotificationPresenter {
+                    icon: "icons/info.svg",
+                    text: format!(
+                        "The price of your subscription will change to ${}.{:02} on {}",
+                        new_amount_in_cents / 100,
+                        new_amount_in_cents % 100,
+                        effective_at.date(),
+                    ),
+                    needs_response: false,
+                    actor: None,
+                    can_navigate: false,
+                })
+            }
         }
     }
 
@@ -442,7 +461,8 @@ impl NotificationPanel {
         cx: &mut Context<Self>,
     ) {
         let should_mark_as_read = match notification {
-            Notification::ContactRequestAccepted { .. } => true,
+            Notification::ContactRequestAccepted { .. }
+            | Notification::BillingPriceChange { .. } => true,
             Notification::ContactRequest { .. }
             | Notification::ChannelInvitation { .. }
             | Notification::ChannelMessageMention { .. } => false,
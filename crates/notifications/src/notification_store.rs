@@ -302,6 +302,7 @@ impl NotificationStore {
                     user_ids.push(sender_id);
                     message_ids.push(message_id);
                 }
+                Notification::BillingPriceChange { .. } => {}
             }
         }
 
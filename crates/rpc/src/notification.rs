@@ -38,6 +38,13 @@ pub enum Notification {
         sender_id: u64,
         channel_id: u64,
     },
+    BillingPriceChange {
+        #[serde(rename = "entity_id")]
+        billing_subscription_id: u64,
+        new_amount_in_cents: i64,
+        /// The Unix timestamp at which the new price takes effect.
+        effective_at: i64,
+    },
 }
 
 impl Notification {
@@ -96,6 +103,11 @@ mod tests {
                 channel_id: 30,
                 message_id: 1,
             },
+            Notification::BillingPriceChange {
+                billing_subscription_id: 7,
+                new_amount_in_cents: 2_500,
+                effective_at: 1_754_006_400,
+            },
         ] {
             let message = notification.to_proto();
             let deserialized = Notification::from_proto(&message).unwrap();